@@ -1,6 +1,7 @@
 // Copyright 2021 Chay Nabors.
 
 use gear::event::Event;
+use gear::event::NetworkEvent;
 use gear::event::WindowEvent;
 use gear::Engine;
 
@@ -10,13 +11,17 @@ async fn main() {
 
     Engine::new().await.run(move |engine, event| match event {
         Event::UpdateEvent { delta_time: _ } => {
-            engine.renderer.set_clear_color([0.03, 0.03, 0.03, 1.0]).submit();
+            engine.renderer().set_clear_color([0.03, 0.03, 0.03, 1.0]).submit();
         },
         Event::TerminateEvent => (),
         Event::WindowEvent(event) => match event {
             WindowEvent::Resized(_size) => {},
             _ => (),
         },
+        Event::NetworkEvent(_socket, event) => match event {
+            NetworkEvent::Message(_packet) => {},
+            _ => (),
+        },
         _ => (),
     });
 }
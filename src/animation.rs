@@ -0,0 +1,174 @@
+// Copyright 2021 Chay Nabors.
+
+use std::time::Duration;
+
+use nalgebra::Matrix4;
+use nalgebra::Quaternion;
+use nalgebra::Translation3;
+use nalgebra::UnitQuaternion;
+use nalgebra::Vector3;
+
+use crate::SceneNode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    // Every key has an in tangent and an out tangent stored around its value
+    CubicSpline,
+}
+
+#[derive(Clone, Debug)]
+pub enum ChannelValues {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<UnitQuaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+}
+
+// Keyframes for one property of one node, times are in seconds and ascending
+#[derive(Clone, Debug)]
+pub struct AnimationChannel {
+    pub node: usize,
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+    pub interpolation: Interpolation,
+}
+
+#[derive(Clone, Debug)]
+pub struct Animation {
+    pub name: Option<String>,
+    pub channels: Vec<AnimationChannel>,
+    // The time of the last key of any channel
+    pub duration: f32,
+}
+
+impl Animation {
+    // The transform of every node relative to its parent at the time, nodes without channels keep their own
+    pub fn sample(&self, time: f32, nodes: &[SceneNode]) -> Vec<Matrix4<f32>> {
+        let mut translations: Vec<Vector3<f32>> = nodes.iter().map(|node| node.translation).collect();
+        let mut rotations: Vec<UnitQuaternion<f32>> = nodes.iter().map(|node| node.rotation).collect();
+        let mut scales: Vec<Vector3<f32>> = nodes.iter().map(|node| node.scale).collect();
+
+        for channel in &self.channels {
+            if channel.node >= nodes.len() || channel.times.is_empty() {
+                continue;
+            }
+
+            let (from, to, t, span) = keys(&channel.times, time);
+            match &channel.values {
+                ChannelValues::Translation(values) => {
+                    translations[channel.node] = interpolate(values, from, to, t, span, channel.interpolation)
+                },
+                ChannelValues::Scale(values) => {
+                    scales[channel.node] = interpolate(values, from, to, t, span, channel.interpolation)
+                },
+                ChannelValues::Rotation(values) => {
+                    rotations[channel.node] = match channel.interpolation {
+                        Interpolation::Step => values[from],
+                        Interpolation::Linear => values[from].slerp(&values[to], t),
+                        Interpolation::CubicSpline => {
+                            let coords: Vec<_> = values.iter().map(|value| value.into_inner().coords).collect();
+                            let value = interpolate(&coords, from, to, t, span, Interpolation::CubicSpline);
+                            UnitQuaternion::new_normalize(Quaternion::from(value))
+                        },
+                    }
+                },
+            }
+        }
+
+        (0..nodes.len())
+            .map(|i| {
+                Translation3::from(translations[i]).to_homogeneous()
+                    * rotations[i].to_homogeneous()
+                    * Matrix4::new_nonuniform_scaling(&scales[i])
+            })
+            .collect()
+    }
+}
+
+// The keys around the time, how far it is between them and the time between them. Times outside the keys hold the
+// first or last one
+fn keys(times: &[f32], time: f32) -> (usize, usize, f32, f32) {
+    let last = times.len() - 1;
+    if time <= times[0] {
+        return (0, 0, 0., 0.);
+    }
+    if time >= times[last] {
+        return (last, last, 0., 0.);
+    }
+
+    let to = times.iter().position(|key| *key > time).unwrap_or(last);
+    let from = to - 1;
+    let span = times[to] - times[from];
+    (from, to, (time - times[from]) / span, span)
+}
+
+fn interpolate<T>(values: &[T], from: usize, to: usize, t: f32, span: f32, interpolation: Interpolation) -> T
+where
+    T: Copy + std::ops::Add<Output = T> + std::ops::Mul<f32, Output = T>,
+{
+    match interpolation {
+        Interpolation::Step => values[from],
+        Interpolation::Linear => values[from] * (1. - t) + values[to] * t,
+        // Hermite spline through the values with the tangents scaled to the time between the keys
+        Interpolation::CubicSpline => {
+            let (t2, t3) = (t * t, t * t * t);
+            let start = values[from * 3 + 1];
+            let out_tangent = values[from * 3 + 2] * span;
+            let end = values[to * 3 + 1];
+            let in_tangent = values[to * 3] * span;
+            start * (2. * t3 - 3. * t2 + 1.)
+                + out_tangent * (t3 - 2. * t2 + t)
+                + end * (-2. * t3 + 3. * t2)
+                + in_tangent * (t3 - t2)
+        },
+    }
+}
+
+// Playback state of one of a scene's animations, draw it with Renderer::draw_scene_animated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnimationPlayer {
+    pub animation: usize,
+    pub duration: f32,
+    // Seconds into the animation
+    pub time: f32,
+    // Negative speeds play backwards
+    pub speed: f32,
+    pub looping: bool,
+    pub playing: bool,
+}
+
+impl AnimationPlayer {
+    pub fn new(animation: usize, duration: f32) -> AnimationPlayer {
+        AnimationPlayer { animation, duration, time: 0., speed: 1., looping: true, playing: true }
+    }
+
+    // Call it with the delta time of every update
+    pub fn update(&mut self, delta_time: Duration) {
+        if !self.playing {
+            return;
+        }
+
+        let time = self.time + delta_time.as_secs_f32() * self.speed;
+        if self.looping && self.duration > 0. {
+            self.time = time.rem_euclid(self.duration);
+        } else {
+            self.time = time.max(0.).min(self.duration);
+            if time < 0. || time > self.duration {
+                self.playing = false;
+            }
+        }
+    }
+
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn seek(&mut self, time: f32) {
+        self.time = time.max(0.).min(self.duration);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use image::imageops;
+use image::RgbaImage;
+use wgpu::BindGroupLayout;
+use wgpu::Device;
+use wgpu::Queue;
+
+use crate::GearError;
+use crate::Result;
+use crate::Texture;
+use crate::TextureOptions;
+
+// Every adapter supports textures this big
+const MAX_ATLAS_SIZE: u32 = 8192;
+// Keeps neighbouring images from bleeding into each other when filtered
+const PADDING: u32 = 1;
+
+// Regions of one texture as [left, top, right, bottom] in texture coordinates, so sprites drawn from any of them batch
+// together. Frames are numbered in the order they were added, named ones can also be found by name
+#[derive(Clone, Debug)]
+pub struct TextureAtlas {
+    texture: Texture,
+    frames: Vec<[f32; 4]>,
+    names: HashMap<String, usize>,
+}
+
+impl TextureAtlas {
+    // A sprite sheet of equally sized frames numbered left to right and then top to bottom
+    pub fn from_grid(texture: Texture, frame_size: [u32; 2]) -> TextureAtlas {
+        let size = texture.size();
+        let columns = size[0] / frame_size[0].max(1);
+        let rows = size[1] / frame_size[1].max(1);
+        let mut frames = Vec::with_capacity((columns * rows) as usize);
+        for row in 0..rows {
+            for column in 0..columns {
+                let origin = [column * frame_size[0], row * frame_size[1]];
+                frames.push(region(origin, frame_size, size));
+            }
+        }
+        TextureAtlas { texture, frames, names: HashMap::new() }
+    }
+
+    // Regions given in pixels as [left, top, width, height]
+    pub fn from_regions(texture: Texture, regions: &[(&str, [u32; 4])]) -> TextureAtlas {
+        let size = texture.size();
+        let frames = regions.iter().map(|(_, rect)| region([rect[0], rect[1]], [rect[2], rect[3]], size)).collect();
+        let names = regions.iter().enumerate().map(|(i, (name, _))| (name.to_string(), i)).collect();
+        TextureAtlas { texture, frames, names }
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    pub fn frame(&self, index: usize) -> Option<[f32; 4]> {
+        self.frames.get(index).copied()
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn region(&self, name: &str) -> Option<[f32; 4]> {
+        self.names.get(name).map(|index| self.frames[*index])
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names.get(name).copied()
+    }
+}
+
+// Loose images packed into a single atlas when created with Renderer::create_atlas
+#[derive(Debug, Default)]
+pub struct AtlasPacker {
+    images: Vec<(String, RgbaImage)>,
+}
+
+impl AtlasPacker {
+    pub fn new() -> AtlasPacker {
+        AtlasPacker::default()
+    }
+
+    // A name that was already added is replaced
+    pub fn add(&mut self, name: &str, image: RgbaImage) -> &mut Self {
+        match self.images.iter_mut().find(|(existing, _)| existing == name) {
+            Some((_, existing)) => *existing = image,
+            None => self.images.push((name.to_string(), image)),
+        }
+        self
+    }
+
+    pub fn add_file<P: AsRef<Path>>(&mut self, name: &str, path: P) -> Result<&mut Self> {
+        let image = image::open(path)?.into_rgba8();
+        Ok(self.add(name, image))
+    }
+
+    pub(crate) fn pack(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        texture_layout: &BindGroupLayout,
+        options: &TextureOptions,
+    ) -> Result<TextureAtlas> {
+        // Tallest first, so each row wastes as little as it can
+        let mut order = (0..self.images.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| std::cmp::Reverse(self.images[*i].1.height()));
+
+        let area = self
+            .images
+            .iter()
+            .map(|(_, image)| (image.width() + PADDING) as u64 * (image.height() + PADDING) as u64)
+            .sum::<u64>();
+        let mut size = ((area as f64).sqrt().ceil() as u32).max(1).next_power_of_two();
+        let origins = loop {
+            if let Some(origins) = place(&self.images, &order, size) {
+                break origins;
+            }
+            if size >= MAX_ATLAS_SIZE {
+                return Err(GearError::AtlasFull);
+            }
+            size *= 2;
+        };
+
+        let mut atlas = RgbaImage::new(size, size);
+        for ((_, image), origin) in self.images.iter().zip(&origins) {
+            imageops::replace(&mut atlas, image, origin[0], origin[1]);
+        }
+        let texture = Texture::from_image(device, queue, texture_layout, atlas, options);
+
+        let frames = self
+            .images
+            .iter()
+            .zip(&origins)
+            .map(|((_, image), origin)| region(*origin, [image.width(), image.height()], [size, size]))
+            .collect();
+        let names = self.images.iter().enumerate().map(|(i, (name, _))| (name.clone(), i)).collect();
+        Ok(TextureAtlas { texture, frames, names })
+    }
+}
+
+// Where each image goes on a square page this big, in rows as tall as the tallest image in them
+fn place(images: &[(String, RgbaImage)], order: &[usize], size: u32) -> Option<Vec<[u32; 2]>> {
+    let mut origins = vec![[0, 0]; images.len()];
+    let mut cursor = [0, 0];
+    let mut row_height = 0;
+    for i in order {
+        let image = &images[*i].1;
+        if cursor[0] + image.width() > size {
+            cursor = [0, cursor[1] + row_height];
+            row_height = 0;
+        }
+        if cursor[0] + image.width() > size || cursor[1] + image.height() > size {
+            return None;
+        }
+
+        origins[*i] = cursor;
+        cursor[0] += image.width() + PADDING;
+        row_height = row_height.max(image.height() + PADDING);
+    }
+    Some(origins)
+}
+
+fn region(origin: [u32; 2], size: [u32; 2], texture_size: [u32; 2]) -> [f32; 4] {
+    let width = texture_size[0].max(1) as f32;
+    let height = texture_size[1].max(1) as f32;
+    [
+        origin[0] as f32 / width,
+        origin[1] as f32 / height,
+        (origin[0] + size[0]) as f32 / width,
+        (origin[1] + size[1]) as f32 / height,
+    ]
+}
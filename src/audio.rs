@@ -5,6 +5,8 @@ use std::fmt::Debug;
 use log::info;
 use rodio::OutputStream;
 use rodio::OutputStreamHandle;
+use rodio::Sample;
+use rodio::Source;
 
 use crate::Sound;
 
@@ -18,6 +20,15 @@ impl AudioSource {
         self
     }
 
+    pub(crate) fn queue_source<S>(&self, source: S) -> &Self
+    where
+        S: Source + Send + 'static,
+        S::Item: Sample + Send,
+    {
+        self.sink.append(source);
+        self
+    }
+
     pub fn set_volume(&self, volume: f32) -> &Self {
         self.sink.set_volume(volume);
         self
@@ -0,0 +1,83 @@
+// Copyright 2021 Chay Nabors.
+
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use nalgebra::Vector4;
+
+// An axis aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> Aabb {
+        Aabb { min, max }
+    }
+
+    // None without any points
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Option<Aabb> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Aabb::new(first, first), |aabb, point| aabb.union(&Aabb::new(point, point))))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.coords.inf(&other.min.coords).into(), self.max.coords.sup(&other.max.coords).into())
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        nalgebra::center(&self.min, &self.max)
+    }
+
+    // The box around this one after it has been transformed
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Aabb {
+        let corners = (0..8).map(|i| {
+            let corner = Point3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            transform.transform_point(&corner)
+        });
+        Aabb::from_points(corners).unwrap_or(*self)
+    }
+}
+
+// The six planes bounding what a camera sees, each facing inward
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // Clip space depth goes from zero to one, so reversed and infinite projections work as well
+    pub fn from_view_projection(view_projection: &Matrix4<f32>) -> Frustum {
+        let row = |i: usize| view_projection.row(i).transpose();
+        let planes = [row(3) + row(0), row(3) - row(0), row(3) + row(1), row(3) - row(1), row(2), row(3) - row(2)];
+        Frustum { planes }
+    }
+
+    // Conservative, boxes near the corners of the frustum can pass while being outside of it
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal
+            let corner = Vector4::new(
+                if plane.x >= 0. { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0. { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0. { aabb.max.z } else { aabb.min.z },
+                1.,
+            );
+            plane.dot(&corner) >= 0.
+        })
+    }
+
+    pub(crate) fn planes(&self) -> &[Vector4<f32>; 6] {
+        &self.planes
+    }
+
+    pub fn contains(&self, point: &Point3<f32>) -> bool {
+        self.planes.iter().all(|plane| plane.dot(&point.to_homogeneous()) >= 0.)
+    }
+}
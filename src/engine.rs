@@ -1,8 +1,13 @@
 // Copyright 2021 Chay Nabors.
 
+use std::fmt::Debug;
+use std::hint::{self,};
+use std::mem;
+use std::thread::{self,};
 use std::time::Duration;
 use std::time::Instant;
 
+use hecs::World;
 use log::info;
 use winit::event::Event as WinitEvent;
 use winit::event::WindowEvent as WinitWindowEvent;
@@ -15,47 +20,176 @@ use crate::input::InputEvent;
 use crate::input::MouseEvent;
 use crate::network::Network;
 use crate::network::NetworkEvent;
+use crate::network::SocketId;
 use crate::renderer::Renderer;
+use crate::renderer::RendererSettings;
+use crate::schedule::Schedule;
+use crate::schedule::Stage;
+use crate::state::GameState;
+use crate::state::StateChange;
+use crate::time::Time;
 use crate::window::Window;
 use crate::window::WindowEvent;
 
+// Fixed updates catching up after a long frame stop at this many, the rest of the time is dropped
+const MAX_FIXED_STEPS: u32 = 8;
+// Sleeps wake up late by up to a millisecond or two on most platforms, so the end of a frame limit's wait spins
+const SPIN_TIME: Duration = Duration::from_millis(2);
+
 #[derive(Clone, Debug)]
 pub enum Event {
+    // Scaled by the engine's time, which has the unscaled one too
     UpdateEvent { delta_time: Duration },
+    // Steps of the same length however fast frames are drawn, counted by tick from zero. The ones a frame is due come
+    // before its UpdateEvent, and they come slower or stop along with the engine's time
+    FixedUpdate { tick: u64, step: Duration },
     TerminateEvent,
     WindowEvent(WindowEvent),
     InputEvent(InputEvent),
-    NetworkEvent(NetworkEvent),
+    NetworkEvent(SocketId, NetworkEvent),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EngineSettings {
+    pub renderer: RendererSettings,
+    // Per pixel transparency, where the clear color's alpha lets the desktop show through. Whether the compositor
+    // blends the window depends on the platform, where it doesn't the window stays opaque
+    pub transparent: bool,
 }
 
-#[derive(Debug)]
 pub struct Engine {
     event_loop: Option<EventLoop<()>>,
     pub window: Window,
     pub input: Input,
-    pub renderer: Renderer,
-    pub audio: Audio,
+    // None for headless engines
+    renderer: Option<Renderer>,
+    audio: Option<Audio>,
     pub network: Network,
+    pub time: Time,
+    // Entities and their components, for the schedule's systems and the event handler to query
+    pub world: World,
+    pub schedule: Schedule,
+    states: Vec<Box<dyn GameState>>,
+    // A state is taken off the stack while its hook or event runs, since it gets the engine
+    active: bool,
+    state_changes: Vec<StateChange>,
+    fixed_step: Duration,
+    frame_rate_limit: Option<u32>,
+    last_frame: Instant,
+    accumulator: Duration,
+    tick: u64,
+    terminated: bool,
 }
 
 impl Engine {
     pub async fn new() -> Engine {
+        Engine::with_settings(EngineSettings::default()).await
+    }
+
+    pub async fn with_settings(settings: EngineSettings) -> Engine {
         let event_loop = EventLoop::new();
-        let window = Window::new(&event_loop);
+        let window = Window::new(&event_loop, settings.transparent);
         let input = Input::new();
-        let renderer = Renderer::new(&window).await.unwrap();
+        let renderer = Renderer::new(&window, &settings.renderer).await.unwrap();
         let audio = Audio::new();
-        let network = Network::new();
 
-        Engine { event_loop: Some(event_loop), window, input, renderer, audio, network }
+        Engine::create(Some(event_loop), window, Some(renderer), Some(audio))
+    }
+
+    // Without a window, renderer or audio for dedicated servers. The same events are sent apart from the window's and
+    // the input's. Frames aren't limited unless set, so a server that doesn't want to keep a core busy sets one
+    pub fn headless() -> Engine {
+        Engine::create(None, Window::headless(), None, None)
+    }
+
+    fn create(
+        event_loop: Option<EventLoop<()>>,
+        window: Window,
+        renderer: Option<Renderer>,
+        audio: Option<Audio>,
+    ) -> Engine {
+        Engine {
+            event_loop,
+            window,
+            input: Input::new(),
+            renderer,
+            audio,
+            network: Network::new(),
+            time: Time::new(),
+            world: World::new(),
+            schedule: Schedule::default(),
+            states: vec![],
+            active: false,
+            state_changes: vec![],
+            fixed_step: Duration::from_secs(1) / 60,
+            frame_rate_limit: None,
+            last_frame: Instant::now(),
+            accumulator: Duration::default(),
+            tick: 0,
+            terminated: false,
+        }
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.renderer.is_none()
+    }
+
+    // Panics on headless engines, try_renderer for code that runs on both
+    pub fn renderer(&mut self) -> &mut Renderer {
+        self.renderer.as_mut().expect("headless engines have no renderer")
+    }
+
+    pub fn try_renderer(&mut self) -> Option<&mut Renderer> {
+        self.renderer.as_mut()
+    }
+
+    // Panics on headless engines, try_audio for code that runs on both
+    pub fn audio(&mut self) -> &mut Audio {
+        self.audio.as_mut().expect("headless engines have no audio")
+    }
+
+    pub fn try_audio(&mut self) -> Option<&mut Audio> {
+        self.audio.as_mut()
+    }
+
+    // Sixty a second unless set, a step of zero stops the fixed updates
+    pub fn set_fixed_step(&mut self, step: Duration) -> &mut Self {
+        self.fixed_step = step;
+        self
+    }
+
+    pub fn fixed_step(&self) -> Duration {
+        self.fixed_step
+    }
+
+    // Frames a second the engine waits to stay under, apart from any vsync of the renderer's present mode. None or
+    // zero draws frames as fast as they come
+    pub fn set_frame_rate_limit(&mut self, limit: Option<u32>) -> &mut Self {
+        self.frame_rate_limit = limit.filter(|limit| *limit > 0);
+        self
+    }
+
+    pub fn frame_rate_limit(&self) -> Option<u32> {
+        self.frame_rate_limit
     }
 
     pub fn run<F: 'static + FnMut(&mut Engine, Event)>(mut self, mut event_handler: F) {
         let mut size = [0, 0];
+        self.last_frame = Instant::now();
+        // States pushed before the engine runs enter here
+        self.apply_state_changes();
 
-        let mut prev_now = Instant::now();
-
-        let event_loop = self.event_loop.take().unwrap();
+        let event_loop = match self.event_loop.take() {
+            Some(event_loop) => event_loop,
+            None => {
+                while !self.terminated {
+                    self.frame(&mut event_handler);
+                }
+                info!("Terminating game");
+                self.dispatch(&mut event_handler, Event::TerminateEvent);
+                return;
+            },
+        };
         event_loop.run(move |event, _, control_flow| {
             *control_flow = ControlFlow::Poll;
 
@@ -65,19 +199,21 @@ impl Engine {
                         WinitWindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                         WinitWindowEvent::Resized(new_size) => {
                             size = [new_size.width, new_size.height];
-                            self.renderer.resize(size);
-                            event_handler(&mut self, Event::WindowEvent(WindowEvent::Resized(size)));
+                            if let Some(renderer) = &mut self.renderer {
+                                renderer.resize(size);
+                            }
+                            self.dispatch(&mut event_handler, Event::WindowEvent(WindowEvent::Resized(size)));
                         },
                         WinitWindowEvent::Moved(new_position) => {
                             let position = [new_position.x, new_position.y];
-                            event_handler(&mut self, Event::WindowEvent(WindowEvent::Moved(position)));
+                            self.dispatch(&mut event_handler, Event::WindowEvent(WindowEvent::Moved(position)));
                         },
                         WinitWindowEvent::KeyboardInput { input, .. } => {
-                            event_handler(&mut self, Event::InputEvent(InputEvent::KeyboardEvent(input)))
+                            self.dispatch(&mut event_handler, Event::InputEvent(InputEvent::KeyboardEvent(input)))
                         },
                         WinitWindowEvent::CursorMoved { position, .. } => {
-                            event_handler(
-                                &mut self,
+                            self.dispatch(
+                                &mut event_handler,
                                 Event::InputEvent(InputEvent::MouseEvent(MouseEvent::CursorMoved([
                                     position.x - size[0] as f64 / 2.,
                                     position.y - size[1] as f64 / 2.,
@@ -89,26 +225,160 @@ impl Engine {
                     };
                 },
                 WinitEvent::MainEventsCleared => self.window.request_redraw(),
-                WinitEvent::RedrawRequested(_) => {
-                    while let Some(event) = self.network.get_event() {
-                        event_handler(&mut self, Event::NetworkEvent(event));
-                    }
-
-                    let now = Instant::now();
-                    let delta_time = now - prev_now;
-                    prev_now = now;
-                    event_handler(&mut self, Event::UpdateEvent { delta_time });
-                },
+                WinitEvent::RedrawRequested(_) => self.frame(&mut event_handler),
                 WinitEvent::LoopDestroyed => {
                     info!("Terminating game");
-                    event_handler(&mut self, Event::TerminateEvent); // terminate event
+                    self.dispatch(&mut event_handler, Event::TerminateEvent); // terminate event
                 },
                 _ => (),
             }
         })
     }
 
+    // Over the current state, which pauses. Like pop_state and switch_state it happens once the event being sent is
+    // done with, or when the engine starts running
+    pub fn push_state<S: 'static + GameState>(&mut self, state: S) -> &mut Self {
+        self.state_changes.push(StateChange::Push(Box::new(state)));
+        self
+    }
+
+    // The state under it resumes
+    pub fn pop_state(&mut self) -> &mut Self {
+        self.state_changes.push(StateChange::Pop);
+        self
+    }
+
+    // Replaces the current state, the ones under it aren't resumed
+    pub fn switch_state<S: 'static + GameState>(&mut self, state: S) -> &mut Self {
+        self.state_changes.push(StateChange::Switch(Box::new(state)));
+        self
+    }
+
+    // States on the stack with the paused ones, counting the one whose hook or event is running
+    pub fn state_count(&self) -> usize {
+        self.states.len() + self.active as usize
+    }
+
+    // To the current state and then the handler
+    fn dispatch<F: FnMut(&mut Engine, Event)>(&mut self, event_handler: &mut F, event: Event) {
+        if let Some(mut state) = self.states.pop() {
+            self.hook(state.as_mut(), |state, engine| state.event(engine, &event));
+            self.states.push(state);
+        }
+        event_handler(self, event);
+        self.apply_state_changes();
+    }
+
+    // The hooks can change the stack again, which is applied after them
+    fn apply_state_changes(&mut self) {
+        while !self.state_changes.is_empty() {
+            for change in mem::take(&mut self.state_changes) {
+                match change {
+                    StateChange::Push(mut state) => {
+                        if let Some(mut top) = self.states.pop() {
+                            self.hook(top.as_mut(), |top, engine| top.pause(engine));
+                            self.states.push(top);
+                        }
+                        self.hook(state.as_mut(), |state, engine| state.enter(engine));
+                        self.states.push(state);
+                    },
+                    StateChange::Pop => {
+                        if let Some(mut state) = self.states.pop() {
+                            self.hook(state.as_mut(), |state, engine| state.exit(engine));
+                            if let Some(mut top) = self.states.pop() {
+                                self.hook(top.as_mut(), |top, engine| top.resume(engine));
+                                self.states.push(top);
+                            }
+                        }
+                    },
+                    StateChange::Switch(mut state) => {
+                        if let Some(mut top) = self.states.pop() {
+                            self.hook(top.as_mut(), |top, engine| top.exit(engine));
+                        }
+                        self.hook(state.as_mut(), |state, engine| state.enter(engine));
+                        self.states.push(state);
+                    },
+                }
+            }
+        }
+    }
+
+    fn hook<F: FnOnce(&mut dyn GameState, &mut Engine)>(&mut self, state: &mut dyn GameState, hook: F) {
+        self.active = true;
+        hook(state, self);
+        self.active = false;
+    }
+
+    // The network, fixed update and update events of a frame
+    fn frame<F: FnMut(&mut Engine, Event)>(&mut self, event_handler: &mut F) {
+        if let Some(limit) = self.frame_rate_limit {
+            wait_until(self.last_frame + Duration::from_secs(1) / limit);
+        }
+
+        self.network.manual_poll();
+        while let Some((socket, event)) = self.network.get_event() {
+            self.dispatch(event_handler, Event::NetworkEvent(socket, event));
+        }
+
+        let now = Instant::now();
+        let delta_time = self.time.advance(now - self.last_frame);
+        self.last_frame = now;
+
+        // The step is read every frame so the handler can change it between them
+        let step = self.fixed_step;
+        self.accumulator = match step > Duration::default() {
+            true => self.accumulator + delta_time,
+            false => Duration::default(),
+        };
+        let mut steps = 0;
+        while step > Duration::default() && self.accumulator >= step {
+            if steps == MAX_FIXED_STEPS {
+                self.accumulator = Duration::default();
+                break;
+            }
+            self.dispatch(event_handler, Event::FixedUpdate { tick: self.tick, step });
+            Schedule::run(self, Stage::FixedUpdate, step);
+            self.accumulator -= step;
+            self.tick += 1;
+            steps += 1;
+        }
+        Schedule::run(self, Stage::PreUpdate, delta_time);
+        Schedule::run(self, Stage::Update, delta_time);
+        self.dispatch(event_handler, Event::UpdateEvent { delta_time });
+        Schedule::run(self, Stage::PostUpdate, delta_time);
+    }
+
+    // Headless engines stop after the frame they're in
     pub fn terminate(&mut self) {
+        self.terminated = true;
         self.window.close();
     }
 }
+
+impl Debug for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Engine")
+            .field("window", &self.window)
+            .field("input", &self.input)
+            .field("renderer", &self.renderer)
+            .field("audio", &self.audio)
+            .field("network", &self.network)
+            .field("time", &self.time)
+            .field("world", &"world")
+            .field("schedule", &self.schedule)
+            .field("states", &self.states.len())
+            .field("fixed_step", &self.fixed_step)
+            .field("frame_rate_limit", &self.frame_rate_limit)
+            .finish()
+    }
+}
+
+fn wait_until(deadline: Instant) {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining > SPIN_TIME {
+        thread::sleep(remaining - SPIN_TIME);
+    }
+    while Instant::now() < deadline {
+        hint::spin_loop();
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::TextureAtlas;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopMode {
+    // Stops on the last frame
+    Once,
+    Loop,
+    // Plays forwards and then backwards without repeating the frames at either end
+    PingPong,
+}
+
+// Frames of an atlas shown one after the other at a fixed rate
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpriteAnimation {
+    // Indices into the atlas
+    pub frames: Vec<usize>,
+    // Frames per second
+    pub fps: f32,
+    pub mode: LoopMode,
+}
+
+impl SpriteAnimation {
+    pub fn new(frames: Vec<usize>, fps: f32, mode: LoopMode) -> SpriteAnimation {
+        SpriteAnimation { frames, fps, mode }
+    }
+
+    // Consecutive frames, like a row of a grid atlas
+    pub fn from_range(frames: Range<usize>, fps: f32, mode: LoopMode) -> SpriteAnimation {
+        SpriteAnimation { frames: frames.collect(), fps, mode }
+    }
+
+    // Frames shown before the animation ends or starts over, twice the frames less the ends for ping pong
+    fn cycle_length(&self) -> u64 {
+        let frames = self.frames.len() as u64;
+        match self.mode {
+            LoopMode::PingPong => (frames * 2).saturating_sub(2).max(1),
+            _ => frames.max(1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpriteAnimationEvent {
+    // A looping animation started over, ping pong ones once they are back at the first frame
+    Looped,
+    // An animation played once reached its last frame and stopped
+    Finished,
+}
+
+// Named animations of one atlas, draw it with Renderer::draw_animated_sprite
+#[derive(Clone, Debug)]
+pub struct AnimatedSprite {
+    atlas: TextureAtlas,
+    animations: HashMap<String, SpriteAnimation>,
+    current: Option<String>,
+    // Seconds into the current animation
+    time: f32,
+    cycles: u64,
+    frame: usize,
+    playing: bool,
+}
+
+impl AnimatedSprite {
+    pub fn new(atlas: TextureAtlas) -> AnimatedSprite {
+        AnimatedSprite { atlas, animations: HashMap::new(), current: None, time: 0., cycles: 0, frame: 0, playing: false }
+    }
+
+    pub fn add(&mut self, name: &str, animation: SpriteAnimation) -> &mut Self {
+        self.animations.insert(name.to_owned(), animation);
+        self
+    }
+
+    // Starts the animation from its first frame, names that weren't added change nothing and return false
+    pub fn play(&mut self, name: &str) -> bool {
+        let animation = match self.animations.get(name) {
+            Some(animation) => animation,
+            None => return false,
+        };
+        self.frame = animation.frames.first().copied().unwrap_or(self.frame);
+        self.current = Some(name.to_owned());
+        self.time = 0.;
+        self.cycles = 0;
+        self.playing = true;
+        true
+    }
+
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    // Continues where the animation was paused, animations that finished stay on their last frame
+    pub fn resume(&mut self) {
+        if !self.finished() {
+            self.playing = self.current.is_some();
+        }
+    }
+
+    pub fn playing(&self) -> bool {
+        self.playing
+    }
+
+    // True once an animation played once has reached its last frame
+    pub fn finished(&self) -> bool {
+        match self.animation() {
+            Some(animation) => {
+                animation.mode == LoopMode::Once && (self.time * animation.fps) as u64 >= animation.cycle_length()
+            },
+            None => false,
+        }
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    fn animation(&self) -> Option<&SpriteAnimation> {
+        self.current.as_ref().and_then(|name| self.animations.get(name))
+    }
+
+    // Call it with the delta time of every update. When more than one cycle passes in an update only one event is
+    // returned
+    pub fn update(&mut self, delta_time: Duration) -> Option<SpriteAnimationEvent> {
+        if !self.playing {
+            return None;
+        }
+        // Borrows only the animations so the playback state can change alongside
+        let animation = match self.current.as_ref().and_then(|name| self.animations.get(name)) {
+            Some(animation) if !animation.frames.is_empty() && animation.fps > 0. => animation,
+            _ => return None,
+        };
+
+        self.time += delta_time.as_secs_f32();
+        let step = (self.time * animation.fps) as u64;
+        let length = animation.cycle_length();
+        let frames = animation.frames.len() as u64;
+        let index = match animation.mode {
+            LoopMode::Once => step.min(frames - 1),
+            LoopMode::Loop => step % length,
+            LoopMode::PingPong => match step % length {
+                i if i < frames => i,
+                i => length - i,
+            },
+        };
+        self.frame = animation.frames[index as usize];
+
+        let cycles = step / length;
+        if cycles == self.cycles {
+            return None;
+        }
+        self.cycles = cycles;
+        match animation.mode {
+            LoopMode::Once => {
+                self.playing = false;
+                Some(SpriteAnimationEvent::Finished)
+            },
+            _ => Some(SpriteAnimationEvent::Looped),
+        }
+    }
+
+    // The index into the atlas of the frame being shown
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn region(&self) -> Option<[f32; 4]> {
+        self.atlas.frame(self.frame)
+    }
+
+    pub fn atlas(&self) -> &TextureAtlas {
+        &self.atlas
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::fs::{self,};
+use std::path::Path;
+
+use fontdue::FontSettings;
+use image::RgbaImage;
+use nalgebra::Matrix3;
+use nalgebra::Point2;
+use nalgebra::Vector2;
+use wgpu::BindGroupLayout;
+use wgpu::Device;
+use wgpu::Queue;
+
+use crate::GearError;
+use crate::Loadable;
+use crate::Result;
+use crate::Texture;
+use crate::TextureFilter;
+use crate::TextureOptions;
+use crate::TextureWrap;
+
+// Glyphs are packed into pages this big, another page is started once one fills up
+const ATLAS_SIZE: u32 = 1024;
+// Keeps neighbouring glyphs from bleeding into each other when filtered
+const GLYPH_PADDING: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextStyle {
+    // The height of a line in pixels, glyphs are rasterized at it rounded to a whole pixel
+    pub size: f32,
+    pub color: [f32; 4],
+    pub align: TextAlign,
+}
+
+impl Default for TextStyle {
+    fn default() -> TextStyle {
+        TextStyle { size: 16., color: [1., 1., 1., 1.], align: TextAlign::Left }
+    }
+}
+
+#[derive(Debug)]
+struct Glyph {
+    advance: f32,
+    // From the pen on the baseline to the bottom left of the bitmap, y is up
+    offset: [f32; 2],
+    size: [f32; 2],
+    // The page and the region of it as [left, top, right, bottom], None for glyphs without pixels like spaces
+    bitmap: Option<(usize, [f32; 4])>,
+}
+
+#[derive(Debug)]
+struct AtlasPage {
+    texture: Texture,
+    // Glyphs are placed left to right in rows as tall as the tallest glyph in them
+    cursor: [u32; 2],
+    row_height: u32,
+}
+
+impl AtlasPage {
+    fn allocate(&mut self, size: [u32; 2]) -> Option<[u32; 2]> {
+        if self.cursor[0] + size[0] > ATLAS_SIZE {
+            self.cursor = [0, self.cursor[1] + self.row_height];
+            self.row_height = 0;
+        }
+        if self.cursor[1] + size[1] > ATLAS_SIZE {
+            return None;
+        }
+
+        let origin = self.cursor;
+        self.cursor[0] += size[0] + GLYPH_PADDING;
+        self.row_height = self.row_height.max(size[1] + GLYPH_PADDING);
+        Some(origin)
+    }
+}
+
+// A ttf or otf font along with the glyphs rasterized from it so far, draw it with Renderer::draw_text
+pub struct Font {
+    font: fontdue::Font,
+    glyphs: HashMap<(char, u32), Glyph>,
+    pages: Vec<AtlasPage>,
+}
+
+impl Font {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Font> {
+        let font =
+            fontdue::Font::from_bytes(bytes, FontSettings::default()).map_err(|e| GearError::FontError(e.to_string()))?;
+        Ok(Font { font, glyphs: HashMap::new(), pages: vec![] })
+    }
+
+    // The distance between the baselines of two lines
+    pub fn line_height(&self, size: f32) -> f32 {
+        let px = pixel_size(size) as f32;
+        self.font.horizontal_line_metrics(px).map_or(px, |metrics| metrics.new_line_size)
+    }
+
+    // The width of the widest line and the height of all of them
+    pub fn measure(&self, text: &str, size: f32) -> [f32; 2] {
+        let px = pixel_size(size) as f32;
+        let width = text.lines().map(|line| self.line_width(line, px)).fold(0., f32::max);
+        [width, text.lines().count() as f32 * self.line_height(size)]
+    }
+
+    fn line_width(&self, line: &str, px: f32) -> f32 {
+        let mut width = 0.;
+        let mut previous = None;
+        for c in line.chars() {
+            width += self.kern(previous, c, px) + self.font.metrics(c, px).advance_width;
+            previous = Some(c);
+        }
+        width
+    }
+
+    fn kern(&self, previous: Option<char>, c: char, px: f32) -> f32 {
+        previous.and_then(|previous| self.font.horizontal_kern(previous, c, px)).unwrap_or(0.)
+    }
+
+    // The texture, sprite transform and region of every visible glyph, rasterizing the ones not seen yet. The position
+    // is the top of the first line and where lines start, are centered or end depending on the alignment
+    pub(crate) fn layout(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        texture_layout: &BindGroupLayout,
+        text: &str,
+        position: Point2<f32>,
+        style: &TextStyle,
+    ) -> Vec<(Texture, Matrix3<f32>, [f32; 4])> {
+        let mut glyphs = vec![];
+        let size = pixel_size(style.size);
+        let px = size as f32;
+        let ascent = self.font.horizontal_line_metrics(px).map_or(px, |metrics| metrics.ascent);
+        let line_height = self.line_height(style.size);
+
+        // Pens are kept on whole pixels so glyphs map onto the screen one to one with the default camera
+        let mut baseline = (position.y - ascent).round();
+        for line in text.lines() {
+            let width = self.line_width(line, px);
+            let mut pen = match style.align {
+                TextAlign::Left => position.x,
+                TextAlign::Center => position.x - width / 2.,
+                TextAlign::Right => position.x - width,
+            };
+
+            let mut previous = None;
+            for c in line.chars() {
+                pen += self.kern(previous, c, px);
+                previous = Some(c);
+
+                self.rasterize(device, queue, texture_layout, c, size);
+                let glyph = &self.glyphs[&(c, size)];
+                if let Some((page, region)) = glyph.bitmap {
+                    let left = (pen + glyph.offset[0]).round();
+                    let bottom = baseline + glyph.offset[1];
+                    let center = Vector2::new(left + glyph.size[0] / 2., bottom + glyph.size[1] / 2.);
+                    let transform = Matrix3::new_translation(&center)
+                        * Matrix3::new_nonuniform_scaling(&Vector2::new(glyph.size[0], glyph.size[1]));
+                    glyphs.push((self.pages[page].texture.clone(), transform, region));
+                }
+                pen += glyph.advance;
+            }
+
+            baseline -= line_height.round();
+        }
+        glyphs
+    }
+
+    fn rasterize(&mut self, device: &Device, queue: &Queue, texture_layout: &BindGroupLayout, c: char, size: u32) {
+        if self.glyphs.contains_key(&(c, size)) {
+            return;
+        }
+
+        let (metrics, coverage) = self.font.rasterize(c, size as f32);
+        let bitmap_size = [metrics.width as u32, metrics.height as u32];
+        let bitmap = match bitmap_size[0] > 0 && bitmap_size[1] > 0 {
+            true => self.allocate(device, queue, texture_layout, bitmap_size).map(|(page, origin)| {
+                // White with the coverage as alpha, so the color comes from the tint
+                let mut pixels = Vec::with_capacity(coverage.len() * 4);
+                for alpha in &coverage {
+                    pixels.extend_from_slice(&[255, 255, 255, *alpha]);
+                }
+                self.pages[page].texture.write(queue, origin, bitmap_size, &pixels);
+
+                let atlas = ATLAS_SIZE as f32;
+                let region = [
+                    origin[0] as f32 / atlas,
+                    origin[1] as f32 / atlas,
+                    (origin[0] + bitmap_size[0]) as f32 / atlas,
+                    (origin[1] + bitmap_size[1]) as f32 / atlas,
+                ];
+                (page, region)
+            }),
+            false => None,
+        };
+
+        let glyph = Glyph {
+            advance: metrics.advance_width,
+            offset: [metrics.xmin as f32, metrics.ymin as f32],
+            size: [bitmap_size[0] as f32, bitmap_size[1] as f32],
+            bitmap,
+        };
+        self.glyphs.insert((c, size), glyph);
+    }
+
+    // Glyphs too big for an empty page are never drawn
+    fn allocate(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        texture_layout: &BindGroupLayout,
+        size: [u32; 2],
+    ) -> Option<(usize, [u32; 2])> {
+        if size[0] > ATLAS_SIZE || size[1] > ATLAS_SIZE {
+            return None;
+        }
+
+        if let Some(origin) = self.pages.last_mut().and_then(|page| page.allocate(size)) {
+            return Some((self.pages.len() - 1, origin));
+        }
+
+        let options =
+            TextureOptions { srgb: false, mipmaps: false, filter: TextureFilter::Linear, wrap: TextureWrap::Clamp };
+        let image = RgbaImage::new(ATLAS_SIZE, ATLAS_SIZE);
+        let texture = Texture::from_image(device, queue, texture_layout, image, &options);
+        let mut page = AtlasPage { texture, cursor: [0, 0], row_height: 0 };
+        let origin = page.allocate(size)?;
+        self.pages.push(page);
+        Some((self.pages.len() - 1, origin))
+    }
+}
+
+impl Loadable for Font {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Font::from_bytes(&fs::read(path)?)
+    }
+}
+
+fn pixel_size(size: f32) -> u32 {
+    size.round().max(1.) as u32
+}
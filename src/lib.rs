@@ -1,41 +1,225 @@
 // Copyright 2021 Chay Nabors.
 
+mod animation;
+mod atlas;
 mod audio;
+mod bounds;
 mod engine;
+mod flipbook;
+mod font;
 mod input;
 mod loadable;
 mod model;
 mod network;
 mod renderer;
 mod result;
+mod scene;
+mod schedule;
 mod sound;
+mod state;
+mod terrain;
 mod texture;
+mod time;
+mod video;
 mod window;
 
+pub use animation::Animation;
+pub use animation::AnimationChannel;
+pub use animation::AnimationPlayer;
+pub use animation::ChannelValues;
+pub use animation::Interpolation;
+pub use atlas::AtlasPacker;
+pub use atlas::TextureAtlas;
 pub use audio::Audio;
 pub use audio::AudioSource;
+pub use bounds::Aabb;
+pub use bounds::Frustum;
 pub use engine::Engine;
+pub use engine::EngineSettings;
+pub use flipbook::AnimatedSprite;
+pub use flipbook::LoopMode;
+pub use flipbook::SpriteAnimation;
+pub use flipbook::SpriteAnimationEvent;
+pub use font::Font;
+pub use font::TextAlign;
+pub use font::TextStyle;
+pub use hecs as ecs;
 pub use input::Input;
 pub use input::KeyCode;
 pub use input::KeyState;
 pub use loadable::Loadable;
+pub use model::Mesh;
 pub use model::Model;
+pub use model::SkinVertex;
 pub use nalgebra as math;
 pub use nalgebra_glm as math_ext;
+pub use network::apply_delta;
+pub use network::decode_message;
+pub use network::encode_delta;
+pub use network::normalize_address;
+#[cfg(not(target_arch = "wasm32"))]
+pub use network::Announcer;
+pub use network::Channel;
+pub use network::Client;
+pub use network::ConnectToken;
+pub use network::Delivery;
+pub use network::DisconnectReason;
+#[cfg(not(target_arch = "wasm32"))]
+pub use network::DiscoveredServer;
+#[cfg(not(target_arch = "wasm32"))]
+pub use network::Discovery;
+pub use network::InputBuffer;
+pub use network::IpFamily;
+pub use network::Lobby;
+pub use network::LobbyId;
+pub use network::LobbyInfo;
+pub use network::LobbyPlayer;
+pub use network::LobbyServer;
 pub use network::Network;
 pub use network::NetworkConfig;
+pub use network::NetworkSimulation;
+pub use network::NetworkStats;
 pub use network::Packet;
+pub use network::Peer;
+pub use network::PeerId;
+pub use network::Prediction;
+pub use network::Priority;
+pub use network::RateLimit;
+pub use network::RelayServer;
+pub use network::Reliability;
+pub use network::Rendezvous;
+pub use network::RendezvousServer;
+pub use network::ReplicaId;
+pub use network::Replicas;
+pub use network::Replicator;
+pub use network::Rpc;
+pub use network::Server;
+#[cfg(not(target_arch = "wasm32"))]
+pub use network::ServerInfo;
+#[cfg(not(target_arch = "wasm32"))]
+pub use network::SignalingServer;
+pub use network::SnapshotReceiver;
+pub use network::SnapshotSender;
 pub use network::Socket;
+pub use network::SocketConfig;
+pub use network::SocketId;
+pub use network::TokenKey;
+pub use network::Transport;
+pub use renderer::AdapterInfo;
+pub use renderer::AdapterPreference;
+pub use renderer::AdapterType;
+pub use renderer::AttachmentDescriptor;
+pub use renderer::AttachmentFormat;
+pub use renderer::AttachmentId;
+pub use renderer::AttributeFormat;
+pub use renderer::Billboard;
+pub use renderer::Bloom;
+pub use renderer::Camera2D;
+pub use renderer::Camera3D;
+pub use renderer::ColorGrading;
+pub use renderer::ColorLut;
+pub use renderer::ColorTarget;
+pub use renderer::ComputeBinding;
+pub use renderer::ComputeBindingKind;
+pub use renderer::ComputeShaderId;
+pub use renderer::Cubemap;
+pub use renderer::Curve;
+pub use renderer::CurveValue;
+pub use renderer::DebugView;
+pub use renderer::Decal;
+pub use renderer::DecalPool;
+pub use renderer::DepthOfField;
+pub use renderer::DirectionalLight;
+pub use renderer::Fog;
+pub use renderer::FogFalloff;
+pub use renderer::FrameCapture;
+pub use renderer::FrameStats;
+pub use renderer::GpuMaterial;
+pub use renderer::GpuMesh;
+pub use renderer::GpuScene;
+pub use renderer::GpuTerrain;
+pub use renderer::GpuTiming;
+pub use renderer::GraphicsBackend;
+pub use renderer::IndirectBatch;
+pub use renderer::InstanceData;
+pub use renderer::Light;
+pub use renderer::Light2D;
+pub use renderer::LightCone;
+pub use renderer::Material;
+pub use renderer::MeshLod;
+pub use renderer::NineSlice;
+pub use renderer::ParticleEmitter;
+pub use renderer::ParticleSettings;
+pub use renderer::PassDescriptor;
+pub use renderer::PassId;
+pub use renderer::PassKind;
+pub use renderer::PointLight;
+pub use renderer::PostEffects;
+pub use renderer::PresentMode;
+pub use renderer::Projection;
+pub use renderer::RenderGraph;
+pub use renderer::RenderLayer;
+pub use renderer::RenderTarget;
+pub use renderer::RenderTargetFormat;
 pub use renderer::Renderer;
+pub use renderer::RendererSettings;
+pub use renderer::ShaderId;
+pub use renderer::ShaderKind;
+pub use renderer::Shadow;
+pub use renderer::SpotLight;
+pub use renderer::Ssao;
+pub use renderer::Ssr;
+pub use renderer::StencilMode;
+pub use renderer::StorageBuffer;
+pub use renderer::StorageTexture;
+pub use renderer::TerrainLayer;
+pub use renderer::TerrainMaterial;
+pub use renderer::TileLayer;
+pub use renderer::Tonemapper;
+pub use renderer::VertexLayout;
+pub use renderer::Viewport;
+pub use renderer::Water;
+pub use result::GearError;
 pub use result::Result;
+pub use scene::Scene;
+pub use scene::SceneImage;
+pub use scene::SceneMaterial;
+pub use scene::SceneMesh;
+pub use scene::SceneNode;
+pub use scene::ScenePrimitive;
+pub use scene::SceneSkin;
+pub use schedule::Schedule;
+pub use schedule::Stage;
+pub use schedule::SystemId;
 pub use sound::Sound;
+pub use state::GameState;
+pub use terrain::Heightmap;
+pub use terrain::Terrain;
 pub use texture::Texture;
+pub use texture::TextureArray;
+pub use texture::TextureFilter;
+pub use texture::TextureOptions;
+pub use texture::TextureWrap;
+pub use time::Time;
+pub use video::Video;
+pub use video::VideoCodec;
+pub use video::VideoDecoder;
+pub use video::VideoFrame;
+pub use video::VideoPlayer;
 pub use window::Window;
 
 pub mod event {
     pub use crate::engine::Event;
     pub use crate::input::InputEvent;
     pub use crate::input::MouseEvent;
+    pub use crate::network::ClientEvent;
+    pub use crate::network::ConnectFailure;
+    pub use crate::network::LobbyEvent;
     pub use crate::network::NetworkEvent;
+    pub use crate::network::NetworkEventStream;
+    pub use crate::network::RendezvousEvent;
+    pub use crate::network::ReplicationEvent;
+    pub use crate::network::ServerEvent;
+    pub use crate::network::TimeoutReason;
     pub use crate::window::WindowEvent;
 }
@@ -1,12 +1,17 @@
 // Copyright 2021 Chay Nabors.
 
+use std::f32::consts::PI;
 use std::path::Path;
 
 use bytemuck::Pod;
 use bytemuck::Zeroable;
+use nalgebra::Point3;
+use nalgebra::Vector2;
+use nalgebra::Vector3;
 use tobj::LoadOptions;
 
 use crate::result::Result;
+use crate::Aabb;
 use crate::Loadable;
 
 #[repr(C)]
@@ -17,11 +22,175 @@ pub struct Vertex {
     pub normal: [f32; 3],
 }
 
+// Up to four joints of a skin moving the vertex and how much each of them does
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct SkinVertex {
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+}
+
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
 }
 
+// The generated meshes are centered on the origin with y up, and wrap their texture once around or over each face
+impl Mesh {
+    // None without any vertices
+    pub fn bounds(&self) -> Option<Aabb> {
+        Aabb::from_points(self.vertices.iter().map(|vertex| Point3::from(vertex.position)))
+    }
+
+    // Each face is split into a grid of subdivisions by subdivisions quads
+    pub fn cube(size: Vector3<f32>, subdivisions: u32) -> Mesh {
+        let half = size / 2.;
+        let (x, y, z) = (Vector3::x() * size.x, Vector3::y() * size.y, Vector3::z() * size.z);
+        // The top left corner of each face as it is textured, then its right and down edges
+        let faces = [
+            (Vector3::new(half.x, half.y, half.z), -z, -y),
+            (Vector3::new(-half.x, half.y, -half.z), z, -y),
+            (Vector3::new(-half.x, half.y, -half.z), x, z),
+            (Vector3::new(-half.x, -half.y, half.z), x, -z),
+            (Vector3::new(-half.x, half.y, half.z), x, -y),
+            (Vector3::new(half.x, half.y, -half.z), -x, -y),
+        ];
+
+        let mut mesh = Mesh { vertices: vec![], indices: vec![] };
+        for &(corner, right, down) in &faces {
+            mesh.append(Mesh::grid(corner, right, down, subdivisions));
+        }
+        mesh
+    }
+
+    // Facing up, with the texture's top toward -z
+    pub fn plane(size: Vector2<f32>, subdivisions: u32) -> Mesh {
+        let corner = Vector3::new(-size.x / 2., 0., -size.y / 2.);
+        Mesh::grid(corner, Vector3::x() * size.x, Vector3::z() * size.y, subdivisions)
+    }
+
+    // Sectors go around the y axis and stacks from pole to pole
+    pub fn sphere(radius: f32, sectors: u32, stacks: u32) -> Mesh {
+        let stacks = stacks.max(2);
+        let profile = (0..=stacks).map(|stack| {
+            let angle = PI * stack as f32 / stacks as f32;
+            let normal = Vector2::new(angle.sin(), angle.cos());
+            (normal * radius, normal)
+        });
+        Mesh::lathe(&profile.collect::<Vec<_>>(), sectors)
+    }
+
+    // Capped at both ends, the stacks split the side along its height
+    pub fn cylinder(radius: f32, height: f32, sectors: u32, stacks: u32) -> Mesh {
+        let stacks = stacks.max(1);
+        let profile = (0..=stacks).map(|stack| {
+            let y = height / 2. - height * stack as f32 / stacks as f32;
+            (Vector2::new(radius, y), Vector2::new(1., 0.))
+        });
+        let mut mesh = Mesh::lathe(&profile.collect::<Vec<_>>(), sectors);
+        mesh.append(Mesh::disk(radius, height / 2., sectors, true));
+        mesh.append(Mesh::disk(radius, -height / 2., sectors, false));
+        mesh
+    }
+
+    // The height is from the top of one cap to the bottom of the other, so it is never less than the diameter. The
+    // stacks split each of the hemispheres
+    pub fn capsule(radius: f32, height: f32, sectors: u32, stacks: u32) -> Mesh {
+        let stacks = stacks.max(1);
+        let half = (height / 2. - radius).max(0.);
+        let hemisphere = |stack: u32, y: f32| {
+            let angle = PI / 2. * stack as f32 / stacks as f32;
+            let normal = Vector2::new(angle.sin(), angle.cos());
+            (normal * radius + Vector2::new(0., y), normal)
+        };
+        let top = (0..=stacks).map(|stack| hemisphere(stack, half));
+        let bottom = (stacks..=stacks * 2).map(|stack| hemisphere(stack, -half));
+        Mesh::lathe(&top.chain(bottom).collect::<Vec<_>>(), sectors)
+    }
+
+    // Offsets the other mesh's indices to follow this one's vertices
+    fn append(&mut self, other: Mesh) {
+        let base = self.vertices.len() as u32;
+        self.vertices.extend(other.vertices);
+        self.indices.extend(other.indices.into_iter().map(|index| base + index));
+    }
+
+    // A flat grid of quads, it faces the way of down crossed with right
+    fn grid(corner: Vector3<f32>, right: Vector3<f32>, down: Vector3<f32>, subdivisions: u32) -> Mesh {
+        let cells = subdivisions.max(1);
+        let normal = down.cross(&right).normalize();
+        let mut vertices = vec![];
+        for row in 0..=cells {
+            for column in 0..=cells {
+                let (u, v) = (column as f32 / cells as f32, row as f32 / cells as f32);
+                let position = corner + right * u + down * v;
+                vertices.push(Vertex { position: position.into(), tex_coords: [u, v], normal: normal.into() });
+            }
+        }
+        Mesh { vertices, indices: grid_indices(cells, cells) }
+    }
+
+    // Spins a profile of points and normals around the y axis, given in distance from the axis and height from the top
+    // down. The texture runs along the profile by its length
+    fn lathe(profile: &[(Vector2<f32>, Vector2<f32>)], sectors: u32) -> Mesh {
+        let sectors = sectors.max(3);
+        let mut lengths = vec![0.];
+        for pair in profile.windows(2) {
+            let length = lengths[lengths.len() - 1] + (pair[1].0 - pair[0].0).norm();
+            lengths.push(length);
+        }
+        let total = lengths[lengths.len() - 1].max(f32::EPSILON);
+
+        let mut vertices = vec![];
+        for ((point, normal), length) in profile.iter().zip(lengths) {
+            for sector in 0..=sectors {
+                let u = sector as f32 / sectors as f32;
+                let (sin, cos) = (u * PI * 2.).sin_cos();
+                vertices.push(Vertex {
+                    position: [point.x * sin, point.y, point.x * cos],
+                    tex_coords: [u, length / total],
+                    normal: [normal.x * sin, normal.y, normal.x * cos],
+                });
+            }
+        }
+        Mesh { vertices, indices: grid_indices(sectors, profile.len() as u32 - 1) }
+    }
+
+    // A flat circle at a height facing up or down, textured by its position across
+    fn disk(radius: f32, y: f32, sectors: u32, up: bool) -> Mesh {
+        let sectors = sectors.max(3);
+        let normal = [0., if up { 1. } else { -1. }, 0.];
+        let mut vertices = vec![Vertex { position: [0., y, 0.], tex_coords: [0.5, 0.5], normal }];
+        for sector in 0..=sectors {
+            let (sin, cos) = (sector as f32 / sectors as f32 * PI * 2.).sin_cos();
+            let tex_coords = [0.5 + sin / 2., if up { 0.5 + cos / 2. } else { 0.5 - cos / 2. }];
+            vertices.push(Vertex { position: [radius * sin, y, radius * cos], tex_coords, normal });
+        }
+
+        let mut indices = vec![];
+        for sector in 1..=sectors {
+            match up {
+                true => indices.extend_from_slice(&[0, sector, sector + 1]),
+                false => indices.extend_from_slice(&[0, sector + 1, sector]),
+            }
+        }
+        Mesh { vertices, indices }
+    }
+}
+
+// Two counter clockwise triangles for each quad of a grid of vertices laid out in rows
+fn grid_indices(columns: u32, rows: u32) -> Vec<u32> {
+    let mut indices = vec![];
+    for row in 0..rows {
+        for column in 0..columns {
+            let a = row * (columns + 1) + column;
+            let (b, c) = (a + columns + 1, a + 1);
+            indices.extend_from_slice(&[a, b, c, c, b, b + 1]);
+        }
+    }
+    indices
+}
+
 #[derive(Default)]
 pub struct Model {
     pub meshes: Vec<Mesh>,
@@ -1,56 +1,432 @@
 // Copyright 2021 Chay Nabors.
 
+mod address;
+mod channel;
+mod client;
+mod compression;
+mod config;
+mod delivery;
+#[cfg(not(target_arch = "wasm32"))]
+mod discovery;
+mod encryption;
+mod lobby;
+mod message;
+mod mtu;
+mod prediction;
+mod protocol;
+mod rate_limit;
+mod recording;
+mod relay;
+mod rendezvous;
+mod replication;
+mod rpc;
+mod server;
+mod signaling;
+mod simulation;
+mod snapshot;
+mod stats;
+mod token;
+mod transport;
+mod worker;
+
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::{self,};
+use std::io::ErrorKind;
+use std::io::{self,};
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::thread::sleep;
+use std::task::Context;
+use std::task::Poll;
 use std::thread::JoinHandle;
 use std::thread::{self,};
 use std::time::Duration;
-use std::time::Instant;
 
 use crossbeam::channel::Receiver;
+use crossbeam::channel::RecvTimeoutError;
 use crossbeam::channel::Sender;
 use crossbeam::channel::TryRecvError;
-pub use laminar::Config as NetworkConfig;
+use crossbeam::channel::TrySendError;
+use crossbeam::channel::{self,};
+use futures_core::Stream;
+use instant::Instant;
 pub use laminar::Packet;
-use laminar::SocketEvent;
+use log::error;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::sync::mpsc::{self,};
+use tokio::sync::Notify;
 
+pub use self::address::normalize_address;
+pub use self::address::IpFamily;
+pub use self::channel::Channel;
+pub use self::channel::Reliability;
+pub use self::client::Client;
+pub use self::client::ClientEvent;
+pub use self::config::NetworkConfig;
+pub use self::config::SocketConfig;
+pub use self::config::Transport;
+pub use self::delivery::Delivery;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::discovery::Announcer;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::discovery::DiscoveredServer;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::discovery::Discovery;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::discovery::ServerInfo;
+pub use self::encryption::PublicKey;
+pub use self::encryption::StaticKeypair;
+pub use self::lobby::Lobby;
+pub use self::lobby::LobbyEvent;
+pub use self::lobby::LobbyId;
+pub use self::lobby::LobbyInfo;
+pub use self::lobby::LobbyPlayer;
+pub use self::lobby::LobbyServer;
+pub use self::message::decode_message;
+pub use self::prediction::InputBuffer;
+pub use self::prediction::Prediction;
+pub use self::rate_limit::Priority;
+pub use self::rate_limit::RateLimit;
+use self::recording::Recorder;
+use self::recording::Replay;
+pub use self::relay::RelayServer;
+pub use self::rendezvous::Rendezvous;
+pub use self::rendezvous::RendezvousEvent;
+pub use self::rendezvous::RendezvousServer;
+pub use self::replication::ReplicaId;
+pub use self::replication::Replicas;
+pub use self::replication::ReplicationEvent;
+pub use self::replication::Replicator;
+pub use self::rpc::Rpc;
+pub use self::server::Peer;
+pub use self::server::PeerId;
+pub use self::server::Server;
+pub use self::server::ServerEvent;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::signaling::SignalingServer;
+pub use self::simulation::NetworkSimulation;
+pub use self::snapshot::apply_delta;
+pub use self::snapshot::encode_delta;
+pub use self::snapshot::SnapshotReceiver;
+pub use self::snapshot::SnapshotSender;
+pub use self::stats::NetworkStats;
+pub use self::token::ConnectToken;
+pub use self::token::TokenKey;
+use self::transport::LoopbackTransport;
+use self::transport::TransportBackend;
+use self::worker::Command;
+use self::worker::EventSender;
+use self::worker::Shared;
+use self::worker::SocketWorker;
+use crate::GearError;
 use crate::Result;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectFailure {
+    Denied,
+    TimedOut,
+    HandshakeFailed,
+}
+
+// Sent along with a disconnect so the remote can tell a kick from a peer leaving, gear attaches no meaning to the code
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisconnectReason {
+    pub code: u16,
+    pub message: String,
+}
+
+impl DisconnectReason {
+    pub fn new(code: u16, message: &str) -> DisconnectReason {
+        DisconnectReason { code, message: message.to_owned() }
+    }
+}
+
+// Handshakes that time out are reported as ConnectFailed instead
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeoutReason {
+    // Nothing was heard from the peer for the idle timeout
+    Idle,
+    // Packet loss stayed above NetworkConfig::max_packet_loss for a whole measurement window
+    PacketLoss,
+}
+
 #[derive(Clone, Debug)]
 pub enum NetworkEvent {
     Message(Packet),
     Connect(SocketAddr),
-    Timeout(SocketAddr),
-    Disconnect(SocketAddr),
+    ConnectFailed(SocketAddr, ConnectFailure),
+    Timeout(SocketAddr, TimeoutReason),
+    // Raised when messages to the peer start queueing behind its rate limit and again once the queue drains
+    Congested(SocketAddr, bool),
+    // The transport failed to send to the peer, the connection itself is left alone
+    Error(SocketAddr, Arc<GearError>),
+    Disconnect(SocketAddr, Option<DisconnectReason>),
+    // The peer's NAT mapping was rebound mid session, from here on it is only known by the second address
+    AddressChanged(SocketAddr, SocketAddr),
 }
 
+// Tells apart the sockets bound on the same network, ids are never reused
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SocketId(u32);
+
 #[derive(Debug)]
 pub struct Socket {
-    sender: Sender<Packet>,
+    id: SocketId,
+    sender: Sender<Command>,
     stop_signal: Arc<AtomicBool>,
+    wake: Option<Arc<Notify>>,
+    next_stream: AtomicU8,
+    shared: Arc<Shared>,
+    preferred_family: Option<IpFamily>,
+    manual_poll: bool,
 }
 
 impl Socket {
-    pub fn send(&self, packet: Packet) -> &Self {
-        self.sender.send(packet).unwrap();
+    pub fn id(&self) -> SocketId {
+        self.id
+    }
+
+    // Blocks while the command queue is full
+    fn command(&self, command: Command) -> Result<()> {
+        self.sender.send(command).map_err(|_| GearError::SocketClosed)?;
+        self.wake();
+        Ok(())
+    }
+
+    fn try_command(&self, command: Command) -> Result<()> {
+        match self.sender.try_send(command) {
+            Ok(()) => {
+                self.wake();
+                Ok(())
+            },
+            Err(TrySendError::Full(_)) => Err(GearError::SocketFull),
+            Err(TrySendError::Disconnected(_)) => Err(GearError::SocketClosed),
+        }
+    }
+
+    // Control commands sent to a closed socket are dropped, there is nothing left for them to control
+    fn control(&self, command: Command) -> &Self {
+        let _ = self.command(command);
         self
     }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            wake.notify_one();
+        }
+    }
+
+    // Messages are only delivered to and accepted from peers that completed the handshake
+    pub fn send(&self, packet: Packet) -> Result<&Self> {
+        self.send_with_priority(packet, Priority::default())
+    }
+
+    pub fn send_with_priority(&self, packet: Packet, priority: Priority) -> Result<&Self> {
+        self.command(Command::Send(packet, priority))?;
+        Ok(self)
+    }
+
+    pub fn try_send(&self, packet: Packet) -> Result<&Self> {
+        self.try_command(Command::Send(packet, Priority::default()))?;
+        Ok(self)
+    }
+
+    pub fn broadcast(&self, payload: Vec<u8>, delivery: Delivery) -> Result<&Self> {
+        self.command(Command::Broadcast { payload, delivery, except: None })?;
+        Ok(self)
+    }
+
+    pub fn broadcast_except(&self, address: SocketAddr, payload: Vec<u8>, delivery: Delivery) -> Result<&Self> {
+        self.command(Command::Broadcast { payload, delivery, except: Some(address) })?;
+        Ok(self)
+    }
+
+    pub fn broadcast_message<T: Serialize>(&self, message: &T, delivery: Delivery) -> Result<&Self> {
+        self.broadcast(bincode::serialize(message)?, delivery)
+    }
+
+    pub fn create_channel(&self, reliability: Reliability) -> Channel {
+        // Stream 255 is laminar's default stream, wrap before reaching it
+        let stream = self
+            .next_stream
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |stream| Some((stream + 1) % u8::MAX))
+            .unwrap();
+        Channel::new(reliability, stream)
+    }
+
+    pub fn send_on(&self, channel: Channel, address: SocketAddr, payload: Vec<u8>) -> Result<&Self> {
+        self.send(channel.packet(address, payload))
+    }
+
+    pub fn send_message_on<T: Serialize>(&self, channel: Channel, address: SocketAddr, message: &T) -> Result<&Self> {
+        self.send_message(address, message, channel.delivery())
+    }
+
+    pub fn send_message<T: Serialize>(&self, address: SocketAddr, message: &T, delivery: Delivery) -> Result<&Self> {
+        self.send(message::encode(address, message, delivery)?)
+    }
+
+    pub fn connect(&self, address: SocketAddr) -> &Self {
+        self.control(Command::Connect { address, token: None, relay: None })
+    }
+
+    // Required by servers configured with a token key
+    pub fn connect_with_token(&self, address: SocketAddr, token: &ConnectToken) -> Result<&Self> {
+        self.command(Command::Connect { address, token: Some(token.clone()), relay: None })?;
+        Ok(self)
+    }
+
+    // For peers that can't be reached directly, the peer must have called use_relay with the same relay
+    pub fn connect_via(&self, address: SocketAddr, relay: SocketAddr) -> Result<&Self> {
+        self.command(Command::Connect { address, token: None, relay: Some(relay) })?;
+        Ok(self)
+    }
+
+    // Keeps this socket registered with the relay so peers can reach it through there
+    pub fn use_relay(&self, relay: SocketAddr) -> &Self {
+        self.control(Command::UseRelay(relay))
+    }
+
+    // The token a peer presented when it connected, only servers that validate tokens keep them
+    pub fn connect_token(&self, address: SocketAddr) -> Option<ConnectToken> {
+        self.shared.tokens.lock().unwrap().get(&address).cloned()
+    }
+
+    // Resolves with the configured family preference and connects to the first address
+    pub fn connect_to<A: ToSocketAddrs>(&self, addresses: A) -> Result<&Self> {
+        let address = address::resolve(addresses, self.preferred_family)?
+            .first()
+            .copied()
+            .ok_or_else(|| io::Error::from(ErrorKind::AddrNotAvailable))?;
+        Ok(self.connect(address))
+    }
+
+    pub fn disconnect(&self, address: SocketAddr) -> &Self {
+        self.control(Command::Disconnect(address, None))
+    }
+
+    // The local disconnect event carries the same reason the remote receives
+    pub fn disconnect_peer(&self, address: SocketAddr, reason: DisconnectReason) -> &Self {
+        self.control(Command::Disconnect(address, Some(reason)))
+    }
+
+    pub fn stats(&self, address: SocketAddr) -> Option<NetworkStats> {
+        self.shared.stats.lock().unwrap().get(&address).copied()
+    }
+
+    // Time since the socket was bound, this is the clock peers estimate in estimated_remote_time
+    pub fn local_time(&self) -> Duration {
+        self.shared.epoch.elapsed()
+    }
+
+    // The peer's local_time right now, estimated from pings and smoothed, None until the first pong
+    pub fn estimated_remote_time(&self, address: SocketAddr) -> Option<Duration> {
+        let offset = *self.shared.clock_offsets.lock().unwrap().get(&address)?;
+        Some(Duration::from_secs_f64((self.local_time().as_secs_f64() + offset).max(0.)))
+    }
+
+    // What other browsers connect to a WebRTC socket with, None until the signaling server has answered and for every
+    // other transport
+    pub fn signaled_address(&self) -> Option<SocketAddr> {
+        *self.shared.signaled_address.lock().unwrap()
+    }
+
+    pub fn set_simulation(&self, simulation: Option<NetworkSimulation>) -> &Self {
+        self.control(Command::SetSimulation(simulation))
+    }
+
+    // Replaces the configured rate limit for a single connected peer
+    pub fn set_rate_limit(&self, address: SocketAddr, limit: Option<RateLimit>) -> &Self {
+        self.control(Command::SetRateLimit(address, limit))
+    }
+
+    pub fn set_connection_filter<F: 'static + FnMut(SocketAddr) -> bool + Send>(&self, filter: F) -> &Self {
+        self.control(Command::SetConnectionFilter(Box::new(filter)))
+    }
+
+    // Incoming connections are denied while this many peers are connected, the filter isn't consulted for them
+    pub fn set_max_peers(&self, max_peers: Option<usize>) -> &Self {
+        self.control(Command::SetMaxPeers(max_peers))
+    }
+}
+
+impl Socket {
+    // Unlike dropping, flushes whatever is still queued and tells every peer before the worker stops
+    // Manually polled sockets close on the next manual_poll, so this can't wait for them
+    pub fn close(mut self, timeout: Duration) -> Result<()> {
+        let (done, finished) = channel::bounded(1);
+        self.command(Command::Close(done))?;
+        if self.manual_poll {
+            // The worker stops itself once closed, dropping must not pull it out of manual_poll before that
+            self.stop_signal = Arc::new(AtomicBool::new(false));
+            return Ok(());
+        }
+
+        match finished.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::from(ErrorKind::TimedOut).into()),
+            Err(RecvTimeoutError::Disconnected) => Err(GearError::SocketClosed),
+        }
+    }
 }
 
 impl Drop for Socket {
     fn drop(&mut self) {
         self.stop_signal.swap(true, Ordering::Relaxed);
+        self.wake();
+    }
+}
+
+#[derive(Debug)]
+pub struct NetworkEventStream {
+    receiver: UnboundedReceiver<NetworkEvent>,
+}
+
+impl Stream for NetworkEventStream {
+    type Item = NetworkEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<NetworkEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+enum Driver {
+    Thread(JoinHandle<()>),
+    Manual { worker: SocketWorker, stop: Arc<AtomicBool> },
+}
+
+impl Debug for Driver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Driver::Thread(thread) => f.debug_tuple("Thread").field(thread).finish(),
+            Driver::Manual { stop, .. } => f.debug_struct("Manual").field("stop", stop).finish(),
+        }
     }
 }
 
+#[derive(Debug)]
+struct BoundSocket {
+    id: SocketId,
+    driver: Driver,
+    receiver: Receiver<NetworkEvent>,
+}
+
 #[derive(Default, Debug)]
 pub struct Network {
-    socket_thread: Option<JoinHandle<()>>,
-    receiver: Option<Receiver<SocketEvent>>,
+    sockets: Vec<BoundSocket>,
+    next_id: AtomicU32,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
 }
 
 impl Network {
@@ -58,54 +434,170 @@ impl Network {
         Network::default()
     }
 
-    pub(crate) fn get_event(&mut self) -> Option<NetworkEvent> {
-        if let Some(receiver) = &self.receiver {
-            loop {
-                match receiver.try_recv() {
-                    Ok(message) => {
-                        return Some(match message {
-                            SocketEvent::Packet(packet) => NetworkEvent::Message(packet),
-                            SocketEvent::Connect(address) => NetworkEvent::Connect(address),
-                            SocketEvent::Timeout(address) => NetworkEvent::Timeout(address),
-                            SocketEvent::Disconnect(address) => NetworkEvent::Disconnect(address),
-                        })
-                    },
-                    Err(e) => match e {
-                        TryRecvError::Empty => break,
-                        TryRecvError::Disconnected => {
-                            self.socket_thread.take().unwrap().join().unwrap();
-                            self.receiver.take();
-                            break;
-                        },
-                    },
-                }
+    fn next_id(&self) -> SocketId {
+        SocketId(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn sockets(&self) -> impl Iterator<Item = SocketId> + '_ {
+        self.sockets.iter().map(|socket| socket.id)
+    }
+
+    pub(crate) fn get_event(&mut self) -> Option<(SocketId, NetworkEvent)> {
+        if let Some(replay) = &mut self.replay {
+            let event = replay.next(Instant::now());
+            if replay.is_finished() {
+                self.replay = None;
+            }
+            if event.is_some() {
+                return event;
+            }
+        }
+
+        let (socket, event) = self.next_event()?;
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(socket, &event) {
+                error!("Failed to record network event: {:?}", e);
+                self.recorder = None;
+            }
+        }
+
+        Some((socket, event))
+    }
+
+    // Everything get_event hands out from now on is written to the file along with when it happened
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(Recorder::create(path)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<()> {
+        match self.recorder.take() {
+            Some(mut recorder) => recorder.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // Recorded events keep the socket ids they were recorded with and are mixed in with live traffic
+    pub fn replay<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.replay = Some(Replay::load(path)?);
+        Ok(())
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    fn next_event(&mut self) -> Option<(SocketId, NetworkEvent)> {
+        let mut i = 0;
+        while i < self.sockets.len() {
+            match self.sockets[i].receiver.try_recv() {
+                Ok(event) => return Some((self.sockets[i].id, event)),
+                Err(TryRecvError::Empty) => i += 1,
+                Err(TryRecvError::Disconnected) => {
+                    if let Driver::Thread(thread) = self.sockets.remove(i).driver {
+                        thread.join().unwrap();
+                    }
+                },
             }
         }
 
         None
     }
 
+    // Steps every socket bound with manual polling, sockets whose handle was dropped are closed
+    // Closed sockets stay around until their last events have been handed out
+    pub fn manual_poll(&mut self) {
+        self.sockets.retain(|socket| match &socket.driver {
+            Driver::Manual { worker, stop } => {
+                !stop.load(Ordering::Relaxed) && !(worker.is_finished() && socket.receiver.is_empty())
+            },
+            Driver::Thread(_) => true,
+        });
+
+        for socket in &mut self.sockets {
+            if let Driver::Manual { worker, .. } = &mut socket.driver {
+                worker.poll();
+            }
+        }
+    }
+
     pub fn bind<A: ToSocketAddrs>(&mut self, addresses: A) -> Result<Socket> {
         self.bind_with_config(addresses, NetworkConfig::default())
     }
 
+    pub fn bind_tcp<A: ToSocketAddrs>(&mut self, addresses: A) -> Result<Socket> {
+        self.bind_with_config(addresses, NetworkConfig { transport: Transport::Tcp, ..Default::default() })
+    }
+
     pub fn bind_with_config<A: ToSocketAddrs>(&mut self, addresses: A, config: NetworkConfig) -> Result<Socket> {
-        let mut socket = laminar::Socket::bind_with_config(addresses, config)?;
-        let sender = socket.get_packet_sender();
-        let receiver = socket.get_event_receiver();
+        let transport = SocketWorker::transport(addresses, &config)?;
+        Ok(self.start(transport, config))
+    }
+
+    // Both sockets run the normal connection path over an in process pipe, the second one connects to the first
+    pub fn bind_loopback(&mut self) -> (Socket, Socket) {
+        self.bind_loopback_with_config(NetworkConfig::default())
+    }
+
+    pub fn bind_loopback_with_config(&mut self, config: NetworkConfig) -> (Socket, Socket) {
+        let (host, client) = LoopbackTransport::pair();
+        let host_address = host.address();
+        let host = self.start(Box::new(host), config.clone());
+        let client = self.start(Box::new(client), config);
+        client.connect(host_address);
+        (host, client)
+    }
+
+    fn start(&mut self, transport: Box<dyn TransportBackend>, mut config: NetworkConfig) -> Socket {
+        // Browsers have no threads to run a worker on, the engine steps it every frame through manual_poll instead
+        if cfg!(target_arch = "wasm32") {
+            config.manual_poll = true;
+        }
+
+        let (events, receiver) = channel::unbounded();
+        let manual_poll = config.manual_poll;
+        let preferred_family = config.preferred_family;
+        let (worker, sender, shared) = SocketWorker::new(transport, config, EventSender::Blocking(events));
         let stop_signal = Arc::new(AtomicBool::new(false));
         let stop = stop_signal.clone();
 
-        let socket_thread = thread::spawn(move || {
-            while !stop.load(Ordering::Relaxed) {
-                socket.manual_poll(Instant::now());
-                sleep(Duration::from_millis(1));
-            }
-        });
+        let driver = if manual_poll {
+            Driver::Manual { worker, stop }
+        } else {
+            Driver::Thread(thread::spawn(move || worker.run(stop)))
+        };
+        let id = self.next_id();
+        self.sockets.push(BoundSocket { id, driver, receiver });
+
+        let next_stream = AtomicU8::new(0);
+        Socket { id, sender, stop_signal, wake: None, next_stream, shared, preferred_family, manual_poll }
+    }
+
+    // Must be called from within a tokio runtime, the socket is driven by a task on it and manual polling is ignored
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn bind_async<A: ToSocketAddrs>(&self, addresses: A) -> Result<(Socket, NetworkEventStream)> {
+        self.bind_async_with_config(addresses, NetworkConfig::default())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn bind_async_with_config<A: ToSocketAddrs>(
+        &self,
+        addresses: A,
+        config: NetworkConfig,
+    ) -> Result<(Socket, NetworkEventStream)> {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let preferred_family = config.preferred_family;
+        let (worker, sender, shared) = SocketWorker::bind(addresses, config, EventSender::Async(events))?;
+        let stop_signal = Arc::new(AtomicBool::new(false));
+        let wake = Arc::new(Notify::new());
 
-        self.socket_thread = Some(socket_thread);
-        self.receiver = Some(receiver);
+        tokio::spawn(worker.run_async(stop_signal.clone(), wake.clone()));
 
-        Ok(Socket { sender, stop_signal })
+        let id = self.next_id();
+        let next_stream = AtomicU8::new(0);
+        let wake = Some(wake);
+        let socket = Socket { id, sender, stop_signal, wake, next_stream, shared, preferred_family, manual_poll: false };
+        Ok((socket, NetworkEventStream { receiver }))
     }
 }
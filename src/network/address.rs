@@ -0,0 +1,42 @@
+// Copyright 2021 Chay Nabors.
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+
+use crate::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn of(address: SocketAddr) -> IpFamily {
+        match address {
+            SocketAddr::V4(_) => IpFamily::V4,
+            SocketAddr::V6(_) => IpFamily::V6,
+        }
+    }
+}
+
+// IPv4 peers reach a dual stack socket as mapped IPv6 addresses, this turns them back into plain IPv4
+pub fn normalize_address(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4() {
+            Some(ip) if v6.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            _ => address,
+        },
+        SocketAddr::V4(_) => address,
+    }
+}
+
+// Resolved addresses of the preferred family come first, otherwise resolution order is kept
+pub(crate) fn resolve<A: ToSocketAddrs>(addresses: A, preferred: Option<IpFamily>) -> Result<Vec<SocketAddr>> {
+    let mut addresses = addresses.to_socket_addrs()?.collect::<Vec<_>>();
+    if let Some(preferred) = preferred {
+        addresses.sort_by_key(|&address| IpFamily::of(address) != preferred);
+    }
+    Ok(addresses)
+}
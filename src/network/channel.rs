@@ -0,0 +1,57 @@
+// Copyright 2021 Chay Nabors.
+
+use std::net::SocketAddr;
+
+use laminar::DeliveryGuarantee;
+use laminar::OrderingGuarantee;
+use laminar::Packet;
+
+use super::Delivery;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reliability {
+    Unreliable,
+    UnreliableSequenced,
+    ReliableUnordered,
+    ReliableSequenced,
+    ReliableOrdered,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Channel {
+    delivery: Delivery,
+}
+
+impl Channel {
+    pub(crate) fn new(reliability: Reliability, stream: u8) -> Channel {
+        let delivery = match reliability {
+            Reliability::Unreliable => Delivery::Unreliable,
+            Reliability::UnreliableSequenced => Delivery::UnreliableSequenced(Some(stream)),
+            Reliability::ReliableUnordered => Delivery::ReliableUnordered,
+            Reliability::ReliableSequenced => Delivery::ReliableSequenced(Some(stream)),
+            Reliability::ReliableOrdered => Delivery::ReliableOrdered(Some(stream)),
+        };
+
+        Channel { delivery }
+    }
+
+    pub fn delivery(&self) -> Delivery {
+        self.delivery
+    }
+
+    pub fn packet(&self, address: SocketAddr, payload: Vec<u8>) -> Packet {
+        self.delivery.packet(address, payload)
+    }
+
+    // Unordered channels carry no stream on the wire, so every packet with the same guarantee matches them
+    pub fn matches(&self, packet: &Packet) -> bool {
+        match (self.delivery, packet.delivery_guarantee(), packet.order_guarantee()) {
+            (Delivery::Unreliable, DeliveryGuarantee::Unreliable, OrderingGuarantee::None) => true,
+            (Delivery::UnreliableSequenced(a), DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(b)) => a == b,
+            (Delivery::ReliableUnordered, DeliveryGuarantee::Reliable, OrderingGuarantee::None) => true,
+            (Delivery::ReliableSequenced(a), DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(b)) => a == b,
+            (Delivery::ReliableOrdered(a), DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(b)) => a == b,
+            _ => false,
+        }
+    }
+}
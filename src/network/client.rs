@@ -0,0 +1,97 @@
+// Copyright 2021 Chay Nabors.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use laminar::Packet;
+use serde::Serialize;
+
+use super::normalize_address;
+use super::ConnectFailure;
+use super::Delivery;
+use super::DisconnectReason;
+use super::NetworkEvent;
+use super::Socket;
+use super::TimeoutReason;
+use crate::Result;
+
+#[derive(Clone, Debug)]
+pub enum ClientEvent {
+    Connected,
+    ConnectFailed(ConnectFailure),
+    Disconnected(Option<DisconnectReason>),
+    TimedOut(TimeoutReason),
+    Message(Packet),
+}
+
+#[derive(Debug)]
+pub struct Client {
+    socket: Socket,
+    server: SocketAddr,
+    connected: bool,
+}
+
+impl Client {
+    // Events carry normalized addresses, so the server is kept as one
+    pub fn connect(socket: Socket, server: SocketAddr) -> Client {
+        let server = normalize_address(server);
+        socket.connect(server);
+        Client { socket, server, connected: false }
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    // Where the server's clock is now, comparable with Server::time for interpolation and lag compensation
+    pub fn server_time(&self) -> Option<Duration> {
+        self.socket.estimated_remote_time(self.server)
+    }
+
+    // Events from anything other than the server are ignored
+    pub fn handle_event(&mut self, event: NetworkEvent) -> Option<ClientEvent> {
+        match event {
+            NetworkEvent::Connect(address) if address == self.server => {
+                self.connected = true;
+                Some(ClientEvent::Connected)
+            },
+            NetworkEvent::ConnectFailed(address, failure) if address == self.server => {
+                Some(ClientEvent::ConnectFailed(failure))
+            },
+            NetworkEvent::Message(packet) if packet.addr() == self.server => Some(ClientEvent::Message(packet)),
+            NetworkEvent::Disconnect(address, reason) if address == self.server => {
+                self.connected = false;
+                Some(ClientEvent::Disconnected(reason))
+            },
+            NetworkEvent::Timeout(address, reason) if address == self.server => {
+                self.connected = false;
+                Some(ClientEvent::TimedOut(reason))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn send(&self, payload: Vec<u8>, delivery: Delivery) -> Result<&Self> {
+        self.socket.send(delivery.packet(self.server, payload))?;
+        Ok(self)
+    }
+
+    pub fn send_message<T: Serialize>(&self, message: &T, delivery: Delivery) -> Result<&Self> {
+        self.socket.send_message(self.server, message, delivery)?;
+        Ok(self)
+    }
+
+    pub fn disconnect(&mut self) -> &Self {
+        self.connected = false;
+        self.socket.disconnect(self.server);
+        self
+    }
+}
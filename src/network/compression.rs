@@ -0,0 +1,88 @@
+// Copyright 2021 Chay Nabors.
+
+use std::convert::TryInto;
+
+const UNCOMPRESSED: u8 = 0;
+const COMPRESSED: u8 = 1;
+// The largest frame the stream transports carry, nothing legitimate decompresses to more
+const MAX_SIZE: usize = 1 << 24;
+
+pub(crate) fn compress(payload: &[u8], threshold: usize) -> Vec<u8> {
+    if payload.len() >= threshold {
+        let compressed = lz4_flex::compress_prepend_size(payload);
+        if compressed.len() < payload.len() {
+            let mut data = Vec::with_capacity(compressed.len() + 1);
+            data.push(COMPRESSED);
+            data.extend_from_slice(&compressed);
+            return data;
+        }
+    }
+
+    let mut data = Vec::with_capacity(payload.len() + 1);
+    data.push(UNCOMPRESSED);
+    data.extend_from_slice(payload);
+    data
+}
+
+pub(crate) fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    match data.split_first()? {
+        (&UNCOMPRESSED, payload) => Some(payload.to_vec()),
+        (&COMPRESSED, payload) => decompress_block(payload),
+        _ => None,
+    }
+}
+
+// The prepended size comes from the peer, so it is checked before anything that large is allocated
+fn decompress_block(payload: &[u8]) -> Option<Vec<u8>> {
+    let size = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?) as usize;
+    if size > MAX_SIZE {
+        return None;
+    }
+
+    let mut data = vec![0; size];
+    match lz4_flex::decompress_into(&payload[4..], &mut data) {
+        Ok(written) if written == size => Some(data),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compress;
+    use super::decompress;
+    use super::COMPRESSED;
+    use super::MAX_SIZE;
+    use super::UNCOMPRESSED;
+
+    #[test]
+    fn round_trip() {
+        let repetitive = vec![3; 1024];
+        let compressed = compress(&repetitive, 64);
+        assert_eq!(compressed[0], COMPRESSED);
+        assert!(compressed.len() < repetitive.len());
+        assert_eq!(decompress(&compressed), Some(repetitive));
+
+        // Under the threshold payloads are sent as they are
+        let small = vec![1, 2, 3];
+        let uncompressed = compress(&small, 64);
+        assert_eq!(uncompressed[0], UNCOMPRESSED);
+        assert_eq!(decompress(&uncompressed), Some(small));
+    }
+
+    #[test]
+    fn oversized_header() {
+        let mut compressed = compress(&vec![3; 1024], 64);
+        compressed[1..5].copy_from_slice(&(MAX_SIZE as u32 + 1).to_le_bytes());
+        assert_eq!(decompress(&compressed), None);
+    }
+
+    #[test]
+    fn wrong_header() {
+        let mut compressed = compress(&vec![3; 1024], 64);
+        compressed[1..5].copy_from_slice(&1000u32.to_le_bytes());
+        assert_eq!(decompress(&compressed), None);
+        assert_eq!(decompress(&[COMPRESSED, 1, 0]), None);
+        assert_eq!(decompress(&[2, 1, 2, 3]), None);
+        assert_eq!(decompress(&[]), None);
+    }
+}
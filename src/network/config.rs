@@ -0,0 +1,104 @@
+// Copyright 2021 Chay Nabors.
+
+use std::time::Duration;
+
+pub use laminar::Config as SocketConfig;
+
+use super::IpFamily;
+use super::NetworkSimulation;
+use super::PublicKey;
+use super::RateLimit;
+use super::StaticKeypair;
+use super::TokenKey;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    // Both stream transports ignore delivery guarantees, everything arrives reliably and in order
+    Tcp,
+    // For networks that block udp, the remote has to be bound with the same transport
+    WebSocket,
+    // The only transport in browser builds. Peers find each other through signaling_url and are known by the address
+    // the signaling server sees them at, the addresses given to bind are ignored
+    WebRtc,
+}
+
+impl Default for Transport {
+    fn default() -> Transport {
+        Transport::Udp
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    pub transport: Transport,
+    // Overrides socket.idle_connection_timeout, pending handshakes also expire after it
+    pub idle_timeout: Duration,
+    // Overrides socket.heartbeat_interval, keeps quiet connections from idling out
+    pub heartbeat_interval: Option<Duration>,
+    // Peers whose measured packet loss reaches this are timed out
+    pub max_packet_loss: Option<f32>,
+    // Probes every connection for the largest datagram that gets through, the result is reported in NetworkStats
+    pub mtu_discovery: bool,
+    // Overrides socket.fragment_size, laminar fragments per socket so this should fit the smallest expected mtu
+    pub fragment_size: Option<u16>,
+    pub socket: SocketConfig,
+    // Serves IPv4 and IPv6 from one udp socket bound on the port of the first address, other transports ignore this
+    pub dual_stack: bool,
+    // Decides which address is used when a host resolves to both families
+    pub preferred_family: Option<IpFamily>,
+    // Both ends of a connection have to agree on this, mismatched handshakes are denied
+    pub encryption: bool,
+    // Encrypted connects are answered with this, and denied without it
+    pub static_key: Option<StaticKeypair>,
+    // The public half of the static key of whatever this socket connects to, encrypted connects can't be made without
+    // it and only the holder of the private half can answer them
+    pub remote_key: Option<PublicKey>,
+    // Payloads at least this large are lz4 compressed when the remote also has compression enabled
+    pub compression_threshold: Option<usize>,
+    // Incoming connections are denied unless they present a valid, unused token signed with this key
+    pub token_key: Option<TokenKey>,
+    pub simulation: Option<NetworkSimulation>,
+    // Applied to every peer as it connects
+    pub rate_limit: Option<RateLimit>,
+    // How long the socket thread sleeps between polls. Async sockets wait on their sockets instead, and only step this
+    // often while a rate limit holds messages back
+    pub poll_interval: Duration,
+    // Skips the socket thread, the socket is only polled by Network::manual_poll which the engine calls every frame.
+    // Always on in browser builds
+    pub manual_poll: bool,
+    // Commands queued for the socket thread before Socket::send blocks and Socket::try_send fails
+    pub command_capacity: usize,
+    // The websocket url of a SignalingServer, WebRTC sockets can't be bound without one
+    pub signaling_url: Option<String>,
+    // Stun and turn server urls WebRTC uses to find a path through NATs, without any only direct routes are tried
+    pub ice_servers: Vec<String>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            transport: Transport::default(),
+            idle_timeout: Duration::from_secs(5),
+            heartbeat_interval: None,
+            max_packet_loss: None,
+            mtu_discovery: true,
+            fragment_size: None,
+            socket: SocketConfig::default(),
+            dual_stack: false,
+            preferred_family: None,
+            encryption: false,
+            static_key: None,
+            remote_key: None,
+            compression_threshold: None,
+            token_key: None,
+            simulation: None,
+            rate_limit: None,
+            poll_interval: Duration::from_millis(1),
+            manual_poll: false,
+            command_capacity: 4096,
+            signaling_url: None,
+            ice_servers: vec![],
+        }
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright 2021 Chay Nabors.
+
+use std::net::SocketAddr;
+
+use laminar::DeliveryGuarantee;
+use laminar::OrderingGuarantee;
+use laminar::Packet;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Delivery {
+    Unreliable,
+    UnreliableSequenced(Option<u8>),
+    ReliableUnordered,
+    ReliableSequenced(Option<u8>),
+    ReliableOrdered(Option<u8>),
+}
+
+impl Delivery {
+    pub(crate) fn of(packet: &Packet) -> Delivery {
+        match (packet.delivery_guarantee(), packet.order_guarantee()) {
+            (DeliveryGuarantee::Unreliable, OrderingGuarantee::None) => Delivery::Unreliable,
+            (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(stream)) => Delivery::UnreliableSequenced(stream),
+            (DeliveryGuarantee::Reliable, OrderingGuarantee::None) => Delivery::ReliableUnordered,
+            (DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(stream)) => Delivery::ReliableSequenced(stream),
+            (_, OrderingGuarantee::Ordered(stream)) => Delivery::ReliableOrdered(stream),
+        }
+    }
+
+    pub fn packet(self, address: SocketAddr, payload: Vec<u8>) -> Packet {
+        match self {
+            Delivery::Unreliable => Packet::unreliable(address, payload),
+            Delivery::UnreliableSequenced(stream) => Packet::unreliable_sequenced(address, payload, stream),
+            Delivery::ReliableUnordered => Packet::reliable_unordered(address, payload),
+            Delivery::ReliableSequenced(stream) => Packet::reliable_sequenced(address, payload, stream),
+            Delivery::ReliableOrdered(stream) => Packet::reliable_ordered(address, payload, stream),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+
+use crate::Result;
+
+// Keeps stray broadcasts from other software on the same port from being parsed as announcements
+const MAGIC: &[u8; 4] = b"gear";
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+// A server that misses this many announcements is assumed gone
+const EXPIRY: Duration = Duration::from_secs(3);
+const MAX_ANNOUNCEMENT: usize = 1024;
+
+// The port is the one the game socket listens on, the address comes from where the announcement was received
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub name: String,
+    pub players: u32,
+    pub max_players: u32,
+    pub version: String,
+    pub port: u16,
+    pub metadata: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredServer {
+    pub address: SocketAddr,
+    pub info: ServerInfo,
+    pub last_seen: Instant,
+}
+
+// Broadcasts the server's info on the local network, call update regularly to keep announcing
+#[derive(Debug)]
+pub struct Announcer {
+    socket: UdpSocket,
+    target: SocketAddr,
+    info: ServerInfo,
+    next_announce: Instant,
+}
+
+impl Announcer {
+    pub fn bind(discovery_port: u16, info: ServerInfo) -> Result<Announcer> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), discovery_port);
+        Ok(Announcer { socket, target, info, next_announce: Instant::now() })
+    }
+
+    pub fn info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    // Changes are announced right away rather than on the next interval
+    pub fn set_info(&mut self, info: ServerInfo) -> &Self {
+        self.info = info;
+        self.next_announce = Instant::now();
+        self
+    }
+
+    pub fn update(&mut self) -> Result<()> {
+        let now = Instant::now();
+        if now < self.next_announce {
+            return Ok(());
+        }
+
+        self.next_announce = now + ANNOUNCE_INTERVAL;
+        let mut payload = MAGIC.to_vec();
+        payload.extend(bincode::serialize(&self.info)?);
+        match self.socket.send_to(&payload, self.target) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Listens for announcements, several clients on one machine can share the discovery port
+#[derive(Debug)]
+pub struct Discovery {
+    socket: UdpSocket,
+    servers: HashMap<SocketAddr, DiscoveredServer>,
+}
+
+impl Discovery {
+    pub fn bind(discovery_port: u16) -> Result<Discovery> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_broadcast(true)?;
+        socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), discovery_port).into())?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Discovery { socket: UdpSocket::from(socket), servers: HashMap::new() })
+    }
+
+    // Every server heard from recently, sorted by address so server browsers don't reorder on every call
+    pub fn discover(&mut self) -> Result<Vec<DiscoveredServer>> {
+        let now = Instant::now();
+        let mut buffer = [0; MAX_ANNOUNCEMENT];
+        loop {
+            let (len, source) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            let info = match buffer[..len].strip_prefix(MAGIC).map(bincode::deserialize::<ServerInfo>) {
+                Some(Ok(info)) => info,
+                _ => continue,
+            };
+
+            let address = SocketAddr::new(source.ip(), info.port);
+            self.servers.insert(address, DiscoveredServer { address, info, last_seen: now });
+        }
+
+        self.servers.retain(|_, server| now - server.last_seen < EXPIRY);
+        let mut servers = self.servers.values().cloned().collect::<Vec<_>>();
+        servers.sort_by_key(|server| server.address);
+        Ok(servers)
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2021 Chay Nabors.
+
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::{self,};
+
+use snow::Builder;
+use snow::HandshakeState;
+use snow::StatelessTransportState;
+
+use crate::Result;
+
+// The initiator knows the responder's static key up front, so only the holder of its private half can answer
+const NOISE_PARAMS: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE_SIZE: usize = 65535;
+const NONCE_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+// Nonces this far behind the newest one are no longer tracked and are dropped
+const REPLAY_WINDOW: u64 = 128;
+
+pub type PublicKey = [u8; 32];
+
+// A socket's long lived identity for encrypted connections, the public half is what clients pin
+#[derive(Clone)]
+pub struct StaticKeypair {
+    pub private: [u8; 32],
+    pub public: PublicKey,
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Result<StaticKeypair> {
+        let keypair = Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        Ok(StaticKeypair {
+            private: keypair.private[..].try_into().unwrap(),
+            public: keypair.public[..].try_into().unwrap(),
+        })
+    }
+}
+
+impl Debug for StaticKeypair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticKeypair").field("public", &self.public).finish()
+    }
+}
+
+pub(crate) struct Handshake {
+    state: HandshakeState,
+    request: Vec<u8>,
+}
+
+impl Handshake {
+    pub(crate) fn initiate(remote_key: &PublicKey) -> Result<Handshake> {
+        let mut state = Builder::new(NOISE_PARAMS.parse()?).remote_public_key(remote_key).build_initiator()?;
+        let mut request = vec![0; MAX_MESSAGE_SIZE];
+        let len = state.write_message(&[], &mut request)?;
+        request.truncate(len);
+
+        Ok(Handshake { state, request })
+    }
+
+    pub(crate) fn request(&self) -> &[u8] {
+        &self.request
+    }
+
+    pub(crate) fn respond(request: &[u8], keypair: &StaticKeypair) -> Result<(Cipher, Vec<u8>)> {
+        let mut state = Builder::new(NOISE_PARAMS.parse()?).local_private_key(&keypair.private).build_responder()?;
+        let mut payload = vec![0; MAX_MESSAGE_SIZE];
+        state.read_message(request, &mut payload)?;
+
+        let mut response = vec![0; MAX_MESSAGE_SIZE];
+        let len = state.write_message(&[], &mut response)?;
+        response.truncate(len);
+
+        Ok((Cipher::new(state.into_stateless_transport_mode()?), response))
+    }
+
+    pub(crate) fn complete(mut self, response: &[u8]) -> Result<Cipher> {
+        let mut payload = vec![0; MAX_MESSAGE_SIZE];
+        self.state.read_message(response, &mut payload)?;
+
+        Ok(Cipher::new(self.state.into_stateless_transport_mode()?))
+    }
+}
+
+// Datagrams can be lost or reordered, so each one carries the nonce it was sealed with
+pub(crate) struct Cipher {
+    transport: StatelessTransportState,
+    nonce: u64,
+    // The newest nonce received and a bit for each of the ones before it, so nothing is accepted twice
+    newest: Option<u64>,
+    received: u128,
+}
+
+impl Cipher {
+    fn new(transport: StatelessTransportState) -> Cipher {
+        Cipher { transport, nonce: 0, newest: None, received: 0 }
+    }
+
+    pub(crate) fn encrypt(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce;
+        self.nonce += 1;
+
+        let mut message = vec![0; NONCE_SIZE + payload.len() + TAG_SIZE];
+        message[..NONCE_SIZE].copy_from_slice(&nonce.to_le_bytes());
+        let len = self.transport.write_message(nonce, payload, &mut message[NONCE_SIZE..])?;
+        message.truncate(NONCE_SIZE + len);
+
+        Ok(message)
+    }
+
+    // Replays are checked before decrypting but only recorded after, so forged nonces can't move the window
+    pub(crate) fn decrypt(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        if message.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(snow::Error::Decrypt.into());
+        }
+
+        let nonce = u64::from_le_bytes(message[..NONCE_SIZE].try_into().unwrap());
+        if self.is_replayed(nonce) {
+            return Err(snow::Error::Decrypt.into());
+        }
+
+        let mut payload = vec![0; message.len() - NONCE_SIZE - TAG_SIZE];
+        let len = self.transport.read_message(nonce, &message[NONCE_SIZE..], &mut payload)?;
+        payload.truncate(len);
+        self.record(nonce);
+
+        Ok(payload)
+    }
+
+    fn is_replayed(&self, nonce: u64) -> bool {
+        match self.newest {
+            Some(newest) if nonce <= newest => {
+                newest - nonce >= REPLAY_WINDOW || self.received & (1 << (newest - nonce)) != 0
+            },
+            _ => false,
+        }
+    }
+
+    fn record(&mut self, nonce: u64) {
+        match self.newest {
+            Some(newest) if nonce <= newest => self.received |= 1 << (newest - nonce),
+            Some(newest) if nonce - newest < REPLAY_WINDOW => {
+                self.received = self.received << (nonce - newest) | 1;
+                self.newest = Some(nonce);
+            },
+            _ => {
+                self.received = 1;
+                self.newest = Some(nonce);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cipher;
+    use super::Handshake;
+    use super::StaticKeypair;
+    use super::REPLAY_WINDOW;
+
+    fn ciphers() -> (Cipher, Cipher) {
+        let keypair = StaticKeypair::generate().unwrap();
+        let handshake = Handshake::initiate(&keypair.public).unwrap();
+        let (responder, response) = Handshake::respond(handshake.request(), &keypair).unwrap();
+        (handshake.complete(&response).unwrap(), responder)
+    }
+
+    fn sealed(cipher: &mut Cipher, nonce: u64) -> Vec<u8> {
+        cipher.nonce = nonce;
+        cipher.encrypt(&nonce.to_le_bytes()).unwrap()
+    }
+
+    #[test]
+    fn duplicate_nonce() {
+        let (mut initiator, mut responder) = ciphers();
+        let message = sealed(&mut initiator, 0);
+        assert_eq!(responder.decrypt(&message).unwrap(), 0u64.to_le_bytes());
+        assert!(responder.decrypt(&message).is_err());
+    }
+
+    #[test]
+    fn window_edges() {
+        let (mut initiator, mut responder) = ciphers();
+        let newest = 200;
+        assert!(responder.decrypt(&sealed(&mut initiator, newest)).is_ok());
+
+        // The oldest nonce still in the window, then the first one behind it
+        let oldest = newest - (REPLAY_WINDOW - 1);
+        assert!(responder.decrypt(&sealed(&mut initiator, oldest)).is_ok());
+        assert!(responder.decrypt(&sealed(&mut initiator, oldest)).is_err());
+        assert!(responder.decrypt(&sealed(&mut initiator, oldest - 1)).is_err());
+        assert!(responder.decrypt(&sealed(&mut initiator, 0)).is_err());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (mut initiator, mut responder) = ciphers();
+        for &nonce in &[5, 3, 4, 0, 9, 1] {
+            assert!(responder.decrypt(&sealed(&mut initiator, nonce)).is_ok());
+        }
+        for &nonce in &[5, 3, 4, 0, 9, 1] {
+            assert!(responder.decrypt(&sealed(&mut initiator, nonce)).is_err());
+        }
+
+        // Moving the window past a nonce that was never received drops it too
+        assert!(responder.decrypt(&sealed(&mut initiator, 9 + REPLAY_WINDOW)).is_ok());
+        assert!(responder.decrypt(&sealed(&mut initiator, 2)).is_err());
+        assert!(responder.decrypt(&sealed(&mut initiator, 10)).is_ok());
+    }
+
+    #[test]
+    fn forged_nonce_doesnt_move_the_window() {
+        let (mut initiator, mut responder) = ciphers();
+        assert!(responder.decrypt(&sealed(&mut initiator, 0)).is_ok());
+
+        let mut forged = sealed(&mut initiator, 1000);
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert!(responder.decrypt(&forged).is_err());
+        assert!(responder.decrypt(&sealed(&mut initiator, 1)).is_ok());
+    }
+}
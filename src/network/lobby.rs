@@ -0,0 +1,294 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::decode_message;
+use super::Delivery;
+use super::NetworkEvent;
+use super::Socket;
+use crate::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LobbyId(u32);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyPlayer {
+    pub address: SocketAddr,
+    pub metadata: HashMap<String, String>,
+    pub ready: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyInfo {
+    pub id: LobbyId,
+    pub name: String,
+    pub host: SocketAddr,
+    pub max_players: usize,
+    pub players: Vec<LobbyPlayer>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum LobbyRequest {
+    Host { name: String, max_players: usize },
+    List,
+    Join(LobbyId),
+    Leave,
+    SetMetadata { key: String, value: String },
+    SetReady(bool),
+    Start,
+}
+
+#[derive(Serialize, Deserialize)]
+enum LobbyResponse {
+    Listing(Vec<LobbyInfo>),
+    Joined(LobbyInfo),
+    JoinFailed,
+    Updated(LobbyInfo),
+    Left,
+    Started { host: SocketAddr, peers: Vec<SocketAddr> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LobbyEvent {
+    Listed(Vec<LobbyInfo>),
+    Joined(LobbyInfo),
+    JoinFailed,
+    Updated(LobbyInfo),
+    // The host left or the listing server went away
+    Left,
+    // The lobby is gone and the players are connecting to each other, the usual connect events follow
+    // The peers are every other player in the lobby, including the host unless this is the host
+    Started { host: SocketAddr, peers: Vec<SocketAddr> },
+}
+
+// Runs on a publicly reachable socket and keeps track of every open lobby
+#[derive(Debug, Default)]
+pub struct LobbyServer {
+    lobbies: HashMap<LobbyId, LobbyInfo>,
+    members: HashMap<SocketAddr, LobbyId>,
+    next_id: u32,
+}
+
+impl LobbyServer {
+    pub fn new() -> LobbyServer {
+        LobbyServer::default()
+    }
+
+    pub fn lobbies(&self) -> impl Iterator<Item = &LobbyInfo> + '_ {
+        self.lobbies.values()
+    }
+
+    pub fn handle_event(&mut self, socket: &Socket, event: &NetworkEvent) -> Result<()> {
+        match event {
+            NetworkEvent::Message(packet) => {
+                if let Ok(request) = decode_message::<LobbyRequest>(packet) {
+                    self.handle_request(socket, packet.addr(), request)?;
+                }
+            },
+            NetworkEvent::Disconnect(address, _) | NetworkEvent::Timeout(address, _) => self.leave(socket, *address)?,
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn handle_request(&mut self, socket: &Socket, address: SocketAddr, request: LobbyRequest) -> Result<()> {
+        match request {
+            LobbyRequest::Host { name, max_players } => {
+                self.leave(socket, address)?;
+                let id = LobbyId(self.next_id);
+                self.next_id = self.next_id.wrapping_add(1);
+                let host = LobbyPlayer { address, metadata: HashMap::new(), ready: false };
+                let lobby = LobbyInfo { id, name, host: address, max_players: max_players.max(1), players: vec![host] };
+                send(socket, address, &LobbyResponse::Joined(lobby.clone()))?;
+                self.lobbies.insert(id, lobby);
+                self.members.insert(address, id);
+            },
+            LobbyRequest::List => {
+                let listing = self.lobbies.values().cloned().collect();
+                send(socket, address, &LobbyResponse::Listing(listing))?;
+            },
+            LobbyRequest::Join(id) => {
+                if self.members.get(&address) == Some(&id) {
+                    return Ok(());
+                }
+
+                self.leave(socket, address)?;
+                match self.lobbies.get_mut(&id) {
+                    Some(lobby) if lobby.players.len() < lobby.max_players => {
+                        lobby.players.push(LobbyPlayer { address, metadata: HashMap::new(), ready: false });
+                        self.members.insert(address, id);
+                        send(socket, address, &LobbyResponse::Joined(self.lobbies[&id].clone()))?;
+                        self.update(socket, id)?;
+                    },
+                    _ => send(socket, address, &LobbyResponse::JoinFailed)?,
+                }
+            },
+            LobbyRequest::Leave => self.leave(socket, address)?,
+            LobbyRequest::SetMetadata { key, value } => {
+                if let Some(player) = self.player(address) {
+                    player.metadata.insert(key, value);
+                    self.update(socket, self.members[&address])?;
+                }
+            },
+            LobbyRequest::SetReady(ready) => {
+                if let Some(player) = self.player(address) {
+                    player.ready = ready;
+                    self.update(socket, self.members[&address])?;
+                }
+            },
+            // Only the host can start, and only once every player is ready
+            LobbyRequest::Start => {
+                let id = match self.members.get(&address) {
+                    Some(&id) if self.lobbies[&id].host == address => id,
+                    _ => return Ok(()),
+                };
+
+                if !self.lobbies[&id].players.iter().all(|player| player.ready) {
+                    return Ok(());
+                }
+
+                let lobby = self.lobbies.remove(&id).unwrap();
+                let players = lobby.players.iter().map(|player| player.address).collect::<Vec<_>>();
+                for &player in &players {
+                    self.members.remove(&player);
+                    let peers = players.iter().copied().filter(|&peer| peer != player).collect();
+                    send(socket, player, &LobbyResponse::Started { host: lobby.host, peers })?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn player(&mut self, address: SocketAddr) -> Option<&mut LobbyPlayer> {
+        let id = self.members.get(&address)?;
+        self.lobbies.get_mut(id)?.players.iter_mut().find(|player| player.address == address)
+    }
+
+    fn update(&self, socket: &Socket, id: LobbyId) -> Result<()> {
+        if let Some(lobby) = self.lobbies.get(&id) {
+            for player in &lobby.players {
+                send(socket, player.address, &LobbyResponse::Updated(lobby.clone()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The lobby closes when its host leaves
+    fn leave(&mut self, socket: &Socket, address: SocketAddr) -> Result<()> {
+        let id = match self.members.remove(&address) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        if self.lobbies[&id].host == address {
+            let lobby = self.lobbies.remove(&id).unwrap();
+            for player in lobby.players {
+                self.members.remove(&player.address);
+                send(socket, player.address, &LobbyResponse::Left)?;
+            }
+        } else {
+            self.lobbies.get_mut(&id).unwrap().players.retain(|player| player.address != address);
+            send(socket, address, &LobbyResponse::Left)?;
+            self.update(socket, id)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn send<T: Serialize>(socket: &Socket, address: SocketAddr, message: &T) -> Result<()> {
+    socket.send_message(address, message, Delivery::ReliableOrdered(None))?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Lobby {
+    server: SocketAddr,
+    lobby: Option<LobbyInfo>,
+}
+
+impl Lobby {
+    pub fn connect(socket: &Socket, server: SocketAddr) -> Lobby {
+        socket.connect(server);
+        Lobby { server, lobby: None }
+    }
+
+    pub fn lobby(&self) -> Option<&LobbyInfo> {
+        self.lobby.as_ref()
+    }
+
+    pub fn host(&self, socket: &Socket, name: &str, max_players: usize) -> Result<&Self> {
+        self.request(socket, &LobbyRequest::Host { name: name.to_owned(), max_players })
+    }
+
+    pub fn list(&self, socket: &Socket) -> Result<&Self> {
+        self.request(socket, &LobbyRequest::List)
+    }
+
+    pub fn join(&self, socket: &Socket, id: LobbyId) -> Result<&Self> {
+        self.request(socket, &LobbyRequest::Join(id))
+    }
+
+    pub fn leave(&self, socket: &Socket) -> Result<&Self> {
+        self.request(socket, &LobbyRequest::Leave)
+    }
+
+    pub fn set_metadata(&self, socket: &Socket, key: &str, value: &str) -> Result<&Self> {
+        self.request(socket, &LobbyRequest::SetMetadata { key: key.to_owned(), value: value.to_owned() })
+    }
+
+    pub fn set_ready(&self, socket: &Socket, ready: bool) -> Result<&Self> {
+        self.request(socket, &LobbyRequest::SetReady(ready))
+    }
+
+    pub fn start(&self, socket: &Socket) -> Result<&Self> {
+        self.request(socket, &LobbyRequest::Start)
+    }
+
+    fn request(&self, socket: &Socket, request: &LobbyRequest) -> Result<&Self> {
+        send(socket, self.server, request)?;
+        Ok(self)
+    }
+
+    pub fn handle_event(&mut self, socket: &Socket, event: &NetworkEvent) -> Option<LobbyEvent> {
+        match event {
+            NetworkEvent::Message(packet) if packet.addr() == self.server => {
+                match decode_message::<LobbyResponse>(packet).ok()? {
+                    LobbyResponse::Listing(lobbies) => Some(LobbyEvent::Listed(lobbies)),
+                    LobbyResponse::Joined(lobby) => {
+                        self.lobby = Some(lobby.clone());
+                        Some(LobbyEvent::Joined(lobby))
+                    },
+                    LobbyResponse::JoinFailed => Some(LobbyEvent::JoinFailed),
+                    LobbyResponse::Updated(lobby) => {
+                        self.lobby = Some(lobby.clone());
+                        Some(LobbyEvent::Updated(lobby))
+                    },
+                    LobbyResponse::Left => {
+                        self.lobby = None;
+                        Some(LobbyEvent::Left)
+                    },
+                    // Everyone dials everyone else so the connections punch through nat in both directions
+                    LobbyResponse::Started { host, peers } => {
+                        self.lobby = None;
+                        for &peer in &peers {
+                            socket.connect(peer);
+                        }
+                        Some(LobbyEvent::Started { host, peers })
+                    },
+                }
+            },
+            NetworkEvent::Disconnect(address, _) | NetworkEvent::Timeout(address, _) if *address == self.server => {
+                self.lobby.take().map(|_| LobbyEvent::Left)
+            },
+            _ => None,
+        }
+    }
+}
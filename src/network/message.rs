@@ -0,0 +1,18 @@
+// Copyright 2021 Chay Nabors.
+
+use std::net::SocketAddr;
+
+use laminar::Packet;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::Delivery;
+use crate::Result;
+
+pub(crate) fn encode<T: Serialize>(address: SocketAddr, message: &T, delivery: Delivery) -> Result<Packet> {
+    Ok(delivery.packet(address, bincode::serialize(message)?))
+}
+
+pub fn decode_message<T: DeserializeOwned>(packet: &Packet) -> Result<T> {
+    Ok(bincode::deserialize(packet.payload())?)
+}
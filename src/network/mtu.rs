@@ -0,0 +1,75 @@
+// Copyright 2021 Chay Nabors.
+
+use std::time::Duration;
+
+use instant::Instant;
+
+// Payload sizes that fit a 576 byte and a 1500 byte ip packet once ip and udp headers are counted
+const MIN_MTU: usize = 508;
+const MAX_MTU: usize = 1472;
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+const PROBE_ATTEMPTS: u32 = 3;
+// Close enough, probing further only costs packets
+const PRECISION: usize = 8;
+
+// Binary searches the largest datagram that reaches the peer, probes are not fragmented so oversized ones are lost
+#[derive(Debug)]
+pub(crate) struct MtuDiscovery {
+    low: usize,
+    high: usize,
+    probe: Option<(usize, Instant)>,
+    attempts: u32,
+}
+
+impl MtuDiscovery {
+    // Larger probes than the limit would be rejected by the transport before reaching the wire
+    pub(crate) fn new(limit: usize) -> MtuDiscovery {
+        MtuDiscovery { low: MIN_MTU, high: MAX_MTU.min(limit).max(MIN_MTU), probe: None, attempts: 0 }
+    }
+
+    pub(crate) fn mtu(&self) -> usize {
+        self.low
+    }
+
+    fn is_complete(&self) -> bool {
+        self.high - self.low < PRECISION
+    }
+
+    // Returns the size of a probe to send when one is due
+    pub(crate) fn update(&mut self, now: Instant) -> Option<usize> {
+        if self.is_complete() {
+            return None;
+        }
+
+        match self.probe {
+            Some((_, sent)) if now - sent < PROBE_TIMEOUT => return None,
+            Some((size, _)) => {
+                self.attempts += 1;
+                if self.attempts >= PROBE_ATTEMPTS {
+                    self.high = size - 1;
+                    self.attempts = 0;
+                    if self.is_complete() {
+                        self.probe = None;
+                        return None;
+                    }
+                }
+            },
+            None => (),
+        }
+
+        let size = match self.probe {
+            Some((size, _)) if self.attempts > 0 => size,
+            _ => (self.low + self.high + 1) / 2,
+        };
+        self.probe = Some((size, now));
+        Some(size)
+    }
+
+    pub(crate) fn receive_ack(&mut self, size: usize) {
+        if size > self.low && size <= self.high {
+            self.low = size;
+            self.probe = None;
+            self.attempts = 0;
+        }
+    }
+}
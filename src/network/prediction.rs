@@ -0,0 +1,157 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::Channel;
+use super::NetworkEvent;
+use super::Socket;
+use crate::Result;
+
+// Inputs travel unreliably, so every packet repeats the newest unacked inputs to ride out loss
+const REDUNDANT_INPUTS: usize = 8;
+const MAX_PENDING_INPUTS: usize = 256;
+
+#[derive(Serialize, Deserialize)]
+enum PredictionMessage {
+    Inputs(Vec<(u32, Vec<u8>)>),
+    State { acked: u32, state: Vec<u8> },
+}
+
+// Runs on the client, the state is always the last authoritative state with every unacked input applied on top
+#[derive(Debug)]
+pub struct Prediction<S, I> {
+    channel: Channel,
+    server: SocketAddr,
+    sequence: u32,
+    acked: u32,
+    pending: VecDeque<(u32, I)>,
+    state: S,
+}
+
+impl<S: DeserializeOwned, I: Serialize> Prediction<S, I> {
+    pub fn new(channel: Channel, server: SocketAddr, state: S) -> Prediction<S, I> {
+        Prediction { channel, server, sequence: 0, acked: 0, pending: VecDeque::new(), state }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn input<F: FnMut(&mut S, &I)>(&mut self, socket: &Socket, input: I, mut simulate: F) -> Result<u32> {
+        self.sequence += 1;
+        simulate(&mut self.state, &input);
+        self.pending.push_back((self.sequence, input));
+        while self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+
+        let inputs = self
+            .pending
+            .iter()
+            .rev()
+            .take(REDUNDANT_INPUTS)
+            .map(|(sequence, input)| bincode::serialize(input).map(|input| (*sequence, input)))
+            .collect::<bincode::Result<Vec<_>>>()?;
+        socket.send_on(self.channel, self.server, bincode::serialize(&PredictionMessage::Inputs(inputs))?)?;
+
+        Ok(self.sequence)
+    }
+
+    // Replaces the predicted state with the authoritative one and resimulates every input the server has not seen
+    pub fn reconcile<F: FnMut(&mut S, &I)>(&mut self, acked: u32, state: S, mut simulate: F) -> &mut Self {
+        if acked < self.acked {
+            return self;
+        }
+
+        self.acked = acked;
+        self.state = state;
+        while self.pending.front().map_or(false, |(sequence, _)| *sequence <= acked) {
+            self.pending.pop_front();
+        }
+        for (_, input) in &self.pending {
+            simulate(&mut self.state, input);
+        }
+        self
+    }
+
+    // Returns true if the event carried authoritative state and a reconciliation happened
+    pub fn handle_event<F: FnMut(&mut S, &I)>(&mut self, event: &NetworkEvent, simulate: F) -> Result<bool> {
+        match event {
+            NetworkEvent::Message(packet) if packet.addr() == self.server && self.channel.matches(packet) => {
+                match bincode::deserialize::<PredictionMessage>(packet.payload()) {
+                    Ok(PredictionMessage::State { acked, state }) => {
+                        self.reconcile(acked, bincode::deserialize(&state)?, simulate);
+                        Ok(true)
+                    },
+                    _ => Ok(false),
+                }
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+// Runs on the server and hands out each peer's inputs once and in order
+#[derive(Debug)]
+pub struct InputBuffer<I> {
+    channel: Channel,
+    peers: HashMap<SocketAddr, (u32, VecDeque<(u32, I)>)>,
+}
+
+impl<I: DeserializeOwned> InputBuffer<I> {
+    pub fn new(channel: Channel) -> InputBuffer<I> {
+        InputBuffer { channel, peers: HashMap::new() }
+    }
+
+    pub fn handle_event(&mut self, event: &NetworkEvent) -> bool {
+        match event {
+            NetworkEvent::Disconnect(address, _) | NetworkEvent::Timeout(address, _) => {
+                self.peers.remove(address);
+                false
+            },
+            NetworkEvent::Message(packet) if self.channel.matches(packet) => {
+                let inputs = match bincode::deserialize::<PredictionMessage>(packet.payload()) {
+                    Ok(PredictionMessage::Inputs(inputs)) => inputs,
+                    _ => return false,
+                };
+
+                let (received, queue) = self.peers.entry(packet.addr()).or_insert_with(|| (0, VecDeque::new()));
+                let mut inputs = inputs.into_iter().filter(|(sequence, _)| *sequence > *received).collect::<Vec<_>>();
+                inputs.sort_by_key(|(sequence, _)| *sequence);
+                for (sequence, input) in inputs {
+                    if let Ok(input) = bincode::deserialize(&input) {
+                        queue.push_back((sequence, input));
+                        *received = sequence;
+                    }
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers.keys().copied()
+    }
+
+    pub fn pop(&mut self, address: SocketAddr) -> Option<(u32, I)> {
+        self.peers.get_mut(&address)?.1.pop_front()
+    }
+
+    // The acked sequence is the last one popped by the game, not the last one received
+    pub fn send_state<S: Serialize>(&self, socket: &Socket, address: SocketAddr, acked: u32, state: &S) -> Result<&Self> {
+        let message = PredictionMessage::State { acked, state: bincode::serialize(state)? };
+        socket.send_on(self.channel, address, bincode::serialize(&message)?)?;
+        Ok(self)
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright 2021 Chay Nabors.
+
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+use hmac::Hmac;
+use hmac::Mac;
+use hmac::NewMac;
+use laminar::DeliveryGuarantee;
+use laminar::OrderingGuarantee;
+use laminar::Packet;
+use sha2::Sha256;
+
+use super::DisconnectReason;
+
+// Every datagram gear sends is prefixed with one of these so connection management can share the socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum PacketKind {
+    Message = 0,
+    ConnectRequest = 1,
+    ConnectAccept = 2,
+    ConnectDeny = 3,
+    Disconnect = 4,
+    Ping = 5,
+    Pong = 6,
+    MtuProbe = 7,
+    MtuAck = 8,
+    // Several length prefixed messages sharing one delivery guarantee
+    Batch = 9,
+    // Carries the address of the far end ahead of a whole gear datagram
+    Relay = 10,
+    RelayRegister = 11,
+}
+
+// Capabilities advertised in connect requests, the accept echoes the ones both sides share
+pub(crate) const ENCRYPTION: u8 = 1;
+pub(crate) const COMPRESSION: u8 = 1 << 1;
+// The request carries a length prefixed connect token ahead of the handshake, never echoed in the accept
+pub(crate) const TOKEN: u8 = 1 << 2;
+
+impl PacketKind {
+    fn from_u8(value: u8) -> Option<PacketKind> {
+        match value {
+            0 => Some(PacketKind::Message),
+            1 => Some(PacketKind::ConnectRequest),
+            2 => Some(PacketKind::ConnectAccept),
+            3 => Some(PacketKind::ConnectDeny),
+            4 => Some(PacketKind::Disconnect),
+            5 => Some(PacketKind::Ping),
+            6 => Some(PacketKind::Pong),
+            7 => Some(PacketKind::MtuProbe),
+            8 => Some(PacketKind::MtuAck),
+            9 => Some(PacketKind::Batch),
+            10 => Some(PacketKind::Relay),
+            11 => Some(PacketKind::RelayRegister),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn with_payload(packet: &Packet, address: SocketAddr, payload: Vec<u8>) -> Packet {
+    match (packet.delivery_guarantee(), packet.order_guarantee()) {
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::None) => Packet::unreliable(address, payload),
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(stream)) => {
+            Packet::unreliable_sequenced(address, payload, stream)
+        },
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::None) => Packet::reliable_unordered(address, payload),
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(stream)) => {
+            Packet::reliable_sequenced(address, payload, stream)
+        },
+        (_, OrderingGuarantee::Ordered(stream)) => Packet::reliable_ordered(address, payload, stream),
+    }
+}
+
+pub(crate) fn encode(kind: PacketKind, packet: &Packet) -> Packet {
+    let mut payload = Vec::with_capacity(packet.payload().len() + 1);
+    payload.push(kind as u8);
+    payload.extend_from_slice(packet.payload());
+    with_payload(packet, packet.addr(), payload)
+}
+
+pub(crate) fn decode(packet: &Packet) -> Option<(PacketKind, Packet)> {
+    let (&kind, payload) = packet.payload().split_first()?;
+    let kind = PacketKind::from_u8(kind)?;
+    Some((kind, with_payload(packet, packet.addr(), payload.to_vec())))
+}
+
+pub(crate) fn control(kind: PacketKind, address: SocketAddr) -> Packet {
+    control_with(kind, address, &[])
+}
+
+pub(crate) fn control_with(kind: PacketKind, address: SocketAddr, payload: &[u8]) -> Packet {
+    Packet::reliable_unordered(address, header(kind, payload))
+}
+
+pub(crate) fn unreliable_control(kind: PacketKind, address: SocketAddr, payload: &[u8]) -> Packet {
+    Packet::unreliable(address, header(kind, payload))
+}
+
+pub(crate) fn encode_disconnect(reason: &Option<DisconnectReason>) -> Vec<u8> {
+    match reason {
+        Some(reason) => {
+            let mut payload = reason.code.to_le_bytes().to_vec();
+            payload.extend_from_slice(reason.message.as_bytes());
+            payload
+        },
+        None => vec![],
+    }
+}
+
+pub(crate) fn decode_disconnect(payload: &[u8]) -> Option<DisconnectReason> {
+    if payload.len() < 2 {
+        return None;
+    }
+
+    let code = u16::from_le_bytes([payload[0], payload[1]]);
+    Some(DisconnectReason { code, message: String::from_utf8_lossy(&payload[2..]).into_owned() })
+}
+
+// Pings prove they come from the peer holding the session without ever sending the session itself
+pub(crate) fn encode_ping(sequence: u32, session: u64) -> Vec<u8> {
+    let mut payload = sequence.to_le_bytes().to_vec();
+    payload.extend_from_slice(&session_mac(sequence, session).finalize().into_bytes());
+    payload
+}
+
+pub(crate) fn verify_ping(payload: &[u8], session: u64) -> bool {
+    match decode_ping(payload) {
+        Some(sequence) => session_mac(sequence, session).verify(&payload[4..]).is_ok(),
+        None => false,
+    }
+}
+
+pub(crate) fn decode_ping(payload: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(payload.get(..4)?.try_into().ok()?))
+}
+
+fn session_mac(sequence: u32, session: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&session.to_le_bytes()).unwrap();
+    mac.update(&sequence.to_le_bytes());
+    mac
+}
+
+// The packets are already encoded, so each entry keeps its own kind byte
+pub(crate) fn encode_batch(packets: &[Packet]) -> Packet {
+    let mut payload = vec![PacketKind::Batch as u8];
+    for packet in packets {
+        payload.extend_from_slice(&(packet.payload().len() as u16).to_le_bytes());
+        payload.extend_from_slice(packet.payload());
+    }
+    with_payload(&packets[0], packets[0].addr(), payload)
+}
+
+pub(crate) fn decode_batch(packet: &Packet) -> Option<Vec<Packet>> {
+    let mut payload = packet.payload();
+    let mut packets = vec![];
+    while !payload.is_empty() {
+        if payload.len() < 2 {
+            return None;
+        }
+
+        let len = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+        let entry = payload.get(2..2 + len)?;
+        packets.push(with_payload(packet, packet.addr(), entry.to_vec()));
+        payload = &payload[2 + len..];
+    }
+
+    Some(packets)
+}
+
+// Sends the packet through a relay, the address is the destination on the way in and the source on the way out
+pub(crate) fn encode_relay(packet: &Packet, via: SocketAddr, address: SocketAddr) -> Packet {
+    let mut payload = vec![PacketKind::Relay as u8];
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            payload.push(4);
+            payload.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            payload.push(6);
+            payload.extend_from_slice(&ip.octets());
+        },
+    }
+    payload.extend_from_slice(&address.port().to_le_bytes());
+    payload.extend_from_slice(packet.payload());
+    with_payload(packet, via, payload)
+}
+
+// The returned packet is addressed to whatever address the relay header named
+pub(crate) fn decode_relay(packet: &Packet) -> Option<Packet> {
+    let (&kind, payload) = packet.payload().split_first()?;
+    if kind != PacketKind::Relay as u8 {
+        return None;
+    }
+
+    let (&family, payload) = payload.split_first()?;
+    let (ip, payload) = match family {
+        4 if payload.len() >= 4 => {
+            let octets: [u8; 4] = payload[..4].try_into().ok()?;
+            (IpAddr::V4(Ipv4Addr::from(octets)), &payload[4..])
+        },
+        6 if payload.len() >= 16 => {
+            let octets: [u8; 16] = payload[..16].try_into().ok()?;
+            (IpAddr::V6(Ipv6Addr::from(octets)), &payload[16..])
+        },
+        _ => return None,
+    };
+    if payload.len() < 2 {
+        return None;
+    }
+
+    let port = u16::from_le_bytes([payload[0], payload[1]]);
+    Some(with_payload(packet, SocketAddr::new(ip, port), payload[2..].to_vec()))
+}
+
+fn header(kind: PacketKind, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(payload.len() + 1);
+    data.push(kind as u8);
+    data.extend_from_slice(payload);
+    data
+}
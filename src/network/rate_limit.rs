@@ -0,0 +1,257 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::VecDeque;
+
+use instant::Instant;
+use laminar::DeliveryGuarantee;
+use laminar::Packet;
+
+use super::protocol::{self,};
+
+// Decides what leaves first once a rate limit holds messages back, without a limit everything is sent in order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Priority {
+    Critical,
+    High,
+    Normal,
+    Low,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+// Only limits messages, connection management and pings are never held back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_second: Option<usize>,
+    pub packets_per_second: Option<usize>,
+    // Unreliable messages are dropped instead of queued past this, reliable ones always queue
+    pub max_queued_bytes: usize,
+    // Queued messages up to this size are coalesced into shared datagrams once the budget runs out
+    pub coalesce_below: usize,
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit { bytes_per_second: None, packets_per_second: None, max_queued_bytes: 64 * 1024, coalesce_below: 256 }
+    }
+}
+
+// Token buckets hold at most one second worth of budget
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    limit: RateLimit,
+    bytes: f64,
+    packets: f64,
+    last_refill: Instant,
+    queues: [VecDeque<Packet>; 4],
+    queued_bytes: usize,
+}
+
+impl Limiter {
+    pub(crate) fn new(limit: RateLimit, now: Instant) -> Limiter {
+        Limiter {
+            limit,
+            bytes: limit.bytes_per_second.unwrap_or_default() as f64,
+            packets: limit.packets_per_second.unwrap_or_default() as f64,
+            last_refill: now,
+            queues: Default::default(),
+            queued_bytes: 0,
+        }
+    }
+
+    pub(crate) fn is_congested(&self) -> bool {
+        self.queues.iter().any(|queue| !queue.is_empty())
+    }
+
+    pub(crate) fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    pub(crate) fn push(&mut self, packet: Packet, priority: Priority) {
+        let len = packet.payload().len();
+        let unreliable = matches!(packet.delivery_guarantee(), DeliveryGuarantee::Unreliable);
+        if unreliable && self.queued_bytes + len > self.limit.max_queued_bytes {
+            return;
+        }
+
+        self.queued_bytes += len;
+        self.queues[priority as usize].push_back(packet);
+    }
+
+    // Whatever the budget doesn't cover waits for the next step, lower priorities are the first to wait
+    pub(crate) fn drain(&mut self, now: Instant, max_datagram: usize) -> Vec<Packet> {
+        self.refill(now);
+
+        let mut ready = vec![];
+        while self.has_budget() {
+            let queue = match self.queues.iter_mut().find(|queue| !queue.is_empty()) {
+                Some(queue) => queue,
+                None => break,
+            };
+
+            let (packet, queued) = coalesce(queue, self.limit.coalesce_below, max_datagram);
+
+            // The byte bucket may go negative so packets larger than a second of budget still get through
+            self.bytes -= packet.payload().len() as f64;
+            self.packets -= 1.;
+            self.queued_bytes -= queued;
+            ready.push(packet);
+        }
+
+        ready
+    }
+
+    // Ignores the budget, for sockets that are closing
+    pub(crate) fn flush(&mut self, max_datagram: usize) -> Vec<Packet> {
+        let coalesce_below = self.limit.coalesce_below;
+        let mut ready = vec![];
+        for queue in &mut self.queues {
+            while !queue.is_empty() {
+                ready.push(coalesce(queue, coalesce_below, max_datagram).0);
+            }
+        }
+
+        self.queued_bytes = 0;
+        ready
+    }
+
+    fn has_budget(&self) -> bool {
+        self.limit.bytes_per_second.map_or(true, |_| self.bytes > 0.)
+            && self.limit.packets_per_second.map_or(true, |_| self.packets >= 1.)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = (now - self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        if let Some(rate) = self.limit.bytes_per_second {
+            self.bytes = (self.bytes + rate as f64 * elapsed).min(rate as f64);
+        }
+        if let Some(rate) = self.limit.packets_per_second {
+            self.packets = (self.packets + rate as f64 * elapsed).min(rate as f64);
+        }
+    }
+}
+
+// Pops the front of the queue along with any small messages behind it that share its delivery and fit one datagram
+fn coalesce(queue: &mut VecDeque<Packet>, coalesce_below: usize, max_datagram: usize) -> (Packet, usize) {
+    let mut batch = vec![];
+    let mut size = 1;
+    while let Some(packet) = queue.front() {
+        let len = packet.payload().len();
+        let fits = size + 2 + len <= max_datagram && len <= coalesce_below;
+        let compatible = batch.first().map_or(true, |first: &Packet| {
+            first.delivery_guarantee() == packet.delivery_guarantee() && first.order_guarantee() == packet.order_guarantee()
+        });
+        if !batch.is_empty() && !(fits && compatible) {
+            break;
+        }
+
+        size += 2 + len;
+        batch.extend(queue.pop_front());
+        if !fits {
+            break;
+        }
+    }
+
+    let queued = batch.iter().map(|packet| packet.payload().len()).sum();
+    if batch.len() == 1 {
+        return (batch.remove(0), queued);
+    }
+
+    (protocol::encode_batch(&batch), queued)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use laminar::Packet;
+
+    use super::Limiter;
+    use super::Priority;
+    use super::RateLimit;
+
+    const MTU: usize = 1200;
+
+    fn address() -> SocketAddr {
+        "127.0.0.1:7777".parse().unwrap()
+    }
+
+    fn limiter(bytes_per_second: Option<usize>, packets_per_second: Option<usize>, now: Instant) -> Limiter {
+        let limit = RateLimit { bytes_per_second, packets_per_second, max_queued_bytes: 1000, coalesce_below: 0 };
+        Limiter::new(limit, now)
+    }
+
+    fn reliable(len: usize) -> Packet {
+        Packet::reliable_ordered(address(), vec![0; len], None)
+    }
+
+    #[test]
+    fn packet_budget_refills() {
+        let now = Instant::now();
+        let mut limiter = limiter(None, Some(2), now);
+        for _ in 0..5 {
+            limiter.push(reliable(10), Priority::Normal);
+        }
+
+        assert_eq!(limiter.drain(now, MTU).len(), 2);
+        assert_eq!(limiter.drain(now, MTU).len(), 0);
+        assert_eq!(limiter.drain(now + Duration::from_millis(500), MTU).len(), 1);
+        assert!(limiter.is_congested());
+        assert_eq!(limiter.queued_bytes(), 20);
+
+        // The bucket holds at most a second of budget however long it waited
+        limiter.push(reliable(10), Priority::Normal);
+        assert_eq!(limiter.drain(now + Duration::from_secs(10), MTU).len(), 2);
+        assert_eq!(limiter.queued_bytes(), 10);
+    }
+
+    #[test]
+    fn byte_budget_goes_negative() {
+        let now = Instant::now();
+        let mut limiter = limiter(Some(100), None, now);
+        limiter.push(reliable(300), Priority::Normal);
+        limiter.push(reliable(10), Priority::Normal);
+
+        // Larger than a second of budget, still sent but paid back before anything else goes
+        assert_eq!(limiter.drain(now, MTU).len(), 1);
+        assert_eq!(limiter.drain(now + Duration::from_secs(1), MTU).len(), 0);
+        assert_eq!(limiter.drain(now + Duration::from_secs(3), MTU).len(), 1);
+        assert!(!limiter.is_congested());
+    }
+
+    #[test]
+    fn higher_priorities_first() {
+        let now = Instant::now();
+        let mut limiter = limiter(None, Some(1), now);
+        limiter.push(reliable(1), Priority::Low);
+        limiter.push(reliable(2), Priority::Critical);
+        limiter.push(reliable(3), Priority::Normal);
+
+        let lens = (0..3)
+            .flat_map(|second| limiter.drain(now + Duration::from_secs(second), MTU))
+            .map(|packet| packet.payload().len())
+            .collect::<Vec<_>>();
+        assert_eq!(lens, vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn unreliable_dropped_past_the_queue_limit() {
+        let now = Instant::now();
+        let mut limiter = limiter(None, Some(1), now);
+        limiter.push(Packet::unreliable(address(), vec![0; 600]), Priority::Normal);
+        limiter.push(Packet::unreliable(address(), vec![0; 600]), Priority::Normal);
+        assert_eq!(limiter.queued_bytes(), 600);
+
+        limiter.push(reliable(600), Priority::Normal);
+        assert_eq!(limiter.queued_bytes(), 1200);
+        assert_eq!(limiter.flush(MTU).len(), 2);
+        assert_eq!(limiter.queued_bytes(), 0);
+    }
+}
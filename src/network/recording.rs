@@ -0,0 +1,131 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use instant::Instant;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::ConnectFailure;
+use super::Delivery;
+use super::DisconnectReason;
+use super::NetworkEvent;
+use super::SocketId;
+use super::TimeoutReason;
+use crate::Result;
+
+// Errors are not recorded, they describe the local transport rather than what the game received
+#[derive(Debug, Serialize, Deserialize)]
+enum RecordedEvent {
+    Message { address: SocketAddr, payload: Vec<u8>, delivery: Delivery },
+    Connect(SocketAddr),
+    ConnectFailed(SocketAddr, ConnectFailure),
+    Timeout(SocketAddr, TimeoutReason),
+    Disconnect(SocketAddr, Option<DisconnectReason>),
+    Congested(SocketAddr, bool),
+    AddressChanged(SocketAddr, SocketAddr),
+}
+
+impl RecordedEvent {
+    fn from_event(event: &NetworkEvent) -> Option<RecordedEvent> {
+        let event = match event {
+            NetworkEvent::Message(packet) => RecordedEvent::Message {
+                address: packet.addr(),
+                payload: packet.payload().to_vec(),
+                delivery: Delivery::of(packet),
+            },
+            NetworkEvent::Connect(address) => RecordedEvent::Connect(*address),
+            NetworkEvent::ConnectFailed(address, failure) => RecordedEvent::ConnectFailed(*address, *failure),
+            NetworkEvent::Timeout(address, reason) => RecordedEvent::Timeout(*address, *reason),
+            NetworkEvent::Disconnect(address, reason) => RecordedEvent::Disconnect(*address, reason.clone()),
+            NetworkEvent::Congested(address, congested) => RecordedEvent::Congested(*address, *congested),
+            NetworkEvent::AddressChanged(previous, address) => RecordedEvent::AddressChanged(*previous, *address),
+            NetworkEvent::Error(..) => return None,
+        };
+        Some(event)
+    }
+
+    fn into_event(self) -> NetworkEvent {
+        match self {
+            RecordedEvent::Message { address, payload, delivery } => {
+                NetworkEvent::Message(delivery.packet(address, payload))
+            },
+            RecordedEvent::Connect(address) => NetworkEvent::Connect(address),
+            RecordedEvent::ConnectFailed(address, failure) => NetworkEvent::ConnectFailed(address, failure),
+            RecordedEvent::Timeout(address, reason) => NetworkEvent::Timeout(address, reason),
+            RecordedEvent::Disconnect(address, reason) => NetworkEvent::Disconnect(address, reason),
+            RecordedEvent::Congested(address, congested) => NetworkEvent::Congested(address, congested),
+            RecordedEvent::AddressChanged(previous, address) => NetworkEvent::AddressChanged(previous, address),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    start: Instant,
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> Result<Recorder> {
+        Ok(Recorder { start: Instant::now(), writer: BufWriter::new(File::create(path)?) })
+    }
+
+    pub(crate) fn record(&mut self, socket: SocketId, event: &NetworkEvent) -> Result<()> {
+        if let Some(event) = RecordedEvent::from_event(event) {
+            bincode::serialize_into(&mut self.writer, &(self.start.elapsed(), socket, event))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+// Hands recorded events back out at the pace they were recorded at, relative to when the replay was loaded
+#[derive(Debug)]
+pub(crate) struct Replay {
+    start: Instant,
+    events: VecDeque<(Duration, SocketId, RecordedEvent)>,
+}
+
+impl Replay {
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Replay> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut events = VecDeque::new();
+        loop {
+            match bincode::deserialize_from::<_, (Duration, SocketId, RecordedEvent)>(&mut reader) {
+                Ok(event) => events.push_back(event),
+                Err(e) => match *e {
+                    bincode::ErrorKind::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof => break,
+                    _ => return Err(e.into()),
+                },
+            }
+        }
+
+        Ok(Replay { start: Instant::now(), events })
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub(crate) fn next(&mut self, now: Instant) -> Option<(SocketId, NetworkEvent)> {
+        match self.events.front() {
+            Some((time, _, _)) if now - self.start >= *time => {
+                let (_, socket, event) = self.events.pop_front()?;
+                Some((socket, event.into_event()))
+            },
+            _ => None,
+        }
+    }
+}
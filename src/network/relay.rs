@@ -0,0 +1,78 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::{self,};
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+use std::time::Instant;
+
+use laminar::SocketEvent;
+
+use super::protocol::{self,};
+use super::SocketConfig;
+use crate::Result;
+
+// Clients send a keepalive every second, three missed ones and they can no longer be reached through the relay
+const EXPIRY: Duration = Duration::from_secs(3);
+
+// Forwards traffic between peers that can't reach each other directly, run it on a publicly reachable address
+pub struct RelayServer {
+    socket: laminar::Socket,
+    clients: HashMap<SocketAddr, Instant>,
+}
+
+impl RelayServer {
+    pub fn bind<A: ToSocketAddrs>(addresses: A) -> Result<RelayServer> {
+        RelayServer::bind_with_config(addresses, SocketConfig::default())
+    }
+
+    pub fn bind_with_config<A: ToSocketAddrs>(addresses: A, config: SocketConfig) -> Result<RelayServer> {
+        Ok(RelayServer { socket: laminar::Socket::bind_with_config(addresses, config)?, clients: HashMap::new() })
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    // Forwards everything that arrived since the last call, call it regularly
+    pub fn update(&mut self) -> Result<()> {
+        let now = Instant::now();
+        self.socket.manual_poll(now);
+        while let Some(event) = self.socket.recv() {
+            let packet = match event {
+                SocketEvent::Packet(packet) => packet,
+                SocketEvent::Timeout(address) | SocketEvent::Disconnect(address) => {
+                    self.clients.remove(&address);
+                    continue;
+                },
+                SocketEvent::Connect(_) => continue,
+            };
+
+            // Registrations only refresh the client
+            let source = packet.addr();
+            self.clients.insert(source, now);
+            let relayed = match protocol::decode_relay(&packet) {
+                Some(relayed) => relayed,
+                None => continue,
+            };
+
+            // Only registered clients can be reached so the relay can't be used to send to arbitrary addresses
+            let destination = relayed.addr();
+            if self.clients.contains_key(&destination) {
+                self.socket.send(protocol::encode_relay(&relayed, destination, source))?;
+            }
+        }
+
+        self.clients.retain(|_, seen| now - *seen < EXPIRY);
+        Ok(())
+    }
+}
+
+impl Debug for RelayServer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayServer").field("clients", &self.clients).finish()
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::decode_message;
+use super::Delivery;
+use super::NetworkEvent;
+use super::Socket;
+
+#[derive(Serialize, Deserialize)]
+enum RendezvousMessage {
+    Register { session: String },
+    Introduce { peer: SocketAddr },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RendezvousEvent {
+    // Both clients are now connecting to each other, the usual connect events follow
+    Introduced(SocketAddr),
+    // Connecting directly failed and the connection is being retried through the relay
+    Relayed(SocketAddr),
+}
+
+// Runs on a publicly reachable socket and pairs up clients that register the same session
+#[derive(Debug, Default)]
+pub struct RendezvousServer {
+    waiting: HashMap<String, SocketAddr>,
+}
+
+impl RendezvousServer {
+    pub fn new() -> RendezvousServer {
+        RendezvousServer::default()
+    }
+
+    pub fn handle_event(&mut self, socket: &Socket, event: &NetworkEvent) {
+        match event {
+            NetworkEvent::Message(packet) => {
+                let session = match decode_message::<RendezvousMessage>(packet) {
+                    Ok(RendezvousMessage::Register { session }) => session,
+                    _ => return,
+                };
+
+                let address = packet.addr();
+                match self.waiting.remove(&session) {
+                    Some(peer) if peer != address => {
+                        introduce(socket, address, peer);
+                        introduce(socket, peer, address);
+                    },
+                    _ => {
+                        self.waiting.insert(session, address);
+                    },
+                }
+            },
+            NetworkEvent::Disconnect(address, _) | NetworkEvent::Timeout(address, _) => {
+                self.waiting.retain(|_, waiting| waiting != address);
+            },
+            _ => (),
+        }
+    }
+}
+
+fn introduce(socket: &Socket, address: SocketAddr, peer: SocketAddr) {
+    let _ = socket.send_message(address, &RendezvousMessage::Introduce { peer }, Delivery::ReliableUnordered);
+}
+
+#[derive(Debug)]
+pub struct Rendezvous {
+    coordinator: SocketAddr,
+    session: String,
+    relay: Option<SocketAddr>,
+    // Introduced peers that haven't fallen back to the relay yet
+    punching: HashSet<SocketAddr>,
+}
+
+impl Rendezvous {
+    pub fn start(socket: &Socket, coordinator: SocketAddr, session: &str) -> Rendezvous {
+        socket.connect(coordinator);
+        Rendezvous { coordinator, session: session.to_owned(), relay: None, punching: HashSet::new() }
+    }
+
+    // Peers that can't be hole punched are connected through the relay instead, both sides need the same relay
+    pub fn start_with_relay(socket: &Socket, coordinator: SocketAddr, session: &str, relay: SocketAddr) -> Rendezvous {
+        socket.use_relay(relay);
+        Rendezvous { relay: Some(relay), ..Rendezvous::start(socket, coordinator, session) }
+    }
+
+    pub fn handle_event(&mut self, socket: &Socket, event: &NetworkEvent) -> Option<RendezvousEvent> {
+        match event {
+            NetworkEvent::Connect(address) if *address == self.coordinator => {
+                let register = RendezvousMessage::Register { session: self.session.clone() };
+                let _ = socket.send_message(self.coordinator, &register, Delivery::ReliableUnordered);
+                None
+            },
+            NetworkEvent::Message(packet) if packet.addr() == self.coordinator => {
+                match decode_message::<RendezvousMessage>(packet) {
+                    Ok(RendezvousMessage::Introduce { peer }) => {
+                        socket.connect(peer);
+                        self.punching.insert(peer);
+                        Some(RendezvousEvent::Introduced(peer))
+                    },
+                    _ => None,
+                }
+            },
+            NetworkEvent::Connect(address) => {
+                self.punching.remove(address);
+                None
+            },
+            NetworkEvent::ConnectFailed(address, _) if self.punching.remove(address) => {
+                let relay = self.relay?;
+                socket.connect_via(*address, relay).ok()?;
+                Some(RendezvousEvent::Relayed(*address))
+            },
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::Channel;
+use super::NetworkEvent;
+use super::Socket;
+use crate::Result;
+
+// A replicator behind by more than this many ticks drops the rest instead of flushing every frame to catch up
+const MAX_CATCH_UP_TICKS: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ReplicaId(u32);
+
+#[derive(Serialize, Deserialize)]
+enum ReplicationMessage {
+    Spawn { id: ReplicaId, kind: u16, state: Vec<u8> },
+    Update { id: ReplicaId, state: Vec<u8> },
+    Despawn { id: ReplicaId },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplicationEvent {
+    Spawned(ReplicaId, u16),
+    Updated(ReplicaId),
+    Despawned(ReplicaId),
+}
+
+#[derive(Debug)]
+struct Replica {
+    kind: u16,
+    state: Vec<u8>,
+    spawned: bool,
+    dirty: bool,
+}
+
+// Owns the authoritative state, replicas are sent to every connected peer on the given channel
+#[derive(Debug)]
+pub struct Replicator {
+    channel: Channel,
+    tick: Duration,
+    elapsed: Duration,
+    replicas: BTreeMap<ReplicaId, Replica>,
+    despawned: Vec<ReplicaId>,
+    next_id: u32,
+}
+
+impl Replicator {
+    pub fn new(channel: Channel, tick: Duration) -> Replicator {
+        Replicator { channel, tick, elapsed: Duration::default(), replicas: BTreeMap::new(), despawned: vec![], next_id: 0 }
+    }
+
+    pub fn set_tick(&mut self, tick: Duration) -> &mut Self {
+        self.tick = tick;
+        self
+    }
+
+    // The kind is opaque to gear and lets the remote side pick a type to decode the state as
+    pub fn spawn<T: Serialize>(&mut self, kind: u16, object: &T) -> Result<ReplicaId> {
+        let id = ReplicaId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        let state = bincode::serialize(object)?;
+        self.replicas.insert(id, Replica { kind, state, spawned: false, dirty: false });
+        Ok(id)
+    }
+
+    // Only marks the replica dirty when the serialized state actually changed
+    pub fn set<T: Serialize>(&mut self, id: ReplicaId, object: &T) -> Result<&mut Self> {
+        if let Some(replica) = self.replicas.get_mut(&id) {
+            let state = bincode::serialize(object)?;
+            if state != replica.state {
+                replica.state = state;
+                replica.dirty = true;
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn despawn(&mut self, id: ReplicaId) -> &mut Self {
+        if let Some(replica) = self.replicas.remove(&id) {
+            if replica.spawned {
+                self.despawned.push(id);
+            }
+        }
+        self
+    }
+
+    pub fn contains(&self, id: ReplicaId) -> bool {
+        self.replicas.contains_key(&id)
+    }
+
+    // Peers that connect later receive every replica that has already been spawned
+    pub fn handle_event(&mut self, socket: &Socket, event: &NetworkEvent) -> Result<()> {
+        if let NetworkEvent::Connect(address) = event {
+            let snapshot = self
+                .replicas
+                .iter()
+                .filter(|(_, replica)| replica.spawned)
+                .map(|(&id, replica)| ReplicationMessage::Spawn { id, kind: replica.kind, state: replica.state.clone() })
+                .collect::<Vec<_>>();
+
+            if !snapshot.is_empty() {
+                socket.send_on(self.channel, *address, bincode::serialize(&snapshot)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn update(&mut self, socket: &Socket, delta_time: Duration) -> Result<()> {
+        self.elapsed += delta_time;
+        if self.elapsed < self.tick {
+            return Ok(());
+        }
+
+        self.elapsed -= self.tick;
+        self.elapsed = self.elapsed.min(self.tick * MAX_CATCH_UP_TICKS);
+        self.flush(socket)
+    }
+
+    // Sends everything that changed since the last flush without waiting for the tick
+    pub fn flush(&mut self, socket: &Socket) -> Result<()> {
+        let mut messages = self.despawned.drain(..).map(|id| ReplicationMessage::Despawn { id }).collect::<Vec<_>>();
+
+        for (&id, replica) in &mut self.replicas {
+            if !replica.spawned {
+                messages.push(ReplicationMessage::Spawn { id, kind: replica.kind, state: replica.state.clone() });
+            } else if replica.dirty {
+                messages.push(ReplicationMessage::Update { id, state: replica.state.clone() });
+            }
+            replica.spawned = true;
+            replica.dirty = false;
+        }
+
+        if !messages.is_empty() {
+            socket.broadcast(bincode::serialize(&messages)?, self.channel.delivery())?;
+        }
+
+        Ok(())
+    }
+}
+
+// Mirrors the replicas of a single authority, the channel must match the one the authority replicates on
+#[derive(Debug)]
+pub struct Replicas {
+    channel: Channel,
+    authority: SocketAddr,
+    replicas: HashMap<ReplicaId, (u16, Vec<u8>)>,
+}
+
+impl Replicas {
+    pub fn new(channel: Channel, authority: SocketAddr) -> Replicas {
+        Replicas { channel, authority, replicas: HashMap::new() }
+    }
+
+    pub fn handle_event(&mut self, event: &NetworkEvent) -> Vec<ReplicationEvent> {
+        match event {
+            NetworkEvent::Message(packet) if packet.addr() == self.authority && self.channel.matches(packet) => {
+                match bincode::deserialize::<Vec<ReplicationMessage>>(packet.payload()) {
+                    Ok(messages) => messages.into_iter().filter_map(|message| self.apply(message)).collect(),
+                    Err(_) => vec![],
+                }
+            },
+            NetworkEvent::Disconnect(address, _) | NetworkEvent::Timeout(address, _) if *address == self.authority => {
+                self.replicas.drain().map(|(id, _)| ReplicationEvent::Despawned(id)).collect()
+            },
+            _ => vec![],
+        }
+    }
+
+    fn apply(&mut self, message: ReplicationMessage) -> Option<ReplicationEvent> {
+        match message {
+            ReplicationMessage::Spawn { id, kind, state } => match self.replicas.insert(id, (kind, state)) {
+                Some(_) => Some(ReplicationEvent::Updated(id)),
+                None => Some(ReplicationEvent::Spawned(id, kind)),
+            },
+            // Updates can overtake the snapshot of a freshly connected peer, the snapshot carries the same state
+            ReplicationMessage::Update { id, state } => {
+                let (_, current) = self.replicas.get_mut(&id)?;
+                *current = state;
+                Some(ReplicationEvent::Updated(id))
+            },
+            ReplicationMessage::Despawn { id } => {
+                self.replicas.remove(&id)?;
+                Some(ReplicationEvent::Despawned(id))
+            },
+        }
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = ReplicaId> + '_ {
+        self.replicas.keys().copied()
+    }
+
+    pub fn kind(&self, id: ReplicaId) -> Option<u16> {
+        self.replicas.get(&id).map(|(kind, _)| *kind)
+    }
+
+    pub fn state<T: DeserializeOwned>(&self, id: ReplicaId) -> Option<Result<T>> {
+        self.replicas.get(&id).map(|(_, state)| bincode::deserialize(state).map_err(Into::into))
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::{self,};
+use std::net::SocketAddr;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::Channel;
+use super::NetworkEvent;
+use super::Socket;
+use crate::Result;
+
+type Handler = Box<dyn FnMut(SocketAddr, &[u8]) -> Option<Vec<u8>> + Send>;
+type Callback = Box<dyn FnOnce(Option<&[u8]>) + Send>;
+
+#[derive(Serialize, Deserialize)]
+enum RpcMessage {
+    Call { name: String, request: Option<u32>, arguments: Vec<u8> },
+    Response { request: u32, result: Option<Vec<u8>> },
+}
+
+// Both sides have to create the channel in the same order, calls are reliable as long as the channel is
+pub struct Rpc {
+    channel: Channel,
+    handlers: HashMap<String, Handler>,
+    pending: HashMap<u32, (SocketAddr, Callback)>,
+    next_request: u32,
+}
+
+impl Rpc {
+    pub fn new(channel: Channel) -> Rpc {
+        Rpc { channel, handlers: HashMap::new(), pending: HashMap::new(), next_request: 0 }
+    }
+
+    pub fn register<T, F>(&mut self, name: &str, mut handler: F) -> &mut Self
+    where
+        T: DeserializeOwned,
+        F: 'static + FnMut(SocketAddr, T) + Send,
+    {
+        self.register_with_response(name, move |address, arguments: T| handler(address, arguments))
+    }
+
+    // A registered name replaces the previous handler
+    pub fn register_with_response<T, R, F>(&mut self, name: &str, mut handler: F) -> &mut Self
+    where
+        T: DeserializeOwned,
+        R: Serialize,
+        F: 'static + FnMut(SocketAddr, T) -> R + Send,
+    {
+        let handler = move |address, arguments: &[u8]| {
+            let arguments = bincode::deserialize(arguments).ok()?;
+            bincode::serialize(&handler(address, arguments)).ok()
+        };
+        self.handlers.insert(name.to_owned(), Box::new(handler));
+        self
+    }
+
+    pub fn unregister(&mut self, name: &str) -> &mut Self {
+        self.handlers.remove(name);
+        self
+    }
+
+    pub fn call<T: Serialize>(&self, socket: &Socket, address: SocketAddr, name: &str, arguments: &T) -> Result<&Self> {
+        let call = RpcMessage::Call { name: name.to_owned(), request: None, arguments: bincode::serialize(arguments)? };
+        socket.send_on(self.channel, address, bincode::serialize(&call)?)?;
+        Ok(self)
+    }
+
+    pub fn broadcast<T: Serialize>(&self, socket: &Socket, name: &str, arguments: &T) -> Result<&Self> {
+        let call = RpcMessage::Call { name: name.to_owned(), request: None, arguments: bincode::serialize(arguments)? };
+        socket.broadcast(bincode::serialize(&call)?, self.channel.delivery())?;
+        Ok(self)
+    }
+
+    // The callback gets None if the remote has no such handler, the response does not decode, or the peer leaves
+    pub fn request<T, R, F>(
+        &mut self,
+        socket: &Socket,
+        address: SocketAddr,
+        name: &str,
+        arguments: &T,
+        callback: F,
+    ) -> Result<&mut Self>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+        F: 'static + FnOnce(Option<R>) + Send,
+    {
+        let request = self.next_request;
+        self.next_request = self.next_request.wrapping_add(1);
+
+        let call =
+            RpcMessage::Call { name: name.to_owned(), request: Some(request), arguments: bincode::serialize(arguments)? };
+        socket.send_on(self.channel, address, bincode::serialize(&call)?)?;
+
+        let callback = move |result: Option<&[u8]>| callback(result.and_then(|result| bincode::deserialize(result).ok()));
+        self.pending.insert(request, (address, Box::new(callback)));
+        Ok(self)
+    }
+
+    // Returns true if the event was an rpc and has been consumed
+    pub fn handle_event(&mut self, socket: &Socket, event: &NetworkEvent) -> Result<bool> {
+        match event {
+            NetworkEvent::Message(packet) if self.channel.matches(packet) => {
+                let message = match bincode::deserialize::<RpcMessage>(packet.payload()) {
+                    Ok(message) => message,
+                    Err(_) => return Ok(false),
+                };
+
+                match message {
+                    RpcMessage::Call { name, request, arguments } => {
+                        let result = self.handlers.get_mut(&name).and_then(|handler| handler(packet.addr(), &arguments));
+                        if let Some(request) = request {
+                            let response = RpcMessage::Response { request, result };
+                            socket.send_on(self.channel, packet.addr(), bincode::serialize(&response)?)?;
+                        }
+                    },
+                    RpcMessage::Response { request, result } => {
+                        if let Some((address, callback)) = self.pending.remove(&request) {
+                            if address == packet.addr() {
+                                callback(result.as_deref());
+                            } else {
+                                self.pending.insert(request, (address, callback));
+                            }
+                        }
+                    },
+                }
+
+                Ok(true)
+            },
+            NetworkEvent::Disconnect(address, _) | NetworkEvent::Timeout(address, _) => {
+                let requests =
+                    self.pending.iter().filter(|(_, (peer, _))| peer == address).map(|(&id, _)| id).collect::<Vec<_>>();
+                for request in requests {
+                    let (_, callback) = self.pending.remove(&request).unwrap();
+                    callback(None);
+                }
+
+                Ok(false)
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+impl Debug for Rpc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rpc")
+            .field("channel", &self.channel)
+            .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2021 Chay Nabors.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::{self,};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use laminar::Packet;
+use serde::Serialize;
+
+use super::Delivery;
+use super::DisconnectReason;
+use super::NetworkEvent;
+use super::Socket;
+use super::TimeoutReason;
+use crate::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(u32);
+
+// Allocated on connect and kept for the whole session, even if the peer's address changes
+pub struct Peer {
+    id: PeerId,
+    address: SocketAddr,
+    user_data: Option<Box<dyn Any + Send>>,
+}
+
+impl Peer {
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // None when nothing was set or it was set as a different type
+    pub fn user_data<T: 'static>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref()
+    }
+
+    pub fn user_data_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut()?.downcast_mut()
+    }
+
+    pub fn set_user_data<T: 'static + Send>(&mut self, user_data: T) -> &Self {
+        self.user_data = Some(Box::new(user_data));
+        self
+    }
+}
+
+impl Debug for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Peer").field("id", &self.id).field("address", &self.address).finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId, Option<DisconnectReason>),
+    PeerTimedOut(PeerId, TimeoutReason),
+    Message(PeerId, Packet),
+}
+
+#[derive(Debug)]
+pub struct Server {
+    socket: Socket,
+    peers: HashMap<PeerId, Peer>,
+    ids: HashMap<SocketAddr, PeerId>,
+    next_id: u32,
+}
+
+impl Server {
+    pub fn new(socket: Socket, max_peers: usize) -> Server {
+        Server::with_filter(socket, max_peers, |_| true)
+    }
+
+    // The filter is only consulted while the server has room for another peer
+    pub fn with_filter<F: 'static + FnMut(SocketAddr) -> bool + Send>(
+        socket: Socket,
+        max_peers: usize,
+        filter: F,
+    ) -> Server {
+        // The worker counts its peers as it accepts them, the game thread only hears about them later
+        socket.set_max_peers(Some(max_peers)).set_connection_filter(filter);
+
+        Server { socket, peers: HashMap::new(), ids: HashMap::new(), next_id: 0 }
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    // The shared timeline, clients estimate it with Client::server_time
+    pub fn time(&self) -> Duration {
+        self.socket.local_time()
+    }
+
+    pub fn handle_event(&mut self, event: NetworkEvent) -> Option<ServerEvent> {
+        match event {
+            NetworkEvent::Connect(address) => {
+                let id = PeerId(self.next_id);
+                self.next_id = self.next_id.wrapping_add(1);
+                self.peers.insert(id, Peer { id, address, user_data: None });
+                self.ids.insert(address, id);
+                Some(ServerEvent::PeerConnected(id))
+            },
+            NetworkEvent::Message(packet) => self.ids.get(&packet.addr()).map(|&id| ServerEvent::Message(id, packet)),
+            NetworkEvent::Disconnect(address, reason) => {
+                self.remove(address).map(|id| ServerEvent::PeerDisconnected(id, reason))
+            },
+            NetworkEvent::Timeout(address, reason) => self.remove(address).map(|id| ServerEvent::PeerTimedOut(id, reason)),
+            NetworkEvent::AddressChanged(previous, address) => {
+                let id = self.ids.remove(&previous)?;
+                self.ids.insert(address, id);
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.address = address;
+                }
+                None
+            },
+            NetworkEvent::ConnectFailed(..) | NetworkEvent::Congested(..) | NetworkEvent::Error(..) => None,
+        }
+    }
+
+    fn remove(&mut self, address: SocketAddr) -> Option<PeerId> {
+        let id = self.ids.remove(&address)?;
+        self.peers.remove(&id);
+        Some(id)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.peers.keys().copied()
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn address(&self, peer: PeerId) -> Option<SocketAddr> {
+        self.peers.get(&peer).map(Peer::address)
+    }
+
+    pub fn get(&self, peer: PeerId) -> Option<&Peer> {
+        self.peers.get(&peer)
+    }
+
+    pub fn get_mut(&mut self, peer: PeerId) -> Option<&mut Peer> {
+        self.peers.get_mut(&peer)
+    }
+
+    pub fn peer(&self, address: SocketAddr) -> Option<PeerId> {
+        self.ids.get(&address).copied()
+    }
+
+    pub fn send(&self, peer: PeerId, payload: Vec<u8>, delivery: Delivery) -> Result<&Self> {
+        if let Some(address) = self.address(peer) {
+            self.socket.send(delivery.packet(address, payload))?;
+        }
+        Ok(self)
+    }
+
+    pub fn send_message<T: Serialize>(&self, peer: PeerId, message: &T, delivery: Delivery) -> Result<&Self> {
+        if let Some(address) = self.address(peer) {
+            self.socket.send_message(address, message, delivery)?;
+        }
+        Ok(self)
+    }
+
+    pub fn broadcast(&self, payload: Vec<u8>, delivery: Delivery) -> Result<&Self> {
+        self.socket.broadcast(payload, delivery)?;
+        Ok(self)
+    }
+
+    pub fn broadcast_except(&self, peer: PeerId, payload: Vec<u8>, delivery: Delivery) -> Result<&Self> {
+        match self.address(peer) {
+            Some(address) => self.socket.broadcast_except(address, payload, delivery)?,
+            None => self.socket.broadcast(payload, delivery)?,
+        };
+        Ok(self)
+    }
+
+    pub fn kick(&self, peer: PeerId) -> &Self {
+        if let Some(address) = self.address(peer) {
+            self.socket.disconnect(address);
+        }
+        self
+    }
+
+    pub fn kick_with_reason(&self, peer: PeerId, reason: DisconnectReason) -> &Self {
+        if let Some(address) = self.address(peer) {
+            self.socket.disconnect_peer(address, reason);
+        }
+        self
+    }
+}
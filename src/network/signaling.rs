@@ -0,0 +1,28 @@
+// Copyright 2021 Chay Nabors.
+
+#[cfg(not(target_arch = "wasm32"))]
+mod server;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::server::SignalingServer;
+
+// Browsers say hello this often, the server forgets the ones it hasn't heard from in three times as long
+pub(crate) const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// What WebRTC peers trade through the signaling server to open a connection. The peer is who the signal is for on the
+// way in and who sent it on the way out
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Signal {
+    Hello,
+    // The address the server knows the client by, the one other peers send to
+    Welcome { address: SocketAddr },
+    Offer { peer: SocketAddr, sdp: String },
+    Answer { peer: SocketAddr, sdp: String },
+    Candidate { peer: SocketAddr, candidate: String, sdp_mid: Option<String>, sdp_m_line_index: Option<u16> },
+}
@@ -0,0 +1,187 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::{self,};
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+use std::time::Instant;
+
+use laminar::Packet;
+use laminar::SocketEvent;
+
+use super::Signal;
+use super::KEEPALIVE_INTERVAL;
+use crate::network::transport::StreamTransport;
+use crate::network::transport::TransportBackend;
+use crate::network::transport::WebSocketConnection;
+use crate::Result;
+
+const EXPIRY: Duration = Duration::from_secs(KEEPALIVE_INTERVAL.as_secs() * 3);
+
+impl Signal {
+    // Swaps who the signal is for with who sent it, None for the ones that aren't forwarded
+    fn forward(self, source: SocketAddr) -> Option<(SocketAddr, Signal)> {
+        match self {
+            Signal::Offer { peer, sdp } => Some((peer, Signal::Offer { peer: source, sdp })),
+            Signal::Answer { peer, sdp } => Some((peer, Signal::Answer { peer: source, sdp })),
+            Signal::Candidate { peer, candidate, sdp_mid, sdp_m_line_index } => {
+                Some((peer, Signal::Candidate { peer: source, candidate, sdp_mid, sdp_m_line_index }))
+            },
+            Signal::Hello | Signal::Welcome { .. } => None,
+        }
+    }
+}
+
+// Introduces browsers to each other so they can open WebRTC connections, run it where every browser can reach it.
+// Nothing but the negotiation goes through it, peers talk directly once connected
+pub struct SignalingServer {
+    transport: StreamTransport<WebSocketConnection>,
+    clients: HashSet<SocketAddr>,
+}
+
+impl SignalingServer {
+    pub fn bind<A: ToSocketAddrs>(addresses: A) -> Result<SignalingServer> {
+        Ok(SignalingServer { transport: StreamTransport::bind(addresses, EXPIRY)?, clients: HashSet::new() })
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    // Forwards everything that arrived since the last call, call it regularly
+    pub fn update(&mut self) -> Result<()> {
+        self.transport.poll(Instant::now());
+        while let Some(event) = self.transport.recv() {
+            let packet = match event {
+                SocketEvent::Packet(packet) => packet,
+                SocketEvent::Timeout(address) | SocketEvent::Disconnect(address) => {
+                    self.clients.remove(&address);
+                    continue;
+                },
+                SocketEvent::Connect(_) => continue,
+            };
+
+            let source = packet.addr();
+            let signal = match bincode::deserialize::<Signal>(packet.payload()) {
+                Ok(signal) => signal,
+                Err(_) => continue,
+            };
+
+            if signal == Signal::Hello {
+                self.clients.insert(source);
+                self.send(source, &Signal::Welcome { address: source })?;
+                continue;
+            }
+
+            // Only clients that said hello can be reached so the server can't be used to send to arbitrary addresses
+            match signal.forward(source) {
+                Some((destination, signal)) if self.clients.contains(&source) && self.clients.contains(&destination) => {
+                    self.send(destination, &signal)?;
+                },
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Clients that already left are skipped, sending to them would dial them
+    fn send(&mut self, address: SocketAddr, signal: &Signal) -> Result<()> {
+        if self.transport.is_connected(address) {
+            self.transport.send(Packet::reliable_ordered(address, bincode::serialize(signal)?, None))?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for SignalingServer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignalingServer").field("clients", &self.clients).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use laminar::Packet;
+    use laminar::SocketEvent;
+
+    use super::Signal;
+    use super::SignalingServer;
+    use crate::network::transport::StreamTransport;
+    use crate::network::transport::TransportBackend;
+    use crate::network::transport::WebSocketConnection;
+
+    type Client = StreamTransport<WebSocketConnection>;
+
+    fn client() -> Client {
+        StreamTransport::bind("127.0.0.1:0", Duration::from_secs(5)).unwrap()
+    }
+
+    fn send(client: &mut Client, server: SocketAddr, signal: &Signal) {
+        client.send(Packet::reliable_ordered(server, bincode::serialize(signal).unwrap(), None)).unwrap();
+    }
+
+    fn receive(server: &mut SignalingServer, client: &mut Client) -> Signal {
+        for _ in 0..200 {
+            client.poll(Instant::now());
+            server.update().unwrap();
+            if let Some(SocketEvent::Packet(packet)) = client.recv() {
+                return bincode::deserialize(packet.payload()).unwrap();
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("nothing arrived");
+    }
+
+    fn welcome(server: &mut SignalingServer, client: &mut Client) -> SocketAddr {
+        let server_address = server.transport.local_addr().unwrap();
+        send(client, server_address, &Signal::Hello);
+        match receive(server, client) {
+            Signal::Welcome { address } => address,
+            signal => panic!("expected a welcome, got {:?}", signal),
+        }
+    }
+
+    #[test]
+    fn forwards_between_clients() {
+        let mut server = SignalingServer::bind("127.0.0.1:0").unwrap();
+        let server_address = server.transport.local_addr().unwrap();
+        let mut first = client();
+        let mut second = client();
+        let first_address = welcome(&mut server, &mut first);
+        let second_address = welcome(&mut server, &mut second);
+        assert_eq!(server.clients(), 2);
+
+        let sdp = "v=0".to_owned();
+        send(&mut first, server_address, &Signal::Offer { peer: second_address, sdp: sdp.clone() });
+        assert_eq!(receive(&mut server, &mut second), Signal::Offer { peer: first_address, sdp });
+    }
+
+    #[test]
+    fn strangers_are_ignored() {
+        let mut server = SignalingServer::bind("127.0.0.1:0").unwrap();
+        let server_address = server.transport.local_addr().unwrap();
+        let mut stranger = client();
+        let mut second = client();
+        let second_address = welcome(&mut server, &mut second);
+
+        // Never said hello, so it can't reach anyone
+        send(&mut stranger, server_address, &Signal::Offer { peer: second_address, sdp: "v=0".to_owned() });
+        for _ in 0..40 {
+            stranger.poll(Instant::now());
+            server.update().unwrap();
+            second.poll(Instant::now());
+            assert!(second.recv().is_none());
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(server.clients(), 1);
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2021 Chay Nabors.
+
+use std::time::Duration;
+
+use instant::Instant;
+use laminar::DeliveryGuarantee;
+use laminar::OrderingGuarantee;
+use laminar::Packet;
+use rand::Rng;
+
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+// Applied to outgoing packets, enable it on both ends to degrade traffic in both directions. Over udp loss happens
+// under laminar so lost reliable packets are resent, the other transports only lose unreliable ones. Only unreliable
+// packets are duplicated and only unordered ones get jitter and reordering, above laminar that would break guarantees
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkSimulation {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub packet_loss: f32,
+    pub duplication: f32,
+    pub reordering: f32,
+}
+
+#[derive(Debug)]
+pub(crate) struct Simulator {
+    settings: NetworkSimulation,
+    queue: Vec<(Instant, Packet)>,
+    // The transport drops datagrams itself, under its own resends
+    conditioned: bool,
+}
+
+impl Simulator {
+    pub(crate) fn new(settings: NetworkSimulation, conditioned: bool) -> Simulator {
+        Simulator { settings, queue: vec![], conditioned }
+    }
+
+    pub(crate) fn enqueue(&mut self, packet: Packet, now: Instant) {
+        let mut rng = rand::thread_rng();
+        let unreliable = packet.delivery_guarantee() == DeliveryGuarantee::Unreliable;
+        if unreliable && !self.conditioned && rng.gen::<f32>() < self.settings.packet_loss {
+            return;
+        }
+
+        // A constant delay keeps ordered packets in the order they were sent
+        if packet.order_guarantee() != OrderingGuarantee::None {
+            self.queue.push((now + self.settings.latency, packet));
+            return;
+        }
+
+        let copies = if unreliable && rng.gen::<f32>() < self.settings.duplication { 2 } else { 1 };
+        for _ in 0..copies {
+            let jitter = self.settings.jitter.as_secs_f32() * rng.gen_range(-1.0..1.0);
+            let mut delay = Duration::from_secs_f32((self.settings.latency.as_secs_f32() + jitter).max(0.));
+            if rng.gen::<f32>() < self.settings.reordering {
+                delay += self.settings.latency + self.settings.jitter + REORDER_DELAY;
+            }
+
+            self.queue.push((now + delay, packet.clone()));
+        }
+    }
+
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<Packet> {
+        let (due, pending): (Vec<_>, Vec<_>) = self.queue.drain(..).partition(|(time, _)| *time <= now);
+        self.queue = pending;
+
+        let mut due = due;
+        due.sort_by_key(|(time, _)| *time);
+        due.into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.queue.iter().map(|(time, _)| *time).min()
+    }
+
+    pub(crate) fn into_pending(self) -> Vec<Packet> {
+        self.queue.into_iter().map(|(_, packet)| packet).collect()
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::Channel;
+use super::NetworkEvent;
+use super::Socket;
+use crate::Result;
+
+// Unchanged gaps shorter than this are cheaper to resend than to start a new run
+const MIN_GAP: usize = 3;
+// The largest frame the stream transports carry, no snapshot sent in one message is bigger
+const MAX_SNAPSHOT_SIZE: usize = 1 << 24;
+
+#[derive(Serialize, Deserialize)]
+enum SnapshotMessage {
+    Snapshot { sequence: u32, baseline: Option<u32>, delta: Vec<u8> },
+    Ack { sequence: u32 },
+}
+
+// Bincode lays fixed size fields out at fixed offsets, so a byte level diff only carries the changed fields
+pub fn encode_delta(baseline: &[u8], current: &[u8]) -> Vec<u8> {
+    let mut delta = vec![];
+    write_varint(&mut delta, current.len());
+
+    let changed = |i: usize| baseline.get(i).copied().unwrap_or(0) != current[i];
+    let mut position = 0;
+    let mut i = 0;
+    while i < current.len() {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut end = i + 1;
+        while end < current.len() && (end..(end + MIN_GAP).min(current.len())).any(changed) {
+            end += 1;
+        }
+
+        write_varint(&mut delta, start - position);
+        write_varint(&mut delta, end - start);
+        delta.extend_from_slice(&current[start..end]);
+        position = end;
+        i = end;
+    }
+
+    delta
+}
+
+pub fn apply_delta(baseline: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut delta = delta;
+    let len = read_varint(&mut delta)?;
+    // Taken from the peer, and bytes left at zero aren't carried so the delta itself doesn't bound it
+    if len > MAX_SNAPSHOT_SIZE {
+        return None;
+    }
+
+    let mut current = baseline.to_vec();
+    current.resize(len, 0);
+
+    let mut position = 0;
+    while !delta.is_empty() {
+        let start = position.checked_add(read_varint(&mut delta)?)?;
+        let run = read_varint(&mut delta)?;
+        let end = start.checked_add(run)?;
+        if end > len || run > delta.len() {
+            return None;
+        }
+
+        current[start..end].copy_from_slice(&delta[..run]);
+        delta = &delta[run..];
+        position = end;
+    }
+
+    Some(current)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(buffer: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = buffer.split_first()?;
+        *buffer = rest;
+        value |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+// Snapshots are delta encoded per peer against the newest snapshot that peer acknowledged
+#[derive(Debug)]
+pub struct SnapshotSender {
+    channel: Channel,
+    capacity: usize,
+    sequence: u32,
+    history: VecDeque<(u32, Vec<u8>)>,
+    baselines: HashMap<SocketAddr, Option<u32>>,
+}
+
+impl SnapshotSender {
+    // The channel should be unreliable, lost snapshots are superseded rather than resent
+    pub fn new(channel: Channel, capacity: usize) -> SnapshotSender {
+        SnapshotSender { channel, capacity, sequence: 0, history: VecDeque::new(), baselines: HashMap::new() }
+    }
+
+    pub fn handle_event(&mut self, event: &NetworkEvent) -> bool {
+        match event {
+            NetworkEvent::Connect(address) => {
+                self.baselines.insert(*address, None);
+                false
+            },
+            NetworkEvent::Disconnect(address, _) | NetworkEvent::Timeout(address, _) => {
+                self.baselines.remove(address);
+                false
+            },
+            NetworkEvent::Message(packet) if self.channel.matches(packet) => {
+                match bincode::deserialize::<SnapshotMessage>(packet.payload()) {
+                    Ok(SnapshotMessage::Ack { sequence }) => {
+                        if let Some(baseline) = self.baselines.get_mut(&packet.addr()) {
+                            if baseline.map_or(true, |baseline| sequence > baseline) && sequence <= self.sequence {
+                                *baseline = Some(sequence);
+                            }
+                        }
+                        true
+                    },
+                    _ => false,
+                }
+            },
+            _ => false,
+        }
+    }
+
+    pub fn send<T: Serialize>(&mut self, socket: &Socket, snapshot: &T) -> Result<u32> {
+        let current = bincode::serialize(snapshot)?;
+        self.sequence += 1;
+
+        let history = &self.history;
+        for (&address, baseline) in &mut self.baselines {
+            // Baselines that fell out of the history are forgotten and the peer gets a full snapshot
+            let state = baseline.and_then(|sequence| history.iter().find(|(s, _)| *s == sequence));
+            if state.is_none() {
+                *baseline = None;
+            }
+
+            let delta = encode_delta(state.map_or(&[][..], |(_, state)| state.as_slice()), &current);
+            let message = SnapshotMessage::Snapshot { sequence: self.sequence, baseline: *baseline, delta };
+            socket.send_on(self.channel, address, bincode::serialize(&message)?)?;
+        }
+
+        self.history.push_back((self.sequence, current));
+        while self.history.len() > self.capacity {
+            self.history.pop_front();
+        }
+
+        Ok(self.sequence)
+    }
+
+    pub fn acked(&self, address: SocketAddr) -> Option<u32> {
+        self.baselines.get(&address).copied().flatten()
+    }
+}
+
+#[derive(Debug)]
+pub struct SnapshotReceiver {
+    channel: Channel,
+    authority: SocketAddr,
+    capacity: usize,
+    history: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl SnapshotReceiver {
+    // The capacity should be at least that of the sender, otherwise baselines can go missing
+    pub fn new(channel: Channel, authority: SocketAddr, capacity: usize) -> SnapshotReceiver {
+        SnapshotReceiver { channel, authority, capacity, history: VecDeque::new() }
+    }
+
+    // Returns the snapshot carried by the event if it is newer than every snapshot received so far
+    pub fn handle_event<T: DeserializeOwned>(&mut self, socket: &Socket, event: &NetworkEvent) -> Result<Option<T>> {
+        let packet = match event {
+            NetworkEvent::Message(packet) if packet.addr() == self.authority && self.channel.matches(packet) => packet,
+            _ => return Ok(None),
+        };
+
+        let (sequence, baseline, delta) = match bincode::deserialize::<SnapshotMessage>(packet.payload()) {
+            Ok(SnapshotMessage::Snapshot { sequence, baseline, delta }) => (sequence, baseline, delta),
+            _ => return Ok(None),
+        };
+
+        if self.history.back().map_or(false, |(latest, _)| sequence <= *latest) {
+            return Ok(None);
+        }
+
+        let state = match baseline {
+            Some(baseline) => match self.history.iter().find(|(s, _)| *s == baseline) {
+                Some((_, state)) => apply_delta(state, &delta),
+                None => None,
+            },
+            None => apply_delta(&[], &delta),
+        };
+
+        let state = match state {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+
+        let snapshot = bincode::deserialize(&state)?;
+        socket.send_on(self.channel, self.authority, bincode::serialize(&SnapshotMessage::Ack { sequence })?)?;
+
+        self.history.push_back((sequence, state));
+        while self.history.len() > self.capacity {
+            self.history.pop_front();
+        }
+
+        Ok(Some(snapshot))
+    }
+
+    pub fn latest(&self) -> Option<u32> {
+        self.history.back().map(|(sequence, _)| *sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply_delta;
+    use super::encode_delta;
+    use super::write_varint;
+    use super::MAX_SNAPSHOT_SIZE;
+
+    #[test]
+    fn round_trip() {
+        let baseline = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let changed = [1, 2, 0, 4, 5, 6, 7, 8, 9, 10, 0, 12];
+        let grown = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 0, 0];
+        let shrunk = [1, 2, 3, 9];
+        for current in [&changed[..], &grown[..], &shrunk[..], &baseline[..], &[][..]].iter() {
+            assert_eq!(apply_delta(&baseline, &encode_delta(&baseline, current)).as_deref(), Some(*current));
+            assert_eq!(apply_delta(&[], &encode_delta(&[], current)).as_deref(), Some(*current));
+        }
+    }
+
+    #[test]
+    fn truncated_delta() {
+        let baseline = [0; 16];
+        let current = [7; 16];
+        let delta = encode_delta(&baseline, &current);
+        // The length alone is a whole delta that changes nothing, every cut after it is short of a run
+        assert_eq!(apply_delta(&baseline, &delta[..1]).as_deref(), Some(&baseline[..]));
+        for len in 2..delta.len() {
+            assert_eq!(apply_delta(&baseline, &delta[..len]), None);
+        }
+    }
+
+    #[test]
+    fn oversized_delta() {
+        let mut delta = vec![];
+        write_varint(&mut delta, MAX_SNAPSHOT_SIZE + 1);
+        assert_eq!(apply_delta(&[], &delta), None);
+    }
+}
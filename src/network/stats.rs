@@ -0,0 +1,156 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use instant::Instant;
+
+const PING_INTERVAL: Duration = Duration::from_millis(250);
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+const LOSS_WINDOW: usize = 64;
+const RTT_SMOOTHING: f32 = 0.1;
+const CLOCK_SMOOTHING: f64 = 0.1;
+// Pongs that took much longer than usual were likely queued somewhere and say little about the remote clock
+const CLOCK_OUTLIER: f32 = 2.;
+// Keeps the resend estimate finite when nearly everything is lost
+const MAX_RESEND_LOSS: f64 = 0.99;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NetworkStats {
+    pub rtt: Duration,
+    pub packet_loss: f32,
+    pub bytes_sent_per_second: u64,
+    pub bytes_received_per_second: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    // Not a count, laminar resends every reliable packet that is lost without reporting it. Estimated from the reliable
+    // packets sent and the packet loss the pings measured at the time
+    pub estimated_resends: f64,
+    // Bytes held back by the rate limit
+    pub queued_bytes: usize,
+    // Largest datagram payload known to reach the peer, starts out at a size every path supports
+    pub mtu: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct PeerStats {
+    stats: NetworkStats,
+    window_start: Instant,
+    window_sent: u64,
+    window_received: u64,
+    next_ping: u32,
+    last_ping: Option<Instant>,
+    pending_pings: VecDeque<(u32, Instant)>,
+    ping_results: VecDeque<bool>,
+    // Seconds to add to the local socket clock to get the remote one
+    clock_offset: Option<f64>,
+}
+
+impl PeerStats {
+    pub(crate) fn new(now: Instant) -> PeerStats {
+        PeerStats {
+            stats: NetworkStats::default(),
+            window_start: now,
+            window_sent: 0,
+            window_received: 0,
+            next_ping: 0,
+            last_ping: None,
+            pending_pings: VecDeque::new(),
+            ping_results: VecDeque::with_capacity(LOSS_WINDOW),
+            clock_offset: None,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    pub(crate) fn clock_offset(&self) -> Option<f64> {
+        self.clock_offset
+    }
+
+    // Packet loss is only trusted once the whole window has been measured
+    pub(crate) fn loss_measured(&self) -> bool {
+        self.ping_results.len() == LOSS_WINDOW
+    }
+
+    // A reliable packet lost with probability p is sent 1 / (1 - p) times on average
+    pub(crate) fn record_sent(&mut self, bytes: usize, reliable: bool) {
+        self.window_sent += bytes as u64;
+        self.stats.packets_sent += 1;
+        if reliable {
+            let loss = (self.stats.packet_loss as f64).min(MAX_RESEND_LOSS);
+            self.stats.estimated_resends += loss / (1. - loss);
+        }
+    }
+
+    pub(crate) fn record_received(&mut self, bytes: usize) {
+        self.window_received += bytes as u64;
+        self.stats.packets_received += 1;
+    }
+
+    // Returns the sequence of a ping to send when one is due
+    pub(crate) fn update(&mut self, now: Instant) -> Option<u32> {
+        let elapsed = now - self.window_start;
+        if elapsed >= Duration::from_secs(1) {
+            let seconds = elapsed.as_secs_f32();
+            self.stats.bytes_sent_per_second = (self.window_sent as f32 / seconds) as u64;
+            self.stats.bytes_received_per_second = (self.window_received as f32 / seconds) as u64;
+            self.window_start = now;
+            self.window_sent = 0;
+            self.window_received = 0;
+        }
+
+        while let Some(&(_, sent)) = self.pending_pings.front() {
+            if now - sent < PING_TIMEOUT {
+                break;
+            }
+            self.pending_pings.pop_front();
+            self.record_ping(false);
+        }
+
+        if self.last_ping.map_or(true, |last| now - last >= PING_INTERVAL) {
+            let sequence = self.next_ping;
+            self.next_ping = self.next_ping.wrapping_add(1);
+            self.last_ping = Some(now);
+            self.pending_pings.push_back((sequence, now));
+            return Some(sequence);
+        }
+
+        None
+    }
+
+    // The remote time was read when the pong was sent, which is assumed to be halfway through the round trip
+    pub(crate) fn receive_pong(&mut self, sequence: u32, remote_time: Duration, now: Instant, local_time: Duration) {
+        let index = match self.pending_pings.iter().position(|&(pending, _)| pending == sequence) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let (_, sent) = self.pending_pings.remove(index).unwrap();
+        let sample = now - sent;
+        let offset = remote_time.as_secs_f64() + sample.as_secs_f64() / 2. - local_time.as_secs_f64();
+        self.clock_offset = match self.clock_offset {
+            None => Some(offset),
+            Some(previous) if sample > self.stats.rtt.mul_f32(CLOCK_OUTLIER) => Some(previous),
+            Some(previous) => Some(previous * (1. - CLOCK_SMOOTHING) + offset * CLOCK_SMOOTHING),
+        };
+
+        self.stats.rtt = if self.stats.rtt == Duration::default() {
+            sample
+        } else {
+            self.stats.rtt.mul_f32(1. - RTT_SMOOTHING) + sample.mul_f32(RTT_SMOOTHING)
+        };
+        self.record_ping(true);
+    }
+
+    fn record_ping(&mut self, arrived: bool) {
+        if self.ping_results.len() == LOSS_WINDOW {
+            self.ping_results.pop_front();
+        }
+        self.ping_results.push_back(arrived);
+
+        let lost = self.ping_results.iter().filter(|&&arrived| !arrived).count();
+        self.stats.packet_loss = lost as f32 / self.ping_results.len() as f32;
+    }
+}
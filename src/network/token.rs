@@ -0,0 +1,139 @@
+// Copyright 2021 Chay Nabors.
+
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::SystemTime;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::UNIX_EPOCH;
+
+use hmac::Hmac;
+use hmac::Mac;
+use hmac::NewMac;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::Result;
+
+pub type TokenKey = [u8; 32];
+
+// Issued by a backend that shares the key with the game servers, clients can read but not forge them
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectToken {
+    pub client_id: u64,
+    // Seconds since the unix epoch
+    pub expires: u64,
+    pub user_data: Vec<u8>,
+    mac: Vec<u8>,
+}
+
+impl ConnectToken {
+    pub fn issue(key: &TokenKey, client_id: u64, lifetime: Duration, user_data: Vec<u8>) -> ConnectToken {
+        let expires = (unix_time() + lifetime).as_secs();
+        let mac = sign(key, client_id, expires, &user_data);
+        ConnectToken { client_id, expires, user_data, mac }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ConnectToken> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn is_valid(&self, key: &TokenKey) -> bool {
+        if unix_time().as_secs() >= self.expires {
+            return false;
+        }
+
+        let mut mac = mac(key);
+        mac.update(&payload(self.client_id, self.expires, &self.user_data));
+        mac.verify(&self.mac).is_ok()
+    }
+
+    pub(crate) fn mac(&self) -> &[u8] {
+        &self.mac
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn unix_time() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+// Browsers have no system clock outside of javascript
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn unix_time() -> Duration {
+    Duration::from_millis(js_sys::Date::now() as u64)
+}
+
+fn mac(key: &TokenKey) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key).unwrap()
+}
+
+fn payload(client_id: u64, expires: u64, user_data: &[u8]) -> Vec<u8> {
+    let mut payload = client_id.to_le_bytes().to_vec();
+    payload.extend_from_slice(&expires.to_le_bytes());
+    payload.extend_from_slice(user_data);
+    payload
+}
+
+fn sign(key: &TokenKey, client_id: u64, expires: u64, user_data: &[u8]) -> Vec<u8> {
+    let mut mac = mac(key);
+    mac.update(&payload(client_id, expires, user_data));
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::sign;
+    use super::unix_time;
+    use super::ConnectToken;
+
+    const KEY: [u8; 32] = [7; 32];
+    const LIFETIME: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn valid_token() {
+        let token = ConnectToken::issue(&KEY, 42, LIFETIME, vec![1, 2, 3]);
+        assert!(token.is_valid(&KEY));
+
+        let token = ConnectToken::from_bytes(&token.to_bytes().unwrap()).unwrap();
+        assert!(token.is_valid(&KEY));
+        assert!(!token.is_valid(&[8; 32]));
+    }
+
+    #[test]
+    fn tampered_token() {
+        let token = ConnectToken::issue(&KEY, 42, LIFETIME, vec![1, 2, 3]);
+
+        let mut tampered = token.clone();
+        tampered.client_id = 43;
+        assert!(!tampered.is_valid(&KEY));
+
+        let mut tampered = token.clone();
+        tampered.user_data[0] = 0;
+        assert!(!tampered.is_valid(&KEY));
+
+        let mut tampered = token.clone();
+        tampered.expires += 60;
+        assert!(!tampered.is_valid(&KEY));
+
+        let mut tampered = token;
+        tampered.mac[0] ^= 1;
+        assert!(!tampered.is_valid(&KEY));
+    }
+
+    #[test]
+    fn expired_token() {
+        assert!(!ConnectToken::issue(&KEY, 42, Duration::default(), vec![]).is_valid(&KEY));
+
+        // Signed properly but expired a second ago
+        let expires = unix_time().as_secs() - 1;
+        let token = ConnectToken { client_id: 42, expires, user_data: vec![], mac: sign(&KEY, 42, expires, &[]) };
+        assert!(!token.is_valid(&KEY));
+    }
+}
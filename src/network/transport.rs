@@ -0,0 +1,67 @@
+// Copyright 2021 Chay Nabors.
+
+#[cfg(not(target_arch = "wasm32"))]
+mod dual_stack;
+mod loopback;
+#[cfg(not(target_arch = "wasm32"))]
+mod readiness;
+mod relay;
+#[cfg(not(target_arch = "wasm32"))]
+mod stream;
+#[cfg(not(target_arch = "wasm32"))]
+mod tcp;
+#[cfg(not(target_arch = "wasm32"))]
+mod udp;
+#[cfg(target_arch = "wasm32")]
+mod webrtc;
+#[cfg(not(target_arch = "wasm32"))]
+mod websocket;
+
+use std::net::SocketAddr;
+
+use instant::Instant;
+use laminar::Packet;
+use laminar::SocketEvent;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::dual_stack::DualStack;
+pub(crate) use self::loopback::LoopbackTransport;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::readiness::Readiness;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::readiness::Source;
+pub(crate) use self::relay::Relayed;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::stream::StreamTransport;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::tcp::TcpConnection;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::udp::UdpTransport;
+#[cfg(target_arch = "wasm32")]
+pub(crate) use self::webrtc::WebRtcTransport;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::websocket::WebSocketConnection;
+use crate::Result;
+
+// Everything the socket worker needs from the layer that actually moves bytes
+pub(crate) trait TransportBackend: Send {
+    fn send(&mut self, packet: Packet) -> Result<()>;
+    fn poll(&mut self, now: Instant);
+    fn recv(&mut self) -> Option<SocketEvent>;
+
+    // Drops that share of outgoing datagrams under the backend's own resends, false when it has none to do it under
+    fn set_packet_loss(&mut self, _packet_loss: f32) -> bool {
+        false
+    }
+
+    // The sockets recv reads from, for the async worker to wait on. None when there are none and it has to poll
+    #[cfg(not(target_arch = "wasm32"))]
+    fn sources(&self) -> Option<Vec<Source<'_>>> {
+        None
+    }
+
+    // The address peers reach this end at, for backends that are handed one instead of binding it
+    fn signaled_address(&self) -> Option<SocketAddr> {
+        None
+    }
+}
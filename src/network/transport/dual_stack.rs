@@ -0,0 +1,86 @@
+// Copyright 2021 Chay Nabors.
+
+use std::io::ErrorKind;
+use std::io::{self,};
+use std::net::IpAddr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::time::Instant;
+
+use laminar::Packet;
+use laminar::SocketEvent;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+
+use super::Source;
+use super::TransportBackend;
+use super::UdpTransport;
+use crate::network::normalize_address;
+use crate::network::protocol::{self,};
+use crate::network::SocketConfig;
+use crate::Result;
+
+// A single IPv6 udp socket that also serves IPv4, addresses are normalized so each peer has exactly one address
+pub(crate) struct DualStack {
+    socket: UdpTransport,
+}
+
+impl DualStack {
+    pub(crate) fn bind(addresses: &[SocketAddr], config: SocketConfig) -> Result<DualStack> {
+        let address = addresses.first().copied().ok_or_else(|| io::Error::from(ErrorKind::AddrNotAvailable))?;
+        let ip = match address.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => Ipv6Addr::UNSPECIFIED,
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::new(IpAddr::V6(ip), address.port()).into())?;
+        socket.set_nonblocking(!config.blocking_mode)?;
+
+        Ok(DualStack { socket: UdpTransport::from_socket(UdpSocket::from(socket), config)? })
+    }
+}
+
+fn to_v6(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        SocketAddr::V6(_) => address,
+    }
+}
+
+impl TransportBackend for DualStack {
+    fn send(&mut self, packet: Packet) -> Result<()> {
+        let packet = protocol::with_payload(&packet, to_v6(packet.addr()), packet.payload().to_vec());
+        TransportBackend::send(&mut self.socket, packet)
+    }
+
+    fn poll(&mut self, now: Instant) {
+        TransportBackend::poll(&mut self.socket, now);
+    }
+
+    fn recv(&mut self) -> Option<SocketEvent> {
+        let event = match TransportBackend::recv(&mut self.socket)? {
+            SocketEvent::Packet(packet) => {
+                let address = normalize_address(packet.addr());
+                SocketEvent::Packet(protocol::with_payload(&packet, address, packet.payload().to_vec()))
+            },
+            SocketEvent::Connect(address) => SocketEvent::Connect(normalize_address(address)),
+            SocketEvent::Timeout(address) => SocketEvent::Timeout(normalize_address(address)),
+            SocketEvent::Disconnect(address) => SocketEvent::Disconnect(normalize_address(address)),
+        };
+        Some(event)
+    }
+
+    fn set_packet_loss(&mut self, packet_loss: f32) -> bool {
+        TransportBackend::set_packet_loss(&mut self.socket, packet_loss)
+    }
+
+    fn sources(&self) -> Option<Vec<Source<'_>>> {
+        self.socket.sources()
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::io::{self,};
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use socket2::SockRef;
+use tokio::io::Interest;
+use tokio::net::{self,};
+
+// A socket a backend reads from. The id stays the same while the socket is open, a new socket gets a new one
+pub(crate) struct Source<'a> {
+    pub(crate) id: u64,
+    pub(crate) socket: SockRef<'a>,
+    pub(crate) datagram: bool,
+    // Also waits for the socket to become writable, for connects that haven't finished and writes that were held back
+    pub(crate) writable: bool,
+}
+
+// Listeners are registered as streams, tokio has no other way to wait on them without accepting
+enum Registration {
+    Datagram(net::UdpSocket),
+    Stream(net::TcpStream),
+}
+
+impl Registration {
+    fn new(source: &Source) -> io::Result<Registration> {
+        let socket = source.socket.try_clone()?;
+        match source.datagram {
+            true => Ok(Registration::Datagram(net::UdpSocket::from_std(socket.into())?)),
+            false => Ok(Registration::Stream(net::TcpStream::from_std(socket.into())?)),
+        }
+    }
+
+    fn poll_ready(&self, writable: bool, cx: &mut Context<'_>) -> bool {
+        match self {
+            Registration::Datagram(socket) => {
+                socket.poll_recv_ready(cx).is_ready() || (writable && socket.poll_send_ready(cx).is_ready())
+            },
+            Registration::Stream(stream) => {
+                stream.poll_read_ready(cx).is_ready() || (writable && stream.poll_write_ready(cx).is_ready())
+            },
+        }
+    }
+
+    fn try_io(&self, interest: Interest, f: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        match self {
+            Registration::Datagram(socket) => socket.try_io(interest, f),
+            Registration::Stream(stream) => stream.try_io(interest, f),
+        }
+    }
+}
+
+// A backend's sockets registered with tokio, for the async worker to wait on instead of polling. The registrations are
+// clones, the backend keeps reading from its own sockets
+#[derive(Default)]
+pub(crate) struct Readiness {
+    registrations: HashMap<u64, (Registration, bool)>,
+}
+
+impl Readiness {
+    // Registers the sockets that are new and drops those the backend closed, a clone kept around would hold them open
+    pub(crate) fn update(&mut self, sources: Vec<Source>) -> io::Result<()> {
+        let mut registrations = HashMap::with_capacity(sources.len());
+        let mut result = Ok(());
+        for source in sources {
+            let registration = match self.registrations.remove(&source.id) {
+                Some((registration, _)) => registration,
+                None => match Registration::new(&source) {
+                    Ok(registration) => registration,
+                    Err(e) => {
+                        result = Err(e);
+                        continue;
+                    },
+                },
+            };
+
+            registrations.insert(source.id, (registration, source.writable));
+        }
+
+        self.registrations = registrations;
+        result
+    }
+
+    pub(crate) fn ready(&self) -> Ready<'_> {
+        Ready { readiness: self }
+    }
+
+    // Readiness is only cleared for the sockets nothing arrived on while the poll ran, so nothing is missed meanwhile
+    pub(crate) fn poll(&self, mut poll: impl FnMut()) {
+        let registrations = self.registrations.values().collect::<Vec<_>>();
+        clear_around(&registrations, &mut poll);
+    }
+}
+
+// Tokio only clears readiness inside try_io, each ready socket's snapshot is taken before the poll and cleared after it
+fn clear_around(registrations: &[&(Registration, bool)], poll: &mut dyn FnMut()) {
+    let ((registration, writable), rest) = match registrations.split_first() {
+        Some(first) => first,
+        None => return poll(),
+    };
+
+    let interest = if *writable { Interest::READABLE | Interest::WRITABLE } else { Interest::READABLE };
+    let mut polled = false;
+    let _ = registration.try_io(interest, || {
+        polled = true;
+        clear_around(rest, poll);
+        Err(ErrorKind::WouldBlock.into())
+    });
+
+    if !polled {
+        clear_around(rest, poll);
+    }
+}
+
+// Resolves once any of the sockets may have something to read, or room to write for those waiting on it
+pub(crate) struct Ready<'a> {
+    readiness: &'a Readiness,
+}
+
+impl Future for Ready<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut ready = false;
+        for (registration, writable) in self.readiness.registrations.values() {
+            // Every socket is polled so each one has the waker registered
+            ready |= registration.poll_ready(*writable, cx);
+        }
+
+        if ready {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use socket2::SockRef;
+    use tokio::time::{self,};
+
+    use super::Readiness;
+    use super::Source;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn cleared_once_drained() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+
+        let mut readiness = Readiness::default();
+        let source = Source { id: 0, socket: SockRef::from(&socket), datagram: true, writable: false };
+        readiness.update(vec![source]).unwrap();
+        readiness.poll(|| ());
+        assert!(time::timeout(WAIT, readiness.ready()).await.is_err());
+
+        sender.send_to(&[1], address).unwrap();
+        assert!(time::timeout(WAIT, readiness.ready()).await.is_ok());
+
+        // Cleared once the poll has read it
+        readiness.poll(|| while socket.recv(&mut [0; 16]).is_ok() {});
+        assert!(time::timeout(WAIT, readiness.ready()).await.is_err());
+
+        // Arriving while the poll runs keeps it ready
+        readiness.poll(|| {
+            sender.send_to(&[2], address).unwrap();
+        });
+        assert!(time::timeout(WAIT, readiness.ready()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn closed_sources_are_dropped() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        let mut readiness = Readiness::default();
+        let source = Source { id: 7, socket: SockRef::from(&socket), datagram: true, writable: false };
+        readiness.update(vec![source]).unwrap();
+        assert_eq!(readiness.registrations.len(), 1);
+        readiness.update(vec![]).unwrap();
+        assert!(readiness.registrations.is_empty());
+    }
+}
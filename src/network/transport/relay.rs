@@ -0,0 +1,123 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use instant::Instant;
+use laminar::Packet;
+use laminar::SocketEvent;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::Source;
+use super::TransportBackend;
+use crate::network::protocol::PacketKind;
+use crate::network::protocol::{self,};
+use crate::Result;
+
+// Relays forget clients that go quiet for a few seconds, this keeps the allocation alive
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Wraps any transport so peers behind a relay still look like plain addresses to the worker
+pub(crate) struct Relayed {
+    inner: Box<dyn TransportBackend>,
+    // Peer to the relay that reaches it and when the peer was last heard from through it
+    routes: HashMap<SocketAddr, (SocketAddr, Instant)>,
+    // Relay to when it is next sent a keepalive
+    relays: HashMap<SocketAddr, Instant>,
+    // The transport only sees the relay, which keepalives hold open, so relayed peers are timed out here
+    idle_timeout: Duration,
+    timeouts: VecDeque<SocketAddr>,
+}
+
+impl Relayed {
+    pub(crate) fn new(inner: Box<dyn TransportBackend>, idle_timeout: Duration) -> Relayed {
+        Relayed { inner, routes: HashMap::new(), relays: HashMap::new(), idle_timeout, timeouts: VecDeque::new() }
+    }
+
+    // Registering is what lets the relay forward traffic from peers that have never been routed through it
+    pub(crate) fn register(&mut self, relay: SocketAddr, now: Instant) {
+        self.relays.entry(relay).or_insert(now);
+    }
+
+    pub(crate) fn route(&mut self, peer: SocketAddr, relay: Option<SocketAddr>, now: Instant) {
+        match relay {
+            Some(relay) => {
+                self.register(relay, now);
+                self.routes.insert(peer, (relay, now));
+            },
+            None => {
+                self.routes.remove(&peer);
+            },
+        }
+    }
+}
+
+impl TransportBackend for Relayed {
+    fn send(&mut self, packet: Packet) -> Result<()> {
+        match self.routes.get(&packet.addr()) {
+            Some(&(relay, _)) => self.inner.send(protocol::encode_relay(&packet, relay, packet.addr())),
+            None => self.inner.send(packet),
+        }
+    }
+
+    fn poll(&mut self, now: Instant) {
+        for (&relay, next_keepalive) in &mut self.relays {
+            if now >= *next_keepalive {
+                *next_keepalive = now + KEEPALIVE_INTERVAL;
+                let _ = self.inner.send(protocol::unreliable_control(PacketKind::RelayRegister, relay, &[]));
+            }
+        }
+
+        let idle_timeout = self.idle_timeout;
+        let timeouts = &mut self.timeouts;
+        self.routes.retain(|&peer, (_, last_received)| {
+            let idle = now - *last_received >= idle_timeout;
+            if idle {
+                timeouts.push_back(peer);
+            }
+            !idle
+        });
+
+        self.inner.poll(now);
+    }
+
+    fn recv(&mut self) -> Option<SocketEvent> {
+        if let Some(peer) = self.timeouts.pop_front() {
+            return Some(SocketEvent::Timeout(peer));
+        }
+
+        loop {
+            let packet = match self.inner.recv()? {
+                SocketEvent::Packet(packet) if self.relays.contains_key(&packet.addr()) => packet,
+                // The worker never knew the relay as a peer, so its connection events mean nothing there
+                SocketEvent::Connect(address) | SocketEvent::Timeout(address) | SocketEvent::Disconnect(address)
+                    if self.relays.contains_key(&address) =>
+                {
+                    continue
+                },
+                event => return Some(event),
+            };
+
+            // Anything else from a relay is its own traffic and never reaches the worker
+            if let Some(relayed) = protocol::decode_relay(&packet) {
+                self.routes.insert(relayed.addr(), (packet.addr(), Instant::now()));
+                return Some(SocketEvent::Packet(relayed));
+            }
+        }
+    }
+
+    fn set_packet_loss(&mut self, packet_loss: f32) -> bool {
+        self.inner.set_packet_loss(packet_loss)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn sources(&self) -> Option<Vec<Source<'_>>> {
+        self.inner.sources()
+    }
+
+    fn signaled_address(&self) -> Option<SocketAddr> {
+        self.inner.signaled_address()
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::io::{self,};
+use std::iter::{self,};
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+use std::time::Instant;
+
+use laminar::Packet;
+use laminar::SocketEvent;
+use log::error;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::SockRef;
+use socket2::Socket;
+use socket2::Type;
+
+use super::Source;
+use super::TransportBackend;
+use crate::Result;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A framed, nonblocking connection over a tcp stream
+pub(crate) trait Connection: Send + Sized {
+    fn accept(stream: TcpStream) -> Self;
+    fn connect(stream: TcpStream, address: SocketAddr) -> Self;
+    fn send(&mut self, payload: Vec<u8>) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    // Ok(None) when no complete message is available yet, Err once the connection is gone
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>>;
+    // None once the connection has failed
+    fn stream(&self) -> Option<&TcpStream>;
+    // Whether writes are held back until the stream has room for them
+    fn is_blocked(&self) -> bool;
+}
+
+// A nonblocking connect that hasn't completed yet, with what was sent to it meanwhile
+struct Dialing {
+    socket: Socket,
+    source: u64,
+    started: Instant,
+    queued: Vec<Vec<u8>>,
+}
+
+struct Open<C> {
+    connection: C,
+    // Carried over from the dial, so the async worker can tell it apart from a later connection to the same peer
+    source: u64,
+    last_received: Instant,
+}
+
+pub(crate) struct StreamTransport<C: Connection> {
+    listener: TcpListener,
+    connections: HashMap<SocketAddr, Open<C>>,
+    dialing: HashMap<SocketAddr, Dialing>,
+    // Half open connections never fail a read, so connections that go quiet are timed out here
+    idle_timeout: Duration,
+    // The listener is source zero
+    next_source: u64,
+    events: VecDeque<SocketEvent>,
+}
+
+impl<C: Connection> StreamTransport<C> {
+    pub(crate) fn bind<A: ToSocketAddrs>(addresses: A, idle_timeout: Duration) -> Result<StreamTransport<C>> {
+        let listener = TcpListener::bind(addresses)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(StreamTransport {
+            listener,
+            connections: HashMap::new(),
+            dialing: HashMap::new(),
+            idle_timeout,
+            next_source: 1,
+            events: VecDeque::new(),
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Sending to a peer that isn't connected dials it, servers that only answer check this first
+    pub(crate) fn is_connected(&self, address: SocketAddr) -> bool {
+        self.connections.contains_key(&address)
+    }
+
+    fn next_source(&mut self) -> u64 {
+        let source = self.next_source;
+        self.next_source += 1;
+        source
+    }
+
+    fn open(stream: &TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)
+    }
+
+    // Nonblocking connects return an error while they're in progress, failures are read back as the socket is polled
+    fn dial(address: SocketAddr) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        let _ = socket.connect(&address.into());
+        Ok(socket)
+    }
+
+    // Connects are done once the socket has a peer, until then writing to them would fail
+    fn poll_dialing(&mut self, now: Instant) {
+        let mut finished = vec![];
+        for (address, dialing) in &self.dialing {
+            let result = match dialing.socket.take_error() {
+                Ok(None) if dialing.socket.peer_addr().is_ok() => Some(Ok(())),
+                Ok(None) if now - dialing.started >= CONNECT_TIMEOUT => Some(Err(io::Error::from(ErrorKind::TimedOut))),
+                Ok(None) => None,
+                Ok(Some(e)) | Err(e) => Some(Err(e)),
+            };
+
+            if let Some(result) = result {
+                finished.push((*address, result));
+            }
+        }
+
+        for (address, result) in finished {
+            let dialing = self.dialing.remove(&address).unwrap();
+            let stream = TcpStream::from(dialing.socket);
+            if let Err(e) = result.and_then(|_| Self::open(&stream)) {
+                // The worker fails the connect like it would have if nothing had answered
+                error!("Failed to connect to {}: {}", address, e);
+                self.events.push_back(SocketEvent::Timeout(address));
+                continue;
+            }
+
+            let mut connection = C::connect(stream, address);
+            if dialing.queued.into_iter().try_for_each(|payload| connection.send(payload)).is_err() {
+                self.events.push_back(SocketEvent::Disconnect(address));
+                continue;
+            }
+            self.connections.insert(address, Open { connection, source: dialing.source, last_received: now });
+        }
+    }
+
+    fn close(&mut self, address: SocketAddr) {
+        if self.connections.remove(&address).is_some() {
+            self.events.push_back(SocketEvent::Disconnect(address));
+        }
+    }
+}
+
+impl<C: Connection> TransportBackend for StreamTransport<C> {
+    fn send(&mut self, packet: Packet) -> Result<()> {
+        let address = packet.addr();
+        if !self.connections.contains_key(&address) {
+            if !self.dialing.contains_key(&address) {
+                let socket = Self::dial(address)?;
+                let source = self.next_source();
+                self.dialing.insert(address, Dialing { socket, source, started: Instant::now(), queued: vec![] });
+            }
+
+            self.dialing.get_mut(&address).unwrap().queued.push(packet.payload().to_vec());
+            return Ok(());
+        }
+
+        // Streams are already reliable and ordered, delivery guarantees don't apply
+        let result = self.connections.get_mut(&address).unwrap().connection.send(packet.payload().to_vec());
+        if let Err(e) = result {
+            self.close(address);
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self, now: Instant) {
+        self.poll_dialing(now);
+
+        loop {
+            match self.listener.accept() {
+                Ok((stream, address)) => match Self::open(&stream) {
+                    Ok(()) => {
+                        let open = Open { connection: C::accept(stream), source: self.next_source(), last_received: now };
+                        self.connections.insert(address, open);
+                    },
+                    Err(e) => error!("Failed to configure connection from {}: {}", address, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    break;
+                },
+            }
+        }
+
+        let mut closed = vec![];
+        let mut idle = vec![];
+        for (address, open) in &mut self.connections {
+            loop {
+                match open.connection.recv() {
+                    Ok(Some(payload)) => {
+                        open.last_received = now;
+                        self.events.push_back(SocketEvent::Packet(Packet::reliable_ordered(*address, payload, None)))
+                    },
+                    Ok(None) => break,
+                    Err(_) => {
+                        closed.push(*address);
+                        break;
+                    },
+                }
+            }
+
+            if open.connection.flush().is_err() {
+                closed.push(*address);
+            } else if now - open.last_received >= self.idle_timeout {
+                idle.push(*address);
+            }
+        }
+
+        for address in closed {
+            self.close(address);
+        }
+
+        for address in idle {
+            self.connections.remove(&address);
+            self.events.push_back(SocketEvent::Timeout(address));
+        }
+    }
+
+    fn recv(&mut self) -> Option<SocketEvent> {
+        self.events.pop_front()
+    }
+
+    // Connects that are still dialing finish once the socket becomes writable
+    fn sources(&self) -> Option<Vec<Source<'_>>> {
+        let listener = Source { id: 0, socket: SockRef::from(&self.listener), datagram: false, writable: false };
+        let dialing = self.dialing.values().map(|dialing| Source {
+            id: dialing.source,
+            socket: SockRef::from(&dialing.socket),
+            datagram: false,
+            writable: true,
+        });
+        let connections = self.connections.values().filter_map(|open| {
+            let stream = open.connection.stream()?;
+            Some(Source {
+                id: open.source,
+                socket: SockRef::from(stream),
+                datagram: false,
+                writable: open.connection.is_blocked(),
+            })
+        });
+
+        Some(iter::once(listener).chain(dialing).chain(connections).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use laminar::Packet;
+    use laminar::SocketEvent;
+
+    use super::StreamTransport;
+    use super::TransportBackend;
+    use crate::network::transport::TcpConnection;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn quiet_connections_time_out() {
+        let mut server = StreamTransport::<TcpConnection>::bind("127.0.0.1:0", IDLE_TIMEOUT).unwrap();
+        let mut client = StreamTransport::<TcpConnection>::bind("127.0.0.1:0", IDLE_TIMEOUT).unwrap();
+        let server_address = server.listener.local_addr().unwrap();
+        client.send(Packet::reliable_ordered(server_address, vec![1, 2, 3], None)).unwrap();
+
+        let mut received = None;
+        for _ in 0..200 {
+            client.poll(Instant::now());
+            server.poll(Instant::now());
+            if let Some(SocketEvent::Packet(packet)) = server.recv() {
+                assert_eq!(packet.payload(), [1, 2, 3]);
+                received = Some((packet.addr(), Instant::now()));
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let (client_address, received) = received.expect("the client never connected");
+        server.poll(received + IDLE_TIMEOUT / 2);
+        assert!(server.recv().is_none());
+        server.poll(received + IDLE_TIMEOUT);
+        assert!(matches!(server.recv(), Some(SocketEvent::Timeout(address)) if address == client_address));
+        assert!(server.connections.is_empty());
+    }
+}
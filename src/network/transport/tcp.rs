@@ -0,0 +1,106 @@
+// Copyright 2021 Chay Nabors.
+
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::io::{self,};
+use std::net::SocketAddr;
+use std::net::TcpStream;
+
+use super::stream::Connection;
+
+const HEADER_SIZE: usize = 4;
+const MAX_FRAME_SIZE: usize = 1 << 24;
+const READ_CHUNK_SIZE: usize = 4096;
+
+// Frames are a little endian u32 length followed by the payload
+pub(crate) struct TcpConnection {
+    stream: TcpStream,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+}
+
+impl TcpConnection {
+    fn new(stream: TcpStream) -> TcpConnection {
+        TcpConnection { stream, read_buffer: vec![], write_buffer: vec![] }
+    }
+
+    fn take_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.read_buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let len = u32::from_le_bytes(self.read_buffer[..HEADER_SIZE].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(ErrorKind::InvalidData.into());
+        }
+
+        if self.read_buffer.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+
+        let frame = self.read_buffer[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+        self.read_buffer.drain(..HEADER_SIZE + len);
+        Ok(Some(frame))
+    }
+}
+
+impl Connection for TcpConnection {
+    fn accept(stream: TcpStream) -> TcpConnection {
+        TcpConnection::new(stream)
+    }
+
+    fn connect(stream: TcpStream, _address: SocketAddr) -> TcpConnection {
+        TcpConnection::new(stream)
+    }
+
+    fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        self.write_buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.write_buffer.extend_from_slice(&payload);
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.write_buffer.is_empty() {
+            match self.stream.write(&self.write_buffer) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.write_buffer.drain(..written);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            if let Some(frame) = self.take_frame()? {
+                return Ok(Some(frame));
+            }
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(ErrorKind::ConnectionAborted.into()),
+                Ok(read) => self.read_buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn stream(&self) -> Option<&TcpStream> {
+        Some(&self.stream)
+    }
+
+    fn is_blocked(&self) -> bool {
+        !self.write_buffer.is_empty()
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2021 Chay Nabors.
+
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::time::Instant;
+
+use laminar::LinkConditioner;
+use laminar::Packet;
+use laminar::SocketEvent;
+use socket2::SockRef;
+
+use super::Source;
+use super::TransportBackend;
+use crate::network::SocketConfig;
+use crate::Result;
+
+// Laminar over a udp socket. Laminar doesn't hand its socket out, so a clone of it is kept to wait on
+pub(crate) struct UdpTransport {
+    socket: laminar::Socket,
+    source: UdpSocket,
+    blocking: bool,
+}
+
+impl UdpTransport {
+    pub(crate) fn bind(addresses: &[SocketAddr], config: SocketConfig) -> Result<UdpTransport> {
+        UdpTransport::from_socket(UdpSocket::bind(addresses)?, config)
+    }
+
+    pub(crate) fn from_socket(socket: UdpSocket, config: SocketConfig) -> Result<UdpTransport> {
+        let source = socket.try_clone()?;
+        let blocking = config.blocking_mode;
+        Ok(UdpTransport { socket: laminar::Socket::from_udp_socket(socket, config)?, source, blocking })
+    }
+}
+
+impl TransportBackend for UdpTransport {
+    fn send(&mut self, packet: Packet) -> Result<()> {
+        Ok(self.socket.send(packet)?)
+    }
+
+    fn poll(&mut self, now: Instant) {
+        self.socket.manual_poll(now);
+    }
+
+    fn recv(&mut self) -> Option<SocketEvent> {
+        self.socket.recv()
+    }
+
+    fn set_packet_loss(&mut self, packet_loss: f32) -> bool {
+        let conditioner = match packet_loss > 0. {
+            true => {
+                let mut conditioner = LinkConditioner::new();
+                conditioner.set_packet_loss(packet_loss);
+                Some(conditioner)
+            },
+            false => None,
+        };
+
+        self.socket.set_link_conditioner(conditioner);
+        true
+    }
+
+    // A blocking socket already waits inside the poll
+    fn sources(&self) -> Option<Vec<Source<'_>>> {
+        if self.blocking {
+            return None;
+        }
+
+        Some(vec![Source { id: 0, socket: SockRef::from(&self.source), datagram: true, writable: false }])
+    }
+}
@@ -0,0 +1,533 @@
+// Copyright 2021 Chay Nabors.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::mem::{self,};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use instant::Instant;
+use js_sys::Reflect;
+use js_sys::Uint8Array;
+use laminar::DeliveryGuarantee;
+use laminar::Packet;
+use laminar::SocketEvent;
+use log::error;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::spawn_local;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::BinaryType;
+use web_sys::MessageEvent;
+use web_sys::RtcConfiguration;
+use web_sys::RtcDataChannel;
+use web_sys::RtcDataChannelInit;
+use web_sys::RtcDataChannelState;
+use web_sys::RtcDataChannelType;
+use web_sys::RtcIceCandidateInit;
+use web_sys::RtcIceConnectionState;
+use web_sys::RtcIceServer;
+use web_sys::RtcPeerConnection;
+use web_sys::RtcPeerConnectionIceEvent;
+use web_sys::RtcSdpType;
+use web_sys::RtcSessionDescriptionInit;
+use web_sys::WebSocket;
+
+use super::TransportBackend;
+use crate::network::signaling::Signal;
+use crate::network::signaling::KEEPALIVE_INTERVAL;
+use crate::GearError;
+use crate::Result;
+
+// Finding a path can take a while, especially through a turn server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Both ends create the channels themselves under these ids, so neither waits for the other to announce them
+const RELIABLE_CHANNEL: u16 = 0;
+const UNRELIABLE_CHANNEL: u16 = 1;
+
+type Callback = Closure<dyn FnMut(JsValue)>;
+
+fn callback<F: 'static + FnMut(JsValue)>(f: F) -> Callback {
+    Closure::wrap(Box::new(f) as Box<dyn FnMut(JsValue)>)
+}
+
+fn js_error(e: JsValue) -> GearError {
+    GearError::WebRtcError(format!("{:?}", e))
+}
+
+fn bytes(event: JsValue) -> Vec<u8> {
+    Uint8Array::new(&event.unchecked_into::<MessageEvent>().data()).to_vec()
+}
+
+// What the browser's callbacks hand over, they run between polls
+#[derive(Default)]
+struct Inbox {
+    signals: VecDeque<Signal>,
+    // Held until the signaling server has answered the first hello
+    outgoing: VecDeque<Signal>,
+    packets: VecDeque<Packet>,
+    // Peers whose offer or answer couldn't be made
+    failed: Vec<SocketAddr>,
+    signaling_closed: bool,
+}
+
+// Candidates can arrive before the description they belong to, they wait here until it's set
+#[derive(Default)]
+struct Negotiation {
+    described: bool,
+    candidates: Vec<RtcIceCandidateInit>,
+    // Set once the peer is replaced or dropped, whatever its negotiation still comes up with is stale
+    abandoned: bool,
+}
+
+struct Peer {
+    connection: RtcPeerConnection,
+    reliable: RtcDataChannel,
+    unreliable: RtcDataChannel,
+    negotiation: Rc<RefCell<Negotiation>>,
+    // Whether this end made the offer
+    offered: bool,
+    opened: bool,
+    started: Instant,
+    last_received: Instant,
+    // Sent once both channels are open
+    queued: Vec<Packet>,
+    _callbacks: Vec<Callback>,
+}
+
+impl Peer {
+    fn new(
+        address: SocketAddr,
+        ice_servers: &[String],
+        inbox: &Rc<RefCell<Inbox>>,
+        offered: bool,
+        now: Instant,
+    ) -> Result<Peer> {
+        let mut configuration = RtcConfiguration::new();
+        let servers = ice_servers.iter().map(|url| {
+            let mut server = RtcIceServer::new();
+            server.urls(&JsValue::from_str(url));
+            server
+        });
+        configuration.ice_servers(&servers.collect::<js_sys::Array>());
+        let connection = RtcPeerConnection::new_with_configuration(&configuration).map_err(js_error)?;
+
+        let reliable = Peer::channel(&connection, "reliable", RELIABLE_CHANNEL);
+        let unreliable = Peer::channel(&connection, "unreliable", UNRELIABLE_CHANNEL);
+
+        let candidates = inbox.clone();
+        let on_candidate = callback(move |event| {
+            // The last one has no candidate, it only says gathering is done
+            if let Some(candidate) = event.unchecked_into::<RtcPeerConnectionIceEvent>().candidate() {
+                candidates.borrow_mut().outgoing.push_back(Signal::Candidate {
+                    peer: address,
+                    candidate: candidate.candidate(),
+                    sdp_mid: candidate.sdp_mid(),
+                    sdp_m_line_index: candidate.sdp_m_line_index(),
+                });
+            }
+        });
+        let on_reliable = Peer::receive(inbox, address, true);
+        let on_unreliable = Peer::receive(inbox, address, false);
+        connection.set_onicecandidate(Some(on_candidate.as_ref().unchecked_ref()));
+        reliable.set_onmessage(Some(on_reliable.as_ref().unchecked_ref()));
+        unreliable.set_onmessage(Some(on_unreliable.as_ref().unchecked_ref()));
+
+        Ok(Peer {
+            connection,
+            reliable,
+            unreliable,
+            negotiation: Rc::default(),
+            offered,
+            opened: false,
+            started: now,
+            last_received: now,
+            queued: vec![],
+            _callbacks: vec![on_candidate, on_reliable, on_unreliable],
+        })
+    }
+
+    // Unreliable packets go out once without resends, like they would over udp
+    fn channel(connection: &RtcPeerConnection, label: &str, id: u16) -> RtcDataChannel {
+        let mut init = RtcDataChannelInit::new();
+        init.negotiated(true).id(id);
+        if id == UNRELIABLE_CHANNEL {
+            init.ordered(false).max_retransmits(0);
+        }
+
+        let channel = connection.create_data_channel_with_data_channel_dict(label, &init);
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+        channel
+    }
+
+    // Reliable packets arrive in order, the worker only looks at the guarantee to tell the two apart
+    fn receive(inbox: &Rc<RefCell<Inbox>>, address: SocketAddr, reliable: bool) -> Callback {
+        let inbox = inbox.clone();
+        callback(move |event| {
+            let payload = bytes(event);
+            let packet = match reliable {
+                true => Packet::reliable_ordered(address, payload, None),
+                false => Packet::unreliable(address, payload),
+            };
+            inbox.borrow_mut().packets.push_back(packet);
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        self.reliable.ready_state() == RtcDataChannelState::Open
+            && self.unreliable.ready_state() == RtcDataChannelState::Open
+    }
+
+    fn send(&self, packet: &Packet) -> Result<()> {
+        let channel = match packet.delivery_guarantee() {
+            DeliveryGuarantee::Reliable => &self.reliable,
+            DeliveryGuarantee::Unreliable => &self.unreliable,
+        };
+        channel.send_with_u8_array(packet.payload()).map_err(js_error)
+    }
+
+    fn offer(&self, address: SocketAddr, inbox: &Rc<RefCell<Inbox>>) {
+        let connection = self.connection.clone();
+        let negotiation = self.negotiation.clone();
+        let inbox = inbox.clone();
+        spawn_local(async move {
+            let result = async {
+                let offer = JsFuture::from(connection.create_offer()).await?;
+                let sdp = describe_local(&connection, &offer, RtcSdpType::Offer).await?;
+                Ok::<_, JsValue>(Some(Signal::Offer { peer: address, sdp }))
+            };
+            settle(&inbox, &negotiation, address, result.await);
+        });
+    }
+
+    fn answer(&self, address: SocketAddr, sdp: String, inbox: &Rc<RefCell<Inbox>>) {
+        let connection = self.connection.clone();
+        let negotiation = self.negotiation.clone();
+        let inbox = inbox.clone();
+        spawn_local(async move {
+            let result = async {
+                describe_remote(&connection, &negotiation, RtcSdpType::Offer, &sdp).await?;
+                let answer = JsFuture::from(connection.create_answer()).await?;
+                let sdp = describe_local(&connection, &answer, RtcSdpType::Answer).await?;
+                Ok::<_, JsValue>(Some(Signal::Answer { peer: address, sdp }))
+            };
+            settle(&inbox, &negotiation, address, result.await);
+        });
+    }
+
+    fn accept(&self, address: SocketAddr, sdp: String, inbox: &Rc<RefCell<Inbox>>) {
+        let connection = self.connection.clone();
+        let negotiation = self.negotiation.clone();
+        let inbox = inbox.clone();
+        spawn_local(async move {
+            let result = describe_remote(&connection, &negotiation, RtcSdpType::Answer, &sdp).await;
+            settle(&inbox, &negotiation, address, result.map(|_| None));
+        });
+    }
+
+    fn add_candidate(&self, candidate: RtcIceCandidateInit) {
+        let mut negotiation = self.negotiation.borrow_mut();
+        if !negotiation.described {
+            negotiation.candidates.push(candidate);
+            return;
+        }
+
+        let connection = self.connection.clone();
+        spawn_local(async move {
+            let added = connection.add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&candidate));
+            if let Err(e) = JsFuture::from(added).await {
+                error!("Failed to add ICE candidate: {:?}", e);
+            }
+        });
+    }
+}
+
+impl Drop for Peer {
+    // The callbacks go with the peer, the browser must not call them after this
+    fn drop(&mut self) {
+        self.negotiation.borrow_mut().abandoned = true;
+        self.connection.set_onicecandidate(None);
+        self.reliable.set_onmessage(None);
+        self.unreliable.set_onmessage(None);
+        self.reliable.close();
+        self.unreliable.close();
+        self.connection.close();
+    }
+}
+
+// Sets what create_offer or create_answer came up with as the local description, its sdp is what the remote needs
+async fn describe_local(
+    connection: &RtcPeerConnection,
+    description: &JsValue,
+    kind: RtcSdpType,
+) -> std::result::Result<String, JsValue> {
+    let sdp = Reflect::get(description, &JsValue::from_str("sdp"))?.as_string().unwrap_or_default();
+    let mut init = RtcSessionDescriptionInit::new(kind);
+    init.sdp(&sdp);
+    JsFuture::from(connection.set_local_description(&init)).await?;
+    Ok(sdp)
+}
+
+// Candidates that arrived early are added once the remote description they belong to is set
+async fn describe_remote(
+    connection: &RtcPeerConnection,
+    negotiation: &RefCell<Negotiation>,
+    kind: RtcSdpType,
+    sdp: &str,
+) -> std::result::Result<(), JsValue> {
+    let mut init = RtcSessionDescriptionInit::new(kind);
+    init.sdp(sdp);
+    JsFuture::from(connection.set_remote_description(&init)).await?;
+
+    let candidates = {
+        let mut negotiation = negotiation.borrow_mut();
+        negotiation.described = true;
+        mem::take(&mut negotiation.candidates)
+    };
+    for candidate in candidates {
+        JsFuture::from(connection.add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&candidate))).await?;
+    }
+    Ok(())
+}
+
+fn settle(
+    inbox: &RefCell<Inbox>,
+    negotiation: &RefCell<Negotiation>,
+    address: SocketAddr,
+    result: std::result::Result<Option<Signal>, JsValue>,
+) {
+    if negotiation.borrow().abandoned {
+        return;
+    }
+
+    let mut inbox = inbox.borrow_mut();
+    match result {
+        Ok(Some(signal)) => inbox.outgoing.push_back(signal),
+        Ok(None) => (),
+        Err(e) => {
+            error!("Failed to negotiate a connection with {}: {:?}", address, e);
+            inbox.failed.push(address);
+        },
+    }
+}
+
+// Browser peers over data channels, negotiated through a SignalingServer they keep a websocket open to. Peers are known
+// by the address the server sees them at
+pub(crate) struct WebRtcTransport {
+    signaling: WebSocket,
+    ice_servers: Vec<String>,
+    idle_timeout: Duration,
+    // Handed out by the signaling server
+    address: Option<SocketAddr>,
+    inbox: Rc<RefCell<Inbox>>,
+    peers: HashMap<SocketAddr, Peer>,
+    events: VecDeque<SocketEvent>,
+    next_hello: Instant,
+    _callbacks: Vec<Callback>,
+}
+
+// Browsers run wasm on a single thread and the worker is only ever stepped by manual polling, so it never leaves it
+unsafe impl Send for WebRtcTransport {}
+
+impl WebRtcTransport {
+    pub(crate) fn connect(
+        signaling_url: &str,
+        ice_servers: Vec<String>,
+        idle_timeout: Duration,
+    ) -> Result<WebRtcTransport> {
+        let signaling = WebSocket::new(signaling_url).map_err(js_error)?;
+        signaling.set_binary_type(BinaryType::Arraybuffer);
+        let inbox = Rc::new(RefCell::new(Inbox::default()));
+
+        let signals = inbox.clone();
+        let on_message = callback(move |event| match bincode::deserialize(&bytes(event)) {
+            Ok(signal) => signals.borrow_mut().signals.push_back(signal),
+            Err(e) => error!("Failed to decode signal: {}", e),
+        });
+        let closed = inbox.clone();
+        let on_close = callback(move |_| closed.borrow_mut().signaling_closed = true);
+        signaling.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        signaling.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        Ok(WebRtcTransport {
+            signaling,
+            ice_servers,
+            idle_timeout,
+            address: None,
+            inbox,
+            peers: HashMap::new(),
+            events: VecDeque::new(),
+            next_hello: Instant::now(),
+            _callbacks: vec![on_message, on_close],
+        })
+    }
+
+    fn signal(&self, signal: &Signal) {
+        let sent = bincode::serialize(signal)
+            .map_err(GearError::from)
+            .and_then(|message| self.signaling.send_with_u8_array(&message).map_err(js_error));
+        if let Err(e) = sent {
+            error!("Failed to signal: {:?}", e);
+        }
+    }
+
+    // Offers are held back until the server has answered, without an address of its own this end couldn't settle
+    // whose offer wins when both offer at once
+    fn flush_signals(&mut self, now: Instant) {
+        if self.signaling.ready_state() != WebSocket::OPEN {
+            return;
+        }
+
+        if now >= self.next_hello {
+            self.next_hello = now + KEEPALIVE_INTERVAL;
+            self.signal(&Signal::Hello);
+        }
+
+        if self.address.is_some() {
+            let outgoing = mem::take(&mut self.inbox.borrow_mut().outgoing);
+            for signal in &outgoing {
+                self.signal(signal);
+            }
+        }
+    }
+
+    fn handle_signal(&mut self, signal: Signal, now: Instant) {
+        match signal {
+            Signal::Welcome { address } => self.address = Some(address),
+            Signal::Offer { peer: address, sdp } => {
+                // When both ends offer at once the lower address keeps its offer and the other end answers it
+                let keep = match (self.peers.get(&address), self.address) {
+                    (Some(peer), Some(own)) => peer.offered && own < address,
+                    _ => false,
+                };
+                if keep {
+                    return;
+                }
+
+                let (queued, started) = match self.peers.remove(&address) {
+                    Some(mut peer) => (mem::take(&mut peer.queued), peer.started),
+                    None => (vec![], now),
+                };
+                match Peer::new(address, &self.ice_servers, &self.inbox, false, started) {
+                    Ok(mut peer) => {
+                        peer.queued = queued;
+                        peer.answer(address, sdp, &self.inbox);
+                        self.peers.insert(address, peer);
+                    },
+                    Err(e) => error!("Failed to answer {}: {:?}", address, e),
+                }
+            },
+            Signal::Answer { peer: address, sdp } => {
+                if let Some(peer) = self.peers.get(&address).filter(|peer| peer.offered) {
+                    peer.accept(address, sdp, &self.inbox);
+                }
+            },
+            Signal::Candidate { peer: address, candidate, sdp_mid, sdp_m_line_index } => {
+                if let Some(peer) = self.peers.get(&address) {
+                    let mut init = RtcIceCandidateInit::new(&candidate);
+                    init.sdp_mid(sdp_mid.as_deref()).sdp_m_line_index(sdp_m_line_index);
+                    peer.add_candidate(init);
+                }
+            },
+            Signal::Hello => (),
+        }
+    }
+
+    fn update_peers(&mut self, now: Instant) {
+        let mut closed = vec![];
+        for (&address, peer) in &mut self.peers {
+            if !peer.opened && peer.is_open() {
+                peer.opened = true;
+                peer.last_received = now;
+                for packet in mem::take(&mut peer.queued) {
+                    if let Err(e) = peer.send(&packet) {
+                        error!("Failed to send to {}: {:?}", address, e);
+                    }
+                }
+            }
+
+            let failed = peer.connection.ice_connection_state() == RtcIceConnectionState::Failed;
+            let event = match peer.opened {
+                // The worker fails the connect like it would have if nothing had answered
+                false if failed || now - peer.started >= CONNECT_TIMEOUT => SocketEvent::Timeout(address),
+                true if failed || now - peer.last_received >= self.idle_timeout => SocketEvent::Timeout(address),
+                true if !peer.is_open() => SocketEvent::Disconnect(address),
+                _ => continue,
+            };
+            closed.push((address, event));
+        }
+
+        for (address, event) in closed {
+            self.peers.remove(&address);
+            self.events.push_back(event);
+        }
+    }
+}
+
+impl TransportBackend for WebRtcTransport {
+    fn send(&mut self, packet: Packet) -> Result<()> {
+        let address = packet.addr();
+        if !self.peers.contains_key(&address) {
+            let peer = Peer::new(address, &self.ice_servers, &self.inbox, true, Instant::now())?;
+            peer.offer(address, &self.inbox);
+            self.peers.insert(address, peer);
+        }
+
+        let peer = self.peers.get_mut(&address).unwrap();
+        if !peer.opened {
+            peer.queued.push(packet);
+            return Ok(());
+        }
+        peer.send(&packet)
+    }
+
+    fn poll(&mut self, now: Instant) {
+        let signals = mem::take(&mut self.inbox.borrow_mut().signals);
+        for signal in signals {
+            self.handle_signal(signal, now);
+        }
+        self.flush_signals(now);
+
+        let (packets, failed, signaling_closed) = {
+            let mut inbox = self.inbox.borrow_mut();
+            (mem::take(&mut inbox.packets), mem::take(&mut inbox.failed), mem::take(&mut inbox.signaling_closed))
+        };
+        if signaling_closed {
+            error!("Lost the signaling server, connected peers stay but no new ones can be reached");
+        }
+
+        for packet in packets {
+            if let Some(peer) = self.peers.get_mut(&packet.addr()) {
+                peer.last_received = now;
+                self.events.push_back(SocketEvent::Packet(packet));
+            }
+        }
+
+        for address in failed {
+            if self.peers.remove(&address).is_some() {
+                self.events.push_back(SocketEvent::Timeout(address));
+            }
+        }
+
+        self.update_peers(now);
+    }
+
+    fn recv(&mut self) -> Option<SocketEvent> {
+        self.events.pop_front()
+    }
+
+    fn signaled_address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+}
+
+impl Drop for WebRtcTransport {
+    fn drop(&mut self) {
+        self.signaling.set_onmessage(None);
+        self.signaling.set_onclose(None);
+        let _ = self.signaling.close();
+    }
+}
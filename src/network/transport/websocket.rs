@@ -0,0 +1,155 @@
+// Copyright 2021 Chay Nabors.
+
+use std::io::ErrorKind;
+use std::io::{self,};
+use std::mem;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+
+use tungstenite::handshake::client::ClientHandshake;
+use tungstenite::handshake::server::NoCallback;
+use tungstenite::handshake::server::ServerHandshake;
+use tungstenite::handshake::MidHandshake;
+use tungstenite::Error;
+use tungstenite::HandshakeError;
+use tungstenite::Message;
+use tungstenite::WebSocket;
+
+use super::stream::Connection;
+
+enum State {
+    Accepting(MidHandshake<ServerHandshake<TcpStream, NoCallback>>),
+    Connecting(MidHandshake<ClientHandshake<TcpStream>>),
+    Open(WebSocket<TcpStream>),
+    Failed,
+}
+
+pub(crate) struct WebSocketConnection {
+    state: State,
+    queued: Vec<Vec<u8>>,
+    // Tungstenite keeps what it couldn't write to itself, all that shows is the last write running into a full buffer
+    blocked: bool,
+}
+
+impl WebSocketConnection {
+    fn advance(&mut self) {
+        self.state = match mem::replace(&mut self.state, State::Failed) {
+            State::Accepting(handshake) => match handshake.handshake() {
+                Ok(socket) => State::Open(socket),
+                Err(HandshakeError::Interrupted(handshake)) => State::Accepting(handshake),
+                Err(HandshakeError::Failure(_)) => State::Failed,
+            },
+            State::Connecting(handshake) => match handshake.handshake() {
+                Ok((socket, _)) => State::Open(socket),
+                Err(HandshakeError::Interrupted(handshake)) => State::Connecting(handshake),
+                Err(HandshakeError::Failure(_)) => State::Failed,
+            },
+            state => state,
+        };
+
+        if let State::Open(_) = self.state {
+            for payload in mem::take(&mut self.queued) {
+                let _ = self.send(payload);
+            }
+        }
+    }
+}
+
+impl Connection for WebSocketConnection {
+    fn accept(stream: TcpStream) -> WebSocketConnection {
+        let state = match tungstenite::accept(stream) {
+            Ok(socket) => State::Open(socket),
+            Err(HandshakeError::Interrupted(handshake)) => State::Accepting(handshake),
+            Err(HandshakeError::Failure(_)) => State::Failed,
+        };
+
+        WebSocketConnection { state, queued: vec![], blocked: false }
+    }
+
+    fn connect(stream: TcpStream, address: SocketAddr) -> WebSocketConnection {
+        let state = match tungstenite::client(format!("ws://{}/", address).as_str(), stream) {
+            Ok((socket, _)) => State::Open(socket),
+            Err(HandshakeError::Interrupted(handshake)) => State::Connecting(handshake),
+            Err(HandshakeError::Failure(_)) => State::Failed,
+        };
+
+        WebSocketConnection { state, queued: vec![], blocked: false }
+    }
+
+    fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        match &mut self.state {
+            State::Open(socket) => match socket.write_message(Message::Binary(payload)) {
+                Ok(()) => Ok(()),
+                // The message stays queued inside the socket until the next flush
+                Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                    self.blocked = true;
+                    Ok(())
+                },
+                Err(e) => Err(into_io(e)),
+            },
+            State::Failed => Err(ErrorKind::ConnectionAborted.into()),
+            _ => {
+                self.queued.push(payload);
+                Ok(())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.advance();
+        match &mut self.state {
+            State::Open(socket) => match socket.write_pending() {
+                Ok(()) => {
+                    self.blocked = false;
+                    Ok(())
+                },
+                Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                    self.blocked = true;
+                    Ok(())
+                },
+                Err(e) => Err(into_io(e)),
+            },
+            State::Failed => Err(ErrorKind::ConnectionAborted.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.advance();
+        let socket = match &mut self.state {
+            State::Open(socket) => socket,
+            State::Failed => return Err(ErrorKind::ConnectionAborted.into()),
+            _ => return Ok(None),
+        };
+
+        loop {
+            match socket.read_message() {
+                Ok(Message::Binary(payload)) => return Ok(Some(payload)),
+                Ok(Message::Close(_)) => return Err(ErrorKind::ConnectionAborted.into()),
+                Ok(_) => (),
+                Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(into_io(e)),
+            }
+        }
+    }
+
+    fn stream(&self) -> Option<&TcpStream> {
+        match &self.state {
+            State::Accepting(handshake) => Some(handshake.get_ref().get_ref()),
+            State::Connecting(handshake) => Some(handshake.get_ref().get_ref()),
+            State::Open(socket) => Some(socket.get_ref()),
+            State::Failed => None,
+        }
+    }
+
+    fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+}
+
+fn into_io(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::new(ErrorKind::Other, e.to_string()),
+    }
+}
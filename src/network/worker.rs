@@ -0,0 +1,1142 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crossbeam::channel::Receiver;
+use crossbeam::channel::Sender;
+use crossbeam::channel::{self,};
+use instant::Instant;
+use laminar::DeliveryGuarantee;
+use laminar::Packet;
+use laminar::SocketEvent;
+use log::error;
+use tokio::sync::mpsc::UnboundedSender;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::sync::Notify;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::{self,};
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::address::resolve;
+use super::compression::{self,};
+use super::encryption::Cipher;
+use super::encryption::Handshake;
+use super::encryption::PublicKey;
+use super::encryption::StaticKeypair;
+use super::mtu::MtuDiscovery;
+use super::normalize_address;
+use super::protocol::PacketKind;
+use super::protocol::{self,};
+use super::rate_limit::Limiter;
+use super::simulation::Simulator;
+use super::stats::PeerStats;
+use super::token::unix_time;
+#[cfg(not(target_arch = "wasm32"))]
+use super::transport::DualStack;
+#[cfg(not(target_arch = "wasm32"))]
+use super::transport::Readiness;
+use super::transport::Relayed;
+#[cfg(not(target_arch = "wasm32"))]
+use super::transport::StreamTransport;
+#[cfg(not(target_arch = "wasm32"))]
+use super::transport::TcpConnection;
+use super::transport::TransportBackend;
+#[cfg(not(target_arch = "wasm32"))]
+use super::transport::UdpTransport;
+#[cfg(target_arch = "wasm32")]
+use super::transport::WebRtcTransport;
+#[cfg(not(target_arch = "wasm32"))]
+use super::transport::WebSocketConnection;
+use super::ConnectFailure;
+use super::ConnectToken;
+use super::Delivery;
+use super::DisconnectReason;
+use super::NetworkConfig;
+use super::NetworkEvent;
+use super::NetworkSimulation;
+use super::NetworkStats;
+use super::Priority;
+use super::RateLimit;
+use super::TimeoutReason;
+use super::TokenKey;
+use super::Transport;
+use crate::GearError;
+use crate::Result;
+
+// Reliable packets sent while closing get this long to be resent if they are lost
+const CLOSE_LINGER: Duration = Duration::from_millis(100);
+
+// Connect requests are repeated while pending, this is also what opens NAT mappings for hole punching
+const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+// Async sockets wait on their transport's sockets, when nothing arrives they still step this often for heartbeats, pings
+// and timeouts
+const TIMER_INTERVAL: Duration = Duration::from_millis(50);
+
+pub(crate) type ConnectionFilter = Box<dyn FnMut(SocketAddr) -> bool + Send>;
+
+pub(crate) enum Command {
+    Send(Packet, Priority),
+    Broadcast { payload: Vec<u8>, delivery: Delivery, except: Option<SocketAddr> },
+    // The relay, when there is one, carries every packet for the peer
+    Connect { address: SocketAddr, token: Option<ConnectToken>, relay: Option<SocketAddr> },
+    UseRelay(SocketAddr),
+    Disconnect(SocketAddr, Option<DisconnectReason>),
+    SetConnectionFilter(ConnectionFilter),
+    SetMaxPeers(Option<usize>),
+    SetSimulation(Option<NetworkSimulation>),
+    SetRateLimit(SocketAddr, Option<RateLimit>),
+    // Answered once the worker has stopped
+    Close(Sender<Result<()>>),
+}
+
+// State the worker publishes for the socket handle to read without a round trip
+#[derive(Debug)]
+pub(crate) struct Shared {
+    pub(crate) stats: Mutex<HashMap<SocketAddr, NetworkStats>>,
+    pub(crate) tokens: Mutex<HashMap<SocketAddr, ConnectToken>>,
+    pub(crate) clock_offsets: Mutex<HashMap<SocketAddr, f64>>,
+    // Where the socket clock starts, it is what pongs report to the remote end
+    pub(crate) epoch: Instant,
+    // What the signaling server knows a WebRTC socket by, None for every other transport
+    pub(crate) signaled_address: Mutex<Option<SocketAddr>>,
+}
+
+pub(crate) enum EventSender {
+    Blocking(Sender<NetworkEvent>),
+    Async(UnboundedSender<NetworkEvent>),
+}
+
+impl EventSender {
+    fn send(&self, event: NetworkEvent) {
+        // The receiving end only goes away when the network is torn down
+        match self {
+            EventSender::Blocking(sender) => {
+                let _ = sender.send(event);
+            },
+            EventSender::Async(sender) => {
+                let _ = sender.send(event);
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum PeerState {
+    Connecting(Instant),
+    Connected,
+}
+
+struct Peer {
+    state: PeerState,
+    handshake: Option<Handshake>,
+    cipher: Option<Cipher>,
+    features: u8,
+    stats: PeerStats,
+    request: Vec<u8>,
+    last_request: Instant,
+    limiter: Option<Limiter>,
+    mtu: MtuDiscovery,
+    token: Option<ConnectToken>,
+    // Chosen by the accepting side, or both sides when they accepted each other, pings are signed with it so a peer is
+    // recognized after its address changes
+    session: u64,
+    // Sequence of the newest ping from the peer, older ones can't move it to another address
+    last_ping: Option<u32>,
+    // What this side accepted the peer with, resent as it is whenever the accept was lost
+    accept: Vec<u8>,
+}
+
+impl Peer {
+    fn connecting(now: Instant, handshake: Option<Handshake>, request: Vec<u8>) -> Peer {
+        Peer {
+            state: PeerState::Connecting(now),
+            handshake,
+            cipher: None,
+            features: 0,
+            stats: PeerStats::new(now),
+            request,
+            last_request: now,
+            limiter: None,
+            mtu: MtuDiscovery::new(0),
+            token: None,
+            session: 0,
+            last_ping: None,
+            accept: vec![],
+        }
+    }
+
+    fn connected(
+        cipher: Option<Cipher>,
+        features: u8,
+        session: u64,
+        rate_limit: Option<RateLimit>,
+        mtu_limit: usize,
+    ) -> Peer {
+        let now = Instant::now();
+        Peer {
+            state: PeerState::Connected,
+            handshake: None,
+            cipher,
+            features,
+            stats: PeerStats::new(now),
+            request: vec![],
+            last_request: now,
+            limiter: rate_limit.map(|limit| Limiter::new(limit, now)),
+            mtu: MtuDiscovery::new(mtu_limit),
+            token: None,
+            session,
+            last_ping: None,
+            accept: vec![],
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        matches!(self.state, PeerState::Connected)
+    }
+
+    fn compression(&self) -> bool {
+        self.features & protocol::COMPRESSION != 0
+    }
+}
+
+// Control packets of encrypted peers are sealed like messages, so nobody else can disconnect or move them
+fn seal(cipher: &mut Option<Cipher>, payload: Vec<u8>) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.encrypt(&payload),
+        None => Ok(payload),
+    }
+}
+
+fn open(cipher: &mut Option<Cipher>, payload: &[u8]) -> Option<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.decrypt(payload).ok(),
+        None => Some(payload.to_vec()),
+    }
+}
+
+pub(crate) struct SocketWorker {
+    transport: Relayed,
+    commands: Receiver<Command>,
+    events: EventSender,
+    shared: Arc<Shared>,
+    peers: HashMap<SocketAddr, Peer>,
+    connection_filter: Option<ConnectionFilter>,
+    max_peers: Option<usize>,
+    handshake_timeout: Duration,
+    max_packet_loss: Option<f32>,
+    mtu_discovery: bool,
+    mtu_limit: usize,
+    encryption: bool,
+    static_key: Option<StaticKeypair>,
+    remote_key: Option<PublicKey>,
+    compression_threshold: Option<usize>,
+    token_key: Option<TokenKey>,
+    // Macs of accepted tokens until they expire, so a token can't be replayed from another address
+    used_tokens: HashMap<Vec<u8>, (SocketAddr, u64)>,
+    simulator: Option<Simulator>,
+    rate_limit: Option<RateLimit>,
+    heartbeat_interval: Option<Duration>,
+    poll_interval: Duration,
+    signaled_address: Option<SocketAddr>,
+    closing: Option<(Instant, Sender<Result<()>>, Result<()>)>,
+    finished: bool,
+}
+
+impl SocketWorker {
+    pub(crate) fn bind<A: ToSocketAddrs>(
+        addresses: A,
+        config: NetworkConfig,
+        events: EventSender,
+    ) -> Result<(SocketWorker, Sender<Command>, Arc<Shared>)> {
+        let transport = SocketWorker::transport(addresses, &config)?;
+        Ok(SocketWorker::new(transport, config, events))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn transport<A: ToSocketAddrs>(addresses: A, config: &NetworkConfig) -> Result<Box<dyn TransportBackend>> {
+        let addresses = resolve(addresses, config.preferred_family)?;
+        let mut socket_config = config.socket.clone();
+        socket_config.idle_connection_timeout = config.idle_timeout;
+        socket_config.heartbeat_interval = config.heartbeat_interval;
+        if let Some(fragment_size) = config.fragment_size {
+            socket_config.fragment_size = fragment_size;
+        }
+
+        let addresses = &addresses[..];
+        let transport: Box<dyn TransportBackend> = match config.transport {
+            Transport::Udp if config.dual_stack => Box::new(DualStack::bind(addresses, socket_config)?),
+            Transport::Udp => Box::new(UdpTransport::bind(addresses, socket_config)?),
+            Transport::Tcp => Box::new(StreamTransport::<TcpConnection>::bind(addresses, config.idle_timeout)?),
+            Transport::WebSocket => Box::new(StreamTransport::<WebSocketConnection>::bind(addresses, config.idle_timeout)?),
+            Transport::WebRtc => return Err(GearError::UnsupportedTransport(Transport::WebRtc)),
+        };
+
+        Ok(transport)
+    }
+
+    // Browser peers have no address to bind, the signaling server hands one out once connected
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn transport<A: ToSocketAddrs>(_addresses: A, config: &NetworkConfig) -> Result<Box<dyn TransportBackend>> {
+        match (config.transport, &config.signaling_url) {
+            (Transport::WebRtc, Some(url)) => {
+                Ok(Box::new(WebRtcTransport::connect(url, config.ice_servers.clone(), config.idle_timeout)?))
+            },
+            (Transport::WebRtc, None) => Err(GearError::WebRtcError("no signaling url to find peers through".to_owned())),
+            (transport, _) => Err(GearError::UnsupportedTransport(transport)),
+        }
+    }
+
+    pub(crate) fn new(
+        transport: Box<dyn TransportBackend>,
+        config: NetworkConfig,
+        events: EventSender,
+    ) -> (SocketWorker, Sender<Command>, Arc<Shared>) {
+        // Manually polled sockets are stepped on the thread that sends to them, a bounded queue could never drain
+        let (sender, commands) =
+            if config.manual_poll { channel::unbounded() } else { channel::bounded(config.command_capacity) };
+        let shared = Arc::new(Shared {
+            stats: Mutex::default(),
+            tokens: Mutex::default(),
+            clock_offsets: Mutex::default(),
+            epoch: Instant::now(),
+            signaled_address: Mutex::default(),
+        });
+
+        let mut transport = Relayed::new(transport, config.idle_timeout);
+        let conditioned = transport.set_packet_loss(config.simulation.map_or(0., |settings| settings.packet_loss));
+        let worker = SocketWorker {
+            transport,
+            commands,
+            events,
+            shared: shared.clone(),
+            peers: HashMap::new(),
+            connection_filter: None,
+            max_peers: None,
+            handshake_timeout: config.idle_timeout,
+            max_packet_loss: config.max_packet_loss,
+            mtu_discovery: config.mtu_discovery,
+            mtu_limit: config.fragment_size.unwrap_or(config.socket.fragment_size) as usize,
+            encryption: config.encryption,
+            static_key: config.static_key,
+            remote_key: config.remote_key,
+            compression_threshold: config.compression_threshold,
+            token_key: config.token_key,
+            used_tokens: HashMap::new(),
+            simulator: config.simulation.map(|settings| Simulator::new(settings, conditioned)),
+            rate_limit: config.rate_limit,
+            heartbeat_interval: config.heartbeat_interval,
+            poll_interval: config.poll_interval,
+            signaled_address: None,
+            closing: None,
+            finished: false,
+        };
+
+        (worker, sender, shared)
+    }
+
+    pub(crate) fn run(mut self, stop: Arc<AtomicBool>) {
+        while !stop.load(Ordering::Relaxed) && !self.finished {
+            self.poll();
+            sleep(self.poll_interval);
+        }
+    }
+
+    // Steps when the transport's sockets are ready, a command wakes it or the next timer is due
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) async fn run_async(mut self, stop: Arc<AtomicBool>, wake: Arc<Notify>) {
+        let mut readiness = Readiness::default();
+        loop {
+            readiness.poll(|| self.poll());
+            if stop.load(Ordering::Relaxed) || self.finished {
+                break;
+            }
+
+            // Transports without sockets to wait on are polled like the socket thread would
+            let now = Instant::now();
+            let deadline = match self.transport.sources().map(|sources| readiness.update(sources)) {
+                Some(Ok(())) => self.deadline(now),
+                Some(Err(e)) => {
+                    error!("Failed to wait on socket, polling instead: {}", e);
+                    now + self.poll_interval
+                },
+                None => now + self.poll_interval,
+            };
+
+            tokio::select! {
+                _ = readiness.ready() => (),
+                _ = wake.notified() => (),
+                _ = time::sleep_until(deadline.into()) => (),
+            }
+        }
+    }
+
+    // When the worker next has something to do that no packet or command would wake it for
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    fn deadline(&self, now: Instant) -> Instant {
+        let mut deadline = now + self.heartbeat_interval.map_or(TIMER_INTERVAL, |interval| interval.min(TIMER_INTERVAL));
+        if let Some(due) = self.simulator.as_ref().and_then(Simulator::next_due) {
+            deadline = deadline.min(due);
+        }
+        if let Some((closing, _, _)) = &self.closing {
+            deadline = deadline.min(*closing);
+        }
+
+        for peer in self.peers.values() {
+            if let PeerState::Connecting(_) = peer.state {
+                deadline = deadline.min(peer.last_request + HANDSHAKE_RETRY_INTERVAL);
+            }
+            // Budgets refill with time, so held back messages are retried at the poll interval
+            if peer.limiter.as_ref().map_or(false, Limiter::is_congested) {
+                deadline = deadline.min(now + self.poll_interval);
+            }
+        }
+
+        deadline
+    }
+
+    pub(crate) fn poll(&mut self) {
+        self.step(Instant::now());
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    fn step(&mut self, now: Instant) {
+        while let Ok(command) = self.commands.try_recv() {
+            self.handle_command(command, now);
+        }
+
+        let due = self.simulator.as_mut().map(|simulator| simulator.due(now)).unwrap_or_default();
+        for packet in due {
+            self.transmit(packet);
+        }
+
+        self.drain_limiters(now);
+        self.transport.poll(now);
+        self.publish_address();
+
+        while let Some(event) = self.transport.recv() {
+            self.handle_socket_event(event);
+        }
+
+        self.expire_handshakes(now);
+        self.retry_handshakes(now);
+        self.update_stats(now);
+        self.probe_mtu(now);
+
+        match self.closing.take() {
+            Some((deadline, done, result)) if now >= deadline => {
+                let _ = done.send(result);
+                self.finished = true;
+            },
+            closing => self.closing = closing,
+        }
+    }
+
+    fn publish_address(&mut self) {
+        let address = self.transport.signaled_address();
+        if address != self.signaled_address {
+            self.signaled_address = address;
+            *self.shared.signaled_address.lock().unwrap() = address;
+        }
+    }
+
+    fn send(&mut self, packet: Packet) {
+        if let Some(peer) = self.peers.get_mut(&packet.addr()) {
+            let reliable = packet.delivery_guarantee() == DeliveryGuarantee::Reliable;
+            peer.stats.record_sent(packet.payload().len(), reliable);
+        }
+
+        self.route(packet);
+    }
+
+    fn route(&mut self, packet: Packet) {
+        if let Some(simulator) = &mut self.simulator {
+            simulator.enqueue(packet, Instant::now());
+            return;
+        }
+
+        self.transmit(packet);
+    }
+
+    fn transmit(&mut self, packet: Packet) {
+        let address = packet.addr();
+        if let Err(e) = self.transport.send(packet) {
+            self.emit(NetworkEvent::Error(address, Arc::new(e)));
+        }
+    }
+
+    fn emit(&self, event: NetworkEvent) {
+        self.events.send(event);
+    }
+
+    fn state(&self, address: SocketAddr) -> Option<PeerState> {
+        self.peers.get(&address).map(|peer| peer.state)
+    }
+
+    fn features(&self) -> u8 {
+        let mut features = 0;
+        if self.encryption {
+            features |= protocol::ENCRYPTION;
+        }
+        if self.compression_threshold.is_some() {
+            features |= protocol::COMPRESSION;
+        }
+        features
+    }
+
+    fn handle_command(&mut self, command: Command, now: Instant) {
+        match command {
+            Command::Send(packet, priority) => {
+                let address = normalize_address(packet.addr());
+                self.send_message(protocol::with_payload(&packet, address, packet.payload().to_vec()), priority);
+            },
+            Command::Broadcast { payload, delivery, except } => {
+                let except = except.map(normalize_address);
+                let addresses: Vec<SocketAddr> = self
+                    .peers
+                    .iter()
+                    .filter(|(address, peer)| peer.is_connected() && Some(**address) != except)
+                    .map(|(address, _)| *address)
+                    .collect();
+
+                for address in addresses {
+                    self.send_message(delivery.packet(address, payload.clone()), Priority::default());
+                }
+            },
+            Command::Connect { address, token, relay } => {
+                let address = normalize_address(address);
+                if !self.peers.contains_key(&address) {
+                    self.transport.route(address, relay.map(normalize_address), now);
+                    self.connect(address, token, now);
+                }
+            },
+            Command::UseRelay(relay) => self.transport.register(normalize_address(relay), now),
+            Command::Disconnect(address, reason) => {
+                let address = normalize_address(address);
+                if let Some(mut peer) = self.peers.remove(&address) {
+                    if let Ok(payload) = seal(&mut peer.cipher, protocol::encode_disconnect(&reason)) {
+                        self.send(protocol::control_with(PacketKind::Disconnect, address, &payload));
+                    }
+                    if peer.is_connected() {
+                        self.emit(NetworkEvent::Disconnect(address, reason));
+                    }
+                }
+            },
+            Command::SetConnectionFilter(filter) => self.connection_filter = Some(filter),
+            Command::SetMaxPeers(max_peers) => self.max_peers = max_peers,
+            Command::SetSimulation(settings) => {
+                let conditioned = self.transport.set_packet_loss(settings.map_or(0., |settings| settings.packet_loss));
+                let simulator = settings.map(|settings| Simulator::new(settings, conditioned));
+                let previous = std::mem::replace(&mut self.simulator, simulator);
+                for packet in previous.map(Simulator::into_pending).unwrap_or_default() {
+                    self.route(packet);
+                }
+            },
+            Command::SetRateLimit(address, limit) => {
+                let address = normalize_address(address);
+                let peer = match self.peers.get_mut(&address) {
+                    Some(peer) if peer.is_connected() => peer,
+                    _ => return,
+                };
+
+                // Anything still queued behind the old limit goes out right away
+                let previous = std::mem::replace(&mut peer.limiter, limit.map(|limit| Limiter::new(limit, now)));
+                let mtu = peer.mtu.mtu();
+                if let Some(mut previous) = previous {
+                    let congested = previous.is_congested();
+                    for packet in previous.drain(now, mtu) {
+                        self.send(packet);
+                    }
+                    if congested {
+                        self.emit(NetworkEvent::Congested(address, false));
+                    }
+                }
+            },
+            Command::Close(done) => {
+                let result = self.close();
+                self.closing = Some((now + CLOSE_LINGER, done, result));
+            },
+        }
+    }
+
+    // Everything still held back by rate limits or simulated latency goes out ahead of the disconnects
+    fn close(&mut self) -> Result<()> {
+        let mut pending = self.simulator.take().map(Simulator::into_pending).unwrap_or_default();
+        for peer in self.peers.values_mut() {
+            let mtu = peer.mtu.mtu();
+            pending.extend(peer.limiter.as_mut().map(|limiter| limiter.flush(mtu)).unwrap_or_default());
+        }
+
+        let mut disconnected = vec![];
+        for (address, mut peer) in std::mem::take(&mut self.peers) {
+            if let Ok(payload) = seal(&mut peer.cipher, vec![]) {
+                pending.push(protocol::control_with(PacketKind::Disconnect, address, &payload));
+            }
+            if peer.is_connected() {
+                disconnected.push(address);
+            }
+        }
+
+        let result = pending.into_iter().try_for_each(|packet| self.transport.send(packet));
+        for address in disconnected {
+            self.emit(NetworkEvent::Disconnect(address, None));
+        }
+
+        result
+    }
+
+    fn connect(&mut self, address: SocketAddr, token: Option<ConnectToken>, now: Instant) {
+        let handshake = if self.encryption {
+            // Without a pinned key there's no telling who answers
+            let handshake = match &self.remote_key {
+                Some(remote_key) => Handshake::initiate(remote_key),
+                None => Err(snow::Error::Input.into()),
+            };
+            match handshake {
+                Ok(handshake) => Some(handshake),
+                Err(e) => {
+                    error!("Failed to start handshake with {}: {:?}", address, e);
+                    self.emit(NetworkEvent::ConnectFailed(address, ConnectFailure::HandshakeFailed));
+                    return;
+                },
+            }
+        } else {
+            None
+        };
+
+        let token = match token.map(|token| token.to_bytes()).transpose() {
+            Ok(token) => token,
+            Err(e) => {
+                error!("Failed to encode connect token for {}: {:?}", address, e);
+                self.emit(NetworkEvent::ConnectFailed(address, ConnectFailure::HandshakeFailed));
+                return;
+            },
+        };
+
+        let mut request = vec![self.features()];
+        if let Some(token) = &token {
+            request[0] |= protocol::TOKEN;
+            request.extend_from_slice(&(token.len() as u16).to_le_bytes());
+            request.extend_from_slice(token);
+        }
+        if let Some(handshake) = &handshake {
+            request.extend_from_slice(handshake.request());
+        }
+
+        self.send(protocol::control_with(PacketKind::ConnectRequest, address, &request));
+        self.peers.insert(address, Peer::connecting(now, handshake, request));
+    }
+
+    fn send_message(&mut self, packet: Packet, priority: Priority) {
+        let address = packet.addr();
+        let threshold = self.compression_threshold.unwrap_or(usize::MAX);
+        let peer = match self.peers.get_mut(&address) {
+            Some(peer) if peer.is_connected() => peer,
+            _ => return,
+        };
+
+        let payload =
+            if peer.compression() { compression::compress(packet.payload(), threshold) } else { packet.payload().to_vec() };
+
+        let payload = match &mut peer.cipher {
+            Some(cipher) => match cipher.encrypt(&payload) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    self.emit(NetworkEvent::Error(address, Arc::new(e.into())));
+                    return;
+                },
+            },
+            None => payload,
+        };
+
+        let packet = protocol::encode(PacketKind::Message, &protocol::with_payload(&packet, address, payload));
+        self.throttle(packet, priority);
+    }
+
+    fn throttle(&mut self, packet: Packet, priority: Priority) {
+        let address = packet.addr();
+        let (limiter, mtu) = match self.peers.get_mut(&address) {
+            Some(Peer { limiter: Some(limiter), mtu, .. }) => (limiter, mtu.mtu()),
+            _ => return self.send(packet),
+        };
+
+        let congested = limiter.is_congested();
+        limiter.push(packet, priority);
+        let ready = limiter.drain(Instant::now(), mtu);
+        let now_congested = limiter.is_congested();
+
+        for packet in ready {
+            self.send(packet);
+        }
+        if congested != now_congested {
+            self.emit(NetworkEvent::Congested(address, now_congested));
+        }
+    }
+
+    fn drain_limiters(&mut self, now: Instant) {
+        let mut ready = vec![];
+        let mut drained = vec![];
+        for (address, peer) in &mut self.peers {
+            let mtu = peer.mtu.mtu();
+            if let Some(limiter) = peer.limiter.as_mut().filter(|limiter| limiter.is_congested()) {
+                ready.extend(limiter.drain(now, mtu));
+                if !limiter.is_congested() {
+                    drained.push(*address);
+                }
+            }
+        }
+
+        for packet in ready {
+            self.send(packet);
+        }
+        for address in drained {
+            self.emit(NetworkEvent::Congested(address, false));
+        }
+    }
+
+    fn handle_socket_event(&mut self, event: SocketEvent) {
+        match event {
+            SocketEvent::Packet(packet) => {
+                if let Some(peer) = self.peers.get_mut(&packet.addr()) {
+                    peer.stats.record_received(packet.payload().len());
+                }
+
+                if let Some((kind, packet)) = protocol::decode(&packet) {
+                    self.handle_packet(kind, packet);
+                }
+            },
+            SocketEvent::Connect(_) => (),
+            SocketEvent::Timeout(address) => match self.peers.remove(&address).map(|peer| peer.state) {
+                Some(PeerState::Connecting(_)) => self.emit(NetworkEvent::ConnectFailed(address, ConnectFailure::TimedOut)),
+                Some(PeerState::Connected) => self.emit(NetworkEvent::Timeout(address, TimeoutReason::Idle)),
+                None => (),
+            },
+            SocketEvent::Disconnect(address) => {
+                if let Some(PeerState::Connected) = self.peers.remove(&address).map(|peer| peer.state) {
+                    self.emit(NetworkEvent::Disconnect(address, None));
+                }
+            },
+        }
+    }
+
+    fn handle_packet(&mut self, kind: PacketKind, packet: Packet) {
+        let address = packet.addr();
+        match kind {
+            PacketKind::Message => self.receive_message(packet),
+            // The transport unwraps relayed traffic, these only make sense to a relay server
+            PacketKind::Relay | PacketKind::RelayRegister => (),
+            // Only messages are ever coalesced
+            PacketKind::Batch => {
+                for entry in protocol::decode_batch(&packet).unwrap_or_default() {
+                    if let Some((PacketKind::Message, message)) = protocol::decode(&entry) {
+                        self.receive_message(message);
+                    }
+                }
+            },
+            PacketKind::ConnectRequest => self.receive_connect_request(packet),
+            PacketKind::ConnectAccept => self.receive_connect_accept(packet),
+            PacketKind::ConnectDeny => {
+                if let Some(PeerState::Connecting(_)) = self.state(address) {
+                    self.peers.remove(&address);
+                    self.emit(NetworkEvent::ConnectFailed(address, ConnectFailure::Denied));
+                }
+            },
+            PacketKind::Disconnect => {
+                let payload = match self.peers.get_mut(&address).map(|peer| open(&mut peer.cipher, packet.payload())) {
+                    Some(Some(payload)) => payload,
+                    _ => return,
+                };
+
+                if let Some(PeerState::Connected) = self.peers.remove(&address).map(|peer| peer.state) {
+                    self.emit(NetworkEvent::Disconnect(address, protocol::decode_disconnect(&payload)));
+                }
+            },
+            // The pong echoes the sequence followed by the socket clock
+            PacketKind::Ping => {
+                let sequence = match self.peers.get_mut(&address) {
+                    Some(peer) if peer.is_connected() => {
+                        open(&mut peer.cipher, packet.payload()).and_then(|ping| protocol::decode_ping(&ping))
+                    },
+                    Some(_) => return,
+                    None => self.migrate(address, packet.payload()),
+                };
+                let (sequence, peer) = match (sequence, self.peers.get_mut(&address)) {
+                    (Some(sequence), Some(peer)) => (sequence, peer),
+                    _ => return,
+                };
+
+                peer.last_ping = Some(peer.last_ping.map_or(sequence, |last| last.max(sequence)));
+                let mut pong = sequence.to_le_bytes().to_vec();
+                pong.extend_from_slice(&(self.shared.epoch.elapsed().as_micros() as u64).to_le_bytes());
+                self.send(protocol::unreliable_control(PacketKind::Pong, address, &pong));
+            },
+            // The probe is answered with its size so the prober can tell which probe made it
+            PacketKind::MtuProbe => {
+                let payload = packet.payload();
+                if payload.len() < 2 || u16::from_le_bytes([payload[0], payload[1]]) as usize != payload.len() + 1 {
+                    return;
+                }
+
+                if let Some(PeerState::Connected) = self.state(address) {
+                    self.send(protocol::unreliable_control(PacketKind::MtuAck, address, &payload[..2]));
+                }
+            },
+            PacketKind::MtuAck => {
+                let size = match packet.payload().try_into() {
+                    Ok(size) => u16::from_le_bytes(size),
+                    Err(_) => return,
+                };
+
+                if let Some(peer) = self.peers.get_mut(&address) {
+                    peer.mtu.receive_ack(size as usize);
+                }
+            },
+            PacketKind::Pong => {
+                let payload = packet.payload();
+                let (sequence, remote_time) = match (payload.get(..4), payload.get(4..12)) {
+                    (Some(sequence), Some(time)) => match (sequence.try_into(), time.try_into()) {
+                        (Ok(sequence), Ok(time)) => (u32::from_le_bytes(sequence), u64::from_le_bytes(time)),
+                        _ => return,
+                    },
+                    _ => return,
+                };
+
+                let now = Instant::now();
+                let local_time = now - self.shared.epoch;
+                if let Some(peer) = self.peers.get_mut(&address) {
+                    peer.stats.receive_pong(sequence, Duration::from_micros(remote_time), now, local_time);
+                }
+            },
+        }
+    }
+
+    // A ping from an unknown address signed with a known session, and sealed by its cipher when it has one, means the
+    // peer's NAT rebound its port. Only pings newer than any the peer sent before count, so one seen on the way can't be
+    // replayed from elsewhere. Returns the ping's sequence once the peer has moved
+    fn migrate(&mut self, address: SocketAddr, payload: &[u8]) -> Option<u32> {
+        let (previous, sequence) =
+            self.peers.iter_mut().filter(|(_, peer)| peer.is_connected()).find_map(|(previous, peer)| {
+                let ping = open(&mut peer.cipher, payload)?;
+                let sequence = protocol::decode_ping(&ping)?;
+                if peer.last_ping.map_or(false, |last| sequence <= last) || !protocol::verify_ping(&ping, peer.session) {
+                    return None;
+                }
+                Some((*previous, sequence))
+            })?;
+
+        let peer = self.peers.remove(&previous)?;
+        self.peers.insert(address, peer);
+        self.emit(NetworkEvent::AddressChanged(previous, address));
+        Some(sequence)
+    }
+
+    fn receive_message(&mut self, packet: Packet) {
+        let address = packet.addr();
+        let peer = match self.peers.get_mut(&address) {
+            Some(peer) if peer.is_connected() => peer,
+            _ => return,
+        };
+
+        // Anything that fails to decrypt or decompress is forged, replayed or corrupted, drop it
+        let payload = match open(&mut peer.cipher, packet.payload()) {
+            Some(payload) => payload,
+            None => return,
+        };
+
+        let payload = if peer.compression() {
+            match compression::decompress(&payload) {
+                Some(payload) => payload,
+                None => return,
+            }
+        } else {
+            payload
+        };
+
+        self.emit(NetworkEvent::Message(protocol::with_payload(&packet, address, payload)));
+    }
+
+    fn receive_connect_request(&mut self, packet: Packet) {
+        let address = packet.addr();
+        let (features, request) = match packet.payload().split_first() {
+            Some((&features, request)) => (features, request),
+            None => return,
+        };
+
+        let (token, request) = if features & protocol::TOKEN != 0 {
+            if request.len() < 2 {
+                return;
+            }
+
+            let len = u16::from_le_bytes([request[0], request[1]]) as usize;
+            match request.get(2..2 + len) {
+                Some(token) => (ConnectToken::from_bytes(token).ok(), &request[2 + len..]),
+                None => return,
+            }
+        } else {
+            (None, request)
+        };
+
+        match self.peers.get(&address).map(|peer| (peer.state, peer.accept.clone())) {
+            // A lost accept is resent as it was, the initiator is still waiting on that same handshake
+            Some((PeerState::Connected, accept)) => {
+                if !accept.is_empty() {
+                    self.send(protocol::control_with(PacketKind::ConnectAccept, address, &accept));
+                }
+            },
+            // Both sides connecting to each other at once
+            Some((PeerState::Connecting(_), _)) => {
+                if !self.encryption {
+                    self.respond(address, features, request.to_vec());
+                    return;
+                }
+
+                // Only one side may respond to an encrypted handshake, the one with the smaller request backs off
+                let ours = self.peers.get(&address).and_then(|peer| peer.handshake.as_ref()).map(|h| h.request());
+                if ours.map_or(false, |ours| ours < request) {
+                    self.respond(address, features, request.to_vec());
+                }
+            },
+            None => {
+                let connected = self.peers.values().filter(|peer| peer.is_connected()).count();
+                let full = self.max_peers.map_or(false, |max_peers| connected >= max_peers);
+                let accepted = !full
+                    && match &mut self.connection_filter {
+                        Some(filter) => filter(address),
+                        None => true,
+                    };
+
+                let key = self.token_key;
+                let token = match key {
+                    Some(key) => match token.filter(|token| self.accept_token(address, token, &key)) {
+                        Some(token) => Some(token),
+                        None => {
+                            self.send(protocol::control(PacketKind::ConnectDeny, address));
+                            return;
+                        },
+                    },
+                    None => None,
+                };
+
+                if accepted && self.encryption == (features & protocol::ENCRYPTION != 0) {
+                    self.respond(address, features, request.to_vec());
+                    if let Some(peer) = self.peers.get_mut(&address) {
+                        peer.token = token;
+                    }
+                } else {
+                    self.send(protocol::control(PacketKind::ConnectDeny, address));
+                }
+            },
+        }
+    }
+
+    fn accept_token(&mut self, address: SocketAddr, token: &ConnectToken, key: &TokenKey) -> bool {
+        if !token.is_valid(key) {
+            return false;
+        }
+
+        let now = unix_time().as_secs();
+        self.used_tokens.retain(|_, (_, expires)| *expires > now);
+        match self.used_tokens.get(token.mac()) {
+            Some((used_by, _)) => *used_by == address,
+            None => {
+                self.used_tokens.insert(token.mac().to_vec(), (address, token.expires));
+                true
+            },
+        }
+    }
+
+    fn respond(&mut self, address: SocketAddr, features: u8, request: Vec<u8>) {
+        let features = features & self.features();
+        if !self.encryption {
+            self.accept(address, None, features, vec![]);
+            return;
+        }
+
+        // Without a static key this side has nothing to prove itself with
+        let handshake = match &self.static_key {
+            Some(static_key) => Handshake::respond(&request, static_key),
+            None => Err(snow::Error::Input.into()),
+        };
+        match handshake {
+            Ok((cipher, response)) => self.accept(address, Some(cipher), features, response),
+            Err(_) => {
+                self.peers.remove(&address);
+                self.send(protocol::control(PacketKind::ConnectDeny, address));
+            },
+        }
+    }
+
+    fn receive_connect_accept(&mut self, packet: Packet) {
+        let address = packet.addr();
+        match self.peers.get_mut(&address) {
+            Some(peer) if matches!(peer.state, PeerState::Connecting(_)) => (),
+            // Unencrypted peers connecting at once accept each other, both then settle on the two sessions combined.
+            // Encrypted ones never do, only one of them responds
+            Some(peer) if peer.cipher.is_none() && !peer.accept.is_empty() => {
+                let ours = peer.accept.get(1..9).and_then(|session| session.try_into().ok()).map(u64::from_le_bytes);
+                let theirs = packet.payload().get(1..9).and_then(|session| session.try_into().ok()).map(u64::from_le_bytes);
+                if let (Some(ours), Some(theirs)) = (ours, theirs) {
+                    peer.session = ours ^ theirs;
+                }
+                return;
+            },
+            _ => return,
+        }
+
+        let handshake = self.peers.remove(&address).and_then(|peer| peer.handshake);
+        let accepted = packet.payload().split_first().and_then(|(&features, payload)| {
+            let session = u64::from_le_bytes(payload.get(..8)?.try_into().ok()?);
+            Some((features, session, &payload[8..]))
+        });
+        let session = match (handshake, accepted) {
+            (None, Some((features, session, _))) if features & protocol::ENCRYPTION == 0 => Some((None, features, session)),
+            (Some(handshake), Some((features, session, response))) if features & protocol::ENCRYPTION != 0 => {
+                handshake.complete(response).ok().map(|cipher| (Some(cipher), features, session))
+            },
+            _ => None,
+        };
+
+        match session {
+            Some((cipher, features, session)) => {
+                let peer = Peer::connected(cipher, features, session, self.rate_limit, self.mtu_limit);
+                self.peers.insert(address, peer);
+                self.emit(NetworkEvent::Connect(address));
+            },
+            None => self.emit(NetworkEvent::ConnectFailed(address, ConnectFailure::HandshakeFailed)),
+        }
+    }
+
+    fn accept(&mut self, address: SocketAddr, cipher: Option<Cipher>, features: u8, response: Vec<u8>) {
+        let session = rand::random();
+        let mut payload = vec![features];
+        payload.extend_from_slice(&u64::to_le_bytes(session));
+        payload.extend_from_slice(&response);
+
+        let mut peer = Peer::connected(cipher, features, session, self.rate_limit, self.mtu_limit);
+        peer.accept = payload.clone();
+        self.peers.insert(address, peer);
+        self.send(protocol::control_with(PacketKind::ConnectAccept, address, &payload));
+        self.emit(NetworkEvent::Connect(address));
+    }
+
+    fn expire_handshakes(&mut self, now: Instant) {
+        let timeout = self.handshake_timeout;
+        let expired: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter_map(|(address, peer)| match peer.state {
+                PeerState::Connecting(started) if now - started >= timeout => Some(*address),
+                _ => None,
+            })
+            .collect();
+
+        for address in expired {
+            self.peers.remove(&address);
+            self.emit(NetworkEvent::ConnectFailed(address, ConnectFailure::TimedOut));
+        }
+    }
+
+    fn update_stats(&mut self, now: Instant) {
+        if let Some(max_packet_loss) = self.max_packet_loss {
+            let lossy: Vec<SocketAddr> = self
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.is_connected() && peer.stats.loss_measured())
+                .filter(|(_, peer)| peer.stats.stats().packet_loss >= max_packet_loss)
+                .map(|(address, _)| *address)
+                .collect();
+
+            for address in lossy {
+                let mut peer = self.peers.remove(&address).unwrap();
+                if let Ok(payload) = seal(&mut peer.cipher, vec![]) {
+                    self.send(protocol::control_with(PacketKind::Disconnect, address, &payload));
+                }
+                self.emit(NetworkEvent::Timeout(address, TimeoutReason::PacketLoss));
+            }
+        }
+
+        let mut pings = vec![];
+        for (address, peer) in self.peers.iter_mut().filter(|(_, peer)| peer.is_connected()) {
+            if let Some(sequence) = peer.stats.update(now) {
+                if let Ok(payload) = seal(&mut peer.cipher, protocol::encode_ping(sequence, peer.session)) {
+                    pings.push((*address, payload));
+                }
+            }
+        }
+
+        for (address, payload) in pings {
+            self.send(protocol::unreliable_control(PacketKind::Ping, address, &payload));
+        }
+
+        let mut tokens = self.shared.tokens.lock().unwrap();
+        tokens.clear();
+        for (address, peer) in self.peers.iter().filter(|(_, peer)| peer.is_connected()) {
+            if let Some(token) = &peer.token {
+                tokens.insert(*address, token.clone());
+            }
+        }
+
+        let mut clock_offsets = self.shared.clock_offsets.lock().unwrap();
+        clock_offsets.clear();
+        for (address, peer) in self.peers.iter().filter(|(_, peer)| peer.is_connected()) {
+            if let Some(offset) = peer.stats.clock_offset() {
+                clock_offsets.insert(*address, offset);
+            }
+        }
+
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.clear();
+        for (address, peer) in self.peers.iter().filter(|(_, peer)| peer.is_connected()) {
+            let queued_bytes = peer.limiter.as_ref().map_or(0, Limiter::queued_bytes);
+            stats.insert(*address, NetworkStats { queued_bytes, mtu: peer.mtu.mtu(), ..peer.stats.stats() });
+        }
+    }
+
+    fn probe_mtu(&mut self, now: Instant) {
+        if !self.mtu_discovery {
+            return;
+        }
+
+        let mut probes = vec![];
+        for (address, peer) in self.peers.iter_mut().filter(|(_, peer)| peer.is_connected()) {
+            if let Some(size) = peer.mtu.update(now) {
+                probes.push((*address, size));
+            }
+        }
+
+        // The size counts the packet kind byte, so the whole gear payload of the probe is exactly that large
+        for (address, size) in probes {
+            let mut payload = vec![0; size - 1];
+            payload[..2].copy_from_slice(&(size as u16).to_le_bytes());
+            self.send(protocol::unreliable_control(PacketKind::MtuProbe, address, &payload));
+        }
+    }
+
+    fn retry_handshakes(&mut self, now: Instant) {
+        let mut retries = vec![];
+        for (address, peer) in &mut self.peers {
+            if let PeerState::Connecting(_) = peer.state {
+                if now - peer.last_request >= HANDSHAKE_RETRY_INTERVAL {
+                    peer.last_request = now;
+                    retries.push(protocol::unreliable_control(PacketKind::ConnectRequest, *address, &peer.request));
+                }
+            }
+        }
+
+        for packet in retries {
+            self.send(packet);
+        }
+    }
+}
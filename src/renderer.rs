@@ -1,20 +1,63 @@
 // Copyright 2021 Chay Nabors.
 
+mod adapter;
+mod billboard;
+mod camera;
+mod capture;
+mod compute;
+mod debug;
+mod decal;
+mod depth_effects;
+mod fog;
+mod graph;
+mod indirect;
+mod instance;
+mod light;
+mod light2d;
+mod lod;
+mod lut;
+mod material;
+mod mesh;
+mod nine_slice;
+mod outline;
+mod particles;
+mod pbr;
+mod pick;
+mod post;
+mod scene;
+mod shader;
+mod shadow;
+mod skin;
+mod skybox;
+mod sprite;
+mod stats;
+mod target;
+mod terrain;
+mod tilemap;
+mod timing;
+mod viewport;
+mod water;
+
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self,};
 use std::ops::Range;
+use std::path::Path;
 use std::sync::Weak;
 
+use bytemuck::Pod;
 use bytemuck::Zeroable;
 use log::error;
 use log::info;
 use nalgebra::Isometry3;
+use nalgebra::Matrix3;
 use nalgebra::Matrix4;
+use nalgebra::Point2;
 use nalgebra::Point3;
 use nalgebra::Translation3;
 use nalgebra::UnitQuaternion;
 use wgpu::vertex_attr_array;
 use wgpu::Adapter;
-use wgpu::BackendBit;
 use wgpu::BindGroup;
 use wgpu::BindGroupDescriptor;
 use wgpu::BindGroupEntry;
@@ -40,7 +83,6 @@ use wgpu::DepthStencilState;
 use wgpu::Device;
 use wgpu::DeviceDescriptor;
 use wgpu::DynamicOffset;
-use wgpu::Extent3d;
 use wgpu::Face;
 use wgpu::Features;
 use wgpu::FragmentState;
@@ -50,22 +92,21 @@ use wgpu::InputStepMode;
 use wgpu::Instance;
 use wgpu::Limits;
 use wgpu::LoadOp;
+use wgpu::Maintain;
 use wgpu::MultisampleState;
 use wgpu::Operations;
 use wgpu::PipelineLayout;
 use wgpu::PipelineLayoutDescriptor;
 use wgpu::PolygonMode;
-use wgpu::PowerPreference;
-use wgpu::PresentMode;
 use wgpu::PrimitiveState;
 use wgpu::PrimitiveTopology;
 use wgpu::Queue;
+use wgpu::RenderPass;
 use wgpu::RenderPassColorAttachment;
 use wgpu::RenderPassDepthStencilAttachment;
 use wgpu::RenderPassDescriptor;
 use wgpu::RenderPipeline;
 use wgpu::RenderPipelineDescriptor;
-use wgpu::RequestAdapterOptions;
 use wgpu::ShaderFlags;
 use wgpu::ShaderModule;
 use wgpu::ShaderModuleDescriptor;
@@ -75,47 +116,204 @@ use wgpu::StencilState;
 use wgpu::Surface;
 use wgpu::SwapChain;
 use wgpu::SwapChainDescriptor;
-use wgpu::Texture;
-use wgpu::TextureDescriptor;
-use wgpu::TextureDimension;
 use wgpu::TextureFormat;
 use wgpu::TextureUsage;
-use wgpu::TextureView;
-use wgpu::TextureViewDescriptor;
 use wgpu::VertexBufferLayout;
 use wgpu::VertexState;
 use wgpu::BIND_BUFFER_ALIGNMENT;
 
+pub use self::adapter::AdapterInfo;
+pub use self::adapter::AdapterPreference;
+pub use self::adapter::AdapterType;
+pub use self::adapter::GraphicsBackend;
+pub use self::adapter::RendererSettings;
+pub use self::billboard::Billboard;
+use self::billboard::BillboardRenderer;
+pub use self::camera::Camera2D;
+pub use self::camera::Camera3D;
+pub use self::camera::Projection;
+pub use self::capture::FrameCapture;
+use self::capture::FrameCapturer;
+pub use self::compute::ComputeBinding;
+pub use self::compute::ComputeBindingKind;
+use self::compute::ComputeRegistry;
+pub use self::compute::ComputeShaderId;
+pub use self::compute::StorageBuffer;
+pub use self::compute::StorageTexture;
+use self::debug::DebugRenderer;
+pub use self::debug::DebugView;
+pub use self::decal::Decal;
+pub use self::decal::DecalPool;
+use self::decal::DecalRenderer;
+use self::depth_effects::DepthEffects;
+use self::depth_effects::EffectCamera;
+pub use self::fog::Fog;
+pub use self::fog::FogFalloff;
+pub use self::graph::AttachmentDescriptor;
+pub use self::graph::AttachmentFormat;
+pub use self::graph::AttachmentId;
+pub use self::graph::ColorTarget;
+use self::graph::CompiledGraph;
+pub use self::graph::PassDescriptor;
+pub use self::graph::PassId;
+pub use self::graph::PassKind;
+pub use self::graph::RenderGraph;
+pub use self::indirect::IndirectBatch;
+use self::indirect::IndirectCuller;
+use self::instance::InstanceBuffer;
+pub use self::instance::InstanceData;
+pub use self::light::DirectionalLight;
+pub use self::light::Light;
+pub use self::light::PointLight;
+pub use self::light::Shadow;
+pub use self::light::SpotLight;
+pub use self::light2d::Light2D;
+pub use self::light2d::LightCone;
+use self::light2d::Lighting2D;
+pub use self::lod::MeshLod;
+pub use self::lut::ColorGrading;
+pub use self::lut::ColorLut;
+pub use self::material::GpuMaterial;
+pub use self::material::Material;
+use self::material::MaterialLayout;
+pub use self::mesh::AttributeFormat;
+pub use self::mesh::GpuMesh;
+use self::mesh::MeshInput;
+pub use self::mesh::VertexLayout;
+pub use self::nine_slice::NineSlice;
+use self::outline::OutlineRenderer;
+pub use self::particles::Curve;
+pub use self::particles::CurveValue;
+pub use self::particles::ParticleEmitter;
+use self::particles::ParticleRenderer;
+pub use self::particles::ParticleSettings;
+use self::pbr::PbrPipeline;
+use self::pick::ObjectPicker;
+pub use self::post::Bloom;
+pub use self::post::DepthOfField;
+pub use self::post::PostEffects;
+use self::post::PostProcessor;
+pub use self::post::Ssao;
+pub use self::post::Ssr;
+pub use self::post::Tonemapper;
+pub use self::scene::GpuScene;
+pub use self::shader::ShaderId;
+pub use self::shader::ShaderKind;
+use self::shader::ShaderRegistry;
+use self::shadow::ShadowMap;
+use self::skin::JointBuffer;
+pub use self::skybox::Cubemap;
+use self::skybox::SkyboxRenderer;
+pub use self::sprite::RenderLayer;
+use self::sprite::SpriteBatcher;
+pub use self::sprite::StencilMode;
+pub use self::stats::FrameStats;
+use self::stats::StatsOverlay;
+pub use self::target::RenderTarget;
+pub use self::target::RenderTargetFormat;
+use self::target::TargetDraw;
+pub use self::terrain::GpuTerrain;
+pub use self::terrain::TerrainLayer;
+pub use self::terrain::TerrainMaterial;
+use self::terrain::TerrainRenderer;
+pub use self::tilemap::TileLayer;
+use self::tilemap::TileRenderer;
+use self::timing::GpuTimer;
+pub use self::timing::GpuTiming;
+pub use self::viewport::Viewport;
+use self::viewport::ViewportClearer;
+pub use self::water::Water;
+use self::water::WaterRenderer;
+use crate::model::Mesh;
 use crate::model::Vertex;
+use crate::texture::{self,};
+use crate::Aabb;
+use crate::AnimatedSprite;
+use crate::AnimationPlayer;
+use crate::AtlasPacker;
+use crate::Font;
+use crate::Frustum;
+use crate::GearError;
+use crate::Result;
+use crate::Scene;
+use crate::Terrain;
+use crate::TextStyle;
+use crate::TextureArray;
+use crate::TextureAtlas;
+use crate::TextureOptions;
+use crate::TextureWrap;
+use crate::VideoFrame;
+use crate::VideoPlayer;
 use crate::Window;
 
 const VERTEX_BUFFER_SIZE: u64 = 32000000;
 const INDEX_BUFFER_SIZE: u64 = 32000000;
 const MAX_UNIFORM_COUNT: u64 = 1 << 20;
 const TEXTURE_FORMAT: TextureFormat = TextureFormat::Bgra8UnormSrgb;
-const DEPTH_TEXTURE_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+// The stencil is for sprite masks, nothing samples the scene's depth so it doesn't need to be a float
+const DEPTH_TEXTURE_FORMAT: TextureFormat = TextureFormat::Depth24PlusStencil8;
+// Every scene pipeline draws to this, colors past one survive until tonemapping
+const HDR_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+// How finished frames are handed to the screen. Modes the adapter doesn't support fall back to Fifo
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresentMode {
+    // Waits for vertical blank, no tearing but up to a frame of latency
+    Fifo,
+    // Replaces the waiting frame with newer ones, no tearing with less latency
+    Mailbox,
+    // Shows frames as soon as they are done, the least latency but it tears
+    Immediate,
+}
+
+impl PresentMode {
+    fn to_wgpu(self) -> wgpu::PresentMode {
+        match self {
+            PresentMode::Fifo => wgpu::PresentMode::Fifo,
+            PresentMode::Mailbox => wgpu::PresentMode::Mailbox,
+            PresentMode::Immediate => wgpu::PresentMode::Immediate,
+        }
+    }
+}
 
 #[repr(C, align(256))]
 #[derive(Copy, Clone, Debug, Zeroable)]
 struct Uniforms {
     mvp: [[f32; 4]; 4],
+    model: [[f32; 4]; 4],
+    // Inverse transpose of the model matrix so normals stay perpendicular under nonuniform scaling
+    normal: [[f32; 4]; 4],
+    // How much is drawn and whether it is the inverted pattern, see MeshLod
+    lod_fade: [f32; 4],
+    // The material's roughness and metallic factors for screen space reflections, rough by default
+    surface: [f32; 4],
 }
 
 #[derive(Debug)]
 struct DrawCall {
+    uniform: u32,
     base_vertex: i32,
     indices: Range<u32>,
 }
 
+#[derive(Debug)]
+struct MeshDraw {
+    mesh: GpuMesh,
+    uniform: u32,
+}
+
 #[derive(Debug)]
 pub struct Renderer {
     _instance: Instance,
     surface: Surface,
-    _adapter: Adapter,
+    adapter: Adapter,
+    adapter_info: AdapterInfo,
     device: Device,
     queue: Queue,
     swap_chain_descriptor: SwapChainDescriptor,
     swap_chain: SwapChain,
+    present_mode: PresentMode,
+    render_scale: f32,
 
     vertex_buffer: Buffer,
     index_buffer: Buffer,
@@ -123,47 +321,94 @@ pub struct Renderer {
     _uniform_bind_group_layout: BindGroupLayout,
     uniform_bind_group: BindGroup,
 
-    depth_texture: Texture,
-    depth_texture_view: TextureView,
-    _shader_module: ShaderModule,
-    _pipeline_layout: PipelineLayout,
+    graph: CompiledGraph,
+    post: PostProcessor,
+    depth_effects: DepthEffects,
+    decals: DecalRenderer,
+    sample_count: u32,
+    debug_view: Option<DebugView>,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
     pipeline: RenderPipeline,
+    texture_bind_group_layout: BindGroupLayout,
+    texture_array_layout: BindGroupLayout,
+    sprites: SpriteBatcher,
+    tiles: TileRenderer,
+    lighting_2d: Lighting2D,
+    stats: StatsOverlay,
+    // Uploads made between frames are counted into the next one
+    frame_stats: FrameStats,
+    last_frame_stats: FrameStats,
+    timer: GpuTimer,
+    camera_2d: Camera2D,
+    material_layout: MaterialLayout,
+    joints: JointBuffer,
+    instances: InstanceBuffer,
+    shadow_map: ShadowMap,
+    picker: ObjectPicker,
+    pick_id: Option<u32>,
+    outlines: OutlineRenderer,
+    outline: Option<[f32; 4]>,
+    pbr: PbrPipeline,
+    indirect: IndirectCuller,
+    particles: ParticleRenderer,
+    billboards: BillboardRenderer,
+    debug: DebugRenderer,
+    skybox: SkyboxRenderer,
+    capturer: FrameCapturer,
+    viewport_clearer: ViewportClearer,
+    shaders: ShaderRegistry,
+    terrains: TerrainRenderer,
+    water: WaterRenderer,
+    compute: ComputeRegistry,
+    directional_light: Option<DirectionalLight>,
+    ambient_light: [f32; 3],
 
     clear_color: [f64; 4],
     view: Isometry3<f32>,
     projection: Matrix4<f32>,
+    // What the view and projection came from, if anything
+    camera_3d: Option<Camera3D>,
+    viewports: Vec<Viewport>,
     _bound_texture: Option<Weak<crate::Texture>>,
-    draw_calls: Vec<Vec<DrawCall>>,
+    draw_calls: Vec<DrawCall>,
+    mesh_draws: Vec<MeshDraw>,
+    target_draws: Vec<TargetDraw>,
     vertex_data: Vec<Vertex>,
     index_data: Vec<u32>,
     uniform_data: Vec<Uniforms>,
+    // In world space for each of the uniforms, None is drawn from everywhere
+    bounds: Vec<Option<Aabb>>,
+    // Which of the uniforms' draws the camera being drawn from can see
+    visible: Vec<bool>,
+    frustum_culling: bool,
 }
 
 impl Renderer {
-    pub(crate) async fn new(window: &Window) -> Option<Renderer> {
+    pub(crate) async fn new(window: &Window, settings: &RendererSettings) -> Option<Renderer> {
         info!("Initializing rendering backend");
 
-        let instance = Instance::new(BackendBit::all());
+        let instance = adapter::create_instance(settings);
 
         let surface = unsafe { instance.create_surface(window) };
 
-        let adapter = match instance
-            .request_adapter(&RequestAdapterOptions {
-                power_preference: PowerPreference::HighPerformance,
-                compatible_surface: Some(&surface),
-            })
-            .await
-        {
+        let (adapter, adapter_info) = match adapter::select_adapter(&instance, &surface, settings).await {
             Some(adapter) => adapter,
             None => {
                 error!("Failed to find any suitable graphics adapter");
                 return None;
             },
         };
+        info!("Using {:?} adapter {}", adapter_info.adapter_type, adapter_info.name);
 
         let (device, queue) = match adapter
             .request_device(
-                &DeviceDescriptor { label: Some("device"), features: Features::empty(), limits: Limits::default() },
+                &DeviceDescriptor {
+                    label: Some("device"),
+                    // Wireframes and pass timings need them, and are only there where the adapter supports them
+                    features: adapter.features() & (Features::NON_FILL_POLYGON_MODE | Features::TIMESTAMP_QUERY),
+                    limits: Limits::default(),
+                },
                 None,
             )
             .await
@@ -177,7 +422,7 @@ impl Renderer {
 
         let window_size = window.size();
 
-        let (swap_chain_descriptor, swap_chain) = create_swap_chain(&device, &surface, window_size);
+        let (swap_chain_descriptor, swap_chain) = create_swap_chain(&device, &surface, window_size, PresentMode::Fifo);
 
         let vertex_buffer = device.create_buffer(&BufferDescriptor {
             label: Some("vertex_buffer"),
@@ -204,7 +449,7 @@ impl Renderer {
             label: None,
             entries: &[BindGroupLayoutEntry {
                 binding: 0,
-                visibility: ShaderStage::VERTEX,
+                visibility: ShaderStage::VERTEX | ShaderStage::FRAGMENT,
                 ty: BindingType::Buffer {
                     ty: BufferBindingType::Uniform,
                     has_dynamic_offset: true,
@@ -227,8 +472,6 @@ impl Renderer {
             label: None,
         });
 
-        let (depth_texture, depth_texture_view) = create_depth_texture(&device, window_size);
-
         let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
             label: Some("shader"),
             source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader.wgsl"))),
@@ -241,58 +484,115 @@ impl Renderer {
             push_constant_ranges: &[],
         });
 
-        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            vertex: VertexState {
-                module: &shader_module,
-                entry_point: "main",
-                buffers: &[VertexBufferLayout {
-                    array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
-                    step_mode: InputStepMode::Vertex,
-                    attributes: &vertex_attr_array![
-                        0 => Float32x3,
-                        1 => Float32x2,
-                        2 => Float32x3,
-                    ],
-                }],
-            },
-            primitive: PrimitiveState {
-                topology: PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: FrontFace::Ccw,
-                cull_mode: Some(Face::Back),
-                clamp_depth: false,
-                polygon_mode: PolygonMode::Fill,
-                conservative: false,
-            },
-            depth_stencil: Some(DepthStencilState {
-                format: DEPTH_TEXTURE_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: CompareFunction::GreaterEqual,
-                stencil: StencilState::default(),
-                bias: DepthBiasState::default(),
-            }),
-            multisample: MultisampleState { count: 1, mask: !0, alpha_to_coverage_enabled: false },
-            fragment: Some(FragmentState {
-                module: &shader_module,
-                entry_point: "main",
-                targets: &[ColorTargetState {
-                    format: swap_chain_descriptor.format,
-                    blend: Some(BlendState::REPLACE),
-                    write_mask: ColorWrite::ALL,
-                }],
-            }),
-        });
+        let pipeline = create_pipeline(&device, &pipeline_layout, &shader_module, HDR_TEXTURE_FORMAT, 1);
+
+        let texture_bind_group_layout = texture::create_bind_group_layout(&device);
+        let texture_array_layout = texture::create_array_bind_group_layout(&device);
+        let sprites = SpriteBatcher::new(
+            &device,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            &texture_bind_group_layout,
+            &texture_array_layout,
+        );
+        let lighting_2d = Lighting2D::new(
+            &device,
+            &queue,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            &texture_bind_group_layout,
+            window_size,
+        );
+        let stats = StatsOverlay::new(&device, &queue, &texture_bind_group_layout);
+        let timer = GpuTimer::new(&device, &queue);
+        let material_layout = MaterialLayout::new(&device, &queue, &texture_bind_group_layout, &texture_array_layout);
+        let joints = JointBuffer::new(&device);
+        let instances = InstanceBuffer::new(&device);
+        let shadow_map = ShadowMap::new(&device, &uniform_bind_group_layout, joints.layout());
+        let picker = ObjectPicker::new(&device, &uniform_bind_group_layout, joints.layout());
+        let outlines = OutlineRenderer::new(&device, &uniform_bind_group_layout, joints.layout(), TEXTURE_FORMAT);
+        let pbr = PbrPipeline::new(
+            &device,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            &uniform_bind_group_layout,
+            material_layout.layout(),
+            &shadow_map,
+            joints.layout(),
+        );
+        let indirect = IndirectCuller::new(&device);
+        let particles = ParticleRenderer::new(
+            &device,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            &texture_bind_group_layout,
+            material_layout.white(),
+        );
+        let billboards =
+            BillboardRenderer::new(&device, HDR_TEXTURE_FORMAT, DEPTH_TEXTURE_FORMAT, &texture_bind_group_layout);
+        let debug = DebugRenderer::new(&device, HDR_TEXTURE_FORMAT, DEPTH_TEXTURE_FORMAT);
+        let skybox = SkyboxRenderer::new(&device, HDR_TEXTURE_FORMAT, DEPTH_TEXTURE_FORMAT);
+        let viewport_clearer = ViewportClearer::new(&device, HDR_TEXTURE_FORMAT, DEPTH_TEXTURE_FORMAT);
+        let shaders = ShaderRegistry::new(
+            &device,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            &uniform_bind_group_layout,
+            pbr.scene_layout(),
+            material_layout.layout(),
+            material_layout.white(),
+        );
+        let terrains = TerrainRenderer::new(
+            &device,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            &uniform_bind_group_layout,
+            pbr.scene_layout(),
+            material_layout.white(),
+        );
+        let graph = CompiledGraph::new(
+            &device,
+            RenderGraph::default(),
+            window_size,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            1,
+            &shaders,
+        )
+        .ok()?;
+        let mut post = PostProcessor::new(&device, &queue, HDR_TEXTURE_FORMAT, swap_chain_descriptor.format, window_size);
+        post.set_premultiplied(window.transparent());
+        let depth_effects =
+            DepthEffects::new(&device, &uniform_bind_group_layout, joints.layout(), HDR_TEXTURE_FORMAT, &post, window_size);
+        let decals = DecalRenderer::new(
+            &device,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            &texture_bind_group_layout,
+            &depth_effects,
+            window_size,
+        );
+        let water = WaterRenderer::new(
+            &device,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+            &uniform_bind_group_layout,
+            pbr.scene_layout(),
+            &texture_bind_group_layout,
+            &depth_effects,
+        );
 
         Some(Renderer {
             _instance: instance,
             surface,
-            _adapter: adapter,
+            adapter,
+            adapter_info,
             device,
             queue,
             swap_chain_descriptor,
             swap_chain,
+            present_mode: PresentMode::Fifo,
+            render_scale: 1.,
 
             vertex_buffer,
             index_buffer,
@@ -300,71 +600,1294 @@ impl Renderer {
             _uniform_bind_group_layout: uniform_bind_group_layout,
             uniform_bind_group,
 
-            depth_texture,
-            depth_texture_view,
-            _shader_module: shader_module,
-            _pipeline_layout: pipeline_layout,
+            graph,
+            post,
+            depth_effects,
+            decals,
+            sample_count: 1,
+            debug_view: None,
+            shader_module,
+            pipeline_layout,
             pipeline,
+            texture_bind_group_layout,
+            texture_array_layout,
+            sprites,
+            tiles: TileRenderer::default(),
+            lighting_2d,
+            stats,
+            frame_stats: FrameStats::default(),
+            last_frame_stats: FrameStats::default(),
+            timer,
+            camera_2d: Camera2D::default(),
+            material_layout,
+            joints,
+            instances,
+            shadow_map,
+            picker,
+            pick_id: None,
+            outlines,
+            outline: None,
+            pbr,
+            indirect,
+            particles,
+            billboards,
+            debug,
+            skybox,
+            capturer: FrameCapturer::new(TEXTURE_FORMAT),
+            viewport_clearer,
+            shaders,
+            terrains,
+            water,
+            compute: ComputeRegistry::new(),
+            directional_light: Some(DirectionalLight::default()),
+            ambient_light: [0.03, 0.03, 0.03],
 
             clear_color: [0., 0., 0., 1.],
             view: Isometry3::identity(),
             projection: Matrix4::identity(),
+            camera_3d: None,
+            viewports: vec![],
             _bound_texture: None,
             vertex_data: vec![],
             index_data: vec![],
             uniform_data: vec![],
+            bounds: vec![],
+            visible: vec![],
+            frustum_culling: true,
             draw_calls: vec![],
+            mesh_draws: vec![],
+            target_draws: vec![],
         })
     }
 
     pub(crate) fn resize(&mut self, size: [u32; 2]) {
-        let (swap_chain_descriptor, swap_chain) = create_swap_chain(&self.device, &self.surface, size);
+        let (swap_chain_descriptor, swap_chain) = create_swap_chain(&self.device, &self.surface, size, self.present_mode);
         self.swap_chain_descriptor = swap_chain_descriptor;
         self.swap_chain = swap_chain;
+        self.apply_camera();
+        self.resize_scene();
+    }
 
-        let (depth_texture, depth_texture_view) = create_depth_texture(&self.device, size);
-        self.depth_texture = depth_texture;
-        self.depth_texture_view = depth_texture_view;
+    // The scene's targets follow the render size rather than the window
+    fn resize_scene(&mut self) {
+        let size = self.render_size();
+        self.graph.resize(&self.device, size, &self.shaders);
+        self.post.resize(&self.device, size);
+        self.depth_effects.resize(&self.device, &self.post, size);
+        self.decals.resize(&self.device, &self.depth_effects, size);
+        self.water.resize(&self.device, &self.depth_effects);
+        self.lighting_2d.resize(&self.device, &self.texture_bind_group_layout, size);
     }
 
+    // The scene is drawn at this fraction of the window's resolution and filtered onto the window, below one trades
+    // sharpness for speed and above one supersamples
+    pub fn set_render_scale(&mut self, render_scale: f32) -> &mut Self {
+        let render_scale = render_scale.max(0.1).min(4.);
+        if render_scale != self.render_scale {
+            self.render_scale = render_scale;
+            self.resize_scene();
+        }
+        self
+    }
+
+    pub fn render_scale(&self) -> f32 {
+        self.render_scale
+    }
+
+    // In pixels, what the render graph and its attachments are sized to
+    pub fn render_size(&self) -> [u32; 2] {
+        let width = (self.swap_chain_descriptor.width as f32 * self.render_scale) as u32;
+        let height = (self.swap_chain_descriptor.height as f32 * self.render_scale) as u32;
+        [width.max(1), height.max(1)]
+    }
+
+    // The adapter chosen by the settings the renderer was created with
+    pub fn adapter_info(&self) -> &AdapterInfo {
+        &self.adapter_info
+    }
+
+    // Every adapter of the backend, or of every backend for None, to choose from before creating the engine
+    pub fn available_adapters(backend: Option<GraphicsBackend>) -> Vec<AdapterInfo> {
+        adapter::available_adapters(backend)
+    }
+
+    // The alpha is kept through post processing, on a transparent window it is how much of the desktop is covered
     pub fn set_clear_color(&mut self, clear_color: [f64; 4]) -> &mut Self {
         self.clear_color = clear_color;
         self
     }
 
+    // Takes over from any camera that was set
     pub fn set_view(&mut self, view: Isometry3<f32>) -> &mut Self {
         self.view = view;
+        self.camera_3d = None;
         self
     }
 
     pub fn set_projection(&mut self, projection: Matrix4<f32>) -> &mut Self {
         self.projection = projection;
+        self.camera_3d = None;
         self
     }
 
+    pub fn view(&self) -> Isometry3<f32> {
+        self.view
+    }
+
+    pub fn projection(&self) -> Matrix4<f32> {
+        self.projection
+    }
+
+    pub fn view_projection(&self) -> Matrix4<f32> {
+        self.projection * self.view.to_homogeneous()
+    }
+
+    // Sets the view and projection from the camera, the projection follows the window's aspect ratio as it is resized
+    pub fn set_camera_3d(&mut self, camera: Camera3D) -> &mut Self {
+        self.camera_3d = Some(camera);
+        self.apply_camera();
+        self
+    }
+
+    pub fn camera_3d(&self) -> Option<Camera3D> {
+        self.camera_3d
+    }
+
+    fn apply_camera(&mut self) {
+        if let Some(camera) = self.camera_3d {
+            let [width, height] = self.viewport_size();
+            self.view = camera.view();
+            self.projection = camera.projection(width / height.max(1.));
+        }
+    }
+
+    // Draws the scene once for each viewport in place of the view and projection, sprites are drawn once over all of
+    // them. No viewports draws the whole window from the view and projection
+    pub fn set_viewports(&mut self, viewports: Vec<Viewport>) -> &mut Self {
+        self.viewports = viewports;
+        self
+    }
+
+    pub fn viewports(&self) -> &[Viewport] {
+        &self.viewports
+    }
+
     pub fn bind_texture(&mut self, _texture: &crate::Texture) -> &mut Self {
         self
     }
 
+    // Rgba8 pixels with rows tightly packed starting from the top
+    pub fn create_texture(&self, size: [u32; 2], rgba: &[u8], options: &TextureOptions) -> Result<crate::Texture> {
+        crate::Texture::from_rgba(&self.device, &self.queue, &self.texture_bind_group_layout, size, rgba, options)
+    }
+
+    // An encoded png or jpeg
+    pub fn create_texture_from_bytes(&self, bytes: &[u8], options: &TextureOptions) -> Result<crate::Texture> {
+        crate::Texture::from_bytes(&self.device, &self.queue, &self.texture_bind_group_layout, bytes, options)
+    }
+
+    pub fn create_texture_from_file<P: AsRef<Path>>(&self, path: P, options: &TextureOptions) -> Result<crate::Texture> {
+        self.create_texture_from_bytes(&fs::read(path)?, options)
+    }
+
+    // Rgba8 layers of the same size, each with rows tightly packed starting from the top
+    pub fn create_texture_array(&self, size: [u32; 2], layers: &[&[u8]], options: &TextureOptions) -> Result<TextureArray> {
+        TextureArray::from_rgba(&self.device, &self.queue, &self.texture_array_layout, size, layers, options)
+    }
+
+    // Encoded pngs or jpegs that all have the same size
+    pub fn create_texture_array_from_bytes(&self, layers: &[&[u8]], options: &TextureOptions) -> Result<TextureArray> {
+        TextureArray::from_bytes(&self.device, &self.queue, &self.texture_array_layout, layers, options)
+    }
+
+    // Black until frames are written to it, without mipmaps since every frame would have to rebuild them
+    pub fn create_video_texture(&self, player: &VideoPlayer) -> Result<crate::Texture> {
+        let size = player.size();
+        let rgba = vec![0; (size[0] * size[1] * 4) as usize];
+        self.create_texture(size, &rgba, &TextureOptions { mipmaps: false, ..TextureOptions::default() })
+    }
+
+    // Frames that don't match the size of the texture are left out
+    pub fn write_video_frame(&mut self, texture: &crate::Texture, frame: &VideoFrame) -> &mut Self {
+        if frame.size == texture.size() {
+            texture.write(&self.queue, [0, 0], frame.size, &frame.rgba);
+            self.frame_stats.buffer_uploads += 1;
+        }
+        self
+    }
+
+    // Rgba8 faces in the order +x, -x, +y, -y, +z, -z, each size by size pixels
+    pub fn create_cubemap(&self, size: u32, faces: [&[u8]; 6]) -> Result<Cubemap> {
+        Cubemap::from_rgba(&self.device, &self.queue, size, &faces)
+    }
+
+    // Image files for the faces in the order +x, -x, +y, -y, +z, -z
+    pub fn create_cubemap_from_files<P: AsRef<Path>>(&self, faces: [P; 6]) -> Result<Cubemap> {
+        let mut images = Vec::with_capacity(6);
+        for face in faces.iter() {
+            images.push(image::open(face)?.into_rgba8());
+        }
+        Cubemap::from_images(&self.device, &self.queue, &images)
+    }
+
+    // Drawn behind everything in the scene in place of the clear color, None goes back to the clear color. Screen space
+    // reflections show it where their rays miss
+    pub fn set_environment(&mut self, environment: Option<Cubemap>) -> &mut Self {
+        self.depth_effects.set_environment(&self.device, environment.as_ref());
+        self.skybox.set_environment(&self.device, environment);
+        self
+    }
+
+    pub fn environment(&self) -> Option<&Cubemap> {
+        self.skybox.environment()
+    }
+
+    // Sprites are drawn through this camera, by default world units are pixels from the center of the window
+    pub fn set_camera_2d(&mut self, camera: Camera2D) -> &mut Self {
+        self.camera_2d = camera;
+        self
+    }
+
+    pub fn camera_2d(&self) -> Camera2D {
+        self.camera_2d
+    }
+
+    pub fn viewport_size(&self) -> [f32; 2] {
+        [self.swap_chain_descriptor.width as f32, self.swap_chain_descriptor.height as f32]
+    }
+
+    pub fn world_to_screen(&self, point: Point2<f32>) -> Point2<f32> {
+        self.camera_2d.world_to_screen(point, self.viewport_size())
+    }
+
+    pub fn screen_to_world(&self, point: Point2<f32>) -> Point2<f32> {
+        self.camera_2d.screen_to_world(point, self.viewport_size())
+    }
+
+    // Sprites, text and tile layers drawn after this go on the layer until it is set again
+    pub fn set_render_layer(&mut self, layer: RenderLayer) -> &mut Self {
+        self.sprites.set_layer(layer);
+        self
+    }
+
+    pub fn render_layer(&self) -> RenderLayer {
+        self.sprites.layer()
+    }
+
+    // Sprites on a layer with lower keys draw under those with higher ones, like a sprite's y in a top down game drawn
+    // as its negative. Applies to every sprite drawn after this until it is set again
+    pub fn set_sort_key(&mut self, sort_key: f32) -> &mut Self {
+        self.sprites.set_sort_key(sort_key);
+        self
+    }
+
+    pub fn sort_key(&self) -> f32 {
+        self.sprites.sort_key()
+    }
+
+    // Sprites drawn after this are clipped to the rectangle until it is set again, in window pixels as
+    // [x, y, width, height] from the top left. None draws them unclipped
+    pub fn set_scissor(&mut self, scissor: Option<[u32; 4]>) -> &mut Self {
+        self.sprites.set_scissor(scissor);
+        self
+    }
+
+    pub fn scissor(&self) -> Option<[u32; 4]> {
+        self.sprites.scissor()
+    }
+
+    // How sprites drawn after this use the stencil buffer until it is set again, masks drawn with StencilMode::Write
+    // decide where sprites drawn with the tests show
+    pub fn set_stencil(&mut self, stencil: StencilMode) -> &mut Self {
+        self.sprites.set_stencil(stencil);
+        self
+    }
+
+    pub fn stencil(&self) -> StencilMode {
+        self.sprites.stencil()
+    }
+
+    // The transform maps a unit square centered on the origin into world space, y is up.
+    // Sprites sharing a layer and a sort key are batched by texture, so draw order only holds between sprites sharing one
+    pub fn draw_sprite(&mut self, texture: &crate::Texture, transform: Matrix3<f32>, tint: [f32; 4]) -> &mut Self {
+        self.sprites.push(texture, &transform, [0., 0., 1., 1.], tint);
+        self
+    }
+
+    // Part of the texture as [left, top, right, bottom] in texture coordinates
+    pub fn draw_sprite_region(
+        &mut self,
+        texture: &crate::Texture,
+        transform: Matrix3<f32>,
+        region: [f32; 4],
+        tint: [f32; 4],
+    ) -> &mut Self {
+        self.sprites.push(texture, &transform, region, tint);
+        self
+    }
+
+    // Sprites drawn from any of the layers batch together like sprites sharing a texture
+    pub fn draw_sprite_layer(
+        &mut self,
+        array: &TextureArray,
+        layer: u32,
+        transform: Matrix3<f32>,
+        tint: [f32; 4],
+    ) -> &mut Self {
+        self.sprites.push_layer(array, layer, &transform, [0., 0., 1., 1.], tint);
+        self
+    }
+
+    // Names the atlas doesn't have draw nothing
+    pub fn draw_atlas_sprite(
+        &mut self,
+        atlas: &TextureAtlas,
+        name: &str,
+        transform: Matrix3<f32>,
+        tint: [f32; 4],
+    ) -> &mut Self {
+        if let Some(region) = atlas.region(name) {
+            self.sprites.push(atlas.texture(), &transform, region, tint);
+        }
+        self
+    }
+
+    // Lit by the 2d lights over the ambient light and drawn over every unlit sprite. Normal maps are in tangent space with
+    // green pointing up the sprite, None lights the sprite as if it were flat
+    pub fn draw_lit_sprite(
+        &mut self,
+        texture: &crate::Texture,
+        normal_map: Option<&crate::Texture>,
+        transform: Matrix3<f32>,
+        tint: [f32; 4],
+    ) -> &mut Self {
+        self.lighting_2d.push_sprite(texture, normal_map, &transform, [0., 0., 1., 1.], tint);
+        self
+    }
+
+    // The region applies to the normal map as well as the texture
+    pub fn draw_lit_sprite_region(
+        &mut self,
+        texture: &crate::Texture,
+        normal_map: Option<&crate::Texture>,
+        transform: Matrix3<f32>,
+        region: [f32; 4],
+        tint: [f32; 4],
+    ) -> &mut Self {
+        self.lighting_2d.push_sprite(texture, normal_map, &transform, region, tint);
+        self
+    }
+
+    // Lights last a frame and only reach lit sprites
+    pub fn submit_light_2d(&mut self, light: Light2D) -> &mut Self {
+        self.lighting_2d.push_light(light);
+        self
+    }
+
+    // Blocks lights casting shadows along the line through the points in world space for a frame
+    pub fn draw_occluder(&mut self, points: &[Point2<f32>], closed: bool) -> &mut Self {
+        self.lighting_2d.push_occluder(points, closed);
+        self
+    }
+
+    pub fn set_ambient_light_2d(&mut self, ambient: [f32; 3]) -> &mut Self {
+        self.lighting_2d.set_ambient(ambient);
+        self
+    }
+
+    pub fn ambient_light_2d(&self) -> [f32; 3] {
+        self.lighting_2d.ambient()
+    }
+
+    // Borders keep their size however the transform scales the sprite, for panels and buttons
+    pub fn draw_nine_slice(
+        &mut self,
+        texture: &crate::Texture,
+        slice: &NineSlice,
+        transform: Matrix3<f32>,
+        tint: [f32; 4],
+    ) -> &mut Self {
+        for (transform, region) in slice.pieces(texture.size(), &transform) {
+            self.sprites.push(texture, &transform, region, tint);
+        }
+        self
+    }
+
+    // Sprites without a frame to show draw nothing
+    pub fn draw_animated_sprite(&mut self, sprite: &AnimatedSprite, transform: Matrix3<f32>, tint: [f32; 4]) -> &mut Self {
+        if let Some(region) = sprite.region() {
+            self.sprites.push(sprite.atlas().texture(), &transform, region, tint);
+        }
+        self
+    }
+
+    pub fn create_atlas(&self, packer: &AtlasPacker, options: &TextureOptions) -> Result<TextureAtlas> {
+        packer.pack(&self.device, &self.queue, &self.texture_bind_group_layout, options)
+    }
+
+    // Tiles are frames of the atlas, each world unit is a pixel at a camera zoom of one
+    pub fn create_tile_layer(&self, atlas: &TextureAtlas, size: [u32; 2], tile_size: [f32; 2]) -> TileLayer {
+        TileLayer::new(&self.device, self.sprites.uniform_layout(), atlas, size, tile_size)
+    }
+
+    // Layers draw under every sprite of the same render layer in the order of the calls, chunks with changed tiles are
+    // uploaded again first
+    pub fn draw_tile_layer(&mut self, layer: &mut TileLayer) -> &mut Self {
+        self.tiles.push(&self.device, &self.queue, layer, self.sprites.layer());
+        self
+    }
+
+    // Glyphs are drawn as sprites through the 2d camera. The position is the top of the first line and where lines
+    // start, are centered or end depending on the alignment
+    pub fn draw_text(&mut self, font: &mut Font, text: &str, position: Point2<f32>, style: &TextStyle) -> &mut Self {
+        let glyphs = font.layout(&self.device, &self.queue, &self.texture_bind_group_layout, text, position, style);
+        for (texture, transform, region) in glyphs {
+            self.sprites.push(&texture, &transform, region, style.color);
+        }
+        self
+    }
+
+    // Uploads the model's vertices every frame, upload_mesh and draw_mesh avoid that for anything drawn repeatedly
     pub fn draw_model(&mut self, model: &crate::Model, position: Point3<f32>, rotation: UnitQuaternion<f32>) -> &mut Self {
-        let mut draw_call = vec![];
+        let uniform = self.uniform_data.len() as u32;
+        let mut bounds: Option<Aabb> = None;
         for mesh in &model.meshes {
-            draw_call.push(DrawCall {
+            bounds = match (bounds, mesh.bounds()) {
+                (Some(bounds), Some(mesh_bounds)) => Some(bounds.union(&mesh_bounds)),
+                (bounds, mesh_bounds) => bounds.or(mesh_bounds),
+            };
+            self.draw_calls.push(DrawCall {
+                uniform,
                 base_vertex: self.vertex_data.len() as i32,
                 indices: self.index_data.len() as u32..(self.index_data.len() + mesh.indices.len()) as u32,
             });
             self.vertex_data.extend(&mesh.vertices);
             self.index_data.extend(&mesh.indices);
         }
-        self.draw_calls.push(draw_call);
 
         let model = Translation3::from(position) * rotation;
-        let mvp = self.projection * (self.view * model).to_homogeneous();
-        self.uniform_data.push(Uniforms { mvp: mvp.into() });
+        self.push_uniforms(model.to_homogeneous(), bounds);
+
+        self
+    }
+
+    pub fn upload_mesh(&self, mesh: &Mesh) -> GpuMesh {
+        GpuMesh::new(&self.device, mesh)
+    }
+
+    // Only drawn with a material whose mesh shader was created for the same layout, the vertices have to match it
+    pub fn upload_custom_mesh<V: Pod>(&self, vertices: &[V], indices: &[u32], layout: &VertexLayout) -> GpuMesh {
+        GpuMesh::with_layout(&self.device, vertices, indices, layout)
+    }
+
+    // Room for this many vertices and indices, it grows as needed when updated. With a layout it is a custom mesh
+    pub fn create_dynamic_mesh(
+        &self,
+        vertex_capacity: usize,
+        index_capacity: usize,
+        layout: Option<&VertexLayout>,
+    ) -> GpuMesh {
+        GpuMesh::dynamic(&self.device, vertex_capacity, index_capacity, layout)
+    }
+
+    // Meant for meshes changing every frame like trails and ropes, an update applies to every draw of the frame it's in
+    // so it should come before the mesh is drawn
+    pub fn update_mesh(&mut self, mesh: &mut GpuMesh, data: &Mesh) -> &mut Self {
+        mesh.update(&self.device, &self.queue, bytemuck::cast_slice(&data.vertices), &data.indices, data.bounds());
+        self.frame_stats.buffer_uploads += 2;
+        self
+    }
+
+    // Custom meshes keep their layout and are never culled
+    pub fn update_custom_mesh<V: Pod>(&mut self, mesh: &mut GpuMesh, vertices: &[V], indices: &[u32]) -> &mut Self {
+        mesh.update(&self.device, &self.queue, bytemuck::cast_slice(vertices), indices, None);
+        self.frame_stats.buffer_uploads += 2;
+        self
+    }
+
+    // The model matrix is combined with the view and projection set at the time of the call
+    pub fn draw_mesh(&mut self, mesh: &GpuMesh, model: Matrix4<f32>) -> &mut Self {
+        if !mesh.accepts(&MeshInput::Plain) {
+            return self;
+        }
 
+        let uniform = self.push_uniforms(model, mesh.bounds());
+        self.shadow_map.push(mesh, uniform, MeshInput::Plain);
+        self.picker.push(mesh, uniform, MeshInput::Plain, self.pick_id);
+        self.outlines.push(mesh, uniform, MeshInput::Plain, self.outline);
+        self.depth_effects.push(mesh, uniform, MeshInput::Plain);
+        self.mesh_draws.push(MeshDraw { mesh: mesh.clone(), uniform });
         self
     }
 
+    pub fn create_material(&self, material: &Material) -> GpuMaterial {
+        self.material_layout.create(&self.device, material)
+    }
+
+    pub fn draw_mesh_with_material(&mut self, mesh: &GpuMesh, material: &GpuMaterial, model: Matrix4<f32>) -> &mut Self {
+        self.push_material_draw(mesh, material, model, MeshInput::Plain, mesh.bounds());
+        self
+    }
+
+    // One draw for all of the instances, their transforms are combined with the view and projection set at the time of
+    // the call. Materials with a custom shader draw every instance on its own and ignore the colors
+    pub fn draw_mesh_instanced(&mut self, mesh: &GpuMesh, material: &GpuMaterial, instances: &[InstanceData]) -> &mut Self {
+        if material.shader().is_some() {
+            for instance in instances {
+                self.push_material_draw(mesh, material, instance.transform, MeshInput::Plain, mesh.bounds());
+            }
+            return self;
+        }
+
+        // Culled as a whole by the box around every instance
+        let bounds = mesh.bounds().and_then(|bounds| {
+            let mut boxes = instances.iter().map(|instance| bounds.transform(&instance.transform));
+            let first = boxes.next()?;
+            Some(boxes.fold(first, |union, aabb| union.union(&aabb)))
+        });
+        let instances = self.instances.push(instances);
+        self.push_material_draw(mesh, material, Matrix4::identity(), MeshInput::Instanced(instances), bounds);
+        self
+    }
+
+    // The joint matrices move the mesh from its bind pose to where each joint is, relative to the model.
+    // Meshes without a skin and materials with a custom shader are drawn in the bind pose
+    pub fn draw_skinned_mesh(
+        &mut self,
+        mesh: &GpuMesh,
+        material: &GpuMaterial,
+        model: Matrix4<f32>,
+        joints: &[Matrix4<f32>],
+    ) -> &mut Self {
+        // Joints can move the vertices anywhere, so skinned meshes are never culled
+        let (input, bounds) = match self.joints.push(joints) {
+            Some(slot) => (MeshInput::Skinned(slot), None),
+            None => (MeshInput::Plain, mesh.bounds()),
+        };
+        self.push_material_draw(mesh, material, model, input, bounds);
+        self
+    }
+
+    // The level is picked by how much of the first viewport's camera the model covers, or the view and projection set
+    // at the time of the call without viewports. Materials with a custom shader draw both levels while fading
+    pub fn draw_mesh_lod(&mut self, lod: &MeshLod, material: &GpuMaterial, model: Matrix4<f32>) -> &mut Self {
+        let (view, projection) =
+            self.viewports.first().map_or((self.view, self.projection), |viewport| (viewport.view, viewport.projection));
+        let coverage = lod.coverage(&model, &(projection * view.to_homogeneous()), &projection);
+        for (index, (mesh, weight)) in lod.select(coverage).into_iter().enumerate() {
+            let uniform = self.push_material_draw(mesh, material, model, MeshInput::Plain, mesh.bounds());
+            self.uniform_data[uniform as usize].lod_fade = [weight, index as f32, 0., 0.];
+        }
+        self
+    }
+
+    // Levels past the eighth are left out, and a lod with a single level at zero draws every instance with its mesh
+    pub fn create_indirect_batch(&self, lod: &MeshLod, instances: &[InstanceData]) -> IndirectBatch {
+        self.indirect.create(&self.device, &self.queue, lod, instances)
+    }
+
+    // Replaces the instances from the first one on, those past the end of the batch are left out
+    pub fn write_indirect_instances(&mut self, batch: &IndirectBatch, first: u32, instances: &[InstanceData]) -> &mut Self {
+        self.indirect.write(&self.queue, batch, first, instances);
+        self.frame_stats.buffer_uploads += 1;
+        self
+    }
+
+    // Culls the instances and picks their levels like draw_mesh_lod does on the gpu, with one indirect draw for each
+    // level. Levels swap without fading, and only what the camera sees casts shadows. A batch is drawn once a frame
+    // however often it is pushed, and materials with a custom shader are drawn with the built in one, which is the only
+    // one that reads the culled instances
+    pub fn draw_indirect(&mut self, batch: &IndirectBatch, material: &GpuMaterial) -> &mut Self {
+        let (view, projection) =
+            self.viewports.first().map_or((self.view, self.projection), |viewport| (viewport.view, viewport.projection));
+        let view_projection = projection * view.to_homogeneous();
+        let levels = self.indirect.push(&self.queue, batch, view_projection, &projection, self.frustum_culling);
+        // Pushing writes the culling uniforms and resets the draws
+        if !levels.is_empty() {
+            self.frame_stats.buffer_uploads += 2;
+        }
+        for (mesh, input) in levels {
+            let uniform = self.push_uniforms(Matrix4::identity(), None);
+            self.push_pbr_draw(&mesh, material, uniform, input);
+        }
+        self
+    }
+
+    // Layers past the fourth are left out
+    pub fn create_terrain(&self, terrain: &Terrain, material: &TerrainMaterial) -> GpuTerrain {
+        self.terrains.create(&self.device, terrain, material)
+    }
+
+    // Levels of detail follow the first viewport's camera like mesh lods do
+    pub fn draw_terrain(&mut self, terrain: &GpuTerrain) -> &mut Self {
+        let view = self.viewports.first().map_or(self.view, |viewport| viewport.view);
+        for (mesh, model) in terrain.meshes(view.inverse() * Point3::origin()) {
+            let uniform = self.push_uniforms(model, mesh.bounds());
+            self.shadow_map.push(&mesh, uniform, MeshInput::Plain);
+            self.picker.push(&mesh, uniform, MeshInput::Plain, self.pick_id);
+            self.outlines.push(&mesh, uniform, MeshInput::Plain, self.outline);
+            self.depth_effects.push(&mesh, uniform, MeshInput::Plain);
+            self.terrains.push(terrain, mesh, uniform);
+        }
+        self
+    }
+
+    // The bounds are relative to the model
+    fn push_material_draw(
+        &mut self,
+        mesh: &GpuMesh,
+        material: &GpuMaterial,
+        model: Matrix4<f32>,
+        input: MeshInput,
+        bounds: Option<Aabb>,
+    ) -> u32 {
+        let uniform = self.push_uniforms(model, bounds);
+        match material.shader() {
+            Some(shader) => {
+                self.shadow_map.push(mesh, uniform, MeshInput::Plain);
+                self.picker.push(mesh, uniform, MeshInput::Plain, self.pick_id);
+                self.outlines.push(mesh, uniform, MeshInput::Plain, self.outline);
+                self.depth_effects.push(mesh, uniform, MeshInput::Plain);
+                self.shaders.push_mesh(shader, mesh, material, uniform);
+            },
+            None => self.push_pbr_draw(mesh, material, uniform, input),
+        }
+        uniform
+    }
+
+    fn push_pbr_draw(&mut self, mesh: &GpuMesh, material: &GpuMaterial, uniform: u32, input: MeshInput) {
+        self.shadow_map.push(mesh, uniform, input.clone());
+        self.picker.push(mesh, uniform, input.clone(), self.pick_id);
+        self.outlines.push(mesh, uniform, input.clone(), self.outline);
+        self.uniform_data[uniform as usize].surface = material.surface();
+        // Transparent meshes leave the depth behind them for the effects reading it
+        if !material.transparent() {
+            self.depth_effects.push(mesh, uniform, input.clone());
+        }
+        self.pbr.push(mesh, material, uniform, input);
+    }
+
+    // Keeps an id texture of the meshes drawn with a pick id, off by default
+    pub fn set_picking(&mut self, picking: bool) -> &mut Self {
+        self.picker.set_enabled(picking);
+        self
+    }
+
+    pub fn picking(&self) -> bool {
+        self.picker.enabled()
+    }
+
+    // Meshes drawn until it is set again can be picked as this id, u32::MAX is taken to mean nothing.
+    // Every instance of an instanced draw is picked as the same id
+    pub fn set_pick_id(&mut self, id: Option<u32>) -> &mut Self {
+        self.pick_id = id;
+        self
+    }
+
+    pub fn pick_id(&self) -> Option<u32> {
+        self.pick_id
+    }
+
+    // The id of the closest mesh under a point in pixels from the top left of the window in the last submitted frame.
+    // Waits for the gpu, so it is meant for clicks rather than every frame
+    pub fn pick(&self, position: Point2<f32>) -> Option<u32> {
+        if position.x < 0. || position.y < 0. {
+            return None;
+        }
+        self.picker.pick(&self.device, &self.queue, [position.x as u32, position.y as u32])
+    }
+
+    // Meshes drawn until it is set again are outlined in this color over the finished frame, even where something is
+    // in front of them. Every instance of an instanced draw is outlined
+    pub fn set_outline(&mut self, color: Option<[f32; 4]>) -> &mut Self {
+        self.outline = color;
+        self
+    }
+
+    pub fn outline(&self) -> Option<[f32; 4]> {
+        self.outline
+    }
+
+    // In window pixels from one to sixteen, two by default
+    pub fn set_outline_width(&mut self, width: f32) -> &mut Self {
+        self.outlines.set_width(width);
+        self
+    }
+
+    pub fn outline_width(&self) -> f32 {
+        self.outlines.width()
+    }
+
+    // Replaces the passes run every frame, the default one draws the scene straight to the screen
+    pub fn set_render_graph(&mut self, graph: RenderGraph) -> Result<&mut Self> {
+        let size = self.render_size();
+        let sample_count = self.sample_count;
+        let (format, depth_format) = (HDR_TEXTURE_FORMAT, DEPTH_TEXTURE_FORMAT);
+        self.graph = CompiledGraph::new(&self.device, graph, size, format, depth_format, sample_count, &self.shaders)?;
+        Ok(self)
+    }
+
+    pub fn render_graph(&self) -> &RenderGraph {
+        self.graph.graph()
+    }
+
+    // Applied to whatever the render graph draws to the screen, None only clips it onto the screen
+    pub fn set_post_effects(&mut self, effects: Option<PostEffects>) -> &mut Self {
+        self.post.set_effects(effects);
+        self
+    }
+
+    pub fn post_effects(&self) -> Option<PostEffects> {
+        self.post.effects()
+    }
+
+    // Colors from zero to one with red changing fastest, then green, then blue
+    pub fn create_color_lut(&self, size: u32, colors: &[[f32; 3]]) -> Result<ColorLut> {
+        ColorLut::new(&self.device, &self.queue, size, colors)
+    }
+
+    // A .cube file like Resolve and Photoshop export
+    pub fn load_color_lut<P: AsRef<Path>>(&self, path: P) -> Result<ColorLut> {
+        let source = fs::read_to_string(path)?;
+        ColorLut::from_cube(&self.device, &self.queue, &source)
+    }
+
+    // Applied after tonemapping to the screen and render targets, None turns it off
+    pub fn set_color_grading(&mut self, grading: Option<ColorGrading>) -> &mut Self {
+        self.post.set_grading(&self.device, grading);
+        self
+    }
+
+    pub fn color_grading(&self) -> Option<&ColorGrading> {
+        self.post.grading()
+    }
+
+    // Skips meshes the camera can't see, they still cast shadows
+    pub fn set_frustum_culling(&mut self, frustum_culling: bool) -> &mut Self {
+        self.frustum_culling = frustum_culling;
+        self
+    }
+
+    pub fn frustum_culling(&self) -> bool {
+        self.frustum_culling
+    }
+
+    // Takes effect from the next frame
+    pub fn set_present_mode(&mut self, present_mode: PresentMode) -> &mut Self {
+        if present_mode != self.present_mode {
+            self.present_mode = present_mode;
+            self.swap_chain_descriptor.present_mode = present_mode.to_wgpu();
+            self.swap_chain = self.device.create_swap_chain(&self.surface, &self.swap_chain_descriptor);
+        }
+        self
+    }
+
+    pub fn present_mode(&self) -> PresentMode {
+        self.present_mode
+    }
+
+    // Samples per pixel in scene passes, 1 turns multisampling off. Every scene pipeline and target is rebuilt
+    pub fn set_sample_count(&mut self, sample_count: u32) -> Result<&mut Self> {
+        // Scene passes render into the hdr and depth targets, both have to be multisampled
+        let supported = [HDR_TEXTURE_FORMAT, DEPTH_TEXTURE_FORMAT]
+            .iter()
+            .all(|format| self.adapter.get_texture_format_features(*format).flags.sample_count_supported(sample_count));
+        if !supported {
+            return Err(GearError::UnsupportedSampleCount(sample_count));
+        }
+        if sample_count == self.sample_count {
+            return Ok(self);
+        }
+
+        let size = self.render_size();
+        self.sample_count = sample_count;
+        self.pipeline =
+            create_pipeline(&self.device, &self.pipeline_layout, &self.shader_module, HDR_TEXTURE_FORMAT, sample_count);
+        self.sprites.set_sample_count(&self.device, sample_count);
+        self.lighting_2d.set_sample_count(&self.device, sample_count);
+        self.pbr.set_sample_count(&self.device, sample_count);
+        self.particles.set_sample_count(&self.device, sample_count);
+        self.billboards.set_sample_count(&self.device, sample_count);
+        self.debug.set_sample_count(&self.device, sample_count);
+        self.skybox.set_sample_count(&self.device, sample_count);
+        self.viewport_clearer.set_sample_count(&self.device, sample_count);
+        self.shaders.set_sample_count(&self.device, sample_count);
+        self.terrains.set_sample_count(&self.device, sample_count);
+        self.water.set_sample_count(&self.device, sample_count);
+        self.decals.set_sample_count(&self.device, sample_count);
+        self.graph.set_sample_count(&self.device, size, sample_count, &self.shaders);
+        Ok(self)
+    }
+
+    // The next submitted frame as it is presented, ready once a frame or two more have been submitted
+    pub fn capture_frame(&mut self) -> FrameCapture {
+        self.capturer.capture()
+    }
+
+    // Saves the next submitted frame in the background, the image format is picked from the extension
+    pub fn save_frame<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.capturer.save(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn create_render_target(&self, size: [u32; 2], format: RenderTargetFormat) -> RenderTarget {
+        RenderTarget::new(
+            &self.device,
+            &self.texture_bind_group_layout,
+            &self.post,
+            size,
+            format,
+            HDR_TEXTURE_FORMAT,
+            DEPTH_TEXTURE_FORMAT,
+        )
+    }
+
+    // Draws this frame's meshes, lights, billboards and particles from another camera into the target before the frame
+    // itself.
+    // Sprites, fullscreen shaders and debug shapes are left out, and a target's texture must not be drawn into itself
+    pub fn render_to_target(&mut self, target: &RenderTarget, view: Isometry3<f32>, projection: Matrix4<f32>) -> &mut Self {
+        self.target_draws.push(TargetDraw { target: target.clone(), view, projection });
+        self
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    // Meshes drawn with a material's own shader or without a material are drawn as usual
+    pub fn set_debug_view(&mut self, debug_view: Option<DebugView>) -> Result<&mut Self> {
+        if debug_view == Some(DebugView::Wireframe) && !self.device.features().contains(Features::NON_FILL_POLYGON_MODE) {
+            return Err(GearError::UnsupportedFeature("wireframe".into()));
+        }
+        if debug_view == self.debug_view {
+            return Ok(self);
+        }
+
+        self.debug_view = debug_view;
+        self.pbr.set_debug_view(&self.device, debug_view);
+        Ok(self)
+    }
+
+    pub fn debug_view(&self) -> Option<DebugView> {
+        self.debug_view
+    }
+
+    // Frame rate, frame time and draw calls in the top left corner of the window, drawn over every render layer
+    pub fn show_stats(&mut self, show: bool) -> &mut Self {
+        self.stats.set_enabled(show);
+        self
+    }
+
+    pub fn stats_shown(&self) -> bool {
+        self.stats.enabled()
+    }
+
+    // Timestamps around each pass, which the adapter has to support
+    pub fn set_gpu_timing(&mut self, enabled: bool) -> Result<&mut Self> {
+        if enabled && !self.timer.supported() {
+            return Err(GearError::UnsupportedFeature("timestamp queries".into()));
+        }
+        self.timer.set_enabled(enabled);
+        Ok(self)
+    }
+
+    pub fn gpu_timing(&self) -> bool {
+        self.timer.enabled()
+    }
+
+    // How long the gpu spent on each pass of a recent frame in the order they ran. They take a frame or more to come
+    // back, and are empty until they do or with timing off
+    pub fn gpu_timings(&self) -> &[GpuTiming] {
+        self.timer.timings()
+    }
+
+    // Counted on the cpu as the last submitted frame was recorded, so they're there without any support from the adapter
+    pub fn frame_stats(&self) -> FrameStats {
+        self.last_frame_stats
+    }
+
+    // Quads in screen space are brought into world space so they stay put however the 2d camera moves
+    fn draw_stats(&mut self) {
+        let world = match self.camera_2d.view().try_inverse() {
+            Some(world) => world,
+            None => return,
+        };
+        let state = (self.sprites.layer(), self.sprites.sort_key(), self.sprites.stencil(), self.sprites.scissor());
+        self.sprites.set_layer(RenderLayer(i32::MAX));
+        self.sprites.set_sort_key(0.);
+        self.sprites.set_stencil(StencilMode::Off);
+        self.sprites.set_scissor(None);
+        for (transform, region, tint) in self.stats.quads(self.viewport_size()) {
+            self.sprites.push(self.stats.font(), &(world * transform), region, tint);
+        }
+        let (layer, sort_key, stencil, scissor) = state;
+        self.sprites.set_layer(layer);
+        self.sprites.set_sort_key(sort_key);
+        self.sprites.set_stencil(stencil);
+        self.sprites.set_scissor(scissor);
+    }
+
+    pub fn create_shader(&mut self, source: &str, kind: ShaderKind) -> Result<ShaderId> {
+        self.shaders.create(&self.device, source, kind)
+    }
+
+    // A mesh shader for custom meshes of the layout, its vertex stage reads the attributes at their locations
+    pub fn create_custom_mesh_shader(&mut self, source: &str, layout: &VertexLayout) -> Result<ShaderId> {
+        self.shaders.create_with_layout(&self.device, source, ShaderKind::Mesh, Some(layout))
+    }
+
+    // Watched shaders are recompiled whenever the file changes, the new pipeline is used from the next frame on
+    pub fn load_shader<P: AsRef<Path>>(&mut self, path: P, kind: ShaderKind, watch: bool) -> Result<ShaderId> {
+        self.shaders.load(&self.device, path.as_ref(), kind, watch)
+    }
+
+    // Drawn after every mesh in the order of the calls, sprites still draw on top
+    pub fn draw_fullscreen(&mut self, shader: ShaderId) -> &mut Self {
+        if self.shaders.is_fullscreen(shader) {
+            self.shaders.push_fullscreen(shader);
+        }
+        self
+    }
+
+    // The entry point is named main and the bindings are group zero in order, there is no prelude
+    pub fn create_compute_shader(&mut self, source: &str, bindings: &[ComputeBindingKind]) -> Result<ComputeShaderId> {
+        self.compute.create(&self.device, source, bindings)
+    }
+
+    // Zeroed, the size is rounded up to a multiple of four bytes
+    pub fn create_storage_buffer(&self, size: u64) -> StorageBuffer {
+        StorageBuffer::new(&self.device, size)
+    }
+
+    // Written before the next frame's dispatches run, the offset in bytes is a multiple of four
+    pub fn write_storage_buffer<T: Pod>(&mut self, buffer: &StorageBuffer, offset: u64, data: &[T]) -> &mut Self {
+        buffer.write(&self.queue, offset, bytemuck::cast_slice(data));
+        self.frame_stats.buffer_uploads += 1;
+        self
+    }
+
+    // The contents once everything submitted so far has run. Waits for the gpu, so it is meant for results needed now
+    pub fn read_storage_buffer(&self, buffer: &StorageBuffer) -> Option<Vec<u8>> {
+        buffer.read(&self.device, &self.queue)
+    }
+
+    // Rgba8 that compute shaders write and anything else can draw
+    pub fn create_storage_texture(&self, size: [u32; 2], options: &TextureOptions) -> StorageTexture {
+        StorageTexture::new(&self.device, &self.texture_bind_group_layout, size, options)
+    }
+
+    // Runs in the render graph's compute pass, or before anything is drawn in a graph without one
+    pub fn dispatch(
+        &mut self,
+        shader: ComputeShaderId,
+        bindings: &[ComputeBinding],
+        workgroups: [u32; 3],
+    ) -> Result<&mut Self> {
+        self.compute.dispatch(&self.device, shader, bindings, workgroups)?;
+        Ok(self)
+    }
+
+    // Images are uploaded once per use as color or as data, primitives without a material get the default one
+    pub fn upload_scene(&self, scene: &Scene) -> Result<GpuScene> {
+        let mut textures = HashMap::new();
+        let mut texture = |image: Option<usize>, srgb: bool| -> Result<Option<crate::Texture>> {
+            let image = match image {
+                Some(image) => image,
+                None => return Ok(None),
+            };
+            if let Some(texture) = textures.get(&(image, srgb)) {
+                return Ok(Some(texture.clone()));
+            }
+
+            let data = &scene.images[image];
+            let options = TextureOptions { srgb, wrap: TextureWrap::Repeat, ..Default::default() };
+            let texture = self.create_texture(data.size, &data.rgba, &options)?;
+            textures.insert((image, srgb), texture.clone());
+            Ok(Some(texture))
+        };
+
+        let mut materials = vec![];
+        for material in &scene.materials {
+            let material = Material {
+                albedo: texture(material.albedo, true)?,
+                albedo_factor: material.albedo_factor,
+                normal: texture(material.normal, false)?,
+                normal_scale: material.normal_scale,
+                metallic_roughness: texture(material.metallic_roughness, false)?,
+                metallic_factor: material.metallic_factor,
+                roughness_factor: material.roughness_factor,
+                emissive: texture(material.emissive, true)?,
+                emissive_factor: material.emissive_factor,
+                shader: None,
+                transparent: material.transparent,
+            };
+            materials.push(self.create_material(&material));
+        }
+        let default_material = self.create_material(&Material::default());
+
+        let meshes = scene
+            .meshes
+            .iter()
+            .map(|mesh| {
+                mesh.primitives
+                    .iter()
+                    .map(|primitive| {
+                        let material = primitive.material.map_or(&default_material, |material| &materials[material]);
+                        let mesh = match &primitive.skin {
+                            Some(skin) => GpuMesh::with_skin(&self.device, &primitive.mesh, skin),
+                            None => self.upload_mesh(&primitive.mesh),
+                        };
+                        (mesh, material.clone())
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(GpuScene {
+            meshes,
+            nodes: scene.nodes.clone(),
+            skins: scene.skins.clone(),
+            animations: scene.animations.clone(),
+            roots: scene.roots.clone(),
+        })
+    }
+
+    // Draws the scene at rest
+    pub fn draw_scene(&mut self, scene: &GpuScene, transform: Matrix4<f32>) -> &mut Self {
+        self.draw_scene_pose(scene, &scene.pose(None), transform)
+    }
+
+    pub fn draw_scene_animated(
+        &mut self,
+        scene: &GpuScene,
+        player: &AnimationPlayer,
+        transform: Matrix4<f32>,
+    ) -> &mut Self {
+        self.draw_scene_pose(scene, &scene.pose(Some(player)), transform)
+    }
+
+    fn draw_scene_pose(&mut self, scene: &GpuScene, pose: &[Matrix4<f32>], transform: Matrix4<f32>) -> &mut Self {
+        scene.visit(pose, transform, |primitives, transform, joints| {
+            for (mesh, material) in primitives {
+                match joints {
+                    Some(joints) => self.draw_skinned_mesh(mesh, material, transform, joints),
+                    None => self.draw_mesh_with_material(mesh, material, transform),
+                };
+            }
+        });
+        self
+    }
+
+    pub fn create_particle_emitter(&self, settings: ParticleSettings) -> ParticleEmitter {
+        self.particles.create_emitter(&self.device, settings)
+    }
+
+    // Drawn after every mesh with the view and projection set at the time of submission
+    pub fn draw_particles(&mut self, emitter: &mut ParticleEmitter) -> &mut Self {
+        self.particles.push(&self.queue, emitter);
+        self
+    }
+
+    // Projected onto the meshes drawn before billboards and particles, with the view and projection set at the time of
+    // submission. Only meshes on the gpu take decals, and render targets and passes of another size leave them out
+    pub fn draw_decal(&mut self, decal: &Decal) -> &mut Self {
+        self.decals.push(decal, 1.);
+        self
+    }
+
+    // Every decal in the pool faded by its age
+    pub fn draw_decals(&mut self, pool: &DecalPool) -> &mut Self {
+        for (decal, opacity) in pool.iter() {
+            self.decals.push(decal, opacity);
+        }
+        self
+    }
+
+    // Drawn over the meshes and under billboards and particles, seeing through to what is below it. Like decals it only
+    // shows on the screen and not in render targets or passes of another size
+    pub fn draw_water(&mut self, water: &Water) -> &mut Self {
+        let uniform = self.push_uniforms(water.transform(), Some(Water::bounds()));
+        self.water.push(water, uniform);
+        self
+    }
+
+    // Drawn after every mesh and sorted back to front with the view and projection set at the time of submission, the
+    // texture is sampled as a sprite texture
+    pub fn draw_billboard(&mut self, texture: &crate::Texture, billboard: &Billboard) -> &mut Self {
+        self.billboards.push(texture, billboard);
+        self
+    }
+
+    // Debug shapes are in world space, drawn over the scene with the view and projection set at the time of submission and
+    // gone the frame after
+    pub fn debug_line(&mut self, start: Point3<f32>, end: Point3<f32>, color: [f32; 4]) -> &mut Self {
+        self.debug.line(start, end, color);
+        self
+    }
+
+    pub fn debug_aabb(&mut self, min: Point3<f32>, max: Point3<f32>, color: [f32; 4]) -> &mut Self {
+        self.debug.aabb(min, max, color);
+        self
+    }
+
+    pub fn debug_sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4]) -> &mut Self {
+        self.debug.sphere(center, radius, color);
+        self
+    }
+
+    // The transform's x, y and z axes in red, green and blue, each as long as the size before the transform
+    pub fn debug_axes(&mut self, transform: Matrix4<f32>, size: f32) -> &mut Self {
+        self.debug.axes(&transform, size);
+        self
+    }
+
+    // Lights everything drawn with a material, None leaves only the ambient and emissive terms.
+    // Meshes drawn with or without a material cast its shadows, models drawn with draw_model don't
+    pub fn set_directional_light(&mut self, light: Option<DirectionalLight>) -> &mut Self {
+        self.directional_light = light;
+        self
+    }
+
+    pub fn set_ambient_light(&mut self, color: [f32; 3]) -> &mut Self {
+        self.ambient_light = color;
+        self
+    }
+
+    // None clears the air, meshes drawn without a material, billboards and particles are never fogged
+    pub fn set_fog(&mut self, fog: Option<Fog>) -> &mut Self {
+        self.pbr.set_fog(fog);
+        self.skybox.set_fog(fog);
+        self
+    }
+
+    // Point and spot lights only last for the frame they are submitted in, like draws
+    pub fn submit_light<L: Into<Light>>(&mut self, light: L) -> &mut Self {
+        self.pbr.push_light(light.into());
+        self
+    }
+
+    fn push_uniforms(&mut self, model: Matrix4<f32>, bounds: Option<Aabb>) -> u32 {
+        let mvp = self.projection * self.view.to_homogeneous() * model;
+        let normal = model.try_inverse().unwrap_or_else(Matrix4::identity).transpose();
+        self.uniform_data.push(Uniforms {
+            mvp: mvp.into(),
+            model: model.into(),
+            normal: normal.into(),
+            lod_fade: [1., 0., 0., 0.],
+            surface: [1., 0., 0., 0.],
+        });
+        self.bounds.push(bounds.map(|bounds| bounds.transform(&model)));
+        self.uniform_data.len() as u32 - 1
+    }
+
+    // Decals read the depth prepass, which is only drawn for the screen
+    fn render_meshes<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        vertex_data_len: usize,
+        index_data_len: usize,
+        decals: bool,
+    ) -> FrameStats {
+        let mut stats = self.skybox.render(render_pass);
+
+        if self.draw_calls.len() > 0 {
+            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(0..vertex_data_len as u64));
+            render_pass.set_index_buffer(self.index_buffer.slice(0..index_data_len as u64), IndexFormat::Uint32);
+            render_pass.set_pipeline(&self.pipeline);
+            stats.pipeline_switches += 1;
+            for draw_call in self.draw_calls.iter().filter(|draw_call| self.visible[draw_call.uniform as usize]) {
+                let offset = (draw_call.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+                render_pass.set_bind_group(0, &self.uniform_bind_group, &[offset]);
+                render_pass.draw_indexed(draw_call.indices.clone(), draw_call.base_vertex, 0..1);
+                stats.draw(draw_call.indices.end - draw_call.indices.start, 1);
+            }
+        }
+
+        if self.mesh_draws.len() > 0 {
+            render_pass.set_pipeline(&self.pipeline);
+            stats.pipeline_switches += 1;
+            for draw in self.mesh_draws.iter().filter(|draw| self.visible[draw.uniform as usize]) {
+                let offset = (draw.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+                render_pass.set_bind_group(0, &self.uniform_bind_group, &[offset]);
+                render_pass.set_vertex_buffer(0, draw.mesh.vertex_buffer().slice(..));
+                render_pass.set_index_buffer(draw.mesh.index_buffer().slice(..), IndexFormat::Uint32);
+                render_pass.draw_indexed(0..draw.mesh.index_count(), 0, 0..1);
+                stats.draw(draw.mesh.index_count(), 1);
+            }
+        }
+
+        stats += self.pbr.render(render_pass, &self.uniform_bind_group, &self.joints, &self.instances, &self.visible);
+        let scene_bind_group = self.pbr.scene_bind_group();
+        stats += self.shaders.render_meshes(render_pass, &self.uniform_bind_group, scene_bind_group, &self.visible);
+        stats += self.terrains.render(render_pass, &self.uniform_bind_group, scene_bind_group, &self.visible);
+        if decals {
+            stats += self.decals.render(render_pass);
+        }
+        stats
+    }
+
+    // After the meshes and water, neither of which they write depth for
+    fn render_transparent<'a>(&'a self, render_pass: &mut RenderPass<'a>) -> FrameStats {
+        let mut stats = self.billboards.render(render_pass);
+        stats += self.particles.render(render_pass);
+        stats
+    }
+
+    // Points the uniforms of the frame at a camera, everything submitted until they are written again sees it. Returns
+    // the number of writes
+    fn prepare_view(
+        &mut self,
+        view: Isometry3<f32>,
+        projection: Matrix4<f32>,
+        light_view_projection: Option<Matrix4<f32>>,
+    ) -> usize {
+        let view_projection = projection * view.to_homogeneous();
+        let uniforms = self
+            .uniform_data
+            .iter()
+            .map(|uniforms| {
+                let mvp = view_projection * Matrix4::from(uniforms.model);
+                Uniforms { mvp: mvp.into(), ..*uniforms }
+            })
+            .collect::<Vec<_>>();
+        self.queue.write_buffer(&self.uniform_buffer, 0, uniform_bytes(&uniforms));
+
+        let frustum = Frustum::from_view_projection(&view_projection);
+        let culling = self.frustum_culling;
+        self.visible = self
+            .bounds
+            .iter()
+            .map(|bounds| !culling || bounds.map_or(true, |bounds| frustum.intersects(&bounds)))
+            .collect();
+
+        let camera_position = view.inverse() * Point3::origin();
+        let distances: Vec<f32> = self
+            .uniform_data
+            .iter()
+            .map(|uniforms| {
+                (Point3::new(uniforms.model[3][0], uniforms.model[3][1], uniforms.model[3][2]) - camera_position).norm()
+            })
+            .collect();
+        self.pbr.sort_transparent(&distances);
+        let mut writes = 1 + self.pbr.prepare(
+            &self.queue,
+            view_projection,
+            camera_position,
+            self.directional_light,
+            self.ambient_light,
+            light_view_projection,
+        );
+        writes += self.particles.prepare(&self.queue, view_projection, view);
+        writes += self.billboards.prepare(&self.queue, view_projection, view);
+        writes += self.debug.prepare(&self.queue, view_projection);
+        writes += self.skybox.prepare(&self.queue, view, projection);
+        writes
+    }
+
+    fn wgpu_clear_color(&self) -> Color {
+        Color { r: self.clear_color[0], g: self.clear_color[1], b: self.clear_color[2], a: self.clear_color[3] }
+    }
+
     pub fn submit(&mut self) {
         let frame = match self.swap_chain.get_current_frame() {
             Ok(frame) => frame,
@@ -382,77 +1905,364 @@ impl Renderer {
 
         let render_texture = frame.output;
 
+        self.stats.tick();
+        if self.stats.enabled() {
+            self.stats.set_draw_calls(self.last_frame_stats.draw_calls);
+            self.draw_stats();
+        }
+
+        let mut stats = std::mem::take(&mut self.frame_stats);
         let vertex_data = bytemuck::cast_slice(&self.vertex_data);
         let index_data = bytemuck::cast_slice(&self.index_data);
-        let uniform_data = unsafe {
-            std::slice::from_raw_parts(
-                self.uniform_data.as_ptr() as *const u8,
-                self.uniform_data.len() * BIND_BUFFER_ALIGNMENT as usize,
-            )
-        };
+        self.queue.write_buffer(&self.vertex_buffer, 0, vertex_data);
+        self.queue.write_buffer(&self.index_buffer, 0, index_data);
+        let (vertex_data_len, index_data_len) = (vertex_data.len(), index_data.len());
+        stats.buffer_uploads += 2;
+
+        let view_projection = self.camera_2d.view_projection(self.viewport_size());
+        let window_size = [self.swap_chain_descriptor.width, self.swap_chain_descriptor.height];
+        stats.buffer_uploads += self.sprites.prepare(&self.queue, view_projection, window_size);
+        stats.buffer_uploads += self.lighting_2d.prepare(&self.queue, view_projection);
+        stats.buffer_uploads += self.tiles.prepare(&self.queue, &self.camera_2d, self.viewport_size());
+
+        self.shaders.reload_changed(&self.device);
+        let render_size = self.render_size();
+        stats.buffer_uploads += self.shaders.prepare(&self.queue, [render_size[0] as f32, render_size[1] as f32]);
+        stats.buffer_uploads += self.post.prepare(&self.queue);
+        stats.buffer_uploads += self.water.prepare(&self.queue);
+        stats.buffer_uploads += self.joints.prepare(&self.queue);
+        stats.buffer_uploads += self.instances.prepare(&self.queue);
+        let size = [self.swap_chain_descriptor.width, self.swap_chain_descriptor.height];
+        stats.buffer_uploads += self.picker.prepare(&self.device, &self.queue, size);
+        stats.buffer_uploads += self.outlines.prepare(&self.device, &self.queue, size);
 
+        // Shadows follow the first viewport's camera
+        let view = self.viewports.first().map_or(self.view, |viewport| viewport.view);
+        let camera_position = view.inverse() * Point3::origin();
+        let light_view_projection = self.shadow_map.prepare(&self.queue, self.directional_light, camera_position);
+        // The shadow map writes its uniforms when there's a light to draw it for
+        stats.buffer_uploads += light_view_projection.is_some() as usize;
+
+        self.timer.collect();
         let mut encoder = self.device.create_command_encoder(&CommandEncoderDescriptor { label: None });
 
-        {
+        if !self.graph.graph().passes().iter().any(|pass| pass.kind == PassKind::Compute) {
+            let timing = self.timer.start(&mut encoder, "compute");
+            self.compute.run(&mut encoder);
+            self.timer.stop(&mut encoder, timing);
+        }
+        let timing = self.timer.start(&mut encoder, "particles");
+        self.particles.simulate(&mut encoder);
+        self.timer.stop(&mut encoder, timing);
+        let timing = self.timer.start(&mut encoder, "indirect_culling");
+        self.indirect.cull(&mut encoder);
+        self.timer.stop(&mut encoder, timing);
+
+        let timing = self.timer.start(&mut encoder, "shadows");
+        stats += self.shadow_map.render(&mut encoder, &self.uniform_bind_group, &self.joints, &self.instances);
+        self.timer.stop(&mut encoder, timing);
+        let timing = self.timer.start(&mut encoder, "lights_2d");
+        stats += self.lighting_2d.render(&mut encoder, self.sprites.quad_indices());
+        self.timer.stop(&mut encoder, timing);
+
+        // Targets and viewports share the uniforms of the frame, so each one is submitted with the uniforms rewritten
+        // for its camera before the next is written
+        let target_draws = std::mem::take(&mut self.target_draws);
+        for draw in &target_draws {
+            stats.buffer_uploads += self.prepare_view(draw.view, draw.projection, light_view_projection);
+
+            let attachments =
+                draw.target.attachments(&self.device, HDR_TEXTURE_FORMAT, DEPTH_TEXTURE_FORMAT, self.sample_count);
+            let (view, resolve_target) = draw.target.color(&attachments);
+            let timing = self.timer.start(&mut encoder, "target");
             let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
-                label: Some("render_pass"),
+                label: Some("target_pass"),
                 color_attachments: &[RenderPassColorAttachment {
-                    view: &render_texture.view,
-                    resolve_target: None,
-                    ops: Operations {
-                        load: LoadOp::Clear(Color {
-                            r: self.clear_color[0],
-                            g: self.clear_color[1],
-                            b: self.clear_color[2],
-                            a: self.clear_color[3],
-                        }),
-                        store: true,
-                    },
+                    view,
+                    resolve_target,
+                    ops: Operations { load: LoadOp::Clear(self.wgpu_clear_color()), store: true },
                 }],
                 depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture_view,
+                    view: attachments.depth(),
                     depth_ops: Some(Operations { load: LoadOp::Clear(0.0), store: true }),
-                    stencil_ops: None,
+                    stencil_ops: Some(Operations { load: LoadOp::Clear(0), store: true }),
                 }),
             });
+            stats += self.render_meshes(&mut render_pass, vertex_data_len, index_data_len, false);
+            stats += self.render_transparent(&mut render_pass);
+            drop(render_pass);
+            draw.target.finish(&mut encoder, &self.post);
+            self.timer.stop(&mut encoder, timing);
+
+            self.queue.submit(Some(encoder.finish()));
+            encoder = self.device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        }
+
+        let viewports = match self.viewports.is_empty() {
+            true => vec![None],
+            false => self.viewports.iter().copied().map(Some).collect(),
+        };
+        // Without viewports the post effects say what the camera uses
+        let camera_effects = viewports
+            .iter()
+            .map(|viewport| match viewport {
+                Some(viewport) => (viewport.ssao, viewport.ssr, viewport.depth_of_field),
+                None => self
+                    .post
+                    .effects()
+                    .map_or((None, None, None), |effects| (effects.ssao, effects.ssr, effects.depth_of_field)),
+            })
+            .collect::<Vec<_>>();
+        let depth_effects = camera_effects
+            .iter()
+            .any(|(ssao, ssr, depth_of_field)| ssao.is_some() || ssr.is_some() || depth_of_field.is_some());
+        self.post.set_use_processed(depth_effects);
+        for (index, viewport) in viewports.iter().enumerate() {
+            let (view, projection) =
+                viewport.map_or((self.view, self.projection), |viewport| (viewport.view, viewport.projection));
+            stats.buffer_uploads += self.prepare_view(view, projection, light_view_projection);
+            let timing = self.timer.start(&mut encoder, "pick");
+            stats += self.picker.render(
+                &mut encoder,
+                &self.uniform_bind_group,
+                &self.joints,
+                &self.instances,
+                viewport.map(|viewport| viewport.pixels(size).unwrap_or([0; 4])),
+                index == 0,
+            );
+            self.timer.stop(&mut encoder, timing);
+            let timing = self.timer.start(&mut encoder, "outline_mask");
+            stats += self.outlines.render_mask(
+                &mut encoder,
+                &self.uniform_bind_group,
+                &self.joints,
+                &self.instances,
+                viewport.map(|viewport| viewport.pixels(size).unwrap_or([0; 4])),
+                index == 0,
+            );
+            self.timer.stop(&mut encoder, timing);
+
+            // The depth and normals are drawn before the scene for decals and water to read, and kept for the effects after
+            let rect =
+                viewport.map_or(Some([0, 0, render_size[0], render_size[1]]), |viewport| viewport.pixels(render_size));
+            let camera = rect.map(|rect| {
+                let (ssao, ssr, depth_of_field) = camera_effects[index];
+                EffectCamera { view, projection, rect, ssao, ssr, depth_of_field, first: index == 0 }
+            });
+            if let Some(camera) = camera {
+                let prepass = !self.decals.is_empty() || !self.water.is_empty();
+                if camera.uses_effects() || prepass {
+                    stats.buffer_uploads += self.depth_effects.prepare(&self.queue, &camera);
+                    let timing = self.timer.start(&mut encoder, "depth_prepass");
+                    stats += self.depth_effects.render_prepass(
+                        &mut encoder,
+                        &self.uniform_bind_group,
+                        &self.joints,
+                        &self.instances,
+                        camera.rect,
+                    );
+                    self.timer.stop(&mut encoder, timing);
+                }
+                stats.buffer_uploads += self.decals.prepare(&self.queue, view, projection, camera.rect);
+            }
 
-            if self.draw_calls.len() > 0 {
-                render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(0..vertex_data.len() as u64));
-                render_pass.set_index_buffer(self.index_buffer.slice(0..index_data.len() as u64), IndexFormat::Uint32);
-                render_pass.set_pipeline(&self.pipeline);
-                for i in 0..self.draw_calls.len() {
-                    let offset = (i as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
-                    render_pass.set_bind_group(0, &self.uniform_bind_group, &[offset]);
-                    for k in 0..self.draw_calls[i].len() {
-                        render_pass.draw_indexed(
-                            self.draw_calls[i][k].indices.clone(),
-                            self.draw_calls[i][k].base_vertex,
-                            0..1,
-                        );
+            let screen = self.post.scene_view();
+            for scheduled in self.graph.order() {
+                let pass = self.graph.pass(scheduled);
+                if pass.kind == PassKind::Compute {
+                    if index == 0 {
+                        let timing = self.timer.start(&mut encoder, "compute");
+                        self.compute.run(&mut encoder);
+                        self.timer.stop(&mut encoder, timing);
                     }
+                    continue;
                 }
+
+                // Only the first viewport clears whole targets, the others clear their own rectangle
+                let color_load = match scheduled.clear_color && index == 0 {
+                    true => LoadOp::Clear(self.wgpu_clear_color()),
+                    false => LoadOp::Load,
+                };
+                let depth_load = if scheduled.clear_depth && index == 0 { LoadOp::Clear(0.0) } else { LoadOp::Load };
+                let stencil_load = if scheduled.clear_depth && index == 0 { LoadOp::Clear(0) } else { LoadOp::Load };
+
+                let (view, resolve_target) = self.graph.color_target(pass, screen);
+                let label = if pass.kind == PassKind::Scene { "scene" } else { "fullscreen" };
+                let timing = self.timer.start(&mut encoder, label);
+                let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                    label: Some("render_pass"),
+                    color_attachments: &[RenderPassColorAttachment {
+                        view,
+                        resolve_target,
+                        ops: Operations { load: color_load, store: true },
+                    }],
+                    depth_stencil_attachment: pass.depth.map(|depth| RenderPassDepthStencilAttachment {
+                        view: self.graph.view(depth),
+                        depth_ops: Some(Operations { load: depth_load, store: true }),
+                        stencil_ops: Some(Operations { load: stencil_load, store: true }),
+                    }),
+                });
+
+                let target_size = self.graph.target_size(pass, render_size);
+                let rect = match viewport {
+                    Some(viewport) => match viewport.pixels(target_size) {
+                        Some(rect) => Some((viewport, rect)),
+                        None => {
+                            drop(render_pass);
+                            self.timer.stop(&mut encoder, timing);
+                            continue;
+                        },
+                    },
+                    None => None,
+                };
+                // Fullscreen passes only clip to the viewport so they still line up with their inputs
+                if let Some((_, rect)) = rect {
+                    render_pass.set_scissor_rect(rect[0], rect[1], rect[2], rect[3]);
+                }
+
+                match pass.kind {
+                    PassKind::Scene => {
+                        if let Some((viewport, rect)) = rect {
+                            let [x, y, width, height] = [rect[0] as f32, rect[1] as f32, rect[2] as f32, rect[3] as f32];
+                            render_pass.set_viewport(x, y, width, height, 0., 1.);
+                            let clear_color = viewport.clear_color.filter(|_| scheduled.clear_color);
+                            let clear_depth = viewport.clear_depth && scheduled.clear_depth;
+                            self.viewport_clearer.clear(&mut render_pass, clear_color, clear_depth);
+                        }
+
+                        let full_size = target_size == render_size;
+                        stats += self.render_meshes(&mut render_pass, vertex_data_len, index_data_len, full_size);
+                        // The pass is ended for the water to copy what is under it and picked back up where it was
+                        if full_size && pass.color == ColorTarget::Screen && !self.water.is_empty() {
+                            drop(render_pass);
+                            self.water.copy_refraction(&mut encoder, self.post.scene_texture());
+                            render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                                label: Some("water_pass"),
+                                color_attachments: &[RenderPassColorAttachment {
+                                    view,
+                                    resolve_target,
+                                    ops: Operations { load: LoadOp::Load, store: true },
+                                }],
+                                depth_stencil_attachment: pass.depth.map(|depth| RenderPassDepthStencilAttachment {
+                                    view: self.graph.view(depth),
+                                    depth_ops: Some(Operations { load: LoadOp::Load, store: true }),
+                                    stencil_ops: Some(Operations { load: LoadOp::Load, store: true }),
+                                }),
+                            });
+                            if let Some((_, [x, y, width, height])) = rect {
+                                render_pass.set_viewport(x as f32, y as f32, width as f32, height as f32, 0., 1.);
+                                render_pass.set_scissor_rect(x, y, width, height);
+                            }
+                            let scene_bind_group = self.pbr.scene_bind_group();
+                            stats += self.water.render(
+                                &mut render_pass,
+                                &self.uniform_bind_group,
+                                scene_bind_group,
+                                &self.visible,
+                            );
+                        }
+                        stats += self.render_transparent(&mut render_pass);
+                        stats += self.shaders.render_fullscreen(&mut render_pass);
+                        stats += self.debug.render(&mut render_pass);
+                        if index + 1 == viewports.len() {
+                            if rect.is_some() {
+                                let [width, height] = target_size;
+                                render_pass.set_viewport(0., 0., width as f32, height as f32, 0., 1.);
+                                render_pass.set_scissor_rect(0, 0, width, height);
+                            }
+                            let mut layers: Vec<RenderLayer> = self.sprites.layers().chain(self.tiles.layers()).collect();
+                            layers.sort();
+                            layers.dedup();
+                            for layer in layers {
+                                stats += self.tiles.render(&mut render_pass, &self.sprites, layer);
+                                stats += self.sprites.render(&mut render_pass, layer, target_size);
+                            }
+                            stats += self.lighting_2d.render_sprites(&mut render_pass, self.sprites.quad_indices());
+                        }
+                    },
+                    PassKind::Fullscreen(shader) => {
+                        stats += self.shaders.render_pass(&mut render_pass, shader, scheduled.inputs.as_ref())
+                    },
+                    PassKind::Compute => (),
+                }
+                drop(render_pass);
+                self.timer.stop(&mut encoder, timing);
+            }
+
+            if let (true, Some(camera)) = (depth_effects, camera) {
+                let timing = self.timer.start(&mut encoder, "depth_effects");
+                self.depth_effects.render(&mut encoder, &camera, self.post.processed_view());
+                self.timer.stop(&mut encoder, timing);
+            }
+
+            if index + 1 < viewports.len() {
+                self.queue.submit(Some(encoder.finish()));
+                encoder = self.device.create_command_encoder(&CommandEncoderDescriptor { label: None });
             }
         }
 
-        self.queue.write_buffer(&self.vertex_buffer, 0, vertex_data);
-        self.queue.write_buffer(&self.index_buffer, 0, index_data);
-        self.queue.write_buffer(&self.uniform_buffer, 0, uniform_data);
+        let timing = self.timer.start(&mut encoder, "post");
+        self.post.render(&mut encoder, &render_texture.view);
+        self.timer.stop(&mut encoder, timing);
+        let timing = self.timer.start(&mut encoder, "outlines");
+        self.outlines.composite(&mut encoder, &render_texture.view);
+        self.timer.stop(&mut encoder, timing);
+        let readback = self.capturer.copy(&self.device, &mut encoder, &self.post, &self.outlines, size);
+        self.timer.resolve(&self.device, &mut encoder);
+
         self.queue.submit(Some(encoder.finish()));
+        if let Some(readback) = readback {
+            readback.finish();
+        }
+        self.timer.finish();
+        self.last_frame_stats = stats;
+        // Finishes the readbacks of earlier frames
+        self.device.poll(Maintain::Poll);
 
         self.vertex_data.clear();
         self.index_data.clear();
         self.uniform_data.clear();
+        self.bounds.clear();
         self.draw_calls.clear();
+        self.mesh_draws.clear();
+        self.joints.clear();
+        self.instances.clear();
+        self.shadow_map.clear();
+        self.picker.clear();
+        self.outlines.clear();
+        self.depth_effects.clear();
+        self.decals.clear();
+        self.pbr.clear();
+        self.indirect.clear();
+        self.particles.clear();
+        self.billboards.clear();
+        self.debug.clear();
+        self.tiles.clear();
+        self.lighting_2d.clear();
+        self.shaders.clear();
+        self.terrains.clear();
+        self.water.clear();
+        self.compute.clear();
     }
 }
 
-fn create_swap_chain(device: &Device, surface: &Surface, size: [u32; 2]) -> (SwapChainDescriptor, SwapChain) {
+// Uniforms are padded out to the binding alignment, which bytemuck can't see
+fn uniform_bytes(uniforms: &[Uniforms]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(uniforms.as_ptr() as *const u8, uniforms.len() * BIND_BUFFER_ALIGNMENT as usize) }
+}
+
+fn create_swap_chain(
+    device: &Device,
+    surface: &Surface,
+    size: [u32; 2],
+    present_mode: PresentMode,
+) -> (SwapChainDescriptor, SwapChain) {
     let swap_chain_descriptor = SwapChainDescriptor {
         usage: TextureUsage::RENDER_ATTACHMENT,
         format: TEXTURE_FORMAT,
         width: size[0],
         height: size[1],
-        present_mode: PresentMode::Fifo,
+        present_mode: present_mode.to_wgpu(),
     };
 
     let swap_chain = device.create_swap_chain(surface, &swap_chain_descriptor);
@@ -460,18 +2270,50 @@ fn create_swap_chain(device: &Device, surface: &Surface, size: [u32; 2]) -> (Swa
     (swap_chain_descriptor, swap_chain)
 }
 
-fn create_depth_texture(device: &Device, size: [u32; 2]) -> (Texture, TextureView) {
-    let texture = device.create_texture(&TextureDescriptor {
-        label: Some("depth texture"),
-        size: Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 },
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: TextureDimension::D2,
-        format: DEPTH_TEXTURE_FORMAT,
-        usage: TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED,
-    });
-
-    let view = texture.create_view(&TextureViewDescriptor::default());
-
-    (texture, view)
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    sample_count: u32,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "main",
+            buffers: &[VertexBufferLayout {
+                array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Vertex,
+                attributes: &vertex_attr_array![
+                    0 => Float32x3,
+                    1 => Float32x2,
+                    2 => Float32x3,
+                ],
+            }],
+        },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: Some(Face::Back),
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: DEPTH_TEXTURE_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format, blend: Some(BlendState::REPLACE), write_mask: ColorWrite::ALL }],
+        }),
+    })
 }
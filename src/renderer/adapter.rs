@@ -0,0 +1,146 @@
+// Copyright 2021 Chay Nabors.
+
+use wgpu::Adapter;
+use wgpu::Backend;
+use wgpu::BackendBit;
+use wgpu::DeviceType;
+use wgpu::Instance;
+use wgpu::PowerPreference;
+use wgpu::RequestAdapterOptions;
+use wgpu::Surface;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GraphicsBackend {
+    Vulkan,
+    Dx12,
+    Dx11,
+    Metal,
+    Gl,
+}
+
+impl GraphicsBackend {
+    fn bits(backend: Option<GraphicsBackend>) -> BackendBit {
+        match backend {
+            Some(GraphicsBackend::Vulkan) => BackendBit::VULKAN,
+            Some(GraphicsBackend::Dx12) => BackendBit::DX12,
+            Some(GraphicsBackend::Dx11) => BackendBit::DX11,
+            Some(GraphicsBackend::Metal) => BackendBit::METAL,
+            Some(GraphicsBackend::Gl) => BackendBit::GL,
+            None => BackendBit::all(),
+        }
+    }
+
+    fn from_wgpu(backend: Backend) -> Option<GraphicsBackend> {
+        match backend {
+            Backend::Vulkan => Some(GraphicsBackend::Vulkan),
+            Backend::Dx12 => Some(GraphicsBackend::Dx12),
+            Backend::Dx11 => Some(GraphicsBackend::Dx11),
+            Backend::Metal => Some(GraphicsBackend::Metal),
+            Backend::Gl => Some(GraphicsBackend::Gl),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AdapterType {
+    Discrete,
+    Integrated,
+    Virtual,
+    Cpu,
+    Other,
+}
+
+impl From<DeviceType> for AdapterType {
+    fn from(device_type: DeviceType) -> AdapterType {
+        match device_type {
+            DeviceType::DiscreteGpu => AdapterType::Discrete,
+            DeviceType::IntegratedGpu => AdapterType::Integrated,
+            DeviceType::VirtualGpu => AdapterType::Virtual,
+            DeviceType::Cpu => AdapterType::Cpu,
+            DeviceType::Other => AdapterType::Other,
+        }
+    }
+}
+
+// Falls back to what wgpu picks for the power preference when no adapter matches
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdapterPreference {
+    // A discrete gpu where there is one
+    HighPerformance,
+    // An integrated gpu where there is one
+    LowPower,
+    // The first adapter with a name containing this, ignoring case
+    Named(String),
+}
+
+impl Default for AdapterPreference {
+    fn default() -> AdapterPreference {
+        AdapterPreference::HighPerformance
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RendererSettings {
+    // None lets wgpu use any backend
+    pub backend: Option<GraphicsBackend>,
+    pub adapter: AdapterPreference,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdapterInfo {
+    pub name: String,
+    // None for backends gear doesn't name, like WebGPU in a browser
+    pub backend: Option<GraphicsBackend>,
+    pub adapter_type: AdapterType,
+}
+
+impl AdapterInfo {
+    fn new(adapter: &Adapter) -> AdapterInfo {
+        let info = adapter.get_info();
+        let backend = GraphicsBackend::from_wgpu(info.backend);
+        AdapterInfo { name: info.name, backend, adapter_type: info.device_type.into() }
+    }
+}
+
+// Every adapter of the backend, or of every backend for None
+pub(crate) fn available_adapters(backend: Option<GraphicsBackend>) -> Vec<AdapterInfo> {
+    let instance = Instance::new(GraphicsBackend::bits(backend));
+    instance.enumerate_adapters(GraphicsBackend::bits(backend)).map(|adapter| AdapterInfo::new(&adapter)).collect()
+}
+
+pub(crate) fn create_instance(settings: &RendererSettings) -> Instance {
+    Instance::new(GraphicsBackend::bits(settings.backend))
+}
+
+pub(crate) async fn select_adapter(
+    instance: &Instance,
+    surface: &Surface,
+    settings: &RendererSettings,
+) -> Option<(Adapter, AdapterInfo)> {
+    let adapters: Vec<Adapter> = instance.enumerate_adapters(GraphicsBackend::bits(settings.backend)).collect();
+    let matches = |adapter: &Adapter| {
+        let info = adapter.get_info();
+        match &settings.adapter {
+            AdapterPreference::HighPerformance => info.device_type == DeviceType::DiscreteGpu,
+            AdapterPreference::LowPower => info.device_type == DeviceType::IntegratedGpu,
+            AdapterPreference::Named(name) => info.name.to_lowercase().contains(&name.to_lowercase()),
+        }
+    };
+    // Adapters that can't present to the window have no preferred format for it
+    let chosen =
+        adapters.into_iter().find(|adapter| matches(adapter) && adapter.get_swap_chain_preferred_format(surface).is_some());
+
+    let adapter = match chosen {
+        Some(adapter) => adapter,
+        None => {
+            let power_preference = match settings.adapter {
+                AdapterPreference::LowPower => PowerPreference::LowPower,
+                _ => PowerPreference::HighPerformance,
+            };
+            instance.request_adapter(&RequestAdapterOptions { power_preference, compatible_surface: Some(surface) }).await?
+        },
+    };
+    let info = AdapterInfo::new(&adapter);
+    Some((adapter, info))
+}
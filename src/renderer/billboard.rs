@@ -0,0 +1,345 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+use std::ops::Range;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Isometry3;
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use nalgebra::Vector2;
+use nalgebra::Vector3;
+use wgpu::vertex_attr_array;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingType;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::InputStepMode;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+
+use super::stats::FrameStats;
+use crate::Texture;
+
+// Billboards past this in one frame are dropped
+const MAX_BILLBOARDS: usize = 1 << 14;
+
+// A textured quad in the world that turns to face the camera
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Billboard {
+    pub position: Point3<f32>,
+    // Width and height in world units
+    pub size: Vector2<f32>,
+    // Turns around this axis only and stays upright along it, like a tree or a flame. Without it the billboard faces
+    // the camera fully
+    pub axis: Option<Vector3<f32>>,
+    // Part of the texture as [left, top, right, bottom] in texture coordinates
+    pub region: [f32; 4],
+    pub tint: [f32; 4],
+}
+
+impl Default for Billboard {
+    fn default() -> Billboard {
+        Billboard {
+            position: Point3::origin(),
+            size: Vector2::new(1., 1.),
+            axis: None,
+            region: [0., 0., 1., 1.],
+            tint: [1., 1., 1., 1.],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct BillboardInstance {
+    position: [f32; 4],
+    axis: [f32; 4],
+    size: [f32; 2],
+    region: [f32; 4],
+    tint: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct BillboardUniforms {
+    view_projection: [[f32; 4]; 4],
+    camera_right: [f32; 4],
+    camera_up: [f32; 4],
+    camera_position: [f32; 4],
+}
+
+#[derive(Debug)]
+struct QueuedBillboard {
+    texture: Texture,
+    instance: BillboardInstance,
+}
+
+#[derive(Debug)]
+struct Batch {
+    texture: Texture,
+    instances: Range<u32>,
+}
+
+#[derive(Debug)]
+pub(crate) struct BillboardRenderer {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    pipeline: RenderPipeline,
+    instance_buffer: Buffer,
+    uniform_buffer: Buffer,
+    uniform_bind_group: BindGroup,
+    billboards: Vec<QueuedBillboard>,
+    batches: Vec<Batch>,
+}
+
+impl BillboardRenderer {
+    pub(crate) fn new(
+        device: &Device,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        texture_layout: &BindGroupLayout,
+    ) -> BillboardRenderer {
+        let instance_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("billboard_instance_buffer"),
+            size: (MAX_BILLBOARDS * size_of::<BillboardInstance>()) as u64,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("billboard_uniform_buffer"),
+            size: size_of::<BillboardUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("billboard_uniform_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<BillboardUniforms>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let uniform_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("billboard_uniform_bind_group"),
+            layout: &uniform_bind_group_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() }],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("billboard_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("billboard.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("billboard_pipeline_layout"),
+            bind_group_layouts: &[&uniform_bind_group_layout, texture_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader_module, format, depth_format, 1);
+
+        BillboardRenderer {
+            format,
+            depth_format,
+            shader_module,
+            pipeline_layout,
+            pipeline,
+            instance_buffer,
+            uniform_buffer,
+            uniform_bind_group,
+            billboards: vec![],
+            batches: vec![],
+        }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.shader_module,
+            self.format,
+            self.depth_format,
+            sample_count,
+        );
+    }
+
+    pub(crate) fn push(&mut self, texture: &Texture, billboard: &Billboard) {
+        if self.billboards.len() >= MAX_BILLBOARDS {
+            return;
+        }
+
+        let p = billboard.position;
+        let (locked, axis) = billboard.axis.map_or((0., Vector3::y()), |axis| (1., axis));
+        let instance = BillboardInstance {
+            position: [p.x, p.y, p.z, locked],
+            axis: [axis.x, axis.y, axis.z, 0.],
+            size: [billboard.size.x, billboard.size.y],
+            region: billboard.region,
+            tint: billboard.tint,
+        };
+        self.billboards.push(QueuedBillboard { texture: texture.clone(), instance });
+    }
+
+    // Sorted back to front for the camera so they blend over each other, runs sharing a texture are drawn together
+    // Returns the number of writes
+    pub(crate) fn prepare(&mut self, queue: &Queue, view_projection: Matrix4<f32>, view: Isometry3<f32>) -> usize {
+        self.batches.clear();
+        if self.billboards.is_empty() {
+            return 0;
+        }
+
+        let camera_position = view.inverse() * Point3::origin();
+        let distance = |billboard: &QueuedBillboard| {
+            let [x, y, z, _] = billboard.instance.position;
+            (Point3::new(x, y, z) - camera_position).norm_squared()
+        };
+        self.billboards.sort_by(|a, b| distance(b).partial_cmp(&distance(a)).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut instances = Vec::with_capacity(self.billboards.len());
+        for (i, billboard) in self.billboards.iter().enumerate() {
+            instances.push(billboard.instance);
+
+            let index = i as u32;
+            match self.batches.last_mut() {
+                Some(batch) if batch.texture.id() == billboard.texture.id() => batch.instances.end = index + 1,
+                _ => self.batches.push(Batch { texture: billboard.texture.clone(), instances: index..index + 1 }),
+            }
+        }
+
+        let rotation = view.rotation.inverse();
+        let right = rotation * Vector3::x();
+        let up = rotation * Vector3::y();
+        let uniforms = BillboardUniforms {
+            view_projection: view_projection.into(),
+            camera_right: [right.x, right.y, right.z, 0.],
+            camera_up: [up.x, up.y, up.z, 0.],
+            camera_position: [camera_position.x, camera_position.y, camera_position.z, 1.],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instances));
+        2
+    }
+
+    pub(crate) fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.batches.is_empty() {
+            return stats;
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_vertex_buffer(0, self.instance_buffer.slice(..));
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        stats.pipeline_switches += 1;
+        for batch in &self.batches {
+            render_pass.set_bind_group(1, batch.texture.bind_group(), &[]);
+            render_pass.draw(0..6, batch.instances.clone());
+            stats.draw(6, batch.instances.end - batch.instances.start);
+            stats.texture_binds += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.billboards.clear();
+        self.batches.clear();
+    }
+}
+
+// Billboards are hidden behind meshes but don't hide anything themselves
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("billboard_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "main",
+            buffers: &[VertexBufferLayout {
+                array_stride: size_of::<BillboardInstance>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Instance,
+                attributes: &vertex_attr_array![
+                    0 => Float32x4,
+                    1 => Float32x4,
+                    2 => Float32x2,
+                    3 => Float32x4,
+                    4 => Float32x4,
+                ],
+            }],
+        },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format, blend: Some(BlendState::ALPHA_BLENDING), write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
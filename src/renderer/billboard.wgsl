@@ -0,0 +1,73 @@
+[[block]]
+struct BillboardUniforms {
+    view_projection: mat4x4<f32>;
+    // The camera's axes and position in world space
+    camera_right: vec4<f32>;
+    camera_up: vec4<f32>;
+    camera_position: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> frame: BillboardUniforms;
+
+[[group(1), binding(0)]]
+var billboard_texture: texture_2d<f32>;
+[[group(1), binding(1)]]
+var billboard_sampler: sampler;
+
+struct InstanceInput {
+    // The w is one when the billboard turns around the axis only
+    [[location(0)]] position: vec4<f32>;
+    [[location(1)]] axis: vec4<f32>;
+    [[location(2)]] size: vec2<f32>;
+    [[location(3)]] region: vec4<f32>;
+    [[location(4)]] tint: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[location(1)]] tint: vec4<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] vertex: u32, in: InstanceInput) -> VertexOutput {
+    // Two triangles per billboard, counter clockwise from the bottom left
+    var corners: array<vec2<f32>, 6> = array<vec2<f32>, 6>(
+        vec2<f32>(-0.5, -0.5),
+        vec2<f32>(0.5, -0.5),
+        vec2<f32>(0.5, 0.5),
+        vec2<f32>(-0.5, -0.5),
+        vec2<f32>(0.5, 0.5),
+        vec2<f32>(-0.5, 0.5)
+    );
+    let corner = corners[vertex % 6u];
+
+    var right: vec3<f32> = frame.camera_right.xyz;
+    var up: vec3<f32> = frame.camera_up.xyz;
+    if (in.position.w > 0.5) {
+        up = normalize(in.axis.xyz);
+        let to_camera = frame.camera_position.xyz - in.position.xyz;
+        let across = cross(up, to_camera);
+        // Looking straight down the axis there is no way to face the camera, so the camera's right is kept
+        if (dot(across, across) > 0.000001) {
+            right = normalize(across);
+        }
+    }
+    let offset = right * corner.x * in.size.x + up * corner.y * in.size.y;
+
+    var out: VertexOutput;
+    out.tex_coord = vec2<f32>(mix(in.region.x, in.region.z, corner.x + 0.5), mix(in.region.w, in.region.y, corner.y + 0.5));
+    out.tint = in.tint;
+    out.pos = frame.view_projection * vec4<f32>(in.position.xyz + offset, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(billboard_texture, billboard_sampler, in.tex_coord) * in.tint;
+    if (color.a <= 0.0) {
+        discard;
+    }
+    return color;
+}
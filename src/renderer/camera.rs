@@ -0,0 +1,180 @@
+// Copyright 2021 Chay Nabors.
+
+use std::f32::consts::FRAC_PI_3;
+
+use nalgebra::Isometry3;
+use nalgebra::Matrix3;
+use nalgebra::Matrix4;
+use nalgebra::Point2;
+use nalgebra::Point3;
+use nalgebra::Rotation2;
+use nalgebra::Rotation3;
+use nalgebra::Translation3;
+use nalgebra::UnitQuaternion;
+use nalgebra::Vector2;
+use nalgebra::Vector3;
+
+// At a zoom of one a world unit is one pixel, the position ends up in the center of the viewport
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera2D {
+    pub position: Point2<f32>,
+    pub zoom: f32,
+    // Counter clockwise in radians, the world appears to turn the other way
+    pub rotation: f32,
+}
+
+impl Default for Camera2D {
+    fn default() -> Camera2D {
+        Camera2D { position: Point2::origin(), zoom: 1., rotation: 0. }
+    }
+}
+
+impl Camera2D {
+    pub fn new(position: Point2<f32>, zoom: f32, rotation: f32) -> Camera2D {
+        Camera2D { position, zoom, rotation }
+    }
+
+    // World to pixels relative to the center of the viewport with y up
+    pub fn view(&self) -> Matrix3<f32> {
+        Matrix3::new_scaling(self.zoom)
+            * Rotation2::new(-self.rotation).to_homogeneous()
+            * Matrix3::new_translation(&-self.position.coords)
+    }
+
+    pub fn view_projection(&self, viewport: [f32; 2]) -> Matrix4<f32> {
+        let projection =
+            Matrix4::new_orthographic(-viewport[0] / 2., viewport[0] / 2., -viewport[1] / 2., viewport[1] / 2., -1., 1.);
+        let view = Matrix4::new_nonuniform_scaling(&Vector3::new(self.zoom, self.zoom, 1.))
+            * Rotation3::from_axis_angle(&Vector3::z_axis(), -self.rotation).to_homogeneous()
+            * Matrix4::new_translation(&Vector3::new(-self.position.x, -self.position.y, 0.));
+        projection * view
+    }
+
+    // Screen positions are in pixels from the top left of the viewport with y down
+    pub fn world_to_screen(&self, point: Point2<f32>, viewport: [f32; 2]) -> Point2<f32> {
+        let view = self.view().transform_point(&point);
+        Point2::new(view.x + viewport[0] / 2., viewport[1] / 2. - view.y)
+    }
+
+    pub fn screen_to_world(&self, point: Point2<f32>, viewport: [f32; 2]) -> Point2<f32> {
+        let view = Point2::new(point.x - viewport[0] / 2., viewport[1] / 2. - point.y);
+        let inverse = Matrix3::new_translation(&self.position.coords)
+            * Rotation2::new(self.rotation).to_homogeneous()
+            * Matrix3::new_scaling(1. / self.zoom);
+        inverse.transform_point(&view)
+    }
+
+    // The corners of the visible region are not axis aligned once rotated, this is the box around them
+    pub fn visible_bounds(&self, viewport: [f32; 2]) -> (Point2<f32>, Point2<f32>) {
+        let corners = [[0., 0.], [viewport[0], 0.], [viewport[0], viewport[1]], [0., viewport[1]]];
+        let mut min = Vector2::repeat(f32::MAX);
+        let mut max = Vector2::repeat(f32::MIN);
+        for corner in &corners {
+            let world = self.screen_to_world(Point2::from(*corner), viewport);
+            min = min.inf(&world.coords);
+            max = max.sup(&world.coords);
+        }
+        (Point2::from(min), Point2::from(max))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    // The vertical field of view in radians
+    Perspective { fov: f32 },
+    // How much of the world fits in the height of the view
+    Orthographic { height: f32 },
+}
+
+// Looks down its negative z axis with y up. Projections map depth the reversed way the scene expects, with near at
+// one and far at zero
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera3D {
+    pub position: Point3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+    pub projection: Projection,
+    pub near: f32,
+    // Infinity for perspective projections that never cut anything off, orthographic ones need it to be finite
+    pub far: f32,
+}
+
+impl Default for Camera3D {
+    fn default() -> Camera3D {
+        Camera3D {
+            position: Point3::origin(),
+            rotation: UnitQuaternion::identity(),
+            projection: Projection::Perspective { fov: FRAC_PI_3 },
+            near: 0.1,
+            far: 1000.,
+        }
+    }
+}
+
+impl Camera3D {
+    // At the eye looking towards the target, up only has to be roughly up
+    pub fn look_at(eye: Point3<f32>, target: Point3<f32>, up: Vector3<f32>) -> Camera3D {
+        let mut camera = Camera3D { position: eye, ..Default::default() };
+        camera.look_towards(target, up);
+        camera
+    }
+
+    // Turns without moving, nothing changes when the target is where the camera is
+    pub fn look_towards(&mut self, target: Point3<f32>, up: Vector3<f32>) {
+        let backwards = self.position - target;
+        if backwards.norm_squared() > f32::EPSILON {
+            self.rotation = UnitQuaternion::face_towards(&backwards, &up);
+        }
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        self.rotation * -Vector3::z()
+    }
+
+    pub fn right(&self) -> Vector3<f32> {
+        self.rotation * Vector3::x()
+    }
+
+    pub fn up(&self) -> Vector3<f32> {
+        self.rotation * Vector3::y()
+    }
+
+    // World to camera space, what Renderer::set_view takes
+    pub fn view(&self) -> Isometry3<f32> {
+        Isometry3::from_parts(Translation3::from(self.position.coords), self.rotation).inverse()
+    }
+
+    // The aspect is the width over the height of what the camera draws into
+    pub fn projection(&self, aspect: f32) -> Matrix4<f32> {
+        let aspect = aspect.max(f32::EPSILON);
+        let near = self.near.max(f32::EPSILON);
+        let mut projection = Matrix4::zeros();
+        match self.projection {
+            Projection::Perspective { fov } => {
+                let focal = 1. / (fov / 2.).tan();
+                projection[(0, 0)] = focal / aspect;
+                projection[(1, 1)] = focal;
+                // Depth is near over the distance, which goes to zero as the distance does
+                if self.far.is_finite() {
+                    projection[(2, 2)] = near / (self.far - near);
+                    projection[(2, 3)] = near * self.far / (self.far - near);
+                } else {
+                    projection[(2, 3)] = near;
+                }
+                projection[(3, 2)] = -1.;
+            },
+            Projection::Orthographic { height } => {
+                let depth = (self.far - near).max(f32::EPSILON);
+                projection[(0, 0)] = 2. / (height * aspect).max(f32::EPSILON);
+                projection[(1, 1)] = 2. / height.max(f32::EPSILON);
+                projection[(2, 2)] = 1. / depth;
+                projection[(2, 3)] = self.far / depth;
+                projection[(3, 3)] = 1.;
+            },
+        }
+        projection
+    }
+
+    pub fn view_projection(&self, aspect: f32) -> Matrix4<f32> {
+        self.projection(aspect) * self.view().to_homogeneous()
+    }
+}
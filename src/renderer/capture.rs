@@ -0,0 +1,188 @@
+// Copyright 2021 Chay Nabors.
+
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::thread::{self,};
+
+use crossbeam::channel::Receiver;
+use crossbeam::channel::Sender;
+use crossbeam::channel::{self,};
+use image::RgbaImage;
+use log::error;
+use wgpu::Buffer;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::CommandEncoder;
+use wgpu::Device;
+use wgpu::Extent3d;
+use wgpu::ImageCopyBuffer;
+use wgpu::ImageCopyTexture;
+use wgpu::ImageDataLayout;
+use wgpu::MapMode;
+use wgpu::Origin3d;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsage;
+use wgpu::TextureViewDescriptor;
+use wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+use super::outline::OutlineRenderer;
+use super::post::PostProcessor;
+use crate::GearError;
+use crate::Result;
+
+// A frame on its way back from the gpu, check on it once a frame or two have been submitted
+#[derive(Debug)]
+pub struct FrameCapture {
+    receiver: Receiver<Result<RgbaImage>>,
+}
+
+impl FrameCapture {
+    // None until the image has arrived, and again after it has been taken
+    pub fn try_take(&self) -> Option<Result<RgbaImage>> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[derive(Debug)]
+enum CaptureTarget {
+    Capture(Sender<Result<RgbaImage>>),
+    File(PathBuf),
+}
+
+// The copy of a frame in a buffer the cpu can read once the frame is done
+#[derive(Debug)]
+pub(crate) struct Readback {
+    buffer: Buffer,
+    size: [u32; 2],
+    bytes_per_row: u32,
+    format: TextureFormat,
+    targets: Vec<CaptureTarget>,
+}
+
+// The swap chain can't be copied from, so captured frames are tonemapped a second time to a texture that can
+#[derive(Debug)]
+pub(crate) struct FrameCapturer {
+    format: TextureFormat,
+    targets: Vec<CaptureTarget>,
+}
+
+impl FrameCapturer {
+    pub(crate) fn new(format: TextureFormat) -> FrameCapturer {
+        FrameCapturer { format, targets: vec![] }
+    }
+
+    pub(crate) fn capture(&mut self) -> FrameCapture {
+        let (sender, receiver) = channel::bounded(1);
+        self.targets.push(CaptureTarget::Capture(sender));
+        FrameCapture { receiver }
+    }
+
+    pub(crate) fn save(&mut self, path: PathBuf) {
+        self.targets.push(CaptureTarget::File(path));
+    }
+
+    // After the post processor has rendered the frame
+    pub(crate) fn copy(
+        &mut self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        post: &PostProcessor,
+        outlines: &OutlineRenderer,
+        size: [u32; 2],
+    ) -> Option<Readback> {
+        if self.targets.is_empty() {
+            return None;
+        }
+
+        let extent = Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 };
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("capture_texture"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: self.format,
+            usage: TextureUsage::RENDER_ATTACHMENT | TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        post.composite(encoder, &view);
+        outlines.composite(encoder, &view);
+
+        // Rows of a copy have to start on a multiple of the alignment
+        let bytes_per_row =
+            (size[0] * 4 + COPY_BYTES_PER_ROW_ALIGNMENT - 1) / COPY_BYTES_PER_ROW_ALIGNMENT * COPY_BYTES_PER_ROW_ALIGNMENT;
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("capture_buffer"),
+            size: (bytes_per_row * size[1]) as u64,
+            usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture { texture: &texture, mip_level: 0, origin: Origin3d::ZERO },
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(bytes_per_row),
+                    rows_per_image: NonZeroU32::new(size[1]),
+                },
+            },
+            extent,
+        );
+
+        let targets = self.targets.drain(..).collect();
+        Some(Readback { buffer, size, bytes_per_row, format: self.format, targets })
+    }
+}
+
+impl Readback {
+    // After the frame is submitted. The buffer is mapped once the device is polled, which happens every submission,
+    // and read on another thread
+    pub(crate) fn finish(self) {
+        let map = self.buffer.slice(..).map_async(MapMode::Read);
+        thread::spawn(move || {
+            let result = match pollster::block_on(map) {
+                Ok(()) => self.image().ok_or(GearError::CaptureFailed),
+                Err(_) => Err(GearError::CaptureFailed),
+            };
+
+            for target in self.targets {
+                match (target, &result) {
+                    (CaptureTarget::Capture(sender), Ok(image)) => {
+                        let _ = sender.send(Ok(image.clone()));
+                    },
+                    (CaptureTarget::Capture(sender), Err(_)) => {
+                        let _ = sender.send(Err(GearError::CaptureFailed));
+                    },
+                    (CaptureTarget::File(path), Ok(image)) => {
+                        if let Err(e) = image.save(&path) {
+                            error!("Failed to save the frame to {}: {}", path.display(), e);
+                        }
+                    },
+                    (CaptureTarget::File(path), Err(_)) => {
+                        error!("Failed to capture the frame for {}", path.display());
+                    },
+                }
+            }
+        });
+    }
+
+    fn image(&self) -> Option<RgbaImage> {
+        let data = self.buffer.slice(..).get_mapped_range();
+        let mut pixels = Vec::with_capacity((self.size[0] * self.size[1] * 4) as usize);
+        for row in data.chunks(self.bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..(self.size[0] * 4) as usize]);
+        }
+        drop(data);
+        self.buffer.unmap();
+
+        if let TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb = self.format {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        RgbaImage::from_raw(self.size[0], self.size[1], pixels)
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferAddress;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::CommandEncoder;
+use wgpu::CommandEncoderDescriptor;
+use wgpu::ComputePassDescriptor;
+use wgpu::ComputePipeline;
+use wgpu::ComputePipelineDescriptor;
+use wgpu::Device;
+use wgpu::ErrorFilter;
+use wgpu::Extent3d;
+use wgpu::Maintain;
+use wgpu::MapMode;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::Queue;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StorageTextureAccess;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureUsage;
+use wgpu::TextureViewDimension;
+
+use crate::GearError;
+use crate::Result;
+use crate::Texture;
+use crate::TextureOptions;
+
+// Storage textures are written as rgba8unorm, which every adapter can store to and sample from
+const STORAGE_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ComputeShaderId(u32);
+
+// What each binding of group zero is, in the order of their binding numbers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComputeBindingKind {
+    // A storage buffer bound as var<uniform>
+    Uniform,
+    // A storage buffer bound as var<storage>, read_write unless it is read only
+    Storage { read_only: bool },
+    // Any texture as texture_2d<f32>, read with textureLoad
+    Texture,
+    // A storage texture as texture_storage_2d<rgba8unorm> with write access
+    StorageTexture,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ComputeBinding<'a> {
+    Uniform(&'a StorageBuffer),
+    Storage(&'a StorageBuffer),
+    Texture(&'a Texture),
+    StorageTexture(&'a StorageTexture),
+}
+
+impl ComputeBinding<'_> {
+    fn matches(&self, kind: ComputeBindingKind) -> bool {
+        match (self, kind) {
+            (ComputeBinding::Uniform(_), ComputeBindingKind::Uniform) => true,
+            (ComputeBinding::Storage(_), ComputeBindingKind::Storage { .. }) => true,
+            (ComputeBinding::Texture(_), ComputeBindingKind::Texture) => true,
+            (ComputeBinding::StorageTexture(_), ComputeBindingKind::StorageTexture) => true,
+            _ => false,
+        }
+    }
+}
+
+// A buffer compute shaders read and write, it can also be used for vertices and indirect draws. Clones share the same
+// buffer
+#[derive(Clone, Debug)]
+pub struct StorageBuffer {
+    buffer: Arc<Buffer>,
+    size: u64,
+}
+
+impl StorageBuffer {
+    pub(crate) fn new(device: &Device, size: u64) -> StorageBuffer {
+        // Copies and writes are in multiples of four bytes
+        let size = ((size + 3) & !3).max(4);
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("storage_buffer"),
+            size,
+            usage: BufferUsage::STORAGE
+                | BufferUsage::UNIFORM
+                | BufferUsage::VERTEX
+                | BufferUsage::INDIRECT
+                | BufferUsage::COPY_SRC
+                | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        StorageBuffer { buffer: Arc::new(buffer), size }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // The offset and length are multiples of four, bytes past the end of the buffer are dropped
+    pub(crate) fn write(&self, queue: &Queue, offset: u64, data: &[u8]) {
+        if offset >= self.size || offset % 4 != 0 {
+            return;
+        }
+        let len = (data.len() as u64).min(self.size - offset) as usize & !3;
+        if len > 0 {
+            queue.write_buffer(&self.buffer, offset, &data[..len]);
+        }
+    }
+
+    // Waits for the gpu to finish everything submitted so far, so it is meant for results needed right away
+    pub(crate) fn read(&self, device: &Device, queue: &Queue) -> Option<Vec<u8>> {
+        let readback = device.create_buffer(&BufferDescriptor {
+            label: Some("storage_readback_buffer"),
+            size: self.size,
+            usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: Some("storage_encoder") });
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &readback, 0, self.size);
+        queue.submit(Some(encoder.finish()));
+
+        let slice = readback.slice(0..self.size as BufferAddress);
+        let map = slice.map_async(MapMode::Read);
+        device.poll(Maintain::Wait);
+        pollster::block_on(map).ok()?;
+        let data = slice.get_mapped_range().to_vec();
+        readback.unmap();
+        Some(data)
+    }
+}
+
+// A texture compute shaders write, drawn like any other through texture()
+#[derive(Clone, Debug)]
+pub struct StorageTexture {
+    texture: Texture,
+}
+
+impl StorageTexture {
+    pub(crate) fn new(
+        device: &Device,
+        layout: &BindGroupLayout,
+        size: [u32; 2],
+        options: &TextureOptions,
+    ) -> StorageTexture {
+        let size = [size[0].max(1), size[1].max(1)];
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("storage_texture"),
+            size: Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: STORAGE_TEXTURE_FORMAT,
+            usage: TextureUsage::STORAGE | TextureUsage::SAMPLED | TextureUsage::COPY_DST,
+        });
+        let options = TextureOptions { srgb: false, mipmaps: false, ..*options };
+        StorageTexture { texture: Texture::from_texture(device, layout, texture, size, &options) }
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.texture.size()
+    }
+}
+
+#[derive(Debug)]
+struct ComputeShader {
+    bindings: Vec<ComputeBindingKind>,
+    layout: BindGroupLayout,
+    pipeline: ComputePipeline,
+}
+
+#[derive(Debug)]
+struct Dispatch {
+    shader: ComputeShaderId,
+    bind_group: BindGroup,
+    workgroups: [u32; 3],
+}
+
+// Dispatches run in the order they were made, at the render graph's compute pass or before anything is drawn when it
+// has none
+#[derive(Debug, Default)]
+pub(crate) struct ComputeRegistry {
+    shaders: Vec<ComputeShader>,
+    dispatches: Vec<Dispatch>,
+}
+
+impl ComputeRegistry {
+    pub(crate) fn new() -> ComputeRegistry {
+        ComputeRegistry::default()
+    }
+
+    pub(crate) fn create(
+        &mut self,
+        device: &Device,
+        source: &str,
+        bindings: &[ComputeBindingKind],
+    ) -> Result<ComputeShaderId> {
+        let entries: Vec<BindGroupLayoutEntry> = bindings
+            .iter()
+            .enumerate()
+            .map(|(i, kind)| BindGroupLayoutEntry {
+                binding: i as u32,
+                visibility: ShaderStage::COMPUTE,
+                ty: match *kind {
+                    ComputeBindingKind::Uniform => BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    ComputeBindingKind::Storage { read_only } => BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    ComputeBindingKind::Texture => BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    ComputeBindingKind::StorageTexture => BindingType::StorageTexture {
+                        access: StorageTextureAccess::WriteOnly,
+                        format: STORAGE_TEXTURE_FORMAT,
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                },
+                count: None,
+            })
+            .collect();
+
+        // Validation errors are caught here instead of reaching the device's panicking error handler
+        device.push_error_scope(ErrorFilter::Validation);
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("compute_bind_group_layout"),
+            entries: &entries,
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("compute_pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("compute_shader"),
+            source: ShaderSource::Wgsl(Cow::Owned(source.to_owned())),
+            flags: ShaderFlags::VALIDATION,
+        });
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("compute_pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point: "main",
+        });
+        if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+            return Err(GearError::ShaderError(e.to_string()));
+        }
+
+        self.shaders.push(ComputeShader { bindings: bindings.to_vec(), layout, pipeline });
+        Ok(ComputeShaderId(self.shaders.len() as u32 - 1))
+    }
+
+    // The bindings have to be the kinds the shader was created with
+    pub(crate) fn dispatch(
+        &mut self,
+        device: &Device,
+        shader: ComputeShaderId,
+        bindings: &[ComputeBinding],
+        workgroups: [u32; 3],
+    ) -> Result<()> {
+        let compute_shader = self
+            .shaders
+            .get(shader.0 as usize)
+            .ok_or(GearError::ShaderError(format!("unknown compute shader {:?}", shader)))?;
+        let matches = bindings.len() == compute_shader.bindings.len()
+            && bindings.iter().zip(&compute_shader.bindings).all(|(binding, kind)| binding.matches(*kind));
+        if !matches {
+            return Err(GearError::ShaderError(format!("bindings don't match compute shader {:?}", shader)));
+        }
+
+        let entries: Vec<BindGroupEntry> = bindings
+            .iter()
+            .enumerate()
+            .map(|(i, binding)| BindGroupEntry {
+                binding: i as u32,
+                resource: match binding {
+                    ComputeBinding::Uniform(buffer) | ComputeBinding::Storage(buffer) => buffer.buffer.as_entire_binding(),
+                    ComputeBinding::Texture(texture) => BindingResource::TextureView(texture.view()),
+                    ComputeBinding::StorageTexture(texture) => BindingResource::TextureView(texture.texture.view()),
+                },
+            })
+            .collect();
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("compute_bind_group"),
+            layout: &compute_shader.layout,
+            entries: &entries,
+        });
+
+        if workgroups.iter().all(|count| *count > 0) {
+            self.dispatches.push(Dispatch { shader, bind_group, workgroups });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn run(&mut self, encoder: &mut CommandEncoder) {
+        if self.dispatches.is_empty() {
+            return;
+        }
+
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor { label: Some("compute_pass") });
+        for dispatch in &self.dispatches {
+            compute_pass.set_pipeline(&self.shaders[dispatch.shader.0 as usize].pipeline);
+            compute_pass.set_bind_group(0, &dispatch.bind_group, &[]);
+            let [x, y, z] = dispatch.workgroups;
+            compute_pass.dispatch(x, y, z);
+        }
+        drop(compute_pass);
+        self.dispatches.clear();
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.dispatches.clear();
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::f32::consts::TAU;
+use std::mem::size_of;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use nalgebra::Vector3;
+use wgpu::vertex_attr_array;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingType;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::InputStepMode;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+
+use super::stats::FrameStats;
+
+// Lines past this in one frame are dropped
+const MAX_LINES: usize = 1 << 16;
+// Segments in each of the three circles a sphere is drawn with
+const SPHERE_SEGMENTS: usize = 32;
+
+// Replaces the lighting of meshes drawn with a physically based material
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    // Only the edges of every triangle, needs an adapter that can draw lines in place of polygons
+    Wireframe,
+    Normals,
+    // Brighter where more fragments were drawn to a pixel, hidden ones included
+    Overdraw,
+    Depth,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct DebugUniforms {
+    view_projection: [[f32; 4]; 4],
+}
+
+// Lines in world space that are drawn over the scene for a single frame
+#[derive(Debug)]
+pub(crate) struct DebugRenderer {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    pipeline: RenderPipeline,
+    vertex_buffer: Buffer,
+    uniform_buffer: Buffer,
+    uniform_bind_group: BindGroup,
+    vertices: Vec<DebugVertex>,
+    vertex_count: u32,
+}
+
+impl DebugRenderer {
+    pub(crate) fn new(device: &Device, format: TextureFormat, depth_format: TextureFormat) -> DebugRenderer {
+        let vertex_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("debug_vertex_buffer"),
+            size: (MAX_LINES * 2 * size_of::<DebugVertex>()) as u64,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("debug_uniform_buffer"),
+            size: size_of::<DebugUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("debug_uniform_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<DebugUniforms>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let uniform_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("debug_uniform_bind_group"),
+            layout: &uniform_bind_group_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() }],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("debug_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("debug.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("debug_pipeline_layout"),
+            bind_group_layouts: &[&uniform_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader_module, format, depth_format, 1);
+
+        DebugRenderer {
+            format,
+            depth_format,
+            shader_module,
+            pipeline_layout,
+            pipeline,
+            vertex_buffer,
+            uniform_buffer,
+            uniform_bind_group,
+            vertices: vec![],
+            vertex_count: 0,
+        }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.shader_module,
+            self.format,
+            self.depth_format,
+            sample_count,
+        );
+    }
+
+    pub(crate) fn line(&mut self, start: Point3<f32>, end: Point3<f32>, color: [f32; 4]) {
+        if self.vertices.len() >= MAX_LINES * 2 {
+            return;
+        }
+
+        self.vertices.push(DebugVertex { position: [start.x, start.y, start.z], color });
+        self.vertices.push(DebugVertex { position: [end.x, end.y, end.z], color });
+    }
+
+    pub(crate) fn aabb(&mut self, min: Point3<f32>, max: Point3<f32>, color: [f32; 4]) {
+        let corner = |i: usize| {
+            Point3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+
+        // Every pair of corners that differ along a single axis
+        for i in 0..8 {
+            for axis in [1, 2, 4].iter() {
+                if i & axis == 0 {
+                    self.line(corner(i), corner(i | axis), color);
+                }
+            }
+        }
+    }
+
+    // A circle around each axis
+    pub(crate) fn sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4]) {
+        let point = |axis: usize, angle: f32| {
+            let (sin, cos) = (angle.sin() * radius, angle.cos() * radius);
+            let offset = match axis {
+                0 => Vector3::new(0., cos, sin),
+                1 => Vector3::new(cos, 0., sin),
+                _ => Vector3::new(cos, sin, 0.),
+            };
+            center + offset
+        };
+
+        for axis in 0..3 {
+            for segment in 0..SPHERE_SEGMENTS {
+                let start = segment as f32 / SPHERE_SEGMENTS as f32 * TAU;
+                let end = (segment + 1) as f32 / SPHERE_SEGMENTS as f32 * TAU;
+                self.line(point(axis, start), point(axis, end), color);
+            }
+        }
+    }
+
+    // The transform's x, y and z axes in red, green and blue
+    pub(crate) fn axes(&mut self, transform: &Matrix4<f32>, size: f32) {
+        let origin = transform.transform_point(&Point3::origin());
+        let colors = [[1., 0., 0., 1.], [0., 1., 0., 1.], [0., 0., 1., 1.]];
+        for (axis, color) in colors.iter().enumerate() {
+            let end = transform.transform_point(&Point3::from(Vector3::ith(axis, size)));
+            self.line(origin, end, *color);
+        }
+    }
+
+    // Once for every camera the lines are drawn from, returns the number of writes
+    pub(crate) fn prepare(&mut self, queue: &Queue, view_projection: Matrix4<f32>) -> usize {
+        self.vertex_count = self.vertices.len() as u32;
+        if self.vertices.is_empty() {
+            return 0;
+        }
+
+        let uniforms = DebugUniforms { view_projection: view_projection.into() };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&self.vertices));
+        2
+    }
+
+    // Lines, which draw no triangles
+    pub(crate) fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.vertex_count == 0 {
+            return stats;
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.draw(0..self.vertex_count, 0..1);
+        stats.draw_calls += 1;
+        stats.pipeline_switches += 1;
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.vertices.clear();
+    }
+}
+
+// Lines are drawn over whatever is in the depth buffer and leave it untouched
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("debug_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "main",
+            buffers: &[VertexBufferLayout {
+                array_stride: size_of::<DebugVertex>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Vertex,
+                attributes: &vertex_attr_array![
+                    0 => Float32x3,
+                    1 => Float32x4,
+                ],
+            }],
+        },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::LineList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::Always,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format, blend: Some(BlendState::ALPHA_BLENDING), write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
@@ -0,0 +1,30 @@
+struct VertexInput {
+    [[location(0)]] pos: vec3<f32>;
+    [[location(1)]] color: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[location(0)]] color: vec4<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct Uniforms {
+    view_projection: mat4x4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.color = in.color;
+    out.pos = uniforms.view_projection * vec4<f32>(in.pos, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return in.color;
+}
@@ -0,0 +1,531 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::ops::Range;
+use std::time::Duration;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Isometry3;
+use nalgebra::Matrix4;
+use wgpu::util::BufferInitDescriptor;
+use wgpu::util::DeviceExt;
+use wgpu::vertex_attr_array;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::BlendComponent;
+use wgpu::BlendFactor;
+use wgpu::BlendOperation;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::Face;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::IndexFormat;
+use wgpu::InputStepMode;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureViewDimension;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+
+use super::depth_effects::DepthEffects;
+use super::stats::FrameStats;
+use crate::Texture;
+
+// Decals past this in one frame are dropped
+const MAX_DECALS: usize = 1 << 12;
+// Corners of the unit cube a decal's transform is applied to, x, y and z come from the bits of the index
+const CUBE_CORNERS: [[f32; 3]; 8] = [
+    [-0.5, -0.5, -0.5],
+    [0.5, -0.5, -0.5],
+    [-0.5, 0.5, -0.5],
+    [0.5, 0.5, -0.5],
+    [-0.5, -0.5, 0.5],
+    [0.5, -0.5, 0.5],
+    [-0.5, 0.5, 0.5],
+    [0.5, 0.5, 0.5],
+];
+// Counter clockwise from outside of the cube
+const CUBE_INDICES: [u32; 36] =
+    [0, 2, 3, 0, 3, 1, 4, 5, 7, 4, 7, 6, 0, 4, 6, 0, 6, 2, 1, 3, 7, 1, 7, 5, 0, 1, 5, 0, 5, 4, 2, 6, 7, 2, 7, 3];
+
+// A texture projected onto whatever is inside of a box. The transform maps a unit cube centered on the origin into
+// world space and the texture is projected down its z axis, so its x and y are the texture's width and height and z is
+// how deep it reaches
+#[derive(Clone, Debug)]
+pub struct Decal {
+    pub texture: Texture,
+    pub transform: Matrix4<f32>,
+    // Part of the texture as [left, top, right, bottom] in texture coordinates
+    pub region: [f32; 4],
+    pub tint: [f32; 4],
+    // How long a decal spawned into a pool lasts, None lasts until the pool runs out of room
+    pub lifetime: Option<Duration>,
+    // Taken off the end of the lifetime to fade out over
+    pub fade: Duration,
+}
+
+impl Decal {
+    pub fn new(texture: &Texture, transform: Matrix4<f32>) -> Decal {
+        Decal {
+            texture: texture.clone(),
+            transform,
+            region: [0., 0., 1., 1.],
+            tint: [1., 1., 1., 1.],
+            lifetime: None,
+            fade: Duration::from_secs(0),
+        }
+    }
+
+    // How opaque the decal is at an age, zero once it has expired
+    fn opacity(&self, age: Duration) -> f32 {
+        let lifetime = match self.lifetime {
+            Some(lifetime) => lifetime,
+            None => return 1.,
+        };
+        let left = match lifetime.checked_sub(age) {
+            Some(left) => left,
+            None => return 0.,
+        };
+        match self.fade.as_secs_f32() > 0. {
+            true => (left.as_secs_f32() / self.fade.as_secs_f32()).min(1.),
+            false => 1.,
+        }
+    }
+}
+
+// Keeps decals alive over frames, like bullet holes and tire marks. When it is full the oldest decal makes room
+#[derive(Clone, Debug)]
+pub struct DecalPool {
+    decals: VecDeque<(Decal, Duration)>,
+    capacity: usize,
+}
+
+impl DecalPool {
+    pub fn new(capacity: usize) -> DecalPool {
+        DecalPool { decals: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn spawn(&mut self, decal: Decal) -> &mut Self {
+        if self.capacity == 0 {
+            return self;
+        }
+        if self.decals.len() >= self.capacity {
+            self.decals.pop_front();
+        }
+        self.decals.push_back((decal, Duration::from_secs(0)));
+        self
+    }
+
+    // Ages every decal and removes the ones that have expired
+    pub fn update(&mut self, delta_time: Duration) {
+        for (_, age) in &mut self.decals {
+            *age += delta_time;
+        }
+        self.decals.retain(|(decal, age)| decal.opacity(*age) > 0.);
+    }
+
+    pub fn len(&self) -> usize {
+        self.decals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decals.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.decals.clear();
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Decal, f32)> {
+        self.decals.iter().map(|(decal, age)| (decal, decal.opacity(*age)))
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct DecalInstance {
+    model: [[f32; 4]; 4],
+    inverse: [[f32; 4]; 4],
+    region: [f32; 4],
+    tint: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct DecalUniforms {
+    view_projection: [[f32; 4]; 4],
+    projection: [[f32; 4]; 4],
+    inverse_view: [[f32; 4]; 4],
+    rect: [f32; 4],
+    screen: [f32; 4],
+}
+
+#[derive(Debug)]
+struct QueuedDecal {
+    texture: Texture,
+    instance: DecalInstance,
+}
+
+#[derive(Debug)]
+struct Batch {
+    texture: Texture,
+    instances: Range<u32>,
+}
+
+// Decals are drawn in the scene pass where the depth prepass of the depth effects says something was drawn, that has to
+// have run for the camera first
+#[derive(Debug)]
+pub(crate) struct DecalRenderer {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    pipeline: RenderPipeline,
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    instance_buffer: Buffer,
+    uniform_buffer: Buffer,
+    layout: BindGroupLayout,
+    bind_group: BindGroup,
+    size: [u32; 2],
+    decals: Vec<QueuedDecal>,
+    batches: Vec<Batch>,
+}
+
+impl DecalRenderer {
+    pub(crate) fn new(
+        device: &Device,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        texture_layout: &BindGroupLayout,
+        depth_effects: &DepthEffects,
+        size: [u32; 2],
+    ) -> DecalRenderer {
+        let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("decal_vertex_buffer"),
+            contents: bytemuck::cast_slice(&CUBE_CORNERS),
+            usage: BufferUsage::VERTEX,
+        });
+
+        let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("decal_index_buffer"),
+            contents: bytemuck::cast_slice(&CUBE_INDICES),
+            usage: BufferUsage::INDEX,
+        });
+
+        let instance_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("decal_instance_buffer"),
+            size: (MAX_DECALS * size_of::<DecalInstance>()) as u64,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("decal_uniform_buffer"),
+            size: size_of::<DecalUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("decal_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::VERTEX | ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<DecalUniforms>() as _),
+                    },
+                    count: None,
+                },
+                texture_entry(1),
+                texture_entry(2),
+            ],
+        });
+
+        let bind_group = create_bind_group(device, &layout, &uniform_buffer, depth_effects);
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("decal_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("decal.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("decal_pipeline_layout"),
+            bind_group_layouts: &[&layout, texture_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader_module, format, depth_format, 1);
+
+        DecalRenderer {
+            format,
+            depth_format,
+            shader_module,
+            pipeline_layout,
+            pipeline,
+            vertex_buffer,
+            index_buffer,
+            instance_buffer,
+            uniform_buffer,
+            layout,
+            bind_group,
+            size,
+            decals: vec![],
+            batches: vec![],
+        }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.shader_module,
+            self.format,
+            self.depth_format,
+            sample_count,
+        );
+    }
+
+    // After the depth effects have been resized
+    pub(crate) fn resize(&mut self, device: &Device, depth_effects: &DepthEffects, size: [u32; 2]) {
+        self.size = size;
+        self.bind_group = create_bind_group(device, &self.layout, &self.uniform_buffer, depth_effects);
+    }
+
+    pub(crate) fn push(&mut self, decal: &Decal, opacity: f32) {
+        if self.decals.len() >= MAX_DECALS || opacity <= 0. {
+            return;
+        }
+
+        let inverse = decal.transform.try_inverse().unwrap_or_else(Matrix4::zeros);
+        let [r, g, b, a] = decal.tint;
+        let instance = DecalInstance {
+            model: decal.transform.into(),
+            inverse: inverse.into(),
+            region: decal.region,
+            tint: [r, g, b, a * opacity],
+        };
+        self.decals.push(QueuedDecal { texture: decal.texture.clone(), instance });
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.decals.is_empty()
+    }
+
+    // Decals blend over each other in the order they were drawn, runs sharing a texture are drawn together
+    // Returns the number of writes
+    pub(crate) fn prepare(
+        &mut self,
+        queue: &Queue,
+        view: Isometry3<f32>,
+        projection: Matrix4<f32>,
+        rect: [u32; 4],
+    ) -> usize {
+        if self.decals.is_empty() {
+            return 0;
+        }
+
+        let mut writes = 1;
+        // Built by the first camera of the frame, the others draw the same decals
+        if self.batches.is_empty() {
+            let mut instances = Vec::with_capacity(self.decals.len());
+            for (i, decal) in self.decals.iter().enumerate() {
+                instances.push(decal.instance);
+
+                let index = i as u32;
+                match self.batches.last_mut() {
+                    Some(batch) if batch.texture.id() == decal.texture.id() => batch.instances.end = index + 1,
+                    _ => self.batches.push(Batch { texture: decal.texture.clone(), instances: index..index + 1 }),
+                }
+            }
+            queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instances));
+            writes += 1;
+        }
+
+        let [width, height] = [self.size[0].max(1) as f32, self.size[1].max(1) as f32];
+        let uniforms = DecalUniforms {
+            view_projection: (projection * view.to_homogeneous()).into(),
+            projection: projection.into(),
+            inverse_view: view.inverse().to_homogeneous().into(),
+            rect: [rect[0] as f32 / width, rect[1] as f32 / height, rect[2] as f32 / width, rect[3] as f32 / height],
+            screen: [width, height, 1. / width, 1. / height],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        writes
+    }
+
+    pub(crate) fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.batches.is_empty() {
+            return stats;
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        stats.pipeline_switches += 1;
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint32);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        for batch in &self.batches {
+            render_pass.set_bind_group(1, batch.texture.bind_group(), &[]);
+            render_pass.draw_indexed(0..CUBE_INDICES.len() as u32, 0, batch.instances.clone());
+            stats.draw(CUBE_INDICES.len() as u32, batch.instances.end - batch.instances.start);
+            stats.texture_binds += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.decals.clear();
+        self.batches.clear();
+    }
+}
+
+fn create_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    uniform_buffer: &Buffer,
+    depth_effects: &DepthEffects,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("decal_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() },
+            BindGroupEntry { binding: 1, resource: BindingResource::TextureView(depth_effects.normal_view()) },
+            BindGroupEntry { binding: 2, resource: BindingResource::TextureView(depth_effects.linear_depth_view()) },
+        ],
+    })
+}
+
+// Only the back of each box is drawn, so a decal still shows with the camera inside of it. The depth buffer is left alone
+// since the depth prepass already says where the decal lands, and the scene's alpha is kept
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+) -> RenderPipeline {
+    let blend = BlendState {
+        color: BlendComponent {
+            src_factor: BlendFactor::SrcAlpha,
+            dst_factor: BlendFactor::OneMinusSrcAlpha,
+            operation: BlendOperation::Add,
+        },
+        alpha: BlendComponent {
+            src_factor: BlendFactor::Zero,
+            dst_factor: BlendFactor::One,
+            operation: BlendOperation::Add,
+        },
+    };
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("decal_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "main",
+            buffers: &[
+                VertexBufferLayout {
+                    array_stride: size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    step_mode: InputStepMode::Vertex,
+                    attributes: &vertex_attr_array![0 => Float32x3],
+                },
+                VertexBufferLayout {
+                    array_stride: size_of::<DecalInstance>() as wgpu::BufferAddress,
+                    step_mode: InputStepMode::Instance,
+                    attributes: &vertex_attr_array![
+                        1 => Float32x4,
+                        2 => Float32x4,
+                        3 => Float32x4,
+                        4 => Float32x4,
+                        5 => Float32x4,
+                        6 => Float32x4,
+                        7 => Float32x4,
+                        8 => Float32x4,
+                        9 => Float32x4,
+                        10 => Float32x4,
+                    ],
+                },
+            ],
+        },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: Some(Face::Front),
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::Always,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format, blend: Some(blend), write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
@@ -0,0 +1,103 @@
+[[block]]
+struct DecalUniforms {
+    view_projection: mat4x4<f32>;
+    projection: mat4x4<f32>;
+    // From the camera's space back into the world
+    inverse_view: mat4x4<f32>;
+    // Left, top, width and height of the camera's viewport as fractions of the screen
+    rect: vec4<f32>;
+    // Width and height in pixels and one over each
+    screen: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> frame: DecalUniforms;
+[[group(0), binding(1)]]
+var normals: texture_2d<f32>;
+[[group(0), binding(2)]]
+var depths: texture_2d<f32>;
+
+[[group(1), binding(0)]]
+var decal_texture: texture_2d<f32>;
+[[group(1), binding(1)]]
+var decal_sampler: sampler;
+
+struct VertexInput {
+    [[location(0)]] pos: vec3<f32>;
+};
+
+struct InstanceInput {
+    [[location(1)]] model_0: vec4<f32>;
+    [[location(2)]] model_1: vec4<f32>;
+    [[location(3)]] model_2: vec4<f32>;
+    [[location(4)]] model_3: vec4<f32>;
+    [[location(5)]] inverse_0: vec4<f32>;
+    [[location(6)]] inverse_1: vec4<f32>;
+    [[location(7)]] inverse_2: vec4<f32>;
+    [[location(8)]] inverse_3: vec4<f32>;
+    [[location(9)]] region: vec4<f32>;
+    [[location(10)]] tint: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[location(0)]] inverse_0: vec4<f32>;
+    [[location(1)]] inverse_1: vec4<f32>;
+    [[location(2)]] inverse_2: vec4<f32>;
+    [[location(3)]] inverse_3: vec4<f32>;
+    // The way the decal projects in world space
+    [[location(4)]] forward: vec3<f32>;
+    [[location(5)]] region: vec4<f32>;
+    [[location(6)]] tint: vec4<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main(in: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    var out: VertexOutput;
+    out.inverse_0 = instance.inverse_0;
+    out.inverse_1 = instance.inverse_1;
+    out.inverse_2 = instance.inverse_2;
+    out.inverse_3 = instance.inverse_3;
+    out.forward = normalize((model * vec4<f32>(0.0, 0.0, 1.0, 0.0)).xyz);
+    out.region = instance.region;
+    out.tint = instance.tint;
+    out.pos = frame.view_projection * model * vec4<f32>(in.pos, 1.0);
+    return out;
+}
+
+// Assumes a perspective projection
+fn view_position(uv: vec2<f32>, depth: f32) -> vec3<f32> {
+    let local = (uv - frame.rect.xy) / frame.rect.zw;
+    let ndc = vec2<f32>(local.x * 2.0 - 1.0, 1.0 - local.y * 2.0);
+    let p = frame.projection;
+    let x = (ndc.x + p[2][0]) * depth / p[0][0];
+    let y = (ndc.y + p[2][1]) * depth / p[1][1];
+    return vec3<f32>(x, y, -depth);
+}
+
+// Whatever was drawn under the pixel is moved into the decal's box, the parts outside of it are left alone
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let pixel = vec2<i32>(in.pos.xy);
+    let depth = textureLoad(depths, pixel, 0).r;
+    if (depth <= 0.0) {
+        discard;
+    }
+
+    let uv = in.pos.xy * frame.screen.zw;
+    let world = frame.inverse_view * vec4<f32>(view_position(uv, depth), 1.0);
+    let inverse = mat4x4<f32>(in.inverse_0, in.inverse_1, in.inverse_2, in.inverse_3);
+    let local = (inverse * world).xyz;
+    if (abs(local.x) > 0.5 || abs(local.y) > 0.5 || abs(local.z) > 0.5) {
+        discard;
+    }
+
+    // Fades out on surfaces turned away from the decal instead of smearing along them
+    let normal = normalize((frame.inverse_view * vec4<f32>(textureLoad(normals, pixel, 0).xyz, 0.0)).xyz);
+    let facing = smoothStep(0.0, 0.3, dot(normal, in.forward));
+
+    let tex_coord = vec2<f32>(mix(in.region.x, in.region.z, local.x + 0.5), mix(in.region.w, in.region.y, local.y + 0.5));
+    let color = textureSampleLevel(decal_texture, decal_sampler, tex_coord, 0.0) * in.tint;
+    return vec4<f32>(color.rgb, color.a * facing);
+}
@@ -0,0 +1,723 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Isometry3;
+use nalgebra::Matrix4;
+use wgpu::vertex_attr_array;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Color;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CommandEncoder;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::Extent3d;
+use wgpu::FilterMode;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::InputStepMode;
+use wgpu::LoadOp;
+use wgpu::MultisampleState;
+use wgpu::Operations;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPassColorAttachment;
+use wgpu::RenderPassDepthStencilAttachment;
+use wgpu::RenderPassDescriptor;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::TextureViewDimension;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+use wgpu::BIND_BUFFER_ALIGNMENT;
+
+use super::instance::InstanceBuffer;
+use super::mesh::MeshInput;
+use super::mesh::MeshVariant;
+use super::post::PostProcessor;
+use super::skin::JointBuffer;
+use super::stats::FrameStats;
+use super::Cubemap;
+use super::DepthOfField;
+use super::GpuMesh;
+use super::Ssao;
+use super::Ssr;
+use crate::model::Vertex;
+
+const NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+// Distance from the camera rather than the depth buffer's reversed depth, so it doesn't need the projection to read
+const LINEAR_DEPTH_FORMAT: TextureFormat = TextureFormat::R32Float;
+const DEPTH_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+const OCCLUSION_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+// The roughness and metallic factors of whatever was drawn
+const SURFACE_FORMAT: TextureFormat = TextureFormat::Rg8Unorm;
+// Reflected color and how much of it is added to the scene
+const REFLECTION_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct PrepassUniforms {
+    view: [[f32; 4]; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct EffectUniforms {
+    projection: [[f32; 4]; 4],
+    inverse_view: [[f32; 4]; 4],
+    rect: [f32; 4],
+    screen: [f32; 4],
+    ssao: [f32; 4],
+    ssr: [f32; 4],
+    ssr_surface: [f32; 4],
+    depth_of_field: [f32; 4],
+}
+
+#[derive(Debug)]
+struct DepthMesh {
+    mesh: GpuMesh,
+    uniform: u32,
+    input: MeshInput,
+}
+
+#[derive(Debug)]
+struct Target {
+    _texture: wgpu::Texture,
+    view: TextureView,
+}
+
+impl Target {
+    fn new(device: &Device, size: [u32; 2], format: TextureFormat, usage: TextureUsage) -> Target {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("depth_effects_target"),
+            size: Extent3d { width: size[0].max(1), height: size[1].max(1), depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        Target { _texture: texture, view }
+    }
+}
+
+#[derive(Debug)]
+struct Targets {
+    normal: Target,
+    linear_depth: Target,
+    surface: Target,
+    depth: Target,
+    occlusion: Target,
+    reflection: Target,
+}
+
+impl Targets {
+    fn new(device: &Device, size: [u32; 2]) -> Targets {
+        let sampled = TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED;
+        Targets {
+            normal: Target::new(device, size, NORMAL_FORMAT, sampled),
+            linear_depth: Target::new(device, size, LINEAR_DEPTH_FORMAT, sampled),
+            surface: Target::new(device, size, SURFACE_FORMAT, sampled),
+            depth: Target::new(device, size, DEPTH_FORMAT, TextureUsage::RENDER_ATTACHMENT),
+            occlusion: Target::new(device, size, OCCLUSION_FORMAT, sampled),
+            reflection: Target::new(device, size, REFLECTION_FORMAT, sampled),
+        }
+    }
+}
+
+// One camera's part of the screen in pixels and the effects it uses
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EffectCamera {
+    pub(crate) view: Isometry3<f32>,
+    pub(crate) projection: Matrix4<f32>,
+    pub(crate) rect: [u32; 4],
+    pub(crate) ssao: Option<Ssao>,
+    pub(crate) ssr: Option<Ssr>,
+    pub(crate) depth_of_field: Option<DepthOfField>,
+    // The first camera of the frame also copies the rest of the screen
+    pub(crate) first: bool,
+}
+
+impl EffectCamera {
+    pub(crate) fn uses_effects(&self) -> bool {
+        self.ssao.is_some() || self.ssr.is_some() || self.depth_of_field.is_some()
+    }
+}
+
+// Ssao, reflections, depth of field and decals need the depth and normals of the scene without multisampling, so every
+// mesh is drawn again to its own targets before they run. The effects then write the scene to the post processor's
+// processed target
+#[derive(Debug)]
+pub(crate) struct DepthEffects {
+    pipeline: RenderPipeline,
+    skinned_pipeline: RenderPipeline,
+    instanced_pipeline: RenderPipeline,
+    prepass_buffer: Buffer,
+    prepass_bind_group: BindGroup,
+    layout: BindGroupLayout,
+    sampler: Sampler,
+    ssao: RenderPipeline,
+    ssr: RenderPipeline,
+    apply: RenderPipeline,
+    uniform_buffer: Buffer,
+    size: [u32; 2],
+    targets: Targets,
+    // The ssao and reflection passes can't read what they write, so they bind the normals there instead
+    trace_bind_group: BindGroup,
+    apply_bind_group: BindGroup,
+    environment_layout: BindGroupLayout,
+    environment_sampler: Sampler,
+    // Black until an environment is set, which the reflections are told about
+    environment_bind_group: BindGroup,
+    environment: bool,
+    meshes: Vec<DepthMesh>,
+}
+
+impl DepthEffects {
+    pub(crate) fn new(
+        device: &Device,
+        object_layout: &BindGroupLayout,
+        joint_layout: &BindGroupLayout,
+        hdr_format: TextureFormat,
+        post: &PostProcessor,
+        size: [u32; 2],
+    ) -> DepthEffects {
+        let prepass_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("depth_prepass_uniform_buffer"),
+            size: size_of::<PrepassUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let prepass_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("depth_prepass_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<PrepassUniforms>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let prepass_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("depth_prepass_bind_group"),
+            layout: &prepass_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: prepass_buffer.as_entire_binding() }],
+        });
+
+        let prepass_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("depth_prepass_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("depth_prepass.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let prepass_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("depth_prepass_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &prepass_layout],
+            push_constant_ranges: &[],
+        });
+
+        let skinned_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("depth_prepass_skinned_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &prepass_layout, joint_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_prepass_pipeline(device, &prepass_pipeline_layout, &prepass_module, MeshVariant::Plain);
+        let skinned_pipeline =
+            create_prepass_pipeline(device, &skinned_pipeline_layout, &prepass_module, MeshVariant::Skinned);
+        let instanced_pipeline =
+            create_prepass_pipeline(device, &prepass_pipeline_layout, &prepass_module, MeshVariant::Instanced);
+
+        let texture_entry = |binding, filterable| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("depth_effects_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<EffectUniforms>() as _),
+                    },
+                    count: None,
+                },
+                texture_entry(1, true),
+                texture_entry(2, true),
+                texture_entry(3, false),
+                texture_entry(4, true),
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: false },
+                    count: None,
+                },
+                texture_entry(6, true),
+                texture_entry(7, true),
+            ],
+        });
+
+        let environment_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("depth_effects_environment_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::Cube,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: false },
+                    count: None,
+                },
+            ],
+        });
+
+        let environment_sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("depth_effects_environment_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let black = device.create_texture(&TextureDescriptor {
+            label: Some("depth_effects_black_environment"),
+            size: Extent3d { width: 1, height: 1, depth_or_array_layers: 6 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            usage: TextureUsage::SAMPLED,
+        });
+        let black =
+            black.create_view(&TextureViewDescriptor { dimension: Some(TextureViewDimension::Cube), ..Default::default() });
+        let environment_bind_group =
+            create_environment_bind_group(device, &environment_layout, &black, &environment_sampler);
+
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("depth_effects_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let effects_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("depth_effects_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("depth_effects.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let effects_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("depth_effects_pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let ssr_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("depth_effects_ssr_pipeline_layout"),
+            bind_group_layouts: &[&layout, &environment_layout],
+            push_constant_ranges: &[],
+        });
+
+        let ssao = create_effect_pipeline(device, &effects_pipeline_layout, &effects_module, "ssao", OCCLUSION_FORMAT);
+        let ssr = create_effect_pipeline(device, &ssr_pipeline_layout, &effects_module, "ssr", REFLECTION_FORMAT);
+        let apply = create_effect_pipeline(device, &effects_pipeline_layout, &effects_module, "apply", hdr_format);
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("depth_effects_uniform_buffer"),
+            size: size_of::<EffectUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let targets = Targets::new(device, size);
+        let (trace_bind_group, apply_bind_group) =
+            create_bind_groups(device, &layout, &uniform_buffer, &sampler, &targets, post);
+
+        DepthEffects {
+            pipeline,
+            skinned_pipeline,
+            instanced_pipeline,
+            prepass_buffer,
+            prepass_bind_group,
+            layout,
+            sampler,
+            ssao,
+            ssr,
+            apply,
+            uniform_buffer,
+            size,
+            targets,
+            trace_bind_group,
+            apply_bind_group,
+            environment_layout,
+            environment_sampler,
+            environment_bind_group,
+            environment: false,
+            meshes: vec![],
+        }
+    }
+
+    // After the post processor has been resized
+    pub(crate) fn resize(&mut self, device: &Device, post: &PostProcessor, size: [u32; 2]) {
+        self.size = size;
+        self.targets = Targets::new(device, size);
+        let (trace_bind_group, apply_bind_group) =
+            create_bind_groups(device, &self.layout, &self.uniform_buffer, &self.sampler, &self.targets, post);
+        self.trace_bind_group = trace_bind_group;
+        self.apply_bind_group = apply_bind_group;
+    }
+
+    // Reflected where rays leave the screen, None reflects black there
+    pub(crate) fn set_environment(&mut self, device: &Device, environment: Option<&Cubemap>) {
+        if let Some(environment) = environment {
+            self.environment_bind_group = create_environment_bind_group(
+                device,
+                &self.environment_layout,
+                environment.view(),
+                &self.environment_sampler,
+            );
+        }
+        self.environment = environment.is_some();
+    }
+
+    pub(crate) fn push(&mut self, mesh: &GpuMesh, uniform: u32, input: MeshInput) {
+        if mesh.accepts(&input) {
+            self.meshes.push(DepthMesh { mesh: mesh.clone(), uniform, input });
+        }
+    }
+
+    // Before each camera's render, the uniforms are shared between them, returns the number of writes
+    pub(crate) fn prepare(&self, queue: &Queue, camera: &EffectCamera) -> usize {
+        let prepass = PrepassUniforms { view: camera.view.to_homogeneous().into() };
+        queue.write_buffer(&self.prepass_buffer, 0, bytemuck::bytes_of(&prepass));
+
+        let [width, height] = [self.size[0].max(1) as f32, self.size[1].max(1) as f32];
+        let rect = camera.rect;
+        let ssao = camera.ssao.map_or([0.; 4], |ssao| [ssao.radius, ssao.intensity, ssao.bias, 1.]);
+        let environment = if self.environment { 1. } else { 0. };
+        let (ssr, ssr_surface) = camera.ssr.map_or(([0.; 4], [0.; 4]), |ssr| {
+            (
+                [ssr.max_distance, ssr.thickness, ssr.steps.max(1) as f32, 1.],
+                [ssr.roughness_cutoff.max(f32::EPSILON), ssr.intensity, environment, 0.],
+            )
+        });
+        let depth_of_field = camera.depth_of_field.map_or([0.; 4], |depth_of_field| {
+            [depth_of_field.focal_distance, depth_of_field.aperture, depth_of_field.max_blur, 1.]
+        });
+        let uniforms = EffectUniforms {
+            projection: camera.projection.into(),
+            inverse_view: camera.view.inverse().to_homogeneous().into(),
+            rect: [rect[0] as f32 / width, rect[1] as f32 / height, rect[2] as f32 / width, rect[3] as f32 / height],
+            screen: [width, height, 1. / width, 1. / height],
+            ssao,
+            ssr,
+            ssr_surface,
+            depth_of_field,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        2
+    }
+
+    // The normals, linear depth and surfaces of the camera's part of the screen, for the effects and anything else
+    // reading them
+    pub(crate) fn render_prepass(
+        &self,
+        encoder: &mut CommandEncoder,
+        object_bind_group: &BindGroup,
+        joints: &JointBuffer,
+        instances: &InstanceBuffer,
+        rect: [u32; 4],
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        let [x, y, width, height] = rect;
+        let clear = Operations { load: LoadOp::Clear(Color::TRANSPARENT), store: true };
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("depth_prepass"),
+            color_attachments: &[
+                RenderPassColorAttachment { view: &self.targets.normal.view, resolve_target: None, ops: clear },
+                RenderPassColorAttachment { view: &self.targets.linear_depth.view, resolve_target: None, ops: clear },
+                RenderPassColorAttachment { view: &self.targets.surface.view, resolve_target: None, ops: clear },
+            ],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &self.targets.depth.view,
+                depth_ops: Some(Operations { load: LoadOp::Clear(0.0), store: true }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_viewport(x as f32, y as f32, width as f32, height as f32, 0., 1.);
+        render_pass.set_scissor_rect(x, y, width, height);
+
+        for mesh in &self.meshes {
+            render_pass.set_pipeline(match mesh.input.variant() {
+                MeshVariant::Plain => &self.pipeline,
+                MeshVariant::Skinned => &self.skinned_pipeline,
+                MeshVariant::Instanced => &self.instanced_pipeline,
+            });
+            let offset = (mesh.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(0, object_bind_group, &[offset]);
+            render_pass.set_bind_group(1, &self.prepass_bind_group, &[]);
+            stats += mesh.mesh.draw(&mut render_pass, &mesh.input, joints, 2, instances);
+            stats.pipeline_switches += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn size(&self) -> [u32; 2] {
+        self.size
+    }
+
+    pub(crate) fn normal_view(&self) -> &TextureView {
+        &self.targets.normal.view
+    }
+
+    pub(crate) fn linear_depth_view(&self) -> &TextureView {
+        &self.targets.linear_depth.view
+    }
+
+    // Runs every camera of a frame once any of them uses an effect, so the processed scene is complete. A camera using
+    // an effect must have run the prepass first
+    pub(crate) fn render(&self, encoder: &mut CommandEncoder, camera: &EffectCamera, output: &TextureView) {
+        let [x, y, width, height] = camera.rect;
+
+        if camera.ssao.is_some() {
+            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("ssao_pass"),
+                color_attachments: &[RenderPassColorAttachment {
+                    view: &self.targets.occlusion.view,
+                    resolve_target: None,
+                    ops: Operations { load: LoadOp::Clear(Color::WHITE), store: true },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_scissor_rect(x, y, width, height);
+            render_pass.set_pipeline(&self.ssao);
+            render_pass.set_bind_group(0, &self.trace_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        if camera.ssr.is_some() {
+            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("ssr_pass"),
+                color_attachments: &[RenderPassColorAttachment {
+                    view: &self.targets.reflection.view,
+                    resolve_target: None,
+                    ops: Operations { load: LoadOp::Clear(Color::TRANSPARENT), store: true },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_scissor_rect(x, y, width, height);
+            render_pass.set_pipeline(&self.ssr);
+            render_pass.set_bind_group(0, &self.trace_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.environment_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        let load = if camera.first { LoadOp::Clear(Color::BLACK) } else { LoadOp::Load };
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("depth_effects_pass"),
+            color_attachments: &[RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: Operations { load, store: true },
+            }],
+            depth_stencil_attachment: None,
+        });
+        if !camera.first {
+            render_pass.set_scissor_rect(x, y, width, height);
+        }
+        render_pass.set_pipeline(&self.apply);
+        render_pass.set_bind_group(0, &self.apply_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.meshes.clear();
+    }
+}
+
+fn create_bind_groups(
+    device: &Device,
+    layout: &BindGroupLayout,
+    uniform_buffer: &Buffer,
+    sampler: &Sampler,
+    targets: &Targets,
+    post: &PostProcessor,
+) -> (BindGroup, BindGroup) {
+    let bind_group = |occlusion, reflection| {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("depth_effects_bind_group"),
+            layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() },
+                BindGroupEntry { binding: 1, resource: BindingResource::TextureView(post.scene_view()) },
+                BindGroupEntry { binding: 2, resource: BindingResource::TextureView(&targets.normal.view) },
+                BindGroupEntry { binding: 3, resource: BindingResource::TextureView(&targets.linear_depth.view) },
+                BindGroupEntry { binding: 4, resource: BindingResource::TextureView(occlusion) },
+                BindGroupEntry { binding: 5, resource: BindingResource::Sampler(sampler) },
+                BindGroupEntry { binding: 6, resource: BindingResource::TextureView(&targets.surface.view) },
+                BindGroupEntry { binding: 7, resource: BindingResource::TextureView(reflection) },
+            ],
+        })
+    };
+    (bind_group(&targets.normal.view, &targets.normal.view), bind_group(&targets.occlusion.view, &targets.reflection.view))
+}
+
+fn create_environment_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    environment: &TextureView,
+    sampler: &Sampler,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("depth_effects_environment_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry { binding: 0, resource: BindingResource::TextureView(environment) },
+            BindGroupEntry { binding: 1, resource: BindingResource::Sampler(sampler) },
+        ],
+    })
+}
+
+// Depth is reversed like the scene's so the closest surface is the one kept
+fn create_prepass_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    variant: MeshVariant,
+) -> RenderPipeline {
+    let vertex_attributes = vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x3];
+    let mut buffers = vec![VertexBufferLayout {
+        array_stride: size_of::<Vertex>() as wgpu::BufferAddress,
+        step_mode: InputStepMode::Vertex,
+        attributes: &vertex_attributes,
+    }];
+    buffers.extend(variant.extra_buffer());
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("depth_prepass_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: variant.entry_point(), buffers: &buffers },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[
+                ColorTargetState { format: NORMAL_FORMAT, blend: None, write_mask: ColorWrite::ALL },
+                ColorTargetState { format: LINEAR_DEPTH_FORMAT, blend: None, write_mask: ColorWrite::ALL },
+                ColorTargetState { format: SURFACE_FORMAT, blend: None, write_mask: ColorWrite::ALL },
+            ],
+        }),
+    })
+}
+
+fn create_effect_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    entry_point: &str,
+    format: TextureFormat,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("depth_effects_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: "main", buffers: &[] },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point,
+            targets: &[ColorTargetState { format, blend: None, write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
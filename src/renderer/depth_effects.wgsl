@@ -0,0 +1,307 @@
+struct EffectOutput {
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct EffectUniforms {
+    projection: mat4x4<f32>;
+    // From the camera's space back into the world
+    inverse_view: mat4x4<f32>;
+    // Left, top, width and height of the camera's viewport as fractions of the screen
+    rect: vec4<f32>;
+    // Width and height in pixels and one over each
+    screen: vec4<f32>;
+    // Radius, intensity, bias and whether it is on
+    ssao: vec4<f32>;
+    // Max distance, thickness, steps and whether it is on
+    ssr: vec4<f32>;
+    // Roughness cutoff, intensity and whether there is an environment
+    ssr_surface: vec4<f32>;
+    // Focal distance, aperture, largest blur in pixels and whether it is on
+    depth_of_field: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> effects: EffectUniforms;
+[[group(0), binding(1)]]
+var scene: texture_2d<f32>;
+[[group(0), binding(2)]]
+var normals: texture_2d<f32>;
+[[group(0), binding(3)]]
+var depths: texture_2d<f32>;
+[[group(0), binding(4)]]
+var occlusion_map: texture_2d<f32>;
+[[group(0), binding(5)]]
+var effect_sampler: sampler;
+[[group(0), binding(6)]]
+var surfaces: texture_2d<f32>;
+[[group(0), binding(7)]]
+var reflection_map: texture_2d<f32>;
+
+[[group(1), binding(0)]]
+var environment: texture_cube<f32>;
+[[group(1), binding(1)]]
+var environment_sampler: sampler;
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] index: u32) -> EffectOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: EffectOutput;
+    out.tex_coord = vec2<f32>(uv.x, 1.0 - uv.y);
+    out.pos = vec4<f32>(uv * 2.0 - vec2<f32>(1.0, 1.0), 0.0, 1.0);
+    return out;
+}
+
+fn texel(uv: vec2<f32>) -> vec2<i32> {
+    let pixel = clamp(uv * effects.screen.xy, vec2<f32>(0.0, 0.0), effects.screen.xy - vec2<f32>(1.0, 1.0));
+    return vec2<i32>(pixel);
+}
+
+// Distance in front of the camera, zero where nothing was drawn
+fn linear_depth(uv: vec2<f32>) -> f32 {
+    return textureLoad(depths, texel(uv), 0).r;
+}
+
+// Assumes a perspective projection
+fn view_position(uv: vec2<f32>, depth: f32) -> vec3<f32> {
+    let local = (uv - effects.rect.xy) / effects.rect.zw;
+    let ndc = vec2<f32>(local.x * 2.0 - 1.0, 1.0 - local.y * 2.0);
+    let p = effects.projection;
+    let x = (ndc.x + p[2][0]) * depth / p[0][0];
+    let y = (ndc.y + p[2][1]) * depth / p[1][1];
+    return vec3<f32>(x, y, -depth);
+}
+
+fn screen_uv(position: vec3<f32>) -> vec2<f32> {
+    let clip = effects.projection * vec4<f32>(position, 1.0);
+    let ndc = clip.xy / clip.w;
+    let local = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    return effects.rect.xy + local * effects.rect.zw;
+}
+
+// Jimenez's interleaved gradient noise, it repeats little enough that a 4x4 blur hides it
+fn noise(pixel: vec2<f32>) -> f32 {
+    return fract(52.9829189 * fract(dot(pixel, vec2<f32>(0.06711056, 0.00583715))));
+}
+
+// Sixteen samples over the hemisphere around the normal, closer to the point the later they come
+[[stage(fragment)]]
+fn ssao(in: EffectOutput) -> [[location(0)]] vec4<f32> {
+    let depth = linear_depth(in.tex_coord);
+    if (depth <= 0.0) {
+        return vec4<f32>(1.0, 1.0, 1.0, 1.0);
+    }
+
+    let position = view_position(in.tex_coord, depth);
+    let normal = normalize(textureLoad(normals, texel(in.tex_coord), 0).xyz);
+    let angle = noise(floor(in.pos.xy)) * 6.2831853;
+    let random = vec3<f32>(cos(angle), sin(angle), 0.0);
+    let tangent = normalize(random - normal * dot(random, normal));
+    let bitangent = cross(normal, tangent);
+    let radius = effects.ssao.x;
+    let bias = effects.ssao.z;
+
+    var occlusion: f32 = 0.0;
+    var i: i32 = 0;
+    loop {
+        if (i >= 16) {
+            break;
+        }
+        let t = (f32(i) + 0.5) / 16.0;
+        let phi = f32(i) * 2.3999632;
+        let spread = sqrt(1.0 - t * t);
+        let direction = tangent * cos(phi) * spread + bitangent * sin(phi) * spread + normal * t;
+        let scale = mix(0.1, 1.0, t * t);
+        let target = position + direction * radius * scale;
+
+        let sample_depth = linear_depth(screen_uv(target));
+        // Surfaces far in front of the point don't darken it
+        let range = smoothStep(0.0, 1.0, radius / max(abs(depth - sample_depth), 0.0001));
+        if (sample_depth > 0.0 && sample_depth <= -target.z - bias) {
+            occlusion = occlusion + range;
+        }
+        continuing {
+            i = i + 1;
+        }
+    }
+
+    let ao = clamp(1.0 - occlusion / 16.0 * effects.ssao.y, 0.0, 1.0);
+    return vec4<f32>(ao, ao, ao, 1.0);
+}
+
+fn inside_rect(uv: vec2<f32>) -> bool {
+    let local = (uv - effects.rect.xy) / effects.rect.zw;
+    return local.x >= 0.0 && local.y >= 0.0 && local.x <= 1.0 && local.y <= 1.0;
+}
+
+// Marches the reflected ray in view space until it passes behind the depth, then halves the last step a few times to
+// find where it crossed. Alpha is how much of the reflection is added to the scene
+[[stage(fragment)]]
+fn ssr(in: EffectOutput) -> [[location(0)]] vec4<f32> {
+    let depth = linear_depth(in.tex_coord);
+    let surface = textureLoad(surfaces, texel(in.tex_coord), 0);
+    let cutoff = effects.ssr_surface.x;
+    if (depth <= 0.0 || surface.r >= cutoff) {
+        return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+    }
+
+    let position = view_position(in.tex_coord, depth);
+    let normal = normalize(textureLoad(normals, texel(in.tex_coord), 0).xyz);
+    let v = normalize(-position);
+    let r = reflect(-v, normal);
+    let steps = i32(effects.ssr.z);
+    let step_length = effects.ssr.x / effects.ssr.z;
+    let thickness = effects.ssr.y;
+    // Offsetting the start of each pixel's ray trades banding for noise
+    let jitter = noise(floor(in.pos.xy));
+
+    var hit: f32 = 0.0;
+    var hit_uv: vec2<f32> = in.tex_coord;
+    var near: f32 = 0.0;
+    var far: f32 = 0.0;
+    var i: i32 = 0;
+    loop {
+        if (i >= steps) {
+            break;
+        }
+        far = (f32(i) + jitter) * step_length + step_length;
+        let target = position + r * far;
+        let uv = screen_uv(target);
+        if (target.z > -0.01 || !inside_rect(uv)) {
+            break;
+        }
+        let sample_depth = linear_depth(uv);
+        if (sample_depth > 0.0 && -target.z > sample_depth && -target.z - sample_depth < thickness) {
+            hit = 1.0;
+            break;
+        }
+        near = far;
+        continuing {
+            i = i + 1;
+        }
+    }
+
+    if (hit > 0.5) {
+        var j: i32 = 0;
+        loop {
+            if (j >= 4) {
+                break;
+            }
+            let middle = (near + far) * 0.5;
+            let target = position + r * middle;
+            if (-target.z > linear_depth(screen_uv(target))) {
+                far = middle;
+            } else {
+                near = middle;
+            }
+            continuing {
+                j = j + 1;
+            }
+        }
+        hit_uv = screen_uv(position + r * far);
+    }
+
+    // Hits fade towards the sides of the viewport, where the rays that would have replaced them leave it
+    let local = (hit_uv - effects.rect.xy) / effects.rect.zw;
+    let edge = min(min(local.x, 1.0 - local.x), min(local.y, 1.0 - local.y));
+    let on_screen = hit * clamp(edge * 10.0, 0.0, 1.0);
+    let world_r = (effects.inverse_view * vec4<f32>(r, 0.0)).xyz;
+    let missed = textureSampleLevel(environment, environment_sampler, world_r, 0.0).rgb * effects.ssr_surface.z;
+    let found = textureSampleLevel(scene, effect_sampler, hit_uv, 0.0).rgb;
+    let color = mix(missed, found, on_screen);
+
+    // Schlick's approximation, metals reflect their own color head on which isn't known here so they reflect white
+    let f0 = mix(0.04, 1.0, surface.g);
+    let fresnel = f0 + (1.0 - f0) * pow(1.0 - max(dot(normal, v), 0.0), 5.0);
+    let strength = fresnel * (1.0 - surface.r / cutoff) * effects.ssr_surface.y;
+    return vec4<f32>(color, strength);
+}
+
+fn reflection(uv: vec2<f32>) -> vec3<f32> {
+    if (effects.ssr.w < 0.5) {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+    let reflected = textureLoad(reflection_map, texel(uv), 0);
+    return reflected.rgb * reflected.a;
+}
+
+fn raw_occlusion(uv: vec2<f32>) -> f32 {
+    if (effects.ssao.w < 0.5) {
+        return 1.0;
+    }
+    return textureLoad(occlusion_map, texel(uv), 0).r;
+}
+
+// Averaged over the size of the noise pattern
+fn occlusion(uv: vec2<f32>) -> f32 {
+    if (effects.ssao.w < 0.5) {
+        return 1.0;
+    }
+
+    var total: f32 = 0.0;
+    var i: i32 = 0;
+    loop {
+        if (i >= 16) {
+            break;
+        }
+        let offset = vec2<f32>(f32(i % 4) - 1.5, f32(i / 4) - 1.5) * effects.screen.zw;
+        total = total + textureLoad(occlusion_map, texel(uv + offset), 0).r;
+        continuing {
+            i = i + 1;
+        }
+    }
+    return total / 16.0;
+}
+
+// The circle of confusion in pixels, nothing drawn is as far away as it gets
+fn blur_size(depth: f32) -> f32 {
+    if (effects.depth_of_field.w < 0.5) {
+        return 0.0;
+    }
+    if (depth <= 0.0) {
+        return effects.depth_of_field.z;
+    }
+    let coc = effects.depth_of_field.y * abs(depth - effects.depth_of_field.x) / depth;
+    return min(coc, 1.0) * effects.depth_of_field.z;
+}
+
+// Outside the camera's viewport the scene is passed through
+[[stage(fragment)]]
+fn apply(in: EffectOutput) -> [[location(0)]] vec4<f32> {
+    let uv = in.tex_coord;
+    let scene_color = textureSampleLevel(scene, effect_sampler, uv, 0.0);
+    let low = effects.rect.xy;
+    let high = effects.rect.xy + effects.rect.zw;
+    if (uv.x < low.x || uv.y < low.y || uv.x > high.x || uv.y > high.y) {
+        return scene_color;
+    }
+
+    var color: vec3<f32> = scene_color.rgb * occlusion(uv) + reflection(uv);
+    let size = blur_size(linear_depth(uv));
+    if (size >= 1.0) {
+        var total: vec3<f32> = color;
+        var weight: f32 = 1.0;
+        var i: i32 = 0;
+        loop {
+            if (i >= 16) {
+                break;
+            }
+            let t = (f32(i) + 0.5) / 16.0;
+            let phi = f32(i) * 2.3999632;
+            let offset = vec2<f32>(cos(phi), sin(phi)) * sqrt(t) * size;
+            let tap = clamp(uv + offset * effects.screen.zw, low, high);
+            // Sharp things in front aren't smeared over what is out of focus behind them
+            let tap_weight = clamp(blur_size(linear_depth(tap)) - length(offset) + 1.0, 0.0, 1.0);
+            let tap_color = textureSampleLevel(scene, effect_sampler, tap, 0.0).rgb * raw_occlusion(tap) + reflection(tap);
+            total = total + tap_color * tap_weight;
+            weight = weight + tap_weight;
+            continuing {
+                i = i + 1;
+            }
+        }
+        color = total / weight;
+    }
+
+    return vec4<f32>(color, scene_color.a);
+}
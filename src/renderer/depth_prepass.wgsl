@@ -0,0 +1,110 @@
+struct VertexInput {
+    [[location(0)]] pos: vec3<f32>;
+    [[location(1)]] tex_coord: vec2<f32>;
+    [[location(2)]] normal: vec3<f32>;
+};
+
+struct VertexOutput {
+    [[location(0)]] normal: vec3<f32>;
+    [[location(1)]] view_pos: vec3<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+struct FragmentOutput {
+    [[location(0)]] normal: vec4<f32>;
+    [[location(1)]] depth: vec4<f32>;
+    [[location(2)]] surface: vec4<f32>;
+};
+
+[[block]]
+struct ObjectUniforms {
+    model_view_projection: mat4x4<f32>;
+    model: mat4x4<f32>;
+    normal: mat4x4<f32>;
+    lod_fade: vec4<f32>;
+    // Roughness and metallic factors
+    surface: vec4<f32>;
+};
+
+[[block]]
+struct ViewUniforms {
+    view: mat4x4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> object: ObjectUniforms;
+
+[[group(1), binding(0)]]
+var<uniform> camera: ViewUniforms;
+
+struct SkinInput {
+    [[location(3)]] joints: vec4<u32>;
+    [[location(4)]] weights: vec4<f32>;
+};
+
+[[block]]
+struct JointUniforms {
+    matrices: array<mat4x4<f32>, 128>;
+};
+
+[[group(2), binding(0)]]
+var<uniform> joints: JointUniforms;
+
+struct InstanceInput {
+    [[location(5)]] model_0: vec4<f32>;
+    [[location(6)]] model_1: vec4<f32>;
+    [[location(7)]] model_2: vec4<f32>;
+    [[location(8)]] model_3: vec4<f32>;
+    [[location(9)]] normal_0: vec4<f32>;
+    [[location(10)]] normal_1: vec4<f32>;
+    [[location(11)]] normal_2: vec4<f32>;
+    [[location(12)]] normal_3: vec4<f32>;
+};
+
+fn output(pos: vec4<f32>, normal: vec4<f32>, model: mat4x4<f32>, normal_matrix: mat4x4<f32>) -> VertexOutput {
+    var out: VertexOutput;
+    out.normal = (camera.view * normal_matrix * normal).xyz;
+    out.view_pos = (camera.view * model * pos).xyz;
+    out.pos = object.model_view_projection * pos;
+    return out;
+}
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> VertexOutput {
+    return output(vec4<f32>(in.pos, 1.0), vec4<f32>(in.normal, 0.0), object.model, object.normal);
+}
+
+[[stage(vertex)]]
+fn skinned(in: VertexInput, skin: SkinInput) -> VertexOutput {
+    let pos = vec4<f32>(in.pos, 1.0);
+    let skinned = joints.matrices[skin.joints.x] * pos * skin.weights.x
+        + joints.matrices[skin.joints.y] * pos * skin.weights.y
+        + joints.matrices[skin.joints.z] * pos * skin.weights.z
+        + joints.matrices[skin.joints.w] * pos * skin.weights.w;
+    let normal = vec4<f32>(in.normal, 0.0);
+    let skinned_normal = joints.matrices[skin.joints.x] * normal * skin.weights.x
+        + joints.matrices[skin.joints.y] * normal * skin.weights.y
+        + joints.matrices[skin.joints.z] * normal * skin.weights.z
+        + joints.matrices[skin.joints.w] * normal * skin.weights.w;
+    return output(skinned, skinned_normal, object.model, object.normal);
+}
+
+[[stage(vertex)]]
+fn instanced(in: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    let normal = mat4x4<f32>(instance.normal_0, instance.normal_1, instance.normal_2, instance.normal_3);
+    let pos = vec4<f32>(in.pos, 1.0);
+    var out: VertexOutput = output(pos, vec4<f32>(in.normal, 0.0), object.model * model, object.normal * normal);
+    out.pos = object.model_view_projection * model * pos;
+    return out;
+}
+
+// View space normals, the distance in front of the camera and the surface, zero where nothing was drawn
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> FragmentOutput {
+    var out: FragmentOutput;
+    out.normal = vec4<f32>(normalize(in.normal), 1.0);
+    out.depth = vec4<f32>(-in.view_pos.z, 0.0, 0.0, 0.0);
+    out.surface = vec4<f32>(object.surface.xy, 0.0, 0.0);
+    return out;
+}
@@ -0,0 +1,55 @@
+// Copyright 2021 Chay Nabors.
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+
+// How fog thickens with distance from the camera
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FogFalloff {
+    // Nothing is hidden before the start and everything is past the end
+    Linear { start: f32, end: f32 },
+    // What is left of the scene halves every ln(2) / density world units
+    Exponential { density: f32 },
+}
+
+// Fades everything drawn with a material, terrain, water and the skybox into a color. Without a skybox the clear color
+// should match it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fog {
+    pub color: [f32; 3],
+    pub falloff: FogFalloff,
+    // Fog is as thick as the falloff says at and below this height
+    pub height: f32,
+    // How quickly it thins out above the height, zero keeps it the same at every height
+    pub height_falloff: f32,
+}
+
+impl Default for Fog {
+    fn default() -> Fog {
+        Fog { color: [0.6, 0.65, 0.7], falloff: FogFalloff::Exponential { density: 0.02 }, height: 0., height_falloff: 0. }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub(crate) struct FogUniforms {
+    // The falloff is in w, zero for no fog, one for linear and two for exponential
+    color: [f32; 4],
+    // The start, the end or density, the height and the height falloff
+    parameters: [f32; 4],
+}
+
+impl FogUniforms {
+    pub(crate) fn new(fog: Option<Fog>) -> FogUniforms {
+        let fog = match fog {
+            Some(fog) => fog,
+            None => return FogUniforms::zeroed(),
+        };
+        let [r, g, b] = fog.color;
+        let (falloff, start, end) = match fog.falloff {
+            FogFalloff::Linear { start, end } => (1., start, end),
+            FogFalloff::Exponential { density } => (2., 0., density),
+        };
+        FogUniforms { color: [r, g, b, falloff], parameters: [start, end, fog.height, fog.height_falloff.max(0.)] }
+    }
+}
@@ -0,0 +1,41 @@
+// Shared by the mesh declarations and the skybox, see FogUniforms
+
+struct Fog {
+    // The falloff is in w, zero for no fog, one for linear and two for exponential
+    color: vec4<f32>;
+    // The start, the end or density, the height and the height falloff
+    parameters: vec4<f32>;
+};
+
+// How much of the fog covers a point seen from the eye, from zero to one
+fn fog_amount(fog: Fog, eye: vec3<f32>, world_pos: vec3<f32>) -> f32 {
+    if (fog.color.w < 0.5) {
+        return 0.0;
+    }
+
+    // The density averaged along the way, it falls off exponentially above the fog's height
+    var thickness: f32 = 1.0;
+    let falloff = fog.parameters.w;
+    if (falloff > 0.0) {
+        let above = falloff * (eye.y - fog.parameters.z);
+        let rise = falloff * (world_pos.y - eye.y);
+        thickness = exp(-above);
+        if (abs(rise) > 0.0001) {
+            thickness = thickness * (1.0 - exp(-rise)) / rise;
+        }
+        thickness = min(thickness, 1.0);
+    }
+
+    let depth = length(world_pos - eye) * thickness;
+    if (fog.color.w < 1.5) {
+        return clamp((depth - fog.parameters.x) / max(fog.parameters.y - fog.parameters.x, 0.0001), 0.0, 1.0);
+    }
+    return 1.0 - exp(-fog.parameters.y * depth);
+}
+
+// The sky is treated as lying at the end of linear fog or far into exponential fog, so only a height falloff lets it
+// show through above the horizon
+fn sky_fog_amount(fog: Fog, eye: vec3<f32>, direction: vec3<f32>) -> f32 {
+    let far = select(10000.0, fog.parameters.y, fog.color.w < 1.5);
+    return fog_amount(fog, eye, eye + normalize(direction) * far);
+}
@@ -0,0 +1,40 @@
+// Prepended to fullscreen shaders, which only provide the fragment stage
+
+struct FullscreenOutput {
+    // From the top left of the viewport
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct FrameUniforms {
+    resolution: vec2<f32>;
+    // Seconds since the renderer was created
+    time: f32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> frame: FrameUniforms;
+
+// The inputs of a render graph pass, unused ones are white
+[[group(1), binding(0)]]
+var input_0: texture_2d<f32>;
+[[group(1), binding(1)]]
+var input_1: texture_2d<f32>;
+[[group(1), binding(2)]]
+var input_2: texture_2d<f32>;
+[[group(1), binding(3)]]
+var input_3: texture_2d<f32>;
+[[group(1), binding(4)]]
+var input_sampler: sampler;
+
+// One triangle covering the whole viewport
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: FullscreenOutput;
+    out.tex_coord = vec2<f32>(uv.x, 1.0 - uv.y);
+    out.pos = vec4<f32>(uv * 2.0 - vec2<f32>(1.0, 1.0), 0.0, 1.0);
+    return out;
+}
+
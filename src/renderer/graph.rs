@@ -0,0 +1,400 @@
+// Copyright 2021 Chay Nabors.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use wgpu::BindGroup;
+use wgpu::Device;
+use wgpu::Extent3d;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+
+use super::shader::ShaderRegistry;
+use super::ShaderId;
+use crate::GearError;
+use crate::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AttachmentId(u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PassId(u32);
+
+// Color attachments have the format every pipeline renders to, so any pass can draw into them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachmentFormat {
+    Color,
+    Depth,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AttachmentDescriptor {
+    pub format: AttachmentFormat,
+    // Relative to the window, attachments are recreated when it is resized
+    pub scale: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColorTarget {
+    Screen,
+    Attachment(AttachmentId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PassKind {
+    // Everything drawn this frame
+    Scene,
+    // A fullscreen shader with the pass inputs bound to input_0 through input_3
+    Fullscreen(ShaderId),
+    // The compute dispatches of the frame, run where it is scheduled for the first viewport. It draws nothing, so its
+    // color target is ignored and it is ordered only by depends_on and the order it was added in
+    Compute,
+}
+
+// The first pass to write an attachment in a frame clears it, later ones draw over what is there
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassDescriptor {
+    pub kind: PassKind,
+    pub color: ColorTarget,
+    // Scene passes need one, fullscreen and compute passes can't have one
+    pub depth: Option<AttachmentId>,
+    // Color attachments sampled by the pass, up to four
+    pub inputs: Vec<AttachmentId>,
+    pub depends_on: Vec<PassId>,
+}
+
+impl PassDescriptor {
+    pub fn new(kind: PassKind, color: ColorTarget) -> PassDescriptor {
+        PassDescriptor { kind, color, depth: None, inputs: vec![], depends_on: vec![] }
+    }
+}
+
+// Passes are run in an order that has every attachment written before the passes reading it, the order they were
+// added in breaks ties. Transitions between writing and sampling an attachment are handled by wgpu
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderGraph {
+    attachments: Vec<AttachmentDescriptor>,
+    passes: Vec<PassDescriptor>,
+}
+
+impl Default for RenderGraph {
+    fn default() -> RenderGraph {
+        let mut graph = RenderGraph::new();
+        let depth = graph.add_attachment(AttachmentDescriptor { format: AttachmentFormat::Depth, scale: 1. });
+        graph.add_pass(PassDescriptor { depth: Some(depth), ..PassDescriptor::new(PassKind::Scene, ColorTarget::Screen) });
+        graph
+    }
+}
+
+impl RenderGraph {
+    pub fn new() -> RenderGraph {
+        RenderGraph { attachments: vec![], passes: vec![] }
+    }
+
+    pub fn add_attachment(&mut self, attachment: AttachmentDescriptor) -> AttachmentId {
+        self.attachments.push(attachment);
+        AttachmentId(self.attachments.len() as u32 - 1)
+    }
+
+    pub fn add_pass(&mut self, pass: PassDescriptor) -> PassId {
+        self.passes.push(pass);
+        PassId(self.passes.len() as u32 - 1)
+    }
+
+    pub fn attachments(&self) -> &[AttachmentDescriptor] {
+        &self.attachments
+    }
+
+    pub fn passes(&self) -> &[PassDescriptor] {
+        &self.passes
+    }
+
+    fn attachment(&self, id: AttachmentId) -> Result<&AttachmentDescriptor> {
+        self.attachments.get(id.0 as usize).ok_or(GearError::RenderGraphError(format!("unknown attachment {:?}", id)))
+    }
+
+    // Of the window, which the screen is as big as
+    fn scale(&self, target: ColorTarget) -> f32 {
+        match target {
+            ColorTarget::Screen => 1.,
+            ColorTarget::Attachment(attachment) => self.attachments[attachment.0 as usize].scale,
+        }
+    }
+
+    // The screen counts as a target like any attachment
+    fn targets(pass: &PassDescriptor) -> Vec<ColorTarget> {
+        if pass.kind == PassKind::Compute {
+            return vec![];
+        }
+        let mut targets = vec![pass.color];
+        targets.extend(pass.depth.map(ColorTarget::Attachment));
+        targets
+    }
+
+    fn validate(&self, shaders: &ShaderRegistry) -> Result<()> {
+        let error = |message: String| Err(GearError::RenderGraphError(message));
+        if !self.passes.iter().any(|pass| pass.kind != PassKind::Compute && pass.color == ColorTarget::Screen) {
+            return error("no pass draws to the screen".to_owned());
+        }
+
+        for (i, pass) in self.passes.iter().enumerate() {
+            let mut scale = 1.;
+            if let (false, ColorTarget::Attachment(color)) = (pass.kind == PassKind::Compute, pass.color) {
+                let attachment = self.attachment(color)?;
+                if attachment.format != AttachmentFormat::Color {
+                    return error(format!("pass {} draws color to a depth attachment", i));
+                }
+                scale = attachment.scale;
+            }
+            if let Some(depth) = pass.depth {
+                let attachment = self.attachment(depth)?;
+                if attachment.format != AttachmentFormat::Depth {
+                    return error(format!("pass {} uses a color attachment for depth", i));
+                }
+                if attachment.scale != scale {
+                    return error(format!("pass {} has color and depth attachments of different sizes", i));
+                }
+            }
+
+            if pass.inputs.len() > 4 {
+                return error(format!("pass {} has more than four inputs", i));
+            }
+            let targets = RenderGraph::targets(pass);
+            for input in &pass.inputs {
+                if self.attachment(*input)?.format != AttachmentFormat::Color {
+                    return error(format!("pass {} samples a depth attachment", i));
+                }
+                if targets.contains(&ColorTarget::Attachment(*input)) {
+                    return error(format!("pass {} samples an attachment it draws to", i));
+                }
+            }
+            if pass.depends_on.iter().any(|dependency| dependency.0 as usize >= self.passes.len()) {
+                return error(format!("pass {} depends on an unknown pass", i));
+            }
+
+            match pass.kind {
+                PassKind::Scene if pass.depth.is_none() => return error(format!("scene pass {} has no depth", i)),
+                PassKind::Fullscreen(_) if pass.depth.is_some() => {
+                    return error(format!("fullscreen pass {} has a depth attachment", i));
+                },
+                PassKind::Fullscreen(shader) if !shaders.is_fullscreen(shader) => {
+                    return error(format!("pass {} doesn't use a fullscreen shader", i));
+                },
+                PassKind::Compute if pass.depth.is_some() || !pass.inputs.is_empty() => {
+                    return error(format!("compute pass {} has attachments", i));
+                },
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Readers of an attachment wait on every pass writing it, writers of the same target keep the order they were added
+    fn schedule(&self) -> Result<Vec<usize>> {
+        let mut dependencies: Vec<HashSet<usize>> = vec![HashSet::new(); self.passes.len()];
+        for (i, pass) in self.passes.iter().enumerate() {
+            dependencies[i].extend(pass.depends_on.iter().map(|dependency| dependency.0 as usize));
+            let targets = RenderGraph::targets(pass);
+            for (j, other) in self.passes.iter().enumerate().filter(|(j, _)| *j != i) {
+                let other_targets = RenderGraph::targets(other);
+                let reads = pass.inputs.iter().any(|input| other_targets.contains(&ColorTarget::Attachment(*input)));
+                let shares_target = j < i && targets.iter().any(|target| other_targets.contains(target));
+                if reads || shares_target {
+                    dependencies[i].insert(j);
+                }
+            }
+        }
+
+        let mut order = vec![];
+        let mut scheduled = vec![false; self.passes.len()];
+        while order.len() < self.passes.len() {
+            let next = (0..self.passes.len())
+                .find(|i| !scheduled[*i] && dependencies[*i].iter().all(|dependency| scheduled[*dependency]));
+            match next {
+                Some(next) => {
+                    scheduled[next] = true;
+                    order.push(next);
+                },
+                None => return Err(GearError::RenderGraphError("the passes depend on each other in a cycle".to_owned())),
+            }
+        }
+
+        Ok(order)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ScheduledPass {
+    pub(crate) pass: usize,
+    pub(crate) clear_color: bool,
+    pub(crate) clear_depth: bool,
+    pub(crate) inputs: Option<BindGroup>,
+}
+
+#[derive(Debug)]
+struct Attachment {
+    _texture: wgpu::Texture,
+    view: TextureView,
+}
+
+impl Attachment {
+    fn new(device: &Device, size: [u32; 2], scale: f32, format: TextureFormat, sample_count: u32) -> Attachment {
+        let width = ((size[0] as f32 * scale) as u32).max(1);
+        let height = ((size[1] as f32 * scale) as u32).max(1);
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("attachment"),
+            size: Extent3d { width, height, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        Attachment { _texture: texture, view }
+    }
+}
+
+// The graph's attachments on the gpu and the order to run its passes in
+#[derive(Debug)]
+pub(crate) struct CompiledGraph {
+    graph: RenderGraph,
+    color_format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+    attachments: Vec<Attachment>,
+    // Color targets of scene passes while multisampling, resolved into the real target at the end of each pass
+    multisampled: HashMap<ColorTarget, Attachment>,
+    order: Vec<ScheduledPass>,
+}
+
+impl CompiledGraph {
+    pub(crate) fn new(
+        device: &Device,
+        graph: RenderGraph,
+        size: [u32; 2],
+        color_format: TextureFormat,
+        depth_format: TextureFormat,
+        sample_count: u32,
+        shaders: &ShaderRegistry,
+    ) -> Result<CompiledGraph> {
+        graph.validate(shaders)?;
+        let mut written = HashSet::new();
+        let order = graph
+            .schedule()?
+            .into_iter()
+            .map(|pass| {
+                let descriptor = &graph.passes[pass];
+                let clear_color = descriptor.kind != PassKind::Compute && written.insert(descriptor.color);
+                let clear_depth = descriptor.depth.map_or(false, |depth| written.insert(ColorTarget::Attachment(depth)));
+                ScheduledPass { pass, clear_color, clear_depth, inputs: None }
+            })
+            .collect();
+
+        let mut compiled = CompiledGraph {
+            graph,
+            color_format,
+            depth_format,
+            sample_count,
+            attachments: vec![],
+            multisampled: HashMap::new(),
+            order,
+        };
+        compiled.resize(device, size, shaders);
+        Ok(compiled)
+    }
+
+    pub(crate) fn resize(&mut self, device: &Device, size: [u32; 2], shaders: &ShaderRegistry) {
+        // Depth is only ever attached to scene passes, so it is multisampled along with them
+        let color_format = self.color_format;
+        let depth_format = self.depth_format;
+        let sample_count = self.sample_count;
+        self.attachments = self
+            .graph
+            .attachments
+            .iter()
+            .map(|attachment| match attachment.format {
+                AttachmentFormat::Color => Attachment::new(device, size, attachment.scale, color_format, 1),
+                AttachmentFormat::Depth => Attachment::new(device, size, attachment.scale, depth_format, sample_count),
+            })
+            .collect();
+
+        self.multisampled.clear();
+        if sample_count > 1 {
+            for pass in self.graph.passes.iter().filter(|pass| pass.kind == PassKind::Scene) {
+                let scale = self.graph.scale(pass.color);
+                if !self.multisampled.contains_key(&pass.color) {
+                    let attachment = Attachment::new(device, size, scale, color_format, sample_count);
+                    self.multisampled.insert(pass.color, attachment);
+                }
+            }
+        }
+
+        for scheduled in &mut self.order {
+            let inputs = &self.graph.passes[scheduled.pass].inputs;
+            scheduled.inputs = match inputs.is_empty() {
+                true => None,
+                false => {
+                    let views: Vec<&TextureView> =
+                        inputs.iter().map(|input| &self.attachments[input.0 as usize].view).collect();
+                    Some(shaders.create_input_bind_group(device, &views))
+                },
+            };
+        }
+    }
+
+    pub(crate) fn set_sample_count(
+        &mut self,
+        device: &Device,
+        size: [u32; 2],
+        sample_count: u32,
+        shaders: &ShaderRegistry,
+    ) {
+        self.sample_count = sample_count;
+        self.resize(device, size, shaders);
+    }
+
+    // The view a pass draws to and the view to resolve into. Fullscreen passes drawing to a target between two scene
+    // passes are not seen by the second one while multisampling, it loads the multisampled copy
+    pub(crate) fn color_target<'a>(
+        &'a self,
+        pass: &PassDescriptor,
+        screen: &'a TextureView,
+    ) -> (&'a TextureView, Option<&'a TextureView>) {
+        let view = match pass.color {
+            ColorTarget::Screen => screen,
+            ColorTarget::Attachment(attachment) => self.view(attachment),
+        };
+        match self.multisampled.get(&pass.color) {
+            Some(multisampled) if pass.kind == PassKind::Scene => (&multisampled.view, Some(view)),
+            _ => (view, None),
+        }
+    }
+
+    // The size of a pass's color target in a window this size
+    pub(crate) fn target_size(&self, pass: &PassDescriptor, size: [u32; 2]) -> [u32; 2] {
+        let scale = self.graph.scale(pass.color);
+        [((size[0] as f32 * scale) as u32).max(1), ((size[1] as f32 * scale) as u32).max(1)]
+    }
+
+    pub(crate) fn order(&self) -> &[ScheduledPass] {
+        &self.order
+    }
+
+    pub(crate) fn pass(&self, scheduled: &ScheduledPass) -> &PassDescriptor {
+        &self.graph.passes[scheduled.pass]
+    }
+
+    pub(crate) fn view(&self, attachment: AttachmentId) -> &TextureView {
+        &self.attachments[attachment.0 as usize].view
+    }
+
+    pub(crate) fn graph(&self) -> &RenderGraph {
+        &self.graph
+    }
+}
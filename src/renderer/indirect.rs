@@ -0,0 +1,329 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Matrix4;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferAddress;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferSlice;
+use wgpu::BufferUsage;
+use wgpu::CommandEncoder;
+use wgpu::ComputePassDescriptor;
+use wgpu::ComputePipeline;
+use wgpu::ComputePipelineDescriptor;
+use wgpu::Device;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::Queue;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+
+use super::instance::InstanceVertex;
+use super::mesh::MeshInput;
+use super::GpuMesh;
+use super::InstanceData;
+use super::MeshLod;
+use crate::Frustum;
+
+// Levels of a lod past this are left out
+const MAX_LEVELS: usize = 8;
+const WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct CullUniforms {
+    view_projection: [[f32; 4]; 4],
+    planes: [[f32; 4]; 6],
+    sphere: [f32; 4],
+    thresholds: [[f32; 4]; 2],
+    counts: [u32; 4],
+    projection: [f32; 4],
+}
+
+// What the gpu reads an indexed indirect draw from
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct DrawArguments {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+// Instances that stay on the gpu for Renderer::draw_indirect. Each frame a compute pass culls them against the camera
+// and sorts the rest into the levels of the lod, which are drawn without the cpu going over a single instance. Clones
+// share the same instances
+#[derive(Clone, Debug)]
+pub struct IndirectBatch {
+    inner: Arc<IndirectData>,
+}
+
+impl IndirectBatch {
+    pub fn lod(&self) -> &MeshLod {
+        &self.inner.lod
+    }
+
+    pub fn count(&self) -> u32 {
+        self.inner.count
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct IndirectData {
+    lod: MeshLod,
+    count: u32,
+    uniform_buffer: Buffer,
+    instance_buffer: Buffer,
+    // Room for every instance in each level, one level after the other
+    visible_buffer: Buffer,
+    argument_buffer: Buffer,
+    bind_group: BindGroup,
+}
+
+// One level of a batch, what a mesh draws instead of a range of the instance buffer
+#[derive(Clone, Debug)]
+pub(crate) struct IndirectDraw {
+    batch: Arc<IndirectData>,
+    level: u32,
+}
+
+impl IndirectDraw {
+    pub(crate) fn instances(&self) -> BufferSlice {
+        let start = (self.level * self.batch.count) as BufferAddress * size_of::<InstanceVertex>() as BufferAddress;
+        self.batch.visible_buffer.slice(start..)
+    }
+
+    pub(crate) fn arguments(&self) -> (&Buffer, BufferAddress) {
+        (&self.batch.argument_buffer, self.level as BufferAddress * size_of::<DrawArguments>() as BufferAddress)
+    }
+}
+
+impl PartialEq for IndirectDraw {
+    fn eq(&self, other: &IndirectDraw) -> bool {
+        Arc::ptr_eq(&self.batch, &other.batch) && self.level == other.level
+    }
+}
+
+impl Eq for IndirectDraw {}
+
+#[derive(Debug)]
+pub(crate) struct IndirectCuller {
+    layout: BindGroupLayout,
+    pipeline: ComputePipeline,
+    batches: Vec<Arc<IndirectData>>,
+}
+
+impl IndirectCuller {
+    pub(crate) fn new(device: &Device) -> IndirectCuller {
+        let storage = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("indirect_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<CullUniforms>() as _),
+                    },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, false),
+                storage(3, false),
+            ],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("indirect_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("indirect.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("indirect_pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("indirect_pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader_module,
+            entry_point: "main",
+        });
+
+        IndirectCuller { layout, pipeline, batches: vec![] }
+    }
+
+    pub(crate) fn create(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        lod: &MeshLod,
+        instances: &[InstanceData],
+    ) -> IndirectBatch {
+        let mut lod = lod.clone();
+        lod.truncate(MAX_LEVELS);
+        let count = instances.len() as u32;
+        let instance_size = size_of::<InstanceVertex>() as BufferAddress;
+        let buffer = |label, size: BufferAddress, usage| {
+            device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size: size.max(4),
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+
+        let uniform_buffer =
+            buffer("indirect_uniform_buffer", size_of::<CullUniforms>() as _, BufferUsage::UNIFORM | BufferUsage::COPY_DST);
+        let instance_buffer = buffer(
+            "indirect_instance_buffer",
+            count as BufferAddress * instance_size,
+            BufferUsage::STORAGE | BufferUsage::COPY_DST,
+        );
+        let levels = lod.level_count().max(1) as BufferAddress;
+        let visible_buffer = buffer(
+            "indirect_visible_buffer",
+            levels * count as BufferAddress * instance_size,
+            BufferUsage::STORAGE | BufferUsage::VERTEX,
+        );
+        let argument_buffer = buffer(
+            "indirect_argument_buffer",
+            levels * size_of::<DrawArguments>() as BufferAddress,
+            BufferUsage::STORAGE | BufferUsage::INDIRECT | BufferUsage::COPY_DST,
+        );
+
+        let vertices = instances.iter().map(InstanceData::vertex).collect::<Vec<_>>();
+        queue.write_buffer(&instance_buffer, 0, bytemuck::cast_slice(&vertices));
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("indirect_bind_group"),
+            layout: &self.layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() },
+                BindGroupEntry { binding: 1, resource: instance_buffer.as_entire_binding() },
+                BindGroupEntry { binding: 2, resource: visible_buffer.as_entire_binding() },
+                BindGroupEntry { binding: 3, resource: argument_buffer.as_entire_binding() },
+            ],
+        });
+
+        let inner =
+            IndirectData { lod, count, uniform_buffer, instance_buffer, visible_buffer, argument_buffer, bind_group };
+        IndirectBatch { inner: Arc::new(inner) }
+    }
+
+    // Instances past the end of the batch are left out
+    pub(crate) fn write(&self, queue: &Queue, batch: &IndirectBatch, first: u32, instances: &[InstanceData]) {
+        let count = instances.len().min(batch.inner.count.saturating_sub(first) as usize);
+        if count == 0 {
+            return;
+        }
+        let vertices = instances[..count].iter().map(InstanceData::vertex).collect::<Vec<_>>();
+        let offset = first as BufferAddress * size_of::<InstanceVertex>() as BufferAddress;
+        queue.write_buffer(&batch.inner.instance_buffer, offset, bytemuck::cast_slice(&vertices));
+    }
+
+    // The levels to draw with what each of them feeds its mesh, none when the batch was already pushed this frame
+    pub(crate) fn push(
+        &mut self,
+        queue: &Queue,
+        batch: &IndirectBatch,
+        view_projection: Matrix4<f32>,
+        projection: &Matrix4<f32>,
+        culling: bool,
+    ) -> Vec<(GpuMesh, MeshInput)> {
+        let data = &batch.inner;
+        if data.count == 0 || self.batches.iter().any(|pushed| Arc::ptr_eq(pushed, data)) {
+            return vec![];
+        }
+
+        // Without bounds the sphere covers everything, like MeshLod's coverage
+        let bounds = data.lod.level(0).and_then(|mesh| mesh.bounds());
+        let sphere = bounds.map_or([0., 0., 0., f32::INFINITY], |bounds| {
+            let center = bounds.center();
+            [center.x, center.y, center.z, (bounds.max - bounds.min).norm() / 2.]
+        });
+        let mut planes = [[0., 0., 0., 1.]; 6];
+        if culling {
+            let frustum = Frustum::from_view_projection(&view_projection);
+            for (plane, frustum_plane) in planes.iter_mut().zip(frustum.planes().iter()) {
+                let length = frustum_plane.xyz().norm().max(f32::EPSILON);
+                *plane = (frustum_plane / length).into();
+            }
+        }
+        let mut thresholds = [[0.; 4]; 2];
+        for (level, threshold) in data.lod.thresholds().enumerate() {
+            thresholds[level / 4][level % 4] = threshold;
+        }
+        let levels = data.lod.level_count() as u32;
+        let uniforms = CullUniforms {
+            view_projection: view_projection.into(),
+            planes,
+            sphere,
+            thresholds,
+            counts: [data.count, levels, data.count, 0],
+            projection: [projection[(1, 1)].abs(), 0., 0., 0.],
+        };
+        queue.write_buffer(&data.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+
+        // The counts start from nothing every frame and are filled in by the culling pass
+        let arguments = (0..levels)
+            .map(|level| DrawArguments {
+                index_count: data.lod.level(level as usize).map_or(0, |mesh| mesh.index_count()),
+                ..Zeroable::zeroed()
+            })
+            .collect::<Vec<_>>();
+        queue.write_buffer(&data.argument_buffer, 0, bytemuck::cast_slice(&arguments));
+
+        self.batches.push(data.clone());
+        (0..levels)
+            .filter_map(|level| {
+                let mesh = data.lod.level(level as usize)?.clone();
+                Some((mesh, MeshInput::Indirect(IndirectDraw { batch: data.clone(), level })))
+            })
+            .collect()
+    }
+
+    // Before anything draws the batches
+    pub(crate) fn cull(&self, encoder: &mut CommandEncoder) {
+        if self.batches.is_empty() {
+            return;
+        }
+
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor { label: Some("indirect_pass") });
+        compute_pass.set_pipeline(&self.pipeline);
+        for batch in &self.batches {
+            compute_pass.set_bind_group(0, &batch.bind_group, &[]);
+            compute_pass.dispatch((batch.count + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1, 1);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.batches.clear();
+    }
+}
@@ -0,0 +1,102 @@
+// One invocation per instance of a batch, the ones the camera sees are written into the part of the visible instances
+// for their level of detail and counted into that level's draw
+
+[[block]]
+struct CullUniforms {
+    view_projection: mat4x4<f32>;
+    // Facing inward with normalized normals, so the distance to them can be measured
+    planes: array<vec4<f32>, 6>;
+    // Around the first level relative to the model, the radius is in w
+    sphere: vec4<f32>;
+    // The coverage each level is drawn from, four to a vector
+    thresholds: array<vec4<f32>, 2>;
+    // Instances, levels and how many instances fit in each level
+    counts: vec4<u32>;
+    // The vertical scale of the projection in x
+    projection: vec4<f32>;
+};
+
+struct Instance {
+    model: mat4x4<f32>;
+    normal: mat4x4<f32>;
+    color: vec4<f32>;
+    layer: vec4<f32>;
+};
+
+[[block]]
+struct Instances {
+    instances: array<Instance>;
+};
+
+// Laid out like the arguments of an indexed indirect draw
+struct DrawArguments {
+    index_count: u32;
+    instance_count: atomic<u32>;
+    first_index: u32;
+    base_vertex: i32;
+    first_instance: u32;
+};
+
+[[block]]
+struct Draws {
+    draws: array<DrawArguments>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> batch: CullUniforms;
+[[group(0), binding(1)]]
+var<storage> instances: [[access(read)]] Instances;
+[[group(0), binding(2)]]
+var<storage> visible: [[access(read_write)]] Instances;
+[[group(0), binding(3)]]
+var<storage> draws: [[access(read_write)]] Draws;
+
+[[stage(compute), workgroup_size(64)]]
+fn main([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let index = id.x;
+    if (index >= batch.counts.x) {
+        return;
+    }
+
+    let instance = instances.instances[index];
+    let center = instance.model * vec4<f32>(batch.sphere.xyz, 1.0);
+    // The longest axis of the model stretches the sphere the most
+    let axes = vec3<f32>(length(instance.model[0].xyz), length(instance.model[1].xyz), length(instance.model[2].xyz));
+    let radius = batch.sphere.w * max(max(axes.x, axes.y), axes.z);
+
+    var i: u32 = 0u;
+    loop {
+        if (i >= 6u) {
+            break;
+        }
+        if (dot(batch.planes[i], center) < -radius) {
+            return;
+        }
+        continuing {
+            i = i + 1u;
+        }
+    }
+
+    // How much of the height of the screen the sphere covers like MeshLod's coverage, past the near plane it all is
+    let w = (batch.view_projection * center).w;
+    var coverage: f32 = 1000000.0;
+    if (w > 0.0) {
+        coverage = radius * batch.projection.x / w;
+    }
+
+    var level: u32 = 0u;
+    loop {
+        if (level >= batch.counts.y) {
+            return;
+        }
+        if (coverage >= batch.thresholds[level / 4u][level % 4u]) {
+            break;
+        }
+        continuing {
+            level = level + 1u;
+        }
+    }
+
+    let slot = atomicAdd(&draws.draws[level].instance_count, 1u);
+    visible.instances[level * batch.counts.z + slot] = instance;
+}
@@ -0,0 +1,94 @@
+// Copyright 2021 Chay Nabors.
+
+use std::mem::size_of;
+use std::ops::Range;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Matrix4;
+use wgpu::Buffer;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Device;
+use wgpu::Queue;
+
+// Instances past this in one frame are dropped
+const MAX_INSTANCES: usize = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceData {
+    pub transform: Matrix4<f32>,
+    // Multiplies the albedo of the material
+    pub color: [f32; 4],
+    // Of the material's albedo layers, when it has them
+    pub layer: u32,
+}
+
+impl Default for InstanceData {
+    fn default() -> InstanceData {
+        InstanceData { transform: Matrix4::identity(), color: [1., 1., 1., 1.], layer: 0 }
+    }
+}
+
+impl InstanceData {
+    pub fn new(transform: Matrix4<f32>) -> InstanceData {
+        InstanceData { transform, ..Default::default() }
+    }
+
+    pub(crate) fn vertex(&self) -> InstanceVertex {
+        let normal = self.transform.try_inverse().unwrap_or_else(Matrix4::identity).transpose();
+        let layer = [self.layer as f32, 0., 0., 0.];
+        InstanceVertex { model: self.transform.into(), normal: normal.into(), color: self.color, layer }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub(crate) struct InstanceVertex {
+    model: [[f32; 4]; 4],
+    normal: [[f32; 4]; 4],
+    color: [f32; 4],
+    // The layer in x
+    layer: [f32; 4],
+}
+
+// Every instanced draw of a frame shares one vertex buffer stepped per instance, each draw gets a range of it
+#[derive(Debug)]
+pub(crate) struct InstanceBuffer {
+    buffer: Buffer,
+    data: Vec<InstanceVertex>,
+}
+
+impl InstanceBuffer {
+    pub(crate) fn new(device: &Device) -> InstanceBuffer {
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("instance_buffer"),
+            size: (MAX_INSTANCES * size_of::<InstanceVertex>()) as u64,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        InstanceBuffer { buffer, data: vec![] }
+    }
+
+    pub(crate) fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    pub(crate) fn push(&mut self, instances: &[InstanceData]) -> Range<u32> {
+        let start = self.data.len() as u32;
+        let count = instances.len().min(MAX_INSTANCES - self.data.len());
+        self.data.extend(instances[..count].iter().map(InstanceData::vertex));
+        start..self.data.len() as u32
+    }
+
+    // Returns the number of writes
+    pub(crate) fn prepare(&self, queue: &Queue) -> usize {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&self.data));
+        1
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.data.clear();
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2021 Chay Nabors.
+
+use nalgebra::Point3;
+use nalgebra::Vector3;
+
+// Light arriving from infinitely far away along the direction, like the sun
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DirectionalLight {
+    pub direction: Vector3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    // None lets the light through everything
+    pub shadow: Option<Shadow>,
+}
+
+impl Default for DirectionalLight {
+    fn default() -> DirectionalLight {
+        DirectionalLight {
+            direction: Vector3::new(-0.3, -1., -0.5),
+            color: [1., 1., 1.],
+            intensity: 3.,
+            shadow: Some(Shadow::default()),
+        }
+    }
+}
+
+// Shadows are cast within a square around the camera, the map has a fixed resolution so a smaller extent is sharper
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shadow {
+    // Half the width of the shadowed square in world units
+    pub extent: f32,
+    // Pulls the depth compared against the shadow map towards the light, raise it if lit surfaces show stripes
+    pub bias: f32,
+    // Pushes surfaces out along their normal in world units, more so the more steeply the light hits them
+    pub normal_bias: f32,
+    // How far apart the filter samples are in shadow map texels, zero gives hard edges
+    pub softness: f32,
+}
+
+impl Default for Shadow {
+    fn default() -> Shadow {
+        Shadow { extent: 20., bias: 0.001, normal_bias: 0.05, softness: 1. }
+    }
+}
+
+// Shines in every direction from a point, fading out completely at the range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PointLight {
+    pub position: Point3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+}
+
+impl Default for PointLight {
+    fn default() -> PointLight {
+        PointLight { position: Point3::origin(), color: [1., 1., 1.], intensity: 10., range: 10. }
+    }
+}
+
+// A point light limited to a cone, full strength within the inner angle and fading out towards the outer one.
+// The angles are in radians from the direction to the edge of the cone
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpotLight {
+    pub position: Point3<f32>,
+    pub direction: Vector3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+impl Default for SpotLight {
+    fn default() -> SpotLight {
+        SpotLight {
+            position: Point3::origin(),
+            direction: Vector3::new(0., -1., 0.),
+            color: [1., 1., 1.],
+            intensity: 10.,
+            range: 10.,
+            inner_angle: 0.4,
+            outer_angle: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Light {
+    Point(PointLight),
+    Spot(SpotLight),
+}
+
+impl From<PointLight> for Light {
+    fn from(light: PointLight) -> Light {
+        Light::Point(light)
+    }
+}
+
+impl From<SpotLight> for Light {
+    fn from(light: SpotLight) -> Light {
+        Light::Spot(light)
+    }
+}
@@ -0,0 +1,741 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+use std::ops::Range;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use image::Rgba;
+use image::RgbaImage;
+use nalgebra::Matrix3;
+use nalgebra::Matrix4;
+use nalgebra::Point2;
+use nalgebra::Vector2;
+use wgpu::vertex_attr_array;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingType;
+use wgpu::BlendComponent;
+use wgpu::BlendFactor;
+use wgpu::BlendOperation;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Color;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CommandEncoder;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::Extent3d;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::IndexFormat;
+use wgpu::InputStepMode;
+use wgpu::LoadOp;
+use wgpu::MultisampleState;
+use wgpu::Operations;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPassColorAttachment;
+use wgpu::RenderPassDepthStencilAttachment;
+use wgpu::RenderPassDescriptor;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+
+use super::stats::FrameStats;
+use crate::Texture;
+use crate::TextureFilter;
+use crate::TextureOptions;
+use crate::TextureWrap;
+
+const MAX_LIT_SPRITES: usize = 1 << 14;
+const MAX_LIGHTS: usize = 1 << 10;
+// No more than the sprite batcher has quad indices for
+const MAX_SHADOW_QUADS: usize = 1 << 16;
+const QUAD_CORNERS: [[f32; 2]; 4] = [[-0.5, -0.5], [0.5, -0.5], [0.5, 0.5], [-0.5, 0.5]];
+const NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+const LIGHTMAP_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const LIGHT_DEPTH_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+
+// Lights only reach what is inside the cone, the angle is in radians from the direction to the edge of the cone
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightCone {
+    pub direction: Vector2<f32>,
+    pub angle: f32,
+}
+
+// Lights lit sprites within the radius in world units, fading out completely at its edge
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light2D {
+    pub position: Point2<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub radius: f32,
+    // How far above the sprites the light is, lower lights make normal maps stand out more
+    pub height: f32,
+    // None shines in every direction
+    pub cone: Option<LightCone>,
+    // Occluders only block lights that cast shadows, each one costs a pass over the lightmap
+    pub shadows: bool,
+}
+
+impl Default for Light2D {
+    fn default() -> Light2D {
+        Light2D {
+            position: Point2::origin(),
+            color: [1., 1., 1.],
+            intensity: 1.,
+            radius: 200.,
+            height: 50.,
+            cone: None,
+            shadows: false,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct LitVertex {
+    position: [f32; 2],
+    tex_coord: [f32; 2],
+    tint: [f32; 4],
+    // The sprite's x and y axes in world space, what turns its normal map with it
+    tangent: [f32; 2],
+    bitangent: [f32; 2],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct LightVertex {
+    world: [f32; 2],
+    light: [f32; 4],
+    color: [f32; 4],
+    direction: [f32; 2],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct LightUniforms {
+    view_projection: [[f32; 4]; 4],
+}
+
+#[derive(Debug)]
+struct QueuedSprite {
+    texture: Texture,
+    normal_map: Option<Texture>,
+    vertices: [LitVertex; 4],
+}
+
+#[derive(Debug)]
+struct Batch {
+    texture: Texture,
+    normal_map: Texture,
+    indices: Range<u32>,
+}
+
+// Sized to the scene, normals of the lit sprites and the light reaching each pixel
+#[derive(Debug)]
+struct LightTargets {
+    normals: Texture,
+    lightmap: Texture,
+    _depth: wgpu::Texture,
+    depth_view: TextureView,
+}
+
+#[derive(Debug)]
+pub(crate) struct Lighting2D {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sprite_module: ShaderModule,
+    sprite_layout: PipelineLayout,
+    normal_pipeline: RenderPipeline,
+    lit_pipeline: RenderPipeline,
+    light_pipeline: RenderPipeline,
+    shadow_pipeline: RenderPipeline,
+    vertex_buffer: Buffer,
+    light_buffer: Buffer,
+    shadow_buffer: Buffer,
+    uniform_buffer: Buffer,
+    uniform_bind_group: BindGroup,
+    flat_normal: Texture,
+    targets: LightTargets,
+    ambient: [f32; 3],
+    sprites: Vec<QueuedSprite>,
+    lights: Vec<Light2D>,
+    occluders: Vec<[Point2<f32>; 2]>,
+    batches: Vec<Batch>,
+    // Index ranges into the light and shadow buffers, lights without shadows share the first pass
+    unshadowed: Range<u32>,
+    shadowed: Vec<(Range<u32>, Range<u32>)>,
+}
+
+impl Lighting2D {
+    pub(crate) fn new(
+        device: &Device,
+        queue: &Queue,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        texture_layout: &BindGroupLayout,
+        size: [u32; 2],
+    ) -> Lighting2D {
+        let vertex_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("lit_sprite_vertex_buffer"),
+            size: (MAX_LIT_SPRITES * 4 * size_of::<LitVertex>()) as u64,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let light_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("light_2d_vertex_buffer"),
+            size: (MAX_LIGHTS * 4 * size_of::<LightVertex>()) as u64,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let shadow_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("light_2d_shadow_buffer"),
+            size: (MAX_SHADOW_QUADS * 4 * size_of::<[f32; 2]>()) as u64,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("light_2d_uniform_buffer"),
+            size: size_of::<LightUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("light_2d_uniform_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<LightUniforms>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let uniform_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("light_2d_uniform_bind_group"),
+            layout: &uniform_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() }],
+        });
+
+        let sprite_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("lit_sprite_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("lit_sprite.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+        let light_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("light_2d_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("light2d.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let sprite_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("lit_sprite_pipeline_layout"),
+            bind_group_layouts: &[&uniform_layout, texture_layout, texture_layout],
+            push_constant_ranges: &[],
+        });
+        let light_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("light_2d_pipeline_layout"),
+            bind_group_layouts: &[&uniform_layout, texture_layout],
+            push_constant_ranges: &[],
+        });
+
+        let normal_target = ColorTargetState { format: NORMAL_FORMAT, blend: None, write_mask: ColorWrite::ALL };
+        let normal_pipeline =
+            create_sprite_pipeline(device, &sprite_layout, &sprite_module, "normals", normal_target, None, 1);
+        let lit_target = ColorTargetState { format, blend: Some(BlendState::ALPHA_BLENDING), write_mask: ColorWrite::ALL };
+        let lit_pipeline =
+            create_sprite_pipeline(device, &sprite_layout, &sprite_module, "lit", lit_target, Some(depth_format), 1);
+        let light_pipeline = create_light_pipeline(device, &light_layout, &light_module, false);
+        let shadow_pipeline = create_light_pipeline(device, &light_layout, &light_module, true);
+
+        let options = TextureOptions { mipmaps: false, ..TextureOptions::linear() };
+        let flat_normal = RgbaImage::from_pixel(1, 1, Rgba([128, 128, 255, 255]));
+        let flat_normal = Texture::from_image(device, queue, texture_layout, flat_normal, &options);
+
+        Lighting2D {
+            format,
+            depth_format,
+            sprite_module,
+            sprite_layout,
+            normal_pipeline,
+            lit_pipeline,
+            light_pipeline,
+            shadow_pipeline,
+            vertex_buffer,
+            light_buffer,
+            shadow_buffer,
+            uniform_buffer,
+            uniform_bind_group,
+            flat_normal,
+            targets: create_targets(device, texture_layout, size),
+            ambient: [0.2, 0.2, 0.2],
+            sprites: vec![],
+            lights: vec![],
+            occluders: vec![],
+            batches: vec![],
+            unshadowed: 0..0,
+            shadowed: vec![],
+        }
+    }
+
+    // Only lit sprites are drawn into the scene's multisampled targets
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        let target =
+            ColorTargetState { format: self.format, blend: Some(BlendState::ALPHA_BLENDING), write_mask: ColorWrite::ALL };
+        self.lit_pipeline = create_sprite_pipeline(
+            device,
+            &self.sprite_layout,
+            &self.sprite_module,
+            "lit",
+            target,
+            Some(self.depth_format),
+            sample_count,
+        );
+    }
+
+    pub(crate) fn resize(&mut self, device: &Device, texture_layout: &BindGroupLayout, size: [u32; 2]) {
+        self.targets = create_targets(device, texture_layout, size);
+    }
+
+    // What every lit sprite gets where no light reaches
+    pub(crate) fn set_ambient(&mut self, ambient: [f32; 3]) {
+        self.ambient = ambient;
+    }
+
+    pub(crate) fn ambient(&self) -> [f32; 3] {
+        self.ambient
+    }
+
+    // The region is in texture coordinates as [left, top, right, bottom], like for unlit sprites
+    pub(crate) fn push_sprite(
+        &mut self,
+        texture: &Texture,
+        normal_map: Option<&Texture>,
+        transform: &Matrix3<f32>,
+        region: [f32; 4],
+        tint: [f32; 4],
+    ) {
+        if self.sprites.len() >= MAX_LIT_SPRITES {
+            return;
+        }
+
+        let tangent = transform.transform_vector(&Vector2::x());
+        let bitangent = transform.transform_vector(&Vector2::y());
+        let tex_coords = [[region[0], region[3]], [region[2], region[3]], [region[2], region[1]], [region[0], region[1]]];
+        let mut vertices = [LitVertex::zeroed(); 4];
+        for (i, vertex) in vertices.iter_mut().enumerate() {
+            let position = transform.transform_point(&Point2::from(QUAD_CORNERS[i]));
+            *vertex = LitVertex {
+                position: [position.x, position.y],
+                tex_coord: tex_coords[i],
+                tint,
+                tangent: [tangent.x, tangent.y],
+                bitangent: [bitangent.x, bitangent.y],
+            };
+        }
+
+        self.sprites.push(QueuedSprite { texture: texture.clone(), normal_map: normal_map.cloned(), vertices });
+    }
+
+    pub(crate) fn push_light(&mut self, light: Light2D) {
+        if self.lights.len() < MAX_LIGHTS {
+            self.lights.push(light);
+        }
+    }
+
+    // A line through the points that blocks lights casting shadows, closed joins the last point back to the first
+    pub(crate) fn push_occluder(&mut self, points: &[Point2<f32>], closed: bool) {
+        self.occluders.extend(points.windows(2).map(|pair| [pair[0], pair[1]]));
+        if closed && points.len() > 2 {
+            self.occluders.push([points[points.len() - 1], points[0]]);
+        }
+    }
+
+    // Returns the number of writes
+    pub(crate) fn prepare(&mut self, queue: &Queue, view_projection: Matrix4<f32>) -> usize {
+        self.batches.clear();
+        self.unshadowed = 0..0;
+        self.shadowed.clear();
+        if self.sprites.is_empty() {
+            self.lights.clear();
+            self.occluders.clear();
+            return 0;
+        }
+
+        let normal_id = |sprite: &QueuedSprite| sprite.normal_map.as_ref().map_or(u64::MAX, |normal_map| normal_map.id());
+        self.sprites.sort_by_key(|sprite| (sprite.texture.id(), normal_id(sprite)));
+
+        let mut vertices = Vec::with_capacity(self.sprites.len() * 4);
+        for (i, sprite) in self.sprites.iter().enumerate() {
+            vertices.extend_from_slice(&sprite.vertices);
+
+            let index = i as u32 * 6;
+            let normal_map = sprite.normal_map.as_ref().unwrap_or(&self.flat_normal);
+            match self.batches.last_mut() {
+                Some(batch) if batch.texture.id() == sprite.texture.id() && batch.normal_map.id() == normal_map.id() => {
+                    batch.indices.end = index + 6
+                },
+                _ => self.batches.push(Batch {
+                    texture: sprite.texture.clone(),
+                    normal_map: normal_map.clone(),
+                    indices: index..index + 6,
+                }),
+            }
+        }
+
+        // Lights without shadows come first so they are drawn together
+        self.lights.sort_by_key(|light| light.shadows);
+        let mut light_vertices = Vec::with_capacity(self.lights.len() * 4);
+        let mut shadow_vertices = vec![];
+        for (i, light) in self.lights.iter().enumerate() {
+            let (cone, direction) = match light.cone {
+                Some(cone) => (cone.angle.cos(), cone.direction.try_normalize(f32::EPSILON).unwrap_or_else(Vector2::zeros)),
+                None => (-1., Vector2::zeros()),
+            };
+            let [r, g, b] = light.color;
+            let color = [r * light.intensity, g * light.intensity, b * light.intensity];
+            for corner in QUAD_CORNERS.iter() {
+                let world = light.position + Vector2::from(*corner) * light.radius * 2.;
+                light_vertices.push(LightVertex {
+                    world: [world.x, world.y],
+                    light: [light.position.x, light.position.y, light.height, light.radius],
+                    color: [color[0], color[1], color[2], cone],
+                    direction: [direction.x, direction.y],
+                });
+            }
+
+            let index = i as u32 * 6;
+            if !light.shadows {
+                self.unshadowed.end = index + 6;
+                continue;
+            }
+
+            let start = shadow_vertices.len() as u32 / 4 * 6;
+            for segment in &self.occluders {
+                if shadow_vertices.len() >= MAX_SHADOW_QUADS * 4 {
+                    break;
+                }
+                if let Some(quad) = shadow_quad(light, segment) {
+                    shadow_vertices.extend_from_slice(&quad);
+                }
+            }
+            let end = shadow_vertices.len() as u32 / 4 * 6;
+            self.shadowed.push((index..index + 6, start..end));
+        }
+
+        let uniforms = LightUniforms { view_projection: view_projection.into() };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+        let mut writes = 2;
+        if !light_vertices.is_empty() {
+            queue.write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&light_vertices));
+            writes += 1;
+        }
+        if !shadow_vertices.is_empty() {
+            queue.write_buffer(&self.shadow_buffer, 0, bytemuck::cast_slice(&shadow_vertices));
+            writes += 1;
+        }
+        self.sprites.clear();
+        self.lights.clear();
+        self.occluders.clear();
+        writes
+    }
+
+    // Fills the lightmap the lit sprites sample, before the scene they are drawn into
+    pub(crate) fn render(&self, encoder: &mut CommandEncoder, quad_indices: &Buffer) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.batches.is_empty() {
+            return stats;
+        }
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("light_2d_normal_pass"),
+                color_attachments: &[RenderPassColorAttachment {
+                    view: self.targets.normals.view(),
+                    resolve_target: None,
+                    ops: Operations { load: LoadOp::Clear(Color::TRANSPARENT), store: true },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(&self.normal_pipeline);
+            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+            render_pass.set_index_buffer(quad_indices.slice(..), IndexFormat::Uint32);
+            render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+            stats.pipeline_switches += 1;
+            for batch in &self.batches {
+                render_pass.set_bind_group(1, batch.texture.bind_group(), &[]);
+                render_pass.set_bind_group(2, batch.normal_map.bind_group(), &[]);
+                render_pass.draw_indexed(batch.indices.clone(), 0, 0..1);
+                stats.draw(batch.indices.end - batch.indices.start, 1);
+                stats.texture_binds += 2;
+            }
+        }
+
+        let [r, g, b] = self.ambient;
+        let ambient = Color { r: r as f64, g: g as f64, b: b as f64, a: 1. };
+        stats += self.render_lights(encoder, quad_indices, LoadOp::Clear(ambient), self.unshadowed.clone(), None);
+        for (light, shadows) in &self.shadowed {
+            stats += self.render_lights(encoder, quad_indices, LoadOp::Load, light.clone(), Some(shadows.clone()));
+        }
+        stats
+    }
+
+    // Shadows mark the depth buffer first so the light is kept out of them
+    fn render_lights(
+        &self,
+        encoder: &mut CommandEncoder,
+        quad_indices: &Buffer,
+        load: LoadOp<Color>,
+        lights: Range<u32>,
+        shadows: Option<Range<u32>>,
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("light_2d_pass"),
+            color_attachments: &[RenderPassColorAttachment {
+                view: self.targets.lightmap.view(),
+                resolve_target: None,
+                ops: Operations { load, store: true },
+            }],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &self.targets.depth_view,
+                depth_ops: Some(Operations { load: LoadOp::Clear(1.), store: false }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_index_buffer(quad_indices.slice(..), IndexFormat::Uint32);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        render_pass.set_bind_group(1, self.targets.normals.bind_group(), &[]);
+        stats.texture_binds += 1;
+        if let Some(shadows) = shadows.filter(|shadows| !shadows.is_empty()) {
+            render_pass.set_pipeline(&self.shadow_pipeline);
+            render_pass.set_vertex_buffer(0, self.shadow_buffer.slice(..));
+            stats.draw(shadows.end - shadows.start, 1);
+            stats.pipeline_switches += 1;
+            render_pass.draw_indexed(shadows, 0, 0..1);
+        }
+        if !lights.is_empty() {
+            render_pass.set_pipeline(&self.light_pipeline);
+            render_pass.set_vertex_buffer(0, self.light_buffer.slice(..));
+            stats.draw(lights.end - lights.start, 1);
+            stats.pipeline_switches += 1;
+            render_pass.draw_indexed(lights, 0, 0..1);
+        }
+        stats
+    }
+
+    // Lit sprites are drawn over the unlit ones, which ignore the lights
+    pub(crate) fn render_sprites<'a>(&'a self, render_pass: &mut RenderPass<'a>, quad_indices: &'a Buffer) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.batches.is_empty() {
+            return stats;
+        }
+
+        render_pass.set_pipeline(&self.lit_pipeline);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(quad_indices.slice(..), IndexFormat::Uint32);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        render_pass.set_bind_group(2, self.targets.lightmap.bind_group(), &[]);
+        stats.pipeline_switches += 1;
+        stats.texture_binds += 1;
+        for batch in &self.batches {
+            render_pass.set_bind_group(1, batch.texture.bind_group(), &[]);
+            render_pass.draw_indexed(batch.indices.clone(), 0, 0..1);
+            stats.draw(batch.indices.end - batch.indices.start, 1);
+            stats.texture_binds += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.batches.clear();
+    }
+}
+
+// The segment pushed away from the light well past its radius, None when the light can't reach it
+fn shadow_quad(light: &Light2D, segment: &[Point2<f32>; 2]) -> Option<[[f32; 2]; 4]> {
+    let [a, b] = *segment;
+    let along = b - a;
+    let t = ((light.position - a).dot(&along) / along.norm_squared().max(f32::EPSILON)).max(0.).min(1.);
+    if (a + along * t - light.position).norm() > light.radius {
+        return None;
+    }
+
+    let far = |point: Point2<f32>| {
+        let away = (point - light.position).try_normalize(f32::EPSILON)?;
+        Some(point + away * light.radius * 4.)
+    };
+    let (far_a, far_b) = (far(a)?, far(b)?);
+    Some([[a.x, a.y], [b.x, b.y], [far_b.x, far_b.y], [far_a.x, far_a.y]])
+}
+
+fn create_targets(device: &Device, texture_layout: &BindGroupLayout, size: [u32; 2]) -> LightTargets {
+    let create = |format: TextureFormat, usage: TextureUsage| {
+        device.create_texture(&TextureDescriptor {
+            label: Some("light_2d_target"),
+            size: Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage,
+        })
+    };
+
+    let usage = TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED;
+    let options = TextureOptions { srgb: false, mipmaps: false, filter: TextureFilter::Linear, wrap: TextureWrap::Clamp };
+    let normals = Texture::from_texture(device, texture_layout, create(NORMAL_FORMAT, usage), size, &options);
+    let lightmap = Texture::from_texture(device, texture_layout, create(LIGHTMAP_FORMAT, usage), size, &options);
+    let depth = create(LIGHT_DEPTH_FORMAT, TextureUsage::RENDER_ATTACHMENT);
+    let depth_view = depth.create_view(&TextureViewDescriptor::default());
+    LightTargets { normals, lightmap, _depth: depth, depth_view }
+}
+
+// Without a depth format the pipeline draws into the normal target, otherwise into the scene like a sprite
+fn create_sprite_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    entry_point: &str,
+    target: ColorTargetState,
+    depth_format: Option<TextureFormat>,
+    sample_count: u32,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("lit_sprite_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "main",
+            buffers: &[VertexBufferLayout {
+                array_stride: size_of::<LitVertex>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Vertex,
+                attributes: &vertex_attr_array![
+                    0 => Float32x2,
+                    1 => Float32x2,
+                    2 => Float32x4,
+                    3 => Float32x2,
+                    4 => Float32x2,
+                ],
+            }],
+        },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: depth_format.map(|format| DepthStencilState {
+            format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::Always,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState { module: shader_module, entry_point, targets: &[target] }),
+    })
+}
+
+// Shadows only write depth, lights add up wherever no shadow is nearer
+fn create_light_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    shadow: bool,
+) -> RenderPipeline {
+    let additive =
+        BlendComponent { src_factor: BlendFactor::One, dst_factor: BlendFactor::One, operation: BlendOperation::Add };
+    let (vertex_entry_point, fragment_entry_point) = if shadow { ("shadow", "occlude") } else { ("light", "shade") };
+    let shadow_attributes = vertex_attr_array![0 => Float32x2];
+    let light_attributes = vertex_attr_array![
+        0 => Float32x2,
+        1 => Float32x4,
+        2 => Float32x4,
+        3 => Float32x2,
+    ];
+    let buffer = match shadow {
+        true => VertexBufferLayout {
+            array_stride: size_of::<[f32; 2]>() as wgpu::BufferAddress,
+            step_mode: InputStepMode::Vertex,
+            attributes: &shadow_attributes,
+        },
+        false => VertexBufferLayout {
+            array_stride: size_of::<LightVertex>() as wgpu::BufferAddress,
+            step_mode: InputStepMode::Vertex,
+            attributes: &light_attributes,
+        },
+    };
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("light_2d_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: vertex_entry_point, buffers: &[buffer] },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: LIGHT_DEPTH_FORMAT,
+            depth_write_enabled: shadow,
+            depth_compare: if shadow { CompareFunction::Always } else { CompareFunction::Less },
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: 1, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: fragment_entry_point,
+            targets: &[ColorTargetState {
+                format: LIGHTMAP_FORMAT,
+                blend: Some(BlendState { color: additive, alpha: additive }),
+                write_mask: if shadow { ColorWrite::empty() } else { ColorWrite::ALL },
+            }],
+        }),
+    })
+}
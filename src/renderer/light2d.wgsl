@@ -0,0 +1,79 @@
+struct LightInput {
+    [[location(0)]] world: vec2<f32>;
+    // Position, height above the sprites and radius
+    [[location(1)]] light: vec4<f32>;
+    // Color times intensity and the cosine of the cone's half angle, -1 for point lights
+    [[location(2)]] color: vec4<f32>;
+    [[location(3)]] direction: vec2<f32>;
+};
+
+struct LightOutput {
+    [[location(0)]] world: vec2<f32>;
+    [[location(1)]] light: vec4<f32>;
+    [[location(2)]] color: vec4<f32>;
+    [[location(3)]] direction: vec2<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct Uniforms {
+    view_projection: mat4x4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+[[group(1), binding(0)]]
+var normals: texture_2d<f32>;
+[[group(1), binding(1)]]
+var normal_sampler: sampler;
+
+// Shadows are drawn nearer than lights, which are only drawn where they are further than what is in the depth buffer
+fn clip(world: vec2<f32>, depth: f32) -> vec4<f32> {
+    let pos = uniforms.view_projection * vec4<f32>(world, 0.0, 1.0);
+    return vec4<f32>(pos.xy, depth * pos.w, pos.w);
+}
+
+[[stage(vertex)]]
+fn shadow([[location(0)]] world: vec2<f32>) -> [[builtin(position)]] vec4<f32> {
+    return clip(world, 0.0);
+}
+
+[[stage(fragment)]]
+fn occlude() -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+}
+
+[[stage(vertex)]]
+fn light(in: LightInput) -> LightOutput {
+    var out: LightOutput;
+    out.world = in.world;
+    out.light = in.light;
+    out.color = in.color;
+    out.direction = in.direction;
+    out.pos = clip(in.world, 0.5);
+    return out;
+}
+
+// Where no lit sprite was drawn the surface faces the camera
+[[stage(fragment)]]
+fn shade(in: LightOutput) -> [[location(0)]] vec4<f32> {
+    let offset = in.light.xy - in.world;
+    let distance = length(offset);
+    let falloff = clamp(1.0 - distance / in.light.w, 0.0, 1.0);
+
+    var cone: f32 = 1.0;
+    if (in.color.w > -1.0) {
+        let angle = dot(-offset / max(distance, 0.0001), in.direction);
+        cone = smoothStep(in.color.w, min(in.color.w + 0.05, 1.0), angle);
+    }
+
+    let stored = textureLoad(normals, vec2<i32>(in.pos.xy), 0);
+    var normal: vec3<f32> = vec3<f32>(0.0, 0.0, 1.0);
+    if (stored.a > 0.5) {
+        normal = normalize(stored.xyz * 2.0 - vec3<f32>(1.0, 1.0, 1.0));
+    }
+    let diffuse = max(dot(normal, normalize(vec3<f32>(offset, in.light.z))), 0.0);
+
+    return vec4<f32>(in.color.rgb * falloff * falloff * cone * diffuse, 1.0);
+}
@@ -0,0 +1,67 @@
+struct VertexInput {
+    [[location(0)]] pos: vec2<f32>;
+    [[location(1)]] tex_coord: vec2<f32>;
+    [[location(2)]] tint: vec4<f32>;
+    [[location(3)]] tangent: vec2<f32>;
+    [[location(4)]] bitangent: vec2<f32>;
+};
+
+struct VertexOutput {
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[location(1)]] tint: vec4<f32>;
+    [[location(2)]] tangent: vec2<f32>;
+    [[location(3)]] bitangent: vec2<f32>;
+    [[location(4)]] screen: vec2<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct Uniforms {
+    view_projection: mat4x4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+[[group(1), binding(0)]]
+var sprite_texture: texture_2d<f32>;
+[[group(1), binding(1)]]
+var sprite_sampler: sampler;
+
+// The normal map while drawing normals and the lightmap while drawing lit sprites
+[[group(2), binding(0)]]
+var detail_texture: texture_2d<f32>;
+[[group(2), binding(1)]]
+var detail_sampler: sampler;
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coord = in.tex_coord;
+    out.tint = in.tint;
+    out.tangent = in.tangent;
+    out.bitangent = in.bitangent;
+    out.pos = uniforms.view_projection * vec4<f32>(in.pos, 0.0, 1.0);
+    out.screen = vec2<f32>(out.pos.x / out.pos.w * 0.5 + 0.5, 0.5 - out.pos.y / out.pos.w * 0.5);
+    return out;
+}
+
+// Tangent space normal maps with green pointing up the sprite, turned with the sprite
+[[stage(fragment)]]
+fn normals(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(sprite_texture, sprite_sampler, in.tex_coord) * in.tint;
+    if (color.a < 0.5) {
+        discard;
+    }
+    let local = textureSample(detail_texture, detail_sampler, in.tex_coord).xyz * 2.0 - vec3<f32>(1.0, 1.0, 1.0);
+    let xy = normalize(in.tangent) * local.x + normalize(in.bitangent) * local.y;
+    let normal = normalize(vec3<f32>(xy, local.z));
+    return vec4<f32>(normal * 0.5 + vec3<f32>(0.5, 0.5, 0.5), 1.0);
+}
+
+[[stage(fragment)]]
+fn lit(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(sprite_texture, sprite_sampler, in.tex_coord) * in.tint;
+    let light = textureSample(detail_texture, detail_sampler, in.screen).rgb;
+    return vec4<f32>(color.rgb * light, color.a);
+}
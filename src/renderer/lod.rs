@@ -0,0 +1,79 @@
+// Copyright 2021 Chay Nabors.
+
+use nalgebra::Matrix4;
+
+use super::GpuMesh;
+
+// Meshes for the same model from most to least detailed, each drawn while the model covers at least its share of the
+// height of the screen. Below the last level nothing is drawn, so a last level at zero always draws
+#[derive(Clone, Debug, Default)]
+pub struct MeshLod {
+    levels: Vec<(GpuMesh, f32)>,
+    // The coverage above each threshold over which a level dithers into the next one, zero swaps them at once
+    pub fade: f32,
+}
+
+impl MeshLod {
+    pub fn new() -> MeshLod {
+        MeshLod::default()
+    }
+
+    pub fn add_level(&mut self, mesh: GpuMesh, min_coverage: f32) -> &mut Self {
+        let index = self.levels.iter().position(|(_, coverage)| *coverage < min_coverage).unwrap_or(self.levels.len());
+        self.levels.insert(index, (mesh, min_coverage));
+        self
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn level(&self, index: usize) -> Option<&GpuMesh> {
+        self.levels.get(index).map(|(mesh, _)| mesh)
+    }
+
+    // The coverage each level is drawn from
+    pub(crate) fn thresholds(&self) -> impl Iterator<Item = f32> + '_ {
+        self.levels.iter().map(|(_, threshold)| *threshold)
+    }
+
+    pub(crate) fn truncate(&mut self, level_count: usize) {
+        self.levels.truncate(level_count);
+    }
+
+    // How much of the height of the screen the sphere around the first level covers, past the near plane everything is
+    // covered
+    pub(crate) fn coverage(&self, model: &Matrix4<f32>, view_projection: &Matrix4<f32>, projection: &Matrix4<f32>) -> f32 {
+        let bounds = match self.levels.first().and_then(|(mesh, _)| mesh.bounds()) {
+            Some(bounds) => bounds.transform(model),
+            None => return f32::INFINITY,
+        };
+        let radius = (bounds.max - bounds.min).norm() / 2.;
+        let w = (view_projection * bounds.center().to_homogeneous()).w;
+        match w > 0. {
+            true => radius * projection[(1, 1)].abs() / w,
+            false => f32::INFINITY,
+        }
+    }
+
+    // The levels drawn at a coverage with how much of each is drawn, the second of two draws the other pixels
+    pub(crate) fn select(&self, coverage: f32) -> Vec<(&GpuMesh, f32)> {
+        let index = match self.levels.iter().position(|(_, threshold)| coverage >= *threshold) {
+            Some(index) => index,
+            None => return vec![],
+        };
+
+        let (mesh, threshold) = &self.levels[index];
+        let weight = match self.fade > 0. {
+            true => ((coverage - threshold) / self.fade).min(1.),
+            false => 1.,
+        };
+        let mut selected = vec![(mesh, weight)];
+        if weight < 1. {
+            if let Some((next, _)) = self.levels.get(index + 1) {
+                selected.push((next, weight));
+            }
+        }
+        selected
+    }
+}
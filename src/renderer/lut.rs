@@ -0,0 +1,162 @@
+// Copyright 2021 Chay Nabors.
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use wgpu::Device;
+use wgpu::Extent3d;
+use wgpu::ImageCopyTexture;
+use wgpu::ImageDataLayout;
+use wgpu::Origin3d;
+use wgpu::Queue;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+
+use crate::GearError;
+use crate::Result;
+
+// Ten bits a channel keeps gradients from banding in four bytes
+const LUT_FORMAT: TextureFormat = TextureFormat::Rgb10a2Unorm;
+// Bigger than what editors export, which is usually 17, 33 or 65
+const MAX_LUT_SIZE: u32 = 256;
+
+// A 3d color lookup table on the gpu, clones share the same texture
+#[derive(Clone, Debug)]
+pub struct ColorLut {
+    inner: Arc<LutData>,
+}
+
+#[derive(Debug)]
+struct LutData {
+    size: u32,
+    _texture: wgpu::Texture,
+    view: TextureView,
+}
+
+impl ColorLut {
+    // Colors from zero to one with red changing fastest, then green, then blue, like in a .cube file
+    pub(crate) fn new(device: &Device, queue: &Queue, size: u32, colors: &[[f32; 3]]) -> Result<ColorLut> {
+        if size < 2 || size > MAX_LUT_SIZE {
+            return Err(GearError::LutError(format!("a size of {} isn't between 2 and {}", size, MAX_LUT_SIZE)));
+        }
+        if colors.len() != (size * size * size) as usize {
+            let message = format!("{} colors where a size of {} needs {}", colors.len(), size, size * size * size);
+            return Err(GearError::LutError(message));
+        }
+
+        let data: Vec<u32> = colors.iter().map(|color| pack(*color)).collect();
+        let extent = Extent3d { width: size, height: size, depth_or_array_layers: size };
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("color_lut"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D3,
+            format: LUT_FORMAT,
+            usage: TextureUsage::SAMPLED | TextureUsage::COPY_DST,
+        });
+        queue.write_texture(
+            ImageCopyTexture { texture: &texture, mip_level: 0, origin: Origin3d::ZERO },
+            bytemuck::cast_slice(&data),
+            ImageDataLayout { offset: 0, bytes_per_row: NonZeroU32::new(4 * size), rows_per_image: NonZeroU32::new(size) },
+            extent,
+        );
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        Ok(ColorLut { inner: Arc::new(LutData { size, _texture: texture, view }) })
+    }
+
+    // Leaves colors as they are, what is bound when there is no grading
+    pub(crate) fn identity(device: &Device, queue: &Queue) -> ColorLut {
+        let colors: Vec<[f32; 3]> = (0..8).map(|i| [(i & 1) as f32, ((i >> 1) & 1) as f32, (i >> 2) as f32]).collect();
+        ColorLut::new(device, queue, 2, &colors).unwrap()
+    }
+
+    pub(crate) fn from_cube(device: &Device, queue: &Queue, source: &str) -> Result<ColorLut> {
+        let (size, colors) = parse_cube(source)?;
+        ColorLut::new(device, queue, size, &colors)
+    }
+
+    // Entries along each side
+    pub fn size(&self) -> u32 {
+        self.inner.size
+    }
+
+    pub(crate) fn view(&self) -> &TextureView {
+        &self.inner.view
+    }
+
+    pub(crate) fn same(&self, other: &ColorLut) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+// Luts map colors as they are shown on screen, so they are applied after tonemapping
+#[derive(Clone, Debug)]
+pub struct ColorGrading {
+    pub lut: ColorLut,
+    // From zero, which leaves colors as they were or as the previous lut has them, to one
+    pub weight: f32,
+    // Blended from instead of the ungraded colors, for transitions between grades
+    pub previous: Option<ColorLut>,
+}
+
+impl ColorGrading {
+    pub fn new(lut: ColorLut) -> ColorGrading {
+        ColorGrading { lut, weight: 1., previous: None }
+    }
+}
+
+fn pack(color: [f32; 3]) -> u32 {
+    let channel = |value: f32| (value.max(0.).min(1.) * 1023. + 0.5) as u32;
+    channel(color[0]) | (channel(color[1]) << 10) | (channel(color[2]) << 20) | (3 << 30)
+}
+
+// The .cube format from Resolve, only 3d tables with the default domain are supported
+fn parse_cube(source: &str) -> Result<(u32, Vec<[f32; 3]>)> {
+    let error = |message: String| Err(GearError::LutError(message));
+    let mut size = None;
+    let mut colors = vec![];
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("TITLE") {
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let keyword = words.next().unwrap_or_default();
+        match keyword {
+            "LUT_3D_SIZE" => match words.next().and_then(|word| word.parse::<u32>().ok()) {
+                Some(value) => size = Some(value),
+                None => return error(format!("line {} has no size", number + 1)),
+            },
+            "LUT_1D_SIZE" => return error("1d luts aren't supported".to_owned()),
+            "DOMAIN_MIN" | "DOMAIN_MAX" | "LUT_3D_INPUT_RANGE" => {
+                let values: Vec<f32> = words.filter_map(|word| word.parse::<f32>().ok()).collect();
+                let default = match keyword {
+                    "DOMAIN_MIN" => vec![0.; 3],
+                    "DOMAIN_MAX" => vec![1.; 3],
+                    _ => vec![0., 1.],
+                };
+                if values != default {
+                    return error(format!("line {} changes the domain, which isn't supported", number + 1));
+                }
+            },
+            _ => {
+                let values: Vec<f32> = line.split_whitespace().filter_map(|word| word.parse::<f32>().ok()).collect();
+                if values.len() != 3 {
+                    return error(format!("line {} isn't a color", number + 1));
+                }
+                colors.push([values[0], values[1], values[2]]);
+            },
+        }
+    }
+
+    match size {
+        Some(size) => Ok((size, colors)),
+        None => error("there is no LUT_3D_SIZE".to_owned()),
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2021 Chay Nabors.
+
+use std::mem::size_of;
+use std::sync::Arc;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use image::Rgba;
+use image::RgbaImage;
+use wgpu::util::BufferInitDescriptor;
+use wgpu::util::DeviceExt;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferUsage;
+use wgpu::Device;
+use wgpu::FilterMode;
+use wgpu::Queue;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderStage;
+use wgpu::TextureSampleType;
+use wgpu::TextureViewDimension;
+
+use super::ShaderId;
+use crate::Texture;
+use crate::TextureArray;
+use crate::TextureOptions;
+
+// Follows the gltf metallic roughness model, every map is optional and multiplied by its factor.
+// Normal and metallic roughness maps hold data rather than color and must be created with TextureOptions::linear
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub albedo: Option<Texture>,
+    pub albedo_factor: [f32; 4],
+    // In place of the albedo, each instance draws the layer its InstanceData picks so meshes textured differently can
+    // share the material and be drawn together
+    pub albedo_layers: Option<TextureArray>,
+    pub normal: Option<Texture>,
+    pub normal_scale: f32,
+    // Roughness is read from the green channel and metalness from the blue one
+    pub metallic_roughness: Option<Texture>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub emissive: Option<Texture>,
+    pub emissive_factor: [f32; 3],
+    // A mesh shader drawing with this material in place of the physically based one
+    pub shader: Option<ShaderId>,
+    // Blends over what is behind by the albedo's alpha without writing depth. Transparent meshes are drawn after the
+    // opaque ones, sorted back to front by their origin for each camera
+    pub transparent: bool,
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            albedo: None,
+            albedo_factor: [1., 1., 1., 1.],
+            albedo_layers: None,
+            normal: None,
+            normal_scale: 1.,
+            metallic_roughness: None,
+            metallic_factor: 0.,
+            roughness_factor: 1.,
+            emissive: None,
+            emissive_factor: [0., 0., 0.],
+            shader: None,
+            transparent: false,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct MaterialUniforms {
+    albedo_factor: [f32; 4],
+    emissive_factor: [f32; 4],
+    // Metallic, roughness, normal scale and one when the albedo layers are used
+    parameters: [f32; 4],
+}
+
+// A material's maps and factors bound together, clones share the same bind group
+#[derive(Clone, Debug)]
+pub struct GpuMaterial {
+    inner: Arc<GpuMaterialData>,
+}
+
+#[derive(Debug)]
+struct GpuMaterialData {
+    _uniform_buffer: Buffer,
+    bind_group: BindGroup,
+    // Kept so the maps outlive the bind group that samples them
+    material: Material,
+}
+
+impl GpuMaterial {
+    pub(crate) fn bind_group(&self) -> &BindGroup {
+        &self.inner.bind_group
+    }
+
+    pub fn shader(&self) -> Option<ShaderId> {
+        self.inner.material.shader
+    }
+
+    pub fn transparent(&self) -> bool {
+        self.inner.material.transparent
+    }
+
+    // Roughness and metallic factors, the maps aren't read outside of the shader
+    pub(crate) fn surface(&self) -> [f32; 4] {
+        [self.inner.material.roughness_factor, self.inner.material.metallic_factor, 0., 0.]
+    }
+}
+
+// Shared by every material, missing maps are filled in with textures that leave the factors unchanged
+#[derive(Debug)]
+pub(crate) struct MaterialLayout {
+    layout: BindGroupLayout,
+    sampler: Sampler,
+    white: Texture,
+    flat_normal: Texture,
+    white_layers: TextureArray,
+}
+
+impl MaterialLayout {
+    pub(crate) fn new(
+        device: &Device,
+        queue: &Queue,
+        texture_layout: &BindGroupLayout,
+        array_layout: &BindGroupLayout,
+    ) -> MaterialLayout {
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("material_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<MaterialUniforms>() as _),
+                    },
+                    count: None,
+                },
+                texture_entry(1),
+                texture_entry(2),
+                texture_entry(3),
+                texture_entry(4),
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: false },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("material_sampler"),
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let options = TextureOptions { mipmaps: false, ..TextureOptions::linear() };
+        let white = RgbaImage::from_pixel(1, 1, Rgba([255, 255, 255, 255]));
+        let white = Texture::from_image(device, queue, texture_layout, white, &options);
+        let flat_normal = RgbaImage::from_pixel(1, 1, Rgba([128, 128, 255, 255]));
+        let flat_normal = Texture::from_image(device, queue, texture_layout, flat_normal, &options);
+        let white_layers = TextureArray::from_rgba(device, queue, array_layout, [1, 1], &[&[255; 4]], &options).unwrap();
+
+        MaterialLayout { layout, sampler, white, flat_normal, white_layers }
+    }
+
+    pub(crate) fn layout(&self) -> &BindGroupLayout {
+        &self.layout
+    }
+
+    pub(crate) fn white(&self) -> &Texture {
+        &self.white
+    }
+
+    pub(crate) fn create(&self, device: &Device, material: &Material) -> GpuMaterial {
+        let emissive = material.emissive_factor;
+        let uniforms = MaterialUniforms {
+            albedo_factor: material.albedo_factor,
+            emissive_factor: [emissive[0], emissive[1], emissive[2], 0.],
+            parameters: [
+                material.metallic_factor,
+                material.roughness_factor,
+                material.normal_scale,
+                if material.albedo_layers.is_some() { 1. } else { 0. },
+            ],
+        };
+
+        let uniform_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("material_uniform_buffer"),
+            contents: bytemuck::bytes_of(&uniforms),
+            usage: BufferUsage::UNIFORM,
+        });
+
+        let albedo = material.albedo.as_ref().unwrap_or(&self.white);
+        let normal = material.normal.as_ref().unwrap_or(&self.flat_normal);
+        let metallic_roughness = material.metallic_roughness.as_ref().unwrap_or(&self.white);
+        let emissive = material.emissive.as_ref().unwrap_or(&self.white);
+        let albedo_layers = material.albedo_layers.as_ref().unwrap_or(&self.white_layers);
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("material_bind_group"),
+            layout: &self.layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() },
+                BindGroupEntry { binding: 1, resource: BindingResource::TextureView(albedo.view()) },
+                BindGroupEntry { binding: 2, resource: BindingResource::TextureView(normal.view()) },
+                BindGroupEntry { binding: 3, resource: BindingResource::TextureView(metallic_roughness.view()) },
+                BindGroupEntry { binding: 4, resource: BindingResource::TextureView(emissive.view()) },
+                BindGroupEntry { binding: 5, resource: BindingResource::Sampler(&self.sampler) },
+                BindGroupEntry { binding: 6, resource: BindingResource::TextureView(albedo_layers.view()) },
+            ],
+        });
+
+        let data = GpuMaterialData { _uniform_buffer: uniform_buffer, bind_group, material: material.clone() };
+        GpuMaterial { inner: Arc::new(data) }
+    }
+}
@@ -0,0 +1,448 @@
+// Copyright 2021 Chay Nabors.
+
+use std::mem::size_of;
+use std::ops::Range;
+use std::sync::Arc;
+
+use bytemuck::Pod;
+use wgpu::util::BufferInitDescriptor;
+use wgpu::util::DeviceExt;
+use wgpu::Buffer;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Device;
+use wgpu::IndexFormat;
+use wgpu::InputStepMode;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::VertexAttribute;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexFormat;
+
+use super::indirect::IndirectDraw;
+use super::instance::InstanceBuffer;
+use super::instance::InstanceVertex;
+use super::skin::JointBuffer;
+use super::stats::FrameStats;
+use crate::model::Mesh;
+use crate::model::SkinVertex;
+use crate::model::Vertex;
+use crate::Aabb;
+
+const SKIN_ATTRIBUTES: [VertexAttribute; 2] = [
+    VertexAttribute { format: VertexFormat::Uint32x4, offset: 0, shader_location: 3 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 16, shader_location: 4 },
+];
+
+// The model matrix, the normal matrix, the color and the layer
+const INSTANCE_ATTRIBUTES: [VertexAttribute; 10] = [
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 0, shader_location: 5 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 16, shader_location: 6 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 32, shader_location: 7 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 48, shader_location: 8 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 64, shader_location: 9 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 80, shader_location: 10 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 96, shader_location: 11 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 112, shader_location: 12 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 128, shader_location: 13 },
+    VertexAttribute { format: VertexFormat::Float32x4, offset: 144, shader_location: 14 },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AttributeFormat {
+    Float32,
+    Float32x2,
+    Float32x3,
+    Float32x4,
+    Uint32,
+    Uint32x2,
+    Uint32x3,
+    Uint32x4,
+    Sint32,
+    Sint32x4,
+    // Four bytes read as floats from zero to one, like a color
+    Unorm8x4,
+}
+
+impl AttributeFormat {
+    fn format(self) -> VertexFormat {
+        match self {
+            AttributeFormat::Float32 => VertexFormat::Float32,
+            AttributeFormat::Float32x2 => VertexFormat::Float32x2,
+            AttributeFormat::Float32x3 => VertexFormat::Float32x3,
+            AttributeFormat::Float32x4 => VertexFormat::Float32x4,
+            AttributeFormat::Uint32 => VertexFormat::Uint32,
+            AttributeFormat::Uint32x2 => VertexFormat::Uint32x2,
+            AttributeFormat::Uint32x3 => VertexFormat::Uint32x3,
+            AttributeFormat::Uint32x4 => VertexFormat::Uint32x4,
+            AttributeFormat::Sint32 => VertexFormat::Sint32,
+            AttributeFormat::Sint32x4 => VertexFormat::Sint32x4,
+            AttributeFormat::Unorm8x4 => VertexFormat::Unorm8x4,
+        }
+    }
+}
+
+// The attributes of a vertex packed one after the other, at shader locations from zero in the same order. Meshes with
+// their own layout are only drawn by mesh shaders created for the same layout
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    pub attributes: Vec<AttributeFormat>,
+}
+
+impl VertexLayout {
+    pub fn new(attributes: Vec<AttributeFormat>) -> VertexLayout {
+        VertexLayout { attributes }
+    }
+
+    // In bytes
+    pub fn stride(&self) -> u64 {
+        self.attributes.iter().map(|attribute| attribute.format().size()).sum()
+    }
+
+    pub(crate) fn vertex_attributes(&self) -> Vec<VertexAttribute> {
+        let mut offset = 0;
+        let mut attributes = vec![];
+        for (location, attribute) in self.attributes.iter().enumerate() {
+            let format = attribute.format();
+            attributes.push(VertexAttribute { format, offset, shader_location: location as u32 });
+            offset += format.size();
+        }
+        attributes
+    }
+}
+
+// Each way of feeding a mesh to the vertex stage needs its own pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MeshVariant {
+    Plain,
+    Skinned,
+    Instanced,
+}
+
+impl MeshVariant {
+    pub(crate) fn entry_point(self) -> &'static str {
+        match self {
+            MeshVariant::Plain => "main",
+            MeshVariant::Skinned => "skinned",
+            MeshVariant::Instanced => "instanced",
+        }
+    }
+
+    // Joints and weights or instances come in a second vertex buffer after the vertices
+    pub(crate) fn extra_buffer(self) -> Option<VertexBufferLayout<'static>> {
+        match self {
+            MeshVariant::Plain => None,
+            MeshVariant::Skinned => Some(VertexBufferLayout {
+                array_stride: size_of::<SkinVertex>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Vertex,
+                attributes: &SKIN_ATTRIBUTES,
+            }),
+            MeshVariant::Instanced => Some(VertexBufferLayout {
+                array_stride: size_of::<InstanceVertex>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Instance,
+                attributes: &INSTANCE_ATTRIBUTES,
+            }),
+        }
+    }
+}
+
+// What a single draw of a mesh needs besides its vertices
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum MeshInput {
+    Plain,
+    // The slot of the joint matrices
+    Skinned(u32),
+    // A range of the instance buffer
+    Instanced(Range<u32>),
+    // A level of instances culled on the gpu, drawn as many times as the culling counted
+    Indirect(IndirectDraw),
+}
+
+impl MeshInput {
+    pub(crate) fn variant(&self) -> MeshVariant {
+        match self {
+            MeshInput::Plain => MeshVariant::Plain,
+            MeshInput::Skinned(_) => MeshVariant::Skinned,
+            MeshInput::Instanced(_) | MeshInput::Indirect(_) => MeshVariant::Instanced,
+        }
+    }
+}
+
+// Vertex and index data uploaded once and drawn as often as needed, clones share the same buffers. Meshes can be
+// updated, clones made before an update keep drawing its old size but with the new data
+#[derive(Clone, Debug)]
+pub struct GpuMesh {
+    inner: Arc<GpuMeshData>,
+}
+
+#[derive(Debug)]
+struct GpuMeshData {
+    vertex_buffer: Arc<Buffer>,
+    index_buffer: Arc<Buffer>,
+    // How many bytes of vertices and how many indices fit before the buffers have to be replaced
+    vertex_capacity: u64,
+    index_capacity: u32,
+    index_count: u32,
+    bounds: Option<Aabb>,
+    // Joints and weights in a second vertex buffer so unskinned pipelines can draw the mesh as well
+    skin_buffer: Option<Arc<Buffer>>,
+    // None for meshes of the usual vertices
+    layout: Option<VertexLayout>,
+}
+
+impl GpuMesh {
+    pub(crate) fn new(device: &Device, mesh: &Mesh) -> GpuMesh {
+        GpuMesh::create(device, mesh, None)
+    }
+
+    // The skin has one entry per vertex of the mesh
+    pub(crate) fn with_skin(device: &Device, mesh: &Mesh, skin: &[SkinVertex]) -> GpuMesh {
+        GpuMesh::create(device, mesh, Some(skin))
+    }
+
+    // Without bounds the mesh is never culled
+    pub(crate) fn with_layout<V: Pod>(device: &Device, vertices: &[V], indices: &[u32], layout: &VertexLayout) -> GpuMesh {
+        let (vertex_buffer, index_buffer) = create_buffers(device, bytemuck::cast_slice(vertices), indices);
+        let vertex_capacity = (vertices.len() * size_of::<V>()) as u64;
+        let index_capacity = indices.len() as u32;
+        GpuMesh {
+            inner: Arc::new(GpuMeshData {
+                vertex_buffer,
+                index_buffer,
+                vertex_capacity,
+                index_capacity,
+                index_count: indices.len() as u32,
+                bounds: None,
+                skin_buffer: None,
+                layout: Some(layout.clone()),
+            }),
+        }
+    }
+
+    // Buffers shared with other meshes, like a terrain chunk drawn with the indices of its level of detail
+    pub(crate) fn from_buffers(
+        vertex_buffer: Arc<Buffer>,
+        vertex_capacity: u64,
+        index_buffer: Arc<Buffer>,
+        index_count: u32,
+        bounds: Option<Aabb>,
+    ) -> GpuMesh {
+        GpuMesh {
+            inner: Arc::new(GpuMeshData {
+                vertex_buffer,
+                index_buffer,
+                vertex_capacity,
+                index_capacity: index_count,
+                index_count,
+                bounds,
+                skin_buffer: None,
+                layout: None,
+            }),
+        }
+    }
+
+    // Room for the vertices and indices, it draws nothing until it is updated
+    pub(crate) fn dynamic(
+        device: &Device,
+        vertex_capacity: usize,
+        index_capacity: usize,
+        layout: Option<&VertexLayout>,
+    ) -> GpuMesh {
+        let stride = layout.map_or(size_of::<Vertex>() as u64, |layout| layout.stride());
+        let vertex_capacity = vertex_capacity as u64 * stride;
+        let index_capacity = index_capacity as u32;
+        let vertex_buffer = create_buffer(device, "mesh_vertex_buffer", vertex_capacity, BufferUsage::VERTEX);
+        let index_buffer = create_buffer(device, "mesh_index_buffer", index_capacity as u64 * 4, BufferUsage::INDEX);
+        GpuMesh {
+            inner: Arc::new(GpuMeshData {
+                vertex_buffer: Arc::new(vertex_buffer),
+                index_buffer: Arc::new(index_buffer),
+                vertex_capacity,
+                index_capacity,
+                index_count: 0,
+                bounds: None,
+                skin_buffer: None,
+                layout: layout.cloned(),
+            }),
+        }
+    }
+
+    fn create(device: &Device, mesh: &Mesh, skin: Option<&[SkinVertex]>) -> GpuMesh {
+        let (vertex_buffer, index_buffer) = create_buffers(device, bytemuck::cast_slice(&mesh.vertices), &mesh.indices);
+
+        let skin_buffer = skin.map(|skin| {
+            Arc::new(device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("mesh_skin_buffer"),
+                contents: bytemuck::cast_slice(skin),
+                usage: BufferUsage::VERTEX,
+            }))
+        });
+
+        GpuMesh {
+            inner: Arc::new(GpuMeshData {
+                vertex_buffer,
+                index_buffer,
+                vertex_capacity: (mesh.vertices.len() * size_of::<Vertex>()) as u64,
+                index_capacity: mesh.indices.len() as u32,
+                index_count: mesh.indices.len() as u32,
+                bounds: mesh.bounds(),
+                skin_buffer,
+                layout: None,
+            }),
+        }
+    }
+
+    // Writes over the buffers while the data fits and replaces them when it doesn't, a skin is kept as it is
+    pub(crate) fn update(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        vertices: &[u8],
+        indices: &[u32],
+        bounds: Option<Aabb>,
+    ) {
+        let data = &self.inner;
+        let (vertex_buffer, vertex_capacity) = match vertices.len() as u64 <= data.vertex_capacity {
+            true => {
+                write_buffer(queue, &data.vertex_buffer, vertices);
+                (data.vertex_buffer.clone(), data.vertex_capacity)
+            },
+            false => {
+                let capacity = (vertices.len() as u64).next_power_of_two();
+                let buffer = create_buffer(device, "mesh_vertex_buffer", capacity, BufferUsage::VERTEX);
+                write_buffer(queue, &buffer, vertices);
+                (Arc::new(buffer), capacity)
+            },
+        };
+        let (index_buffer, index_capacity) = match indices.len() as u32 <= data.index_capacity {
+            true => {
+                write_buffer(queue, &data.index_buffer, bytemuck::cast_slice(indices));
+                (data.index_buffer.clone(), data.index_capacity)
+            },
+            false => {
+                let capacity = (indices.len() as u32).next_power_of_two();
+                let buffer = create_buffer(device, "mesh_index_buffer", capacity as u64 * 4, BufferUsage::INDEX);
+                write_buffer(queue, &buffer, bytemuck::cast_slice(indices));
+                (Arc::new(buffer), capacity)
+            },
+        };
+
+        self.inner = Arc::new(GpuMeshData {
+            vertex_buffer,
+            index_buffer,
+            vertex_capacity,
+            index_capacity,
+            index_count: indices.len() as u32,
+            bounds,
+            skin_buffer: data.skin_buffer.clone(),
+            layout: data.layout.clone(),
+        });
+    }
+
+    pub fn layout(&self) -> Option<&VertexLayout> {
+        self.inner.layout.as_ref()
+    }
+
+    pub(crate) fn vertex_buffer(&self) -> &Buffer {
+        &self.inner.vertex_buffer
+    }
+
+    pub(crate) fn index_buffer(&self) -> &Buffer {
+        &self.inner.index_buffer
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.inner.index_count
+    }
+
+    // Around the vertices in the bind pose
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.inner.bounds
+    }
+
+    pub(crate) fn skin_buffer(&self) -> Option<&Buffer> {
+        self.inner.skin_buffer.as_deref()
+    }
+
+    // Whether the input can be drawn with this mesh by the built in pipelines, skinning needs a skin and none of them
+    // read other vertex layouts
+    pub(crate) fn accepts(&self, input: &MeshInput) -> bool {
+        self.layout().is_none() && (!matches!(input, MeshInput::Skinned(_)) || self.skin_buffer().is_some())
+    }
+
+    // Binds everything the input adds to the vertices and draws the mesh, skinned draws bind their joints at the group
+    pub(crate) fn draw<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        input: &'a MeshInput,
+        joints: &'a JointBuffer,
+        joint_group: u32,
+        instances: &'a InstanceBuffer,
+    ) -> FrameStats {
+        let mut instance_range = 0..1;
+        match input {
+            MeshInput::Plain => {},
+            MeshInput::Skinned(slot) => {
+                if let Some(skin_buffer) = self.skin_buffer() {
+                    render_pass.set_bind_group(joint_group, joints.bind_group(), &[JointBuffer::offset(*slot)]);
+                    render_pass.set_vertex_buffer(1, skin_buffer.slice(..));
+                }
+            },
+            MeshInput::Instanced(range) => {
+                render_pass.set_vertex_buffer(1, instances.buffer().slice(..));
+                instance_range = range.clone();
+            },
+            MeshInput::Indirect(draw) => render_pass.set_vertex_buffer(1, draw.instances()),
+        }
+
+        render_pass.set_vertex_buffer(0, self.vertex_buffer().slice(..));
+        render_pass.set_index_buffer(self.index_buffer().slice(..), IndexFormat::Uint32);
+        let mut stats = FrameStats::default();
+        match input {
+            MeshInput::Indirect(draw) => {
+                let (arguments, offset) = draw.arguments();
+                render_pass.draw_indexed_indirect(arguments, offset);
+                stats.draw_calls += 1;
+            },
+            _ => {
+                stats.draw(self.index_count(), instance_range.end - instance_range.start);
+                render_pass.draw_indexed(0..self.index_count(), 0, instance_range);
+            },
+        }
+        stats
+    }
+}
+
+// Copy destinations so the mesh can be updated later
+fn create_buffers(device: &Device, vertices: &[u8], indices: &[u32]) -> (Arc<Buffer>, Arc<Buffer>) {
+    let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some("mesh_vertex_buffer"),
+        contents: vertices,
+        usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+    });
+
+    let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some("mesh_index_buffer"),
+        contents: bytemuck::cast_slice(indices),
+        usage: BufferUsage::INDEX | BufferUsage::COPY_DST,
+    });
+
+    (Arc::new(vertex_buffer), Arc::new(index_buffer))
+}
+
+fn create_buffer(device: &Device, label: &str, size: u64, usage: BufferUsage) -> Buffer {
+    // Rounded up for copies, which have to be a multiple of four bytes
+    let size = ((size.max(4) + 3) / 4) * 4;
+    device.create_buffer(&BufferDescriptor {
+        label: Some(label),
+        size,
+        usage: usage | BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn write_buffer(queue: &Queue, buffer: &Buffer, data: &[u8]) {
+    if !data.is_empty() {
+        queue.write_buffer(buffer, 0, data);
+    }
+}
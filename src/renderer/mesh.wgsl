@@ -0,0 +1,137 @@
+// Declarations shared by the physically based shader and custom mesh shaders, they follow fog.wgsl
+
+struct VertexInput {
+    [[location(0)]] pos: vec3<f32>;
+    [[location(1)]] tex_coord: vec2<f32>;
+    [[location(2)]] normal: vec3<f32>;
+};
+
+[[block]]
+struct ObjectUniforms {
+    model_view_projection: mat4x4<f32>;
+    model: mat4x4<f32>;
+    normal: mat4x4<f32>;
+    // While fading between levels of detail, how much of the mesh is drawn and whether it is the other half
+    lod_fade: vec4<f32>;
+};
+
+[[block]]
+struct SceneUniforms {
+    view_projection: mat4x4<f32>;
+    camera_position: vec4<f32>;
+    // The direction light travels in, w is 1 when the light is enabled
+    light_direction: vec4<f32>;
+    light_color: vec4<f32>;
+    ambient: vec4<f32>;
+    light_view_projection: mat4x4<f32>;
+    // Bias, normal bias, filter spacing and whether shadows are on
+    shadow: vec4<f32>;
+    fog: Fog;
+};
+
+// Point lights have a cone scale of zero and an offset of one
+struct Light {
+    // The range is in w
+    position: vec4<f32>;
+    color: vec4<f32>;
+    direction: vec4<f32>;
+    cone: vec4<f32>;
+};
+
+[[block]]
+struct LightsUniforms {
+    count: u32;
+    lights: array<Light, 64>;
+};
+
+[[block]]
+struct MaterialUniforms {
+    albedo_factor: vec4<f32>;
+    emissive_factor: vec4<f32>;
+    parameters: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> object: ObjectUniforms;
+
+[[group(1), binding(0)]]
+var<uniform> scene: SceneUniforms;
+[[group(1), binding(1)]]
+var shadow_map: texture_depth_2d;
+[[group(1), binding(2)]]
+var shadow_sampler: sampler_comparison;
+[[group(1), binding(3)]]
+var<uniform> lights: LightsUniforms;
+
+[[group(2), binding(0)]]
+var<uniform> material: MaterialUniforms;
+[[group(2), binding(1)]]
+var albedo_map: texture_2d<f32>;
+[[group(2), binding(2)]]
+var normal_map: texture_2d<f32>;
+[[group(2), binding(3)]]
+var metallic_roughness_map: texture_2d<f32>;
+[[group(2), binding(4)]]
+var emissive_map: texture_2d<f32>;
+[[group(2), binding(5)]]
+var material_sampler: sampler;
+// Read in place of the albedo map when the w of the material parameters is one
+[[group(2), binding(6)]]
+var albedo_layers: texture_2d_array<f32>;
+
+
+// One where the directional light reaches the point and zero where it is in shadow, filtered over a 3x3 kernel
+fn shadow_factor(world_pos: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if (scene.shadow.w < 0.5) {
+        return 1.0;
+    }
+
+    let l = normalize(-scene.light_direction.xyz);
+    let offset = normal * scene.shadow.y * (1.0 - max(dot(normal, l), 0.0));
+    let clip = scene.light_view_projection * vec4<f32>(world_pos + offset, 1.0);
+    let coords = clip.xyz / clip.w;
+    let uv = vec2<f32>(coords.x * 0.5 + 0.5, 0.5 - coords.y * 0.5);
+    let depth = coords.z - scene.shadow.x;
+
+    var lit: f32 = 0.0;
+    var i: i32 = 0;
+    loop {
+        if (i >= 9) {
+            break;
+        }
+        let texel = vec2<f32>(f32(i % 3 - 1), f32(i / 3 - 1)) * scene.shadow.z;
+        lit = lit + textureSampleCompare(shadow_map, shadow_sampler, uv + texel, depth);
+        continuing {
+            i = i + 1;
+        }
+    }
+
+    // Past the far end of the light's view nothing is known to block it
+    if (coords.z > 1.0) {
+        return 1.0;
+    }
+    return lit / 9.0;
+}
+
+// How much of a point or spot light's radiance reaches the point, it falls off with the square of the distance until
+// it is windowed to zero at the range
+fn light_attenuation(light: Light, world_pos: vec3<f32>) -> f32 {
+    let to_light = light.position.xyz - world_pos;
+    let distance_squared = dot(to_light, to_light);
+    let window = clamp(1.0 - pow(distance_squared / (light.position.w * light.position.w), 2.0), 0.0, 1.0);
+    let falloff = window * window / max(distance_squared, 0.0001);
+    let cos_angle = dot(light.direction.xyz, -normalize(to_light));
+    let cone = clamp(cos_angle * light.cone.x + light.cone.y, 0.0, 1.0);
+    return falloff * cone * cone;
+}
+
+// Screen door dithering of meshes fading between levels of detail, the two levels draw opposite pixels
+fn lod_hidden(pos: vec4<f32>) -> bool {
+    let noise = fract(52.9829189 * fract(dot(floor(pos.xy), vec2<f32>(0.06711056, 0.00583715))));
+    return (noise < object.lod_fade.x) == (object.lod_fade.y > 0.5);
+}
+
+// Fades a lit color into the scene's fog
+fn apply_fog(color: vec3<f32>, world_pos: vec3<f32>) -> vec3<f32> {
+    return mix(color, scene.fog.color.rgb, fog_amount(scene.fog, scene.camera_position.xyz, world_pos));
+}
@@ -0,0 +1,62 @@
+// Copyright 2021 Chay Nabors.
+
+use nalgebra::Matrix3;
+use nalgebra::Vector2;
+
+// Splits a sprite into a grid of nine so the corners keep their size, the edges stretch along one axis and the middle
+// stretches along both
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NineSlice {
+    // The part of the texture as [left, top, right, bottom] in texture coordinates, like an atlas region
+    pub region: [f32; 4],
+    // Widths of the borders in texture pixels as [left, top, right, bottom]
+    pub borders: [f32; 4],
+    // World units per texture pixel of the borders, borders shrink to fit sprites too small for them
+    pub border_scale: f32,
+}
+
+impl NineSlice {
+    pub fn new(borders: [f32; 4]) -> NineSlice {
+        NineSlice { region: [0., 0., 1., 1.], borders, border_scale: 1. }
+    }
+
+    // Transforms and regions of the pieces, with the sprite's transform mapping a unit square into world space
+    pub(crate) fn pieces(&self, texture_size: [u32; 2], transform: &Matrix3<f32>) -> Vec<(Matrix3<f32>, [f32; 4])> {
+        let size = [
+            Vector2::new(transform[(0, 0)], transform[(1, 0)]).norm(),
+            Vector2::new(transform[(0, 1)], transform[(1, 1)]).norm(),
+        ];
+        let [left, top, right, bottom] = self.borders;
+        // Border widths as fractions of the unit square
+        let fit = |start: f32, end: f32, size: f32| {
+            let scale = self.border_scale / size.max(f32::EPSILON);
+            let (start, end) = (start * scale, end * scale);
+            let scale = 1. / (start + end).max(1.);
+            (start * scale, end * scale)
+        };
+        let (left_size, right_size) = fit(left, right, size[0]);
+        let (bottom_size, top_size) = fit(bottom, top, size[1]);
+        let xs = [-0.5, -0.5 + left_size, 0.5 - right_size, 0.5];
+        let ys = [-0.5, -0.5 + bottom_size, 0.5 - top_size, 0.5];
+
+        // Texture coordinates go down where world space goes up
+        let [u_start, v_start, u_end, v_end] = self.region;
+        let (width, height) = (texture_size[0].max(1) as f32, texture_size[1].max(1) as f32);
+        let us = [u_start, u_start + left / width, u_end - right / width, u_end];
+        let vs = [v_end, v_end - bottom / height, v_start + top / height, v_start];
+
+        let mut pieces = Vec::with_capacity(9);
+        for row in 0..3 {
+            for column in 0..3 {
+                let extent = Vector2::new(xs[column + 1] - xs[column], ys[row + 1] - ys[row]);
+                if extent.x <= 0. || extent.y <= 0. {
+                    continue;
+                }
+                let center = Vector2::new(xs[column] + xs[column + 1], ys[row] + ys[row + 1]) / 2.;
+                let piece = transform * Matrix3::new_translation(&center) * Matrix3::new_nonuniform_scaling(&extent);
+                pieces.push((piece, [us[column], vs[row + 1], us[column + 1], vs[row]]));
+            }
+        }
+        pieces
+    }
+}
@@ -0,0 +1,480 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use wgpu::vertex_attr_array;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBinding;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Color;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CommandEncoder;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::Extent3d;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::InputStepMode;
+use wgpu::LoadOp;
+use wgpu::MultisampleState;
+use wgpu::Operations;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPassColorAttachment;
+use wgpu::RenderPassDescriptor;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::Texture;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::TextureViewDimension;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+use wgpu::BIND_BUFFER_ALIGNMENT;
+
+use super::instance::InstanceBuffer;
+use super::mesh::MeshInput;
+use super::mesh::MeshVariant;
+use super::skin::JointBuffer;
+use super::stats::FrameStats;
+use super::GpuMesh;
+use crate::model::Vertex;
+
+// Outlined draws past this in one frame are dropped
+const MAX_OUTLINED: u64 = 1 << 14;
+const MASK_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+// Every pixel of the width is another two texels read by each pass
+const MAX_WIDTH: f32 = 16.;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct OutlineUniforms {
+    width: [f32; 4],
+}
+
+#[derive(Debug)]
+struct Outlined {
+    mesh: GpuMesh,
+    uniform: u32,
+    input: MeshInput,
+    color: [f32; 4],
+}
+
+#[derive(Debug)]
+struct OutlineTarget {
+    size: [u32; 2],
+    _mask: Texture,
+    mask_view: TextureView,
+    _grown: Texture,
+    grown_view: TextureView,
+    bind_group: BindGroup,
+}
+
+// Meshes drawn with an outline color are drawn into a mask with the same cameras as the scene, which is grown by the
+// width and drawn around them over the finished frame. Outlines show through whatever is in front of the meshes
+#[derive(Debug)]
+pub(crate) struct OutlineRenderer {
+    pipeline: RenderPipeline,
+    skinned_pipeline: RenderPipeline,
+    instanced_pipeline: RenderPipeline,
+    color_buffer: Buffer,
+    color_bind_group: BindGroup,
+    uniform_buffer: Buffer,
+    layout: BindGroupLayout,
+    grow_pipeline: RenderPipeline,
+    composite_pipeline: RenderPipeline,
+    target: Option<OutlineTarget>,
+    outlined: Vec<Outlined>,
+    width: f32,
+}
+
+impl OutlineRenderer {
+    pub(crate) fn new(
+        device: &Device,
+        object_layout: &BindGroupLayout,
+        joint_layout: &BindGroupLayout,
+        format: TextureFormat,
+    ) -> OutlineRenderer {
+        let color_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("outline_color_buffer"),
+            size: MAX_OUTLINED * BIND_BUFFER_ALIGNMENT,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let color_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("outline_color_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<[f32; 4]>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let color_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("outline_color_bind_group"),
+            layout: &color_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: &color_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(size_of::<[f32; 4]>() as _),
+                }),
+            }],
+        });
+
+        let mask_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("outline_mask_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("outline_mask.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("outline_mask_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &color_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let skinned_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("outline_mask_skinned_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &color_bind_group_layout, joint_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_mask_pipeline(device, &pipeline_layout, &mask_module, MeshVariant::Plain);
+        let skinned_pipeline = create_mask_pipeline(device, &skinned_pipeline_layout, &mask_module, MeshVariant::Skinned);
+        let instanced_pipeline = create_mask_pipeline(device, &pipeline_layout, &mask_module, MeshVariant::Instanced);
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("outline_uniform_buffer"),
+            size: size_of::<OutlineUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("outline_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<OutlineUniforms>() as _),
+                    },
+                    count: None,
+                },
+                texture_entry(1),
+                texture_entry(2),
+            ],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("outline_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("outline.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+        let outline_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("outline_pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let grow_pipeline =
+            create_outline_pipeline(device, &outline_pipeline_layout, &shader_module, "grow", MASK_FORMAT, None);
+        let composite_pipeline = create_outline_pipeline(
+            device,
+            &outline_pipeline_layout,
+            &shader_module,
+            "composite",
+            format,
+            Some(BlendState::ALPHA_BLENDING),
+        );
+
+        OutlineRenderer {
+            pipeline,
+            skinned_pipeline,
+            instanced_pipeline,
+            color_buffer,
+            color_bind_group,
+            uniform_buffer,
+            layout,
+            grow_pipeline,
+            composite_pipeline,
+            target: None,
+            outlined: vec![],
+            width: 2.,
+        }
+    }
+
+    // In window pixels
+    pub(crate) fn set_width(&mut self, width: f32) {
+        self.width = width.max(1.).min(MAX_WIDTH);
+    }
+
+    pub(crate) fn width(&self) -> f32 {
+        self.width
+    }
+
+    pub(crate) fn push(&mut self, mesh: &GpuMesh, uniform: u32, input: MeshInput, color: Option<[f32; 4]>) {
+        let color = match color {
+            Some(color) if mesh.accepts(&input) => color,
+            _ => return,
+        };
+        if (self.outlined.len() as u64) < MAX_OUTLINED {
+            self.outlined.push(Outlined { mesh: mesh.clone(), uniform, input, color });
+        }
+    }
+
+    // Keeps the mask the size of the window while anything is outlined, returns the number of writes
+    pub(crate) fn prepare(&mut self, device: &Device, queue: &Queue, size: [u32; 2]) -> usize {
+        if self.outlined.is_empty() {
+            return 0;
+        }
+
+        if self.target.as_ref().map_or(true, |target| target.size != size) {
+            self.target = Some(create_target(device, &self.layout, &self.uniform_buffer, size));
+        }
+
+        // Colors that are fully transparent would leave the mask uncovered
+        let stride = BIND_BUFFER_ALIGNMENT as usize;
+        let mut colors = vec![0; self.outlined.len() * stride];
+        for (i, outlined) in self.outlined.iter().enumerate() {
+            let [r, g, b, a] = outlined.color;
+            let color = [r, g, b, a.max(1. / 255.)];
+            colors[i * stride..i * stride + size_of::<[f32; 4]>()].copy_from_slice(bytemuck::bytes_of(&color));
+        }
+        queue.write_buffer(&self.color_buffer, 0, &colors);
+        let uniforms = OutlineUniforms { width: [self.width.round(), 0., 0., 0.] };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        2
+    }
+
+    // Once for every viewport after the uniforms are written for its camera like the picker, only the first one clears
+    // the whole mask. The rectangle is in pixels
+    pub(crate) fn render_mask(
+        &self,
+        encoder: &mut CommandEncoder,
+        object_bind_group: &BindGroup,
+        joints: &JointBuffer,
+        instances: &InstanceBuffer,
+        rect: Option<[u32; 4]>,
+        clear: bool,
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        let target = match &self.target {
+            Some(target) if !self.outlined.is_empty() => target,
+            _ => return stats,
+        };
+
+        let load = if clear { LoadOp::Clear(Color::TRANSPARENT) } else { LoadOp::Load };
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("outline_mask_pass"),
+            color_attachments: &[RenderPassColorAttachment {
+                view: &target.mask_view,
+                resolve_target: None,
+                ops: Operations { load, store: true },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        if let Some(rect) = rect {
+            if rect[2] == 0 || rect[3] == 0 {
+                return stats;
+            }
+            render_pass.set_viewport(rect[0] as f32, rect[1] as f32, rect[2] as f32, rect[3] as f32, 0., 1.);
+            render_pass.set_scissor_rect(rect[0], rect[1], rect[2], rect[3]);
+        }
+
+        for (i, outlined) in self.outlined.iter().enumerate() {
+            render_pass.set_pipeline(match outlined.input.variant() {
+                MeshVariant::Plain => &self.pipeline,
+                MeshVariant::Skinned => &self.skinned_pipeline,
+                MeshVariant::Instanced => &self.instanced_pipeline,
+            });
+
+            let offset = (outlined.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(0, object_bind_group, &[offset]);
+            let color_offset = (i as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(1, &self.color_bind_group, &[color_offset]);
+            stats += outlined.mesh.draw(&mut render_pass, &outlined.input, joints, 2, instances);
+            stats.pipeline_switches += 1;
+        }
+        stats
+    }
+
+    // Over a target the size of the window, after everything else is drawn to it
+    pub(crate) fn composite(&self, encoder: &mut CommandEncoder, view: &TextureView) {
+        let target = match &self.target {
+            Some(target) if !self.outlined.is_empty() => target,
+            _ => return,
+        };
+
+        let steps = [
+            (&self.grow_pipeline, &target.grown_view, LoadOp::Clear(Color::TRANSPARENT)),
+            (&self.composite_pipeline, view, LoadOp::Load),
+        ];
+        for (pipeline, view, load) in steps.iter().copied() {
+            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("outline_pass"),
+                color_attachments: &[RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: Operations { load, store: true },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &target.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.outlined.clear();
+    }
+}
+
+fn create_target(device: &Device, layout: &BindGroupLayout, uniform_buffer: &Buffer, size: [u32; 2]) -> OutlineTarget {
+    let texture = |label| {
+        device.create_texture(&TextureDescriptor {
+            label: Some(label),
+            size: Extent3d { width: size[0].max(1), height: size[1].max(1), depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: MASK_FORMAT,
+            usage: TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED,
+        })
+    };
+    let mask = texture("outline_mask_texture");
+    let grown = texture("outline_grown_texture");
+    let mask_view = mask.create_view(&TextureViewDescriptor::default());
+    let grown_view = grown.create_view(&TextureViewDescriptor::default());
+
+    let bind_group = device.create_bind_group(&BindGroupDescriptor {
+        label: Some("outline_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() },
+            BindGroupEntry { binding: 1, resource: BindingResource::TextureView(&mask_view) },
+            BindGroupEntry { binding: 2, resource: BindingResource::TextureView(&grown_view) },
+        ],
+    });
+
+    OutlineTarget { size, _mask: mask, mask_view, _grown: grown, grown_view, bind_group }
+}
+
+// Both faces and no depth, so the mask covers the whole silhouette of the mesh
+fn create_mask_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    variant: MeshVariant,
+) -> RenderPipeline {
+    let vertex_attributes = vertex_attr_array![0 => Float32x3];
+    let mut buffers = vec![VertexBufferLayout {
+        array_stride: size_of::<Vertex>() as wgpu::BufferAddress,
+        step_mode: InputStepMode::Vertex,
+        attributes: &vertex_attributes,
+    }];
+    buffers.extend(variant.extra_buffer());
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("outline_mask_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: variant.entry_point(), buffers: &buffers },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format: MASK_FORMAT, blend: None, write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
+
+fn create_outline_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    entry_point: &str,
+    format: TextureFormat,
+    blend: Option<BlendState>,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("outline_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: "main", buffers: &[] },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point,
+            targets: &[ColorTargetState { format, blend, write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
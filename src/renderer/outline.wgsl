@@ -0,0 +1,72 @@
+// Grows the mask by the outline width in two passes, first along x and then along y, and draws what it grew by
+
+struct OutlineOutput {
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct OutlineUniforms {
+    // The width in pixels in x
+    width: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> outline: OutlineUniforms;
+[[group(0), binding(1)]]
+var mask: texture_2d<f32>;
+// What the first pass grew the mask into, the second pass reads it
+[[group(0), binding(2)]]
+var grown: texture_2d<f32>;
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] index: u32) -> OutlineOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: OutlineOutput;
+    out.pos = vec4<f32>(uv * 2.0 - vec2<f32>(1.0, 1.0), 0.0, 1.0);
+    return out;
+}
+
+// The closest covered texel along the axis within the width, out of bounds texels are left uncovered
+fn closest(source: texture_2d<f32>, pixel: vec2<i32>, axis: vec2<i32>) -> vec4<f32> {
+    let size = textureDimensions(source);
+    let width = i32(outline.width.x);
+    var distance: i32 = 0;
+    loop {
+        if (distance > width) {
+            break;
+        }
+        let before = pixel - axis * distance;
+        if (all(before >= vec2<i32>(0, 0)) && all(before < size)) {
+            let color = textureLoad(source, before, 0);
+            if (color.a > 0.0) {
+                return color;
+            }
+        }
+        let after = pixel + axis * distance;
+        if (all(after >= vec2<i32>(0, 0)) && all(after < size)) {
+            let color = textureLoad(source, after, 0);
+            if (color.a > 0.0) {
+                return color;
+            }
+        }
+        continuing {
+            distance = distance + 1;
+        }
+    }
+    return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+}
+
+[[stage(fragment)]]
+fn grow(in: OutlineOutput) -> [[location(0)]] vec4<f32> {
+    return closest(mask, vec2<i32>(in.pos.xy), vec2<i32>(1, 0));
+}
+
+// Blended over the target, the meshes themselves are left uncovered
+[[stage(fragment)]]
+fn composite(in: OutlineOutput) -> [[location(0)]] vec4<f32> {
+    let pixel = vec2<i32>(in.pos.xy);
+    if (textureLoad(mask, pixel, 0).a > 0.0) {
+        discard;
+    }
+    return closest(grown, pixel, vec2<i32>(0, 1));
+}
@@ -0,0 +1,76 @@
+[[block]]
+struct EmitterUniforms {
+    // The delta time is in w
+    position: vec4<f32>;
+    // The lifetimes are in w
+    velocity_min: vec4<f32>;
+    velocity_max: vec4<f32>;
+    acceleration: vec4<f32>;
+    // Capacity, first particle to spawn, how many to spawn and a seed
+    spawn: vec4<u32>;
+    sizes: array<vec4<f32>, 16>;
+    colors: array<vec4<f32>, 16>;
+};
+
+struct Particle {
+    position: vec3<f32>;
+    age: f32;
+    velocity: vec3<f32>;
+    lifetime: f32;
+};
+
+[[block]]
+struct Particles {
+    particles: array<Particle>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> emitter: EmitterUniforms;
+[[group(0), binding(1)]]
+var<storage> particles: [[access(read_write)]] Particles;
+
+fn hash(x: u32) -> u32 {
+    var h: u32 = (x ^ 61u) ^ (x >> 16u);
+    h = h * 9u;
+    h = h ^ (h >> 4u);
+    h = h * 668265261u;
+    return h ^ (h >> 15u);
+}
+
+// A different number from zero to one for every particle, frame and stream
+fn random(index: u32, stream: u32) -> f32 {
+    return f32(hash(index * 8u + stream + hash(emitter.spawn.w))) / 4294967295.0;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn main([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let index = id.x;
+    let capacity = emitter.spawn.x;
+    if (index >= capacity) {
+        return;
+    }
+
+    // Spawning walks a ring over the buffer, replacing the oldest particles first
+    let offset = (index + capacity - emitter.spawn.y) % capacity;
+    if (offset < emitter.spawn.z) {
+        let t = vec3<f32>(random(index, 0u), random(index, 1u), random(index, 2u));
+        var particle: Particle;
+        particle.position = emitter.position.xyz;
+        particle.age = 0.0;
+        particle.velocity = mix(emitter.velocity_min.xyz, emitter.velocity_max.xyz, t);
+        particle.lifetime = mix(emitter.velocity_min.w, emitter.velocity_max.w, random(index, 3u));
+        particles.particles[index] = particle;
+        return;
+    }
+
+    var particle: Particle = particles.particles[index];
+    if (particle.age >= particle.lifetime) {
+        return;
+    }
+
+    let delta_time = emitter.position.w;
+    particle.age = particle.age + delta_time;
+    particle.velocity = particle.velocity + emitter.acceleration.xyz * delta_time;
+    particle.position = particle.position + particle.velocity * delta_time;
+    particles.particles[index] = particle;
+}
@@ -0,0 +1,580 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Isometry3;
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use nalgebra::Vector3;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingType;
+use wgpu::BlendComponent;
+use wgpu::BlendFactor;
+use wgpu::BlendOperation;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CommandEncoder;
+use wgpu::CompareFunction;
+use wgpu::ComputePassDescriptor;
+use wgpu::ComputePipeline;
+use wgpu::ComputePipelineDescriptor;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::VertexState;
+
+use super::stats::FrameStats;
+use crate::Texture;
+
+// Curves are sampled this many times over the life of a particle and interpolated between on the gpu
+const CURVE_SAMPLES: usize = 16;
+const WORKGROUP_SIZE: u32 = 64;
+// Emitters start from different seeds so they don't all spawn the same pattern
+static NEXT_SEED: AtomicU32 = AtomicU32::new(0);
+
+pub trait CurveValue: Copy + Default {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl CurveValue for f32 {
+    fn lerp(self, other: f32, t: f32) -> f32 {
+        self + (other - self) * t
+    }
+}
+
+impl CurveValue for [f32; 4] {
+    fn lerp(self, other: [f32; 4], t: f32) -> [f32; 4] {
+        [self[0].lerp(other[0], t), self[1].lerp(other[1], t), self[2].lerp(other[2], t), self[3].lerp(other[3], t)]
+    }
+}
+
+// Keys are pairs of a time from zero to one and a value, ordered by time. Times outside the keys hold the first or
+// last value
+#[derive(Clone, Debug, PartialEq)]
+pub struct Curve<T: CurveValue> {
+    pub keys: Vec<(f32, T)>,
+}
+
+impl<T: CurveValue> Curve<T> {
+    pub fn new(keys: Vec<(f32, T)>) -> Curve<T> {
+        Curve { keys }
+    }
+
+    pub fn constant(value: T) -> Curve<T> {
+        Curve { keys: vec![(0., value)] }
+    }
+
+    pub fn linear(start: T, end: T) -> Curve<T> {
+        Curve { keys: vec![(0., start), (1., end)] }
+    }
+
+    pub fn sample(&self, time: f32) -> T {
+        let (first, last) = match (self.keys.first(), self.keys.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return T::default(),
+        };
+        if time <= first.0 {
+            return first.1;
+        }
+        if time >= last.0 {
+            return last.1;
+        }
+
+        let to = self.keys.iter().position(|key| key.0 > time).unwrap_or(self.keys.len() - 1);
+        let (from, to) = (self.keys[to - 1], self.keys[to]);
+        from.1.lerp(to.1, (time - from.0) / (to.0 - from.0))
+    }
+
+    fn samples(&self) -> [T; CURVE_SAMPLES] {
+        let mut samples = [T::default(); CURVE_SAMPLES];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            *sample = self.sample(i as f32 / (CURVE_SAMPLES - 1) as f32);
+        }
+        samples
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParticleSettings {
+    // The most particles alive at once, fixed when the emitter is created. Spawning past it replaces the oldest
+    pub capacity: u32,
+    // Particles per second while emitting
+    pub spawn_rate: f32,
+    // Each particle picks a lifetime in seconds and a velocity between the minimum and the maximum
+    pub lifetime: [f32; 2],
+    pub velocity: [Vector3<f32>; 2],
+    pub acceleration: Vector3<f32>,
+    // Over the life of a particle, from zero at its spawn to one at its death. Sizes are in world units
+    pub size: Curve<f32>,
+    pub color: Curve<[f32; 4]>,
+    pub texture: Option<Texture>,
+    // Adds the particles to what is behind them instead of blending them over it, for fire and sparks
+    pub additive: bool,
+}
+
+impl Default for ParticleSettings {
+    fn default() -> ParticleSettings {
+        ParticleSettings {
+            capacity: 1024,
+            spawn_rate: 64.,
+            lifetime: [1., 2.],
+            velocity: [Vector3::new(-0.5, 1., -0.5), Vector3::new(0.5, 2., 0.5)],
+            acceleration: Vector3::zeros(),
+            size: Curve::linear(0.2, 0.),
+            color: Curve::linear([1., 1., 1., 1.], [1., 1., 1., 0.]),
+            texture: None,
+            additive: false,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct EmitterUniforms {
+    // The delta time is in w
+    position: [f32; 4],
+    // The lifetimes are in w
+    velocity_min: [f32; 4],
+    velocity_max: [f32; 4],
+    acceleration: [f32; 4],
+    // Capacity, first particle to spawn, how many to spawn and a seed
+    spawn: [u32; 4],
+    sizes: [[f32; 4]; CURVE_SAMPLES],
+    colors: [[f32; 4]; CURVE_SAMPLES],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct Particle {
+    position: [f32; 3],
+    age: f32,
+    velocity: [f32; 3],
+    lifetime: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct FrameUniforms {
+    view_projection: [[f32; 4]; 4],
+    camera_right: [f32; 4],
+    camera_up: [f32; 4],
+}
+
+// The particles live on the gpu and only move in frames the emitter is drawn in, call update with the delta time of
+// every update and draw it with Renderer::draw_particles
+#[derive(Debug)]
+pub struct ParticleEmitter {
+    pub settings: ParticleSettings,
+    pub position: Point3<f32>,
+    // Stops spawning without killing the particles already alive
+    pub emitting: bool,
+    inner: Arc<EmitterData>,
+    pending_time: f32,
+    pending_spawns: f32,
+    next: u32,
+    seed: u32,
+}
+
+#[derive(Debug)]
+struct EmitterData {
+    capacity: u32,
+    uniform_buffer: Buffer,
+    _particle_buffer: Buffer,
+    simulate_bind_group: BindGroup,
+    render_bind_group: BindGroup,
+}
+
+impl ParticleEmitter {
+    pub fn update(&mut self, delta_time: Duration) {
+        let delta_time = delta_time.as_secs_f32();
+        self.pending_time += delta_time;
+        if self.emitting {
+            self.pending_spawns += delta_time * self.settings.spawn_rate.max(0.);
+        }
+    }
+
+    // Spawns the particles at once on top of the spawn rate, even while not emitting
+    pub fn burst(&mut self, count: u32) {
+        self.pending_spawns += count as f32;
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.inner.capacity
+    }
+}
+
+#[derive(Debug)]
+struct ParticleDraw {
+    emitter: Arc<EmitterData>,
+    texture: Texture,
+    additive: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct ParticleRenderer {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    alpha_pipeline: RenderPipeline,
+    additive_pipeline: RenderPipeline,
+    simulate_pipeline: ComputePipeline,
+    simulate_layout: BindGroupLayout,
+    render_layout: BindGroupLayout,
+    uniform_buffer: Buffer,
+    uniform_bind_group: BindGroup,
+    white: Texture,
+    draws: Vec<ParticleDraw>,
+}
+
+impl ParticleRenderer {
+    pub(crate) fn new(
+        device: &Device,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        texture_layout: &BindGroupLayout,
+        white: &Texture,
+    ) -> ParticleRenderer {
+        let emitter_layout = |visibility, read_only| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("particle_emitter_bind_group_layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(size_of::<EmitterUniforms>() as _),
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only },
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(size_of::<Particle>() as _),
+                        },
+                        count: None,
+                    },
+                ],
+            })
+        };
+        let simulate_layout = emitter_layout(ShaderStage::COMPUTE, false);
+        let render_layout = emitter_layout(ShaderStage::VERTEX, true);
+
+        let simulate_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("particle_update_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("particle_update.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+        let simulate_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("particle_update_pipeline_layout"),
+            bind_group_layouts: &[&simulate_layout],
+            push_constant_ranges: &[],
+        });
+        let simulate_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("particle_update_pipeline"),
+            layout: Some(&simulate_pipeline_layout),
+            module: &simulate_module,
+            entry_point: "main",
+        });
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("particle_uniform_buffer"),
+            size: size_of::<FrameUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("particle_uniform_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<FrameUniforms>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let uniform_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("particle_uniform_bind_group"),
+            layout: &uniform_bind_group_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() }],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("particle_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("particles.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("particle_pipeline_layout"),
+            bind_group_layouts: &[&uniform_bind_group_layout, &render_layout, texture_layout],
+            push_constant_ranges: &[],
+        });
+
+        let alpha_pipeline = create_pipeline(device, &pipeline_layout, &shader_module, format, depth_format, false, 1);
+        let additive_pipeline = create_pipeline(device, &pipeline_layout, &shader_module, format, depth_format, true, 1);
+
+        ParticleRenderer {
+            format,
+            depth_format,
+            shader_module,
+            pipeline_layout,
+            alpha_pipeline,
+            additive_pipeline,
+            simulate_pipeline,
+            simulate_layout,
+            render_layout,
+            uniform_buffer,
+            uniform_bind_group,
+            white: white.clone(),
+            draws: vec![],
+        }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        let (layout, module) = (&self.pipeline_layout, &self.shader_module);
+        self.alpha_pipeline = create_pipeline(device, layout, module, self.format, self.depth_format, false, sample_count);
+        self.additive_pipeline =
+            create_pipeline(device, layout, module, self.format, self.depth_format, true, sample_count);
+    }
+
+    pub(crate) fn create_emitter(&self, device: &Device, settings: ParticleSettings) -> ParticleEmitter {
+        let capacity = settings.capacity.max(1);
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("particle_emitter_uniform_buffer"),
+            size: size_of::<EmitterUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // Zeroed particles have no lifetime left, so the emitter starts out empty
+        let particle_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("particle_buffer"),
+            size: capacity as u64 * size_of::<Particle>() as u64,
+            usage: BufferUsage::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = |layout| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: Some("particle_emitter_bind_group"),
+                layout,
+                entries: &[
+                    BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() },
+                    BindGroupEntry { binding: 1, resource: particle_buffer.as_entire_binding() },
+                ],
+            })
+        };
+        let simulate_bind_group = bind_group(&self.simulate_layout);
+        let render_bind_group = bind_group(&self.render_layout);
+
+        let inner = EmitterData {
+            capacity,
+            uniform_buffer,
+            _particle_buffer: particle_buffer,
+            simulate_bind_group,
+            render_bind_group,
+        };
+        ParticleEmitter {
+            settings,
+            position: Point3::origin(),
+            emitting: true,
+            inner: Arc::new(inner),
+            pending_time: 0.,
+            pending_spawns: 0.,
+            next: 0,
+            seed: NEXT_SEED.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9e3779b9),
+        }
+    }
+
+    // Hands the time and spawns since the last draw to the gpu, an emitter drawn twice in a frame only moves once
+    pub(crate) fn push(&mut self, queue: &Queue, emitter: &mut ParticleEmitter) {
+        if self.draws.iter().any(|draw| Arc::ptr_eq(&draw.emitter, &emitter.inner)) {
+            return;
+        }
+
+        let settings = &emitter.settings;
+        let capacity = emitter.inner.capacity;
+        let spawns = emitter.pending_spawns.floor();
+        let count = (spawns as u32).min(capacity);
+        let [velocity_min, velocity_max] = settings.velocity;
+        let mut uniforms = EmitterUniforms {
+            position: [emitter.position.x, emitter.position.y, emitter.position.z, emitter.pending_time],
+            velocity_min: [velocity_min.x, velocity_min.y, velocity_min.z, settings.lifetime[0]],
+            velocity_max: [velocity_max.x, velocity_max.y, velocity_max.z, settings.lifetime[1]],
+            acceleration: [settings.acceleration.x, settings.acceleration.y, settings.acceleration.z, 0.],
+            spawn: [capacity, emitter.next, count, emitter.seed],
+            ..Zeroable::zeroed()
+        };
+        for (uniform, size) in uniforms.sizes.iter_mut().zip(settings.size.samples().iter()) {
+            uniform[0] = *size;
+        }
+        uniforms.colors = settings.color.samples();
+        queue.write_buffer(&emitter.inner.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+
+        emitter.pending_time = 0.;
+        emitter.pending_spawns -= spawns;
+        emitter.next = (emitter.next + count) % capacity;
+        emitter.seed = emitter.seed.wrapping_add(1);
+
+        let texture = settings.texture.clone().unwrap_or_else(|| self.white.clone());
+        self.draws.push(ParticleDraw { emitter: emitter.inner.clone(), texture, additive: settings.additive });
+    }
+
+    // Returns the number of writes
+    pub(crate) fn prepare(&self, queue: &Queue, view_projection: Matrix4<f32>, view: Isometry3<f32>) -> usize {
+        if self.draws.is_empty() {
+            return 0;
+        }
+
+        let rotation = view.rotation.inverse();
+        let right = rotation * Vector3::x();
+        let up = rotation * Vector3::y();
+        let uniforms = FrameUniforms {
+            view_projection: view_projection.into(),
+            camera_right: [right.x, right.y, right.z, 0.],
+            camera_up: [up.x, up.y, up.z, 0.],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        1
+    }
+
+    // Before any pass that draws the particles
+    pub(crate) fn simulate(&self, encoder: &mut CommandEncoder) {
+        if self.draws.is_empty() {
+            return;
+        }
+
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor { label: Some("particle_pass") });
+        compute_pass.set_pipeline(&self.simulate_pipeline);
+        for draw in &self.draws {
+            compute_pass.set_bind_group(0, &draw.emitter.simulate_bind_group, &[]);
+            compute_pass.dispatch((draw.emitter.capacity + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1, 1);
+        }
+    }
+
+    pub(crate) fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.draws.is_empty() {
+            return stats;
+        }
+
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        for draw in &self.draws {
+            let pipeline = if draw.additive { &self.additive_pipeline } else { &self.alpha_pipeline };
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(1, &draw.emitter.render_bind_group, &[]);
+            render_pass.set_bind_group(2, draw.texture.bind_group(), &[]);
+            render_pass.draw(0..6, 0..draw.emitter.capacity);
+            stats.draw(6, draw.emitter.capacity);
+            stats.texture_binds += 1;
+            stats.pipeline_switches += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.draws.clear();
+    }
+}
+
+// Particles are hidden behind meshes but don't hide each other, they aren't sorted
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    additive: bool,
+    sample_count: u32,
+) -> RenderPipeline {
+    let blend = match additive {
+        true => BlendState {
+            color: BlendComponent {
+                src_factor: BlendFactor::SrcAlpha,
+                dst_factor: BlendFactor::One,
+                operation: BlendOperation::Add,
+            },
+            alpha: BlendComponent {
+                src_factor: BlendFactor::Zero,
+                dst_factor: BlendFactor::One,
+                operation: BlendOperation::Add,
+            },
+        },
+        false => BlendState::ALPHA_BLENDING,
+    };
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("particle_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: "main", buffers: &[] },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format, blend: Some(blend), write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
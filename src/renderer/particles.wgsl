@@ -0,0 +1,90 @@
+[[block]]
+struct FrameUniforms {
+    view_projection: mat4x4<f32>;
+    // The camera's axes in world space, billboards are spanned by them
+    camera_right: vec4<f32>;
+    camera_up: vec4<f32>;
+};
+
+[[block]]
+struct EmitterUniforms {
+    position: vec4<f32>;
+    velocity_min: vec4<f32>;
+    velocity_max: vec4<f32>;
+    acceleration: vec4<f32>;
+    spawn: vec4<u32>;
+    // Sampled evenly over the life of a particle, sizes are in x
+    sizes: array<vec4<f32>, 16>;
+    colors: array<vec4<f32>, 16>;
+};
+
+struct Particle {
+    position: vec3<f32>;
+    age: f32;
+    velocity: vec3<f32>;
+    lifetime: f32;
+};
+
+[[block]]
+struct Particles {
+    particles: array<Particle>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> frame: FrameUniforms;
+
+[[group(1), binding(0)]]
+var<uniform> emitter: EmitterUniforms;
+[[group(1), binding(1)]]
+var<storage> particles: [[access(read)]] Particles;
+
+[[group(2), binding(0)]]
+var particle_texture: texture_2d<f32>;
+[[group(2), binding(1)]]
+var particle_sampler: sampler;
+
+struct VertexOutput {
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[location(1)]] color: vec4<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] vertex: u32, [[builtin(instance_index)]] instance: u32) -> VertexOutput {
+    let particle = particles.particles[instance];
+    let life = clamp(particle.age / max(particle.lifetime, 0.0001), 0.0, 1.0) * 15.0;
+    let key = u32(floor(life));
+    let next = min(key + 1u, 15u);
+    let t = fract(life);
+    let size = mix(emitter.sizes[key].x, emitter.sizes[next].x, t);
+    let color = mix(emitter.colors[key], emitter.colors[next], t);
+
+    // Two triangles per particle, counter clockwise from the bottom left
+    var corners: array<vec2<f32>, 6> = array<vec2<f32>, 6>(
+        vec2<f32>(-0.5, -0.5),
+        vec2<f32>(0.5, -0.5),
+        vec2<f32>(0.5, 0.5),
+        vec2<f32>(-0.5, -0.5),
+        vec2<f32>(0.5, 0.5),
+        vec2<f32>(-0.5, 0.5)
+    );
+    let corner = corners[vertex % 6u];
+
+    // Dead particles collapse to nothing
+    var scale: f32 = size;
+    if (particle.age >= particle.lifetime) {
+        scale = 0.0;
+    }
+    let offset = (frame.camera_right.xyz * corner.x + frame.camera_up.xyz * corner.y) * scale;
+
+    var out: VertexOutput;
+    out.tex_coord = vec2<f32>(corner.x + 0.5, 0.5 - corner.y);
+    out.color = color;
+    out.pos = frame.view_projection * vec4<f32>(particle.position + offset, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(particle_texture, particle_sampler, in.tex_coord) * in.color;
+}
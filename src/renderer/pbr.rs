@@ -0,0 +1,553 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::mem::size_of;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use wgpu::vertex_attr_array;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::BlendComponent;
+use wgpu::BlendFactor;
+use wgpu::BlendOperation;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::Face;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::InputStepMode;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureViewDimension;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+use wgpu::BIND_BUFFER_ALIGNMENT;
+
+use super::fog::FogUniforms;
+use super::instance::InstanceBuffer;
+use super::mesh::MeshInput;
+use super::mesh::MeshVariant;
+use super::shadow::ShadowMap;
+use super::shadow::SHADOW_MAP_SIZE;
+use super::skin::JointBuffer;
+use super::stats::FrameStats;
+use super::DebugView;
+use super::DirectionalLight;
+use super::Fog;
+use super::GpuMaterial;
+use super::GpuMesh;
+use super::Light;
+use crate::model::Vertex;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct SceneUniforms {
+    view_projection: [[f32; 4]; 4],
+    camera_position: [f32; 4],
+    light_direction: [f32; 4],
+    light_color: [f32; 4],
+    ambient: [f32; 4],
+    light_view_projection: [[f32; 4]; 4],
+    // Bias, normal bias, filter spacing in texture coordinates and whether shadows are on
+    shadow: [f32; 4],
+    fog: FogUniforms,
+}
+
+// Lights submitted past this in one frame are dropped
+const MAX_LIGHTS: usize = 64;
+
+// Point lights are spot lights whose cone covers everything
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct LightUniform {
+    // The range is in w
+    position: [f32; 4],
+    color: [f32; 4],
+    direction: [f32; 4],
+    // Scale and offset mapping the cosine of the angle to the direction onto the cone falloff
+    cone: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct LightsUniforms {
+    count: u32,
+    _padding: [u32; 3],
+    lights: [LightUniform; MAX_LIGHTS],
+}
+
+// Everything about the passes the pipelines draw in that they're rebuilt for when it changes
+#[derive(Clone, Copy, Debug)]
+struct Targets {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+    debug_view: Option<DebugView>,
+}
+
+#[derive(Debug)]
+struct MaterialDraw {
+    mesh: GpuMesh,
+    material: GpuMaterial,
+    uniform: u32,
+    input: MeshInput,
+}
+
+// Lit meshes, the object uniforms are shared with the unlit pipeline and the scene uniforms are written once a frame
+#[derive(Debug)]
+pub(crate) struct PbrPipeline {
+    targets: Targets,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    pipeline: RenderPipeline,
+    skinned_pipeline_layout: PipelineLayout,
+    skinned_pipeline: RenderPipeline,
+    instanced_pipeline: RenderPipeline,
+    transparent_pipeline: RenderPipeline,
+    transparent_skinned_pipeline: RenderPipeline,
+    transparent_instanced_pipeline: RenderPipeline,
+    scene_buffer: Buffer,
+    lights_buffer: Buffer,
+    scene_bind_group_layout: BindGroupLayout,
+    scene_bind_group: BindGroup,
+    draws: Vec<MaterialDraw>,
+    // Indices of the transparent draws from the furthest to the nearest
+    transparent_order: Vec<usize>,
+    lights: Vec<Light>,
+    fog: Option<Fog>,
+}
+
+impl PbrPipeline {
+    pub(crate) fn new(
+        device: &Device,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        object_layout: &BindGroupLayout,
+        material_layout: &BindGroupLayout,
+        shadow_map: &ShadowMap,
+        joint_layout: &BindGroupLayout,
+    ) -> PbrPipeline {
+        let scene_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("pbr_scene_buffer"),
+            size: size_of::<SceneUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let lights_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("pbr_lights_buffer"),
+            size: size_of::<LightsUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let scene_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("pbr_scene_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::VERTEX | ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<SceneUniforms>() as _),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Depth,
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: true },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<LightsUniforms>() as _),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let scene_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("pbr_scene_bind_group"),
+            layout: &scene_bind_group_layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: scene_buffer.as_entire_binding() },
+                BindGroupEntry { binding: 1, resource: BindingResource::TextureView(shadow_map.view()) },
+                BindGroupEntry { binding: 2, resource: BindingResource::Sampler(shadow_map.sampler()) },
+                BindGroupEntry { binding: 3, resource: lights_buffer.as_entire_binding() },
+            ],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("pbr_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(concat!(
+                include_str!("fog.wgsl"),
+                include_str!("mesh.wgsl"),
+                include_str!("pbr.wgsl")
+            ))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("pbr_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &scene_bind_group_layout, material_layout],
+            push_constant_ranges: &[],
+        });
+
+        let skinned_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("pbr_skinned_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &scene_bind_group_layout, material_layout, joint_layout],
+            push_constant_ranges: &[],
+        });
+
+        let targets = Targets { format, depth_format, sample_count: 1, debug_view: None };
+        let create =
+            |layout, variant, transparent| create_pipeline(device, layout, &shader_module, &targets, variant, transparent);
+        let pipeline = create(&pipeline_layout, MeshVariant::Plain, false);
+        let skinned_pipeline = create(&skinned_pipeline_layout, MeshVariant::Skinned, false);
+        let instanced_pipeline = create(&pipeline_layout, MeshVariant::Instanced, false);
+        let transparent_pipeline = create(&pipeline_layout, MeshVariant::Plain, true);
+        let transparent_skinned_pipeline = create(&skinned_pipeline_layout, MeshVariant::Skinned, true);
+        let transparent_instanced_pipeline = create(&pipeline_layout, MeshVariant::Instanced, true);
+
+        PbrPipeline {
+            targets,
+            shader_module,
+            pipeline_layout,
+            pipeline,
+            skinned_pipeline_layout,
+            skinned_pipeline,
+            instanced_pipeline,
+            transparent_pipeline,
+            transparent_skinned_pipeline,
+            transparent_instanced_pipeline,
+            scene_buffer,
+            lights_buffer,
+            scene_bind_group_layout,
+            scene_bind_group,
+            draws: vec![],
+            transparent_order: vec![],
+            lights: vec![],
+            fog: None,
+        }
+    }
+
+    pub(crate) fn set_fog(&mut self, fog: Option<Fog>) {
+        self.fog = fog;
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.targets.sample_count = sample_count;
+        self.create_pipelines(device);
+    }
+
+    pub(crate) fn set_debug_view(&mut self, device: &Device, debug_view: Option<DebugView>) {
+        self.targets.debug_view = debug_view;
+        self.create_pipelines(device);
+    }
+
+    fn create_pipelines(&mut self, device: &Device) {
+        let (shader_module, targets) = (&self.shader_module, &self.targets);
+        let create =
+            |layout, variant, transparent| create_pipeline(device, layout, shader_module, targets, variant, transparent);
+        self.pipeline = create(&self.pipeline_layout, MeshVariant::Plain, false);
+        self.skinned_pipeline = create(&self.skinned_pipeline_layout, MeshVariant::Skinned, false);
+        self.instanced_pipeline = create(&self.pipeline_layout, MeshVariant::Instanced, false);
+        self.transparent_pipeline = create(&self.pipeline_layout, MeshVariant::Plain, true);
+        self.transparent_skinned_pipeline = create(&self.skinned_pipeline_layout, MeshVariant::Skinned, true);
+        self.transparent_instanced_pipeline = create(&self.pipeline_layout, MeshVariant::Instanced, true);
+    }
+
+    pub(crate) fn push(&mut self, mesh: &GpuMesh, material: &GpuMaterial, uniform: u32, input: MeshInput) {
+        if mesh.accepts(&input) {
+            self.draws.push(MaterialDraw { mesh: mesh.clone(), material: material.clone(), uniform, input });
+        }
+    }
+
+    // Distances from the camera indexed by object uniform, the transparent draws are ordered by them until sorted again
+    pub(crate) fn sort_transparent(&mut self, distances: &[f32]) {
+        let draws = &self.draws;
+        self.transparent_order.clear();
+        self.transparent_order.extend((0..draws.len()).filter(|i| draws[*i].material.transparent()));
+        let distance = |i: &usize| distances.get(draws[*i].uniform as usize).copied().unwrap_or(0.);
+        self.transparent_order.sort_by(|a, b| distance(b).partial_cmp(&distance(a)).unwrap_or(Ordering::Equal));
+    }
+
+    pub(crate) fn push_light(&mut self, light: Light) {
+        if self.lights.len() < MAX_LIGHTS {
+            self.lights.push(light);
+        }
+    }
+
+    pub(crate) fn prepare(
+        &mut self,
+        queue: &Queue,
+        view_projection: Matrix4<f32>,
+        camera_position: Point3<f32>,
+        light: Option<DirectionalLight>,
+        ambient: [f32; 3],
+        light_view_projection: Option<Matrix4<f32>>,
+    ) -> usize {
+        // A w of zero switches the light off in the shader
+        let (light_direction, light_color) = match light {
+            Some(light) => {
+                let direction = light.direction.normalize();
+                ([direction.x, direction.y, direction.z, 1.], radiance(light.color, light.intensity))
+            },
+            None => ([0., -1., 0., 0.], [0.; 4]),
+        };
+
+        let shadow = match (light.and_then(|light| light.shadow), light_view_projection) {
+            (Some(shadow), Some(_)) => [shadow.bias, shadow.normal_bias, shadow.softness / SHADOW_MAP_SIZE as f32, 1.],
+            _ => [0.; 4],
+        };
+
+        let uniforms = SceneUniforms {
+            view_projection: view_projection.into(),
+            camera_position: [camera_position.x, camera_position.y, camera_position.z, 1.],
+            light_direction,
+            light_color,
+            ambient: [ambient[0], ambient[1], ambient[2], 0.],
+            light_view_projection: light_view_projection.unwrap_or_else(Matrix4::identity).into(),
+            shadow,
+            fog: FogUniforms::new(self.fog),
+        };
+        queue.write_buffer(&self.scene_buffer, 0, bytemuck::bytes_of(&uniforms));
+
+        let mut lights = LightsUniforms::zeroed();
+        lights.count = self.lights.len() as u32;
+        for (uniform, light) in lights.lights.iter_mut().zip(&self.lights) {
+            *uniform = match *light {
+                Light::Point(light) => LightUniform {
+                    position: [light.position.x, light.position.y, light.position.z, light.range],
+                    color: radiance(light.color, light.intensity),
+                    direction: [0., -1., 0., 0.],
+                    cone: [0., 1., 0., 0.],
+                },
+                Light::Spot(light) => {
+                    let direction = light.direction.normalize();
+                    let cos_outer = light.outer_angle.cos();
+                    let scale = 1. / (light.inner_angle.cos() - cos_outer).max(0.0001);
+                    LightUniform {
+                        position: [light.position.x, light.position.y, light.position.z, light.range],
+                        color: radiance(light.color, light.intensity),
+                        direction: [direction.x, direction.y, direction.z, 0.],
+                        cone: [scale, -cos_outer * scale, 0., 0.],
+                    }
+                },
+            };
+        }
+        queue.write_buffer(&self.lights_buffer, 0, bytemuck::bytes_of(&lights));
+        2
+    }
+
+    // Custom mesh shaders are lit by the same scene uniforms
+    pub(crate) fn scene_layout(&self) -> &BindGroupLayout {
+        &self.scene_bind_group_layout
+    }
+
+    pub(crate) fn scene_bind_group(&self) -> &BindGroup {
+        &self.scene_bind_group
+    }
+
+    pub(crate) fn render<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        object_bind_group: &'a BindGroup,
+        joints: &'a JointBuffer,
+        instances: &'a InstanceBuffer,
+        visible: &[bool],
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        let pipelines = [
+            (MeshVariant::Plain, &self.pipeline),
+            (MeshVariant::Skinned, &self.skinned_pipeline),
+            (MeshVariant::Instanced, &self.instanced_pipeline),
+        ];
+        for (variant, pipeline) in pipelines.iter() {
+            let mut draws = self
+                .draws
+                .iter()
+                .filter(|draw| !draw.material.transparent())
+                .filter(|draw| draw.input.variant() == *variant && visible[draw.uniform as usize])
+                .peekable();
+            if draws.peek().is_none() {
+                continue;
+            }
+
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(1, &self.scene_bind_group, &[]);
+            stats.pipeline_switches += 1;
+            stats.texture_binds += 1;
+            for draw in draws {
+                let offset = (draw.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+                render_pass.set_bind_group(0, object_bind_group, &[offset]);
+                render_pass.set_bind_group(2, draw.material.bind_group(), &[]);
+                stats += draw.mesh.draw(render_pass, &draw.input, joints, 3, instances);
+                stats.texture_binds += 1;
+            }
+        }
+
+        // The pipeline changes with the variant, which can be every draw when they are interleaved
+        render_pass.set_bind_group(1, &self.scene_bind_group, &[]);
+        stats.texture_binds += 1;
+        let mut current = None;
+        for draw in self.transparent_order.iter().map(|i| &self.draws[*i]) {
+            if !visible[draw.uniform as usize] {
+                continue;
+            }
+            let variant = draw.input.variant();
+            if current != Some(variant) {
+                current = Some(variant);
+                render_pass.set_pipeline(match variant {
+                    MeshVariant::Plain => &self.transparent_pipeline,
+                    MeshVariant::Skinned => &self.transparent_skinned_pipeline,
+                    MeshVariant::Instanced => &self.transparent_instanced_pipeline,
+                });
+                stats.pipeline_switches += 1;
+            }
+            let offset = (draw.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(0, object_bind_group, &[offset]);
+            render_pass.set_bind_group(2, draw.material.bind_group(), &[]);
+            stats += draw.mesh.draw(render_pass, &draw.input, joints, 3, instances);
+            stats.texture_binds += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.draws.clear();
+        self.transparent_order.clear();
+        self.lights.clear();
+    }
+}
+
+fn radiance(color: [f32; 3], intensity: f32) -> [f32; 4] {
+    [color[0] * intensity, color[1] * intensity, color[2] * intensity, 0.]
+}
+
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    targets: &Targets,
+    variant: MeshVariant,
+    transparent: bool,
+) -> RenderPipeline {
+    // Overdraw adds up every fragment drawn to a pixel whether it ends up hidden or not
+    let overdraw = targets.debug_view == Some(DebugView::Overdraw);
+    let blend = match overdraw {
+        true => BlendState {
+            color: BlendComponent {
+                src_factor: BlendFactor::One,
+                dst_factor: BlendFactor::One,
+                operation: BlendOperation::Add,
+            },
+            alpha: BlendComponent::REPLACE,
+        },
+        false if transparent => BlendState::ALPHA_BLENDING,
+        false => BlendState::REPLACE,
+    };
+    let (polygon_mode, cull_mode) = match targets.debug_view {
+        Some(DebugView::Wireframe) => (PolygonMode::Line, None),
+        _ => (PolygonMode::Fill, Some(Face::Back)),
+    };
+    let fragment_entry_point = match targets.debug_view {
+        Some(DebugView::Normals) => "normals",
+        Some(DebugView::Overdraw) => "overdraw",
+        Some(DebugView::Depth) => "depth",
+        Some(DebugView::Wireframe) | None => "main",
+    };
+
+    let vertex_attributes = vertex_attr_array![
+        0 => Float32x3,
+        1 => Float32x2,
+        2 => Float32x3,
+    ];
+    let mut buffers = vec![VertexBufferLayout {
+        array_stride: size_of::<Vertex>() as wgpu::BufferAddress,
+        step_mode: InputStepMode::Vertex,
+        attributes: &vertex_attributes,
+    }];
+    buffers.extend(variant.extra_buffer());
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("pbr_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: variant.entry_point(), buffers: &buffers },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode,
+            clamp_depth: false,
+            polygon_mode,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: targets.depth_format,
+            depth_write_enabled: !overdraw && !transparent,
+            depth_compare: if overdraw { CompareFunction::Always } else { CompareFunction::GreaterEqual },
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: targets.sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: fragment_entry_point,
+            targets: &[ColorTargetState { format: targets.format, blend: Some(blend), write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
@@ -0,0 +1,194 @@
+struct VertexOutput {
+    [[location(0)]] world_pos: vec3<f32>;
+    [[location(1)]] tex_coord: vec2<f32>;
+    [[location(2)]] normal: vec3<f32>;
+    [[location(3)]] color: vec4<f32>;
+    [[location(4)]] layer: f32;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+struct SkinInput {
+    [[location(3)]] joints: vec4<u32>;
+    [[location(4)]] weights: vec4<f32>;
+};
+
+[[block]]
+struct JointUniforms {
+    matrices: array<mat4x4<f32>, 128>;
+};
+
+[[group(3), binding(0)]]
+var<uniform> joints: JointUniforms;
+
+struct InstanceInput {
+    [[location(5)]] model_0: vec4<f32>;
+    [[location(6)]] model_1: vec4<f32>;
+    [[location(7)]] model_2: vec4<f32>;
+    [[location(8)]] model_3: vec4<f32>;
+    [[location(9)]] normal_0: vec4<f32>;
+    [[location(10)]] normal_1: vec4<f32>;
+    [[location(11)]] normal_2: vec4<f32>;
+    [[location(12)]] normal_3: vec4<f32>;
+    [[location(13)]] color: vec4<f32>;
+    // The layer of the albedo layers in x
+    [[location(14)]] layer: vec4<f32>;
+};
+
+fn vertex(pos: vec4<f32>, tex_coord: vec2<f32>, normal: vec4<f32>, color: vec4<f32>, layer: f32) -> VertexOutput {
+    var out: VertexOutput;
+    out.world_pos = (object.model * pos).xyz;
+    out.tex_coord = tex_coord;
+    out.normal = (object.normal * normal).xyz;
+    out.color = color;
+    out.layer = layer;
+    out.pos = object.model_view_projection * pos;
+    return out;
+}
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> VertexOutput {
+    let white = vec4<f32>(1.0, 1.0, 1.0, 1.0);
+    return vertex(vec4<f32>(in.pos, 1.0), in.tex_coord, vec4<f32>(in.normal, 0.0), white, 0.0);
+}
+
+// The object uniforms of instanced draws hold only the view and projection
+[[stage(vertex)]]
+fn instanced(in: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    let normal = mat4x4<f32>(instance.normal_0, instance.normal_1, instance.normal_2, instance.normal_3);
+    let pos = model * vec4<f32>(in.pos, 1.0);
+    return vertex(pos, in.tex_coord, normal * vec4<f32>(in.normal, 0.0), instance.color, instance.layer.x);
+}
+
+// Blends the vertex between the joints moving it, the joint matrices are relative to the model
+fn skin(v: vec4<f32>, skin: SkinInput) -> vec4<f32> {
+    return joints.matrices[skin.joints.x] * v * skin.weights.x
+        + joints.matrices[skin.joints.y] * v * skin.weights.y
+        + joints.matrices[skin.joints.z] * v * skin.weights.z
+        + joints.matrices[skin.joints.w] * v * skin.weights.w;
+}
+
+[[stage(vertex)]]
+fn skinned(in: VertexInput, skin_input: SkinInput) -> VertexOutput {
+    let pos = skin(vec4<f32>(in.pos, 1.0), skin_input);
+    let normal = skin(vec4<f32>(in.normal, 0.0), skin_input);
+    return vertex(pos, in.tex_coord, normal, vec4<f32>(1.0, 1.0, 1.0, 1.0), 0.0);
+}
+
+// Meshes carry no tangents, the tangent frame is rebuilt from screen space derivatives
+fn perturb_normal(normal: vec3<f32>, world_pos: vec3<f32>, tex_coord: vec2<f32>, sample: vec3<f32>) -> vec3<f32> {
+    let dp1 = dpdx(world_pos);
+    let dp2 = dpdy(world_pos);
+    let duv1 = dpdx(tex_coord);
+    let duv2 = dpdy(tex_coord);
+    let dp2perp = cross(dp2, normal);
+    let dp1perp = cross(normal, dp1);
+    let tangent = dp2perp * duv1.x + dp1perp * duv2.x;
+    let bitangent = dp2perp * duv1.y + dp1perp * duv2.y;
+    let scale = inverseSqrt(max(max(dot(tangent, tangent), dot(bitangent, bitangent)), 0.00000001));
+    let frame = mat3x3<f32>(tangent * scale, bitangent * scale, normal);
+    return normalize(frame * sample);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (3.14159265 * d * d);
+}
+
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    let gv = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let gl = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return gv * gl;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0, 1.0, 1.0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Outgoing radiance from one light for the given surface, shared by every light type
+fn brdf(
+    n: vec3<f32>,
+    v: vec3<f32>,
+    l: vec3<f32>,
+    radiance: vec3<f32>,
+    albedo: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+) -> vec3<f32> {
+    let h = normalize(v + l);
+    let n_dot_v = max(dot(n, v), 0.0001);
+    let n_dot_l = max(dot(n, l), 0.0);
+    let f0 = mix(vec3<f32>(0.04, 0.04, 0.04), albedo, metallic);
+    let f = fresnel_schlick(max(dot(h, v), 0.0), f0);
+    let specular = distribution_ggx(max(dot(n, h), 0.0), roughness) * geometry_smith(n_dot_v, n_dot_l, roughness) * f
+        / (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+    let diffuse = (vec3<f32>(1.0, 1.0, 1.0) - f) * (1.0 - metallic) * albedo / 3.14159265;
+    return (diffuse + specular) * radiance * n_dot_l;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    if (lod_hidden(in.pos)) {
+        discard;
+    }
+
+    var base: vec4<f32> = textureSample(albedo_map, material_sampler, in.tex_coord);
+    if (material.parameters.w > 0.5) {
+        // The layer is the same over the whole mesh, rounding keeps interpolation from landing it on the next one
+        base = textureSample(albedo_layers, material_sampler, in.tex_coord, i32(round(in.layer)));
+    }
+    let albedo = base * material.albedo_factor * in.color;
+    let metallic_roughness = textureSample(metallic_roughness_map, material_sampler, in.tex_coord);
+    let metallic = metallic_roughness.b * material.parameters.x;
+    let roughness = clamp(metallic_roughness.g * material.parameters.y, 0.04, 1.0);
+    let emissive = textureSample(emissive_map, material_sampler, in.tex_coord).rgb * material.emissive_factor.rgb;
+
+    let normal_sample = textureSample(normal_map, material_sampler, in.tex_coord).xyz * 2.0 - vec3<f32>(1.0, 1.0, 1.0);
+    let normal_sample = vec3<f32>(normal_sample.xy * material.parameters.z, normal_sample.z);
+    let n = perturb_normal(normalize(in.normal), in.world_pos, in.tex_coord, normal_sample);
+    let v = normalize(scene.camera_position.xyz - in.world_pos);
+
+    var color: vec3<f32> = scene.ambient.rgb * albedo.rgb + emissive;
+    if (scene.light_direction.w > 0.5) {
+        let l = normalize(-scene.light_direction.xyz);
+        let shadow = shadow_factor(in.world_pos, normalize(in.normal));
+        color = color + brdf(n, v, l, scene.light_color.rgb, albedo.rgb, metallic, roughness) * shadow;
+    }
+
+    var i: u32 = 0u;
+    loop {
+        if (i >= lights.count) {
+            break;
+        }
+        let light = lights.lights[i];
+        let l = normalize(light.position.xyz - in.world_pos);
+        let radiance = light.color.rgb * light_attenuation(light, in.world_pos);
+        color = color + brdf(n, v, l, radiance, albedo.rgb, metallic, roughness);
+        continuing {
+            i = i + 1u;
+        }
+    }
+
+    return vec4<f32>(apply_fog(color, in.world_pos), albedo.a);
+}
+
+// Debug views, each replaces the lighting above for every mesh
+[[stage(fragment)]]
+fn normals(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(normalize(in.normal) * 0.5 + vec3<f32>(0.5, 0.5, 0.5), 1.0);
+}
+
+[[stage(fragment)]]
+fn overdraw(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(0.1, 0.04, 0.02, 1.0);
+}
+
+// Depth is reversed so near surfaces are bright, it's stretched so distant ones don't all end up black
+[[stage(fragment)]]
+fn depth(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let depth = pow(in.pos.z, 0.25);
+    return vec4<f32>(depth, depth, depth, 1.0);
+}
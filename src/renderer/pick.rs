@@ -0,0 +1,416 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+use std::num::NonZeroU32;
+
+use wgpu::vertex_attr_array;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferAddress;
+use wgpu::BufferBinding;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Color;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CommandEncoder;
+use wgpu::CommandEncoderDescriptor;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::Extent3d;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::ImageCopyBuffer;
+use wgpu::ImageCopyTexture;
+use wgpu::ImageDataLayout;
+use wgpu::InputStepMode;
+use wgpu::LoadOp;
+use wgpu::Maintain;
+use wgpu::MapMode;
+use wgpu::MultisampleState;
+use wgpu::Operations;
+use wgpu::Origin3d;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPassColorAttachment;
+use wgpu::RenderPassDepthStencilAttachment;
+use wgpu::RenderPassDescriptor;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::Texture;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+use wgpu::BIND_BUFFER_ALIGNMENT;
+use wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+use super::instance::InstanceBuffer;
+use super::mesh::MeshInput;
+use super::mesh::MeshVariant;
+use super::skin::JointBuffer;
+use super::stats::FrameStats;
+use super::GpuMesh;
+use crate::model::Vertex;
+
+// Pickable draws past this in one frame are dropped
+const MAX_PICKABLE: u64 = 1 << 14;
+const ID_FORMAT: TextureFormat = TextureFormat::R32Uint;
+const DEPTH_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+// Written wherever nothing pickable was drawn
+const NO_ID: u32 = u32::MAX;
+
+#[derive(Debug)]
+struct Pickable {
+    mesh: GpuMesh,
+    uniform: u32,
+    input: MeshInput,
+    id: u32,
+}
+
+#[derive(Debug)]
+struct PickTarget {
+    size: [u32; 2],
+    texture: Texture,
+    view: TextureView,
+    _depth: Texture,
+    depth_view: TextureView,
+}
+
+// The id of the closest pickable mesh under every pixel of the last frame, drawn with the same cameras as the scene
+// while picking is enabled
+#[derive(Debug)]
+pub(crate) struct ObjectPicker {
+    pipeline: RenderPipeline,
+    skinned_pipeline: RenderPipeline,
+    instanced_pipeline: RenderPipeline,
+    id_buffer: Buffer,
+    id_bind_group: BindGroup,
+    readback: Buffer,
+    target: Option<PickTarget>,
+    pickables: Vec<Pickable>,
+    enabled: bool,
+}
+
+impl ObjectPicker {
+    pub(crate) fn new(device: &Device, object_layout: &BindGroupLayout, joint_layout: &BindGroupLayout) -> ObjectPicker {
+        let id_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("pick_id_buffer"),
+            size: MAX_PICKABLE * BIND_BUFFER_ALIGNMENT,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let id_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("pick_id_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<u32>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let id_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("pick_id_bind_group"),
+            layout: &id_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: &id_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(size_of::<u32>() as _),
+                }),
+            }],
+        });
+
+        // One texel is copied at a time, but a copied row still has to be this long
+        let readback = device.create_buffer(&BufferDescriptor {
+            label: Some("pick_readback_buffer"),
+            size: COPY_BYTES_PER_ROW_ALIGNMENT as u64,
+            usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("pick_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("pick.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("pick_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &id_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let skinned_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("pick_skinned_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &id_bind_group_layout, joint_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader_module, MeshVariant::Plain);
+        let skinned_pipeline = create_pipeline(device, &skinned_pipeline_layout, &shader_module, MeshVariant::Skinned);
+        let instanced_pipeline = create_pipeline(device, &pipeline_layout, &shader_module, MeshVariant::Instanced);
+
+        ObjectPicker {
+            pipeline,
+            skinned_pipeline,
+            instanced_pipeline,
+            id_buffer,
+            id_bind_group,
+            readback,
+            target: None,
+            pickables: vec![],
+            enabled: false,
+        }
+    }
+
+    // The id texture is dropped while disabled
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.target = None;
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn push(&mut self, mesh: &GpuMesh, uniform: u32, input: MeshInput, id: Option<u32>) {
+        let id = match id {
+            Some(id) if self.enabled && mesh.accepts(&input) => id,
+            _ => return,
+        };
+        if (self.pickables.len() as u64) < MAX_PICKABLE {
+            self.pickables.push(Pickable { mesh: mesh.clone(), uniform, input, id });
+        }
+    }
+
+    // Keeps the id texture the size of the window, returns the number of writes
+    pub(crate) fn prepare(&mut self, device: &Device, queue: &Queue, size: [u32; 2]) -> usize {
+        if !self.enabled {
+            return 0;
+        }
+
+        if self.target.as_ref().map_or(true, |target| target.size != size) {
+            self.target = Some(create_target(device, size));
+        }
+
+        if self.pickables.is_empty() {
+            return 0;
+        }
+        let stride = BIND_BUFFER_ALIGNMENT as usize;
+        let mut ids = vec![0; self.pickables.len() * stride];
+        for (i, pickable) in self.pickables.iter().enumerate() {
+            ids[i * stride..i * stride + size_of::<u32>()].copy_from_slice(&pickable.id.to_ne_bytes());
+        }
+        queue.write_buffer(&self.id_buffer, 0, &ids);
+        1
+    }
+
+    // Once for every viewport after the uniforms are written for its camera, only the first one clears the whole
+    // texture. The rectangle is in pixels
+    pub(crate) fn render(
+        &self,
+        encoder: &mut CommandEncoder,
+        object_bind_group: &BindGroup,
+        joints: &JointBuffer,
+        instances: &InstanceBuffer,
+        rect: Option<[u32; 4]>,
+        clear: bool,
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        let target = match &self.target {
+            Some(target) if self.enabled => target,
+            _ => return stats,
+        };
+
+        let (color_load, depth_load) = match clear {
+            true => (LoadOp::Clear(Color { r: NO_ID as f64, g: 0., b: 0., a: 0. }), LoadOp::Clear(0.0)),
+            false => (LoadOp::Load, LoadOp::Load),
+        };
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("pick_pass"),
+            color_attachments: &[RenderPassColorAttachment {
+                view: &target.view,
+                resolve_target: None,
+                ops: Operations { load: color_load, store: true },
+            }],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &target.depth_view,
+                depth_ops: Some(Operations { load: depth_load, store: true }),
+                stencil_ops: None,
+            }),
+        });
+
+        if let Some(rect) = rect {
+            if rect[2] == 0 || rect[3] == 0 {
+                return stats;
+            }
+            render_pass.set_viewport(rect[0] as f32, rect[1] as f32, rect[2] as f32, rect[3] as f32, 0., 1.);
+            render_pass.set_scissor_rect(rect[0], rect[1], rect[2], rect[3]);
+        }
+
+        for (i, pickable) in self.pickables.iter().enumerate() {
+            render_pass.set_pipeline(match pickable.input.variant() {
+                MeshVariant::Plain => &self.pipeline,
+                MeshVariant::Skinned => &self.skinned_pipeline,
+                MeshVariant::Instanced => &self.instanced_pipeline,
+            });
+
+            let offset = (pickable.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(0, object_bind_group, &[offset]);
+            let id_offset = (i as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(1, &self.id_bind_group, &[id_offset]);
+            stats += pickable.mesh.draw(&mut render_pass, &pickable.input, joints, 2, instances);
+            stats.pipeline_switches += 1;
+        }
+        stats
+    }
+
+    // Waits for the gpu, so it is meant for clicks rather than every frame. The position is in pixels from the top left
+    pub(crate) fn pick(&self, device: &Device, queue: &Queue, position: [u32; 2]) -> Option<u32> {
+        let target = self.target.as_ref()?;
+        if position[0] >= target.size[0] || position[1] >= target.size[1] {
+            return None;
+        }
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: Some("pick_encoder") });
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture {
+                texture: &target.texture,
+                mip_level: 0,
+                origin: Origin3d { x: position[0], y: position[1], z: 0 },
+            },
+            ImageCopyBuffer {
+                buffer: &self.readback,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(COPY_BYTES_PER_ROW_ALIGNMENT),
+                    rows_per_image: NonZeroU32::new(1),
+                },
+            },
+            Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+        );
+        queue.submit(Some(encoder.finish()));
+
+        let slice = self.readback.slice(0..size_of::<u32>() as BufferAddress);
+        let map = slice.map_async(MapMode::Read);
+        device.poll(Maintain::Wait);
+        pollster::block_on(map).ok()?;
+        let data = slice.get_mapped_range();
+        let id = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]);
+        drop(data);
+        self.readback.unmap();
+
+        Some(id).filter(|id| *id != NO_ID)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.pickables.clear();
+    }
+}
+
+fn create_target(device: &Device, size: [u32; 2]) -> PickTarget {
+    let extent = Extent3d { width: size[0].max(1), height: size[1].max(1), depth_or_array_layers: 1 };
+    let texture = device.create_texture(&TextureDescriptor {
+        label: Some("pick_texture"),
+        size: extent,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: ID_FORMAT,
+        usage: TextureUsage::RENDER_ATTACHMENT | TextureUsage::COPY_SRC,
+    });
+    let depth = device.create_texture(&TextureDescriptor {
+        label: Some("pick_depth_texture"),
+        size: extent,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: DEPTH_FORMAT,
+        usage: TextureUsage::RENDER_ATTACHMENT,
+    });
+
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    let depth_view = depth.create_view(&TextureViewDescriptor::default());
+    PickTarget { size, texture, view, _depth: depth, depth_view }
+}
+
+// Depth is reversed like the scene's, so the closest mesh wins the same way it does on screen
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    variant: MeshVariant,
+) -> RenderPipeline {
+    let vertex_attributes = vertex_attr_array![0 => Float32x3];
+    let mut buffers = vec![VertexBufferLayout {
+        array_stride: size_of::<Vertex>() as wgpu::BufferAddress,
+        step_mode: InputStepMode::Vertex,
+        attributes: &vertex_attributes,
+    }];
+    buffers.extend(variant.extra_buffer());
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("pick_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: variant.entry_point(), buffers: &buffers },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            // Both faces so open meshes and planes can be picked from either side
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format: ID_FORMAT, blend: None, write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
@@ -0,0 +1,624 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Color;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CommandEncoder;
+use wgpu::Device;
+use wgpu::Extent3d;
+use wgpu::FilterMode;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::LoadOp;
+use wgpu::MultisampleState;
+use wgpu::Operations;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPassColorAttachment;
+use wgpu::RenderPassDescriptor;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::TextureViewDimension;
+use wgpu::VertexState;
+
+use super::lut::ColorGrading;
+use super::lut::ColorLut;
+
+const BLOOM_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+// Render targets that aren't hdr are tonemapped to this
+pub(crate) const LDR_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bloom {
+    // Brightness after exposure above which pixels start to glow
+    pub threshold: f32,
+    pub intensity: f32,
+}
+
+impl Default for Bloom {
+    fn default() -> Bloom {
+        Bloom { threshold: 0.8, intensity: 0.5 }
+    }
+}
+
+// Darkens creases and corners the ambient light would have trouble reaching
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ssao {
+    // In world units, how far around each point is looked at
+    pub radius: f32,
+    pub intensity: f32,
+    // Keeps flat surfaces from darkening themselves
+    pub bias: f32,
+}
+
+impl Default for Ssao {
+    fn default() -> Ssao {
+        Ssao { radius: 0.5, intensity: 1., bias: 0.025 }
+    }
+}
+
+// Blurs what is nearer or further than the focal distance
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DepthOfField {
+    // In world units from the camera
+    pub focal_distance: f32,
+    // How quickly things blur away from the focal distance, zero keeps everything sharp
+    pub aperture: f32,
+    // In pixels, the blur of the furthest out of focus points
+    pub max_blur: f32,
+}
+
+impl Default for DepthOfField {
+    fn default() -> DepthOfField {
+        DepthOfField { focal_distance: 10., aperture: 0.5, max_blur: 8. }
+    }
+}
+
+// Reflections found by marching through the depth of the scene, where a ray leaves the screen or misses the environment
+// is reflected instead. Only the roughness and metallic factors of materials are read, not their maps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ssr {
+    // Samples along each ray, more find thinner things and cost more
+    pub steps: u32,
+    // In world units, how far a ray goes before it gives up
+    pub max_distance: f32,
+    // In world units, how far behind a surface a ray can pass and still hit it
+    pub thickness: f32,
+    // Materials this rough or rougher don't reflect, reflections fade out towards it
+    pub roughness_cutoff: f32,
+    pub intensity: f32,
+}
+
+impl Default for Ssr {
+    fn default() -> Ssr {
+        Ssr { steps: 32, max_distance: 20., thickness: 0.5, roughness_cutoff: 0.6, intensity: 1. }
+    }
+}
+
+// How scene colors past one are brought into the range of the screen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    // Clips every channel at one
+    None,
+    Reinhard,
+    // Narkowicz's fit of the aces filmic curve
+    Aces,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PostEffects {
+    pub bloom: Option<Bloom>,
+    // Scene color is multiplied by this before tonemapping
+    pub exposure: f32,
+    pub tonemapper: Tonemapper,
+    // For the camera set with Renderer::set_view, viewports have their own
+    pub ssao: Option<Ssao>,
+    pub ssr: Option<Ssr>,
+    pub depth_of_field: Option<DepthOfField>,
+}
+
+impl Default for PostEffects {
+    fn default() -> PostEffects {
+        PostEffects {
+            bloom: Some(Bloom::default()),
+            exposure: 1.,
+            tonemapper: Tonemapper::Aces,
+            ssao: None,
+            ssr: None,
+            depth_of_field: None,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct PostUniforms {
+    direction: [f32; 2],
+    threshold: f32,
+    intensity: f32,
+    exposure: f32,
+    tonemapper: f32,
+    premultiply: f32,
+    // Below zero without color grading
+    grading: f32,
+}
+
+#[derive(Debug)]
+struct Target {
+    texture: wgpu::Texture,
+    view: TextureView,
+}
+
+impl Target {
+    fn new(device: &Device, size: [u32; 2], format: TextureFormat) -> Target {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("post_target"),
+            size: Extent3d { width: size[0].max(1), height: size[1].max(1), depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED | TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        Target { texture, view }
+    }
+}
+
+// The render graph draws the screen to an hdr target, which is brought onto the swapchain here.
+// Bloom is extracted from it at half resolution and blurred, then combined with it onto the screen
+#[derive(Debug)]
+pub(crate) struct PostProcessor {
+    layout: BindGroupLayout,
+    sampler: Sampler,
+    bright: RenderPipeline,
+    blur: RenderPipeline,
+    composite: RenderPipeline,
+    ldr_composite: RenderPipeline,
+    hdr_format: TextureFormat,
+    size: [u32; 2],
+    scene: Target,
+    // The scene after ssao, reflections and depth of field, read in its place on frames they are used
+    processed: Target,
+    use_processed: bool,
+    bloom: [Target; 2],
+    // Bright pass, horizontal blur, vertical blur and composite, then the bright pass and composite reading the
+    // processed scene. Each step has its own uniforms since they are all written before the frame is recorded
+    uniform_buffers: Vec<Buffer>,
+    bind_groups: Vec<BindGroup>,
+    // Render targets are tonemapped with the same effects but without bloom
+    target_uniform_buffer: Buffer,
+    effects: Option<PostEffects>,
+    // Transparent windows are composited by the desktop, which expects the color multiplied by the alpha
+    premultiplied: bool,
+    // The luts blended between, the identity stands in for missing ones
+    lut_layout: BindGroupLayout,
+    lut_sampler: Sampler,
+    lut_bind_group: BindGroup,
+    identity_lut: ColorLut,
+    grading: Option<ColorGrading>,
+}
+
+impl PostProcessor {
+    pub(crate) fn new(
+        device: &Device,
+        queue: &Queue,
+        hdr_format: TextureFormat,
+        output_format: TextureFormat,
+        size: [u32; 2],
+    ) -> PostProcessor {
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("post_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<PostUniforms>() as _),
+                    },
+                    count: None,
+                },
+                texture_entry(1),
+                texture_entry(2),
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: false },
+                    count: None,
+                },
+            ],
+        });
+
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("post_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("post_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("post.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let lut_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D3,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let lut_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("post_lut_bind_group_layout"),
+            entries: &[
+                lut_entry(0),
+                lut_entry(1),
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: false },
+                    count: None,
+                },
+            ],
+        });
+        let lut_sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("post_lut_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+        let identity_lut = ColorLut::identity(device, queue);
+        let lut_bind_group = create_lut_bind_group(device, &lut_layout, &lut_sampler, &identity_lut, &identity_lut);
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("post_pipeline_layout"),
+            bind_group_layouts: &[&layout, &lut_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = |entry_point, format| create_pipeline(device, &pipeline_layout, &shader_module, entry_point, format);
+        let bright = pipeline("bright", BLOOM_FORMAT);
+        let blur = pipeline("blur", BLOOM_FORMAT);
+        let composite = pipeline("composite", output_format);
+        let ldr_composite = pipeline("composite", LDR_FORMAT);
+
+        let uniform_buffers = (0..6)
+            .map(|_| {
+                device.create_buffer(&BufferDescriptor {
+                    label: Some("post_uniform_buffer"),
+                    size: size_of::<PostUniforms>() as u64,
+                    usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+        let target_uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("post_target_uniform_buffer"),
+            size: size_of::<PostUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let half = [size[0] / 2, size[1] / 2];
+        let mut post = PostProcessor {
+            layout,
+            sampler,
+            bright,
+            blur,
+            composite,
+            ldr_composite,
+            hdr_format,
+            size,
+            scene: Target::new(device, size, hdr_format),
+            processed: Target::new(device, size, hdr_format),
+            use_processed: false,
+            bloom: [Target::new(device, half, BLOOM_FORMAT), Target::new(device, half, BLOOM_FORMAT)],
+            uniform_buffers,
+            bind_groups: vec![],
+            target_uniform_buffer,
+            effects: None,
+            premultiplied: false,
+            lut_layout,
+            lut_sampler,
+            lut_bind_group,
+            identity_lut,
+            grading: None,
+        };
+        post.create_bind_groups(device);
+        post
+    }
+
+    pub(crate) fn resize(&mut self, device: &Device, size: [u32; 2]) {
+        let half = [size[0] / 2, size[1] / 2];
+        self.size = size;
+        self.scene = Target::new(device, size, self.hdr_format);
+        self.processed = Target::new(device, size, self.hdr_format);
+        self.bloom = [Target::new(device, half, BLOOM_FORMAT), Target::new(device, half, BLOOM_FORMAT)];
+        self.create_bind_groups(device);
+    }
+
+    fn create_bind_groups(&mut self, device: &Device) {
+        let inputs = [
+            (&self.scene.view, &self.scene.view),
+            (&self.bloom[0].view, &self.bloom[0].view),
+            (&self.bloom[1].view, &self.bloom[1].view),
+            (&self.scene.view, &self.bloom[0].view),
+            (&self.processed.view, &self.processed.view),
+            (&self.processed.view, &self.bloom[0].view),
+        ];
+        self.bind_groups = self
+            .uniform_buffers
+            .iter()
+            .zip(inputs.iter())
+            .map(|(uniform_buffer, (source, bloom))| {
+                device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("post_bind_group"),
+                    layout: &self.layout,
+                    entries: &[
+                        BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() },
+                        BindGroupEntry { binding: 1, resource: BindingResource::TextureView(source) },
+                        BindGroupEntry { binding: 2, resource: BindingResource::TextureView(bloom) },
+                        BindGroupEntry { binding: 3, resource: BindingResource::Sampler(&self.sampler) },
+                    ],
+                })
+            })
+            .collect();
+    }
+
+    pub(crate) fn set_effects(&mut self, effects: Option<PostEffects>) {
+        self.effects = effects;
+    }
+
+    pub(crate) fn effects(&self) -> Option<PostEffects> {
+        self.effects
+    }
+
+    pub(crate) fn set_premultiplied(&mut self, premultiplied: bool) {
+        self.premultiplied = premultiplied;
+    }
+
+    // Changing only the weight keeps the bind group, so transitions can set it every frame
+    pub(crate) fn set_grading(&mut self, device: &Device, grading: Option<ColorGrading>) {
+        let luts = |grading: &Option<ColorGrading>| match grading {
+            Some(grading) => (grading.lut.clone(), grading.previous.clone().unwrap_or_else(|| self.identity_lut.clone())),
+            None => (self.identity_lut.clone(), self.identity_lut.clone()),
+        };
+        let (lut, previous) = luts(&grading);
+        let (current_lut, current_previous) = luts(&self.grading);
+        if !lut.same(&current_lut) || !previous.same(&current_previous) {
+            self.lut_bind_group = create_lut_bind_group(device, &self.lut_layout, &self.lut_sampler, &lut, &previous);
+        }
+        self.grading = grading;
+    }
+
+    pub(crate) fn grading(&self) -> Option<&ColorGrading> {
+        self.grading.as_ref()
+    }
+
+    // Where the render graph draws the screen to
+    pub(crate) fn scene_view(&self) -> &TextureView {
+        &self.scene.view
+    }
+
+    pub(crate) fn scene_texture(&self) -> &wgpu::Texture {
+        &self.scene.texture
+    }
+
+    // Written by the depth effects, once set for a frame the rest of post processing reads it
+    pub(crate) fn processed_view(&self) -> &TextureView {
+        &self.processed.view
+    }
+
+    pub(crate) fn set_use_processed(&mut self, use_processed: bool) {
+        self.use_processed = use_processed;
+    }
+
+    // Bright pass and composite bind groups for whichever scene is read this frame
+    fn source_bind_groups(&self) -> (&BindGroup, &BindGroup) {
+        match self.use_processed {
+            true => (&self.bind_groups[4], &self.bind_groups[5]),
+            false => (&self.bind_groups[0], &self.bind_groups[3]),
+        }
+    }
+
+    // Without effects the scene is only clipped onto the screen
+    fn effects_or_plain(&self) -> PostEffects {
+        self.effects.unwrap_or(PostEffects {
+            bloom: None,
+            exposure: 1.,
+            tonemapper: Tonemapper::None,
+            ssao: None,
+            ssr: None,
+            depth_of_field: None,
+        })
+    }
+
+    // Returns the number of writes
+    pub(crate) fn prepare(&self, queue: &Queue) -> usize {
+        let effects = self.effects_or_plain();
+
+        let bloom = effects.bloom.unwrap_or(Bloom { threshold: 0., intensity: 0. });
+        let texel = [1. / (self.size[0] / 2).max(1) as f32, 1. / (self.size[1] / 2).max(1) as f32];
+        let uniforms = PostUniforms {
+            direction: [0., 0.],
+            threshold: bloom.threshold,
+            intensity: bloom.intensity,
+            exposure: effects.exposure,
+            tonemapper: match effects.tonemapper {
+                Tonemapper::None => 0.,
+                Tonemapper::Reinhard => 1.,
+                Tonemapper::Aces => 2.,
+            },
+            premultiply: if self.premultiplied { 1. } else { 0. },
+            grading: self.grading.as_ref().map_or(-1., |grading| grading.weight.max(0.).min(1.)),
+        };
+        let directions = [[0., 0.], [texel[0], 0.], [0., texel[1]], [0., 0.], [0., 0.], [0., 0.]];
+        for (uniform_buffer, direction) in self.uniform_buffers.iter().zip(directions.iter()) {
+            let uniforms = PostUniforms { direction: *direction, ..uniforms };
+            queue.write_buffer(uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        }
+        let uniforms = PostUniforms { intensity: 0., premultiply: 0., ..uniforms };
+        queue.write_buffer(&self.target_uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        self.uniform_buffers.len().min(directions.len()) + 1
+    }
+
+    // Tonemaps an hdr render target with tonemap_target
+    pub(crate) fn create_target_bind_group(&self, device: &Device, source: &TextureView) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("post_target_bind_group"),
+            layout: &self.layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: self.target_uniform_buffer.as_entire_binding() },
+                BindGroupEntry { binding: 1, resource: BindingResource::TextureView(source) },
+                BindGroupEntry { binding: 2, resource: BindingResource::TextureView(source) },
+                BindGroupEntry { binding: 3, resource: BindingResource::Sampler(&self.sampler) },
+            ],
+        })
+    }
+
+    pub(crate) fn tonemap_target(&self, encoder: &mut CommandEncoder, bind_group: &BindGroup, target: &TextureView) {
+        self.draw(encoder, &[(&self.ldr_composite, bind_group, target)]);
+    }
+
+    pub(crate) fn render(&self, encoder: &mut CommandEncoder, screen: &TextureView) {
+        let effects = self.effects_or_plain();
+        let (bright, composite) = self.source_bind_groups();
+        let mut steps = vec![];
+        if effects.bloom.is_some() {
+            steps.push((&self.bright, bright, &self.bloom[0].view));
+            steps.push((&self.blur, &self.bind_groups[1], &self.bloom[1].view));
+            steps.push((&self.blur, &self.bind_groups[2], &self.bloom[0].view));
+        }
+        steps.push((&self.composite, composite, screen));
+        self.draw(encoder, &steps);
+    }
+
+    // Tonemaps the scene to another target, after render so the bloom is already blurred
+    pub(crate) fn composite(&self, encoder: &mut CommandEncoder, target: &TextureView) {
+        let (_, composite) = self.source_bind_groups();
+        self.draw(encoder, &[(&self.composite, composite, target)]);
+    }
+
+    fn draw(&self, encoder: &mut CommandEncoder, steps: &[(&RenderPipeline, &BindGroup, &TextureView)]) {
+        for (pipeline, bind_group, target) in steps.iter().copied() {
+            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("post_pass"),
+                color_attachments: &[RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: Operations { load: LoadOp::Clear(Color::BLACK), store: true },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.set_bind_group(1, &self.lut_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+fn create_lut_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    sampler: &Sampler,
+    lut: &ColorLut,
+    previous: &ColorLut,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("post_lut_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry { binding: 0, resource: BindingResource::TextureView(lut.view()) },
+            BindGroupEntry { binding: 1, resource: BindingResource::TextureView(previous.view()) },
+            BindGroupEntry { binding: 2, resource: BindingResource::Sampler(sampler) },
+        ],
+    })
+}
+
+fn create_pipeline(
+    device: &Device,
+    layout: &wgpu::PipelineLayout,
+    shader_module: &ShaderModule,
+    entry_point: &str,
+    format: TextureFormat,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("post_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: "main", buffers: &[] },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: MultisampleState { count: 1, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point,
+            targets: &[ColorTargetState { format, blend: None, write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
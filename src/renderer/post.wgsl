@@ -0,0 +1,128 @@
+struct PostOutput {
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct PostUniforms {
+    // One texel along the blur axis
+    direction: vec2<f32>;
+    threshold: f32;
+    intensity: f32;
+    exposure: f32;
+    // None, reinhard or aces
+    tonemapper: f32;
+    // Whether the alpha is multiplied into the color for a transparent window
+    premultiply: f32;
+    // How far from the previous lut to the current one, below zero without color grading
+    grading: f32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> post: PostUniforms;
+[[group(0), binding(1)]]
+var source: texture_2d<f32>;
+[[group(0), binding(2)]]
+var bloom: texture_2d<f32>;
+[[group(0), binding(3)]]
+var post_sampler: sampler;
+
+[[group(1), binding(0)]]
+var lut: texture_3d<f32>;
+[[group(1), binding(1)]]
+var previous_lut: texture_3d<f32>;
+[[group(1), binding(2)]]
+var lut_sampler: sampler;
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] index: u32) -> PostOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: PostOutput;
+    out.tex_coord = vec2<f32>(uv.x, 1.0 - uv.y);
+    out.pos = vec4<f32>(uv * 2.0 - vec2<f32>(1.0, 1.0), 0.0, 1.0);
+    return out;
+}
+
+// Keeps the part of each pixel brighter than the threshold
+[[stage(fragment)]]
+fn bright(in: PostOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(source, post_sampler, in.tex_coord).rgb * post.exposure;
+    let brightness = max(max(color.r, color.g), color.b);
+    let contribution = max(brightness - post.threshold, 0.0) / max(brightness, 0.0001);
+    return vec4<f32>(color * contribution, 1.0);
+}
+
+// Nine tap gaussian along one axis
+[[stage(fragment)]]
+fn blur(in: PostOutput) -> [[location(0)]] vec4<f32> {
+    let d = post.direction;
+    var color: vec3<f32> = textureSample(source, post_sampler, in.tex_coord).rgb * 0.227027;
+    color = color + textureSample(source, post_sampler, in.tex_coord + d * 1.0).rgb * 0.1945946;
+    color = color + textureSample(source, post_sampler, in.tex_coord - d * 1.0).rgb * 0.1945946;
+    color = color + textureSample(source, post_sampler, in.tex_coord + d * 2.0).rgb * 0.1216216;
+    color = color + textureSample(source, post_sampler, in.tex_coord - d * 2.0).rgb * 0.1216216;
+    color = color + textureSample(source, post_sampler, in.tex_coord + d * 3.0).rgb * 0.054054;
+    color = color + textureSample(source, post_sampler, in.tex_coord - d * 3.0).rgb * 0.054054;
+    color = color + textureSample(source, post_sampler, in.tex_coord + d * 4.0).rgb * 0.016216;
+    color = color + textureSample(source, post_sampler, in.tex_coord - d * 4.0).rgb * 0.016216;
+    return vec4<f32>(color, 1.0);
+}
+
+// Narkowicz's fit of the aces filmic curve
+fn aces(x: vec3<f32>) -> vec3<f32> {
+    let numerator = x * (2.51 * x + vec3<f32>(0.03, 0.03, 0.03));
+    let denominator = x * (2.43 * x + vec3<f32>(0.59, 0.59, 0.59)) + vec3<f32>(0.14, 0.14, 0.14);
+    let mapped = numerator / denominator;
+    return clamp(mapped, vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(1.0, 1.0, 1.0));
+}
+
+fn srgb_channel(x: f32) -> f32 {
+    if (x <= 0.0031308) {
+        return x * 12.92;
+    }
+    return 1.055 * pow(x, 1.0 / 2.4) - 0.055;
+}
+
+fn linear_channel(x: f32) -> f32 {
+    if (x <= 0.04045) {
+        return x / 12.92;
+    }
+    return pow((x + 0.055) / 1.055, 2.4);
+}
+
+// Texel centers, so the ends of each axis land on the first and last entries
+fn lut_coord(color: vec3<f32>, size: vec3<i32>) -> vec3<f32> {
+    let n = vec3<f32>(size);
+    return color * (n - vec3<f32>(1.0, 1.0, 1.0)) / n + vec3<f32>(0.5, 0.5, 0.5) / n;
+}
+
+// Luts are made for colors as they are stored on screen, which is srgb encoded
+fn grade(color: vec3<f32>) -> vec3<f32> {
+    if (post.grading < 0.0) {
+        return color;
+    }
+    let clamped = clamp(color, vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(1.0, 1.0, 1.0));
+    let encoded = vec3<f32>(srgb_channel(clamped.r), srgb_channel(clamped.g), srgb_channel(clamped.b));
+    let before = textureSampleLevel(previous_lut, lut_sampler, lut_coord(encoded, textureDimensions(previous_lut)), 0.0);
+    let after = textureSampleLevel(lut, lut_sampler, lut_coord(encoded, textureDimensions(lut)), 0.0);
+    let graded = mix(before.rgb, after.rgb, vec3<f32>(post.grading, post.grading, post.grading));
+    return vec3<f32>(linear_channel(graded.r), linear_channel(graded.g), linear_channel(graded.b));
+}
+
+[[stage(fragment)]]
+fn composite(in: PostOutput) -> [[location(0)]] vec4<f32> {
+    let scene = textureSample(source, post_sampler, in.tex_coord);
+    let glow = textureSample(bloom, post_sampler, in.tex_coord).rgb * post.intensity;
+    var color: vec3<f32> = scene.rgb * post.exposure + glow;
+    if (post.tonemapper > 1.5) {
+        color = aces(color);
+    } elseif (post.tonemapper > 0.5) {
+        color = color / (color + vec3<f32>(1.0, 1.0, 1.0));
+    }
+    color = grade(color);
+    if (post.premultiply > 0.5) {
+        let alpha = clamp(scene.a, 0.0, 1.0);
+        return vec4<f32>(color * alpha, alpha);
+    }
+    return vec4<f32>(color, scene.a);
+}
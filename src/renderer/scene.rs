@@ -0,0 +1,85 @@
+// Copyright 2021 Chay Nabors.
+
+use nalgebra::Matrix4;
+
+use super::GpuMaterial;
+use super::GpuMesh;
+use crate::Animation;
+use crate::AnimationPlayer;
+use crate::SceneNode;
+use crate::SceneSkin;
+
+// A scene's meshes and materials on the gpu along with its node hierarchy, draw it with Renderer::draw_scene
+#[derive(Clone, Debug)]
+pub struct GpuScene {
+    pub(crate) meshes: Vec<Vec<(GpuMesh, GpuMaterial)>>,
+    pub(crate) nodes: Vec<SceneNode>,
+    pub(crate) skins: Vec<SceneSkin>,
+    pub(crate) animations: Vec<Animation>,
+    pub(crate) roots: Vec<usize>,
+}
+
+impl GpuScene {
+    pub fn nodes(&self) -> &[SceneNode] {
+        &self.nodes
+    }
+
+    pub fn animations(&self) -> &[Animation] {
+        &self.animations
+    }
+
+    pub fn animation_by_name(&self, name: &str) -> Option<usize> {
+        self.animations.iter().position(|animation| animation.name.as_deref() == Some(name))
+    }
+
+    // Starts at the beginning of the animation and loops it, None if the scene doesn't have it
+    pub fn player(&self, animation: usize) -> Option<AnimationPlayer> {
+        self.animations.get(animation).map(|clip| AnimationPlayer::new(animation, clip.duration))
+    }
+
+    // Every node's transform relative to its parent, at rest without a player
+    pub(crate) fn pose(&self, player: Option<&AnimationPlayer>) -> Vec<Matrix4<f32>> {
+        match player.and_then(|player| self.animations.get(player.animation).map(|clip| (player, clip))) {
+            Some((player, clip)) => clip.sample(player.time, &self.nodes),
+            None => self.nodes.iter().map(|node| node.transform).collect(),
+        }
+    }
+
+    // Calls the closure with every node that has a mesh, its transform and the joint matrices of its skin.
+    // Skinned meshes get the scene's transform since their joints already place them within it
+    pub(crate) fn visit(
+        &self,
+        pose: &[Matrix4<f32>],
+        transform: Matrix4<f32>,
+        mut f: impl FnMut(&[(GpuMesh, GpuMaterial)], Matrix4<f32>, Option<&[Matrix4<f32>]>),
+    ) {
+        // Relative to the scene, nodes outside of it stay None
+        let mut globals = vec![None; self.nodes.len()];
+        let mut stack: Vec<(usize, Matrix4<f32>)> = self.roots.iter().map(|root| (*root, Matrix4::identity())).collect();
+        while let Some((index, parent)) = stack.pop() {
+            let global = parent * pose[index];
+            globals[index] = Some(global);
+            stack.extend(self.nodes[index].children.iter().map(|child| (*child, global)));
+        }
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            let (global, mesh) = match (globals[index], node.mesh) {
+                (Some(global), Some(mesh)) => (global, mesh),
+                _ => continue,
+            };
+
+            match node.skin.map(|skin| &self.skins[skin]) {
+                Some(skin) => {
+                    let joints: Vec<Matrix4<f32>> = skin
+                        .joints
+                        .iter()
+                        .zip(&skin.inverse_bind_matrices)
+                        .map(|(joint, inverse_bind)| globals[*joint].unwrap_or_else(Matrix4::identity) * inverse_bind)
+                        .collect();
+                    f(&self.meshes[mesh], transform, Some(&joints));
+                },
+                None => f(&self.meshes[mesh], transform * global, None),
+            }
+        }
+    }
+}
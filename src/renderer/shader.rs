@@ -0,0 +1,575 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::fs::{self,};
+use std::mem::size_of;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use log::error;
+use log::info;
+use wgpu::vertex_attr_array;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::ErrorFilter;
+use wgpu::Face;
+use wgpu::FilterMode;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::IndexFormat;
+use wgpu::InputStepMode;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureView;
+use wgpu::TextureViewDimension;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+use wgpu::BIND_BUFFER_ALIGNMENT;
+
+use super::stats::FrameStats;
+use super::GpuMaterial;
+use super::GpuMesh;
+use super::VertexLayout;
+use crate::model::Vertex;
+use crate::GearError;
+use crate::Result;
+use crate::Texture;
+
+const MESH_PRELUDE: &str = concat!(include_str!("fog.wgsl"), include_str!("mesh.wgsl"));
+const FULLSCREEN_PRELUDE: &str = include_str!("fullscreen.wgsl");
+// How often watched files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ShaderId(u32);
+
+// Mesh shaders get the declarations in mesh.wgsl and provide both stages, they are used by materials naming them. They
+// read the usual vertices at locations zero to two unless they were created for another vertex layout.
+// Fullscreen shaders get the declarations and vertex stage in fullscreen.wgsl and provide only the fragment stage.
+// Every entry point is named main
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShaderKind {
+    Mesh,
+    Fullscreen,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct FrameUniforms {
+    resolution: [f32; 2],
+    time: f32,
+    _padding: f32,
+}
+
+#[derive(Debug)]
+struct CustomShader {
+    kind: ShaderKind,
+    // Kept to rebuild the pipelines when the sample count changes
+    source: String,
+    pipeline: RenderPipeline,
+    // Fullscreen shaders are also built for render graph passes, which have no depth and a single sample
+    depthless_pipeline: Option<RenderPipeline>,
+    // Mesh shaders read meshes of this layout, None is the usual vertices
+    layout: Option<VertexLayout>,
+    // Set for shaders loaded from a watched file
+    watch: Option<(PathBuf, Option<SystemTime>)>,
+}
+
+#[derive(Debug)]
+struct ShaderDraw {
+    shader: ShaderId,
+    mesh: GpuMesh,
+    material: GpuMaterial,
+    uniform: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct ShaderRegistry {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+    mesh_layout: PipelineLayout,
+    fullscreen_layout: PipelineLayout,
+    frame_buffer: Buffer,
+    frame_bind_group: BindGroup,
+    input_layout: BindGroupLayout,
+    input_sampler: Sampler,
+    white: Texture,
+    default_inputs: BindGroup,
+    shaders: Vec<CustomShader>,
+    last_watch: Instant,
+    created: Instant,
+    mesh_draws: Vec<ShaderDraw>,
+    fullscreen_draws: Vec<ShaderId>,
+}
+
+impl ShaderRegistry {
+    pub(crate) fn new(
+        device: &Device,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        object_layout: &BindGroupLayout,
+        scene_layout: &BindGroupLayout,
+        material_layout: &BindGroupLayout,
+        white: &Texture,
+    ) -> ShaderRegistry {
+        let mesh_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("custom_mesh_pipeline_layout"),
+            bind_group_layouts: &[object_layout, scene_layout, material_layout],
+            push_constant_ranges: &[],
+        });
+
+        let frame_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("frame_uniform_buffer"),
+            size: size_of::<FrameUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let frame_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("frame_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX | ShaderStage::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<FrameUniforms>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let frame_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("frame_bind_group"),
+            layout: &frame_bind_group_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: frame_buffer.as_entire_binding() }],
+        });
+
+        let input_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let input_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("input_bind_group_layout"),
+            entries: &[
+                input_entry(0),
+                input_entry(1),
+                input_entry(2),
+                input_entry(3),
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: false },
+                    count: None,
+                },
+            ],
+        });
+
+        let input_sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("input_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let fullscreen_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("fullscreen_pipeline_layout"),
+            bind_group_layouts: &[&frame_bind_group_layout, &input_layout],
+            push_constant_ranges: &[],
+        });
+
+        let default_inputs = create_input_bind_group(device, &input_layout, &input_sampler, white.view(), &[]);
+
+        ShaderRegistry {
+            format,
+            depth_format,
+            sample_count: 1,
+            mesh_layout,
+            fullscreen_layout,
+            frame_buffer,
+            frame_bind_group,
+            input_layout,
+            input_sampler,
+            white: white.clone(),
+            default_inputs,
+            shaders: vec![],
+            last_watch: Instant::now(),
+            created: Instant::now(),
+            mesh_draws: vec![],
+            fullscreen_draws: vec![],
+        }
+    }
+
+    pub(crate) fn create(&mut self, device: &Device, source: &str, kind: ShaderKind) -> Result<ShaderId> {
+        self.create_with_layout(device, source, kind, None)
+    }
+
+    pub(crate) fn create_with_layout(
+        &mut self,
+        device: &Device,
+        source: &str,
+        kind: ShaderKind,
+        layout: Option<&VertexLayout>,
+    ) -> Result<ShaderId> {
+        let (pipeline, depthless_pipeline) = self.compile(device, source, kind, layout)?;
+        let source = source.to_owned();
+        let layout = layout.cloned();
+        self.shaders.push(CustomShader { kind, source, pipeline, depthless_pipeline, layout, watch: None });
+        Ok(ShaderId(self.shaders.len() as u32 - 1))
+    }
+
+    pub(crate) fn load(&mut self, device: &Device, path: &Path, kind: ShaderKind, watch: bool) -> Result<ShaderId> {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        let source = fs::read_to_string(path)?;
+        let id = self.create(device, &source, kind)?;
+        if watch {
+            self.shaders[id.0 as usize].watch = Some((path.to_owned(), modified));
+        }
+        Ok(id)
+    }
+
+    // A shader that fails to compile keeps its previous pipeline so a typo doesn't take the game down
+    pub(crate) fn reload_changed(&mut self, device: &Device) {
+        let now = Instant::now();
+        if now - self.last_watch < WATCH_INTERVAL {
+            return;
+        }
+        self.last_watch = now;
+
+        for i in 0..self.shaders.len() {
+            let (path, modified) = match &self.shaders[i].watch {
+                Some(watch) => watch.clone(),
+                None => continue,
+            };
+            let current = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+            if current.is_none() || current == modified {
+                continue;
+            }
+
+            let (kind, layout) = (self.shaders[i].kind, self.shaders[i].layout.as_ref());
+            let compiled = fs::read_to_string(&path).map_err(GearError::from).and_then(|source| {
+                let pipelines = self.compile(device, &source, kind, layout)?;
+                Ok((source, pipelines))
+            });
+            let shader = &mut self.shaders[i];
+            shader.watch = Some((path.clone(), current));
+            match compiled {
+                Ok((source, (pipeline, depthless_pipeline))) => {
+                    shader.source = source;
+                    shader.pipeline = pipeline;
+                    shader.depthless_pipeline = depthless_pipeline;
+                    info!("Reloaded shader {}", path.display());
+                },
+                Err(e) => error!("Failed to reload shader {}: {:?}", path.display(), e),
+            }
+        }
+    }
+
+    // Mesh shaders and fullscreen shaders drawn in scene passes follow the sample count of the scene
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.sample_count = sample_count;
+        for i in 0..self.shaders.len() {
+            let shader = &self.shaders[i];
+            match self.compile(device, &shader.source, shader.kind, shader.layout.as_ref()) {
+                Ok((pipeline, depthless_pipeline)) => {
+                    self.shaders[i].pipeline = pipeline;
+                    self.shaders[i].depthless_pipeline = depthless_pipeline;
+                },
+                Err(e) => error!("Failed to rebuild shader for {} samples: {:?}", sample_count, e),
+            }
+        }
+    }
+
+    fn compile(
+        &self,
+        device: &Device,
+        source: &str,
+        kind: ShaderKind,
+        layout: Option<&VertexLayout>,
+    ) -> Result<(RenderPipeline, Option<RenderPipeline>)> {
+        let prelude = match kind {
+            ShaderKind::Mesh => MESH_PRELUDE,
+            ShaderKind::Fullscreen => FULLSCREEN_PRELUDE,
+        };
+        let source = format!("{}{}", prelude, source);
+
+        // Validation errors are caught here instead of reaching the device's panicking error handler
+        device.push_error_scope(ErrorFilter::Validation);
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("custom_shader"),
+            source: ShaderSource::Wgsl(Cow::Owned(source)),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let vertex_attributes = match layout {
+            Some(layout) => layout.vertex_attributes(),
+            None => vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x3].to_vec(),
+        };
+        let array_stride = layout.map_or(size_of::<Vertex>() as wgpu::BufferAddress, |layout| layout.stride());
+
+        let pipeline = match kind {
+            ShaderKind::Mesh => device.create_render_pipeline(&RenderPipelineDescriptor {
+                label: Some("custom_mesh_pipeline"),
+                layout: Some(&self.mesh_layout),
+                vertex: VertexState {
+                    module: &shader_module,
+                    entry_point: "main",
+                    buffers: &[VertexBufferLayout {
+                        array_stride,
+                        step_mode: InputStepMode::Vertex,
+                        attributes: &vertex_attributes,
+                    }],
+                },
+                primitive: PrimitiveState {
+                    topology: PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: FrontFace::Ccw,
+                    cull_mode: Some(Face::Back),
+                    clamp_depth: false,
+                    polygon_mode: PolygonMode::Fill,
+                    conservative: false,
+                },
+                depth_stencil: Some(DepthStencilState {
+                    format: self.depth_format,
+                    depth_write_enabled: true,
+                    depth_compare: CompareFunction::GreaterEqual,
+                    stencil: StencilState::default(),
+                    bias: DepthBiasState::default(),
+                }),
+                multisample: MultisampleState { count: self.sample_count, mask: !0, alpha_to_coverage_enabled: false },
+                fragment: Some(FragmentState {
+                    module: &shader_module,
+                    entry_point: "main",
+                    targets: &[ColorTargetState {
+                        format: self.format,
+                        blend: Some(BlendState::REPLACE),
+                        write_mask: ColorWrite::ALL,
+                    }],
+                }),
+            }),
+            ShaderKind::Fullscreen => {
+                let depth_stencil = DepthStencilState {
+                    format: self.depth_format,
+                    depth_write_enabled: false,
+                    depth_compare: CompareFunction::Always,
+                    stencil: StencilState::default(),
+                    bias: DepthBiasState::default(),
+                };
+                let pipeline = self.fullscreen_pipeline(device, &shader_module, Some(depth_stencil), self.sample_count);
+                let depthless_pipeline = self.fullscreen_pipeline(device, &shader_module, None, 1);
+                return self.finish_compile((pipeline, Some(depthless_pipeline)), device);
+            },
+        };
+
+        self.finish_compile((pipeline, None), device)
+    }
+
+    fn finish_compile<T>(&self, compiled: T, device: &Device) -> Result<T> {
+        match pollster::block_on(device.pop_error_scope()) {
+            Some(e) => Err(GearError::ShaderError(e.to_string())),
+            None => Ok(compiled),
+        }
+    }
+
+    // Drawn over the target, the alpha written decides how much of it shows through
+    fn fullscreen_pipeline(
+        &self,
+        device: &Device,
+        shader_module: &ShaderModule,
+        depth_stencil: Option<DepthStencilState>,
+        sample_count: u32,
+    ) -> RenderPipeline {
+        device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("fullscreen_pipeline"),
+            layout: Some(&self.fullscreen_layout),
+            vertex: VertexState { module: shader_module, entry_point: "main", buffers: &[] },
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                clamp_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil,
+            multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+            fragment: Some(FragmentState {
+                module: shader_module,
+                entry_point: "main",
+                targets: &[ColorTargetState {
+                    format: self.format,
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrite::ALL,
+                }],
+            }),
+        })
+    }
+    pub(crate) fn is_fullscreen(&self, shader: ShaderId) -> bool {
+        self.shaders.get(shader.0 as usize).map_or(false, |shader| shader.kind == ShaderKind::Fullscreen)
+    }
+
+    pub(crate) fn create_input_bind_group(&self, device: &Device, inputs: &[&TextureView]) -> BindGroup {
+        create_input_bind_group(device, &self.input_layout, &self.input_sampler, self.white.view(), inputs)
+    }
+
+    pub(crate) fn push_mesh(&mut self, shader: ShaderId, mesh: &GpuMesh, material: &GpuMaterial, uniform: u32) {
+        self.mesh_draws.push(ShaderDraw { shader, mesh: mesh.clone(), material: material.clone(), uniform });
+    }
+
+    pub(crate) fn push_fullscreen(&mut self, shader: ShaderId) {
+        self.fullscreen_draws.push(shader);
+    }
+
+    // Returns the number of writes
+    pub(crate) fn prepare(&mut self, queue: &Queue, resolution: [f32; 2]) -> usize {
+        let time = self.created.elapsed().as_secs_f32();
+        let uniforms = FrameUniforms { resolution, time, _padding: 0. };
+        queue.write_buffer(&self.frame_buffer, 0, bytemuck::bytes_of(&uniforms));
+        1
+    }
+
+    pub(crate) fn render_meshes<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        object_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
+        visible: &[bool],
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.mesh_draws.is_empty() {
+            return stats;
+        }
+
+        render_pass.set_bind_group(1, scene_bind_group, &[]);
+        stats.texture_binds += 1;
+        for draw in self.mesh_draws.iter().filter(|draw| visible[draw.uniform as usize]) {
+            // Meshes of another vertex layout than the shader's are skipped
+            let shader = &self.shaders[draw.shader.0 as usize];
+            if draw.mesh.layout() != shader.layout.as_ref() {
+                continue;
+            }
+
+            let offset = (draw.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_pipeline(&shader.pipeline);
+            render_pass.set_bind_group(0, object_bind_group, &[offset]);
+            render_pass.set_bind_group(2, draw.material.bind_group(), &[]);
+            render_pass.set_vertex_buffer(0, draw.mesh.vertex_buffer().slice(..));
+            render_pass.set_index_buffer(draw.mesh.index_buffer().slice(..), IndexFormat::Uint32);
+            render_pass.draw_indexed(0..draw.mesh.index_count(), 0, 0..1);
+            stats.draw(draw.mesh.index_count(), 1);
+            stats.texture_binds += 1;
+            stats.pipeline_switches += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn render_fullscreen<'a>(&'a self, render_pass: &mut RenderPass<'a>) -> FrameStats {
+        let mut stats = FrameStats::default();
+        for shader in &self.fullscreen_draws {
+            render_pass.set_pipeline(&self.shaders[shader.0 as usize].pipeline);
+            render_pass.set_bind_group(0, &self.frame_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.default_inputs, &[]);
+            render_pass.draw(0..3, 0..1);
+            stats.draw(3, 1);
+            stats.texture_binds += 1;
+            stats.pipeline_switches += 1;
+        }
+        stats
+    }
+
+    // A render graph pass running a single fullscreen shader
+    pub(crate) fn render_pass<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        shader: ShaderId,
+        inputs: Option<&'a BindGroup>,
+    ) -> FrameStats {
+        let shader = &self.shaders[shader.0 as usize];
+        render_pass.set_pipeline(shader.depthless_pipeline.as_ref().unwrap_or(&shader.pipeline));
+        render_pass.set_bind_group(0, &self.frame_bind_group, &[]);
+        render_pass.set_bind_group(1, inputs.unwrap_or(&self.default_inputs), &[]);
+        render_pass.draw(0..3, 0..1);
+        let mut stats = FrameStats { texture_binds: 1, pipeline_switches: 1, ..FrameStats::default() };
+        stats.draw(3, 1);
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.mesh_draws.clear();
+        self.fullscreen_draws.clear();
+    }
+}
+
+// Slots past the given inputs are filled with white
+fn create_input_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    sampler: &Sampler,
+    white: &TextureView,
+    inputs: &[&TextureView],
+) -> BindGroup {
+    let input = |i: usize| BindingResource::TextureView(inputs.get(i).copied().unwrap_or(white));
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("input_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry { binding: 0, resource: input(0) },
+            BindGroupEntry { binding: 1, resource: input(1) },
+            BindGroupEntry { binding: 2, resource: input(2) },
+            BindGroupEntry { binding: 3, resource: input(3) },
+            BindGroupEntry { binding: 4, resource: BindingResource::Sampler(sampler) },
+        ],
+    })
+}
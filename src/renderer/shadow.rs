@@ -0,0 +1,338 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Isometry3;
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use nalgebra::Vector3;
+use wgpu::vertex_attr_array;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::CommandEncoder;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::Extent3d;
+use wgpu::FilterMode;
+use wgpu::FrontFace;
+use wgpu::InputStepMode;
+use wgpu::LoadOp;
+use wgpu::MultisampleState;
+use wgpu::Operations;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPassDepthStencilAttachment;
+use wgpu::RenderPassDescriptor;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::Texture;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+use wgpu::BIND_BUFFER_ALIGNMENT;
+
+use super::instance::InstanceBuffer;
+use super::mesh::MeshInput;
+use super::mesh::MeshVariant;
+use super::skin::JointBuffer;
+use super::stats::FrameStats;
+use super::DirectionalLight;
+use super::GpuMesh;
+use crate::model::Vertex;
+
+pub(crate) const SHADOW_MAP_SIZE: u32 = 2048;
+const SHADOW_MAP_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct ShadowUniforms {
+    light_view_projection: [[f32; 4]; 4],
+}
+
+#[derive(Debug)]
+struct ShadowCaster {
+    mesh: GpuMesh,
+    uniform: u32,
+    input: MeshInput,
+}
+
+// Depth of everything drawn as a mesh as seen from the directional light, rendered before the render graph runs.
+// Unlike the scene depth it is cleared to one and closer surfaces have smaller depths
+#[derive(Debug)]
+pub(crate) struct ShadowMap {
+    _texture: Texture,
+    view: TextureView,
+    sampler: Sampler,
+    pipeline: RenderPipeline,
+    skinned_pipeline: RenderPipeline,
+    instanced_pipeline: RenderPipeline,
+    uniform_buffer: Buffer,
+    uniform_bind_group: BindGroup,
+    casters: Vec<ShadowCaster>,
+    active: bool,
+}
+
+impl ShadowMap {
+    pub(crate) fn new(device: &Device, object_layout: &BindGroupLayout, joint_layout: &BindGroupLayout) -> ShadowMap {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("shadow_map"),
+            size: Extent3d { width: SHADOW_MAP_SIZE, height: SHADOW_MAP_SIZE, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: SHADOW_MAP_FORMAT,
+            usage: TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+
+        // Filtering the comparisons gives every tap of the pcf kernel soft edges of its own
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("shadow_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            compare: Some(CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("shadow_uniform_buffer"),
+            size: size_of::<ShadowUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("shadow_uniform_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<ShadowUniforms>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let uniform_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("shadow_uniform_bind_group"),
+            layout: &uniform_bind_group_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() }],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("shadow_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("shadow.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("shadow_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &uniform_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let skinned_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("shadow_skinned_pipeline_layout"),
+            bind_group_layouts: &[object_layout, &uniform_bind_group_layout, joint_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader_module, MeshVariant::Plain);
+        let skinned_pipeline = create_pipeline(device, &skinned_pipeline_layout, &shader_module, MeshVariant::Skinned);
+        let instanced_pipeline = create_pipeline(device, &pipeline_layout, &shader_module, MeshVariant::Instanced);
+
+        ShadowMap {
+            _texture: texture,
+            view,
+            sampler,
+            pipeline,
+            skinned_pipeline,
+            instanced_pipeline,
+            uniform_buffer,
+            uniform_bind_group,
+            casters: vec![],
+            active: false,
+        }
+    }
+
+    pub(crate) fn view(&self) -> &TextureView {
+        &self.view
+    }
+
+    pub(crate) fn sampler(&self) -> &Sampler {
+        &self.sampler
+    }
+
+    pub(crate) fn push(&mut self, mesh: &GpuMesh, uniform: u32, input: MeshInput) {
+        if mesh.accepts(&input) {
+            self.casters.push(ShadowCaster { mesh: mesh.clone(), uniform, input });
+        }
+    }
+
+    // Covers a square around the focus point, returns the light's view projection while it casts shadows
+    pub(crate) fn prepare(
+        &mut self,
+        queue: &Queue,
+        light: Option<DirectionalLight>,
+        focus: Point3<f32>,
+    ) -> Option<Matrix4<f32>> {
+        let (light, shadow) = match light.and_then(|light| light.shadow.map(|shadow| (light, shadow))) {
+            Some(light) => light,
+            None => {
+                self.active = false;
+                return None;
+            },
+        };
+
+        let direction = light.direction.normalize();
+        let up = if direction.y.abs() > 0.99 { Vector3::z() } else { Vector3::y() };
+        let extent = shadow.extent;
+
+        // Snapping the focus to whole texels keeps shadow edges from crawling as the camera moves
+        let texel = 2. * extent / SHADOW_MAP_SIZE as f32;
+        let rotation = Isometry3::look_at_rh(&Point3::origin(), &Point3::from(direction), &up);
+        let mut focus = rotation * focus;
+        focus.x = (focus.x / texel).floor() * texel;
+        focus.y = (focus.y / texel).floor() * texel;
+        let focus = rotation.inverse() * focus;
+
+        // Casters up to one extent behind the focus point are still in front of the light
+        let eye = focus - direction * extent * 2.;
+        let view = Isometry3::look_at_rh(&eye, &focus, &up);
+        let projection = Matrix4::new_orthographic(-extent, extent, -extent, extent, 0., extent * 4.);
+        // The projection maps depth to the -1 to 1 range of opengl, wgpu expects 0 to 1
+        #[rustfmt::skip]
+        let depth_remap = Matrix4::new(
+            1., 0., 0., 0.,
+            0., 1., 0., 0.,
+            0., 0., 0.5, 0.5,
+            0., 0., 0., 1.,
+        );
+        let light_view_projection = depth_remap * projection * view.to_homogeneous();
+
+        let uniforms = ShadowUniforms { light_view_projection: light_view_projection.into() };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        self.active = true;
+        Some(light_view_projection)
+    }
+
+    pub(crate) fn render(
+        &self,
+        encoder: &mut CommandEncoder,
+        object_bind_group: &BindGroup,
+        joints: &JointBuffer,
+        instances: &InstanceBuffer,
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if !self.active {
+            return stats;
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("shadow_pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &self.view,
+                depth_ops: Some(Operations { load: LoadOp::Clear(1.0), store: true }),
+                stencil_ops: None,
+            }),
+        });
+
+        for caster in &self.casters {
+            render_pass.set_pipeline(match caster.input.variant() {
+                MeshVariant::Plain => &self.pipeline,
+                MeshVariant::Skinned => &self.skinned_pipeline,
+                MeshVariant::Instanced => &self.instanced_pipeline,
+            });
+            render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+
+            let offset = (caster.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(0, object_bind_group, &[offset]);
+            stats += caster.mesh.draw(&mut render_pass, &caster.input, joints, 2, instances);
+            stats.pipeline_switches += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.casters.clear();
+    }
+}
+
+// Only depth is written, so there is no fragment stage
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    variant: MeshVariant,
+) -> RenderPipeline {
+    let vertex_attributes = vertex_attr_array![0 => Float32x3];
+    let mut buffers = vec![VertexBufferLayout {
+        array_stride: size_of::<Vertex>() as wgpu::BufferAddress,
+        step_mode: InputStepMode::Vertex,
+        attributes: &vertex_attributes,
+    }];
+    buffers.extend(variant.extra_buffer());
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("shadow_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: variant.entry_point(), buffers: &buffers },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            // Both faces cast so open meshes and planes still throw shadows
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: SHADOW_MAP_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        fragment: None,
+    })
+}
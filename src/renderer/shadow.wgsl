@@ -0,0 +1,62 @@
+struct VertexInput {
+    [[location(0)]] pos: vec3<f32>;
+};
+
+[[block]]
+struct ObjectUniforms {
+    model_view_projection: mat4x4<f32>;
+    model: mat4x4<f32>;
+    normal: mat4x4<f32>;
+};
+
+[[block]]
+struct ShadowUniforms {
+    light_view_projection: mat4x4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> object: ObjectUniforms;
+
+[[group(1), binding(0)]]
+var<uniform> shadow: ShadowUniforms;
+
+struct SkinInput {
+    [[location(3)]] joints: vec4<u32>;
+    [[location(4)]] weights: vec4<f32>;
+};
+
+[[block]]
+struct JointUniforms {
+    matrices: array<mat4x4<f32>, 128>;
+};
+
+[[group(2), binding(0)]]
+var<uniform> joints: JointUniforms;
+
+struct InstanceInput {
+    [[location(5)]] model_0: vec4<f32>;
+    [[location(6)]] model_1: vec4<f32>;
+    [[location(7)]] model_2: vec4<f32>;
+    [[location(8)]] model_3: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> [[builtin(position)]] vec4<f32> {
+    return shadow.light_view_projection * object.model * vec4<f32>(in.pos, 1.0);
+}
+
+[[stage(vertex)]]
+fn skinned(in: VertexInput, skin: SkinInput) -> [[builtin(position)]] vec4<f32> {
+    let pos = vec4<f32>(in.pos, 1.0);
+    let skinned = joints.matrices[skin.joints.x] * pos * skin.weights.x
+        + joints.matrices[skin.joints.y] * pos * skin.weights.y
+        + joints.matrices[skin.joints.z] * pos * skin.weights.z
+        + joints.matrices[skin.joints.w] * pos * skin.weights.w;
+    return shadow.light_view_projection * object.model * skinned;
+}
+
+[[stage(vertex)]]
+fn instanced(in: VertexInput, instance: InstanceInput) -> [[builtin(position)]] vec4<f32> {
+    let model = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    return shadow.light_view_projection * object.model * model * vec4<f32>(in.pos, 1.0);
+}
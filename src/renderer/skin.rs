@@ -0,0 +1,120 @@
+// Copyright 2021 Chay Nabors.
+
+use std::mem::size_of;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Matrix4;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferBinding;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::Queue;
+use wgpu::ShaderStage;
+
+// Skins are limited to this many joints, vertices moved by the ones past it end up in the wrong place
+pub(crate) const MAX_JOINTS: usize = 128;
+// Skinned draws past this in one frame are dropped
+const MAX_SKINS: usize = 256;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct JointMatrices {
+    matrices: [[[f32; 4]; 4]; MAX_JOINTS],
+}
+
+// The joint matrices of every skinned draw in a frame, each draw binds its own slot with a dynamic offset
+#[derive(Debug)]
+pub(crate) struct JointBuffer {
+    buffer: Buffer,
+    layout: BindGroupLayout,
+    bind_group: BindGroup,
+    data: Vec<JointMatrices>,
+}
+
+impl JointBuffer {
+    pub(crate) fn new(device: &Device) -> JointBuffer {
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("joint_buffer"),
+            size: (MAX_SKINS * size_of::<JointMatrices>()) as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("joint_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<JointMatrices>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("joint_bind_group"),
+            layout: &layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: &buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(size_of::<JointMatrices>() as _),
+                }),
+            }],
+        });
+
+        JointBuffer { buffer, layout, bind_group, data: vec![] }
+    }
+
+    pub(crate) fn layout(&self) -> &BindGroupLayout {
+        &self.layout
+    }
+
+    pub(crate) fn bind_group(&self) -> &BindGroup {
+        &self.bind_group
+    }
+
+    // The slot the matrices were written to, None once the buffer is full
+    pub(crate) fn push(&mut self, joints: &[Matrix4<f32>]) -> Option<u32> {
+        if self.data.len() >= MAX_SKINS {
+            return None;
+        }
+
+        let mut matrices = JointMatrices::zeroed();
+        for (matrix, joint) in matrices.matrices.iter_mut().zip(joints) {
+            *matrix = (*joint).into();
+        }
+        self.data.push(matrices);
+        Some(self.data.len() as u32 - 1)
+    }
+
+    pub(crate) fn offset(slot: u32) -> DynamicOffset {
+        slot * size_of::<JointMatrices>() as DynamicOffset
+    }
+
+    // Returns the number of writes
+    pub(crate) fn prepare(&self, queue: &Queue) -> usize {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&self.data));
+        1
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.data.clear();
+    }
+}
@@ -0,0 +1,352 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use image::error::ParameterError;
+use image::error::ParameterErrorKind;
+use image::ImageError;
+use image::RgbaImage;
+use nalgebra::Isometry3;
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::Extent3d;
+use wgpu::FilterMode;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::ImageCopyTexture;
+use wgpu::ImageDataLayout;
+use wgpu::MultisampleState;
+use wgpu::Origin3d;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::TextureViewDimension;
+use wgpu::VertexState;
+
+use super::fog::FogUniforms;
+use super::stats::FrameStats;
+use super::Fog;
+use crate::GearError;
+use crate::Result;
+
+// Six square faces of the same size in the order +x, -x, +y, -y, +z, -z, clones share the same texture
+#[derive(Clone, Debug)]
+pub struct Cubemap {
+    inner: Arc<CubemapData>,
+}
+
+#[derive(Debug)]
+struct CubemapData {
+    size: u32,
+    _texture: wgpu::Texture,
+    view: TextureView,
+}
+
+impl Cubemap {
+    pub(crate) fn from_rgba(device: &Device, queue: &Queue, size: u32, faces: &[&[u8]]) -> Result<Cubemap> {
+        let mut images = Vec::with_capacity(faces.len());
+        for face in faces {
+            images.push(RgbaImage::from_raw(size, size, face.to_vec()).ok_or_else(dimension_mismatch)?);
+        }
+        Cubemap::from_images(device, queue, &images)
+    }
+
+    // The faces are srgb color
+    pub(crate) fn from_images(device: &Device, queue: &Queue, faces: &[RgbaImage]) -> Result<Cubemap> {
+        let size = faces.first().map_or(0, |face| face.width());
+        if faces.len() != 6 || size == 0 || faces.iter().any(|face| face.dimensions() != (size, size)) {
+            return Err(dimension_mismatch());
+        }
+
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("cubemap"),
+            size: Extent3d { width: size, height: size, depth_or_array_layers: 6 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            usage: TextureUsage::SAMPLED | TextureUsage::COPY_DST,
+        });
+
+        for (layer, face) in faces.iter().enumerate() {
+            queue.write_texture(
+                ImageCopyTexture { texture: &texture, mip_level: 0, origin: Origin3d { x: 0, y: 0, z: layer as u32 } },
+                face.as_raw(),
+                ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(4 * size),
+                    rows_per_image: NonZeroU32::new(size),
+                },
+                Extent3d { width: size, height: size, depth_or_array_layers: 1 },
+            );
+        }
+
+        let view = texture
+            .create_view(&TextureViewDescriptor { dimension: Some(TextureViewDimension::Cube), ..Default::default() });
+        Ok(Cubemap { inner: Arc::new(CubemapData { size, _texture: texture, view }) })
+    }
+
+    // The width and height of every face
+    pub fn size(&self) -> u32 {
+        self.inner.size
+    }
+
+    pub(crate) fn view(&self) -> &TextureView {
+        &self.inner.view
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct SkyboxUniforms {
+    inverse_view_projection: [[f32; 4]; 4],
+    camera_position: [f32; 4],
+    fog: FogUniforms,
+}
+
+// Draws the environment wherever the scene leaves the far plane uncovered
+#[derive(Debug)]
+pub(crate) struct SkyboxRenderer {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    pipeline: RenderPipeline,
+    bind_group_layout: BindGroupLayout,
+    uniform_buffer: Buffer,
+    sampler: Sampler,
+    environment: Option<(Cubemap, BindGroup)>,
+    fog: Option<Fog>,
+}
+
+impl SkyboxRenderer {
+    pub(crate) fn new(device: &Device, format: TextureFormat, depth_format: TextureFormat) -> SkyboxRenderer {
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("skybox_uniform_buffer"),
+            size: size_of::<SkyboxUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("skybox_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("skybox_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::VERTEX | ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<SkyboxUniforms>() as _),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::Cube,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: false },
+                    count: None,
+                },
+            ],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("skybox_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(concat!(include_str!("fog.wgsl"), include_str!("skybox.wgsl")))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("skybox_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader_module, format, depth_format, 1);
+
+        SkyboxRenderer {
+            format,
+            depth_format,
+            shader_module,
+            pipeline_layout,
+            pipeline,
+            bind_group_layout,
+            uniform_buffer,
+            sampler,
+            environment: None,
+            fog: None,
+        }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.shader_module,
+            self.format,
+            self.depth_format,
+            sample_count,
+        );
+    }
+
+    pub(crate) fn set_environment(&mut self, device: &Device, environment: Option<Cubemap>) {
+        self.environment = environment.map(|cubemap| {
+            let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: Some("skybox_bind_group"),
+                layout: &self.bind_group_layout,
+                entries: &[
+                    BindGroupEntry { binding: 0, resource: self.uniform_buffer.as_entire_binding() },
+                    BindGroupEntry { binding: 1, resource: BindingResource::TextureView(&cubemap.inner.view) },
+                    BindGroupEntry { binding: 2, resource: BindingResource::Sampler(&self.sampler) },
+                ],
+            });
+            (cubemap, bind_group)
+        });
+    }
+
+    pub(crate) fn environment(&self) -> Option<&Cubemap> {
+        self.environment.as_ref().map(|(cubemap, _)| cubemap)
+    }
+
+    pub(crate) fn set_fog(&mut self, fog: Option<Fog>) {
+        self.fog = fog;
+    }
+
+    // Once for every camera the scene is drawn from, returns the number of writes
+    pub(crate) fn prepare(&self, queue: &Queue, view: Isometry3<f32>, projection: Matrix4<f32>) -> usize {
+        if self.environment.is_none() {
+            return 0;
+        }
+
+        let view_projection = projection * view.rotation.to_homogeneous();
+        let inverse_view_projection = view_projection.try_inverse().unwrap_or_else(Matrix4::identity);
+        let camera_position = view.inverse() * Point3::origin();
+        let uniforms = SkyboxUniforms {
+            inverse_view_projection: inverse_view_projection.into(),
+            camera_position: [camera_position.x, camera_position.y, camera_position.z, 1.],
+            fog: FogUniforms::new(self.fog),
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        1
+    }
+
+    pub(crate) fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if let Some((_, bind_group)) = &self.environment {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+            stats.draw(3, 1);
+            stats.texture_binds += 1;
+            stats.pipeline_switches += 1;
+        }
+        stats
+    }
+}
+
+fn dimension_mismatch() -> GearError {
+    ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)).into()
+}
+
+// Depth is cleared to the far plane at zero, so the sky only passes where nothing has been drawn yet
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("skybox_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState { module: shader_module, entry_point: "main", buffers: &[] },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format, blend: None, write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
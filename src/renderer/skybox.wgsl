@@ -0,0 +1,38 @@
+struct VertexOutput {
+    [[location(0)]] direction: vec3<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct Uniforms {
+    // Of the camera's rotation and projection, without its position
+    inverse_view_projection: mat4x4<f32>;
+    camera_position: vec4<f32>;
+    fog: Fog;
+};
+
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+[[group(0), binding(1)]]
+var environment: texture_cube<f32>;
+[[group(0), binding(2)]]
+var environment_sampler: sampler;
+
+// One triangle covering the viewport on the far plane, so anything drawn hides it
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    let pos = uv * 2.0 - vec2<f32>(1.0, 1.0);
+    let world = uniforms.inverse_view_projection * vec4<f32>(pos, 0.5, 1.0);
+    var out: VertexOutput;
+    out.direction = world.xyz / world.w;
+    out.pos = vec4<f32>(pos, 0.0, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(environment, environment_sampler, in.direction);
+    let fog = sky_fog_amount(uniforms.fog, uniforms.camera_position.xyz, in.direction);
+    return vec4<f32>(mix(color.rgb, uniforms.fog.color.rgb, fog), color.a);
+}
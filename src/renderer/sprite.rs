@@ -0,0 +1,590 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::mem::size_of;
+use std::ops::Range;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Matrix3;
+use nalgebra::Matrix4;
+use nalgebra::Point2;
+use wgpu::util::BufferInitDescriptor;
+use wgpu::util::DeviceExt;
+use wgpu::vertex_attr_array;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingType;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::IndexFormat;
+use wgpu::InputStepMode;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilFaceState;
+use wgpu::StencilOperation;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+
+use super::stats::FrameStats;
+use crate::Texture;
+use crate::TextureArray;
+
+// Sprites past this in one frame are dropped
+const MAX_SPRITES: usize = 1 << 16;
+const QUAD_INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+// Counter clockwise from the bottom left, the unit quad a sprite transform is applied to
+const QUAD_CORNERS: [[f32; 2]; 4] = [[-0.5, -0.5], [0.5, -0.5], [0.5, 0.5], [-0.5, 0.5]];
+
+// Sprites and tile layers on higher layers draw over those on lower ones whatever order they were submitted in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RenderLayer(pub i32);
+
+impl RenderLayer {
+    pub const BACKGROUND: RenderLayer = RenderLayer(-1000);
+    pub const WORLD: RenderLayer = RenderLayer(0);
+    pub const CHARACTERS: RenderLayer = RenderLayer(1000);
+    pub const UI: RenderLayer = RenderLayer(2000);
+}
+
+// Lets sprites mask each other through the stencil buffer, which starts every frame at zero. Masks on a layer and sort
+// key draw before the sprites they mask
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StencilMode {
+    Off,
+    // Draws nothing and sets the stencil to the reference wherever the sprite is more than half opaque
+    Write(u8),
+    // Draws only where the stencil is the reference
+    Equal(u8),
+    // Draws only where the stencil isn't the reference
+    NotEqual(u8),
+}
+
+impl Default for StencilMode {
+    fn default() -> StencilMode {
+        StencilMode::Off
+    }
+}
+
+impl StencilMode {
+    fn reference(self) -> u32 {
+        match self {
+            StencilMode::Off => 0,
+            StencilMode::Write(reference) | StencilMode::Equal(reference) | StencilMode::NotEqual(reference) => {
+                reference as u32
+            },
+        }
+    }
+
+    // Which of the pipelines draws with the mode
+    fn index(self) -> usize {
+        match self {
+            StencilMode::Off => 0,
+            StencilMode::Write(_) => 1,
+            StencilMode::Equal(_) => 2,
+            StencilMode::NotEqual(_) => 3,
+        }
+    }
+}
+
+const STENCIL_MODES: [StencilMode; 4] =
+    [StencilMode::Off, StencilMode::Write(0), StencilMode::Equal(0), StencilMode::NotEqual(0)];
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub(crate) struct SpriteVertex {
+    pub(crate) position: [f32; 2],
+    pub(crate) tex_coord: [f32; 2],
+    pub(crate) tint: [f32; 4],
+    // Of the texture array the sprite is drawn from, zero for a texture
+    pub(crate) layer: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub(crate) struct SpriteUniforms {
+    pub(crate) view_projection: [[f32; 4]; 4],
+}
+
+// Sprites drawn from different layers of an array still share a batch
+#[derive(Clone, Debug)]
+enum SpriteTexture {
+    Texture(Texture),
+    Array(TextureArray),
+}
+
+impl SpriteTexture {
+    fn id(&self) -> u64 {
+        match self {
+            SpriteTexture::Texture(texture) => texture.id(),
+            SpriteTexture::Array(array) => array.id(),
+        }
+    }
+
+    fn bind_group(&self) -> &BindGroup {
+        match self {
+            SpriteTexture::Texture(texture) => texture.bind_group(),
+            SpriteTexture::Array(array) => array.bind_group(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct QueuedSprite {
+    layer: RenderLayer,
+    sort_key: f32,
+    stencil: StencilMode,
+    scissor: Option<[u32; 4]>,
+    texture: SpriteTexture,
+    vertices: [SpriteVertex; 4],
+}
+
+#[derive(Debug)]
+struct Batch {
+    layer: RenderLayer,
+    stencil: StencilMode,
+    scissor: Option<[u32; 4]>,
+    texture: SpriteTexture,
+    indices: Range<u32>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SpriteBatcher {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    array_pipeline_layout: PipelineLayout,
+    // One for each stencil mode, the first leaves the stencil alone
+    pipelines: Vec<RenderPipeline>,
+    // The same for sprites drawn from texture arrays
+    array_pipelines: Vec<RenderPipeline>,
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    uniform_buffer: Buffer,
+    uniform_bind_group_layout: BindGroupLayout,
+    uniform_bind_group: BindGroup,
+    // Applied to every sprite pushed until they are set again
+    layer: RenderLayer,
+    sort_key: f32,
+    stencil: StencilMode,
+    scissor: Option<[u32; 4]>,
+    // The window size the scissor rectangles were given in
+    window_size: [u32; 2],
+    sprites: Vec<QueuedSprite>,
+    batches: Vec<Batch>,
+}
+
+impl SpriteBatcher {
+    pub(crate) fn new(
+        device: &Device,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        texture_layout: &BindGroupLayout,
+        array_layout: &BindGroupLayout,
+    ) -> SpriteBatcher {
+        let vertex_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("sprite_vertex_buffer"),
+            size: (MAX_SPRITES * 4 * size_of::<SpriteVertex>()) as u64,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // Every quad uses the same indices, so they are written once for the largest possible batch
+        let indices: Vec<u32> =
+            (0..MAX_SPRITES as u32).flat_map(|sprite| QUAD_INDICES.iter().map(move |index| sprite * 4 + index)).collect();
+        let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("sprite_index_buffer"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: BufferUsage::INDEX,
+        });
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("sprite_uniform_buffer"),
+            size: size_of::<SpriteUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("sprite_uniform_bind_group_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(size_of::<SpriteUniforms>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let uniform_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("sprite_uniform_bind_group"),
+            layout: &uniform_bind_group_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() }],
+        });
+
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("sprite_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("sprite.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("sprite_pipeline_layout"),
+            bind_group_layouts: &[&uniform_bind_group_layout, texture_layout],
+            push_constant_ranges: &[],
+        });
+        let array_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("sprite_array_pipeline_layout"),
+            bind_group_layouts: &[&uniform_bind_group_layout, array_layout],
+            push_constant_ranges: &[],
+        });
+
+        let targets = (format, depth_format, 1);
+        let pipelines = create_pipelines(device, &pipeline_layout, &shader_module, targets, false);
+        let array_pipelines = create_pipelines(device, &array_pipeline_layout, &shader_module, targets, true);
+
+        SpriteBatcher {
+            format,
+            depth_format,
+            shader_module,
+            pipeline_layout,
+            array_pipeline_layout,
+            pipelines,
+            array_pipelines,
+            vertex_buffer,
+            index_buffer,
+            uniform_buffer,
+            uniform_bind_group_layout,
+            uniform_bind_group,
+            layer: RenderLayer::default(),
+            sort_key: 0.,
+            stencil: StencilMode::Off,
+            scissor: None,
+            window_size: [1, 1],
+            sprites: vec![],
+            batches: vec![],
+        }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        let targets = (self.format, self.depth_format, sample_count);
+        self.pipelines = create_pipelines(device, &self.pipeline_layout, &self.shader_module, targets, false);
+        self.array_pipelines = create_pipelines(device, &self.array_pipeline_layout, &self.shader_module, targets, true);
+    }
+
+    // Anything else drawn with the sprite pipeline binds its uniforms through this
+    pub(crate) fn uniform_layout(&self) -> &BindGroupLayout {
+        &self.uniform_bind_group_layout
+    }
+
+    pub(crate) fn pipeline(&self) -> &RenderPipeline {
+        &self.pipelines[0]
+    }
+
+    // Indices for quads of four sprite vertices each, enough for as many quads as sprites in a frame
+    pub(crate) fn quad_indices(&self) -> &Buffer {
+        &self.index_buffer
+    }
+
+    pub(crate) fn set_layer(&mut self, layer: RenderLayer) {
+        self.layer = layer;
+    }
+
+    pub(crate) fn layer(&self) -> RenderLayer {
+        self.layer
+    }
+
+    pub(crate) fn set_sort_key(&mut self, sort_key: f32) {
+        self.sort_key = sort_key;
+    }
+
+    pub(crate) fn sort_key(&self) -> f32 {
+        self.sort_key
+    }
+
+    pub(crate) fn set_stencil(&mut self, stencil: StencilMode) {
+        self.stencil = stencil;
+    }
+
+    pub(crate) fn stencil(&self) -> StencilMode {
+        self.stencil
+    }
+
+    // In window pixels as [x, y, width, height] from the top left
+    pub(crate) fn set_scissor(&mut self, scissor: Option<[u32; 4]>) {
+        self.scissor = scissor;
+    }
+
+    pub(crate) fn scissor(&self) -> Option<[u32; 4]> {
+        self.scissor
+    }
+
+    // The region is in texture coordinates as [left, top, right, bottom]
+    pub(crate) fn push(&mut self, texture: &Texture, transform: &Matrix3<f32>, region: [f32; 4], tint: [f32; 4]) {
+        self.queue(SpriteTexture::Texture(texture.clone()), transform, region, tint, 0);
+    }
+
+    // Layers past the end of the array are left out
+    pub(crate) fn push_layer(
+        &mut self,
+        array: &TextureArray,
+        layer: u32,
+        transform: &Matrix3<f32>,
+        region: [f32; 4],
+        tint: [f32; 4],
+    ) {
+        if layer < array.layer_count() {
+            self.queue(SpriteTexture::Array(array.clone()), transform, region, tint, layer);
+        }
+    }
+
+    fn queue(&mut self, texture: SpriteTexture, transform: &Matrix3<f32>, region: [f32; 4], tint: [f32; 4], layer: u32) {
+        if self.sprites.len() >= MAX_SPRITES {
+            return;
+        }
+
+        let tex_coords = [[region[0], region[3]], [region[2], region[3]], [region[2], region[1]], [region[0], region[1]]];
+        let mut vertices = [SpriteVertex::zeroed(); 4];
+        for (i, vertex) in vertices.iter_mut().enumerate() {
+            let position = transform.transform_point(&Point2::from(QUAD_CORNERS[i]));
+            let position = [position.x, position.y];
+            *vertex = SpriteVertex { position, tex_coord: tex_coords[i], tint, layer: layer as f32 };
+        }
+
+        let (layer, sort_key, stencil, scissor) = (self.layer, self.sort_key, self.stencil, self.scissor);
+        self.sprites.push(QueuedSprite { layer, sort_key, stencil, scissor, texture, vertices });
+    }
+
+    // Sprites are ordered by layer and then sort key, sprites on a layer sharing a sort key and a texture end up next to
+    // each other so they cost a single draw, returns the number of writes
+    pub(crate) fn prepare(&mut self, queue: &Queue, view_projection: Matrix4<f32>, window_size: [u32; 2]) -> usize {
+        self.batches.clear();
+        self.window_size = window_size;
+        if self.sprites.is_empty() {
+            return 0;
+        }
+
+        let is_mask = |sprite: &QueuedSprite| matches!(sprite.stencil, StencilMode::Write(_));
+        self.sprites.sort_by(|a, b| {
+            let sort_key = a.sort_key.partial_cmp(&b.sort_key).unwrap_or(Ordering::Equal);
+            a.layer.cmp(&b.layer).then(sort_key).then(is_mask(b).cmp(&is_mask(a))).then(a.texture.id().cmp(&b.texture.id()))
+        });
+
+        let mut vertices = Vec::with_capacity(self.sprites.len() * 4);
+        for (i, sprite) in self.sprites.iter().enumerate() {
+            vertices.extend_from_slice(&sprite.vertices);
+
+            let index = i as u32 * 6;
+            match self.batches.last_mut() {
+                Some(batch)
+                    if batch.layer == sprite.layer
+                        && batch.stencil == sprite.stencil
+                        && batch.scissor == sprite.scissor
+                        && batch.texture.id() == sprite.texture.id() =>
+                {
+                    batch.indices.end = index + 6
+                },
+                _ => self.batches.push(Batch {
+                    layer: sprite.layer,
+                    stencil: sprite.stencil,
+                    scissor: sprite.scissor,
+                    texture: sprite.texture.clone(),
+                    indices: index..index + 6,
+                }),
+            }
+        }
+
+        let uniforms = SpriteUniforms { view_projection: view_projection.into() };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+        self.sprites.clear();
+        2
+    }
+
+    // In ascending order without repeats
+    pub(crate) fn layers(&self) -> impl Iterator<Item = RenderLayer> + '_ {
+        let mut previous = None;
+        self.batches.iter().map(|batch| batch.layer).filter(move |layer| previous.replace(*layer) != Some(*layer))
+    }
+
+    // Scissor rectangles are scaled from the window to the target, which is left unclipped afterwards
+    pub(crate) fn render<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        layer: RenderLayer,
+        target_size: [u32; 2],
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        let start = self.batches.iter().position(|batch| batch.layer == layer);
+        let batches = match start {
+            Some(start) => self.batches[start..].iter().take_while(|batch| batch.layer == layer),
+            None => return stats,
+        };
+
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint32);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        let mut pipeline = None;
+        let mut scissor = None;
+        for batch in batches {
+            let array = matches!(batch.texture, SpriteTexture::Array(_));
+            if pipeline != Some((batch.stencil.index(), array)) {
+                pipeline = Some((batch.stencil.index(), array));
+                let pipelines = if array { &self.array_pipelines } else { &self.pipelines };
+                render_pass.set_pipeline(&pipelines[batch.stencil.index()]);
+                stats.pipeline_switches += 1;
+            }
+            render_pass.set_stencil_reference(batch.stencil.reference());
+
+            let rect = match batch.scissor {
+                Some(rect) => scale_scissor(rect, self.window_size, target_size),
+                None => [0, 0, target_size[0], target_size[1]],
+            };
+            if rect[2] == 0 || rect[3] == 0 {
+                continue;
+            }
+            if scissor != Some(rect) {
+                scissor = Some(rect);
+                render_pass.set_scissor_rect(rect[0], rect[1], rect[2], rect[3]);
+            }
+
+            render_pass.set_bind_group(1, batch.texture.bind_group(), &[]);
+            render_pass.draw_indexed(batch.indices.clone(), 0, 0..1);
+            stats.draw(batch.indices.end - batch.indices.start, 1);
+            stats.texture_binds += 1;
+        }
+        if scissor.is_some() {
+            render_pass.set_scissor_rect(0, 0, target_size[0], target_size[1]);
+        }
+        stats
+    }
+}
+
+// Kept within the target, rounding outwards so the edges of the rectangle are covered
+fn scale_scissor(rect: [u32; 4], window_size: [u32; 2], target_size: [u32; 2]) -> [u32; 4] {
+    let scale = |value: u32, axis: usize| value as f32 * target_size[axis] as f32 / window_size[axis].max(1) as f32;
+    let left = (scale(rect[0], 0).floor() as u32).min(target_size[0]);
+    let top = (scale(rect[1], 1).floor() as u32).min(target_size[1]);
+    let right = (scale(rect[0] + rect[2], 0).ceil() as u32).min(target_size[0]);
+    let bottom = (scale(rect[1] + rect[3], 1).ceil() as u32).min(target_size[1]);
+    [left, top, right.saturating_sub(left), bottom.saturating_sub(top)]
+}
+
+// One for each stencil mode, the targets are the color format, the depth format and the sample count
+fn create_pipelines(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    targets: (TextureFormat, TextureFormat, u32),
+    array: bool,
+) -> Vec<RenderPipeline> {
+    STENCIL_MODES.iter().map(|stencil| create_pipeline(device, layout, shader_module, targets, *stencil, array)).collect()
+}
+
+// Sprites draw over whatever is in the depth buffer and leave it untouched
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    (format, depth_format, sample_count): (TextureFormat, TextureFormat, u32),
+    stencil: StencilMode,
+    array: bool,
+) -> RenderPipeline {
+    let face = |compare, pass_op| StencilFaceState {
+        compare,
+        fail_op: StencilOperation::Keep,
+        depth_fail_op: StencilOperation::Keep,
+        pass_op,
+    };
+    let stencil_state =
+        |face: StencilFaceState, write_mask| StencilState { front: face, back: face, read_mask: 0xff, write_mask };
+    let (main, mask) = if array { ("array_main", "array_mask") } else { ("main", "mask") };
+    let (stencil, fragment_entry_point, write_mask) = match stencil {
+        StencilMode::Off => (StencilState::default(), main, ColorWrite::ALL),
+        StencilMode::Write(_) => {
+            (stencil_state(face(CompareFunction::Always, StencilOperation::Replace), 0xff), mask, ColorWrite::empty())
+        },
+        StencilMode::Equal(_) => {
+            (stencil_state(face(CompareFunction::Equal, StencilOperation::Keep), 0), main, ColorWrite::ALL)
+        },
+        StencilMode::NotEqual(_) => {
+            (stencil_state(face(CompareFunction::NotEqual, StencilOperation::Keep), 0), main, ColorWrite::ALL)
+        },
+    };
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("sprite_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "main",
+            buffers: &[VertexBufferLayout {
+                array_stride: size_of::<SpriteVertex>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Vertex,
+                attributes: &vertex_attr_array![
+                    0 => Float32x2,
+                    1 => Float32x2,
+                    2 => Float32x4,
+                    3 => Float32,
+                ],
+            }],
+        },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::Always,
+            stencil,
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: fragment_entry_point,
+            targets: &[ColorTargetState { format, blend: Some(BlendState::ALPHA_BLENDING), write_mask }],
+        }),
+    })
+}
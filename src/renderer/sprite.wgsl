@@ -0,0 +1,71 @@
+struct VertexInput {
+    [[location(0)]] pos: vec2<f32>;
+    [[location(1)]] tex_coord: vec2<f32>;
+    [[location(2)]] tint: vec4<f32>;
+    [[location(3)]] layer: f32;
+};
+
+struct VertexOutput {
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[location(1)]] tint: vec4<f32>;
+    [[location(2)]] layer: f32;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[block]]
+struct Uniforms {
+    view_projection: mat4x4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+[[group(1), binding(0)]]
+var sprite_texture: texture_2d<f32>;
+
+[[group(1), binding(1)]]
+var sprite_sampler: sampler;
+
+// Bound in place of the texture by the array pipelines
+[[group(1), binding(0)]]
+var sprite_array: texture_2d_array<f32>;
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coord = in.tex_coord;
+    out.tint = in.tint;
+    out.layer = in.layer;
+    out.pos = uniforms.view_projection * vec4<f32>(in.pos, 0.0, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(sprite_texture, sprite_sampler, in.tex_coord) * in.tint;
+}
+
+// Masks only write the stencil, so what is transparent enough has to be discarded to keep it out
+[[stage(fragment)]]
+fn mask(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(sprite_texture, sprite_sampler, in.tex_coord) * in.tint;
+    if (color.a < 0.5) {
+        discard;
+    }
+    return color;
+}
+
+// The layer is the same over the whole quad, rounding keeps interpolation from landing it on the next one
+[[stage(fragment)]]
+fn array_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(sprite_array, sprite_sampler, in.tex_coord, i32(round(in.layer))) * in.tint;
+}
+
+[[stage(fragment)]]
+fn array_mask(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(sprite_array, sprite_sampler, in.tex_coord, i32(round(in.layer))) * in.tint;
+    if (color.a < 0.5) {
+        discard;
+    }
+    return color;
+}
@@ -0,0 +1,195 @@
+// Copyright 2021 Chay Nabors.
+
+use std::ops::AddAssign;
+use std::time::Duration;
+use std::time::Instant;
+
+use image::Rgba;
+use image::RgbaImage;
+use nalgebra::Matrix3;
+use nalgebra::Vector2;
+use wgpu::BindGroupLayout;
+use wgpu::Device;
+use wgpu::Queue;
+
+use crate::Texture;
+use crate::TextureFilter;
+use crate::TextureOptions;
+use crate::TextureWrap;
+
+// Rows of five pixels from the top, the highest bit is the leftmost pixel. Only what the overlay writes is here
+const GLYPHS: [(char, [u8; 7]); 21] = [
+    ('0', [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110]),
+    ('1', [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110]),
+    ('2', [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111]),
+    ('3', [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110]),
+    ('4', [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010]),
+    ('5', [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110]),
+    ('6', [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110]),
+    ('7', [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000]),
+    ('8', [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110]),
+    ('9', [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100]),
+    ('.', [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100]),
+    (':', [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000]),
+    ('A', [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001]),
+    ('D', [0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110]),
+    ('E', [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111]),
+    ('F', [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000]),
+    ('M', [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001]),
+    ('P', [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000]),
+    ('R', [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001]),
+    ('S', [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110]),
+    ('W', [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010]),
+];
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+// Glyphs are a pixel apart in the font texture so none of them picks up its neighbours
+const CELL_WIDTH: u32 = GLYPH_WIDTH + 1;
+// Window pixels per font pixel
+const SCALE: f32 = 2.;
+const MARGIN: f32 = 8.;
+const PADDING: f32 = 4.;
+// Frame times are averaged over this long before the numbers change so they can be read
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+// What the renderer recorded for the last submitted frame. Its own passes over whole targets, like the post effects,
+// aren't counted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub draw_calls: usize,
+    // Instances culled on the gpu aren't known to the renderer and are left out
+    pub triangles: u64,
+    // Bind groups with textures, so every material and every texture of the sprite and tile batches
+    pub texture_binds: usize,
+    // Writes of buffers and textures, from meshes updated since the frame before to the renderer's own per frame data
+    pub buffer_uploads: usize,
+    pub pipeline_switches: usize,
+}
+
+impl FrameStats {
+    // A draw of triangles from this many vertices or indices for each instance
+    pub(crate) fn draw(&mut self, index_count: u32, instance_count: u32) {
+        self.draw_calls += 1;
+        self.triangles += (index_count / 3) as u64 * instance_count as u64;
+    }
+}
+
+impl AddAssign for FrameStats {
+    fn add_assign(&mut self, other: FrameStats) {
+        self.draw_calls += other.draw_calls;
+        self.triangles += other.triangles;
+        self.texture_binds += other.texture_binds;
+        self.buffer_uploads += other.buffer_uploads;
+        self.pipeline_switches += other.pipeline_switches;
+    }
+}
+
+// Frame rate, frame time and draw calls in the top left corner of the window
+pub(crate) struct StatsOverlay {
+    font: Texture,
+    enabled: bool,
+    last_frame: Option<Instant>,
+    frames: u32,
+    elapsed: Duration,
+    frame_time: Duration,
+    draw_calls: usize,
+}
+
+impl StatsOverlay {
+    pub(crate) fn new(device: &Device, queue: &Queue, texture_layout: &BindGroupLayout) -> StatsOverlay {
+        // The cell after the glyphs is solid for the panel behind the text
+        let mut font = RgbaImage::new(CELL_WIDTH * (GLYPHS.len() as u32 + 1), GLYPH_HEIGHT);
+        for (index, (_, rows)) in GLYPHS.iter().chain(&[(' ', [0b11111; 7])]).enumerate() {
+            for (y, row) in rows.iter().enumerate() {
+                for x in (0..GLYPH_WIDTH).filter(|x| row >> (GLYPH_WIDTH - 1 - x) & 1 == 1) {
+                    font.put_pixel(index as u32 * CELL_WIDTH + x, y as u32, Rgba([255; 4]));
+                }
+            }
+        }
+        let options =
+            TextureOptions { srgb: true, mipmaps: false, filter: TextureFilter::Nearest, wrap: TextureWrap::Clamp };
+        let font = Texture::from_image(device, queue, texture_layout, font, &options);
+
+        StatsOverlay {
+            font,
+            enabled: false,
+            last_frame: None,
+            frames: 0,
+            elapsed: Duration::default(),
+            frame_time: Duration::default(),
+            draw_calls: 0,
+        }
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn font(&self) -> &Texture {
+        &self.font
+    }
+
+    // Called every frame whether or not the overlay is shown so the numbers are right as soon as it is
+    pub(crate) fn tick(&mut self) {
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame.replace(now) {
+            self.frames += 1;
+            self.elapsed += now - last_frame;
+            if self.elapsed >= UPDATE_INTERVAL {
+                self.frame_time = self.elapsed / self.frames;
+                self.frames = 0;
+                self.elapsed = Duration::default();
+            }
+        }
+    }
+
+    // Draws of the last frame, which was submitted before the overlay is drawn
+    pub(crate) fn set_draw_calls(&mut self, draw_calls: usize) {
+        self.draw_calls = draw_calls;
+    }
+
+    fn lines(&self) -> [String; 3] {
+        let seconds = self.frame_time.as_secs_f32();
+        let fps = if seconds > 0. { 1. / seconds } else { 0. };
+        [format!("FPS {:.1}", fps), format!("FRAME {:.2} MS", seconds * 1000.), format!("DRAWS {}", self.draw_calls)]
+    }
+
+    // Transforms in pixels from the center of the viewport with y up, what Camera2D::view maps world space to, with
+    // their regions and tints. The panel comes first to be drawn under the text
+    pub(crate) fn quads(&self, viewport: [f32; 2]) -> Vec<(Matrix3<f32>, [f32; 4], [f32; 4])> {
+        // From the top left of the window with y down
+        let quad = |x: f32, y: f32, width: f32, height: f32| {
+            let center = Vector2::new(x + width / 2. - viewport[0] / 2., viewport[1] / 2. - y - height / 2.);
+            Matrix3::new_translation(&center) * Matrix3::new_nonuniform_scaling(&Vector2::new(width, height))
+        };
+        let font_width = self.font.size()[0] as f32;
+        let region = |index: usize| {
+            let start = (index as u32 * CELL_WIDTH) as f32;
+            [start / font_width, 0., (start + GLYPH_WIDTH as f32) / font_width, 1.]
+        };
+
+        let lines = self.lines();
+        let columns = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) as f32;
+        let advance = CELL_WIDTH as f32 * SCALE;
+        let line_height = (GLYPH_HEIGHT + 2) as f32 * SCALE;
+        let panel_width = columns * advance - SCALE + PADDING * 2.;
+        let panel_height = lines.len() as f32 * line_height - 2. * SCALE + PADDING * 2.;
+
+        let mut quads = vec![(quad(MARGIN, MARGIN, panel_width, panel_height), region(GLYPHS.len()), [0., 0., 0., 0.6])];
+        for (row, line) in lines.iter().enumerate() {
+            let y = MARGIN + PADDING + row as f32 * line_height;
+            for (column, c) in line.chars().enumerate() {
+                if let Some(index) = GLYPHS.iter().position(|(glyph, _)| *glyph == c) {
+                    let x = MARGIN + PADDING + column as f32 * advance;
+                    let size = [GLYPH_WIDTH as f32 * SCALE, GLYPH_HEIGHT as f32 * SCALE];
+                    quads.push((quad(x, y, size[0], size[1]), region(index), [1.; 4]));
+                }
+            }
+        }
+        quads
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2021 Chay Nabors.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+use nalgebra::Isometry3;
+use nalgebra::Matrix4;
+use wgpu::BindGroup;
+use wgpu::BindGroupLayout;
+use wgpu::CommandEncoder;
+use wgpu::Device;
+use wgpu::Extent3d;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+
+use super::post::PostProcessor;
+use super::post::LDR_FORMAT;
+use crate::Texture;
+use crate::TextureFilter;
+use crate::TextureOptions;
+use crate::TextureWrap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderTargetFormat {
+    // Linear colors past one, for targets drawn back into the scene, which tonemaps them along with everything else
+    Hdr,
+    // Tonemapped with the post effects but without bloom
+    Srgb,
+}
+
+// A texture the scene can be drawn into from another camera with Renderer::render_to_target, clones share the same
+// texture
+#[derive(Clone, Debug)]
+pub struct RenderTarget {
+    inner: Arc<RenderTargetData>,
+}
+
+#[derive(Debug)]
+struct RenderTargetData {
+    size: [u32; 2],
+    format: RenderTargetFormat,
+    texture: Texture,
+    // Srgb targets draw the scene here and tonemap it onto the texture
+    hdr: Option<(wgpu::Texture, TextureView, BindGroup)>,
+    attachments: Mutex<TargetAttachments>,
+}
+
+// Recreated whenever the renderer's sample count changes
+#[derive(Debug)]
+pub(crate) struct TargetAttachments {
+    sample_count: u32,
+    _depth: wgpu::Texture,
+    depth_view: TextureView,
+    multisampled: Option<(wgpu::Texture, TextureView)>,
+}
+
+impl TargetAttachments {
+    fn new(
+        device: &Device,
+        size: [u32; 2],
+        hdr_format: TextureFormat,
+        depth_format: TextureFormat,
+        sample_count: u32,
+    ) -> TargetAttachments {
+        let (depth, depth_view) = create_texture(device, size, depth_format, sample_count, false);
+        let multisampled = match sample_count > 1 {
+            true => Some(create_texture(device, size, hdr_format, sample_count, false)),
+            false => None,
+        };
+        TargetAttachments { sample_count, _depth: depth, depth_view, multisampled }
+    }
+
+    pub(crate) fn depth(&self) -> &TextureView {
+        &self.depth_view
+    }
+}
+
+impl RenderTarget {
+    pub(crate) fn new(
+        device: &Device,
+        texture_layout: &BindGroupLayout,
+        post: &PostProcessor,
+        size: [u32; 2],
+        format: RenderTargetFormat,
+        hdr_format: TextureFormat,
+        depth_format: TextureFormat,
+    ) -> RenderTarget {
+        let size = [size[0].max(1), size[1].max(1)];
+        let texture_format = match format {
+            RenderTargetFormat::Hdr => hdr_format,
+            RenderTargetFormat::Srgb => LDR_FORMAT,
+        };
+        let (texture, _) = create_texture(device, size, texture_format, 1, true);
+        let options =
+            TextureOptions { srgb: false, mipmaps: false, filter: TextureFilter::Linear, wrap: TextureWrap::Clamp };
+        let texture = Texture::from_texture(device, texture_layout, texture, size, &options);
+
+        let hdr = match format {
+            RenderTargetFormat::Hdr => None,
+            RenderTargetFormat::Srgb => {
+                let (hdr, view) = create_texture(device, size, hdr_format, 1, true);
+                let bind_group = post.create_target_bind_group(device, &view);
+                Some((hdr, view, bind_group))
+            },
+        };
+
+        // Made again on the first frame drawing into the target if the renderer is multisampling
+        let attachments = TargetAttachments::new(device, size, hdr_format, depth_format, 1);
+        let inner = RenderTargetData { size, format, texture, hdr, attachments: Mutex::new(attachments) };
+        RenderTarget { inner: Arc::new(inner) }
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.inner.size
+    }
+
+    pub fn format(&self) -> RenderTargetFormat {
+        self.inner.format
+    }
+
+    // Holds what was drawn into the target by the last frame rendering it
+    pub fn texture(&self) -> &Texture {
+        &self.inner.texture
+    }
+
+    pub(crate) fn attachments(
+        &self,
+        device: &Device,
+        hdr_format: TextureFormat,
+        depth_format: TextureFormat,
+        sample_count: u32,
+    ) -> MutexGuard<TargetAttachments> {
+        let mut attachments = self.inner.attachments.lock().unwrap();
+        if attachments.sample_count != sample_count {
+            *attachments = TargetAttachments::new(device, self.inner.size, hdr_format, depth_format, sample_count);
+        }
+        attachments
+    }
+
+    // The view the scene is drawn to and the view to resolve it into
+    pub(crate) fn color<'a>(&'a self, attachments: &'a TargetAttachments) -> (&'a TextureView, Option<&'a TextureView>) {
+        let view = match &self.inner.hdr {
+            Some((_, view, _)) => view,
+            None => self.inner.texture.view(),
+        };
+        match &attachments.multisampled {
+            Some((_, multisampled)) => (multisampled, Some(view)),
+            None => (view, None),
+        }
+    }
+
+    // After the scene is drawn
+    pub(crate) fn finish(&self, encoder: &mut CommandEncoder, post: &PostProcessor) {
+        if let Some((_, _, bind_group)) = &self.inner.hdr {
+            post.tonemap_target(encoder, bind_group, self.inner.texture.view());
+        }
+    }
+}
+
+// A camera drawing into a target this frame
+#[derive(Debug)]
+pub(crate) struct TargetDraw {
+    pub(crate) target: RenderTarget,
+    pub(crate) view: Isometry3<f32>,
+    pub(crate) projection: Matrix4<f32>,
+}
+
+fn create_texture(
+    device: &Device,
+    size: [u32; 2],
+    format: TextureFormat,
+    sample_count: u32,
+    sampled: bool,
+) -> (wgpu::Texture, TextureView) {
+    let usage = match sampled {
+        true => TextureUsage::RENDER_ATTACHMENT | TextureUsage::SAMPLED,
+        false => TextureUsage::RENDER_ATTACHMENT,
+    };
+    let texture = device.create_texture(&TextureDescriptor {
+        label: Some("render_target"),
+        size: Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 },
+        mip_level_count: 1,
+        sample_count,
+        dimension: TextureDimension::D2,
+        format,
+        usage,
+    });
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    (texture, view)
+}
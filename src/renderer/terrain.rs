@@ -0,0 +1,520 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use nalgebra::Vector3;
+use wgpu::util::BufferInitDescriptor;
+use wgpu::util::DeviceExt;
+use wgpu::vertex_attr_array;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBindingType;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::Face;
+use wgpu::FilterMode;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::IndexFormat;
+use wgpu::InputStepMode;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureViewDimension;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+use wgpu::BIND_BUFFER_ALIGNMENT;
+
+use super::stats::FrameStats;
+use super::GpuMesh;
+use crate::model::Vertex;
+use crate::Aabb;
+use crate::Terrain;
+use crate::Texture;
+
+// Quads along each side of a chunk, a power of two so every level of detail halves it
+const CHUNK_SIZE: u32 = 32;
+const LEVELS: u32 = 6;
+const MAX_LAYERS: usize = 4;
+// Chunks this many chunk widths away drop to the second level of detail, and each doubling of that drops another
+const LOD_DISTANCE: f32 = 2.;
+
+// A texture repeating every tile size world units along x and z
+#[derive(Clone, Debug)]
+pub struct TerrainLayer {
+    pub texture: Texture,
+    pub tile_size: f32,
+}
+
+// The red, green, blue and alpha channels of the splat map are how much of each of up to four layers covers the
+// terrain, they are normalized so they don't have to add up to one. The splat map stretches over the whole terrain
+#[derive(Clone, Debug)]
+pub struct TerrainMaterial {
+    pub splat_map: Texture,
+    pub layers: Vec<TerrainLayer>,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct TerrainUniforms {
+    scales: [f32; 4],
+    layers: [f32; 4],
+}
+
+#[derive(Debug)]
+struct TerrainChunk {
+    vertex_buffer: Arc<Buffer>,
+    // Relative to the chunk's corner
+    bounds: Aabb,
+    corner: Point3<f32>,
+}
+
+// A terrain chunked and uploaded to the gpu, clones share the same buffers
+#[derive(Clone, Debug)]
+pub struct GpuTerrain {
+    inner: Arc<GpuTerrainData>,
+}
+
+#[derive(Debug)]
+struct GpuTerrainData {
+    chunks: Vec<TerrainChunk>,
+    columns: usize,
+    // Indices of every level of detail for each combination of coarser neighbours
+    indices: Vec<Vec<(Arc<Buffer>, u32)>>,
+    lod_distance: f32,
+    bind_group: BindGroup,
+    _uniform_buffer: Buffer,
+}
+
+impl GpuTerrain {
+    // The meshes of the chunks and their transforms, levels of detail picked for a camera at the position. Neighbours
+    // are kept within a level of each other so the finer one can leave out every other vertex along the edge they
+    // share, which is what keeps cracks from opening between them
+    pub(crate) fn meshes(&self, camera_position: Point3<f32>) -> Vec<(GpuMesh, Matrix4<f32>)> {
+        let data = &self.inner;
+        let mut levels: Vec<u32> = data
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let local = camera_position - chunk.corner.coords;
+                let nearest = local.coords.sup(&chunk.bounds.min.coords).inf(&chunk.bounds.max.coords);
+                let ratio = (local.coords - nearest).norm() / data.lod_distance;
+                (ratio.max(1.).log2().floor() as u32).min(LEVELS - 1)
+            })
+            .collect();
+
+        let columns = data.columns;
+        let rows = data.chunks.len() / columns.max(1);
+        let neighbours = |index: usize| {
+            let (column, row) = (index % columns, index / columns);
+            [
+                Some(index).filter(|_| column > 0).map(|index| index - 1),
+                Some(index + 1).filter(|_| column + 1 < columns),
+                Some(index).filter(|_| row > 0).map(|index| index - columns),
+                Some(index + columns).filter(|_| row + 1 < rows),
+            ]
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in 0..levels.len() {
+                for neighbour in neighbours(index).iter().flatten() {
+                    if levels[index] > levels[*neighbour] + 1 {
+                        levels[index] = levels[*neighbour] + 1;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut meshes = Vec::with_capacity(data.chunks.len());
+        for (index, chunk) in data.chunks.iter().enumerate() {
+            let level = levels[index];
+            let mut coarser = 0;
+            for (side, neighbour) in neighbours(index).iter().enumerate() {
+                if neighbour.map_or(false, |neighbour| levels[neighbour] > level) {
+                    coarser |= 1 << side;
+                }
+            }
+            let (index_buffer, index_count) = &data.indices[level as usize][coarser];
+            let vertex_capacity = ((CHUNK_SIZE + 1) * (CHUNK_SIZE + 1)) as u64 * size_of::<Vertex>() as u64;
+            let mesh = GpuMesh::from_buffers(
+                chunk.vertex_buffer.clone(),
+                vertex_capacity,
+                index_buffer.clone(),
+                *index_count,
+                Some(chunk.bounds),
+            );
+            meshes.push((mesh, Matrix4::new_translation(&chunk.corner.coords)));
+        }
+        meshes
+    }
+}
+
+#[derive(Debug)]
+struct TerrainDraw {
+    terrain: GpuTerrain,
+    mesh: GpuMesh,
+    uniform: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct TerrainRenderer {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    layout: BindGroupLayout,
+    pipeline_layout: PipelineLayout,
+    shader_module: ShaderModule,
+    pipeline: RenderPipeline,
+    layer_sampler: Sampler,
+    splat_sampler: Sampler,
+    white: Texture,
+    draws: Vec<TerrainDraw>,
+}
+
+impl TerrainRenderer {
+    pub(crate) fn new(
+        device: &Device,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        object_layout: &BindGroupLayout,
+        scene_layout: &BindGroupLayout,
+        white: &Texture,
+    ) -> TerrainRenderer {
+        // Past the material bindings the mesh declarations leave unused
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Sampler { filtering: true, comparison: false },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("terrain_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<TerrainUniforms>() as _),
+                    },
+                    count: None,
+                },
+                texture_entry(8),
+                texture_entry(9),
+                texture_entry(10),
+                texture_entry(11),
+                texture_entry(12),
+                sampler_entry(13),
+                sampler_entry(14),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("terrain_pipeline_layout"),
+            bind_group_layouts: &[object_layout, scene_layout, &layout],
+            push_constant_ranges: &[],
+        });
+
+        let source = concat!(include_str!("fog.wgsl"), include_str!("mesh.wgsl"), include_str!("terrain.wgsl"));
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("terrain_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(source)),
+            flags: ShaderFlags::VALIDATION,
+        });
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader_module, format, depth_format, 1);
+
+        let sampler = |label, address_mode| {
+            device.create_sampler(&SamplerDescriptor {
+                label: Some(label),
+                address_mode_u: address_mode,
+                address_mode_v: address_mode,
+                mag_filter: FilterMode::Linear,
+                min_filter: FilterMode::Linear,
+                mipmap_filter: FilterMode::Linear,
+                ..Default::default()
+            })
+        };
+        let layer_sampler = sampler("terrain_layer_sampler", AddressMode::Repeat);
+        let splat_sampler = sampler("terrain_splat_sampler", AddressMode::ClampToEdge);
+
+        TerrainRenderer {
+            format,
+            depth_format,
+            layout,
+            pipeline_layout,
+            shader_module,
+            pipeline,
+            layer_sampler,
+            splat_sampler,
+            white: white.clone(),
+            draws: vec![],
+        }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.shader_module,
+            self.format,
+            self.depth_format,
+            sample_count,
+        );
+    }
+
+    // Layers past the fourth are left out
+    pub(crate) fn create(&self, device: &Device, terrain: &Terrain, material: &TerrainMaterial) -> GpuTerrain {
+        let [width, depth] = terrain.heightmap.size();
+        let columns = ((width.max(2) - 1 + CHUNK_SIZE - 1) / CHUNK_SIZE) as usize;
+        let rows = ((depth.max(2) - 1 + CHUNK_SIZE - 1) / CHUNK_SIZE) as usize;
+        let last = [width.max(2) as i64 - 1, depth.max(2) as i64 - 1];
+
+        let mut chunks = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                let start = [(column as u32 * CHUNK_SIZE) as i64, (row as u32 * CHUNK_SIZE) as i64];
+                let offset = Vector3::new(start[0] as f32 * terrain.spacing, 0., start[1] as f32 * terrain.spacing);
+                let corner = terrain.origin + offset;
+                let mut vertices = Vec::with_capacity(((CHUNK_SIZE + 1) * (CHUNK_SIZE + 1)) as usize);
+                for z in 0..=CHUNK_SIZE as i64 {
+                    for x in 0..=CHUNK_SIZE as i64 {
+                        // Chunks hanging over the far edges repeat the last samples, which draws nothing
+                        let (x, z) = ((start[0] + x).min(last[0]), (start[1] + z).min(last[1]));
+                        let position = terrain.local(x, z) - offset;
+                        let tex_coords = [x as f32 / last[0] as f32, z as f32 / last[1] as f32];
+                        let normal = terrain.sample_normal(x, z).into();
+                        vertices.push(Vertex { position: [position.x, position.y, position.z], tex_coords, normal });
+                    }
+                }
+                let positions = vertices.iter().map(|vertex| Point3::from(vertex.position));
+                let bounds = Aabb::from_points(positions).unwrap_or_else(|| Aabb::new(Point3::origin(), Point3::origin()));
+                let vertex_buffer = Arc::new(device.create_buffer_init(&BufferInitDescriptor {
+                    label: Some("terrain_vertex_buffer"),
+                    contents: bytemuck::cast_slice(&vertices),
+                    usage: BufferUsage::VERTEX,
+                }));
+                chunks.push(TerrainChunk { vertex_buffer, bounds, corner });
+            }
+        }
+
+        let indices = (0..LEVELS)
+            .map(|level| {
+                (0..16)
+                    .map(|coarser| {
+                        let indices = chunk_indices(1 << level, coarser);
+                        let buffer = device.create_buffer_init(&BufferInitDescriptor {
+                            label: Some("terrain_index_buffer"),
+                            contents: bytemuck::cast_slice(&indices),
+                            usage: BufferUsage::INDEX,
+                        });
+                        (Arc::new(buffer), indices.len() as u32)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut uniforms = TerrainUniforms::zeroed();
+        for (i, layer) in material.layers.iter().take(MAX_LAYERS).enumerate() {
+            uniforms.scales[i] = 1. / layer.tile_size.max(f32::EPSILON);
+            uniforms.layers[i] = 1.;
+        }
+        let uniform_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("terrain_uniform_buffer"),
+            contents: bytemuck::bytes_of(&uniforms),
+            usage: BufferUsage::UNIFORM,
+        });
+        let layer = |i: usize| {
+            let texture = material.layers.get(i).map_or(&self.white, |layer| &layer.texture);
+            BindingResource::TextureView(texture.view())
+        };
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("terrain_bind_group"),
+            layout: &self.layout,
+            entries: &[
+                BindGroupEntry { binding: 7, resource: uniform_buffer.as_entire_binding() },
+                BindGroupEntry { binding: 8, resource: BindingResource::TextureView(material.splat_map.view()) },
+                BindGroupEntry { binding: 9, resource: layer(0) },
+                BindGroupEntry { binding: 10, resource: layer(1) },
+                BindGroupEntry { binding: 11, resource: layer(2) },
+                BindGroupEntry { binding: 12, resource: layer(3) },
+                BindGroupEntry { binding: 13, resource: BindingResource::Sampler(&self.layer_sampler) },
+                BindGroupEntry { binding: 14, resource: BindingResource::Sampler(&self.splat_sampler) },
+            ],
+        });
+
+        let lod_distance = CHUNK_SIZE as f32 * terrain.spacing * LOD_DISTANCE;
+        GpuTerrain {
+            inner: Arc::new(GpuTerrainData {
+                chunks,
+                columns,
+                indices,
+                lod_distance,
+                bind_group,
+                _uniform_buffer: uniform_buffer,
+            }),
+        }
+    }
+
+    pub(crate) fn push(&mut self, terrain: &GpuTerrain, mesh: GpuMesh, uniform: u32) {
+        self.draws.push(TerrainDraw { terrain: terrain.clone(), mesh, uniform });
+    }
+
+    pub(crate) fn render<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        object_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
+        visible: &[bool],
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.draws.is_empty() {
+            return stats;
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(1, scene_bind_group, &[]);
+        stats.pipeline_switches += 1;
+        stats.texture_binds += 1;
+        for draw in self.draws.iter().filter(|draw| visible[draw.uniform as usize]) {
+            let offset = (draw.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(0, object_bind_group, &[offset]);
+            render_pass.set_bind_group(2, &draw.terrain.inner.bind_group, &[]);
+            render_pass.set_vertex_buffer(0, draw.mesh.vertex_buffer().slice(..));
+            render_pass.set_index_buffer(draw.mesh.index_buffer().slice(..), IndexFormat::Uint32);
+            render_pass.draw_indexed(0..draw.mesh.index_count(), 0, 0..1);
+            stats.draw(draw.mesh.index_count(), 1);
+            stats.texture_binds += 1;
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.draws.clear();
+    }
+}
+
+// Triangles of a chunk every step vertices. Along the sides in the coarser mask, -x, +x, -z and +z from the lowest
+// bit, every other vertex is moved onto the one before it so the side matches a neighbour of twice the step
+fn chunk_indices(step: u32, coarser: usize) -> Vec<u32> {
+    let row = CHUNK_SIZE + 1;
+    let vertex = |x: u32, z: u32| {
+        let snap = |value: u32| value - value % (step * 2);
+        let x = match (z == 0 && coarser & 4 != 0) || (z == CHUNK_SIZE && coarser & 8 != 0) {
+            true => snap(x),
+            false => x,
+        };
+        let z = match (x == 0 && coarser & 1 != 0) || (x == CHUNK_SIZE && coarser & 2 != 0) {
+            true => snap(z),
+            false => z,
+        };
+        z * row + x
+    };
+
+    let mut indices = vec![];
+    for z in (0..CHUNK_SIZE).step_by(step as usize) {
+        for x in (0..CHUNK_SIZE).step_by(step as usize) {
+            let corners = [vertex(x, z), vertex(x, z + step), vertex(x + step, z), vertex(x + step, z + step)];
+            // Split along the same diagonal as Terrain::height, triangles collapsed by the snapping are left out
+            for triangle in &[[corners[0], corners[1], corners[2]], [corners[3], corners[2], corners[1]]] {
+                if triangle[0] != triangle[1] && triangle[1] != triangle[2] && triangle[0] != triangle[2] {
+                    indices.extend_from_slice(triangle);
+                }
+            }
+        }
+    }
+    indices
+}
+
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("terrain_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "main",
+            buffers: &[VertexBufferLayout {
+                array_stride: size_of::<Vertex>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Vertex,
+                attributes: &vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x3],
+            }],
+        },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: Some(Face::Back),
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format, blend: Some(BlendState::REPLACE), write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
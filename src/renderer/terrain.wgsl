@@ -0,0 +1,86 @@
+// Follows the declarations in mesh.wgsl, the terrain's bindings come after the material ones it leaves unused
+
+[[block]]
+struct TerrainUniforms {
+    // One over the world size each layer repeats at
+    scales: vec4<f32>;
+    // One for the layers the terrain has
+    layers: vec4<f32>;
+};
+
+[[group(2), binding(7)]]
+var<uniform> terrain: TerrainUniforms;
+[[group(2), binding(8)]]
+var splat_map: texture_2d<f32>;
+[[group(2), binding(9)]]
+var layer_0: texture_2d<f32>;
+[[group(2), binding(10)]]
+var layer_1: texture_2d<f32>;
+[[group(2), binding(11)]]
+var layer_2: texture_2d<f32>;
+[[group(2), binding(12)]]
+var layer_3: texture_2d<f32>;
+[[group(2), binding(13)]]
+var layer_sampler: sampler;
+[[group(2), binding(14)]]
+var splat_sampler: sampler;
+
+struct TerrainOutput {
+    [[location(0)]] world_pos: vec3<f32>;
+    [[location(1)]] tex_coord: vec2<f32>;
+    [[location(2)]] normal: vec3<f32>;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> TerrainOutput {
+    var out: TerrainOutput;
+    out.world_pos = (object.model * vec4<f32>(in.pos, 1.0)).xyz;
+    out.tex_coord = in.tex_coord;
+    out.normal = (object.normal * vec4<f32>(in.normal, 0.0)).xyz;
+    out.pos = object.model_view_projection * vec4<f32>(in.pos, 1.0);
+    return out;
+}
+
+// Rough and not metallic, only the diffuse part of the physically based lighting is kept
+[[stage(fragment)]]
+fn main(in: TerrainOutput) -> [[location(0)]] vec4<f32> {
+    let weights = textureSample(splat_map, splat_sampler, in.tex_coord) * terrain.layers;
+    let total = dot(weights, vec4<f32>(1.0, 1.0, 1.0, 1.0));
+    // Where the splat map covers none of the layers the first one shows
+    var blend: vec4<f32> = vec4<f32>(1.0, 0.0, 0.0, 0.0);
+    if (total > 0.0001) {
+        blend = weights / total;
+    }
+
+    let uv = in.world_pos.xz;
+    let albedo = textureSample(layer_0, layer_sampler, uv * terrain.scales.x).rgb * blend.x
+        + textureSample(layer_1, layer_sampler, uv * terrain.scales.y).rgb * blend.y
+        + textureSample(layer_2, layer_sampler, uv * terrain.scales.z).rgb * blend.z
+        + textureSample(layer_3, layer_sampler, uv * terrain.scales.w).rgb * blend.w;
+    let diffuse = albedo / 3.14159265;
+    let n = normalize(in.normal);
+
+    var color: vec3<f32> = scene.ambient.rgb * albedo;
+    if (scene.light_direction.w > 0.5) {
+        let l = normalize(-scene.light_direction.xyz);
+        let shadow = shadow_factor(in.world_pos, n);
+        color = color + diffuse * scene.light_color.rgb * max(dot(n, l), 0.0) * shadow;
+    }
+
+    var i: u32 = 0u;
+    loop {
+        if (i >= lights.count) {
+            break;
+        }
+        let light = lights.lights[i];
+        let l = normalize(light.position.xyz - in.world_pos);
+        let radiance = light.color.rgb * light_attenuation(light, in.world_pos);
+        color = color + diffuse * radiance * max(dot(n, l), 0.0);
+        continuing {
+            i = i + 1u;
+        }
+    }
+
+    return vec4<f32>(apply_fog(color, in.world_pos), 1.0);
+}
@@ -0,0 +1,312 @@
+// Copyright 2021 Chay Nabors.
+
+use std::mem::size_of;
+use std::sync::Arc;
+
+use nalgebra::Matrix4;
+use nalgebra::Point2;
+use nalgebra::Vector2;
+use nalgebra::Vector3;
+use wgpu::util::BufferInitDescriptor;
+use wgpu::util::DeviceExt;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::Buffer;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::Device;
+use wgpu::IndexFormat;
+use wgpu::Queue;
+use wgpu::RenderPass;
+
+use super::sprite::SpriteBatcher;
+use super::sprite::SpriteUniforms;
+use super::sprite::SpriteVertex;
+use super::stats::FrameStats;
+use super::Camera2D;
+use crate::TextureAtlas;
+
+// Tiles per side of a chunk, changing a tile only rebuilds the chunk it is in
+const CHUNK_SIZE: u32 = 32;
+const WHITE: [f32; 4] = [1., 1., 1., 1.];
+
+#[derive(Debug)]
+struct Chunk {
+    dirty: bool,
+    // Kept between rebuilds while the tiles still fit
+    buffer: Option<Arc<Buffer>>,
+    capacity: u32,
+    count: u32,
+}
+
+#[derive(Debug)]
+struct LayerUniforms {
+    buffer: Buffer,
+    bind_group: BindGroup,
+}
+
+// A grid of atlas frames with tile (0, 0) at the bottom left corner of the layer, drawn through the 2d camera
+#[derive(Debug)]
+pub struct TileLayer {
+    // In world units, moves the whole layer
+    pub offset: Vector2<f32>,
+    // How much the layer follows the camera on each axis, one is fixed in the world and zero is fixed to the screen
+    pub parallax: Vector2<f32>,
+    atlas: TextureAtlas,
+    size: [u32; 2],
+    tile_size: [f32; 2],
+    tiles: Vec<Option<usize>>,
+    chunks: Vec<Chunk>,
+    uniforms: Arc<LayerUniforms>,
+}
+
+impl TileLayer {
+    pub(crate) fn new(
+        device: &Device,
+        uniform_layout: &BindGroupLayout,
+        atlas: &TextureAtlas,
+        size: [u32; 2],
+        tile_size: [f32; 2],
+    ) -> TileLayer {
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("tile_layer_uniform_buffer"),
+            size: size_of::<SpriteUniforms>() as u64,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("tile_layer_uniform_bind_group"),
+            layout: uniform_layout,
+            entries: &[BindGroupEntry { binding: 0, resource: buffer.as_entire_binding() }],
+        });
+
+        let chunk_count = chunks_along(size[0]) * chunks_along(size[1]);
+        let chunks =
+            (0..chunk_count).map(|_| Chunk { dirty: false, buffer: None, capacity: 0, count: 0 }).collect::<Vec<_>>();
+
+        TileLayer {
+            offset: Vector2::zeros(),
+            parallax: Vector2::repeat(1.),
+            atlas: atlas.clone(),
+            size,
+            tile_size,
+            tiles: vec![None; (size[0] * size[1]) as usize],
+            chunks,
+            uniforms: Arc::new(LayerUniforms { buffer, bind_group }),
+        }
+    }
+
+    // Indices are frames of the atlas, none or a frame it doesn't have leaves the tile empty. Outside the grid does
+    // nothing
+    pub fn set_tile(&mut self, x: u32, y: u32, tile: Option<usize>) -> &mut Self {
+        if x < self.size[0] && y < self.size[1] {
+            let index = (y * self.size[0] + x) as usize;
+            if self.tiles[index] != tile {
+                self.tiles[index] = tile;
+                let chunk = (y / CHUNK_SIZE) * chunks_along(self.size[0]) + x / CHUNK_SIZE;
+                self.chunks[chunk as usize].dirty = true;
+            }
+        }
+        self
+    }
+
+    pub fn tile(&self, x: u32, y: u32) -> Option<usize> {
+        if x < self.size[0] && y < self.size[1] {
+            self.tiles[(y * self.size[0] + x) as usize]
+        } else {
+            None
+        }
+    }
+
+    pub fn fill(&mut self, tile: Option<usize>) -> &mut Self {
+        self.tiles.iter_mut().for_each(|existing| *existing = tile);
+        self.chunks.iter_mut().for_each(|chunk| chunk.dirty = true);
+        self
+    }
+
+    // In tiles
+    pub fn size(&self) -> [u32; 2] {
+        self.size
+    }
+
+    // In world units
+    pub fn tile_size(&self) -> [f32; 2] {
+        self.tile_size
+    }
+
+    pub fn atlas(&self) -> &TextureAtlas {
+        &self.atlas
+    }
+
+    // Only chunks with changed tiles are written again
+    fn update(&mut self, device: &Device, queue: &Queue) {
+        let chunks_x = chunks_along(self.size[0]);
+        for i in 0..self.chunks.len() {
+            if !self.chunks[i].dirty {
+                continue;
+            }
+
+            let origin = [(i as u32 % chunks_x) * CHUNK_SIZE, (i as u32 / chunks_x) * CHUNK_SIZE];
+            let vertices = self.chunk_vertices(origin);
+            let count = (vertices.len() / 4) as u32;
+            let chunk = &mut self.chunks[i];
+            chunk.dirty = false;
+            chunk.count = count;
+            match &chunk.buffer {
+                Some(buffer) if chunk.capacity >= count => {
+                    queue.write_buffer(buffer, 0, bytemuck::cast_slice(&vertices));
+                },
+                _ if count > 0 => {
+                    let buffer = device.create_buffer_init(&BufferInitDescriptor {
+                        label: Some("tile_chunk_vertex_buffer"),
+                        contents: bytemuck::cast_slice(&vertices),
+                        usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+                    });
+                    chunk.buffer = Some(Arc::new(buffer));
+                    chunk.capacity = count;
+                },
+                _ => (),
+            }
+        }
+    }
+
+    fn chunk_vertices(&self, origin: [u32; 2]) -> Vec<SpriteVertex> {
+        let end = [(origin[0] + CHUNK_SIZE).min(self.size[0]), (origin[1] + CHUNK_SIZE).min(self.size[1])];
+        let mut vertices = vec![];
+        for y in origin[1]..end[1] {
+            for x in origin[0]..end[0] {
+                let region = match self.tile(x, y).and_then(|frame| self.atlas.frame(frame)) {
+                    Some(region) => region,
+                    None => continue,
+                };
+
+                let left = x as f32 * self.tile_size[0];
+                let bottom = y as f32 * self.tile_size[1];
+                let right = left + self.tile_size[0];
+                let top = bottom + self.tile_size[1];
+                let corners = [[left, bottom], [right, bottom], [right, top], [left, top]];
+                let tex_coords =
+                    [[region[0], region[3]], [region[2], region[3]], [region[2], region[1]], [region[0], region[1]]];
+                for (position, tex_coord) in corners.iter().zip(&tex_coords) {
+                    vertices.push(SpriteVertex { position: *position, tex_coord: *tex_coord, tint: WHITE, layer: 0. });
+                }
+            }
+        }
+        vertices
+    }
+
+    // The world space box around a chunk, before the offset and parallax
+    fn chunk_bounds(&self, chunk: usize) -> (Point2<f32>, Point2<f32>) {
+        let chunks_x = chunks_along(self.size[0]);
+        let span = [CHUNK_SIZE as f32 * self.tile_size[0], CHUNK_SIZE as f32 * self.tile_size[1]];
+        let min = Point2::new((chunk as u32 % chunks_x) as f32 * span[0], (chunk as u32 / chunks_x) as f32 * span[1]);
+        (min, min + Vector2::from(span))
+    }
+}
+
+#[derive(Debug)]
+struct TileDraw {
+    order: RenderLayer,
+    atlas: TextureAtlas,
+    offset: Vector2<f32>,
+    parallax: Vector2<f32>,
+    uniforms: Arc<LayerUniforms>,
+    chunks: Vec<(Arc<Buffer>, u32, (Point2<f32>, Point2<f32>))>,
+    visible: Vec<usize>,
+}
+
+// Layers draw with the sprite pipeline in the order they were submitted, under every sprite of their render layer
+#[derive(Debug, Default)]
+pub(crate) struct TileRenderer {
+    draws: Vec<TileDraw>,
+}
+
+impl TileRenderer {
+    pub(crate) fn push(&mut self, device: &Device, queue: &Queue, layer: &mut TileLayer, order: RenderLayer) {
+        layer.update(device, queue);
+
+        let chunks = layer
+            .chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| chunk.count > 0)
+            .filter_map(|(i, chunk)| Some((chunk.buffer.clone()?, chunk.count, layer.chunk_bounds(i))))
+            .collect();
+
+        self.draws.push(TileDraw {
+            order,
+            atlas: layer.atlas.clone(),
+            offset: layer.offset,
+            parallax: layer.parallax,
+            uniforms: layer.uniforms.clone(),
+            chunks,
+            visible: vec![],
+        });
+    }
+
+    // A layer drawn more than once in a frame takes the camera of the last draw for all of them
+    // Returns the number of writes
+    pub(crate) fn prepare(&mut self, queue: &Queue, camera: &Camera2D, viewport: [f32; 2]) -> usize {
+        let view_projection = camera.view_projection(viewport);
+        let (min, max) = camera.visible_bounds(viewport);
+        for draw in &mut self.draws {
+            // Layers that follow the camera less are moved along with it by the difference
+            let shift = draw.offset + camera.position.coords.component_mul(&(Vector2::repeat(1.) - draw.parallax));
+            let model = Matrix4::new_translation(&Vector3::new(shift.x, shift.y, 0.));
+            let uniforms = SpriteUniforms { view_projection: (view_projection * model).into() };
+            queue.write_buffer(&draw.uniforms.buffer, 0, bytemuck::bytes_of(&uniforms));
+
+            let (min, max) = (min - shift, max - shift);
+            draw.visible = draw
+                .chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, _, (low, high)))| low.x <= max.x && low.y <= max.y && high.x >= min.x && high.y >= min.y)
+                .map(|(i, _)| i)
+                .collect();
+        }
+        self.draws.len()
+    }
+
+    pub(crate) fn layers(&self) -> impl Iterator<Item = RenderLayer> + '_ {
+        self.draws.iter().map(|draw| draw.order)
+    }
+
+    pub(crate) fn render<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        sprites: &'a SpriteBatcher,
+        order: RenderLayer,
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if !self.draws.iter().any(|draw| draw.order == order) {
+            return stats;
+        }
+
+        render_pass.set_pipeline(sprites.pipeline());
+        stats.pipeline_switches += 1;
+        render_pass.set_index_buffer(sprites.quad_indices().slice(..), IndexFormat::Uint32);
+        for draw in self.draws.iter().filter(|draw| draw.order == order) {
+            render_pass.set_bind_group(0, &draw.uniforms.bind_group, &[]);
+            render_pass.set_bind_group(1, draw.atlas.texture().bind_group(), &[]);
+            stats.texture_binds += 1;
+            for i in &draw.visible {
+                let (buffer, count, _) = &draw.chunks[*i];
+                render_pass.set_vertex_buffer(0, buffer.slice(..));
+                render_pass.draw_indexed(0..count * 6, 0, 0..1);
+                stats.draw(count * 6, 1);
+            }
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.draws.clear();
+    }
+}
+
+fn chunks_along(tiles: u32) -> u32 {
+    (tiles + CHUNK_SIZE - 1) / CHUNK_SIZE
+}
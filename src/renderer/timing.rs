@@ -0,0 +1,165 @@
+// Copyright 2021 Chay Nabors.
+
+use std::mem::{self,};
+use std::thread::{self,};
+use std::time::Duration;
+
+use crossbeam::channel::Receiver;
+use crossbeam::channel::TryRecvError;
+use crossbeam::channel::{self,};
+use wgpu::Buffer;
+use wgpu::BufferAddress;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::CommandEncoder;
+use wgpu::Device;
+use wgpu::Features;
+use wgpu::MapMode;
+use wgpu::QuerySet;
+use wgpu::QuerySetDescriptor;
+use wgpu::QueryType;
+use wgpu::Queue;
+
+// The start and end of this many passes are written each frame, the rest aren't timed
+const MAX_PASSES: u32 = 64;
+const TIMESTAMP_SIZE: BufferAddress = 8;
+
+// How long the gpu spent on one pass of a frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpuTiming {
+    pub pass: &'static str,
+    pub duration: Duration,
+}
+
+// Timestamps written by the command encoders between passes. They come back after the frame is done, and frames
+// submitted while they're on their way aren't timed
+#[derive(Debug)]
+pub(crate) struct GpuTimer {
+    // None where the adapter can't write timestamps
+    query_set: Option<QuerySet>,
+    enabled: bool,
+    // Nanoseconds a timestamp counts up by
+    period: f32,
+    passes: Vec<&'static str>,
+    resolved: Option<(Buffer, Vec<&'static str>)>,
+    receiver: Option<Receiver<Vec<GpuTiming>>>,
+    timings: Vec<GpuTiming>,
+}
+
+impl GpuTimer {
+    pub(crate) fn new(device: &Device, queue: &Queue) -> GpuTimer {
+        let query_set = match device.features().contains(Features::TIMESTAMP_QUERY) {
+            true => Some(device.create_query_set(&QuerySetDescriptor { ty: QueryType::Timestamp, count: MAX_PASSES * 2 })),
+            false => None,
+        };
+
+        GpuTimer {
+            query_set,
+            enabled: false,
+            period: queue.get_timestamp_period(),
+            passes: vec![],
+            resolved: None,
+            receiver: None,
+            timings: vec![],
+        }
+    }
+
+    pub(crate) fn supported(&self) -> bool {
+        self.query_set.is_some()
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled && self.supported();
+        if !self.enabled {
+            self.receiver = None;
+            self.timings.clear();
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Of the last frame to arrive
+    pub(crate) fn timings(&self) -> &[GpuTiming] {
+        &self.timings
+    }
+
+    // Before the frame's first pass, takes the timings of an earlier one if they've arrived
+    pub(crate) fn collect(&mut self) {
+        if let Some(receiver) = &self.receiver {
+            match receiver.try_recv() {
+                Ok(timings) => {
+                    self.timings = timings;
+                    self.receiver = None;
+                },
+                Err(TryRecvError::Disconnected) => self.receiver = None,
+                Err(TryRecvError::Empty) => (),
+            }
+        }
+    }
+
+    // None when the pass isn't timed, which stop takes as it is
+    pub(crate) fn start(&mut self, encoder: &mut CommandEncoder, pass: &'static str) -> Option<u32> {
+        let query_set = self.query_set.as_ref().filter(|_| self.enabled && self.receiver.is_none())?;
+        let index = self.passes.len() as u32;
+        if index >= MAX_PASSES {
+            return None;
+        }
+        encoder.write_timestamp(query_set, index * 2);
+        self.passes.push(pass);
+        Some(index)
+    }
+
+    pub(crate) fn stop(&self, encoder: &mut CommandEncoder, pass: Option<u32>) {
+        if let (Some(query_set), Some(index)) = (&self.query_set, pass) {
+            encoder.write_timestamp(query_set, index * 2 + 1);
+        }
+    }
+
+    // Into the frame's last encoder
+    pub(crate) fn resolve(&mut self, device: &Device, encoder: &mut CommandEncoder) {
+        let query_set = match &self.query_set {
+            Some(query_set) if !self.passes.is_empty() => query_set,
+            _ => return,
+        };
+        let count = self.passes.len() as u32 * 2;
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("timestamp_buffer"),
+            size: count as BufferAddress * TIMESTAMP_SIZE,
+            usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        encoder.resolve_query_set(query_set, 0..count, &buffer, 0);
+        self.resolved = Some((buffer, mem::take(&mut self.passes)));
+    }
+
+    // After the frame is submitted, the timestamps are read on another thread once the device has been polled
+    pub(crate) fn finish(&mut self) {
+        let (buffer, passes) = match self.resolved.take() {
+            Some(resolved) => resolved,
+            None => return,
+        };
+        let (sender, receiver) = channel::bounded(1);
+        self.receiver = Some(receiver);
+        let period = self.period as f64;
+        let map = buffer.slice(..).map_async(MapMode::Read);
+        thread::spawn(move || {
+            if pollster::block_on(map).is_err() {
+                return;
+            }
+            // Mapped ranges are aligned to at least eight bytes
+            let data = buffer.slice(..).get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&data);
+            let timings = passes
+                .iter()
+                .zip(timestamps.chunks_exact(2))
+                .map(|(&pass, ticks)| {
+                    let nanoseconds = ticks[1].saturating_sub(ticks[0]) as f64 * period;
+                    GpuTiming { pass, duration: Duration::from_nanos(nanoseconds as u64) }
+                })
+                .collect();
+            let _ = sender.send(timings);
+        });
+    }
+}
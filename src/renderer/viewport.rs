@@ -0,0 +1,203 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+
+use nalgebra::Isometry3;
+use nalgebra::Matrix4;
+use wgpu::BlendComponent;
+use wgpu::BlendFactor;
+use wgpu::BlendOperation;
+use wgpu::BlendState;
+use wgpu::Color;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::MultisampleState;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::StencilState;
+use wgpu::TextureFormat;
+use wgpu::VertexState;
+
+use super::Camera3D;
+use super::DepthOfField;
+use super::Ssao;
+use super::Ssr;
+
+// A camera drawing into part of the frame. Viewports are drawn in order, so later ones go over earlier ones
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    // Left, top, width and height as fractions of the window
+    pub rect: [f32; 4],
+    pub view: Isometry3<f32>,
+    // Should have the aspect ratio of the rectangle on screen, see Viewport::aspect
+    pub projection: Matrix4<f32>,
+    // None keeps what earlier viewports drew underneath, like for a picture in picture view
+    pub clear_color: Option<[f64; 4]>,
+    pub clear_depth: bool,
+    // In place of the ones in the post effects, which only apply without viewports
+    pub ssao: Option<Ssao>,
+    pub ssr: Option<Ssr>,
+    pub depth_of_field: Option<DepthOfField>,
+}
+
+impl Default for Viewport {
+    fn default() -> Viewport {
+        Viewport {
+            rect: [0., 0., 1., 1.],
+            view: Isometry3::identity(),
+            projection: Matrix4::identity(),
+            clear_color: Some([0., 0., 0., 1.]),
+            clear_depth: true,
+            ssao: None,
+            ssr: None,
+            depth_of_field: None,
+        }
+    }
+}
+
+impl Viewport {
+    // Points the viewport's view and projection at the camera, with the aspect ratio of the rectangle
+    pub fn set_camera(&mut self, camera: &Camera3D, window_size: [f32; 2]) {
+        self.view = camera.view();
+        self.projection = camera.projection(self.aspect(window_size));
+    }
+
+    // The width over the height of the rectangle in a window this size
+    pub fn aspect(&self, window_size: [f32; 2]) -> f32 {
+        (self.rect[2] * window_size[0]) / (self.rect[3] * window_size[1]).max(f32::EPSILON)
+    }
+
+    // Left, top, width and height in pixels of a target this size, None when nothing of it is on the target
+    pub(crate) fn pixels(&self, size: [u32; 2]) -> Option<[u32; 4]> {
+        let edge = |fraction: f32, size: u32| (fraction * size as f32).round().max(0.).min(size as f32) as u32;
+        let left = edge(self.rect[0], size[0]);
+        let top = edge(self.rect[1], size[1]);
+        let right = edge(self.rect[0] + self.rect[2], size[0]);
+        let bottom = edge(self.rect[1] + self.rect[3], size[1]);
+        match right > left && bottom > top {
+            true => Some([left, top, right - left, bottom - top]),
+            false => None,
+        }
+    }
+}
+
+// Clears a viewport's rectangle, which a render pass can only do for the whole target
+#[derive(Debug)]
+pub(crate) struct ViewportClearer {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    pipelines: [RenderPipeline; 3],
+}
+
+impl ViewportClearer {
+    pub(crate) fn new(device: &Device, format: TextureFormat, depth_format: TextureFormat) -> ViewportClearer {
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("viewport_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("viewport.wgsl"))),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("viewport_pipeline_layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+
+        let pipelines = create_pipelines(device, &pipeline_layout, &shader_module, format, depth_format, 1);
+
+        ViewportClearer { format, depth_format, shader_module, pipeline_layout, pipelines }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.pipelines = create_pipelines(
+            device,
+            &self.pipeline_layout,
+            &self.shader_module,
+            self.format,
+            self.depth_format,
+            sample_count,
+        );
+    }
+
+    // Within the viewport and scissor rectangle the render pass has set
+    pub(crate) fn clear<'a>(&'a self, render_pass: &mut RenderPass<'a>, color: Option<[f64; 4]>, depth: bool) {
+        let pipeline = match (color, depth) {
+            (Some(_), true) => &self.pipelines[0],
+            (Some(_), false) => &self.pipelines[1],
+            (None, true) => &self.pipelines[2],
+            (None, false) => return,
+        };
+
+        if let Some(color) = color {
+            render_pass.set_blend_constant(Color { r: color[0], g: color[1], b: color[2], a: color[3] });
+        }
+        render_pass.set_pipeline(pipeline);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+// Clearing color and depth, only color and only depth
+fn create_pipelines(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+) -> [RenderPipeline; 3] {
+    let constant =
+        BlendComponent { src_factor: BlendFactor::Constant, dst_factor: BlendFactor::Zero, operation: BlendOperation::Add };
+
+    let pipeline = |color: bool, depth: bool| {
+        device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("viewport_clear_pipeline"),
+            layout: Some(layout),
+            vertex: VertexState { module: shader_module, entry_point: "main", buffers: &[] },
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                clamp_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: depth_format,
+                depth_write_enabled: depth,
+                depth_compare: CompareFunction::Always,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+            fragment: Some(FragmentState {
+                module: shader_module,
+                entry_point: "main",
+                targets: &[ColorTargetState {
+                    format,
+                    blend: Some(BlendState { color: constant, alpha: constant }),
+                    write_mask: if color { ColorWrite::ALL } else { ColorWrite::empty() },
+                }],
+            }),
+        })
+    };
+    [pipeline(true, true), pipeline(true, false), pipeline(false, true)]
+}
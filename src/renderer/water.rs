@@ -0,0 +1,501 @@
+// Copyright 2021 Chay Nabors.
+
+use std::borrow::Cow;
+use std::mem::size_of;
+use std::time::Instant;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use nalgebra::Matrix4;
+use nalgebra::Point3;
+use nalgebra::Vector3;
+use wgpu::util::BufferInitDescriptor;
+use wgpu::util::DeviceExt;
+use wgpu::vertex_attr_array;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::BlendState;
+use wgpu::Buffer;
+use wgpu::BufferBinding;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsage;
+use wgpu::ColorTargetState;
+use wgpu::ColorWrite;
+use wgpu::CommandEncoder;
+use wgpu::CompareFunction;
+use wgpu::DepthBiasState;
+use wgpu::DepthStencilState;
+use wgpu::Device;
+use wgpu::DynamicOffset;
+use wgpu::Extent3d;
+use wgpu::FilterMode;
+use wgpu::FragmentState;
+use wgpu::FrontFace;
+use wgpu::ImageCopyTexture;
+use wgpu::IndexFormat;
+use wgpu::InputStepMode;
+use wgpu::MultisampleState;
+use wgpu::Origin3d;
+use wgpu::PipelineLayout;
+use wgpu::PipelineLayoutDescriptor;
+use wgpu::PolygonMode;
+use wgpu::PrimitiveState;
+use wgpu::PrimitiveTopology;
+use wgpu::Queue;
+use wgpu::RenderPass;
+use wgpu::RenderPipeline;
+use wgpu::RenderPipelineDescriptor;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderFlags;
+use wgpu::ShaderModule;
+use wgpu::ShaderModuleDescriptor;
+use wgpu::ShaderSource;
+use wgpu::ShaderStage;
+use wgpu::StencilState;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::TextureViewDimension;
+use wgpu::VertexBufferLayout;
+use wgpu::VertexState;
+use wgpu::BIND_BUFFER_ALIGNMENT;
+
+use super::depth_effects::DepthEffects;
+use super::stats::FrameStats;
+use crate::model::Vertex;
+use crate::Aabb;
+use crate::Texture;
+
+// Water past this in one frame is dropped
+const MAX_WATER: usize = 64;
+// A unit square along x and z facing up, scaled out to the water's size
+const QUAD_VERTICES: [Vertex; 4] = [
+    Vertex { position: [-0.5, 0., -0.5], tex_coords: [0., 0.], normal: [0., 1., 0.] },
+    Vertex { position: [-0.5, 0., 0.5], tex_coords: [0., 1.], normal: [0., 1., 0.] },
+    Vertex { position: [0.5, 0., 0.5], tex_coords: [1., 1.], normal: [0., 1., 0.] },
+    Vertex { position: [0.5, 0., -0.5], tex_coords: [1., 0.], normal: [0., 1., 0.] },
+];
+const QUAD_INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+// A flat rectangle of water centered on a point, lying along x and z. The normal map is scrolled over it twice and the
+// two are combined, it should be created with TextureWrap::Repeat
+#[derive(Clone, Debug)]
+pub struct Water {
+    pub center: Point3<f32>,
+    // Width along x and depth along z
+    pub size: [f32; 2],
+    pub normal_map: Texture,
+    // World units the normal map repeats every
+    pub tile_size: f32,
+    // World units per second each copy of the normal map moves
+    pub scroll: [[f32; 2]; 2],
+    // The tint of what is seen through shallow water and the color of deep water
+    pub shallow_color: [f32; 3],
+    pub deep_color: [f32; 3],
+    // How deep the water has to be before the bottom can't be seen
+    pub depth: f32,
+    // What the surface reflects at grazing angles
+    pub reflection_color: [f32; 3],
+    pub foam_color: [f32; 3],
+    // Foam fades out from where the water meets something until it is this deep
+    pub foam_depth: f32,
+    // How far the waves bend what is seen through the water, as a fraction of the screen
+    pub refraction: f32,
+}
+
+impl Water {
+    pub fn new(center: Point3<f32>, size: [f32; 2], normal_map: &Texture) -> Water {
+        Water {
+            center,
+            size,
+            normal_map: normal_map.clone(),
+            tile_size: 8.,
+            scroll: [[0.3, 0.2], [-0.2, 0.25]],
+            shallow_color: [0.6, 0.9, 0.9],
+            deep_color: [0.02, 0.1, 0.15],
+            depth: 4.,
+            reflection_color: [0.5, 0.65, 0.8],
+            foam_color: [0.9, 0.95, 1.],
+            foam_depth: 0.3,
+            refraction: 0.02,
+        }
+    }
+
+    pub(crate) fn transform(&self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.center.coords)
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(self.size[0], 1., self.size[1]))
+    }
+
+    // Of the unit square the transform is applied to
+    pub(crate) fn bounds() -> Aabb {
+        Aabb::new(Point3::new(-0.5, 0., -0.5), Point3::new(0.5, 0., 0.5))
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct WaterUniforms {
+    shallow_color: [f32; 4],
+    deep_color: [f32; 4],
+    reflection_color: [f32; 4],
+    foam_color: [f32; 4],
+    scroll: [f32; 4],
+    // One over the tile size, the depth, the foam depth and the refraction
+    parameters: [f32; 4],
+    screen: [f32; 4],
+    time: [f32; 4],
+}
+
+#[derive(Debug)]
+struct WaterDraw {
+    normal_map: Texture,
+    uniform: u32,
+    uniforms: WaterUniforms,
+}
+
+#[derive(Debug)]
+struct Refraction {
+    texture: wgpu::Texture,
+    view: TextureView,
+}
+
+impl Refraction {
+    fn new(device: &Device, size: [u32; 2], format: TextureFormat) -> Refraction {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("water_refraction"),
+            size: Extent3d { width: size[0].max(1), height: size[1].max(1), depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsage::COPY_DST | TextureUsage::SAMPLED,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        Refraction { texture, view }
+    }
+}
+
+// Water is drawn in the scene pass after everything opaque, over a copy of the scene taken just before it. It reads the
+// depth prepass of the depth effects to see how deep it is, so that has to have run for the camera first
+#[derive(Debug)]
+pub(crate) struct WaterRenderer {
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    pipeline: RenderPipeline,
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    uniform_buffer: Buffer,
+    layout: BindGroupLayout,
+    bind_group: BindGroup,
+    sampler: Sampler,
+    refraction: Refraction,
+    size: [u32; 2],
+    start: Instant,
+    draws: Vec<WaterDraw>,
+}
+
+impl WaterRenderer {
+    pub(crate) fn new(
+        device: &Device,
+        format: TextureFormat,
+        depth_format: TextureFormat,
+        object_layout: &BindGroupLayout,
+        scene_layout: &BindGroupLayout,
+        texture_layout: &BindGroupLayout,
+        depth_effects: &DepthEffects,
+    ) -> WaterRenderer {
+        let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("water_vertex_buffer"),
+            contents: bytemuck::cast_slice(&QUAD_VERTICES),
+            usage: BufferUsage::VERTEX,
+        });
+
+        let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("water_index_buffer"),
+            contents: bytemuck::cast_slice(&QUAD_INDICES),
+            usage: BufferUsage::INDEX,
+        });
+
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("water_uniform_buffer"),
+            size: MAX_WATER as u64 * BIND_BUFFER_ALIGNMENT,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let texture_entry = |binding, filterable| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        // Past the material bindings the mesh declarations leave unused
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("water_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<WaterUniforms>() as _),
+                    },
+                    count: None,
+                },
+                texture_entry(8, true),
+                texture_entry(9, false),
+                BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: ShaderStage::FRAGMENT,
+                    ty: BindingType::Sampler { filtering: true, comparison: false },
+                    count: None,
+                },
+            ],
+        });
+
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("water_refraction_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let size = depth_effects.size();
+        let refraction = Refraction::new(device, size, format);
+        let bind_group = create_bind_group(device, &layout, &uniform_buffer, &refraction, &sampler, depth_effects);
+
+        let source = concat!(include_str!("fog.wgsl"), include_str!("mesh.wgsl"), include_str!("water.wgsl"));
+        let shader_module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("water_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(source)),
+            flags: ShaderFlags::VALIDATION,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("water_pipeline_layout"),
+            bind_group_layouts: &[object_layout, scene_layout, &layout, texture_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader_module, format, depth_format, 1);
+
+        WaterRenderer {
+            format,
+            depth_format,
+            shader_module,
+            pipeline_layout,
+            pipeline,
+            vertex_buffer,
+            index_buffer,
+            uniform_buffer,
+            layout,
+            bind_group,
+            sampler,
+            refraction,
+            size,
+            start: Instant::now(),
+            draws: vec![],
+        }
+    }
+
+    pub(crate) fn set_sample_count(&mut self, device: &Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.shader_module,
+            self.format,
+            self.depth_format,
+            sample_count,
+        );
+    }
+
+    // After the depth effects have been resized, the scene is copied at their size
+    pub(crate) fn resize(&mut self, device: &Device, depth_effects: &DepthEffects) {
+        self.size = depth_effects.size();
+        self.refraction = Refraction::new(device, self.size, self.format);
+        self.bind_group =
+            create_bind_group(device, &self.layout, &self.uniform_buffer, &self.refraction, &self.sampler, depth_effects);
+    }
+
+    pub(crate) fn push(&mut self, water: &Water, uniform: u32) {
+        if self.draws.len() >= MAX_WATER {
+            return;
+        }
+
+        let color = |[r, g, b]: [f32; 3]| [r, g, b, 1.];
+        let [first, second] = water.scroll;
+        let uniforms = WaterUniforms {
+            shallow_color: color(water.shallow_color),
+            deep_color: color(water.deep_color),
+            reflection_color: color(water.reflection_color),
+            foam_color: color(water.foam_color),
+            scroll: [first[0], first[1], second[0], second[1]],
+            parameters: [
+                1. / water.tile_size.max(f32::EPSILON),
+                water.depth.max(f32::EPSILON),
+                water.foam_depth.max(f32::EPSILON),
+                water.refraction,
+            ],
+            screen: [0.; 4],
+            time: [0.; 4],
+        };
+        self.draws.push(WaterDraw { normal_map: water.normal_map.clone(), uniform, uniforms });
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.draws.is_empty()
+    }
+
+    // Returns the number of writes
+    pub(crate) fn prepare(&mut self, queue: &Queue) -> usize {
+        let [width, height] = [self.size[0].max(1) as f32, self.size[1].max(1) as f32];
+        let time = self.start.elapsed().as_secs_f32();
+        for (i, draw) in self.draws.iter_mut().enumerate() {
+            draw.uniforms.screen = [width, height, 1. / width, 1. / height];
+            draw.uniforms.time = [time, 0., 0., 0.];
+            queue.write_buffer(&self.uniform_buffer, i as u64 * BIND_BUFFER_ALIGNMENT, bytemuck::bytes_of(&draw.uniforms));
+        }
+        self.draws.len()
+    }
+
+    // The scene as it is before the water, for it to be seen through
+    pub(crate) fn copy_refraction(&self, encoder: &mut CommandEncoder, scene: &wgpu::Texture) {
+        encoder.copy_texture_to_texture(
+            ImageCopyTexture { texture: scene, mip_level: 0, origin: Origin3d::ZERO },
+            ImageCopyTexture { texture: &self.refraction.texture, mip_level: 0, origin: Origin3d::ZERO },
+            Extent3d { width: self.size[0].max(1), height: self.size[1].max(1), depth_or_array_layers: 1 },
+        );
+    }
+
+    pub(crate) fn render<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        object_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
+        visible: &[bool],
+    ) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if self.draws.is_empty() {
+            return stats;
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(1, scene_bind_group, &[]);
+        stats.pipeline_switches += 1;
+        stats.texture_binds += 1;
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint32);
+        for (i, draw) in self.draws.iter().enumerate().filter(|(_, draw)| visible[draw.uniform as usize]) {
+            let offset = (draw.uniform as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(0, object_bind_group, &[offset]);
+            let water_offset = (i as DynamicOffset) * (BIND_BUFFER_ALIGNMENT as DynamicOffset);
+            render_pass.set_bind_group(2, &self.bind_group, &[water_offset]);
+            render_pass.set_bind_group(3, draw.normal_map.bind_group(), &[]);
+            render_pass.draw_indexed(0..QUAD_INDICES.len() as u32, 0, 0..1);
+            stats.draw(QUAD_INDICES.len() as u32, 1);
+            stats.texture_binds += 2;
+        }
+        stats
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.draws.clear();
+    }
+}
+
+fn create_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    uniform_buffer: &Buffer,
+    refraction: &Refraction,
+    sampler: &Sampler,
+    depth_effects: &DepthEffects,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("water_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 7,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: uniform_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(size_of::<WaterUniforms>() as _),
+                }),
+            },
+            BindGroupEntry { binding: 8, resource: BindingResource::TextureView(&refraction.view) },
+            BindGroupEntry { binding: 9, resource: BindingResource::TextureView(depth_effects.linear_depth_view()) },
+            BindGroupEntry { binding: 10, resource: BindingResource::Sampler(sampler) },
+        ],
+    })
+}
+
+// The surface is seen from both sides and tested against the scene's depth without writing to it, so whatever comes
+// after can still be drawn behind it. The refraction copy already holds the scene, so the water replaces it
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    depth_format: TextureFormat,
+    sample_count: u32,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("water_pipeline"),
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "main",
+            buffers: &[VertexBufferLayout {
+                array_stride: size_of::<Vertex>() as wgpu::BufferAddress,
+                step_mode: InputStepMode::Vertex,
+                attributes: &vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x3],
+            }],
+        },
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "main",
+            targets: &[ColorTargetState { format, blend: Some(BlendState::REPLACE), write_mask: ColorWrite::ALL }],
+        }),
+    })
+}
@@ -0,0 +1,110 @@
+// Follows the declarations in mesh.wgsl, the water's bindings come after the material ones it leaves unused
+
+[[block]]
+struct WaterUniforms {
+    shallow_color: vec4<f32>;
+    deep_color: vec4<f32>;
+    reflection_color: vec4<f32>;
+    foam_color: vec4<f32>;
+    // World units per second of the first normal map in xy and the second in zw
+    scroll: vec4<f32>;
+    // One over the tile size, the depth, the foam depth and the refraction
+    parameters: vec4<f32>;
+    // Width and height in pixels and one over each
+    screen: vec4<f32>;
+    // Seconds in x
+    time: vec4<f32>;
+};
+
+[[group(2), binding(7)]]
+var<uniform> water: WaterUniforms;
+[[group(2), binding(8)]]
+var refraction: texture_2d<f32>;
+[[group(2), binding(9)]]
+var depths: texture_2d<f32>;
+[[group(2), binding(10)]]
+var refraction_sampler: sampler;
+
+[[group(3), binding(0)]]
+var normal_map: texture_2d<f32>;
+[[group(3), binding(1)]]
+var normal_sampler: sampler;
+
+struct WaterOutput {
+    [[location(0)]] world_pos: vec3<f32>;
+    // How far in front of the camera the surface is, which is what the depth prepass stores
+    [[location(1)]] view_depth: f32;
+    [[builtin(position)]] pos: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> WaterOutput {
+    var out: WaterOutput;
+    out.world_pos = (object.model * vec4<f32>(in.pos, 1.0)).xyz;
+    out.pos = object.model_view_projection * vec4<f32>(in.pos, 1.0);
+    // Assumes a perspective projection, where w is the distance in front of the camera
+    out.view_depth = out.pos.w;
+    return out;
+}
+
+// The normal map's blue channel points up out of the water
+fn wave_normal(uv: vec2<f32>) -> vec3<f32> {
+    let n = textureSample(normal_map, normal_sampler, uv).xyz * 2.0 - 1.0;
+    return vec3<f32>(n.x, n.z, n.y);
+}
+
+// How much water there is between the surface and whatever is behind it at a pixel, nothing behind it is deep
+fn water_depth(pixel: vec2<i32>, view_depth: f32) -> f32 {
+    let depth = textureLoad(depths, pixel, 0).r;
+    if (depth <= 0.0) {
+        return 1000000.0;
+    }
+    return depth - view_depth;
+}
+
+[[stage(fragment)]]
+fn main(in: WaterOutput) -> [[location(0)]] vec4<f32> {
+    let uv = in.world_pos.xz;
+    let time = water.time.x;
+    let first = wave_normal((uv + water.scroll.xy * time) * water.parameters.x);
+    let second = wave_normal((uv + water.scroll.zw * time) * water.parameters.x);
+    let n = normalize(vec3<f32>(first.x + second.x, first.y * second.y, first.z + second.z));
+    let v = normalize(scene.camera_position.xyz - in.world_pos);
+
+    // Bent by the waves, unless that would show something in front of the water through it
+    let screen_uv = in.pos.xy * water.screen.zw;
+    let depth = water_depth(vec2<i32>(in.pos.xy), in.view_depth);
+    let offset = n.xz * water.parameters.w * clamp(depth, 0.0, 1.0);
+    var refracted_uv: vec2<f32> = screen_uv + offset;
+    var refracted_depth: f32 = water_depth(vec2<i32>(refracted_uv * water.screen.xy), in.view_depth);
+    if (refracted_depth < 0.0) {
+        refracted_uv = screen_uv;
+        refracted_depth = depth;
+    }
+    let behind = textureSample(refraction, refraction_sampler, refracted_uv).rgb;
+
+    var light: vec3<f32> = scene.ambient.rgb;
+    var specular: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+    if (scene.light_direction.w > 0.5) {
+        let l = normalize(-scene.light_direction.xyz);
+        let shadow = shadow_factor(in.world_pos, n);
+        light = light + scene.light_color.rgb * max(dot(n, l), 0.0) * shadow;
+        let h = normalize(l + v);
+        specular = scene.light_color.rgb * pow(max(dot(n, h), 0.0), 256.0) * shadow;
+    }
+
+    // The bottom fades into the deep color the further it is below the surface
+    let absorption = clamp(refracted_depth / water.parameters.y, 0.0, 1.0);
+    let body = mix(behind * water.shallow_color.rgb, water.deep_color.rgb * light, absorption);
+
+    // Schlick's approximation with the reflectance of water head on
+    let fresnel = 0.02 + 0.98 * pow(1.0 - max(dot(n, v), 0.0), 5.0);
+    var color: vec3<f32> = mix(body, water.reflection_color.rgb, fresnel) + specular;
+
+    // Broken up by the waves so it doesn't follow the shore in a solid line
+    let breakup = smoothStep(0.3, 0.7, (first.x + second.z) * 0.5 + 0.5);
+    let foam = (1.0 - clamp(depth / water.parameters.z, 0.0, 1.0)) * breakup;
+    color = mix(color, water.foam_color.rgb * light, foam);
+
+    return vec4<f32>(apply_fog(color, in.world_pos), 1.0);
+}
@@ -1,13 +1,44 @@
 // Copyright 2021 Chay Nabors.
 
+use image::ImageError;
 use tobj::LoadError;
 
+use crate::Transport;
+
 #[derive(Debug)]
 pub enum GearError {
     IOError(std::io::Error),
     NetworkError(laminar::ErrorKind),
     OpenFileFailed,
     ParseFileFailed,
+    RenderGraphError(String),
+    SerializationError(bincode::Error),
+    // Wgsl that failed to parse or didn't match the bindings the renderer provides
+    ShaderError(String),
+    EncryptionError(snow::Error),
+    GltfError(gltf::Error),
+    ImageError(ImageError),
+    // A video that couldn't be parsed or decoded
+    VideoError(String),
+    // A font file that couldn't be parsed
+    FontError(String),
+    // A color lookup table that couldn't be parsed or has the wrong number of colors
+    LutError(String),
+    // The socket worker has shut down, either because every handle was dropped or because it panicked
+    SocketClosed,
+    SocketFull,
+    // The transport isn't available on this platform, browsers only have WebRTC and nothing else has it
+    UnsupportedTransport(Transport),
+    // Something the browser's WebRTC or signaling connection reported
+    WebRtcError(String),
+    // A multisampling sample count the adapter doesn't support for the scene targets
+    UnsupportedSampleCount(u32),
+    // Something the graphics adapter can't do
+    UnsupportedFeature(String),
+    // A frame couldn't be read back from the gpu
+    CaptureFailed,
+    // The images don't fit on the biggest texture every adapter supports
+    AtlasFull,
     Unknown,
 }
 
@@ -47,4 +78,34 @@ impl From<laminar::ErrorKind> for GearError {
     }
 }
 
+impl From<bincode::Error> for GearError {
+    fn from(e: bincode::Error) -> Self {
+        GearError::SerializationError(e)
+    }
+}
+
+impl From<snow::Error> for GearError {
+    fn from(e: snow::Error) -> Self {
+        GearError::EncryptionError(e)
+    }
+}
+
+impl From<gltf::Error> for GearError {
+    fn from(e: gltf::Error) -> Self {
+        match e {
+            gltf::Error::Io(e) => GearError::IOError(e),
+            _ => GearError::GltfError(e),
+        }
+    }
+}
+
+impl From<ImageError> for GearError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::IoError(e) => GearError::IOError(e),
+            _ => GearError::ImageError(e),
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, GearError>;
@@ -0,0 +1,303 @@
+// Copyright 2021 Chay Nabors.
+
+use std::path::Path;
+
+use gltf::animation::util::ReadOutputs;
+use gltf::animation::Interpolation as GltfInterpolation;
+use gltf::image::Format;
+use gltf::material::AlphaMode;
+use gltf::mesh::Mode;
+use gltf::Document;
+use nalgebra::Matrix4;
+use nalgebra::Quaternion;
+use nalgebra::UnitQuaternion;
+use nalgebra::Vector3;
+
+use crate::animation::Animation;
+use crate::animation::AnimationChannel;
+use crate::animation::ChannelValues;
+use crate::animation::Interpolation;
+use crate::model::Mesh;
+use crate::model::SkinVertex;
+use crate::model::Vertex;
+use crate::result::Result;
+use crate::Loadable;
+
+// Rgba8 with tightly packed rows starting from the top
+pub struct SceneImage {
+    pub size: [u32; 2],
+    pub rgba: Vec<u8>,
+}
+
+// Maps are indices into the scene's images, see Material for how they are combined
+#[derive(Clone, Debug)]
+pub struct SceneMaterial {
+    pub name: Option<String>,
+    pub albedo: Option<usize>,
+    pub albedo_factor: [f32; 4],
+    pub normal: Option<usize>,
+    pub normal_scale: f32,
+    pub metallic_roughness: Option<usize>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub emissive: Option<usize>,
+    pub emissive_factor: [f32; 3],
+    // The gltf blend alpha mode, masked materials are drawn opaque
+    pub transparent: bool,
+}
+
+pub struct ScenePrimitive {
+    pub mesh: Mesh,
+    pub material: Option<usize>,
+    // One per vertex when the primitive is skinned
+    pub skin: Option<Vec<SkinVertex>>,
+}
+
+pub struct SceneMesh {
+    pub name: Option<String>,
+    pub primitives: Vec<ScenePrimitive>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SceneNode {
+    pub name: Option<String>,
+    // Relative to the parent node
+    pub transform: Matrix4<f32>,
+    // The transform taken apart, animations replace these
+    pub translation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+    pub scale: Vector3<f32>,
+    pub mesh: Option<usize>,
+    // Skinned meshes ignore the node's transform and follow the joints of the skin
+    pub skin: Option<usize>,
+    pub children: Vec<usize>,
+}
+
+// The joints are nodes, their inverse bind matrices take the mesh into the space of each joint
+#[derive(Clone, Debug)]
+pub struct SceneSkin {
+    pub name: Option<String>,
+    pub joints: Vec<usize>,
+    pub inverse_bind_matrices: Vec<Matrix4<f32>>,
+}
+
+// Loaded from .gltf or .glb, upload it with Renderer::upload_scene to draw it
+pub struct Scene {
+    pub images: Vec<SceneImage>,
+    pub materials: Vec<SceneMaterial>,
+    pub meshes: Vec<SceneMesh>,
+    pub nodes: Vec<SceneNode>,
+    pub skins: Vec<SceneSkin>,
+    pub animations: Vec<Animation>,
+    // The nodes of the default scene without a parent
+    pub roots: Vec<usize>,
+}
+
+impl Scene {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Scene> {
+        let (document, buffers, images) = gltf::import_slice(bytes)?;
+        Ok(Scene::from_document(&document, &buffers, images))
+    }
+
+    fn from_document(document: &Document, buffers: &[gltf::buffer::Data], images: Vec<gltf::image::Data>) -> Scene {
+        let images = images.into_iter().map(convert_image).collect();
+
+        let materials = document
+            .materials()
+            .map(|material| {
+                let pbr = material.pbr_metallic_roughness();
+                SceneMaterial {
+                    name: material.name().map(str::to_owned),
+                    albedo: pbr.base_color_texture().map(|info| info.texture().source().index()),
+                    albedo_factor: pbr.base_color_factor(),
+                    normal: material.normal_texture().map(|normal| normal.texture().source().index()),
+                    normal_scale: material.normal_texture().map_or(1., |normal| normal.scale()),
+                    metallic_roughness: pbr.metallic_roughness_texture().map(|info| info.texture().source().index()),
+                    metallic_factor: pbr.metallic_factor(),
+                    roughness_factor: pbr.roughness_factor(),
+                    emissive: material.emissive_texture().map(|info| info.texture().source().index()),
+                    emissive_factor: material.emissive_factor(),
+                    transparent: material.alpha_mode() == AlphaMode::Blend,
+                }
+            })
+            .collect();
+
+        let meshes = document
+            .meshes()
+            .map(|mesh| {
+                let primitives = mesh
+                    .primitives()
+                    .filter(|primitive| primitive.mode() == Mode::Triangles)
+                    .filter_map(|primitive| {
+                        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+                        let positions: Vec<[f32; 3]> = reader.read_positions()?.collect();
+                        let indices: Vec<u32> = match reader.read_indices() {
+                            Some(indices) => indices.into_u32().collect(),
+                            None => (0..positions.len() as u32).collect(),
+                        };
+                        let normals: Vec<[f32; 3]> = match reader.read_normals() {
+                            Some(normals) => normals.collect(),
+                            None => generate_normals(&positions, &indices),
+                        };
+                        let tex_coords: Vec<[f32; 2]> = match reader.read_tex_coords(0) {
+                            Some(tex_coords) => tex_coords.into_f32().collect(),
+                            None => vec![[0., 0.]; positions.len()],
+                        };
+
+                        let vertices = (0..positions.len())
+                            .map(|i| Vertex { position: positions[i], tex_coords: tex_coords[i], normal: normals[i] })
+                            .collect();
+                        let mesh = Mesh { vertices, indices };
+
+                        // Only the first set of joints and weights is used, so four joints per vertex at most
+                        let skin = match (reader.read_joints(0), reader.read_weights(0)) {
+                            (Some(joints), Some(weights)) => Some(
+                                joints
+                                    .into_u16()
+                                    .zip(weights.into_f32())
+                                    .map(|(joints, weights)| SkinVertex {
+                                        joints: [joints[0] as u32, joints[1] as u32, joints[2] as u32, joints[3] as u32],
+                                        weights,
+                                    })
+                                    .collect(),
+                            ),
+                            _ => None,
+                        };
+
+                        Some(ScenePrimitive { mesh, material: primitive.material().index(), skin })
+                    })
+                    .collect();
+                SceneMesh { name: mesh.name().map(str::to_owned), primitives }
+            })
+            .collect();
+
+        let nodes = document
+            .nodes()
+            .map(|node| {
+                let (translation, rotation, scale) = node.transform().decomposed();
+                SceneNode {
+                    name: node.name().map(str::to_owned),
+                    transform: Matrix4::from(node.transform().matrix()),
+                    translation: Vector3::from(translation),
+                    rotation: UnitQuaternion::new_normalize(Quaternion::from(rotation)),
+                    scale: Vector3::from(scale),
+                    mesh: node.mesh().map(|mesh| mesh.index()),
+                    skin: node.skin().map(|skin| skin.index()),
+                    children: node.children().map(|child| child.index()).collect(),
+                }
+            })
+            .collect();
+
+        let skins = document
+            .skins()
+            .map(|skin| {
+                let joints: Vec<usize> = skin.joints().map(|joint| joint.index()).collect();
+                let reader = skin.reader(|buffer| Some(&buffers[buffer.index()]));
+                let inverse_bind_matrices = match reader.read_inverse_bind_matrices() {
+                    Some(matrices) => matrices.map(Matrix4::from).collect(),
+                    None => vec![Matrix4::identity(); joints.len()],
+                };
+                SceneSkin { name: skin.name().map(str::to_owned), joints, inverse_bind_matrices }
+            })
+            .collect();
+
+        // Morph target weights are not supported and their channels are left out
+        let animations = document
+            .animations()
+            .map(|animation| {
+                let channels: Vec<AnimationChannel> = animation
+                    .channels()
+                    .filter_map(|channel| {
+                        let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+                        let times: Vec<f32> = reader.read_inputs()?.collect();
+                        let values = match reader.read_outputs()? {
+                            ReadOutputs::Translations(values) => {
+                                ChannelValues::Translation(values.map(Vector3::from).collect())
+                            },
+                            ReadOutputs::Rotations(values) => ChannelValues::Rotation(
+                                values
+                                    .into_f32()
+                                    .map(|rotation| UnitQuaternion::new_normalize(Quaternion::from(rotation)))
+                                    .collect(),
+                            ),
+                            ReadOutputs::Scales(values) => ChannelValues::Scale(values.map(Vector3::from).collect()),
+                            ReadOutputs::MorphTargetWeights(_) => return None,
+                        };
+                        let interpolation = match channel.sampler().interpolation() {
+                            GltfInterpolation::Step => Interpolation::Step,
+                            GltfInterpolation::Linear => Interpolation::Linear,
+                            GltfInterpolation::CubicSpline => Interpolation::CubicSpline,
+                        };
+                        Some(AnimationChannel { node: channel.target().node().index(), times, values, interpolation })
+                    })
+                    .collect();
+
+                let duration = channels.iter().filter_map(|channel| channel.times.last().copied()).fold(0., f32::max);
+                Animation { name: animation.name().map(str::to_owned), channels, duration }
+            })
+            .collect();
+
+        let roots = match document.default_scene().or_else(|| document.scenes().next()) {
+            Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+            None => vec![],
+        };
+
+        Scene { images, materials, meshes, nodes, skins, animations, roots }
+    }
+}
+
+impl Loadable for Scene {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Scene> {
+        let (document, buffers, images) = gltf::import(path)?;
+        Ok(Scene::from_document(&document, &buffers, images))
+    }
+}
+
+// Sixteen bit channels keep only their high byte
+fn convert_image(image: gltf::image::Data) -> SceneImage {
+    let (channels, bytes_per_channel, bgr) = match image.format {
+        Format::R8 => (1, 1, false),
+        Format::R8G8 => (2, 1, false),
+        Format::R8G8B8 => (3, 1, false),
+        Format::R8G8B8A8 => (4, 1, false),
+        Format::B8G8R8 => (3, 1, true),
+        Format::B8G8R8A8 => (4, 1, true),
+        Format::R16 => (1, 2, false),
+        Format::R16G16 => (2, 2, false),
+        Format::R16G16B16 => (3, 2, false),
+        Format::R16G16B16A16 => (4, 2, false),
+    };
+
+    let mut rgba = Vec::with_capacity((image.width * image.height * 4) as usize);
+    for pixel in image.pixels.chunks_exact(channels * bytes_per_channel) {
+        let channel = |i: usize| pixel[i * bytes_per_channel + bytes_per_channel - 1];
+        let (r, g, b, a) = match channels {
+            1 => (channel(0), channel(0), channel(0), 255),
+            2 => (channel(0), channel(1), 0, 255),
+            3 => (channel(0), channel(1), channel(2), 255),
+            _ => (channel(0), channel(1), channel(2), channel(3)),
+        };
+        if bgr {
+            rgba.extend_from_slice(&[b, g, r, a]);
+        } else {
+            rgba.extend_from_slice(&[r, g, b, a]);
+        }
+    }
+
+    SceneImage { size: [image.width, image.height], rgba }
+}
+
+// Primitives without normals get smooth ones weighted by face area
+fn generate_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    let mut normals = vec![Vector3::zeros(); positions.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+        let (pa, pb, pc) = (Vector3::from(positions[a]), Vector3::from(positions[b]), Vector3::from(positions[c]));
+        let normal = (pb - pa).cross(&(pc - pa));
+        normals[a] += normal;
+        normals[b] += normal;
+        normals[c] += normal;
+    }
+
+    normals.into_iter().map(|normal| normal.try_normalize(f32::EPSILON).unwrap_or_else(Vector3::y).into()).collect()
+}
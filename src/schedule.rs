@@ -0,0 +1,164 @@
+// Copyright 2021 Chay Nabors.
+
+use std::fmt::Debug;
+use std::mem;
+use std::time::Duration;
+
+use crate::Engine;
+
+// When the engine runs a stage's systems. FixedUpdate follows every FixedUpdate event, PreUpdate and Update run before
+// the UpdateEvent is sent so what they change is there for a handler drawing the frame, and PostUpdate runs after it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    FixedUpdate,
+    PreUpdate,
+    Update,
+    PostUpdate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SystemId(u64);
+
+// Takes the scaled delta time for the update stages and the step for FixedUpdate
+type System = Box<dyn FnMut(&mut Engine, Duration)>;
+
+// Systems run stage by stage, in the order they were added within each one. They get the whole engine, whose world
+// they query and change
+#[derive(Default)]
+pub struct Schedule {
+    systems: Vec<(Stage, SystemId, System)>,
+    next_id: u64,
+    // The systems taken out of the schedule while a stage runs
+    running: Vec<SystemId>,
+    // Those of them removed by a system, taken out once the stage is done
+    removed: Vec<SystemId>,
+}
+
+impl Schedule {
+    pub fn add_system<F: 'static + FnMut(&mut Engine, Duration)>(&mut self, stage: Stage, system: F) -> SystemId {
+        let id = SystemId(self.next_id);
+        self.next_id += 1;
+        self.insert(stage, id, Box::new(system));
+        id
+    }
+
+    pub fn remove_system(&mut self, id: SystemId) -> &mut Self {
+        match self.systems.iter().position(|(_, other, _)| *other == id) {
+            Some(index) => {
+                self.systems.remove(index);
+            },
+            None if self.running.contains(&id) && !self.removed.contains(&id) => self.removed.push(id),
+            None => (),
+        }
+        self
+    }
+
+    // Systems that are running count until they are removed
+    pub fn contains(&self, id: SystemId) -> bool {
+        self.systems.iter().any(|(_, other, _)| *other == id) || (self.running.contains(&id) && !self.removed.contains(&id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.systems.len() + self.running.len() - self.removed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The schedule is taken out of the engine while its systems run, anything they add or remove is merged back after
+    pub(crate) fn run(engine: &mut Engine, stage: Stage, delta_time: Duration) {
+        if !engine.schedule.systems.iter().any(|(other, _, _)| *other == stage) {
+            return;
+        }
+
+        // Ids handed out while the stage runs carry on from the schedule's
+        let next_id = engine.schedule.next_id;
+        let running = engine.schedule.systems.iter().map(|(_, id, _)| *id).collect();
+        let mut schedule = mem::replace(&mut engine.schedule, Schedule { next_id, running, ..Schedule::default() });
+        for (_, id, system) in schedule.systems.iter_mut().filter(|(other, _, _)| *other == stage) {
+            // Removed by a system earlier in the stage
+            if engine.schedule.removed.contains(id) {
+                continue;
+            }
+
+            system(engine, delta_time);
+        }
+
+        let changes = mem::take(&mut engine.schedule);
+        schedule.systems.retain(|(_, id, _)| !changes.removed.contains(id));
+        for (stage, id, system) in changes.systems {
+            schedule.insert(stage, id, system);
+        }
+        schedule.next_id = changes.next_id;
+        engine.schedule = schedule;
+    }
+
+    // Kept sorted by stage, a system goes after every other of its stage
+    fn insert(&mut self, stage: Stage, id: SystemId, system: System) {
+        let index = self.systems.iter().position(|(other, _, _)| *other > stage).unwrap_or(self.systems.len());
+        self.systems.insert(index, (stage, id, system));
+    }
+}
+
+impl Debug for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let systems = self.systems.iter().map(|(stage, id, _)| (stage, id)).collect::<Vec<_>>();
+        f.debug_struct("Schedule").field("systems", &systems).field("next_id", &self.next_id).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::Schedule;
+    use super::Stage;
+    use super::SystemId;
+    use crate::Engine;
+
+    #[test]
+    fn removed_later_in_the_same_stage() {
+        let mut engine = Engine::headless();
+        let ran = Rc::new(RefCell::new(vec![]));
+        let later = Rc::new(Cell::new(None::<SystemId>));
+
+        let (first_ran, first_later) = (ran.clone(), later.clone());
+        engine.schedule.add_system(Stage::Update, move |engine, _| {
+            first_ran.borrow_mut().push("first");
+            engine.schedule.remove_system(first_later.get().unwrap());
+        });
+        let second_ran = ran.clone();
+        later.set(Some(engine.schedule.add_system(Stage::Update, move |_, _| second_ran.borrow_mut().push("second"))));
+
+        Schedule::run(&mut engine, Stage::Update, Duration::default());
+        assert_eq!(*ran.borrow(), vec!["first"]);
+        assert!(!engine.schedule.contains(later.get().unwrap()));
+        assert_eq!(engine.schedule.len(), 1);
+    }
+
+    #[test]
+    fn contains_while_running() {
+        let mut engine = Engine::headless();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let other = engine.schedule.add_system(Stage::PostUpdate, |_, _| ());
+
+        let system_seen = seen.clone();
+        engine.schedule.add_system(Stage::Update, move |engine, _| {
+            let schedule = &mut engine.schedule;
+            system_seen.borrow_mut().push((schedule.contains(other), schedule.len()));
+            schedule.remove_system(other);
+            system_seen.borrow_mut().push((schedule.contains(other), schedule.len()));
+            schedule.add_system(Stage::Update, |_, _| ());
+            system_seen.borrow_mut().push((schedule.contains(other), schedule.len()));
+        });
+
+        Schedule::run(&mut engine, Stage::Update, Duration::default());
+        assert_eq!(*seen.borrow(), vec![(true, 2), (false, 1), (false, 2)]);
+        assert_eq!(engine.schedule.len(), 2);
+        assert!(!engine.schedule.contains(other));
+    }
+}
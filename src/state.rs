@@ -0,0 +1,29 @@
+// Copyright 2021 Chay Nabors.
+
+use crate::engine::Event;
+use crate::Engine;
+
+// A screen or mode of the game like a menu, a level or a pause overlay, pushed onto the engine's stack of states. Only
+// the one on top gets events, before the run callback does. Every hook does nothing unless it's implemented
+pub trait GameState {
+    // As it becomes the top state, pushed or switched to
+    fn enter(&mut self, _engine: &mut Engine) {}
+
+    // As it's popped or switched away from
+    fn exit(&mut self, _engine: &mut Engine) {}
+
+    // Another state was pushed over it
+    fn pause(&mut self, _engine: &mut Engine) {}
+
+    // The state over it was popped
+    fn resume(&mut self, _engine: &mut Engine) {}
+
+    fn event(&mut self, _engine: &mut Engine, _event: &Event) {}
+}
+
+// Pushes, pops and switches wait for the event being sent to finish, so a state never changes the stack under itself
+pub(crate) enum StateChange {
+    Push(Box<dyn GameState>),
+    Pop,
+    Switch(Box<dyn GameState>),
+}
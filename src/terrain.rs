@@ -0,0 +1,128 @@
+// Copyright 2021 Chay Nabors.
+
+use std::fs::{self,};
+use std::path::Path;
+
+use nalgebra::Point3;
+use nalgebra::Unit;
+use nalgebra::Vector3;
+
+use crate::Loadable;
+use crate::Result;
+
+// Heights from zero to one in rows starting from the top, which lies along the smallest z of a terrain
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    size: [u32; 2],
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    // Missing heights are zero and extra ones are left out
+    pub fn new(size: [u32; 2], mut heights: Vec<f32>) -> Heightmap {
+        heights.resize((size[0] * size[1]) as usize, 0.);
+        Heightmap { size, heights }
+    }
+
+    // Grayscale png or jpeg, 16 bit pngs keep their precision
+    pub fn from_bytes(bytes: &[u8]) -> Result<Heightmap> {
+        let image = image::load_from_memory(bytes)?.into_luma16();
+        let size = [image.width(), image.height()];
+        let heights = image.pixels().map(|pixel| pixel.0[0] as f32 / u16::MAX as f32).collect();
+        Ok(Heightmap { size, heights })
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.size
+    }
+
+    // Positions past the edges read the nearest edge
+    pub fn get(&self, x: i64, y: i64) -> f32 {
+        if self.heights.is_empty() {
+            return 0.;
+        }
+        let x = x.clamp(0, self.size[0] as i64 - 1) as usize;
+        let y = y.clamp(0, self.size[1] as i64 - 1) as usize;
+        self.heights[y * self.size[0] as usize + x]
+    }
+}
+
+impl Loadable for Heightmap {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Heightmap::from_bytes(&fs::read(path)?)
+    }
+}
+
+// A heightmap laid out along x and z with y up, draw it with Renderer::create_terrain
+#[derive(Clone, Debug)]
+pub struct Terrain {
+    pub heightmap: Heightmap,
+    // Where the first sample is at a height of zero
+    pub origin: Point3<f32>,
+    // World units between neighbouring samples
+    pub spacing: f32,
+    // World units a height of one is raised by
+    pub height_scale: f32,
+}
+
+impl Terrain {
+    pub fn new(heightmap: Heightmap, spacing: f32, height_scale: f32) -> Terrain {
+        Terrain { heightmap, origin: Point3::origin(), spacing, height_scale }
+    }
+
+    // The world size along x and z
+    pub fn extent(&self) -> [f32; 2] {
+        let [width, depth] = self.heightmap.size();
+        [width.saturating_sub(1) as f32 * self.spacing, depth.saturating_sub(1) as f32 * self.spacing]
+    }
+
+    // A sample relative to the origin
+    pub(crate) fn local(&self, x: i64, z: i64) -> Point3<f32> {
+        let height = self.heightmap.get(x, z) * self.height_scale;
+        Point3::new(x as f32 * self.spacing, height, z as f32 * self.spacing)
+    }
+
+    // Smoothed over the neighbouring samples for shading
+    pub(crate) fn sample_normal(&self, x: i64, z: i64) -> Vector3<f32> {
+        let dx = self.local(x + 1, z).y - self.local(x - 1, z).y;
+        let dz = self.local(x, z + 1).y - self.local(x, z - 1).y;
+        Vector3::new(-dx, 2. * self.spacing, -dz).normalize()
+    }
+
+    // The height of the surface as the finest level of detail draws it, None past the edges
+    pub fn height(&self, x: f32, z: f32) -> Option<f32> {
+        self.surface(x, z).map(|(height, _)| height)
+    }
+
+    // The normal of the triangle under the position, None past the edges
+    pub fn normal(&self, x: f32, z: f32) -> Option<Unit<Vector3<f32>>> {
+        self.surface(x, z).map(|(_, normal)| normal)
+    }
+
+    fn surface(&self, x: f32, z: f32) -> Option<(f32, Unit<Vector3<f32>>)> {
+        let [width, depth] = self.heightmap.size();
+        if width < 2 || depth < 2 || self.spacing <= 0. {
+            return None;
+        }
+        let (grid_x, grid_z) = ((x - self.origin.x) / self.spacing, (z - self.origin.z) / self.spacing);
+        let (last_x, last_z) = ((width - 1) as f32, (depth - 1) as f32);
+        if !(0. ..=last_x).contains(&grid_x) || !(0. ..=last_z).contains(&grid_z) {
+            return None;
+        }
+
+        let (cell_x, cell_z) = (grid_x.floor().min(last_x - 1.), grid_z.floor().min(last_z - 1.));
+        let point = |dx: i64, dz: i64| self.local(cell_x as i64 + dx, cell_z as i64 + dz);
+        // Cells are split along the diagonal from their corner at the next x to their corner at the next z
+        let (a, b, c) = match grid_x - cell_x + grid_z - cell_z <= 1. {
+            true => (point(0, 0), point(0, 1), point(1, 0)),
+            false => (point(1, 1), point(1, 0), point(0, 1)),
+        };
+        let normal = Unit::new_normalize((b - a).cross(&(c - a)));
+        let (local_x, local_z) = (grid_x * self.spacing, grid_z * self.spacing);
+        let height = a.y - (normal.x * (local_x - a.x) + normal.z * (local_z - a.z)) / normal.y;
+        Some((self.origin.y + height, normal))
+    }
+}
@@ -1,15 +1,423 @@
 // Copyright 2021 Chay Nabors.
 
-use std::path::Path;
+use std::num::NonZeroU32;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use image::error::ParameterError;
+use image::error::ParameterErrorKind;
+use image::imageops::FilterType;
+use image::imageops::{self,};
+use image::ImageError;
+use image::RgbaImage;
+use wgpu::AddressMode;
+use wgpu::BindGroup;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayout;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::Device;
+use wgpu::Extent3d;
+use wgpu::FilterMode;
+use wgpu::ImageCopyTexture;
+use wgpu::ImageDataLayout;
+use wgpu::Origin3d;
+use wgpu::Queue;
+use wgpu::Sampler;
+use wgpu::SamplerDescriptor;
+use wgpu::ShaderStage;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureSampleType;
+use wgpu::TextureUsage;
+use wgpu::TextureView;
+use wgpu::TextureViewDescriptor;
+use wgpu::TextureViewDimension;
+
+use crate::GearError;
+use crate::Result;
+
+// Batches are split wherever this changes, so it has to be cheap to compare
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureFilter {
+    // Keeps hard pixel edges, for pixel art
+    Nearest,
+    Linear,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureWrap {
+    Clamp,
+    Repeat,
+    Mirror,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextureOptions {
+    // Color images are srgb, data like normals and roughness must not be
+    pub srgb: bool,
+    pub mipmaps: bool,
+    pub filter: TextureFilter,
+    pub wrap: TextureWrap,
+}
+
+impl Default for TextureOptions {
+    fn default() -> TextureOptions {
+        TextureOptions { srgb: true, mipmaps: true, filter: TextureFilter::Linear, wrap: TextureWrap::Clamp }
+    }
+}
+
+impl TextureOptions {
+    pub fn linear() -> TextureOptions {
+        TextureOptions { srgb: false, ..Default::default() }
+    }
+}
+
+// A handle to an image on the gpu, clones share the same image
+#[derive(Clone, Debug)]
+pub struct Texture {
+    inner: Arc<TextureData>,
+}
 
 #[derive(Debug)]
-pub struct Texture;
+struct TextureData {
+    id: u64,
+    size: [u32; 2],
+    texture: wgpu::Texture,
+    view: TextureView,
+    _sampler: Sampler,
+    bind_group: BindGroup,
+}
 
 impl Texture {
-    fn _load<P: AsRef<Path>>(_path: P) -> Result<Self, ()>
-    where
-        Self: Sized,
-    {
-        Ok(Texture)
+    // Rgba8 with tightly packed rows starting from the top
+    pub(crate) fn from_rgba(
+        device: &Device,
+        queue: &Queue,
+        layout: &BindGroupLayout,
+        size: [u32; 2],
+        data: &[u8],
+        options: &TextureOptions,
+    ) -> Result<Texture> {
+        let image = match RgbaImage::from_raw(size[0], size[1], data.to_vec()) {
+            Some(image) => image,
+            None => return Err(dimension_mismatch()),
+        };
+        Ok(Texture::from_image(device, queue, layout, image, options))
+    }
+
+    // Png or jpeg, the format is guessed from the contents
+    pub(crate) fn from_bytes(
+        device: &Device,
+        queue: &Queue,
+        layout: &BindGroupLayout,
+        bytes: &[u8],
+        options: &TextureOptions,
+    ) -> Result<Texture> {
+        let image = image::load_from_memory(bytes)?.into_rgba8();
+        Ok(Texture::from_image(device, queue, layout, image, options))
+    }
+
+    pub(crate) fn from_image(
+        device: &Device,
+        queue: &Queue,
+        layout: &BindGroupLayout,
+        image: RgbaImage,
+        options: &TextureOptions,
+    ) -> Texture {
+        let size = [image.width().max(1), image.height().max(1)];
+        let mip_level_count = if options.mipmaps { 32 - size[0].max(size[1]).leading_zeros() } else { 1 };
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("texture"),
+            size: Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 },
+            mip_level_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: if options.srgb { TextureFormat::Rgba8UnormSrgb } else { TextureFormat::Rgba8Unorm },
+            usage: TextureUsage::SAMPLED | TextureUsage::COPY_DST,
+        });
+
+        // Each level is filtered down from the one above it on the cpu
+        let mut level = image;
+        for mip_level in 0..mip_level_count {
+            if mip_level > 0 {
+                let width = (size[0] >> mip_level).max(1);
+                let height = (size[1] >> mip_level).max(1);
+                level = imageops::resize(&level, width, height, FilterType::Triangle);
+            }
+
+            queue.write_texture(
+                ImageCopyTexture { texture: &texture, mip_level, origin: Origin3d::ZERO },
+                level.as_raw(),
+                ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(4 * level.width()),
+                    rows_per_image: NonZeroU32::new(level.height()),
+                },
+                Extent3d { width: level.width(), height: level.height(), depth_or_array_layers: 1 },
+            );
+        }
+
+        Texture::from_texture(device, layout, texture, size, options)
+    }
+
+    // Wraps a texture something else draws to, it has to be sampled usage
+    pub(crate) fn from_texture(
+        device: &Device,
+        layout: &BindGroupLayout,
+        texture: wgpu::Texture,
+        size: [u32; 2],
+        options: &TextureOptions,
+    ) -> Texture {
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        let sampler = create_sampler(device, options);
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("texture_bind_group"),
+            layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: BindingResource::TextureView(&view) },
+                BindGroupEntry { binding: 1, resource: BindingResource::Sampler(&sampler) },
+            ],
+        });
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        Texture { inner: Arc::new(TextureData { id, size, texture, view, _sampler: sampler, bind_group }) }
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.inner.size
+    }
+
+    // Replaces a region of the first level with rgba8 rows starting from the top, the other levels are left as they are
+    pub(crate) fn write(&self, queue: &Queue, origin: [u32; 2], size: [u32; 2], data: &[u8]) {
+        queue.write_texture(
+            ImageCopyTexture {
+                texture: &self.inner.texture,
+                mip_level: 0,
+                origin: Origin3d { x: origin[0], y: origin[1], z: 0 },
+            },
+            data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(4 * size[0]),
+                rows_per_image: NonZeroU32::new(size[1]),
+            },
+            Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 },
+        );
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    pub(crate) fn view(&self) -> &TextureView {
+        &self.inner.view
+    }
+
+    pub(crate) fn bind_group(&self) -> &BindGroup {
+        &self.inner.bind_group
     }
 }
+
+// Images of the same size in the layers of one texture. Whatever draws from it picks a layer for each sprite or instance,
+// so switching between them doesn't break a batch. Clones share the same layers
+#[derive(Clone, Debug)]
+pub struct TextureArray {
+    inner: Arc<TextureArrayData>,
+}
+
+#[derive(Debug)]
+struct TextureArrayData {
+    id: u64,
+    size: [u32; 2],
+    layer_count: u32,
+    _texture: wgpu::Texture,
+    view: TextureView,
+    _sampler: Sampler,
+    bind_group: BindGroup,
+}
+
+impl TextureArray {
+    // Rgba8 layers with tightly packed rows starting from the top
+    pub(crate) fn from_rgba(
+        device: &Device,
+        queue: &Queue,
+        layout: &BindGroupLayout,
+        size: [u32; 2],
+        layers: &[&[u8]],
+        options: &TextureOptions,
+    ) -> Result<TextureArray> {
+        let images = layers.iter().map(|layer| RgbaImage::from_raw(size[0], size[1], layer.to_vec())).collect();
+        match images {
+            Some(images) => TextureArray::from_images(device, queue, layout, images, options),
+            None => Err(dimension_mismatch()),
+        }
+    }
+
+    // Pngs or jpegs, which all have to be the same size
+    pub(crate) fn from_bytes(
+        device: &Device,
+        queue: &Queue,
+        layout: &BindGroupLayout,
+        layers: &[&[u8]],
+        options: &TextureOptions,
+    ) -> Result<TextureArray> {
+        let images =
+            layers.iter().map(|bytes| Ok(image::load_from_memory(bytes)?.into_rgba8())).collect::<Result<Vec<_>>>()?;
+        TextureArray::from_images(device, queue, layout, images, options)
+    }
+
+    fn from_images(
+        device: &Device,
+        queue: &Queue,
+        layout: &BindGroupLayout,
+        images: Vec<RgbaImage>,
+        options: &TextureOptions,
+    ) -> Result<TextureArray> {
+        let size = match images.first() {
+            Some(image) => [image.width(), image.height()],
+            None => return Err(dimension_mismatch()),
+        };
+        if size[0] == 0 || size[1] == 0 || images.iter().any(|image| [image.width(), image.height()] != size) {
+            return Err(dimension_mismatch());
+        }
+
+        let layer_count = images.len() as u32;
+        let mip_level_count = if options.mipmaps { 32 - size[0].max(size[1]).leading_zeros() } else { 1 };
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("texture_array"),
+            size: Extent3d { width: size[0], height: size[1], depth_or_array_layers: layer_count },
+            mip_level_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: if options.srgb { TextureFormat::Rgba8UnormSrgb } else { TextureFormat::Rgba8Unorm },
+            usage: TextureUsage::SAMPLED | TextureUsage::COPY_DST,
+        });
+
+        // Filtered down on the cpu like a texture's levels, one layer at a time
+        for (layer, image) in images.into_iter().enumerate() {
+            let mut level = image;
+            for mip_level in 0..mip_level_count {
+                if mip_level > 0 {
+                    let width = (size[0] >> mip_level).max(1);
+                    let height = (size[1] >> mip_level).max(1);
+                    level = imageops::resize(&level, width, height, FilterType::Triangle);
+                }
+
+                queue.write_texture(
+                    ImageCopyTexture { texture: &texture, mip_level, origin: Origin3d { x: 0, y: 0, z: layer as u32 } },
+                    level.as_raw(),
+                    ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: NonZeroU32::new(4 * level.width()),
+                        rows_per_image: NonZeroU32::new(level.height()),
+                    },
+                    Extent3d { width: level.width(), height: level.height(), depth_or_array_layers: 1 },
+                );
+            }
+        }
+
+        let view = texture
+            .create_view(&TextureViewDescriptor { dimension: Some(TextureViewDimension::D2Array), ..Default::default() });
+        let sampler = create_sampler(device, options);
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("texture_array_bind_group"),
+            layout,
+            entries: &[
+                BindGroupEntry { binding: 0, resource: BindingResource::TextureView(&view) },
+                BindGroupEntry { binding: 1, resource: BindingResource::Sampler(&sampler) },
+            ],
+        });
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let data = TextureArrayData { id, size, layer_count, _texture: texture, view, _sampler: sampler, bind_group };
+        Ok(TextureArray { inner: Arc::new(data) })
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.inner.size
+    }
+
+    pub fn layer_count(&self) -> u32 {
+        self.inner.layer_count
+    }
+
+    // Shares the ids of textures, so the two never compare equal in a batch
+    pub(crate) fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    pub(crate) fn view(&self) -> &TextureView {
+        &self.inner.view
+    }
+
+    pub(crate) fn bind_group(&self) -> &BindGroup {
+        &self.inner.bind_group
+    }
+}
+
+fn dimension_mismatch() -> GearError {
+    ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)).into()
+}
+
+fn create_sampler(device: &Device, options: &TextureOptions) -> Sampler {
+    let address_mode = match options.wrap {
+        TextureWrap::Clamp => AddressMode::ClampToEdge,
+        TextureWrap::Repeat => AddressMode::Repeat,
+        TextureWrap::Mirror => AddressMode::MirrorRepeat,
+    };
+    let filter = match options.filter {
+        TextureFilter::Nearest => FilterMode::Nearest,
+        TextureFilter::Linear => FilterMode::Linear,
+    };
+    device.create_sampler(&SamplerDescriptor {
+        label: Some("sampler"),
+        address_mode_u: address_mode,
+        address_mode_v: address_mode,
+        mag_filter: filter,
+        min_filter: filter,
+        mipmap_filter: filter,
+        ..Default::default()
+    })
+}
+
+// Every pipeline that samples a texture binds it through this layout
+pub(crate) fn create_bind_group_layout(device: &Device) -> BindGroupLayout {
+    create_layout(device, "texture_bind_group_layout", TextureViewDimension::D2)
+}
+
+// And texture arrays through this one
+pub(crate) fn create_array_bind_group_layout(device: &Device) -> BindGroupLayout {
+    create_layout(device, "texture_array_bind_group_layout", TextureViewDimension::D2Array)
+}
+
+fn create_layout(device: &Device, label: &str, view_dimension: TextureViewDimension) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStage::FRAGMENT,
+                ty: BindingType::Sampler { filtering: true, comparison: false },
+                count: None,
+            },
+        ],
+    })
+}
@@ -0,0 +1,83 @@
+// Copyright 2021 Chay Nabors.
+
+use std::time::Duration;
+
+// How fast the game's time passes, the delta_time of updates and the fixed updates follow it while the unscaled
+// times keep going at the speed of the wall clock
+#[derive(Debug)]
+pub struct Time {
+    scale: f32,
+    paused: bool,
+    delta_time: Duration,
+    unscaled_delta_time: Duration,
+    elapsed: Duration,
+    unscaled_elapsed: Duration,
+}
+
+impl Time {
+    pub(crate) fn new() -> Time {
+        Time {
+            scale: 1.,
+            paused: false,
+            delta_time: Duration::default(),
+            unscaled_delta_time: Duration::default(),
+            elapsed: Duration::default(),
+            unscaled_elapsed: Duration::default(),
+        }
+    }
+
+    // Below one slows time down and above speeds it up, negative scales are taken as zero
+    pub fn set_scale(&mut self, scale: f32) -> &mut Self {
+        self.scale = scale.max(0.);
+        self
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    // Stops time without losing the scale, updates keep coming with a delta_time of zero
+    pub fn pause(&mut self) -> &mut Self {
+        self.paused = true;
+        self
+    }
+
+    pub fn resume(&mut self) -> &mut Self {
+        self.paused = false;
+        self
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    // Of the last update
+    pub fn delta_time(&self) -> Duration {
+        self.delta_time
+    }
+
+    pub fn unscaled_delta_time(&self) -> Duration {
+        self.unscaled_delta_time
+    }
+
+    // Since the engine started running
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn unscaled_elapsed(&self) -> Duration {
+        self.unscaled_elapsed
+    }
+
+    // Once a frame with the wall clock time since the last one, returns it scaled
+    pub(crate) fn advance(&mut self, unscaled_delta_time: Duration) -> Duration {
+        self.unscaled_delta_time = unscaled_delta_time;
+        self.delta_time = match self.paused {
+            true => Duration::default(),
+            false => unscaled_delta_time.mul_f32(self.scale),
+        };
+        self.elapsed += self.delta_time;
+        self.unscaled_elapsed += unscaled_delta_time;
+        self.delta_time
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2021 Chay Nabors.
+
+use std::convert::TryInto;
+use std::fs::{self,};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::{self,};
+use std::time::Duration;
+
+use crossbeam::channel::Receiver;
+use crossbeam::channel::Sender;
+use crossbeam::channel::TryRecvError;
+use crossbeam::channel::{self,};
+use rodio::Sample;
+use rodio::Source;
+
+use crate::AudioSource;
+use crate::GearError;
+use crate::Loadable;
+use crate::Result;
+use crate::Sound;
+
+// Decoded frames waiting to be shown, more smooths over slow frames at the cost of memory
+const FRAMES_AHEAD: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoCodec {
+    Av1,
+    Vp9,
+}
+
+#[derive(Clone, Debug)]
+struct VideoPacket {
+    range: Range<usize>,
+    timestamp: Duration,
+}
+
+// Compressed frames in an ivf file, which is what ffmpeg writes for av1 and vp9 with -f ivf
+#[derive(Clone, Debug)]
+pub struct Video {
+    data: Arc<Vec<u8>>,
+    codec: VideoCodec,
+    size: [u32; 2],
+    packets: Arc<Vec<VideoPacket>>,
+}
+
+impl Video {
+    pub fn from_bytes(data: Vec<u8>) -> Result<Video> {
+        let invalid = |reason: &str| GearError::VideoError(reason.into());
+        if data.len() < 32 || &data[0..4] != b"DKIF" {
+            return Err(invalid("not an ivf file"));
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let codec = match &data[8..12] {
+            b"AV01" => VideoCodec::Av1,
+            b"VP90" => VideoCodec::Vp9,
+            _ => return Err(invalid("the codec is neither av1 nor vp9")),
+        };
+        let size = [u16_at(12) as u32, u16_at(14) as u32];
+        // Timestamps count units of scale / rate seconds
+        let (rate, scale) = (u32_at(16) as u64, u32_at(20) as u64);
+        if rate == 0 {
+            return Err(invalid("the time base is zero"));
+        }
+
+        let mut packets = vec![];
+        let mut at = u16_at(6) as usize;
+        while at + 12 <= data.len() {
+            let len = u32_at(at) as usize;
+            let pts = u64::from_le_bytes(data[at + 4..at + 12].try_into().unwrap());
+            let start = at + 12;
+            if start + len > data.len() {
+                return Err(invalid("a frame runs past the end of the file"));
+            }
+            let timestamp = Duration::from_secs_f64(pts as f64 * scale as f64 / rate as f64);
+            packets.push(VideoPacket { range: start..start + len, timestamp });
+            at = start + len;
+        }
+
+        Ok(Video { data: Arc::new(data), codec, size, packets: Arc::new(packets) })
+    }
+
+    pub fn codec(&self) -> VideoCodec {
+        self.codec
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.size
+    }
+
+    // When the last frame is shown
+    pub fn duration(&self) -> Duration {
+        self.packets.last().map_or(Duration::default(), |packet| packet.timestamp)
+    }
+}
+
+impl Loadable for Video {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Video::from_bytes(fs::read(path)?)
+    }
+}
+
+// Rgba8 with tightly packed rows starting from the top
+#[derive(Clone, Debug)]
+pub struct VideoFrame {
+    pub size: [u32; 2],
+    pub rgba: Vec<u8>,
+    // When the frame is shown from the start of the video
+    pub timestamp: Duration,
+}
+
+impl VideoFrame {
+    // Limited range bt.709 with chroma planes of half the width and height, what decoders hand out for most video
+    pub fn from_i420(size: [u32; 2], planes: [&[u8]; 3], strides: [usize; 3], timestamp: Duration) -> VideoFrame {
+        let (width, height) = (size[0] as usize, size[1] as usize);
+        let mut rgba = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let luma = planes[0][y * strides[0] + x] as f32 - 16.;
+                let u = planes[1][y / 2 * strides[1] + x / 2] as f32 - 128.;
+                let v = planes[2][y / 2 * strides[2] + x / 2] as f32 - 128.;
+                let luma = luma * 1.164;
+                let r = luma + 1.793 * v;
+                let g = luma - 0.213 * u - 0.533 * v;
+                let b = luma + 2.112 * u;
+                rgba.extend_from_slice(&[r.clamp(0., 255.) as u8, g.clamp(0., 255.) as u8, b.clamp(0., 255.) as u8, 255]);
+            }
+        }
+        VideoFrame { size, rgba, timestamp }
+    }
+}
+
+// Decodes on a thread of its own, frames can come out later than their packets went in but must be in the order they
+// are shown
+pub trait VideoDecoder: Send {
+    fn decode(&mut self, packet: &[u8], timestamp: Duration) -> Result<Vec<VideoFrame>>;
+
+    // Frames still held once every packet went in
+    fn flush(&mut self) -> Result<Vec<VideoFrame>> {
+        Ok(vec![])
+    }
+}
+
+// The decoder for the codec when one is built in, av1 with the av1 feature which needs libdav1d on the system. Other
+// codecs need a decoder given to VideoPlayer::with_decoder
+fn default_decoder(codec: VideoCodec) -> Result<Box<dyn VideoDecoder>> {
+    #[cfg(feature = "av1")]
+    {
+        if codec == VideoCodec::Av1 {
+            return Ok(Box::new(av1::Av1Decoder::new()));
+        }
+    }
+    Err(GearError::UnsupportedFeature(format!("decoding {:?} video", codec)))
+}
+
+#[derive(Debug)]
+enum VideoClock {
+    Wall(Duration),
+    // Samples the audio output has taken from the sound, over the samples in a second of it
+    Audio { samples: Arc<AtomicU64>, rate: u64 },
+}
+
+impl VideoClock {
+    fn time(&self) -> Duration {
+        match self {
+            VideoClock::Wall(time) => *time,
+            VideoClock::Audio { samples, rate } => {
+                Duration::from_secs_f64(samples.load(Ordering::Relaxed) as f64 / (*rate).max(1) as f64)
+            },
+        }
+    }
+}
+
+// Streams the frames of a video as they come due, write them to a texture from Renderer::create_video_texture with
+// Renderer::write_video_frame
+#[derive(Debug)]
+pub struct VideoPlayer {
+    size: [u32; 2],
+    duration: Duration,
+    frames: Receiver<Result<VideoFrame>>,
+    next: Option<VideoFrame>,
+    clock: VideoClock,
+    decoded: bool,
+}
+
+impl VideoPlayer {
+    pub fn new(video: &Video) -> Result<VideoPlayer> {
+        Ok(VideoPlayer::with_decoder(video, default_decoder(video.codec)?))
+    }
+
+    pub fn with_decoder(video: &Video, decoder: Box<dyn VideoDecoder>) -> VideoPlayer {
+        let (sender, frames) = channel::bounded(FRAMES_AHEAD);
+        let stream = video.clone();
+        thread::spawn(move || decode(stream, decoder, sender));
+        VideoPlayer {
+            size: video.size,
+            duration: video.duration(),
+            frames,
+            next: None,
+            clock: VideoClock::Wall(Duration::default()),
+            decoded: false,
+        }
+    }
+
+    // Plays the soundtrack on the source and follows how much of it was played from then on instead of the delta times
+    // given to update, so the picture stays with the sound however the audio output buffers it
+    pub fn sync_to_audio(&mut self, source: &AudioSource, sound: &Sound) -> &mut Self {
+        let decoder = sound.decoder();
+        let rate = decoder.sample_rate() as u64 * decoder.channels() as u64;
+        let samples = Arc::new(AtomicU64::new(0));
+        source.queue_source(CountedSource { source: decoder, samples: samples.clone() });
+        self.clock = VideoClock::Audio { samples, rate };
+        self
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.size
+    }
+
+    pub fn time(&self) -> Duration {
+        self.clock.time()
+    }
+
+    // True once the last frame was handed out
+    pub fn finished(&self) -> bool {
+        self.decoded && self.next.is_none()
+    }
+
+    // The newest frame that came due since the last call, frames decoded too late to be shown are skipped
+    pub fn update(&mut self, delta_time: Duration) -> Result<Option<VideoFrame>> {
+        if let VideoClock::Wall(time) = &mut self.clock {
+            *time = (*time + delta_time).min(self.duration);
+        }
+        let time = self.clock.time();
+
+        let mut due = None;
+        loop {
+            if self.next.is_none() {
+                match self.frames.try_recv() {
+                    Ok(frame) => self.next = Some(frame?),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.decoded = true;
+                        break;
+                    },
+                }
+            }
+            match &self.next {
+                Some(frame) if frame.timestamp <= time => due = self.next.take(),
+                _ => break,
+            }
+        }
+        Ok(due)
+    }
+}
+
+// Stops when the player is dropped, which leaves nothing to send the frames to
+fn decode(video: Video, mut decoder: Box<dyn VideoDecoder>, frames: Sender<Result<VideoFrame>>) {
+    let send = |decoded: Result<Vec<VideoFrame>>| match decoded {
+        Ok(decoded) => decoded.into_iter().all(|frame| frames.send(Ok(frame)).is_ok()),
+        Err(e) => {
+            let _ = frames.send(Err(e));
+            false
+        },
+    };
+    for packet in video.packets.iter() {
+        if !send(decoder.decode(&video.data[packet.range.clone()], packet.timestamp)) {
+            return;
+        }
+    }
+    send(decoder.flush());
+}
+
+// Counts the samples taken from the sound as the audio output plays it
+struct CountedSource<S> {
+    source: S,
+    samples: Arc<AtomicU64>,
+}
+
+impl<S: Source> Iterator for CountedSource<S>
+where
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        let sample = self.source.next();
+        if sample.is_some() {
+            self.samples.fetch_add(1, Ordering::Relaxed);
+        }
+        sample
+    }
+}
+
+impl<S: Source> Source for CountedSource<S>
+where
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(feature = "av1")]
+mod av1 {
+    use std::time::Duration;
+
+    use dav1d::Decoder;
+    use dav1d::PixelLayout;
+    use dav1d::PlanarImageComponent;
+
+    use super::VideoDecoder;
+    use super::VideoFrame;
+    use crate::GearError;
+    use crate::Result;
+
+    pub(super) struct Av1Decoder {
+        decoder: Decoder,
+    }
+
+    impl Av1Decoder {
+        pub(super) fn new() -> Av1Decoder {
+            Av1Decoder { decoder: Decoder::new() }
+        }
+
+        fn pictures(&mut self) -> Result<Vec<VideoFrame>> {
+            let mut frames = vec![];
+            loop {
+                match self.decoder.get_picture() {
+                    Ok(picture) => {
+                        if picture.pixel_layout() != PixelLayout::I420 || picture.bit_depth() != 8 {
+                            return Err(GearError::VideoError("only 8 bit 4:2:0 av1 is supported".into()));
+                        }
+                        let planes = [
+                            picture.plane(PlanarImageComponent::Y),
+                            picture.plane(PlanarImageComponent::U),
+                            picture.plane(PlanarImageComponent::V),
+                        ];
+                        let strides = [
+                            picture.stride(PlanarImageComponent::Y) as usize,
+                            picture.stride(PlanarImageComponent::U) as usize,
+                            picture.stride(PlanarImageComponent::V) as usize,
+                        ];
+                        let timestamp = Duration::from_micros(picture.timestamp().unwrap_or(0).max(0) as u64);
+                        let planes = [planes[0].as_ref(), planes[1].as_ref(), planes[2].as_ref()];
+                        let size = [picture.width(), picture.height()];
+                        frames.push(VideoFrame::from_i420(size, planes, strides, timestamp));
+                    },
+                    Err(e) if e.is_again() => return Ok(frames),
+                    Err(e) => return Err(GearError::VideoError(e.to_string())),
+                }
+            }
+        }
+    }
+
+    impl VideoDecoder for Av1Decoder {
+        fn decode(&mut self, packet: &[u8], timestamp: Duration) -> Result<Vec<VideoFrame>> {
+            let timestamp = Some(timestamp.as_micros() as i64);
+            let mut frames = vec![];
+            let mut sent = self.decoder.send_data(packet.to_vec(), None, timestamp, None);
+            // A full decoder takes the rest of the packet once pictures are taken out of it
+            while let Err(e) = &sent {
+                if !e.is_again() {
+                    return Err(GearError::VideoError(e.to_string()));
+                }
+                frames.extend(self.pictures()?);
+                sent = self.decoder.send_pending_data();
+            }
+            frames.extend(self.pictures()?);
+            Ok(frames)
+        }
+
+        fn flush(&mut self) -> Result<Vec<VideoFrame>> {
+            self.pictures()
+        }
+    }
+}
@@ -15,13 +15,24 @@ pub enum WindowEvent {
 #[derive(Debug)]
 pub struct Window {
     window: Option<winit::window::Window>,
+    transparent: bool,
 }
 
 impl Window {
-    pub(crate) fn new(event_loop: &winit::event_loop::EventLoop<()>) -> Self {
+    pub(crate) fn new(event_loop: &winit::event_loop::EventLoop<()>, transparent: bool) -> Self {
         info!("Initializing windowing backend");
 
-        Self { window: Some(create_window(event_loop)) }
+        Self { window: Some(create_window(event_loop, transparent)), transparent }
+    }
+
+    // What a headless engine has in place of a window, it's as if it had been closed
+    pub(crate) fn headless() -> Self {
+        Self { window: None, transparent: false }
+    }
+
+    // Whether the window was created with per pixel transparency
+    pub fn transparent(&self) -> bool {
+        self.transparent
     }
 
     pub fn rename(&self, name: &str) -> &Self {
@@ -81,11 +92,11 @@ unsafe impl raw_window_handle::HasRawWindowHandle for Window {
 // This is a workaround since rodio and winit can't run in parallel when drag
 // and drop is enabled on windows
 #[cfg(target_os = "windows")]
-fn create_window(event_loop: &winit::event_loop::EventLoop<()>) -> winit::window::Window {
-    winit::window::WindowBuilder::new().with_drag_and_drop(false).build(event_loop).unwrap()
+fn create_window(event_loop: &winit::event_loop::EventLoop<()>, transparent: bool) -> winit::window::Window {
+    winit::window::WindowBuilder::new().with_drag_and_drop(false).with_transparent(transparent).build(event_loop).unwrap()
 }
 
 #[cfg(not(target_os = "windows"))]
-fn create_window(event_loop: &winit::event_loop::EventLoop<()>) -> winit::window::Window {
-    winit::window::WindowBuilder::new().build(event_loop).unwrap()
+fn create_window(event_loop: &winit::event_loop::EventLoop<()>, transparent: bool) -> winit::window::Window {
+    winit::window::WindowBuilder::new().with_transparent(transparent).build(event_loop).unwrap()
 }